@@ -0,0 +1,760 @@
+use std::fmt::Display;
+
+use fxhash::{FxHashMap, FxHashSet};
+use rand::{thread_rng, Rng};
+use sea_orm::{entity::prelude::*, ActiveValue, DatabaseTransaction, QueryOrder, TransactionError};
+use serde::{Deserialize, Serialize};
+use teach_tech_core::{
+    auth::{AdminUser, AuthUser, UserID},
+    axum::{
+        body::Bytes,
+        extract::{Path, Query},
+        http::{HeaderMap, StatusCode},
+        response::{IntoResponse, Response},
+        Json,
+    },
+    db::{
+        get_db, get_read_db, insert_batched, paginate, transaction_with_retry, PageQuery,
+        SoftDeletable,
+    },
+    i18n,
+    users::{admins, students},
+};
+use tracing::{error, warn};
+
+use crate::{
+    fees, guardians,
+    invoices::{
+        self,
+        payments::{self, PaymentStatus},
+        InvoiceStatus,
+    },
+    options,
+    provider::{get_provider, CheckoutRequest, PaymentOutcome},
+};
+
+/// The provider name of payments recorded by administrators
+const MANUAL_PROVIDER: &str = "manual";
+
+fn internal_error(context: &str, e: impl Display) -> Response {
+    error!("{context}: {e:#}");
+    (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+}
+
+/// Whether `user_id` may see and pay the invoices of `student_id`, which students can for
+/// themselves, guardians for their students and administrators for everyone.
+async fn can_access(user_id: UserID, student_id: UserID) -> Result<bool, DbErr> {
+    if user_id == student_id {
+        return Ok(true);
+    }
+    if guardians::Entity::find_by_id((student_id, user_id))
+        .one(get_read_db())
+        .await?
+        .is_some()
+    {
+        return Ok(true);
+    }
+    Ok(admins::Entity::find_live_by_id(user_id)
+        .one(get_read_db())
+        .await?
+        .is_some())
+}
+
+fn invalid_amount() -> Response {
+    (StatusCode::BAD_REQUEST, i18n::t("billing.invalid_amount")).into_response()
+}
+
+fn invalid_description() -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        i18n::t("billing.invalid_description"),
+    )
+        .into_response()
+}
+
+fn unknown_student() -> Response {
+    (StatusCode::BAD_REQUEST, i18n::t("billing.unknown_student")).into_response()
+}
+
+/// Adds a successful payment to the invoice, marking it paid once nothing is outstanding.
+async fn apply_payment(
+    txn: &DatabaseTransaction,
+    invoice: invoices::Model,
+    amount_cents: i64,
+) -> Result<invoices::Model, DbErr> {
+    let paid_cents = invoice.paid_cents + amount_cents;
+    let mut active: invoices::ActiveModel = invoice.into();
+    if paid_cents >= *active.amount_cents.as_ref() {
+        active.status = ActiveValue::set(InvoiceStatus::Paid);
+        active.paid_at = ActiveValue::set(Some(chrono::Utc::now().naive_utc()));
+    }
+    active.paid_cents = ActiveValue::set(paid_cents);
+    active.update(txn).await
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateFeeSchedule {
+    pub term: String,
+    pub description: String,
+    pub amount_cents: i64,
+    pub due_on: Date,
+}
+
+pub async fn list_fee_schedules(_: AdminUser) -> Response {
+    match fees::Entity::find()
+        .order_by_desc(fees::Column::DueOn)
+        .order_by_asc(fees::Column::Id)
+        .all(get_read_db())
+        .await
+    {
+        Ok(schedules) => (StatusCode::OK, Json(schedules)).into_response(),
+        Err(e) => internal_error("Error listing fee schedules", e),
+    }
+}
+
+pub async fn create_fee_schedule(
+    AdminUser {
+        user: AuthUser { user_id, .. },
+        ..
+    }: AdminUser,
+    Json(create): Json<CreateFeeSchedule>,
+) -> Response {
+    if create.amount_cents <= 0 {
+        return invalid_amount();
+    }
+    if create.description.trim().is_empty() || create.term.trim().is_empty() {
+        return invalid_description();
+    }
+    let result = fees::ActiveModel {
+        id: ActiveValue::not_set(),
+        term: ActiveValue::set(create.term),
+        description: ActiveValue::set(create.description),
+        amount_cents: ActiveValue::set(create.amount_cents),
+        due_on: ActiveValue::set(create.due_on),
+        created_by: ActiveValue::set(user_id),
+        created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+    }
+    .insert(get_db())
+    .await;
+    match result {
+        Ok(schedule) => (StatusCode::CREATED, Json(schedule)).into_response(),
+        Err(e) => internal_error("Error creating fee schedule", e),
+    }
+}
+
+pub async fn delete_fee_schedule(_: AdminUser, Path(id): Path<i32>) -> Response {
+    match invoices::Entity::find()
+        .filter(invoices::Column::FeeScheduleId.eq(id))
+        .count(get_db())
+        .await
+    {
+        Ok(0) => {}
+        Ok(_) => {
+            return (
+                StatusCode::CONFLICT,
+                i18n::t("billing.fee_schedule_invoiced"),
+            )
+                .into_response();
+        }
+        Err(e) => return internal_error("Error reading invoices", e),
+    }
+    match fees::Entity::delete_by_id(id).exec(get_db()).await {
+        Ok(result) if result.rows_affected == 0 => (StatusCode::NOT_FOUND, ()).into_response(),
+        Ok(_) => (StatusCode::OK, ()).into_response(),
+        Err(e) => internal_error("Error deleting fee schedule", e),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Issued {
+    pub issued: usize,
+}
+
+/// Invoices every student who has not been invoiced for the fee schedule yet, so it can be run
+/// again after students are added.
+pub async fn issue_fee_schedule(
+    AdminUser {
+        user: AuthUser { user_id, .. },
+        ..
+    }: AdminUser,
+    Path(id): Path<i32>,
+) -> Response {
+    let result = transaction_with_retry(get_db(), |txn| {
+        Box::pin(async move {
+            let Some(schedule) = fees::Entity::find_by_id(id).one(txn).await? else {
+                return Ok(None);
+            };
+            let invoiced: FxHashSet<UserID> = invoices::Entity::find()
+                .filter(invoices::Column::FeeScheduleId.eq(id))
+                .all(txn)
+                .await?
+                .into_iter()
+                .map(|invoice| invoice.student_id)
+                .collect();
+            let now = chrono::Utc::now().naive_utc();
+            let new_invoices: Vec<_> = students::Entity::find_live()
+                .all(txn)
+                .await?
+                .into_iter()
+                .filter(|student| !invoiced.contains(&student.user_id))
+                .map(|student| invoices::ActiveModel {
+                    id: ActiveValue::not_set(),
+                    student_id: ActiveValue::set(student.user_id),
+                    fee_schedule_id: ActiveValue::set(Some(schedule.id)),
+                    term: ActiveValue::set(Some(schedule.term.clone())),
+                    description: ActiveValue::set(schedule.description.clone()),
+                    amount_cents: ActiveValue::set(schedule.amount_cents),
+                    paid_cents: ActiveValue::set(0),
+                    due_on: ActiveValue::set(schedule.due_on),
+                    status: ActiveValue::set(InvoiceStatus::Open),
+                    created_by: ActiveValue::set(user_id),
+                    created_at: ActiveValue::set(now),
+                    paid_at: ActiveValue::set(None),
+                })
+                .collect();
+            let issued = new_invoices.len();
+            insert_batched(new_invoices, txn).await?;
+            Ok(Some(issued))
+        })
+    })
+    .await;
+    match result {
+        Ok(Some(issued)) => (StatusCode::OK, Json(Issued { issued })).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => internal_error("Error issuing invoices", e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InvoiceFilter {
+    pub status: Option<InvoiceStatus>,
+    pub student_id: Option<UserID>,
+}
+
+pub async fn list_invoices(
+    _: AdminUser,
+    Query(page): Query<PageQuery>,
+    Query(filter): Query<InvoiceFilter>,
+) -> Response {
+    let Ok(cursor) = page.cursor::<i32>() else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_cursor")).into_response();
+    };
+    let mut select = invoices::Entity::find();
+    if let Some(status) = filter.status {
+        select = select.filter(invoices::Column::Status.eq(status));
+    }
+    if let Some(student_id) = filter.student_id {
+        select = select.filter(invoices::Column::StudentId.eq(student_id));
+    }
+    match paginate(
+        select,
+        invoices::Column::Id,
+        |model| model.id,
+        cursor,
+        page.limit(),
+        get_read_db(),
+    )
+    .await
+    {
+        Ok(page) => (StatusCode::OK, Json(page)).into_response(),
+        Err(e) => internal_error("Error listing invoices", e),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateInvoice {
+    pub student_id: UserID,
+    pub description: String,
+    pub amount_cents: i64,
+    pub due_on: Date,
+    #[serde(default)]
+    pub term: Option<String>,
+}
+
+/// Invoices one student for something outside the fee schedules, such as a field trip.
+pub async fn create_invoice(
+    AdminUser {
+        user: AuthUser { user_id, .. },
+        ..
+    }: AdminUser,
+    Json(create): Json<CreateInvoice>,
+) -> Response {
+    if create.amount_cents <= 0 {
+        return invalid_amount();
+    }
+    if create.description.trim().is_empty() {
+        return invalid_description();
+    }
+    match students::Entity::find_live_by_id(create.student_id)
+        .one(get_db())
+        .await
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => return unknown_student(),
+        Err(e) => return internal_error("Error reading student", e),
+    }
+    let result = invoices::ActiveModel {
+        id: ActiveValue::not_set(),
+        student_id: ActiveValue::set(create.student_id),
+        fee_schedule_id: ActiveValue::set(None),
+        term: ActiveValue::set(create.term),
+        description: ActiveValue::set(create.description),
+        amount_cents: ActiveValue::set(create.amount_cents),
+        paid_cents: ActiveValue::set(0),
+        due_on: ActiveValue::set(create.due_on),
+        status: ActiveValue::set(InvoiceStatus::Open),
+        created_by: ActiveValue::set(user_id),
+        created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+        paid_at: ActiveValue::set(None),
+    }
+    .insert(get_db())
+    .await;
+    match result {
+        Ok(invoice) => (StatusCode::CREATED, Json(invoice)).into_response(),
+        Err(e) => internal_error("Error creating invoice", e),
+    }
+}
+
+enum VoidOutcome {
+    Voided(invoices::Model),
+    NotFound,
+    NotOpen,
+    HasPayments,
+}
+
+pub async fn void_invoice(_: AdminUser, Path(id): Path<i32>) -> Response {
+    let result = transaction_with_retry(get_db(), |txn| {
+        Box::pin(async move {
+            let Some(invoice) = invoices::Entity::find_by_id(id).one(txn).await? else {
+                return Ok(VoidOutcome::NotFound);
+            };
+            if invoice.status != InvoiceStatus::Open {
+                return Ok(VoidOutcome::NotOpen);
+            }
+            // Money that was taken has to be refunded before the invoice can go
+            let paid = payments::Entity::find()
+                .filter(payments::Column::InvoiceId.eq(id))
+                .filter(payments::Column::Status.eq(PaymentStatus::Succeeded))
+                .count(txn)
+                .await?;
+            if paid > 0 {
+                return Ok(VoidOutcome::HasPayments);
+            }
+            let mut active: invoices::ActiveModel = invoice.into();
+            active.status = ActiveValue::set(InvoiceStatus::Void);
+            Ok(VoidOutcome::Voided(active.update(txn).await?))
+        })
+    })
+    .await;
+    match result {
+        Ok(VoidOutcome::Voided(invoice)) => (StatusCode::OK, Json(invoice)).into_response(),
+        Ok(VoidOutcome::NotFound) => (StatusCode::NOT_FOUND, ()).into_response(),
+        Ok(VoidOutcome::NotOpen) => {
+            (StatusCode::CONFLICT, i18n::t("billing.invoice_not_open")).into_response()
+        }
+        Ok(VoidOutcome::HasPayments) => (
+            StatusCode::CONFLICT,
+            i18n::t("billing.invoice_has_payments"),
+        )
+            .into_response(),
+        Err(e) => internal_error("Error voiding invoice", e),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecordPayment {
+    pub amount_cents: i64,
+    /// Such as a check number. One is made up when left out
+    #[serde(default)]
+    pub reference: Option<String>,
+}
+
+enum RecordOutcome {
+    Recorded(payments::Model),
+    NotFound,
+    NotOpen,
+    Overpayment,
+    DuplicateReference,
+}
+
+/// Records a payment made outside the payment provider, such as by check or cash.
+pub async fn record_payment(
+    AdminUser {
+        user: AuthUser { user_id, .. },
+        ..
+    }: AdminUser,
+    Path(id): Path<i32>,
+    Json(record): Json<RecordPayment>,
+) -> Response {
+    if record.amount_cents <= 0 {
+        return invalid_amount();
+    }
+    let reference = record
+        .reference
+        .map(|reference| format!("{MANUAL_PROVIDER}:{reference}"))
+        .unwrap_or_else(|| format!("{MANUAL_PROVIDER}:{:016x}", thread_rng().gen::<u64>()));
+    let result = transaction_with_retry(get_db(), |txn| {
+        let reference = reference.clone();
+        Box::pin(async move {
+            let Some(invoice) = invoices::Entity::find_by_id(id).one(txn).await? else {
+                return Ok(RecordOutcome::NotFound);
+            };
+            if invoice.status != InvoiceStatus::Open {
+                return Ok(RecordOutcome::NotOpen);
+            }
+            if record.amount_cents > invoice.outstanding_cents() {
+                return Ok(RecordOutcome::Overpayment);
+            }
+            if payments::Entity::find()
+                .filter(payments::Column::Reference.eq(&reference))
+                .one(txn)
+                .await?
+                .is_some()
+            {
+                return Ok(RecordOutcome::DuplicateReference);
+            }
+            let now = chrono::Utc::now().naive_utc();
+            let payment = payments::ActiveModel {
+                id: ActiveValue::not_set(),
+                invoice_id: ActiveValue::set(id),
+                paid_by: ActiveValue::set(user_id),
+                amount_cents: ActiveValue::set(record.amount_cents),
+                provider: ActiveValue::set(MANUAL_PROVIDER.to_string()),
+                reference: ActiveValue::set(reference),
+                status: ActiveValue::set(PaymentStatus::Succeeded),
+                created_at: ActiveValue::set(now),
+                completed_at: ActiveValue::set(Some(now)),
+            }
+            .insert(txn)
+            .await?;
+            apply_payment(txn, invoice, record.amount_cents).await?;
+            Ok(RecordOutcome::Recorded(payment))
+        })
+    })
+    .await;
+    match result {
+        Ok(RecordOutcome::Recorded(payment)) => {
+            (StatusCode::CREATED, Json(payment)).into_response()
+        }
+        Ok(RecordOutcome::NotFound) => (StatusCode::NOT_FOUND, ()).into_response(),
+        Ok(RecordOutcome::NotOpen) => {
+            (StatusCode::CONFLICT, i18n::t("billing.invoice_not_open")).into_response()
+        }
+        Ok(RecordOutcome::Overpayment) => {
+            (StatusCode::BAD_REQUEST, i18n::t("billing.overpayment")).into_response()
+        }
+        Ok(RecordOutcome::DuplicateReference) => {
+            (StatusCode::CONFLICT, i18n::t("billing.duplicate_reference")).into_response()
+        }
+        Err(e) => internal_error("Error recording payment", e),
+    }
+}
+
+pub async fn add_guardian(
+    _: AdminUser,
+    Path((student_id, guardian_id)): Path<(UserID, UserID)>,
+) -> Response {
+    match students::Entity::find_live_by_id(student_id)
+        .one(get_db())
+        .await
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => return unknown_student(),
+        Err(e) => return internal_error("Error reading student", e),
+    }
+    match guardians::Entity::find_by_id((student_id, guardian_id))
+        .one(get_db())
+        .await
+    {
+        Ok(Some(_)) => return (StatusCode::OK, ()).into_response(),
+        Ok(None) => {}
+        Err(e) => return internal_error("Error reading guardians", e),
+    }
+    let result = guardians::ActiveModel {
+        student_id: ActiveValue::set(student_id),
+        guardian_id: ActiveValue::set(guardian_id),
+        created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+    }
+    .insert(get_db())
+    .await;
+    match result {
+        Ok(_) => (StatusCode::OK, ()).into_response(),
+        Err(e) => internal_error("Error adding guardian", e),
+    }
+}
+
+pub async fn remove_guardian(
+    _: AdminUser,
+    Path((student_id, guardian_id)): Path<(UserID, UserID)>,
+) -> Response {
+    match guardians::Entity::delete_by_id((student_id, guardian_id))
+        .exec(get_db())
+        .await
+    {
+        Ok(_) => (StatusCode::OK, ()).into_response(),
+        Err(e) => internal_error("Error removing guardian", e),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccountSummary {
+    pub student_id: UserID,
+    pub name: String,
+    pub balance_cents: i64,
+}
+
+async fn balances(student_ids: Vec<UserID>) -> Result<FxHashMap<UserID, i64>, DbErr> {
+    let mut balances = FxHashMap::default();
+    for invoice in invoices::Entity::find()
+        .filter(invoices::Column::StudentId.is_in(student_ids))
+        .filter(invoices::Column::Status.eq(InvoiceStatus::Open))
+        .all(get_read_db())
+        .await?
+    {
+        *balances.entry(invoice.student_id).or_default() += invoice.outstanding_cents();
+    }
+    Ok(balances)
+}
+
+/// The students whose invoices the user can pay: themselves if they are a student, and the
+/// students they are a guardian of.
+pub async fn accounts(AuthUser { user_id, .. }: AuthUser) -> Response {
+    let result: Result<_, DbErr> = async {
+        let mut student_ids: Vec<UserID> = guardians::Entity::find()
+            .filter(guardians::Column::GuardianId.eq(user_id))
+            .all(get_read_db())
+            .await?
+            .into_iter()
+            .map(|guardian| guardian.student_id)
+            .collect();
+        student_ids.push(user_id);
+        let students = students::Entity::find_live()
+            .filter(students::Column::UserId.is_in(student_ids.clone()))
+            .order_by_asc(students::Column::Name)
+            .all(get_read_db())
+            .await?;
+        let balances = balances(student_ids).await?;
+        Ok(students
+            .into_iter()
+            .map(|student| AccountSummary {
+                balance_cents: balances.get(&student.user_id).copied().unwrap_or_default(),
+                student_id: student.user_id,
+                name: student.name,
+            })
+            .collect::<Vec<_>>())
+    }
+    .await;
+    match result {
+        Ok(accounts) => (StatusCode::OK, Json(accounts)).into_response(),
+        Err(e) => internal_error("Error reading accounts", e),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Account {
+    pub student_id: UserID,
+    pub currency: String,
+    pub balance_cents: i64,
+    pub invoices: Vec<invoices::Model>,
+    pub payments: Vec<payments::Model>,
+}
+
+pub async fn student_account(
+    AuthUser { user_id, .. }: AuthUser,
+    Path(student_id): Path<UserID>,
+) -> Response {
+    // Students that the user cannot see are indistinguishable from ones that do not exist
+    match can_access(user_id, student_id).await {
+        Ok(true) => {}
+        Ok(false) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => return internal_error("Error reading guardians", e),
+    }
+    let result: Result<_, DbErr> = async {
+        let invoices = invoices::Entity::find()
+            .filter(invoices::Column::StudentId.eq(student_id))
+            .order_by_desc(invoices::Column::DueOn)
+            .order_by_desc(invoices::Column::Id)
+            .all(get_read_db())
+            .await?;
+        let payments = payments::Entity::find()
+            .filter(payments::Column::InvoiceId.is_in(invoices.iter().map(|invoice| invoice.id)))
+            .order_by_desc(payments::Column::Id)
+            .all(get_read_db())
+            .await?;
+        Ok(Account {
+            student_id,
+            currency: options().currency.clone(),
+            balance_cents: invoices
+                .iter()
+                .map(invoices::Model::outstanding_cents)
+                .sum(),
+            invoices,
+            payments,
+        })
+    }
+    .await;
+    match result {
+        Ok(account) => (StatusCode::OK, Json(account)).into_response(),
+        Err(e) => internal_error("Error reading account", e),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckoutStarted {
+    pub payment_id: i32,
+    /// Where to send the payer
+    pub url: String,
+}
+
+/// Starts paying what is outstanding on an invoice through the payment provider. The invoice is
+/// only updated once the provider reports the payment to its webhook.
+pub async fn checkout(AuthUser { user_id, .. }: AuthUser, Path(id): Path<i32>) -> Response {
+    let invoice = match invoices::Entity::find_by_id(id).one(get_db()).await {
+        Ok(Some(invoice)) => invoice,
+        Ok(None) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => return internal_error("Error reading invoice", e),
+    };
+    match can_access(user_id, invoice.student_id).await {
+        Ok(true) => {}
+        Ok(false) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => return internal_error("Error reading guardians", e),
+    }
+    if invoice.status != InvoiceStatus::Open {
+        return (StatusCode::CONFLICT, i18n::t("billing.invoice_not_open")).into_response();
+    }
+    let Some((provider_name, provider)) = get_provider() else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            i18n::t("billing.payments_unavailable"),
+        )
+            .into_response();
+    };
+    let options = options();
+    let amount_cents = invoice.outstanding_cents();
+    let checkout = match provider
+        .create_checkout(CheckoutRequest {
+            invoice_id: invoice.id,
+            description: invoice.description,
+            amount_cents,
+            currency: options.currency.clone(),
+            success_url: options.success_url.clone().unwrap_or_default(),
+            cancel_url: options.cancel_url.clone().unwrap_or_default(),
+        })
+        .await
+    {
+        Ok(checkout) => checkout,
+        Err(e) => {
+            error!("Error creating checkout with {provider_name}: {e:#}");
+            return (
+                StatusCode::BAD_GATEWAY,
+                i18n::t("billing.provider_unavailable"),
+            )
+                .into_response();
+        }
+    };
+    let result = payments::ActiveModel {
+        id: ActiveValue::not_set(),
+        invoice_id: ActiveValue::set(invoice.id),
+        paid_by: ActiveValue::set(user_id),
+        amount_cents: ActiveValue::set(amount_cents),
+        provider: ActiveValue::set(provider_name.to_string()),
+        reference: ActiveValue::set(checkout.reference),
+        status: ActiveValue::set(PaymentStatus::Pending),
+        created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+        completed_at: ActiveValue::set(None),
+    }
+    .insert(get_db())
+    .await;
+    match result {
+        Ok(payment) => (
+            StatusCode::CREATED,
+            Json(CheckoutStarted {
+                payment_id: payment.id,
+                url: checkout.url,
+            }),
+        )
+            .into_response(),
+        Err(e) => internal_error("Error recording checkout", e),
+    }
+}
+
+/// Reconciles a payment with what the provider reports. Providers retry webhooks, so events for
+/// payments that are no longer pending are ignored.
+async fn reconcile(
+    reference: String,
+    outcome: PaymentOutcome,
+) -> Result<Option<invoices::Model>, TransactionError<DbErr>> {
+    transaction_with_retry(get_db(), |txn| {
+        let reference = reference.clone();
+        Box::pin(async move {
+            let Some(payment) = payments::Entity::find()
+                .filter(payments::Column::Reference.eq(&reference))
+                .one(txn)
+                .await?
+            else {
+                warn!("Webhook for unknown payment {reference}");
+                return Ok(None);
+            };
+            if payment.status != PaymentStatus::Pending {
+                return Ok(None);
+            }
+            let invoice_id = payment.invoice_id;
+            let mut active: payments::ActiveModel = payment.into();
+            active.completed_at = ActiveValue::set(Some(chrono::Utc::now().naive_utc()));
+            let PaymentOutcome::Succeeded { amount_cents } = outcome else {
+                active.status = ActiveValue::set(PaymentStatus::Failed);
+                active.update(txn).await?;
+                return Ok(None);
+            };
+            active.status = ActiveValue::set(PaymentStatus::Succeeded);
+            active.amount_cents = ActiveValue::set(amount_cents);
+            active.update(txn).await?;
+            let Some(invoice) = invoices::Entity::find_by_id(invoice_id).one(txn).await? else {
+                return Ok(None);
+            };
+            if invoice.status != InvoiceStatus::Open {
+                // Such as when the invoice was voided or paid otherwise while the payer was at the
+                // provider, which leaves a refund to an administrator
+                return Ok(Some(invoice));
+            }
+            apply_payment(txn, invoice, amount_cents).await?;
+            Ok(None)
+        })
+    })
+    .await
+}
+
+pub async fn webhook(
+    Path(provider_name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Some((name, provider)) = get_provider().filter(|(name, _)| *name == provider_name) else {
+        return (StatusCode::NOT_FOUND, ()).into_response();
+    };
+    let event = match provider.parse_webhook(&headers, &body) {
+        Ok(Some(event)) => event,
+        Ok(None) => return (StatusCode::OK, ()).into_response(),
+        Err(e) => {
+            warn!("Refused webhook from {name}: {e:#}");
+            return (StatusCode::BAD_REQUEST, i18n::t("billing.invalid_webhook")).into_response();
+        }
+    };
+    let reference = event.reference.clone();
+    match reconcile(event.reference, event.outcome).await {
+        Ok(None) => {}
+        Ok(Some(invoice)) => {
+            let message = format!(
+                "Payment {reference} succeeded for invoice {}, which is no longer open and may \
+                 need a refund",
+                invoice.id
+            );
+            warn!("{message}");
+            if let Err(e) = admins::notifications::notify_all("warning", &message).await {
+                error!("Error notifying admins: {e:#}");
+            }
+        }
+        Err(e) => return internal_error("Error reconciling payment", e),
+    }
+    (StatusCode::OK, ()).into_response()
+}
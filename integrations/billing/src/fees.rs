@@ -0,0 +1,24 @@
+use sea_orm::entity::prelude::*;
+use serde::Serialize;
+use teach_tech_core::auth::UserID;
+
+/// A fee every student is invoiced for in a term, such as tuition.
+#[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "billing_fee_schedules")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    /// Any label for the term, such as `2026-fall`
+    pub term: String,
+    pub description: String,
+    pub amount_cents: i64,
+    pub due_on: Date,
+    #[serde(skip_serializing)]
+    pub created_by: UserID,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,19 @@
+use sea_orm::entity::prelude::*;
+use serde::Serialize;
+use teach_tech_core::auth::UserID;
+
+/// A user who can see and pay the invoices of a student, such as a parent.
+#[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "billing_guardians")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub student_id: UserID,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub guardian_id: UserID,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,87 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+use teach_tech_core::auth::UserID;
+
+#[derive(EnumIter, DeriveActiveEnum, Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[sea_orm(rs_type = "i32", db_type = "Integer")]
+#[serde(rename_all = "snake_case")]
+pub enum InvoiceStatus {
+    Open = 0,
+    Paid = 1,
+    Void = 2,
+}
+
+#[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "billing_invoices")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub student_id: UserID,
+    /// The fee schedule the invoice was issued from, if any
+    pub fee_schedule_id: Option<i32>,
+    pub term: Option<String>,
+    pub description: String,
+    pub amount_cents: i64,
+    pub paid_cents: i64,
+    pub due_on: Date,
+    pub status: InvoiceStatus,
+    #[serde(skip_serializing)]
+    pub created_by: UserID,
+    pub created_at: DateTime,
+    pub paid_at: Option<DateTime>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    /// What is left to pay, which is nothing once the invoice is paid or void.
+    pub fn outstanding_cents(&self) -> i64 {
+        match self.status {
+            InvoiceStatus::Open => (self.amount_cents - self.paid_cents).max(0),
+            InvoiceStatus::Paid | InvoiceStatus::Void => 0,
+        }
+    }
+}
+
+/// Payments towards invoices, whether through a provider or recorded by an administrator.
+pub mod payments {
+    use super::*;
+
+    #[derive(
+        EnumIter, DeriveActiveEnum, Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize,
+    )]
+    #[sea_orm(rs_type = "i32", db_type = "Integer")]
+    #[serde(rename_all = "snake_case")]
+    pub enum PaymentStatus {
+        /// Waiting for the provider to report the outcome
+        Pending = 0,
+        Succeeded = 1,
+        Failed = 2,
+    }
+
+    #[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+    #[sea_orm(table_name = "billing_payments")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i32,
+        pub invoice_id: i32,
+        pub paid_by: UserID,
+        pub amount_cents: i64,
+        /// The payment provider, or `manual` for payments recorded by an administrator
+        pub provider: String,
+        /// What the provider calls the payment, such as a Stripe Checkout Session ID
+        #[sea_orm(unique)]
+        pub reference: String,
+        pub status: PaymentStatus,
+        pub created_at: DateTime,
+        pub completed_at: Option<DateTime>,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
@@ -0,0 +1,158 @@
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+use teach_tech_core::{
+    anyhow::{self, Context},
+    axum::routing::{delete, get, post, put},
+    i18n, TeachCore,
+};
+
+pub mod api;
+pub mod fees;
+pub mod guardians;
+pub mod invoices;
+pub mod provider;
+pub mod stripe;
+
+static OPTIONS: OnceLock<BillingOptions> = OnceLock::new();
+
+const MESSAGES: &[(&str, &str)] = &[
+    ("billing.invalid_amount", "Amounts must be positive"),
+    (
+        "billing.invalid_description",
+        "Descriptions cannot be empty",
+    ),
+    ("billing.unknown_student", "Unknown student"),
+    (
+        "billing.fee_schedule_invoiced",
+        "Invoices were issued from this fee schedule",
+    ),
+    ("billing.invoice_not_open", "The invoice is not open"),
+    (
+        "billing.invoice_has_payments",
+        "The invoice has payments and cannot be voided",
+    ),
+    ("billing.overpayment", "The payment exceeds what is owed"),
+    (
+        "billing.duplicate_reference",
+        "A payment with this reference was already recorded",
+    ),
+    (
+        "billing.payments_unavailable",
+        "Online payments are not configured",
+    ),
+    (
+        "billing.provider_unavailable",
+        "The payment provider could not be reached",
+    ),
+    ("billing.invalid_webhook", "Invalid webhook"),
+];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BillingConfig {
+    #[serde(default)]
+    pub billing: BillingOptions,
+}
+
+/// The `[billing]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BillingOptions {
+    /// The ISO 4217 code every amount is in
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    /// The payment provider students and guardians pay through, such as `stripe`. Without one,
+    /// only payments recorded by administrators are taken
+    pub provider: Option<String>,
+    /// Where payers are sent after paying
+    pub success_url: Option<String>,
+    /// Where payers are sent if they give up
+    pub cancel_url: Option<String>,
+    pub stripe: Option<stripe::StripeOptions>,
+}
+
+impl Default for BillingOptions {
+    fn default() -> Self {
+        Self {
+            currency: default_currency(),
+            provider: None,
+            success_url: None,
+            cancel_url: None,
+            stripe: None,
+        }
+    }
+}
+
+fn default_currency() -> String {
+    "usd".into()
+}
+
+pub(crate) fn options() -> &'static BillingOptions {
+    OPTIONS.get_or_init(BillingOptions::default)
+}
+
+pub async fn add_to_core<S: Clone + Send + Sync + 'static>(
+    mut core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    let mut info = fxhash::FxHashMap::default();
+    info.insert("version", env!("CARGO_PKG_VERSION"));
+    core.add_info("billing", info);
+    i18n::add_catalog(i18n::FALLBACK_LOCALE, MESSAGES.iter().copied());
+    core.declare_config::<BillingConfig>();
+    let config: BillingConfig = toml::from_str(core.get_config_str())?;
+    core.add_db_reset_config(fees::Entity);
+    core.add_db_reset_config(invoices::Entity);
+    core.add_db_reset_config(invoices::payments::Entity);
+    core.add_db_reset_config(guardians::Entity);
+
+    if let Some(options) = config.billing.stripe.clone() {
+        provider::add_provider("stripe", stripe::StripeProvider::new(options)?);
+    }
+    if config.billing.provider.is_some()
+        && (config.billing.success_url.is_none() || config.billing.cancel_url.is_none())
+    {
+        return Err(anyhow::anyhow!(
+            "billing.success_url and billing.cancel_url are required with billing.provider"
+        ));
+    }
+    let provider = config.billing.provider.clone();
+    let _ = OPTIONS.set(config.billing);
+
+    core = core.modify_router(|router| {
+        router
+            .route(
+                "/billing/fee-schedules",
+                get(api::list_fee_schedules).post(api::create_fee_schedule),
+            )
+            .route(
+                "/billing/fee-schedules/:id",
+                delete(api::delete_fee_schedule),
+            )
+            .route(
+                "/billing/fee-schedules/:id/issue",
+                post(api::issue_fee_schedule),
+            )
+            .route(
+                "/billing/invoices",
+                get(api::list_invoices).post(api::create_invoice),
+            )
+            .route("/billing/invoices/:id/void", post(api::void_invoice))
+            .route("/billing/invoices/:id/payments", post(api::record_payment))
+            .route("/billing/invoices/:id/checkout", post(api::checkout))
+            .route(
+                "/billing/guardians/:student_id/:guardian_id",
+                put(api::add_guardian).delete(api::remove_guardian),
+            )
+            .route("/billing/accounts", get(api::accounts))
+            .route("/billing/students/:student_id", get(api::student_account))
+            .route("/billing/webhooks/:provider", post(api::webhook))
+    });
+
+    // Providers may be added by other integrations after this one, so the choice waits for serving
+    if let Some(name) = provider {
+        core.add_on_serve_named("billing", 0, move || async move {
+            provider::select(&name).with_context(|| format!("Selecting billing.provider {name}"))
+        });
+    }
+
+    Ok(core)
+}
@@ -0,0 +1,84 @@
+use std::sync::{Arc, Mutex, OnceLock};
+
+use futures::future::BoxFuture;
+use teach_tech_core::{anyhow, axum::http::HeaderMap};
+
+static PROVIDERS: Mutex<Vec<(String, Arc<dyn PaymentProvider>)>> = Mutex::new(Vec::new());
+static PROVIDER: OnceLock<(String, Arc<dyn PaymentProvider>)> = OnceLock::new();
+
+/// What a payer is asked to pay.
+#[derive(Debug, Clone)]
+pub struct CheckoutRequest {
+    pub invoice_id: i32,
+    pub description: String,
+    pub amount_cents: i64,
+    pub currency: String,
+    /// Where the payer is sent after paying
+    pub success_url: String,
+    /// Where the payer is sent if they give up
+    pub cancel_url: String,
+}
+
+/// A payment page the payer is sent to.
+#[derive(Debug, Clone)]
+pub struct Checkout {
+    /// Identifies the payment in webhooks
+    pub reference: String,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentOutcome {
+    Succeeded { amount_cents: i64 },
+    Failed,
+}
+
+/// The outcome of a checkout, as reported to the provider's webhook.
+#[derive(Debug, Clone)]
+pub struct WebhookEvent {
+    pub reference: String,
+    pub outcome: PaymentOutcome,
+}
+
+/// Takes payments on behalf of the school, such as by card.
+pub trait PaymentProvider: Send + Sync + 'static {
+    fn create_checkout(
+        &self,
+        request: CheckoutRequest,
+    ) -> BoxFuture<'static, anyhow::Result<Checkout>>;
+
+    /// Checks that a request to `/billing/webhooks/{name}` came from the provider, erring if it
+    /// did not. Events that say nothing about the outcome of a checkout are `None`.
+    fn parse_webhook(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> anyhow::Result<Option<WebhookEvent>>;
+}
+
+/// Makes a payment provider available to `billing.provider` under `name`.
+pub fn add_provider(name: impl Into<String>, provider: impl PaymentProvider) {
+    PROVIDERS
+        .lock()
+        .unwrap()
+        .push((name.into(), Arc::new(provider)));
+}
+
+pub(crate) fn select(name: &str) -> anyhow::Result<()> {
+    let provider = PROVIDERS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(provider_name, _)| provider_name == name)
+        .map(|(_, provider)| provider.clone())
+        .ok_or_else(|| anyhow::anyhow!("billing.provider {name} was not added"))?;
+    let _ = PROVIDER.set((name.to_string(), provider));
+    Ok(())
+}
+
+/// The provider in use and its name, if payments are configured.
+pub fn get_provider() -> Option<(&'static str, Arc<dyn PaymentProvider>)> {
+    PROVIDER
+        .get()
+        .map(|(name, provider)| (name.as_str(), provider.clone()))
+}
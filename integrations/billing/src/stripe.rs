@@ -0,0 +1,221 @@
+use std::{sync::Arc, time::Duration};
+
+use futures::future::BoxFuture;
+use ring::hmac;
+use serde::Deserialize;
+use teach_tech_core::{
+    anyhow::{self, Context},
+    axum::http::HeaderMap,
+    http::HttpClient,
+    serde_json, tokio,
+};
+use url::Url;
+use zeroize::Zeroizing;
+
+use crate::provider::{Checkout, CheckoutRequest, PaymentOutcome, PaymentProvider, WebhookEvent};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The `[billing.stripe]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StripeOptions {
+    pub secret_key: Zeroizing<String>,
+    /// The signing secret of the webhook endpoint, which starts with `whsec_`
+    pub webhook_secret: Zeroizing<String>,
+    #[serde(default = "default_api_url")]
+    pub api_url: String,
+    /// How old a webhook request can be before it is refused as a replay
+    #[serde(default = "default_webhook_tolerance_secs")]
+    pub webhook_tolerance_secs: u64,
+}
+
+fn default_api_url() -> String {
+    "https://api.stripe.com".into()
+}
+
+fn default_webhook_tolerance_secs() -> u64 {
+    300
+}
+
+#[derive(Deserialize)]
+struct Session {
+    id: String,
+    url: Option<String>,
+    payment_status: Option<String>,
+    amount_total: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct EventData {
+    object: Session,
+}
+
+#[derive(Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    kind: String,
+    data: EventData,
+}
+
+struct Inner {
+    options: StripeOptions,
+    api_url: Url,
+    webhook_key: hmac::Key,
+    client: HttpClient,
+}
+
+/// Takes card payments through Stripe Checkout.
+pub struct StripeProvider(Arc<Inner>);
+
+impl StripeProvider {
+    pub fn new(options: StripeOptions) -> anyhow::Result<Self> {
+        let api_url: Url = options
+            .api_url
+            .parse()
+            .with_context(|| format!("billing.stripe.api_url {} is invalid", options.api_url))?;
+        if !matches!(api_url.scheme(), "https" | "http") {
+            return Err(anyhow::anyhow!(
+                "billing.stripe.api_url must be an http or https url"
+            ));
+        }
+        Ok(Self(Arc::new(Inner {
+            webhook_key: hmac::Key::new(hmac::HMAC_SHA256, options.webhook_secret.as_bytes()),
+            options,
+            api_url,
+            client: HttpClient::new(TIMEOUT)?,
+        })))
+    }
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl Inner {
+    fn create_checkout(&self, request: &CheckoutRequest) -> anyhow::Result<Checkout> {
+        let form = serde_urlencoded::to_string([
+            ("mode", "payment"),
+            ("client_reference_id", &request.invoice_id.to_string()),
+            ("metadata[invoice_id]", &request.invoice_id.to_string()),
+            ("line_items[0][quantity]", "1"),
+            (
+                "line_items[0][price_data][currency]",
+                &request.currency.to_lowercase(),
+            ),
+            (
+                "line_items[0][price_data][unit_amount]",
+                &request.amount_cents.to_string(),
+            ),
+            (
+                "line_items[0][price_data][product_data][name]",
+                &request.description,
+            ),
+            ("success_url", &request.success_url),
+            ("cancel_url", &request.cancel_url),
+        ])?;
+        let url = self.api_url.join("v1/checkout/sessions")?;
+        let authorization = Zeroizing::new(format!("Bearer {}", &*self.options.secret_key));
+        let response = self.client.send(
+            "POST",
+            url.as_str(),
+            &[("Authorization", &authorization)],
+            Some(("application/x-www-form-urlencoded", form.as_bytes())),
+        )?;
+        if response.status != 200 {
+            return Err(anyhow::anyhow!(
+                "Stripe refused a checkout session with {}: {}",
+                response.status,
+                response.text()
+            ));
+        }
+        let session: Session =
+            serde_json::from_slice(&response.body).context("Parsing the checkout session")?;
+        Ok(Checkout {
+            url: session
+                .url
+                .context("Stripe returned a checkout session without a url")?,
+            reference: session.id,
+        })
+    }
+
+    /// Checks the `Stripe-Signature` header, which signs `{timestamp}.{body}`.
+    fn verify(&self, signature: &str, body: &[u8]) -> anyhow::Result<()> {
+        let mut timestamp = None;
+        let mut signatures = vec![];
+        for part in signature.split(',') {
+            match part.trim().split_once('=') {
+                Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+                Some(("v1", value)) => signatures.extend(unhex(value)),
+                _ => {}
+            }
+        }
+        let timestamp = timestamp.context("Stripe-Signature has no timestamp")?;
+        let age = chrono::Utc::now().timestamp() - timestamp;
+        if age.unsigned_abs() > self.options.webhook_tolerance_secs {
+            return Err(anyhow::anyhow!(
+                "Stripe-Signature is {age} seconds old, which is outside the tolerance"
+            ));
+        }
+        let mut payload = format!("{timestamp}.").into_bytes();
+        payload.extend_from_slice(body);
+        if signatures
+            .iter()
+            .any(|signature| hmac::verify(&self.webhook_key, &payload, signature).is_ok())
+        {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Stripe-Signature does not match"))
+        }
+    }
+}
+
+impl PaymentProvider for StripeProvider {
+    fn create_checkout(
+        &self,
+        request: CheckoutRequest,
+    ) -> BoxFuture<'static, anyhow::Result<Checkout>> {
+        let inner = self.0.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || inner.create_checkout(&request)).await?
+        })
+    }
+
+    fn parse_webhook(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> anyhow::Result<Option<WebhookEvent>> {
+        let signature = headers
+            .get("stripe-signature")
+            .and_then(|value| value.to_str().ok())
+            .context("The request has no Stripe-Signature")?;
+        self.0.verify(signature, body)?;
+        let event: Event = serde_json::from_slice(body).context("Parsing the event")?;
+        let session = event.data.object;
+        let outcome = match event.kind.as_str() {
+            // Bank debits and the like complete later, with async_payment_succeeded
+            "checkout.session.completed" if session.payment_status.as_deref() == Some("paid") => {
+                PaymentOutcome::Succeeded {
+                    amount_cents: session.amount_total.unwrap_or_default(),
+                }
+            }
+            "checkout.session.async_payment_succeeded" => PaymentOutcome::Succeeded {
+                amount_cents: session.amount_total.unwrap_or_default(),
+            },
+            "checkout.session.async_payment_failed" | "checkout.session.expired" => {
+                PaymentOutcome::Failed
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(WebhookEvent {
+            reference: session.id,
+            outcome,
+        }))
+    }
+}
@@ -0,0 +1,132 @@
+use std::io::{Cursor, Read};
+
+use fxhash::FxHashMap;
+use teach_tech_core::anyhow::{self, Context};
+
+/// Only these files are read from an export, as media and attachments are not imported
+const EXTENSIONS: &[&str] = &[".xml", ".html", ".htm"];
+
+/// Limits on what an export may unpack to, so that a small upload cannot fill memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_files: usize,
+    pub max_unpacked_bytes: u64,
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    /// Neither a zip nor a gzipped tar
+    Unrecognized,
+    TooLarge,
+    Invalid(anyhow::Error),
+}
+
+impl From<std::io::Error> for ArchiveError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Invalid(e.into())
+    }
+}
+
+impl From<zip::result::ZipError> for ArchiveError {
+    fn from(e: zip::result::ZipError) -> Self {
+        Self::Invalid(e.into())
+    }
+}
+
+/// The text files of an export, by their path inside it.
+pub struct Archive {
+    files: FxHashMap<String, String>,
+}
+
+fn wanted(path: &str) -> bool {
+    let lower = path.to_ascii_lowercase();
+    EXTENSIONS
+        .iter()
+        .any(|extension| lower.ends_with(extension))
+}
+
+fn normalize(path: &str) -> String {
+    path.trim_start_matches("./")
+        .trim_start_matches('/')
+        .to_string()
+}
+
+struct Unpacker {
+    limits: Limits,
+    unpacked: u64,
+    files: FxHashMap<String, String>,
+}
+
+impl Unpacker {
+    fn add(&mut self, path: &str, size: u64, reader: impl Read) -> Result<(), ArchiveError> {
+        if self.files.len() >= self.limits.max_files {
+            return Err(ArchiveError::TooLarge);
+        }
+        let remaining = self.limits.max_unpacked_bytes.saturating_sub(self.unpacked);
+        if size > remaining {
+            return Err(ArchiveError::TooLarge);
+        }
+        // The declared size can lie, so reading stops at the limit either way
+        let mut bytes = vec![];
+        reader.take(remaining + 1).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 > remaining {
+            return Err(ArchiveError::TooLarge);
+        }
+        self.unpacked += bytes.len() as u64;
+        let text = String::from_utf8_lossy(&bytes).into_owned();
+        self.files.insert(normalize(path), text);
+        Ok(())
+    }
+}
+
+impl Archive {
+    /// Unpacks a zip, such as a Common Cartridge, or a gzipped tar, such as a Moodle backup.
+    pub fn unpack(bytes: &[u8], limits: Limits) -> Result<Self, ArchiveError> {
+        let mut unpacker = Unpacker {
+            limits,
+            unpacked: 0,
+            files: FxHashMap::default(),
+        };
+        if bytes.starts_with(b"PK\x03\x04") {
+            let mut zip = zip::ZipArchive::new(Cursor::new(bytes))?;
+            for i in 0..zip.len() {
+                let file = zip.by_index(i)?;
+                if !file.is_file() || !wanted(file.name()) {
+                    continue;
+                }
+                let name = file.name().to_string();
+                let size = file.size();
+                unpacker.add(&name, size, file)?;
+            }
+        } else if bytes.starts_with(&[0x1f, 0x8b]) {
+            let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(bytes));
+            for entry in tar.entries()? {
+                let entry = entry?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let path = entry.path()?.to_string_lossy().into_owned();
+                if !wanted(&path) {
+                    continue;
+                }
+                let size = entry.size();
+                unpacker.add(&path, size, entry)?;
+            }
+        } else {
+            return Err(ArchiveError::Unrecognized);
+        }
+        Ok(Self {
+            files: unpacker.files,
+        })
+    }
+
+    pub fn get(&self, path: &str) -> Option<&str> {
+        self.files.get(&normalize(path)).map(String::as_str)
+    }
+
+    /// Like [`Self::get`], but a missing file is an error.
+    pub fn require(&self, path: &str) -> anyhow::Result<&str> {
+        self.get(path)
+            .with_context(|| format!("The export has no {path}"))
+    }
+}
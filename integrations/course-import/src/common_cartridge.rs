@@ -0,0 +1,367 @@
+//! IMS Common Cartridge 1.x, including the extensions Canvas adds to its course exports.
+
+use fxhash::{FxHashMap, FxHashSet};
+use roxmltree::Node;
+use teach_tech_core::anyhow::{self, Context};
+
+use crate::{
+    archive::Archive,
+    parse_due_at,
+    plan::{
+        AssignmentPlan, ChoicePlan, CoursePlan, ModulePlan, Parsed, QuestionKind, QuestionPlan,
+        QuizPlan, Skipped, SourceFormat,
+    },
+    xml::{self, child, child_text, children, descendants, find_text},
+};
+
+pub const MANIFEST: &str = "imsmanifest.xml";
+
+struct Resource {
+    kind: String,
+    href: Option<String>,
+    files: Vec<String>,
+    dependencies: Vec<String>,
+}
+
+impl Resource {
+    /// The file the resource is about, which some exporters only list among its files.
+    fn main_file(&self, suffix: &str) -> Option<&str> {
+        self.href
+            .as_deref()
+            .filter(|href| href.ends_with(suffix))
+            .or_else(|| {
+                self.files
+                    .iter()
+                    .map(String::as_str)
+                    .find(|file| file.ends_with(suffix))
+            })
+    }
+}
+
+struct Reader<'a> {
+    archive: &'a Archive,
+    resources: FxHashMap<String, Resource>,
+    skipped: Vec<Skipped>,
+}
+
+/// The content of the `<body>` of an HTML page, or all of it without one.
+fn html_body(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let start = lower
+        .find("<body")
+        .and_then(|start| lower[start..].find('>').map(|end| start + end + 1));
+    let end = lower.rfind("</body>");
+    match (start, end) {
+        (Some(start), Some(end)) if start <= end => html[start..end].trim().to_string(),
+        _ => html.trim().to_string(),
+    }
+}
+
+fn question_kind(name: &str) -> Option<QuestionKind> {
+    Some(match name {
+        "multiple_choice_question" | "cc.multiple_choice.v0p1" => QuestionKind::MultipleChoice,
+        "multiple_answers_question" | "cc.multiple_response.v0p1" => QuestionKind::MultipleAnswer,
+        "true_false_question" | "cc.true_false.v0p1" => QuestionKind::TrueFalse,
+        "short_answer_question" | "cc.fib.v0p1" | "cc.pattern_match.v0p1" => {
+            QuestionKind::ShortAnswer
+        }
+        "numerical_question" => QuestionKind::Numerical,
+        "essay_question" | "cc.essay.v0p1" => QuestionKind::Essay,
+        _ => return None,
+    })
+}
+
+/// The values a response must equal to earn points, skipping those under `<not>`.
+fn correct_values(item: Node) -> FxHashSet<String> {
+    let mut values = FxHashSet::default();
+    for condition in descendants(item, "respcondition") {
+        let scores = children(condition, "setvar")
+            .filter_map(xml::text)
+            .any(|value| value.parse::<f64>().is_ok_and(|value| value > 0.0));
+        if !scores {
+            continue;
+        }
+        for value in descendants(condition, "varequal") {
+            let negated = value
+                .ancestors()
+                .take_while(|ancestor| *ancestor != condition)
+                .any(|ancestor| ancestor.tag_name().name() == "not");
+            if !negated {
+                values.extend(xml::text(value));
+            }
+        }
+    }
+    values
+}
+
+impl Reader<'_> {
+    /// Reads a QTI 1.2 assessment, returning its title and questions.
+    fn parse_qti(&mut self, path: &str) -> anyhow::Result<(String, Vec<QuestionPlan>)> {
+        let doc = xml::parse(path, self.archive.require(path)?)?;
+        let assessment = descendants(doc.root_element(), "assessment")
+            .next()
+            .with_context(|| format!("{path} has no assessment"))?;
+        let title = assessment
+            .attribute("title")
+            .unwrap_or_default()
+            .to_string();
+        let mut questions = vec![];
+        for item in descendants(assessment, "item") {
+            let fields: FxHashMap<String, String> = descendants(item, "qtimetadatafield")
+                .filter_map(|field| {
+                    Some((
+                        child_text(field, "fieldlabel")?,
+                        child_text(field, "fieldentry")?,
+                    ))
+                })
+                .collect();
+            let kind_name = fields
+                .get("question_type")
+                .or_else(|| fields.get("cc_profile"))
+                .cloned()
+                .unwrap_or_default();
+            let item_title = item.attribute("title").unwrap_or_default().to_string();
+            let Some(kind) = question_kind(&kind_name) else {
+                self.skipped.push(Skipped {
+                    title: item_title,
+                    kind: format!("question:{kind_name}"),
+                });
+                continue;
+            };
+            let prompt = xml::find(item, &["presentation", "material"])
+                .and_then(|material| child_text(material, "mattext"))
+                .unwrap_or_default();
+            let correct = correct_values(item);
+            let mut choices: Vec<_> = descendants(item, "response_label")
+                .map(|label| ChoicePlan {
+                    text: descendants(label, "mattext")
+                        .next()
+                        .and_then(xml::text)
+                        .unwrap_or_default(),
+                    correct: label
+                        .attribute("ident")
+                        .is_some_and(|ident| correct.contains(ident)),
+                })
+                .collect();
+            // Free-text questions list their accepted answers in the scoring instead
+            if choices.is_empty() && kind != QuestionKind::Essay {
+                let mut accepted: Vec<_> = correct.into_iter().collect();
+                accepted.sort();
+                choices = accepted
+                    .into_iter()
+                    .map(|text| ChoicePlan {
+                        text,
+                        correct: true,
+                    })
+                    .collect();
+            }
+            questions.push(QuestionPlan {
+                kind,
+                prompt,
+                points: fields
+                    .get("points_possible")
+                    .and_then(|points| points.parse().ok()),
+                choices,
+            });
+        }
+        Ok((title, questions))
+    }
+
+    fn quiz(
+        &mut self,
+        title: &str,
+        qti_path: &str,
+        meta_path: Option<&str>,
+    ) -> anyhow::Result<QuizPlan> {
+        let (qti_title, questions) = self.parse_qti(qti_path)?;
+        let mut instructions = String::new();
+        if let Some(meta_path) = meta_path {
+            let doc = xml::parse(meta_path, self.archive.require(meta_path)?)?;
+            instructions = child_text(doc.root_element(), "description").unwrap_or_default();
+        }
+        Ok(QuizPlan {
+            title: if title.is_empty() {
+                qti_title
+            } else {
+                title.to_string()
+            },
+            instructions,
+            questions,
+        })
+    }
+
+    /// A Canvas assignment, whose settings sit beside the page holding its instructions.
+    fn canvas_assignment(
+        &self,
+        title: &str,
+        settings_path: &str,
+        resource: &Resource,
+    ) -> anyhow::Result<AssignmentPlan> {
+        let doc = xml::parse(settings_path, self.archive.require(settings_path)?)?;
+        let settings = doc.root_element();
+        let instructions = resource
+            .main_file(".html")
+            .and_then(|path| self.archive.get(path))
+            .map(html_body)
+            .unwrap_or_default();
+        Ok(AssignmentPlan {
+            title: child_text(settings, "title").unwrap_or_else(|| title.to_string()),
+            instructions,
+            due_at: child_text(settings, "due_at").and_then(|due_at| parse_due_at(&due_at)),
+            max_points: child_text(settings, "points_possible")
+                .and_then(|points| points.parse().ok()),
+        })
+    }
+
+    /// An assignment in the Common Cartridge 1.3 format.
+    fn cc_assignment(&self, title: &str, path: &str) -> anyhow::Result<AssignmentPlan> {
+        let doc = xml::parse(path, self.archive.require(path)?)?;
+        let assignment = doc.root_element();
+        Ok(AssignmentPlan {
+            title: child_text(assignment, "title").unwrap_or_else(|| title.to_string()),
+            instructions: child_text(assignment, "text").unwrap_or_default(),
+            due_at: None,
+            max_points: child(assignment, "gradable")
+                .and_then(|gradable| gradable.attribute("points_possible"))
+                .and_then(|points| points.parse().ok()),
+        })
+    }
+
+    /// Adds what the module item refers to, or notes that it was skipped.
+    fn add_item(&mut self, module: &mut ModulePlan, item: Node) -> anyhow::Result<()> {
+        let Some(resource_id) = item.attribute("identifierref") else {
+            return Ok(());
+        };
+        let title = child_text(item, "title").unwrap_or_default();
+        let Some(resource) = self.resources.remove(resource_id) else {
+            self.skipped.push(Skipped {
+                title,
+                kind: "missing".into(),
+            });
+            return Ok(());
+        };
+        if resource.kind.starts_with("imsqti_xmlv1p2") {
+            let path = resource
+                .main_file(".xml")
+                .with_context(|| format!("Assessment {resource_id} has no file"))?
+                .to_string();
+            module.quizzes.push(self.quiz(&title, &path, None)?);
+        } else if resource.kind.starts_with("assignment_xmlv1p0") {
+            let path = resource
+                .main_file(".xml")
+                .with_context(|| format!("Assignment {resource_id} has no file"))?
+                .to_string();
+            module.assignments.push(self.cc_assignment(&title, &path)?);
+        } else if let Some(settings) = resource.main_file("assignment_settings.xml") {
+            let settings = settings.to_string();
+            module
+                .assignments
+                .push(self.canvas_assignment(&title, &settings, &resource)?);
+        } else if let Some(meta) = resource.main_file("assessment_meta.xml") {
+            // Canvas links quizzes to their settings, which the QTI of the quiz depends on
+            let meta = meta.to_string();
+            let qti = self
+                .resources
+                .values()
+                .find(|qti| {
+                    qti.kind.starts_with("imsqti_xmlv1p2")
+                        && qti.dependencies.iter().any(|id| id == resource_id)
+                })
+                .and_then(|qti| qti.main_file(".xml"))
+                .map(str::to_string)
+                .or_else(|| {
+                    let qti = meta.replace("assessment_meta.xml", "assessment_qti.xml");
+                    self.archive.get(&qti).is_some().then_some(qti)
+                });
+            match qti {
+                Some(qti) => module.quizzes.push(self.quiz(&title, &qti, Some(&meta))?),
+                None => self.skipped.push(Skipped {
+                    title,
+                    kind: resource.kind,
+                }),
+            }
+        } else {
+            self.skipped.push(Skipped {
+                title,
+                kind: resource.kind,
+            });
+        }
+        Ok(())
+    }
+}
+
+pub fn parse(archive: &Archive) -> anyhow::Result<Parsed> {
+    let doc = xml::parse(MANIFEST, archive.require(MANIFEST)?)?;
+    let manifest = doc.root_element();
+    let title = find_text(manifest, &["metadata", "lom", "general", "title", "string"])
+        .unwrap_or_else(|| "Untitled course".into());
+
+    let mut resources = FxHashMap::default();
+    for resource in descendants(manifest, "resource") {
+        let Some(id) = resource.attribute("identifier") else {
+            continue;
+        };
+        resources.insert(
+            id.to_string(),
+            Resource {
+                kind: resource.attribute("type").unwrap_or_default().to_string(),
+                href: resource.attribute("href").map(xml::percent_decode),
+                files: children(resource, "file")
+                    .filter_map(|file| file.attribute("href"))
+                    .map(xml::percent_decode)
+                    .collect(),
+                dependencies: children(resource, "dependency")
+                    .filter_map(|dependency| dependency.attribute("identifierref"))
+                    .map(str::to_string)
+                    .collect(),
+            },
+        );
+    }
+    let mut reader = Reader {
+        archive,
+        resources,
+        skipped: vec![],
+    };
+
+    let mut modules = vec![];
+    // Items outside of any module, which Moodle would put in its first section
+    let mut loose = ModulePlan {
+        title: "General".into(),
+        assignments: vec![],
+        quizzes: vec![],
+    };
+    // The root item of the organization only holds the modules
+    let top_items = descendants(manifest, "organization")
+        .next()
+        .and_then(|organization| child(organization, "item"))
+        .map(|root| children(root, "item").collect::<Vec<_>>())
+        .unwrap_or_default();
+    for item in top_items {
+        if child(item, "item").is_none() {
+            reader.add_item(&mut loose, item)?;
+            continue;
+        }
+        let mut module = ModulePlan {
+            title: child_text(item, "title").unwrap_or_default(),
+            assignments: vec![],
+            quizzes: vec![],
+        };
+        for nested in descendants(item, "item").skip(1) {
+            reader.add_item(&mut module, nested)?;
+        }
+        modules.push(module);
+    }
+    if !loose.assignments.is_empty() || !loose.quizzes.is_empty() {
+        modules.insert(0, loose);
+    }
+
+    Ok(Parsed {
+        course: CoursePlan {
+            format: SourceFormat::CommonCartridge,
+            title,
+            code: None,
+            modules,
+        },
+        skipped: reader.skipped,
+    })
+}
@@ -0,0 +1,280 @@
+use std::sync::OnceLock;
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use teach_tech_core::{
+    anyhow::{self, Context},
+    auth::{AuthUser, UserID},
+    axum::{
+        body::Bytes,
+        extract::{DefaultBodyLimit, Query},
+        http::StatusCode,
+        response::{IntoResponse, Response},
+        routing::post,
+        Json,
+    },
+    db::{get_read_db, SoftDeletable},
+    i18n, tokio,
+    users::{admins, instructors},
+    TeachCore,
+};
+use tracing::{error, info};
+
+pub mod archive;
+pub mod common_cartridge;
+pub mod moodle;
+pub mod plan;
+pub mod target;
+mod xml;
+
+use archive::{Archive, ArchiveError, Limits};
+use plan::{Counts, CoursePlan, Parsed, Skipped};
+use target::ImportedCourse;
+
+static OPTIONS: OnceLock<CourseImportOptions> = OnceLock::new();
+
+const MESSAGES: &[(&str, &str)] = &[
+    (
+        "course_import.must_be_staff",
+        "Only instructors and administrators can import courses",
+    ),
+    (
+        "course_import.unrecognized",
+        "Expected a Common Cartridge or a Moodle backup",
+    ),
+    (
+        "course_import.too_large",
+        "The export unpacks to more than the allowed size",
+    ),
+    (
+        "course_import.invalid",
+        "The export could not be read: {reason}",
+    ),
+    (
+        "course_import.no_target",
+        "Nothing is configured to create imported courses. Only dry runs are available",
+    ),
+];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CourseImportConfig {
+    #[serde(default)]
+    pub course_import: CourseImportOptions,
+}
+
+/// The `[course_import]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CourseImportOptions {
+    /// What creates imported courses, from those added with [`target::add_target`]. Without one,
+    /// imports can only be dry runs
+    pub target: Option<String>,
+    #[serde(default = "default_max_upload_mb")]
+    pub max_upload_mb: usize,
+    /// How much of an export is read, not counting media and attachments, which are skipped
+    #[serde(default = "default_max_unpacked_mb")]
+    pub max_unpacked_mb: u64,
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+impl Default for CourseImportOptions {
+    fn default() -> Self {
+        Self {
+            target: None,
+            max_upload_mb: default_max_upload_mb(),
+            max_unpacked_mb: default_max_unpacked_mb(),
+            max_files: default_max_files(),
+        }
+    }
+}
+
+fn default_max_upload_mb() -> usize {
+    512
+}
+
+fn default_max_unpacked_mb() -> u64 {
+    256
+}
+
+fn default_max_files() -> usize {
+    20_000
+}
+
+/// Reads the due dates of exports, which are either RFC 3339 or naive UTC.
+pub(crate) fn parse_due_at(due_at: &str) -> Option<NaiveDateTime> {
+    chrono::DateTime::parse_from_rfc3339(due_at)
+        .map(|due_at| due_at.naive_utc())
+        .or_else(|_| NaiveDateTime::parse_from_str(due_at, "%Y-%m-%dT%H:%M:%S"))
+        .or_else(|_| NaiveDateTime::parse_from_str(due_at, "%Y-%m-%d %H:%M:%S"))
+        .ok()
+}
+
+/// Reads a Common Cartridge or Moodle backup, telling them apart by their manifests.
+pub fn read_export(bytes: &[u8], limits: Limits) -> Result<Parsed, ArchiveError> {
+    let archive = Archive::unpack(bytes, limits)?;
+    if archive.get(common_cartridge::MANIFEST).is_some() {
+        common_cartridge::parse(&archive).map_err(ArchiveError::Invalid)
+    } else if archive.get(moodle::MANIFEST).is_some() {
+        moodle::parse(&archive).map_err(ArchiveError::Invalid)
+    } else {
+        Err(ArchiveError::Unrecognized)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// What an import created, or would create in a dry run.
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub counts: Counts,
+    pub course: CoursePlan,
+    pub skipped: Vec<Skipped>,
+    pub imported: Option<ImportedCourse>,
+}
+
+async fn authorize_staff(user_id: UserID) -> Result<(), Response> {
+    let result = async {
+        if admins::Entity::find_live_by_id(user_id)
+            .one(get_read_db())
+            .await?
+            .is_some()
+        {
+            return Ok(true);
+        }
+        instructors::Entity::find_live_by_id(user_id)
+            .one(get_read_db())
+            .await
+            .map(|instructor| instructor.is_some())
+    }
+    .await;
+    match result {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((
+            StatusCode::FORBIDDEN,
+            i18n::t("course_import.must_be_staff"),
+        )
+            .into_response()),
+        Err(e) => {
+            error!("Error reading user roles: {e:#}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, ()).into_response())
+        }
+    }
+}
+
+/// Imports a course export sent as the body. With `?dry_run=true` nothing is created, and the
+/// report shows what would be.
+async fn import(
+    AuthUser { user_id, .. }: AuthUser,
+    Query(query): Query<ImportQuery>,
+    body: Bytes,
+) -> Response {
+    if let Err(response) = authorize_staff(user_id).await {
+        return response;
+    }
+    let target = target::get_target();
+    if target.is_none() && !query.dry_run {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            i18n::t("course_import.no_target"),
+        )
+            .into_response();
+    }
+
+    let options = options();
+    let limits = Limits {
+        max_files: options.max_files,
+        max_unpacked_bytes: options.max_unpacked_mb * 1024 * 1024,
+    };
+    let parsed = match tokio::task::spawn_blocking(move || read_export(&body, limits)).await {
+        Ok(Ok(parsed)) => parsed,
+        Ok(Err(ArchiveError::Unrecognized)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                i18n::t("course_import.unrecognized"),
+            )
+                .into_response();
+        }
+        Ok(Err(ArchiveError::TooLarge)) => {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                i18n::t("course_import.too_large"),
+            )
+                .into_response();
+        }
+        Ok(Err(ArchiveError::Invalid(e))) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                i18n::t_with("course_import.invalid", &[("reason", &format!("{e:#}"))]),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            error!("Error reading course export: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+
+    let mut report = ImportReport {
+        dry_run: query.dry_run,
+        counts: parsed.course.counts(),
+        course: parsed.course,
+        skipped: parsed.skipped,
+        imported: None,
+    };
+    let Some(target) = target.filter(|_| !query.dry_run) else {
+        return (StatusCode::OK, Json(report)).into_response();
+    };
+    match target.import(report.course.clone(), user_id).await {
+        Ok(imported) => {
+            info!(
+                "{user_id} imported {:?} as course {}",
+                report.course.title, imported.course_id
+            );
+            report.imported = Some(imported);
+            (StatusCode::CREATED, Json(report)).into_response()
+        }
+        Err(e) => {
+            error!("Error importing course: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+fn options() -> &'static CourseImportOptions {
+    OPTIONS.get_or_init(CourseImportOptions::default)
+}
+
+pub async fn add_to_core<S: Clone + Send + Sync + 'static>(
+    mut core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    let mut info = fxhash::FxHashMap::default();
+    info.insert("version", env!("CARGO_PKG_VERSION"));
+    core.add_info("course-import", info);
+    i18n::add_catalog(i18n::FALLBACK_LOCALE, MESSAGES.iter().copied());
+    core.declare_config::<CourseImportConfig>();
+    let config: CourseImportConfig = toml::from_str(core.get_config_str())?;
+    let max_upload_bytes = config.course_import.max_upload_mb * 1024 * 1024;
+    let target = config.course_import.target.clone();
+    let _ = OPTIONS.set(config.course_import);
+
+    core = core.modify_router(|router| {
+        router.route(
+            "/course-import",
+            post(import).layer(DefaultBodyLimit::max(max_upload_bytes)),
+        )
+    });
+
+    // Targets come from other integrations, which may be added after this one
+    if let Some(name) = target {
+        core.add_on_serve_named("course-import", 0, move || async move {
+            target::select(&name).with_context(|| format!("Selecting course_import.target {name}"))
+        });
+    }
+
+    Ok(core)
+}
@@ -0,0 +1,234 @@
+//! Moodle course backups (`.mbz`), from Moodle 2.6 onwards.
+
+use fxhash::FxHashMap;
+use roxmltree::{Document, Node};
+use teach_tech_core::anyhow::{self, Context};
+
+use crate::{
+    archive::Archive,
+    plan::{
+        AssignmentPlan, ChoicePlan, CoursePlan, ModulePlan, Parsed, QuestionKind, QuestionPlan,
+        QuizPlan, Skipped, SourceFormat,
+    },
+    xml::{self, child, child_text, children, descendants, find, find_text},
+};
+
+pub const MANIFEST: &str = "moodle_backup.xml";
+const QUESTIONS: &str = "questions.xml";
+
+fn number<T: std::str::FromStr>(node: Node, name: &'static str) -> Option<T> {
+    child_text(node, name).and_then(|value| value.parse().ok())
+}
+
+/// The question bank of the backup.
+struct QuestionBank<'a, 'i> {
+    questions: FxHashMap<String, Node<'a, 'i>>,
+    /// The newest version of each question bank entry, from Moodle 4.0 on
+    entries: FxHashMap<String, Node<'a, 'i>>,
+}
+
+impl<'a, 'i> QuestionBank<'a, 'i> {
+    fn new(doc: Option<&'a Document<'i>>) -> Self {
+        let mut bank = Self {
+            questions: FxHashMap::default(),
+            entries: FxHashMap::default(),
+        };
+        let Some(doc) = doc else {
+            return bank;
+        };
+        for question in descendants(doc.root_element(), "question") {
+            if let Some(id) = question.attribute("id") {
+                bank.questions.insert(id.to_string(), question);
+            }
+        }
+        for entry in descendants(doc.root_element(), "question_bank_entry") {
+            let newest = descendants(entry, "question_versions")
+                .max_by_key(|version| number::<i64>(*version, "version").unwrap_or_default())
+                .and_then(|version| descendants(version, "question").next());
+            if let (Some(id), Some(question)) = (entry.attribute("id"), newest) {
+                bank.entries.insert(id.to_string(), question);
+            }
+        }
+        bank
+    }
+
+    /// The question a quiz slot holds, from the question ID before Moodle 4.0 and the question
+    /// bank entry since.
+    fn question_of(&self, instance: Node) -> Option<Node<'a, 'i>> {
+        if let Some(id) = child_text(instance, "questionid") {
+            return self.questions.get(&id).copied();
+        }
+        let entry = find_text(instance, &["question_reference", "questionbankentryid"])?;
+        self.entries.get(&entry).copied()
+    }
+}
+
+fn question(question: Node, points: Option<f64>) -> Result<QuestionPlan, String> {
+    let qtype = child_text(question, "qtype").unwrap_or_default();
+    let kind = match qtype.as_str() {
+        "multichoice" => {
+            let single = descendants(question, "single")
+                .next()
+                .and_then(xml::text)
+                .is_none_or(|single| single == "1");
+            if single {
+                QuestionKind::MultipleChoice
+            } else {
+                QuestionKind::MultipleAnswer
+            }
+        }
+        "truefalse" => QuestionKind::TrueFalse,
+        "shortanswer" => QuestionKind::ShortAnswer,
+        "numerical" => QuestionKind::Numerical,
+        "essay" => QuestionKind::Essay,
+        _ => return Err(qtype),
+    };
+    let choices = descendants(question, "answer")
+        .filter(|answer| child(*answer, "answertext").is_some())
+        .map(|answer| ChoicePlan {
+            text: child_text(answer, "answertext").unwrap_or_default(),
+            correct: number::<f64>(answer, "fraction").is_some_and(|fraction| fraction > 0.0),
+        })
+        .collect();
+    Ok(QuestionPlan {
+        kind,
+        prompt: child_text(question, "questiontext").unwrap_or_default(),
+        points: points.or_else(|| number(question, "defaultmark")),
+        choices,
+    })
+}
+
+fn assignment(archive: &Archive, directory: &str) -> anyhow::Result<AssignmentPlan> {
+    let path = format!("{directory}/assign.xml");
+    let doc = xml::parse(&path, archive.require(&path)?)?;
+    let assign = descendants(doc.root_element(), "assign")
+        .next()
+        .with_context(|| format!("{path} has no assign"))?;
+    Ok(AssignmentPlan {
+        title: child_text(assign, "name").unwrap_or_default(),
+        instructions: child_text(assign, "intro").unwrap_or_default(),
+        // Zero means no due date
+        due_at: number::<i64>(assign, "duedate")
+            .filter(|&due_at| due_at > 0)
+            .and_then(|due_at| chrono::DateTime::from_timestamp(due_at, 0))
+            .map(|due_at| due_at.naive_utc()),
+        // Negative grades are scales rather than points
+        max_points: number::<f64>(assign, "grade").filter(|&grade| grade > 0.0),
+    })
+}
+
+fn quiz(
+    archive: &Archive,
+    directory: &str,
+    bank: &QuestionBank,
+    skipped: &mut Vec<Skipped>,
+) -> anyhow::Result<QuizPlan> {
+    let path = format!("{directory}/quiz.xml");
+    let doc = xml::parse(&path, archive.require(&path)?)?;
+    let quiz = descendants(doc.root_element(), "quiz")
+        .next()
+        .with_context(|| format!("{path} has no quiz"))?;
+    let mut questions = vec![];
+    for instance in descendants(quiz, "question_instance") {
+        let Some(found) = bank.question_of(instance) else {
+            // Such as slots that draw a random question from a category
+            skipped.push(Skipped {
+                title: format!("Slot {}", child_text(instance, "slot").unwrap_or_default()),
+                kind: "question:random".into(),
+            });
+            continue;
+        };
+        match question(found, number(instance, "maxmark")) {
+            Ok(question) => questions.push(question),
+            Err(qtype) => skipped.push(Skipped {
+                title: child_text(found, "name").unwrap_or_default(),
+                kind: format!("question:{qtype}"),
+            }),
+        }
+    }
+    Ok(QuizPlan {
+        title: child_text(quiz, "name").unwrap_or_default(),
+        instructions: child_text(quiz, "intro").unwrap_or_default(),
+        questions,
+    })
+}
+
+fn section_title(archive: &Archive, section: Node) -> String {
+    let directory = child_text(section, "directory").unwrap_or_default();
+    let path = format!("{directory}/section.xml");
+    let doc = archive
+        .get(&path)
+        .and_then(|text| xml::parse(&path, text).ok());
+    let root = doc.as_ref().map(Document::root_element);
+    if let Some(name) = root.and_then(|root| child_text(root, "name")) {
+        return name;
+    }
+    // Unnamed sections show as their number, with the first one above all topics
+    match root.and_then(|root| number::<u32>(root, "number")) {
+        Some(0) => "General".into(),
+        Some(number) => format!("Topic {number}"),
+        None => child_text(section, "title").unwrap_or_default(),
+    }
+}
+
+pub fn parse(archive: &Archive) -> anyhow::Result<Parsed> {
+    let doc = xml::parse(MANIFEST, archive.require(MANIFEST)?)?;
+    let backup = doc.root_element();
+    let information =
+        child(backup, "information").with_context(|| format!("{MANIFEST} has no information"))?;
+    let questions_doc = archive
+        .get(QUESTIONS)
+        .map(|text| xml::parse(QUESTIONS, text))
+        .transpose()?;
+    let bank = QuestionBank::new(questions_doc.as_ref());
+
+    let mut modules = vec![];
+    let mut module_of_section = FxHashMap::default();
+    for section in find(information, &["contents", "sections"])
+        .into_iter()
+        .flat_map(|sections| children(sections, "section"))
+    {
+        if let Some(id) = child_text(section, "sectionid") {
+            module_of_section.insert(id, modules.len());
+        }
+        modules.push(ModulePlan {
+            title: section_title(archive, section),
+            assignments: vec![],
+            quizzes: vec![],
+        });
+    }
+
+    let mut skipped = vec![];
+    for activity in find(information, &["contents", "activities"])
+        .into_iter()
+        .flat_map(|activities| children(activities, "activity"))
+    {
+        let kind = child_text(activity, "modulename").unwrap_or_default();
+        let title = child_text(activity, "title").unwrap_or_default();
+        let directory = child_text(activity, "directory").unwrap_or_default();
+        let module =
+            child_text(activity, "sectionid").and_then(|id| module_of_section.get(&id).copied());
+        let Some(module) = module.and_then(|module| modules.get_mut(module)) else {
+            skipped.push(Skipped { title, kind });
+            continue;
+        };
+        match kind.as_str() {
+            "assign" => module.assignments.push(assignment(archive, &directory)?),
+            "quiz" => module
+                .quizzes
+                .push(quiz(archive, &directory, &bank, &mut skipped)?),
+            _ => skipped.push(Skipped { title, kind }),
+        }
+    }
+
+    Ok(Parsed {
+        course: CoursePlan {
+            format: SourceFormat::MoodleBackup,
+            title: child_text(information, "original_course_fullname")
+                .unwrap_or_else(|| "Untitled course".into()),
+            code: child_text(information, "original_course_shortname"),
+            modules,
+        },
+        skipped,
+    })
+}
@@ -0,0 +1,117 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceFormat {
+    /// IMS Common Cartridge, which Canvas exports
+    CommonCartridge,
+    MoodleBackup,
+}
+
+/// A course as read from an export, before anything is created.
+#[derive(Debug, Clone, Serialize)]
+pub struct CoursePlan {
+    pub format: SourceFormat,
+    pub title: String,
+    /// The short name of the course, such as `BIO-101`
+    pub code: Option<String>,
+    pub modules: Vec<ModulePlan>,
+}
+
+/// A unit of the course, which Moodle calls a section.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModulePlan {
+    pub title: String,
+    pub assignments: Vec<AssignmentPlan>,
+    pub quizzes: Vec<QuizPlan>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AssignmentPlan {
+    pub title: String,
+    /// HTML, as the LMS stored it
+    pub instructions: String,
+    pub due_at: Option<NaiveDateTime>,
+    pub max_points: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QuizPlan {
+    pub title: String,
+    /// HTML, as the LMS stored it
+    pub instructions: String,
+    pub questions: Vec<QuestionPlan>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuestionKind {
+    /// One correct choice
+    MultipleChoice,
+    /// Any number of correct choices
+    MultipleAnswer,
+    TrueFalse,
+    /// Choices are the accepted answers
+    ShortAnswer,
+    /// Choices are the accepted answers, written as numbers
+    Numerical,
+    Essay,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QuestionPlan {
+    pub kind: QuestionKind,
+    /// HTML, as the LMS stored it
+    pub prompt: String,
+    pub points: Option<f64>,
+    pub choices: Vec<ChoicePlan>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChoicePlan {
+    pub text: String,
+    pub correct: bool,
+}
+
+/// Something in the export that has no counterpart in teach-tech, such as a forum.
+#[derive(Debug, Clone, Serialize)]
+pub struct Skipped {
+    pub title: String,
+    /// What the export calls the kind of content, such as `forum` or `imsdt_xmlv1p1`
+    pub kind: String,
+}
+
+/// What reading an export found.
+#[derive(Debug, Clone, Serialize)]
+pub struct Parsed {
+    pub course: CoursePlan,
+    pub skipped: Vec<Skipped>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Counts {
+    pub modules: usize,
+    pub assignments: usize,
+    pub quizzes: usize,
+    pub questions: usize,
+}
+
+impl CoursePlan {
+    pub fn counts(&self) -> Counts {
+        let mut counts = Counts {
+            modules: self.modules.len(),
+            ..Default::default()
+        };
+        for module in &self.modules {
+            counts.assignments += module.assignments.len();
+            counts.quizzes += module.quizzes.len();
+            counts.questions += module
+                .quizzes
+                .iter()
+                .map(|quiz| quiz.questions.len())
+                .sum::<usize>();
+        }
+        counts
+    }
+}
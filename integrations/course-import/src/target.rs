@@ -0,0 +1,52 @@
+use std::sync::{Arc, Mutex, OnceLock};
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+use teach_tech_core::{anyhow, auth::UserID};
+
+use crate::plan::CoursePlan;
+
+static TARGETS: Mutex<Vec<(String, Arc<dyn ImportTarget>)>> = Mutex::new(Vec::new());
+static TARGET: OnceLock<Arc<dyn ImportTarget>> = OnceLock::new();
+
+/// The course an import created.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportedCourse {
+    /// However the target identifies the course
+    pub course_id: String,
+    pub link: Option<String>,
+}
+
+/// Creates the courses, modules, assignments and quiz questions that imports read, such as an
+/// integration that manages courses.
+pub trait ImportTarget: Send + Sync + 'static {
+    fn import(
+        &self,
+        course: CoursePlan,
+        imported_by: UserID,
+    ) -> BoxFuture<'static, anyhow::Result<ImportedCourse>>;
+}
+
+/// Makes an import target available to `course_import.target` under `name`.
+pub fn add_target(name: impl Into<String>, target: impl ImportTarget) {
+    TARGETS
+        .lock()
+        .unwrap()
+        .push((name.into(), Arc::new(target)));
+}
+
+pub(crate) fn select(name: &str) -> anyhow::Result<()> {
+    let target = TARGETS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(target_name, _)| target_name == name)
+        .map(|(_, target)| target.clone())
+        .ok_or_else(|| anyhow::anyhow!("course_import.target {name} was not added"))?;
+    let _ = TARGET.set(target);
+    Ok(())
+}
+
+pub fn get_target() -> Option<Arc<dyn ImportTarget>> {
+    TARGET.get().cloned()
+}
@@ -0,0 +1,84 @@
+use roxmltree::{Document, Node, ParsingOptions};
+use teach_tech_core::anyhow::{self, Context};
+
+/// What Moodle writes in place of a missing value
+const MOODLE_NULL: &str = "$@NULL@$";
+
+pub fn parse<'a>(path: &str, text: &'a str) -> anyhow::Result<Document<'a>> {
+    Document::parse_with_options(
+        text,
+        ParsingOptions {
+            allow_dtd: true,
+            ..Default::default()
+        },
+    )
+    .with_context(|| format!("Parsing {path}"))
+}
+
+// Exports mix namespaces freely, so elements are matched by their local name alone
+
+pub fn children<'a, 'i>(
+    node: Node<'a, 'i>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'i>> {
+    node.children()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+pub fn child<'a, 'i>(node: Node<'a, 'i>, name: &'static str) -> Option<Node<'a, 'i>> {
+    children(node, name).next()
+}
+
+pub fn descendants<'a, 'i>(
+    node: Node<'a, 'i>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'i>> {
+    node.descendants()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+/// Follows `path` down through the first child of each name.
+pub fn find<'a, 'i>(node: Node<'a, 'i>, path: &[&'static str]) -> Option<Node<'a, 'i>> {
+    path.iter().try_fold(node, |node, name| child(node, name))
+}
+
+/// The trimmed text of the node, unless it is empty.
+pub fn text(node: Node) -> Option<String> {
+    let text: String = node
+        .children()
+        .filter(|child| child.is_text())
+        .filter_map(|child| child.text())
+        .collect();
+    let text = text.trim();
+    (!text.is_empty() && text != MOODLE_NULL).then(|| text.to_string())
+}
+
+pub fn child_text(node: Node, name: &'static str) -> Option<String> {
+    child(node, name).and_then(text)
+}
+
+pub fn find_text(node: Node, path: &[&'static str]) -> Option<String> {
+    find(node, path).and_then(text)
+}
+
+/// Resolves `%20` and the like in the paths of a manifest.
+pub fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(byte) = path
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
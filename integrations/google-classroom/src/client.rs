@@ -0,0 +1,183 @@
+use std::{
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    rand::SystemRandom,
+    signature::{RsaKeyPair, RSA_PKCS1_SHA256},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use teach_tech_core::{
+    anyhow::{self, Context},
+    http::HttpClient,
+    serde_json::{self, json, Value},
+};
+use url::Url;
+
+const API: &str = "https://classroom.googleapis.com/v1";
+const SCOPES: &str = "https://www.googleapis.com/auth/classroom.courses.readonly \
+                      https://www.googleapis.com/auth/classroom.rosters.readonly \
+                      https://www.googleapis.com/auth/classroom.profile.emails \
+                      https://www.googleapis.com/auth/classroom.coursework.students.readonly";
+const TIMEOUT: Duration = Duration::from_secs(30);
+// Tokens are refreshed this long before they expire, so that none expires mid-sync
+const TOKEN_MARGIN: Duration = Duration::from_secs(60);
+
+/// The fields used from the JSON key Google gives out for a service account.
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// A blocking client of the Classroom API, acting as `delegated_user` through a service account
+/// with domain-wide delegation.
+pub struct ClassroomClient {
+    client_email: String,
+    token_uri: Url,
+    key_pair: RsaKeyPair,
+    delegated_user: String,
+    client: HttpClient,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl ClassroomClient {
+    pub fn new(key_file: &Path, delegated_user: String) -> anyhow::Result<Self> {
+        let key = std::fs::read_to_string(key_file)
+            .with_context(|| format!("Reading {}", key_file.display()))?;
+        let key: ServiceAccountKey = serde_json::from_str(&key)
+            .with_context(|| format!("Parsing {}", key_file.display()))?;
+        let der = rustls_pemfile::pkcs8_private_keys(&mut key.private_key.as_bytes())
+            .next()
+            .context("The service account key has no private key")?
+            .context("Parsing the service account's private key")?;
+        let key_pair = RsaKeyPair::from_pkcs8(der.secret_pkcs8_der())
+            .map_err(|e| anyhow::anyhow!("Invalid service account private key: {e}"))?;
+
+        Ok(Self {
+            client_email: key.client_email,
+            token_uri: key.token_uri.parse().context("Invalid token_uri")?,
+            key_pair,
+            delegated_user,
+            client: HttpClient::new(TIMEOUT)?,
+            token: Mutex::new(None),
+        })
+    }
+
+    /// A signed JWT asking for a token on behalf of the delegated user.
+    fn assertion(&self) -> anyhow::Result<String> {
+        let now = chrono::Utc::now().timestamp();
+        let header = URL_SAFE_NO_PAD.encode(json!({ "alg": "RS256", "typ": "JWT" }).to_string());
+        let claims = URL_SAFE_NO_PAD.encode(
+            json!({
+                "iss": self.client_email,
+                "sub": self.delegated_user,
+                "scope": SCOPES,
+                "aud": self.token_uri.as_str(),
+                "iat": now,
+                "exp": now + 3600,
+            })
+            .to_string(),
+        );
+        let message = format!("{header}.{claims}");
+        let mut signature = vec![0; self.key_pair.public().modulus_len()];
+        self.key_pair
+            .sign(
+                &RSA_PKCS1_SHA256,
+                &SystemRandom::new(),
+                message.as_bytes(),
+                &mut signature,
+            )
+            .map_err(|_| anyhow::anyhow!("Signing the token request"))?;
+        Ok(format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature)))
+    }
+
+    fn access_token(&self) -> anyhow::Result<String> {
+        if let Some((token, expires_at)) = &*self.token.lock().unwrap() {
+            if Instant::now() + TOKEN_MARGIN < *expires_at {
+                return Ok(token.clone());
+            }
+        }
+        let body = serde_urlencoded::to_string([
+            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+            ("assertion", &self.assertion()?),
+        ])?;
+        let response = self.client.send(
+            "POST",
+            self.token_uri.as_str(),
+            &[],
+            Some(("application/x-www-form-urlencoded", body.as_bytes())),
+        )?;
+        if response.status != 200 {
+            return Err(anyhow::anyhow!(
+                "Google refused a token with {}: {}",
+                response.status,
+                response.text()
+            ));
+        }
+        let response: TokenResponse =
+            serde_json::from_slice(&response.body).context("Parsing the token response")?;
+        let expires_at = Instant::now() + Duration::from_secs(response.expires_in);
+        *self.token.lock().unwrap() = Some((response.access_token.clone(), expires_at));
+        Ok(response.access_token)
+    }
+
+    fn get(&self, path: &str, query: &impl Serialize) -> anyhow::Result<Value> {
+        let mut url: Url = format!("{API}{path}").parse()?;
+        url.set_query(Some(&serde_urlencoded::to_string(query)?));
+        let authorization = format!("Bearer {}", self.access_token()?);
+        let response = self.client.send(
+            "GET",
+            url.as_str(),
+            &[("Authorization", &authorization)],
+            None,
+        )?;
+        if response.status != 200 {
+            return Err(anyhow::anyhow!(
+                "GET {path} responded with {}: {}",
+                response.status,
+                response.text()
+            ));
+        }
+        serde_json::from_slice(&response.body).with_context(|| format!("Parsing GET {path}"))
+    }
+
+    /// Every item of a paginated listing, which Google returns under `field`.
+    pub fn list<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        field: &str,
+        query: &[(&str, &str)],
+    ) -> anyhow::Result<Vec<T>> {
+        let mut items = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut query = query.to_vec();
+            query.push(("pageSize", "100"));
+            if let Some(page_token) = &page_token {
+                query.push(("pageToken", page_token.as_str()));
+            }
+            let mut page = self.get(path, &query)?;
+            if let Some(page_items) = page.get_mut(field).map(Value::take) {
+                items.extend(
+                    serde_json::from_value::<Vec<T>>(page_items)
+                        .with_context(|| format!("Parsing {field} from {path}"))?,
+                );
+            }
+            match page["nextPageToken"].as_str() {
+                Some(next) if !next.is_empty() => page_token = Some(next.to_string()),
+                _ => return Ok(items),
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use fxhash::FxHashMap;
+use sea_orm::{entity::prelude::*, QueryFilter};
+use serde::{Deserialize, Serialize};
+use teach_tech_core::{
+    anyhow,
+    auth::UserID,
+    calendar::{CalendarEvent, EventKind},
+    db::get_read_db,
+    mail,
+};
+
+use crate::courses::{self, roster};
+
+/// An assignment or question posted to a course.
+#[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "google_classroom_course_work")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    pub course_id: String,
+    pub title: String,
+    pub description: Option<String>,
+    pub work_type: Option<String>,
+    pub max_points: Option<f64>,
+    /// In UTC, as Google reports it
+    pub due: Option<DateTime>,
+    pub state: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GoogleCourseWork {
+    pub id: String,
+    pub title: String,
+    pub description: Option<String>,
+    pub work_type: Option<String>,
+    pub max_points: Option<f64>,
+    pub due_date: Option<Date>,
+    pub due_time: Option<Time>,
+    #[serde(default)]
+    pub state: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct Time {
+    #[serde(default)]
+    pub hours: u32,
+    #[serde(default)]
+    pub minutes: u32,
+}
+
+impl GoogleCourseWork {
+    pub(crate) fn due(&self) -> Option<DateTime> {
+        let date = self.due_date.as_ref()?;
+        let time = self.due_time.as_ref();
+        chrono::NaiveDate::from_ymd_opt(date.year, date.month, date.day)?.and_hms_opt(
+            time.map_or(0, |time| time.hours),
+            time.map_or(0, |time| time.minutes),
+            0,
+        )
+    }
+}
+
+/// The due dates of published course work in the user's courses, for their calendar feed.
+///
+/// Roster entries carry no teach-tech user, so the user is matched by their mail address.
+pub(crate) async fn calendar_events(user_id: UserID) -> anyhow::Result<Vec<CalendarEvent>> {
+    let Some(address) = mail::addresses::Entity::find_by_id(user_id)
+        .one(get_read_db())
+        .await?
+    else {
+        return Ok(vec![]);
+    };
+    let course_ids: Vec<_> = roster::Entity::find()
+        .filter(roster::Column::EmailHash.eq(roster::email_hash(&address.address.0)))
+        .all(get_read_db())
+        .await?
+        .into_iter()
+        .map(|entry| entry.course_id)
+        .collect();
+    if course_ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let links: FxHashMap<_, _> = courses::Entity::find()
+        .filter(courses::Column::Id.is_in(course_ids.clone()))
+        .all(get_read_db())
+        .await?
+        .into_iter()
+        .map(|course| (course.id, course.link))
+        .collect();
+    let work = Entity::find()
+        .filter(Column::CourseId.is_in(course_ids))
+        .filter(Column::State.eq("PUBLISHED"))
+        .filter(Column::Due.is_not_null())
+        .all(get_read_db())
+        .await?;
+    Ok(work
+        .into_iter()
+        .filter_map(|work| {
+            Some(CalendarEvent {
+                uid: format!("google-classroom:{}", work.id),
+                kind: EventKind::Assignment,
+                start: work.due?,
+                end: None,
+                link: links.get(&work.course_id).cloned().flatten(),
+                summary: work.title,
+                description: work.description,
+                location: None,
+                recurrence: None,
+            })
+        })
+        .collect())
+}
+
+/// The grade of each student on each piece of course work.
+pub mod grades {
+    use super::*;
+
+    #[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+    #[sea_orm(table_name = "google_classroom_grades")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub submission_id: String,
+        pub course_id: String,
+        pub course_work_id: String,
+        pub google_user_id: String,
+        pub state: String,
+        pub late: bool,
+        /// The grade returned to the student
+        pub assigned_grade: Option<f64>,
+        /// The grade only the teacher can see so far
+        pub draft_grade: Option<f64>,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub(crate) struct GoogleSubmission {
+        pub id: String,
+        pub course_work_id: String,
+        pub user_id: String,
+        #[serde(default)]
+        pub state: String,
+        #[serde(default)]
+        pub late: bool,
+        pub assigned_grade: Option<f64>,
+        pub draft_grade: Option<f64>,
+    }
+}
@@ -0,0 +1,95 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+use teach_tech_core::encryption::{self, Encrypted};
+
+/// A course as Google Classroom last reported it.
+#[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "google_classroom_courses")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    pub name: String,
+    pub section: Option<String>,
+    pub state: String,
+    pub owner_id: String,
+    pub link: Option<String>,
+    pub synced_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GoogleCourse {
+    pub id: String,
+    pub name: String,
+    pub section: Option<String>,
+    #[serde(default)]
+    pub course_state: String,
+    #[serde(default)]
+    pub owner_id: String,
+    pub alternate_link: Option<String>,
+}
+
+/// The students and teachers of each course.
+pub mod roster {
+    use super::*;
+
+    #[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+    #[sea_orm(table_name = "google_classroom_roster")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub course_id: String,
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub google_user_id: String,
+        pub role: RosterRole,
+        pub name: Option<String>,
+        /// [`encryption::keyed_hash`] of the lowercased email, to match students by their mail
+        /// address
+        #[sea_orm(indexed)]
+        #[serde(skip_serializing)]
+        pub email_hash: Option<String>,
+        pub email: Option<Encrypted<String, Entity>>,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    #[derive(EnumIter, DeriveActiveEnum, Clone, Debug, Copy, PartialEq, Eq, Serialize)]
+    #[sea_orm(rs_type = "i32", db_type = "Integer")]
+    pub enum RosterRole {
+        Student = 0,
+        Teacher = 1,
+    }
+
+    /// The hash that [`Model::email_hash`] holds for `email`.
+    pub fn email_hash(email: &str) -> String {
+        encryption::keyed_hash(&email.trim().to_lowercase())
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub(crate) struct GoogleMember {
+        pub user_id: String,
+        #[serde(default)]
+        pub profile: Profile,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub(crate) struct Profile {
+        pub name: Option<Name>,
+        pub email_address: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub(crate) struct Name {
+        pub full_name: Option<String>,
+    }
+}
@@ -0,0 +1,266 @@
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter, QueryOrder};
+use teach_tech_core::{
+    async_graphql::{
+        self,
+        dynamic::{Field, FieldFuture, FieldValue, InputValue, ResolverContext, TypeRef},
+    },
+    db::get_read_db,
+    graphql::{
+        add_field, add_object, internal_error, json_object, json_value, parent, parent_user_id,
+        require_admin,
+    },
+    mail,
+};
+
+use crate::{
+    course_work::{self, grades},
+    courses::{self, roster},
+};
+
+fn parent_str(ctx: &ResolverContext, key: &str) -> async_graphql::Result<String> {
+    parent(ctx)?
+        .get(key)
+        .and_then(|value| value.as_str())
+        .map(str::to_string)
+        .ok_or_else(|| async_graphql::Error::new(format!("The parent has no {key}")))
+}
+
+fn list<T: serde::Serialize>(models: Vec<T>) -> async_graphql::Result<FieldValue<'static>> {
+    Ok(FieldValue::list(
+        models
+            .iter()
+            .map(json_value)
+            .collect::<async_graphql::Result<Vec<_>>>()?,
+    ))
+}
+
+/// Adds courses, rosters, course work and grades to the GraphQL API. Like the REST endpoints,
+/// only administrators can read them.
+pub fn add_to_schema() {
+    add_object(
+        json_object(
+            "ClassroomCourse",
+            [
+                ("id", "id", TypeRef::named_nn(TypeRef::STRING)),
+                ("name", "name", TypeRef::named_nn(TypeRef::STRING)),
+                ("section", "section", TypeRef::named(TypeRef::STRING)),
+                ("state", "state", TypeRef::named_nn(TypeRef::STRING)),
+                ("ownerId", "owner_id", TypeRef::named_nn(TypeRef::STRING)),
+                ("link", "link", TypeRef::named(TypeRef::STRING)),
+                ("syncedAt", "synced_at", TypeRef::named_nn(TypeRef::STRING)),
+            ],
+        )
+        .field(Field::new(
+            "roster",
+            TypeRef::named_nn_list_nn("ClassroomRosterEntry"),
+            |ctx| {
+                FieldFuture::new(async move {
+                    let models = roster::Entity::find()
+                        .filter(roster::Column::CourseId.eq(parent_str(&ctx, "id")?))
+                        .order_by_asc(roster::Column::GoogleUserId)
+                        .all(get_read_db())
+                        .await
+                        .map_err(|e| internal_error("Error reading roster", e))?;
+                    list(models).map(Some)
+                })
+            },
+        ))
+        .field(Field::new(
+            "courseWork",
+            TypeRef::named_nn_list_nn("ClassroomCourseWork"),
+            |ctx| {
+                FieldFuture::new(async move {
+                    let models = course_work::Entity::find()
+                        .filter(course_work::Column::CourseId.eq(parent_str(&ctx, "id")?))
+                        .order_by_asc(course_work::Column::Id)
+                        .all(get_read_db())
+                        .await
+                        .map_err(|e| internal_error("Error reading course work", e))?;
+                    list(models).map(Some)
+                })
+            },
+        )),
+    );
+
+    add_object(
+        json_object(
+            "ClassroomRosterEntry",
+            [
+                ("courseId", "course_id", TypeRef::named_nn(TypeRef::STRING)),
+                (
+                    "googleUserId",
+                    "google_user_id",
+                    TypeRef::named_nn(TypeRef::STRING),
+                ),
+                ("role", "role", TypeRef::named_nn(TypeRef::STRING)),
+                ("name", "name", TypeRef::named(TypeRef::STRING)),
+                ("email", "email", TypeRef::named(TypeRef::STRING)),
+            ],
+        )
+        .field(Field::new(
+            "course",
+            TypeRef::named("ClassroomCourse"),
+            |ctx| {
+                FieldFuture::new(async move {
+                    let model = courses::Entity::find_by_id(parent_str(&ctx, "course_id")?)
+                        .one(get_read_db())
+                        .await
+                        .map_err(|e| internal_error("Error reading course", e))?;
+                    model.map(|model| json_value(&model)).transpose()
+                })
+            },
+        ))
+        .field(Field::new(
+            "grades",
+            TypeRef::named_nn_list_nn("ClassroomGrade"),
+            |ctx| {
+                FieldFuture::new(async move {
+                    let models = grades::Entity::find()
+                        .filter(grades::Column::CourseId.eq(parent_str(&ctx, "course_id")?))
+                        .filter(
+                            grades::Column::GoogleUserId.eq(parent_str(&ctx, "google_user_id")?),
+                        )
+                        .order_by_asc(grades::Column::CourseWorkId)
+                        .all(get_read_db())
+                        .await
+                        .map_err(|e| internal_error("Error reading grades", e))?;
+                    list(models).map(Some)
+                })
+            },
+        )),
+    );
+
+    add_object(
+        json_object(
+            "ClassroomCourseWork",
+            [
+                ("id", "id", TypeRef::named_nn(TypeRef::STRING)),
+                ("courseId", "course_id", TypeRef::named_nn(TypeRef::STRING)),
+                ("title", "title", TypeRef::named_nn(TypeRef::STRING)),
+                (
+                    "description",
+                    "description",
+                    TypeRef::named(TypeRef::STRING),
+                ),
+                ("workType", "work_type", TypeRef::named(TypeRef::STRING)),
+                ("maxPoints", "max_points", TypeRef::named(TypeRef::FLOAT)),
+                ("due", "due", TypeRef::named(TypeRef::STRING)),
+                ("state", "state", TypeRef::named_nn(TypeRef::STRING)),
+            ],
+        )
+        .field(Field::new(
+            "grades",
+            TypeRef::named_nn_list_nn("ClassroomGrade"),
+            |ctx| {
+                FieldFuture::new(async move {
+                    let models = grades::Entity::find()
+                        .filter(grades::Column::CourseWorkId.eq(parent_str(&ctx, "id")?))
+                        .order_by_asc(grades::Column::GoogleUserId)
+                        .all(get_read_db())
+                        .await
+                        .map_err(|e| internal_error("Error reading grades", e))?;
+                    list(models).map(Some)
+                })
+            },
+        )),
+    );
+
+    add_object(json_object(
+        "ClassroomGrade",
+        [
+            (
+                "submissionId",
+                "submission_id",
+                TypeRef::named_nn(TypeRef::STRING),
+            ),
+            ("courseId", "course_id", TypeRef::named_nn(TypeRef::STRING)),
+            (
+                "courseWorkId",
+                "course_work_id",
+                TypeRef::named_nn(TypeRef::STRING),
+            ),
+            (
+                "googleUserId",
+                "google_user_id",
+                TypeRef::named_nn(TypeRef::STRING),
+            ),
+            ("state", "state", TypeRef::named_nn(TypeRef::STRING)),
+            ("late", "late", TypeRef::named_nn(TypeRef::BOOLEAN)),
+            (
+                "assignedGrade",
+                "assigned_grade",
+                TypeRef::named(TypeRef::FLOAT),
+            ),
+            ("draftGrade", "draft_grade", TypeRef::named(TypeRef::FLOAT)),
+        ],
+    ));
+
+    add_field(
+        "Query",
+        Field::new(
+            "classroomCourses",
+            TypeRef::named_nn_list_nn("ClassroomCourse"),
+            |ctx| {
+                FieldFuture::new(async move {
+                    require_admin(&ctx).await?;
+                    let models = courses::Entity::find()
+                        .order_by_asc(courses::Column::Name)
+                        .all(get_read_db())
+                        .await
+                        .map_err(|e| internal_error("Error reading courses", e))?;
+                    list(models).map(Some)
+                })
+            },
+        ),
+    );
+    add_field(
+        "Query",
+        Field::new(
+            "classroomCourse",
+            TypeRef::named("ClassroomCourse"),
+            |ctx| {
+                FieldFuture::new(async move {
+                    require_admin(&ctx).await?;
+                    let id = ctx.args.try_get("id")?.string()?.to_string();
+                    let model = courses::Entity::find_by_id(id)
+                        .one(get_read_db())
+                        .await
+                        .map_err(|e| internal_error("Error reading course", e))?;
+                    model.map(|model| json_value(&model)).transpose()
+                })
+            },
+        )
+        .argument(InputValue::new("id", TypeRef::named_nn(TypeRef::STRING))),
+    );
+
+    // Roster entries carry no teach-tech user, so students are matched by their mail address
+    add_field(
+        "Student",
+        Field::new(
+            "classroomEnrollments",
+            TypeRef::named_nn_list_nn("ClassroomRosterEntry"),
+            |ctx| {
+                FieldFuture::new(async move {
+                    require_admin(&ctx).await?;
+                    let address = mail::addresses::Entity::find_by_id(parent_user_id(&ctx)?)
+                        .one(get_read_db())
+                        .await
+                        .map_err(|e| internal_error("Error reading mail address", e))?;
+                    let Some(address) = address else {
+                        return list::<roster::Model>(vec![]).map(Some);
+                    };
+                    let models = roster::Entity::find()
+                        .filter(
+                            roster::Column::EmailHash.eq(roster::email_hash(&address.address.0)),
+                        )
+                        .filter(roster::Column::Role.eq(roster::RosterRole::Student))
+                        .order_by_asc(roster::Column::CourseId)
+                        .all(get_read_db())
+                        .await
+                        .map_err(|e| internal_error("Error reading roster", e))?;
+                    list(models).map(Some)
+                })
+            },
+        ),
+    );
+}
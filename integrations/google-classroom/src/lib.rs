@@ -0,0 +1,134 @@
+use std::{path::PathBuf, time::Duration};
+
+use serde::Deserialize;
+use teach_tech_core::{
+    anyhow::{self, Context},
+    auth::AdminUser,
+    axum::{
+        http::StatusCode,
+        response::{IntoResponse, Response},
+        routing::{get, post},
+        Json,
+    },
+    calendar, i18n, tokio, TeachCore,
+};
+
+const MESSAGES: &[(&str, &str)] = &[
+    (
+        "google_classroom.not_configured",
+        "google_classroom is not configured",
+    ),
+    ("google_classroom.sync_running", "A sync is already running"),
+];
+
+mod client;
+pub mod course_work;
+pub mod courses;
+#[cfg(feature = "graphql")]
+mod graphql;
+pub mod sync;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GoogleClassroomConfig {
+    pub google_classroom: Option<GoogleClassroomOptions>,
+}
+
+/// The `[google_classroom]` section of `teach-config.toml`. Without it nothing is synced.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GoogleClassroomOptions {
+    /// The JSON key of a service account with domain-wide delegation
+    pub service_account_key: PathBuf,
+    /// The Workspace user whose view of Classroom is imported, usually a domain administrator
+    pub delegated_user: String,
+    #[serde(default = "default_sync_interval_mins")]
+    pub sync_interval_mins: u64,
+    /// Only these courses are imported. Leaving it empty imports every active course
+    #[serde(default)]
+    pub course_ids: Vec<String>,
+}
+
+fn default_sync_interval_mins() -> u64 {
+    60
+}
+
+fn require_configured() -> Result<(), Response> {
+    if !sync::is_configured() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            i18n::t("google_classroom.not_configured"),
+        )
+            .into_response());
+    }
+    Ok(())
+}
+
+/// Starts a sync in the background, whose outcome shows up in the status.
+async fn start_sync(_: AdminUser) -> Response {
+    if let Err(response) = require_configured() {
+        return response;
+    }
+    if sync::status().running {
+        return (
+            StatusCode::CONFLICT,
+            i18n::t("google_classroom.sync_running"),
+        )
+            .into_response();
+    }
+    tokio::spawn(sync::sync());
+    (StatusCode::ACCEPTED, ()).into_response()
+}
+
+async fn sync_status(_: AdminUser) -> Response {
+    if let Err(response) = require_configured() {
+        return response;
+    }
+    (StatusCode::OK, Json(sync::status())).into_response()
+}
+
+pub async fn add_to_core<S: Clone + Send + Sync + 'static>(
+    mut core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    let mut info = fxhash::FxHashMap::default();
+    info.insert("version", env!("CARGO_PKG_VERSION"));
+    core.add_info("google-classroom", info);
+    i18n::add_catalog(i18n::FALLBACK_LOCALE, MESSAGES.iter().copied());
+    core.declare_config::<GoogleClassroomConfig>();
+    let config: GoogleClassroomConfig = toml::from_str(core.get_config_str())?;
+    core.add_db_reset_config(courses::Entity);
+    core.add_db_reset_config(courses::roster::Entity);
+    // The hashes are recomputed by the next sync, which replaces the roster
+    core.add_encrypted_column(courses::roster::Entity, courses::roster::Column::Email);
+    core.add_db_reset_config(course_work::Entity);
+    core.add_db_reset_config(course_work::grades::Entity);
+    calendar::add_event_source("google-classroom", course_work::calendar_events);
+    #[cfg(feature = "graphql")]
+    graphql::add_to_schema();
+
+    core = core.modify_router(|router| {
+        router
+            .route("/google-classroom/sync", post(start_sync))
+            .route("/google-classroom/status", get(sync_status))
+    });
+
+    let Some(options) = config.google_classroom else {
+        return Ok(core);
+    };
+    if options.sync_interval_mins == 0 {
+        return Err(anyhow::anyhow!(
+            "google_classroom.sync_interval_mins must be at least 1"
+        ));
+    }
+    let client = client::ClassroomClient::new(&options.service_account_key, options.delegated_user)
+        .context("Loading the google_classroom service account")?;
+    sync::init(client, options.course_ids);
+    core.add_scheduled_task(
+        "google-classroom sync",
+        Duration::from_secs(options.sync_interval_mins * 60),
+        || async {
+            sync::sync().await?;
+            Ok(())
+        },
+    );
+
+    Ok(core)
+}
@@ -0,0 +1,258 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, OnceLock,
+};
+
+use sea_orm::{entity::prelude::*, IntoActiveModel};
+use serde::Serialize;
+use teach_tech_core::{
+    anyhow::{self, Context},
+    db::{get_db, insert_batched, transaction_with_retry},
+    encryption::Encrypted,
+    tokio,
+};
+use tracing::{error, info};
+
+use crate::{
+    client::ClassroomClient,
+    course_work::{self, grades, GoogleCourseWork},
+    courses::{
+        self,
+        roster::{self, GoogleMember, RosterRole},
+        GoogleCourse,
+    },
+};
+
+static CLIENT: OnceLock<(Arc<ClassroomClient>, Vec<String>)> = OnceLock::new();
+static SYNCING: AtomicBool = AtomicBool::new(false);
+static STATUS: Mutex<SyncStatus> = Mutex::new(SyncStatus {
+    running: false,
+    last_started: None,
+    last_finished: None,
+    last_error: None,
+    courses: 0,
+    roster: 0,
+    course_work: 0,
+    grades: 0,
+});
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncStatus {
+    pub running: bool,
+    pub last_started: Option<DateTime>,
+    pub last_finished: Option<DateTime>,
+    /// Why the last sync failed, if it did. The tables keep what the sync before it imported
+    pub last_error: Option<String>,
+    pub courses: usize,
+    pub roster: usize,
+    pub course_work: usize,
+    pub grades: usize,
+}
+
+/// Everything imported by one sync.
+struct Snapshot {
+    courses: Vec<courses::Model>,
+    roster: Vec<roster::Model>,
+    course_work: Vec<course_work::Model>,
+    grades: Vec<grades::Model>,
+}
+
+pub(crate) fn init(client: ClassroomClient, course_ids: Vec<String>) {
+    let _ = CLIENT.set((Arc::new(client), course_ids));
+}
+
+pub fn is_configured() -> bool {
+    CLIENT.get().is_some()
+}
+
+pub fn status() -> SyncStatus {
+    STATUS.lock().unwrap().clone()
+}
+
+/// Replaces the mirror tables with what Google Classroom currently holds. Returns `false` without
+/// doing anything if a sync is already running.
+pub async fn sync() -> anyhow::Result<bool> {
+    let Some((client, course_ids)) = CLIENT.get() else {
+        return Err(anyhow::anyhow!("google_classroom is not configured"));
+    };
+    if SYNCING.swap(true, Ordering::Acquire) {
+        return Ok(false);
+    }
+    {
+        let mut status = STATUS.lock().unwrap();
+        status.running = true;
+        status.last_started = Some(chrono::Utc::now().naive_utc());
+    }
+
+    let client = client.clone();
+    let course_ids = course_ids.clone();
+    let result = async {
+        let snapshot = tokio::task::spawn_blocking(move || fetch(&client, &course_ids))
+            .await
+            .context("Fetching from Google Classroom")??;
+        let snapshot = Arc::new(snapshot);
+        store(snapshot.clone()).await?;
+        anyhow::Ok(snapshot)
+    }
+    .await;
+
+    let mut status = STATUS.lock().unwrap();
+    status.running = false;
+    status.last_finished = Some(chrono::Utc::now().naive_utc());
+    SYNCING.store(false, Ordering::Release);
+    match result {
+        Ok(snapshot) => {
+            info!(
+                "Imported {} courses from Google Classroom",
+                snapshot.courses.len()
+            );
+            status.last_error = None;
+            status.courses = snapshot.courses.len();
+            status.roster = snapshot.roster.len();
+            status.course_work = snapshot.course_work.len();
+            status.grades = snapshot.grades.len();
+            Ok(true)
+        }
+        Err(e) => {
+            error!("Error syncing Google Classroom: {e:#}");
+            status.last_error = Some(format!("{e:#}"));
+            Err(e)
+        }
+    }
+}
+
+fn fetch(client: &ClassroomClient, course_ids: &[String]) -> anyhow::Result<Snapshot> {
+    let synced_at = chrono::Utc::now().naive_utc();
+    let google_courses: Vec<GoogleCourse> =
+        client.list("/courses", "courses", &[("courseStates", "ACTIVE")])?;
+    let mut snapshot = Snapshot {
+        courses: vec![],
+        roster: vec![],
+        course_work: vec![],
+        grades: vec![],
+    };
+
+    for course in google_courses {
+        if !course_ids.is_empty() && !course_ids.contains(&course.id) {
+            continue;
+        }
+        let base = format!("/courses/{}", course.id);
+        for (role, path, field) in [
+            (RosterRole::Student, "students", "students"),
+            (RosterRole::Teacher, "teachers", "teachers"),
+        ] {
+            let members: Vec<GoogleMember> = client.list(&format!("{base}/{path}"), field, &[])?;
+            snapshot.roster.extend(members.into_iter().map(|member| {
+                let email = member.profile.email_address.clone().map(|email| {
+                    Encrypted::new(
+                        email,
+                        roster::Column::Email,
+                        (course.id.clone(), member.user_id.clone()),
+                    )
+                });
+                roster::Model {
+                    course_id: course.id.clone(),
+                    google_user_id: member.user_id,
+                    role,
+                    name: member.profile.name.and_then(|name| name.full_name),
+                    email_hash: member
+                        .profile
+                        .email_address
+                        .as_deref()
+                        .map(roster::email_hash),
+                    email,
+                }
+            }));
+        }
+
+        let work: Vec<GoogleCourseWork> =
+            client.list(&format!("{base}/courseWork"), "courseWork", &[])?;
+        snapshot
+            .course_work
+            .extend(work.into_iter().map(|work| course_work::Model {
+                due: work.due(),
+                id: work.id,
+                course_id: course.id.clone(),
+                title: work.title,
+                description: work.description,
+                work_type: work.work_type,
+                max_points: work.max_points,
+                state: work.state,
+            }));
+
+        let submissions: Vec<grades::GoogleSubmission> = client.list(
+            &format!("{base}/courseWork/-/studentSubmissions"),
+            "studentSubmissions",
+            &[],
+        )?;
+        snapshot
+            .grades
+            .extend(submissions.into_iter().map(|submission| grades::Model {
+                submission_id: submission.id,
+                course_id: course.id.clone(),
+                course_work_id: submission.course_work_id,
+                google_user_id: submission.user_id,
+                state: submission.state,
+                late: submission.late,
+                assigned_grade: submission.assigned_grade,
+                draft_grade: submission.draft_grade,
+            }));
+
+        snapshot.courses.push(courses::Model {
+            id: course.id,
+            name: course.name,
+            section: course.section,
+            state: course.course_state,
+            owner_id: course.owner_id,
+            link: course.alternate_link,
+            synced_at,
+        });
+    }
+    Ok(snapshot)
+}
+
+async fn store(snapshot: Arc<Snapshot>) -> anyhow::Result<()> {
+    transaction_with_retry(get_db(), |txn| {
+        let snapshot = snapshot.clone();
+        Box::pin(async move {
+            courses::Entity::delete_many().exec(txn).await?;
+            roster::Entity::delete_many().exec(txn).await?;
+            course_work::Entity::delete_many().exec(txn).await?;
+            grades::Entity::delete_many().exec(txn).await?;
+            insert_batched(
+                snapshot
+                    .courses
+                    .iter()
+                    .map(|model| model.clone().into_active_model()),
+                txn,
+            )
+            .await?;
+            insert_batched(
+                snapshot
+                    .roster
+                    .iter()
+                    .map(|model| model.clone().into_active_model()),
+                txn,
+            )
+            .await?;
+            insert_batched(
+                snapshot
+                    .course_work
+                    .iter()
+                    .map(|model| model.clone().into_active_model()),
+                txn,
+            )
+            .await?;
+            insert_batched(
+                snapshot
+                    .grades
+                    .iter()
+                    .map(|model| model.clone().into_active_model()),
+                txn,
+            )
+            .await
+        })
+    })
+    .await
+    .context("Storing the Google Classroom import")
+}
@@ -0,0 +1,145 @@
+use sea_orm::{entity::prelude::*, ActiveValue};
+use serde::Deserialize;
+use teach_tech_core::{
+    auth::{AuthUser, UserID},
+    axum::{
+        extract::{Path, Query},
+        http::StatusCode,
+        response::{IntoResponse, Response},
+        Json,
+    },
+    db::{get_db, get_read_db, paginate, PageQuery, SoftDeletable},
+    i18n,
+    users::{admins, instructors},
+};
+use tracing::error;
+
+use crate::checks::{self, CheckStatus};
+
+/// Only graders see checks, as reports can show other students' work.
+async fn authorize_staff(user_id: UserID) -> Result<(), Response> {
+    let result = async {
+        if admins::Entity::find_live_by_id(user_id)
+            .one(get_read_db())
+            .await?
+            .is_some()
+        {
+            return Ok(true);
+        }
+        instructors::Entity::find_live_by_id(user_id)
+            .one(get_read_db())
+            .await
+            .map(|instructor| instructor.is_some())
+    }
+    .await;
+    match result {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            Err((StatusCode::FORBIDDEN, i18n::t("plagiarism.must_be_staff")).into_response())
+        }
+        Err(e) => {
+            error!("Error reading user roles: {e:#}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, ()).into_response())
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CheckFilter {
+    pub source: Option<String>,
+    pub submission_id: Option<String>,
+    pub user_id: Option<UserID>,
+}
+
+/// Lists checks, such as those of one submission with `?source=...&submission_id=...`.
+pub async fn list_checks(
+    AuthUser { user_id, .. }: AuthUser,
+    Query(filter): Query<CheckFilter>,
+    Query(page): Query<PageQuery>,
+) -> Response {
+    if let Err(response) = authorize_staff(user_id).await {
+        return response;
+    }
+    let Ok(cursor) = page.cursor::<i32>() else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_cursor")).into_response();
+    };
+    let mut select = checks::Entity::find();
+    if let Some(source) = filter.source {
+        select = select.filter(checks::Column::Source.eq(source));
+    }
+    if let Some(submission_id) = filter.submission_id {
+        select = select.filter(checks::Column::SubmissionId.eq(submission_id));
+    }
+    if let Some(user_id) = filter.user_id {
+        select = select.filter(checks::Column::UserId.eq(user_id));
+    }
+    match paginate(
+        select,
+        checks::Column::Id,
+        |check| check.id,
+        cursor,
+        page.limit(),
+        get_read_db(),
+    )
+    .await
+    {
+        Ok(checks) => (StatusCode::OK, Json(checks)).into_response(),
+        Err(e) => {
+            error!("Error listing plagiarism checks: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+pub async fn get_check(AuthUser { user_id, .. }: AuthUser, Path(id): Path<i32>) -> Response {
+    if let Err(response) = authorize_staff(user_id).await {
+        return response;
+    }
+    match checks::Entity::find_by_id(id).one(get_read_db()).await {
+        Ok(Some(check)) => (StatusCode::OK, Json(check)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => {
+            error!("Error reading plagiarism check: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+/// Queues a failed check again, such as after the provider recovers from an outage.
+pub async fn retry_check(AuthUser { user_id, .. }: AuthUser, Path(id): Path<i32>) -> Response {
+    if let Err(response) = authorize_staff(user_id).await {
+        return response;
+    }
+    let check = match checks::Entity::find_by_id(id).one(get_db()).await {
+        Ok(Some(check)) => check,
+        Ok(None) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => {
+            error!("Error reading plagiarism check: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+    if check.status != CheckStatus::Failed {
+        return (StatusCode::CONFLICT, i18n::t("plagiarism.not_failed")).into_response();
+    }
+    let now = chrono::Utc::now().naive_utc();
+    let result = checks::ActiveModel {
+        id: ActiveValue::unchanged(id),
+        status: ActiveValue::set(CheckStatus::Queued),
+        provider: ActiveValue::set(None),
+        reference: ActiveValue::set(None),
+        attempts: ActiveValue::set(0),
+        next_attempt_at: ActiveValue::set(now),
+        created_at: ActiveValue::set(now),
+        completed_at: ActiveValue::set(None),
+        ..Default::default()
+    }
+    .update(get_db())
+    .await;
+    match result {
+        Ok(check) => (StatusCode::OK, Json(check)).into_response(),
+        Err(e) => {
+            error!("Error retrying plagiarism check: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::BoxFuture;
+use sea_orm::{entity::prelude::*, sea_query::Expr, ActiveValue, QueryOrder, QuerySelect};
+use serde::{Deserialize, Serialize};
+use teach_tech_core::{anyhow, auth::UserID, db::get_db, storage::get_storage, tokio};
+use tracing::{error, warn};
+
+use crate::{
+    options,
+    provider::{get_provider, Document, SimilarityProvider},
+    PlagiarismOptions,
+};
+
+// A claimed check is left alone by other siblings for this long, which must outlast sending it
+const CLAIM_SECS: i64 = 600;
+const BATCH_SIZE: u64 = 20;
+
+type Sink = Arc<dyn Fn(Model) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>;
+
+static SINKS: Mutex<Vec<(String, Sink)>> = Mutex::new(vec![]);
+
+#[derive(EnumIter, DeriveActiveEnum, Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[sea_orm(rs_type = "i32", db_type = "Integer")]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    /// Waiting to be sent to the provider
+    Queued = 0,
+    /// Sent, and waiting for the provider's report
+    Pending = 1,
+    Complete = 2,
+    Failed = 3,
+}
+
+/// A similarity check of a submission, which is also the queue of checks to make.
+#[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "plagiarism_checks")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    /// The integration the submission belongs to
+    pub source: String,
+    /// However the source identifies the submission
+    pub submission_id: String,
+    /// The author of the submission
+    pub user_id: UserID,
+    /// Where the submitted file is stored
+    #[serde(skip_serializing)]
+    pub key: String,
+    pub file_name: String,
+    pub status: CheckStatus,
+    pub provider: Option<String>,
+    /// How the provider refers to the check
+    #[serde(skip_serializing)]
+    pub reference: Option<String>,
+    /// How much of the submission matches other sources, from 0 to 100
+    pub similarity_percent: Option<f64>,
+    pub report_url: Option<String>,
+    pub attempts: i32,
+    #[serde(skip_serializing)]
+    pub next_attempt_at: DateTime,
+    pub last_error: Option<String>,
+    pub created_at: DateTime,
+    pub completed_at: Option<DateTime>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// A submission to check.
+#[derive(Debug, Clone)]
+pub struct NewCheck {
+    pub source: String,
+    pub submission_id: String,
+    pub user_id: UserID,
+    /// Where the submitted file is stored, through [`teach_tech_core::storage`]
+    pub key: String,
+    pub file_name: String,
+}
+
+/// Registers a function that is given every check that completes or fails, such as one that
+/// stores the score and report on the submission. Sinks run in the background, and their errors
+/// are only logged.
+pub fn add_sink<F, Fut>(name: impl Into<String>, f: F)
+where
+    F: Fn(Model) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    SINKS
+        .lock()
+        .unwrap()
+        .push((name.into(), Arc::new(move |model| Box::pin(f(model)))));
+}
+
+/// Queues a check of a submission, returning `None` without queueing it when no provider is
+/// configured. Call it when a submission arrives, as checking happens in the background.
+pub async fn request_check(check: NewCheck) -> Result<Option<Model>, DbErr> {
+    if get_provider().is_none() {
+        return Ok(None);
+    }
+    let now = chrono::Utc::now().naive_utc();
+    ActiveModel {
+        id: ActiveValue::not_set(),
+        source: ActiveValue::set(check.source),
+        submission_id: ActiveValue::set(check.submission_id),
+        user_id: ActiveValue::set(check.user_id),
+        key: ActiveValue::set(check.key),
+        file_name: ActiveValue::set(check.file_name),
+        status: ActiveValue::set(CheckStatus::Queued),
+        provider: ActiveValue::set(None),
+        reference: ActiveValue::set(None),
+        similarity_percent: ActiveValue::set(None),
+        report_url: ActiveValue::set(None),
+        attempts: ActiveValue::set(0),
+        next_attempt_at: ActiveValue::set(now),
+        last_error: ActiveValue::set(None),
+        created_at: ActiveValue::set(now),
+        completed_at: ActiveValue::set(None),
+    }
+    .insert(get_db())
+    .await
+    .map(Some)
+}
+
+fn retry_delay(options: &PlagiarismOptions, attempts: i32) -> chrono::Duration {
+    let secs = options
+        .retry_delay_secs
+        .saturating_mul(1 << attempts.clamp(1, 16).saturating_sub(1))
+        .min(24 * 60 * 60);
+    chrono::Duration::seconds(secs as i64)
+}
+
+/// Takes a check for this sibling, so that no other makes it at the same time.
+async fn claim(check: &Model) -> Result<bool, DbErr> {
+    let until = chrono::Utc::now().naive_utc() + chrono::Duration::seconds(CLAIM_SECS);
+    let result = Entity::update_many()
+        .col_expr(Column::NextAttemptAt, Expr::value(until))
+        .filter(Column::Id.eq(check.id))
+        .filter(Column::NextAttemptAt.eq(check.next_attempt_at))
+        .exec(get_db())
+        .await?;
+    Ok(result.rows_affected == 1)
+}
+
+/// Why a step of a check went wrong.
+enum CheckError {
+    /// Trying again may help, such as when the provider could not be reached
+    Transient(anyhow::Error),
+    Permanent(anyhow::Error),
+}
+
+/// Sends the submission of a queued check, returning the provider's reference.
+async fn submit(check: &Model, provider: &dyn SimilarityProvider) -> Result<String, CheckError> {
+    let Some(storage) = get_storage() else {
+        return Err(CheckError::Transient(anyhow::anyhow!(
+            "Storage is not configured"
+        )));
+    };
+    let object = match storage.get(&check.key).await {
+        Ok(Some(object)) => object,
+        Ok(None) => {
+            return Err(CheckError::Permanent(anyhow::anyhow!(
+                "The submission is no longer stored"
+            )));
+        }
+        Err(e) => return Err(CheckError::Transient(e.context("Reading the submission"))),
+    };
+    provider
+        .submit(Document {
+            check_id: check.id,
+            user_id: check.user_id,
+            file_name: check.file_name.clone(),
+            content_type: object.content_type,
+            bytes: object.bytes,
+        })
+        .await
+        .map_err(CheckError::Transient)
+}
+
+async fn advance(
+    options: &PlagiarismOptions,
+    name: &str,
+    provider: &dyn SimilarityProvider,
+    check: Model,
+) -> Result<(), DbErr> {
+    if !claim(&check).await? {
+        return Ok(());
+    }
+    let now = chrono::Utc::now().naive_utc();
+    let mut model = ActiveModel {
+        id: ActiveValue::unchanged(check.id),
+        next_attempt_at: ActiveValue::set(now),
+        ..Default::default()
+    };
+
+    let mut result = Ok(None);
+    let mut reference = check.reference.clone();
+    if check.status == CheckStatus::Queued {
+        match submit(&check, provider).await {
+            Ok(submitted) => {
+                model.status = ActiveValue::set(CheckStatus::Pending);
+                model.provider = ActiveValue::set(Some(name.to_string()));
+                model.reference = ActiveValue::set(Some(submitted.clone()));
+                reference = Some(submitted);
+            }
+            Err(e) => result = Err(e),
+        }
+    }
+    // Some providers report straight away, so a new submission is asked for its report too
+    if let (Ok(_), Some(reference)) = (&result, &reference) {
+        result = provider
+            .report(reference)
+            .await
+            .map_err(CheckError::Transient);
+    }
+
+    let mut finished = true;
+    match result {
+        Ok(Some(report)) => {
+            model.status = ActiveValue::set(CheckStatus::Complete);
+            model.similarity_percent =
+                ActiveValue::set(Some(report.similarity_percent.clamp(0.0, 100.0)));
+            model.report_url = ActiveValue::set(report.report_url);
+            model.last_error = ActiveValue::set(None);
+            model.completed_at = ActiveValue::set(Some(now));
+        }
+        Ok(None) if now - check.created_at > options.report_timeout() => {
+            model.status = ActiveValue::set(CheckStatus::Failed);
+            model.last_error = ActiveValue::set(Some("The provider did not report in time".into()));
+            model.completed_at = ActiveValue::set(Some(now));
+        }
+        Ok(None) => {
+            model.next_attempt_at =
+                ActiveValue::set(now + chrono::Duration::seconds(options.report_poll_secs as i64));
+            finished = false;
+        }
+        Err(CheckError::Permanent(e)) => {
+            warn!("Plagiarism check {} failed: {e:#}", check.id);
+            model.status = ActiveValue::set(CheckStatus::Failed);
+            model.last_error = ActiveValue::set(Some(format!("{e:#}")));
+            model.completed_at = ActiveValue::set(Some(now));
+        }
+        Err(CheckError::Transient(e)) => {
+            let attempts = check.attempts + 1;
+            warn!(
+                "Error making plagiarism check {} (attempt {attempts}): {e:#}",
+                check.id
+            );
+            model.attempts = ActiveValue::set(attempts);
+            model.last_error = ActiveValue::set(Some(format!("{e:#}")));
+            if attempts >= options.max_attempts {
+                model.status = ActiveValue::set(CheckStatus::Failed);
+                model.completed_at = ActiveValue::set(Some(now));
+            } else {
+                model.next_attempt_at = ActiveValue::set(now + retry_delay(options, attempts));
+                finished = false;
+            }
+        }
+    }
+    let check = model.update(get_db()).await?;
+    if finished {
+        let sinks = SINKS.lock().unwrap().clone();
+        for (name, sink) in sinks {
+            let check = check.clone();
+            tokio::spawn(async move {
+                if let Err(e) = sink(check).await {
+                    error!("Error handing a plagiarism check to {name}: {e:#}");
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn advance_due() -> anyhow::Result<()> {
+    let Some((name, provider)) = get_provider() else {
+        return Ok(());
+    };
+    let options = options();
+    let due = Entity::find()
+        .filter(Column::Status.is_in([CheckStatus::Queued, CheckStatus::Pending]))
+        .filter(Column::NextAttemptAt.lte(chrono::Utc::now().naive_utc()))
+        .order_by_asc(Column::NextAttemptAt)
+        .limit(BATCH_SIZE)
+        .all(get_db())
+        .await?;
+    for check in due {
+        advance(options, name, &*provider, check).await?;
+    }
+    Ok(())
+}
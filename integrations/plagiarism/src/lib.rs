@@ -0,0 +1,130 @@
+use std::{sync::OnceLock, time::Duration};
+
+use serde::Deserialize;
+use teach_tech_core::{
+    anyhow::{self, Context},
+    axum::routing::{get, post},
+    i18n, TeachCore,
+};
+
+pub mod api;
+pub mod checks;
+pub mod provider;
+
+static OPTIONS: OnceLock<PlagiarismOptions> = OnceLock::new();
+
+const MESSAGES: &[(&str, &str)] = &[
+    (
+        "plagiarism.must_be_staff",
+        "Only instructors and administrators can see similarity reports",
+    ),
+    ("plagiarism.not_failed", "Only failed checks can be retried"),
+];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PlagiarismConfig {
+    #[serde(default)]
+    pub plagiarism: PlagiarismOptions,
+}
+
+/// The `[plagiarism]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlagiarismOptions {
+    /// What checks submissions, from those added with [`provider::add_provider`]. Without one,
+    /// no checks are made
+    pub provider: Option<String>,
+    /// How often the queue is checked for submissions to send and reports to collect
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    /// The wait between asking the provider whether a report is ready
+    #[serde(default = "default_report_poll_secs")]
+    pub report_poll_secs: u64,
+    /// Attempts before a check is given up on
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: i32,
+    /// The wait before the first retry, doubling after every failed attempt
+    #[serde(default = "default_retry_delay_secs")]
+    pub retry_delay_secs: u64,
+    #[serde(default = "default_report_timeout_hours")]
+    pub report_timeout_hours: i64,
+}
+
+impl Default for PlagiarismOptions {
+    fn default() -> Self {
+        Self {
+            provider: None,
+            poll_interval_secs: default_poll_interval_secs(),
+            report_poll_secs: default_report_poll_secs(),
+            max_attempts: default_max_attempts(),
+            retry_delay_secs: default_retry_delay_secs(),
+            report_timeout_hours: default_report_timeout_hours(),
+        }
+    }
+}
+
+impl PlagiarismOptions {
+    pub(crate) fn report_timeout(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.report_timeout_hours)
+    }
+}
+
+fn default_poll_interval_secs() -> u64 {
+    60
+}
+
+fn default_report_poll_secs() -> u64 {
+    300
+}
+
+fn default_max_attempts() -> i32 {
+    5
+}
+
+fn default_retry_delay_secs() -> u64 {
+    60
+}
+
+fn default_report_timeout_hours() -> i64 {
+    48
+}
+
+pub(crate) fn options() -> &'static PlagiarismOptions {
+    OPTIONS.get_or_init(PlagiarismOptions::default)
+}
+
+pub async fn add_to_core<S: Clone + Send + Sync + 'static>(
+    mut core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    let mut info = fxhash::FxHashMap::default();
+    info.insert("version", env!("CARGO_PKG_VERSION"));
+    core.add_info("plagiarism", info);
+    i18n::add_catalog(i18n::FALLBACK_LOCALE, MESSAGES.iter().copied());
+    core.declare_config::<PlagiarismConfig>();
+    let config: PlagiarismConfig = toml::from_str(core.get_config_str())?;
+    core.add_db_reset_config(checks::Entity);
+    if config.plagiarism.poll_interval_secs == 0 || config.plagiarism.max_attempts < 1 {
+        return Err(anyhow::anyhow!(
+            "plagiarism.poll_interval_secs and plagiarism.max_attempts must be at least 1"
+        ));
+    }
+    let poll_interval = Duration::from_secs(config.plagiarism.poll_interval_secs);
+    let provider = config.plagiarism.provider.clone();
+    let _ = OPTIONS.set(config.plagiarism);
+
+    core = core.modify_router(|router| {
+        router
+            .route("/plagiarism/checks", get(api::list_checks))
+            .route("/plagiarism/checks/:id", get(api::get_check))
+            .route("/plagiarism/checks/:id/retry", post(api::retry_check))
+    });
+
+    // Providers come from other integrations, which may be added after this one
+    if let Some(name) = provider {
+        core.add_on_serve_named("plagiarism", 0, move || async move {
+            provider::select(&name).with_context(|| format!("Selecting plagiarism.provider {name}"))
+        });
+        core.add_scheduled_task("plagiarism checks", poll_interval, checks::advance_due);
+    }
+
+    Ok(core)
+}
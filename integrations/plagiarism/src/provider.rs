@@ -0,0 +1,63 @@
+use std::sync::{Arc, Mutex, OnceLock};
+
+use futures::future::BoxFuture;
+use teach_tech_core::{anyhow, auth::UserID};
+
+static PROVIDERS: Mutex<Vec<(String, Arc<dyn SimilarityProvider>)>> = Mutex::new(Vec::new());
+static PROVIDER: OnceLock<(String, Arc<dyn SimilarityProvider>)> = OnceLock::new();
+
+/// A submission sent to be checked.
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub check_id: i32,
+    /// The author of the submission
+    pub user_id: UserID,
+    pub file_name: String,
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+}
+
+/// What a provider found.
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// How much of the submission matches other sources, from 0 to 100
+    pub similarity_percent: f64,
+    /// Where graders can see the matches
+    pub report_url: Option<String>,
+}
+
+/// Compares submissions against other work, such as Turnitin or Copyleaks.
+pub trait SimilarityProvider: Send + Sync + 'static {
+    /// Sends a document to be checked, returning how the provider refers to the check.
+    fn submit(&self, document: Document) -> BoxFuture<'static, anyhow::Result<String>>;
+
+    /// The report of a submitted document, or `None` while the provider is still checking it.
+    fn report(&self, reference: &str) -> BoxFuture<'static, anyhow::Result<Option<Report>>>;
+}
+
+/// Makes a provider available to `plagiarism.provider` under `name`.
+pub fn add_provider(name: impl Into<String>, provider: impl SimilarityProvider) {
+    PROVIDERS
+        .lock()
+        .unwrap()
+        .push((name.into(), Arc::new(provider)));
+}
+
+pub(crate) fn select(name: &str) -> anyhow::Result<()> {
+    let provider = PROVIDERS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(provider_name, _)| provider_name == name)
+        .map(|(_, provider)| provider.clone())
+        .ok_or_else(|| anyhow::anyhow!("plagiarism.provider {name} was not added"))?;
+    let _ = PROVIDER.set((name.to_string(), provider));
+    Ok(())
+}
+
+/// The provider in use and its name, if checks are configured.
+pub fn get_provider() -> Option<(&'static str, Arc<dyn SimilarityProvider>)> {
+    PROVIDER
+        .get()
+        .map(|(name, provider)| (name.as_str(), provider.clone()))
+}
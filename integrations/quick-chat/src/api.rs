@@ -0,0 +1,820 @@
+use std::fmt::Display;
+
+use fxhash::FxHashMap;
+use sea_orm::{
+    entity::prelude::*,
+    sea_query::{LikeExpr, SimpleExpr},
+    ActiveValue, DbBackend, QueryOrder,
+};
+use serde::{Deserialize, Serialize};
+use teach_tech_core::{
+    auth::{user_auth, AdminUser, AuthUser, UserID},
+    axum::{
+        extract::{Path, Query},
+        http::StatusCode,
+        response::{IntoResponse, Response},
+        Json,
+    },
+    db::{
+        get_db, paginate, stream_export, transaction_with_retry, ExportQuery, PageQuery, Paginated,
+    },
+    i18n,
+};
+use tracing::error;
+
+use crate::{
+    conversations::{
+        self, direct_key,
+        members::{self, MemberRole},
+        ConversationKind,
+    },
+    db, flood, messages,
+    moderation::{self, can_moderate, ModerationAction},
+    policy::{self, blocks},
+    presence,
+    protocol::ServerFrame,
+    socket::fan_out,
+};
+
+pub(crate) fn internal_error(context: &str, e: impl Display) -> Response {
+    error!("{context}: {e:#}");
+    (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConversationInfo {
+    #[serde(flatten)]
+    pub conversation: conversations::Model,
+    pub members: Vec<members::Model>,
+}
+
+async fn with_members(
+    conversations: Vec<conversations::Model>,
+) -> Result<Vec<ConversationInfo>, DbErr> {
+    let mut members: FxHashMap<i32, Vec<members::Model>> = FxHashMap::default();
+    for member in members::Entity::find()
+        .filter(members::Column::ConversationId.is_in(conversations.iter().map(|c| c.id)))
+        .all(db())
+        .await?
+    {
+        members
+            .entry(member.conversation_id)
+            .or_default()
+            .push(member);
+    }
+    Ok(conversations
+        .into_iter()
+        .map(|conversation| ConversationInfo {
+            members: members.remove(&conversation.id).unwrap_or_default(),
+            conversation,
+        })
+        .collect())
+}
+
+async fn users_exist(user_ids: &[UserID]) -> Result<bool, DbErr> {
+    let count = user_auth::Entity::find()
+        .filter(user_auth::Column::UserId.is_in(user_ids.iter().copied()))
+        .count(get_db())
+        .await?;
+    Ok(count == user_ids.len() as u64)
+}
+
+pub async fn list_conversations(AuthUser { user_id, .. }: AuthUser) -> Response {
+    let result: Result<_, DbErr> = async {
+        let ids: Vec<_> = members::Entity::find()
+            .filter(members::Column::UserId.eq(user_id))
+            .all(db())
+            .await?
+            .into_iter()
+            .map(|member| member.conversation_id)
+            .collect();
+        let conversations = conversations::Entity::find()
+            .filter(conversations::Column::Id.is_in(ids))
+            .all(db())
+            .await?;
+        with_members(conversations).await
+    }
+    .await;
+    match result {
+        Ok(conversations) => (StatusCode::OK, Json(conversations)).into_response(),
+        Err(e) => internal_error("Error listing conversations", e),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateConversation {
+    pub kind: ConversationKind,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub course: Option<String>,
+    /// Members other than the creator
+    #[serde(default)]
+    pub members: Vec<UserID>,
+}
+
+pub async fn create_conversation(
+    AuthUser { user_id, .. }: AuthUser,
+    Json(mut create): Json<CreateConversation>,
+) -> Response {
+    create.members.sort_by_key(|&member| i32::from(member));
+    create.members.dedup();
+    create.members.retain(|&member| member != user_id);
+
+    let mut key = None;
+    match create.kind {
+        ConversationKind::Direct => {
+            let [peer] = create.members[..] else {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    i18n::t("quick_chat.direct_member_count"),
+                )
+                    .into_response();
+            };
+            let direct_key = direct_key(user_id, peer);
+            match conversations::Entity::find()
+                .filter(conversations::Column::DirectKey.eq(&direct_key))
+                .one(db())
+                .await
+            {
+                Ok(Some(existing)) => {
+                    return match with_members(vec![existing]).await {
+                        Ok(mut info) => (StatusCode::OK, Json(info.remove(0))).into_response(),
+                        Err(e) => internal_error("Error reading conversation", e),
+                    };
+                }
+                Ok(None) => {}
+                Err(e) => return internal_error("Error reading conversation", e),
+            }
+            key = Some(direct_key);
+        }
+        ConversationKind::Group => {}
+        ConversationKind::Channel => match policy::is_staff(user_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return (StatusCode::FORBIDDEN, i18n::t("quick_chat.must_be_staff")).into_response()
+            }
+            Err(e) => return internal_error("Error reading user roles", e),
+        },
+    }
+    if create.course.is_some() && create.kind != ConversationKind::Channel {
+        return (
+            StatusCode::BAD_REQUEST,
+            i18n::t("quick_chat.course_needs_channel"),
+        )
+            .into_response();
+    }
+    match users_exist(&create.members).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                i18n::t("quick_chat.unknown_member"),
+            )
+                .into_response()
+        }
+        Err(e) => return internal_error("Error reading users", e),
+    }
+    // Channels are managed by staff, so their membership is the policy
+    if create.kind != ConversationKind::Channel {
+        match policy::check_contacts(user_id, create.members.iter().copied()).await {
+            Ok(None) => {}
+            Ok(Some(reason)) => return (StatusCode::FORBIDDEN, reason).into_response(),
+            Err(e) => return internal_error("Error checking contact policies", e),
+        }
+    }
+
+    let result = transaction_with_retry(db(), |txn| {
+        let create = create.clone();
+        let key = key.clone();
+        Box::pin(async move {
+            let now = chrono::Utc::now().naive_utc();
+            let conversation = conversations::ActiveModel {
+                id: ActiveValue::not_set(),
+                kind: ActiveValue::set(create.kind),
+                name: ActiveValue::set(create.name),
+                course: ActiveValue::set(create.course),
+                direct_key: ActiveValue::set(key),
+                created_by: ActiveValue::set(user_id),
+                created_at: ActiveValue::set(now),
+            }
+            .insert(txn)
+            .await?;
+            // Neither side owns a direct conversation
+            let creator_role = if create.kind == ConversationKind::Direct {
+                MemberRole::Member
+            } else {
+                MemberRole::Owner
+            };
+            let members = std::iter::once((user_id, creator_role))
+                .chain(
+                    create
+                        .members
+                        .iter()
+                        .map(|&member| (member, MemberRole::Member)),
+                )
+                .map(|(member, role)| members::ActiveModel {
+                    conversation_id: ActiveValue::set(conversation.id),
+                    user_id: ActiveValue::set(member),
+                    role: ActiveValue::set(role),
+                    last_read: ActiveValue::set(None),
+                    last_delivered: ActiveValue::set(None),
+                    joined_at: ActiveValue::set(now),
+                    muted_until: ActiveValue::set(None),
+                });
+            members::Entity::insert_many(members).exec(txn).await?;
+            Ok(conversation)
+        })
+    })
+    .await;
+    let conversation = match result {
+        Ok(conversation) => conversation,
+        Err(e) => return internal_error("Error creating conversation", e),
+    };
+    match with_members(vec![conversation]).await {
+        Ok(mut info) => (StatusCode::CREATED, Json(info.remove(0))).into_response(),
+        Err(e) => internal_error("Error reading conversation", e),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadState {
+    pub user_id: UserID,
+    pub last_read: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct History {
+    #[serde(flatten)]
+    pub page: Paginated<messages::Model>,
+    /// How far each member has read
+    pub read: Vec<ReadState>,
+}
+
+pub async fn history(
+    AuthUser { user_id, .. }: AuthUser,
+    Path(conversation_id): Path<i32>,
+    Query(page): Query<PageQuery>,
+) -> Response {
+    match conversations::membership(conversation_id, user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => return internal_error("Error reading conversation members", e),
+    }
+    let Ok(cursor) = page.cursor::<i32>() else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_cursor")).into_response();
+    };
+    let result: Result<_, DbErr> = async {
+        let page = paginate(
+            messages::Entity::find().filter(messages::Column::ConversationId.eq(conversation_id)),
+            messages::Column::Id,
+            |model| model.id,
+            cursor,
+            page.limit(),
+            db(),
+        )
+        .await?;
+        let read = members::Entity::find()
+            .filter(members::Column::ConversationId.eq(conversation_id))
+            .all(db())
+            .await?
+            .into_iter()
+            .map(|member| ReadState {
+                user_id: member.user_id,
+                last_read: member.last_read,
+            })
+            .collect();
+        Ok(History { page, read })
+    }
+    .await;
+    match result {
+        Ok(history) => (StatusCode::OK, Json(history)).into_response(),
+        Err(e) => internal_error("Error reading chat history", e),
+    }
+}
+
+// Returns the conversation if the user may change its members
+async fn managed_conversation(
+    conversation_id: i32,
+    user_id: UserID,
+) -> Result<conversations::Model, Response> {
+    let membership = match conversations::membership(conversation_id, user_id).await {
+        Ok(Some(membership)) => membership,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, ()).into_response()),
+        Err(e) => return Err(internal_error("Error reading conversation members", e)),
+    };
+    let conversation = match conversations::Entity::find_by_id(conversation_id)
+        .one(db())
+        .await
+    {
+        Ok(Some(conversation)) => conversation,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, ()).into_response()),
+        Err(e) => return Err(internal_error("Error reading conversation", e)),
+    };
+    if conversation.kind == ConversationKind::Direct {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            i18n::t("quick_chat.direct_members_fixed"),
+        )
+            .into_response());
+    }
+    if membership.role != MemberRole::Owner {
+        return Err((StatusCode::FORBIDDEN, i18n::t("quick_chat.must_be_owner")).into_response());
+    }
+    Ok(conversation)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddMembers {
+    pub user_ids: Vec<UserID>,
+}
+
+pub async fn add_members(
+    AuthUser { user_id, .. }: AuthUser,
+    Path(conversation_id): Path<i32>,
+    Json(AddMembers { mut user_ids }): Json<AddMembers>,
+) -> Response {
+    let conversation = match managed_conversation(conversation_id, user_id).await {
+        Ok(conversation) => conversation,
+        Err(response) => return response,
+    };
+    user_ids.sort_by_key(|&member| i32::from(member));
+    user_ids.dedup();
+    match users_exist(&user_ids).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                i18n::t("quick_chat.unknown_member"),
+            )
+                .into_response()
+        }
+        Err(e) => return internal_error("Error reading users", e),
+    }
+    if conversation.kind != ConversationKind::Channel {
+        match policy::check_contacts(user_id, user_ids.iter().copied()).await {
+            Ok(None) => {}
+            Ok(Some(reason)) => return (StatusCode::FORBIDDEN, reason).into_response(),
+            Err(e) => return internal_error("Error checking contact policies", e),
+        }
+    }
+
+    let result: Result<_, DbErr> = async {
+        let existing = conversations::member_ids(conversation_id).await?;
+        let now = chrono::Utc::now().naive_utc();
+        let new_members: Vec<_> = user_ids
+            .into_iter()
+            .filter(|member| !existing.contains(member))
+            .map(|member| members::ActiveModel {
+                conversation_id: ActiveValue::set(conversation_id),
+                user_id: ActiveValue::set(member),
+                role: ActiveValue::set(MemberRole::Member),
+                last_read: ActiveValue::set(None),
+                last_delivered: ActiveValue::set(None),
+                joined_at: ActiveValue::set(now),
+                muted_until: ActiveValue::set(None),
+            })
+            .collect();
+        if !new_members.is_empty() {
+            members::Entity::insert_many(new_members).exec(db()).await?;
+        }
+        with_members(vec![conversation]).await
+    }
+    .await;
+    match result {
+        Ok(mut info) => (StatusCode::OK, Json(info.remove(0))).into_response(),
+        Err(e) => internal_error("Error adding conversation members", e),
+    }
+}
+
+/// Owners may remove anyone, and members may remove themselves to leave.
+pub async fn remove_member(
+    AuthUser { user_id, .. }: AuthUser,
+    Path((conversation_id, member)): Path<(i32, UserID)>,
+) -> Response {
+    if member != user_id {
+        if let Err(response) = managed_conversation(conversation_id, user_id).await {
+            return response;
+        }
+    } else {
+        match conversations::Entity::find_by_id(conversation_id)
+            .one(db())
+            .await
+        {
+            Ok(Some(conversation)) if conversation.kind == ConversationKind::Direct => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    i18n::t("quick_chat.cannot_leave_direct"),
+                )
+                    .into_response()
+            }
+            Ok(_) => {}
+            Err(e) => return internal_error("Error reading conversation", e),
+        }
+    }
+    match members::Entity::delete_by_id((conversation_id, member))
+        .exec(db())
+        .await
+    {
+        Ok(result) if result.rows_affected == 0 => (StatusCode::NOT_FOUND, ()).into_response(),
+        Ok(_) => (StatusCode::OK, ()).into_response(),
+        Err(e) => internal_error("Error removing conversation member", e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PresenceQuery {
+    /// Comma separated user IDs
+    pub users: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Presence {
+    pub user_id: UserID,
+    pub online: bool,
+}
+
+pub async fn presence(
+    _: AuthUser,
+    Query(PresenceQuery { users }): Query<PresenceQuery>,
+) -> Response {
+    let users: Option<Vec<UserID>> = users
+        .split(',')
+        .filter(|user| !user.is_empty())
+        .map(|user| user.trim().parse::<u32>().ok()?.try_into().ok())
+        .collect();
+    let Some(users) = users else {
+        return (
+            StatusCode::BAD_REQUEST,
+            i18n::t("quick_chat.invalid_user_id"),
+        )
+            .into_response();
+    };
+    let presence: Vec<_> = users
+        .into_iter()
+        .map(|user_id| Presence {
+            user_id,
+            online: presence::is_online(user_id),
+        })
+        .collect();
+    (StatusCode::OK, Json(presence)).into_response()
+}
+
+const PREVIEW_LEN: usize = 100;
+
+#[derive(Debug, Serialize)]
+pub struct Preview {
+    pub id: i32,
+    pub from: UserID,
+    pub date: DateTime,
+    /// The start of the message, cut to 100 characters
+    pub preview: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Unread {
+    pub conversation_id: i32,
+    pub unread: u64,
+    pub latest: Option<Preview>,
+}
+
+pub async fn unread(AuthUser { user_id, .. }: AuthUser) -> Response {
+    let result: Result<Vec<_>, DbErr> = async {
+        let memberships = members::Entity::find()
+            .filter(members::Column::UserId.eq(user_id))
+            .all(db())
+            .await?;
+        let mut unread = Vec::with_capacity(memberships.len());
+        for membership in memberships {
+            let visible = messages::Entity::find()
+                .filter(messages::Column::ConversationId.eq(membership.conversation_id))
+                .filter(messages::Column::RedactedAt.is_null());
+            let count = visible
+                .clone()
+                .filter(messages::Column::Id.gt(membership.last_read.unwrap_or(0)))
+                .filter(messages::Column::From.ne(user_id))
+                .count(db())
+                .await?;
+            let latest = visible
+                .order_by_desc(messages::Column::Id)
+                .one(db())
+                .await?
+                .map(|message| Preview {
+                    id: message.id,
+                    from: message.from,
+                    date: message.date,
+                    preview: message.message.chars().take(PREVIEW_LEN).collect(),
+                });
+            unread.push(Unread {
+                conversation_id: membership.conversation_id,
+                unread: count,
+                latest,
+            });
+        }
+        Ok(unread)
+    }
+    .await;
+    match result {
+        Ok(unread) => (StatusCode::OK, Json(unread)).into_response(),
+        Err(e) => internal_error("Error counting unread messages", e),
+    }
+}
+
+const MAX_QUERY_LEN: usize = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    /// Only messages sent by this user
+    pub peer: Option<UserID>,
+    pub conversation_id: Option<i32>,
+    pub after: Option<DateTime>,
+    pub before: Option<DateTime>,
+}
+
+// Postgres has full-text search built in, while the other backends fall back to substring
+// matching, which their default collations make case-insensitive
+fn text_matches(q: &str) -> SimpleExpr {
+    match db().get_database_backend() {
+        DbBackend::Postgres => Expr::cust_with_values(
+            "to_tsvector('simple', \"message\") @@ plainto_tsquery('simple', $1)",
+            [q],
+        ),
+        DbBackend::MySql | DbBackend::Sqlite => {
+            let escaped = q
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            Expr::col(messages::Column::Message)
+                .like(LikeExpr::new(format!("%{escaped}%")).escape('\\'))
+        }
+    }
+}
+
+/// Searches the messages of every conversation the user is a member of.
+pub async fn search(
+    AuthUser { user_id, .. }: AuthUser,
+    Query(search): Query<SearchQuery>,
+    Query(page): Query<PageQuery>,
+) -> Response {
+    let q = search.q.trim();
+    if q.is_empty() || q.chars().count() > MAX_QUERY_LEN {
+        return (
+            StatusCode::BAD_REQUEST,
+            i18n::t_with(
+                "quick_chat.query_length",
+                &[("max", &MAX_QUERY_LEN.to_string())],
+            ),
+        )
+            .into_response();
+    }
+    let Ok(cursor) = page.cursor::<i32>() else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_cursor")).into_response();
+    };
+
+    let result: Result<_, DbErr> = async {
+        let conversation_ids: Vec<_> = members::Entity::find()
+            .filter(members::Column::UserId.eq(user_id))
+            .all(db())
+            .await?
+            .into_iter()
+            .map(|member| member.conversation_id)
+            .filter(|&id| search.conversation_id.map_or(true, |wanted| wanted == id))
+            .collect();
+        let mut select = messages::Entity::find()
+            .filter(messages::Column::ConversationId.is_in(conversation_ids))
+            .filter(messages::Column::RedactedAt.is_null())
+            .filter(text_matches(q));
+        if let Some(peer) = search.peer {
+            select = select.filter(messages::Column::From.eq(peer));
+        }
+        if let Some(after) = search.after {
+            select = select.filter(messages::Column::Date.gte(after));
+        }
+        if let Some(before) = search.before {
+            select = select.filter(messages::Column::Date.lt(before));
+        }
+        paginate(
+            select,
+            messages::Column::Id,
+            |model| model.id,
+            cursor,
+            page.limit(),
+            db(),
+        )
+        .await
+    }
+    .await;
+    match result {
+        Ok(messages) => (StatusCode::OK, Json(messages)).into_response(),
+        Err(e) => internal_error("Error searching messages", e),
+    }
+}
+
+/// Removes the content of a message, keeping the row so that the conversation still shows where
+/// it was.
+pub async fn redact_message(
+    AuthUser { user_id, .. }: AuthUser,
+    Path(message_id): Path<i32>,
+) -> Response {
+    let message = match messages::Entity::find_by_id(message_id).one(db()).await {
+        Ok(Some(message)) => message,
+        Ok(None) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => return internal_error("Error reading message", e),
+    };
+    match can_moderate(user_id, message.conversation_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return (
+                StatusCode::FORBIDDEN,
+                i18n::t("quick_chat.must_be_moderator"),
+            )
+                .into_response()
+        }
+        Err(e) => return internal_error("Error reading user roles", e),
+    }
+    if message.redacted_at.is_some() {
+        return (StatusCode::OK, ()).into_response();
+    }
+
+    let result: Result<_, DbErr> = async {
+        messages::ActiveModel {
+            id: ActiveValue::unchanged(message_id),
+            message: ActiveValue::set(String::new()),
+            redacted_at: ActiveValue::set(Some(chrono::Utc::now().naive_utc())),
+            redacted_by: ActiveValue::set(Some(user_id)),
+            ..Default::default()
+        }
+        .update(db())
+        .await?;
+        moderation::record(
+            Some(user_id),
+            ModerationAction::Redact,
+            message.conversation_id,
+            Some(message_id),
+            Some(message.from),
+            None,
+        )
+        .await?;
+        fan_out(
+            message.conversation_id,
+            user_id,
+            &ServerFrame::Redacted {
+                id: message_id,
+                conversation_id: message.conversation_id,
+            },
+        )
+        .await
+    }
+    .await;
+    match result {
+        Ok(_) => (StatusCode::OK, ()).into_response(),
+        Err(e) => internal_error("Error redacting message", e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Mute {
+    pub minutes: u32,
+}
+
+async fn set_muted_until(
+    user_id: UserID,
+    conversation_id: i32,
+    member: UserID,
+    muted_until: Option<DateTime>,
+) -> Response {
+    match can_moderate(user_id, conversation_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return (
+                StatusCode::FORBIDDEN,
+                i18n::t("quick_chat.must_be_moderator"),
+            )
+                .into_response()
+        }
+        Err(e) => return internal_error("Error reading user roles", e),
+    }
+    let result: Result<_, DbErr> = async {
+        if conversations::membership(conversation_id, member)
+            .await?
+            .is_none()
+        {
+            return Ok(false);
+        }
+        members::ActiveModel {
+            conversation_id: ActiveValue::unchanged(conversation_id),
+            user_id: ActiveValue::unchanged(member),
+            muted_until: ActiveValue::set(muted_until),
+            ..Default::default()
+        }
+        .update(db())
+        .await?;
+        let (action, details) = match muted_until {
+            Some(until) => (ModerationAction::Mute, Some(format!("Until {until}"))),
+            None => (ModerationAction::Unmute, None),
+        };
+        moderation::record(
+            Some(user_id),
+            action,
+            conversation_id,
+            None,
+            Some(member),
+            details,
+        )
+        .await?;
+        Ok(true)
+    }
+    .await;
+    match result {
+        Ok(true) => (StatusCode::OK, ()).into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => internal_error("Error muting conversation member", e),
+    }
+}
+
+pub async fn mute_member(
+    AuthUser { user_id, .. }: AuthUser,
+    Path((conversation_id, member)): Path<(i32, UserID)>,
+    Json(Mute { minutes }): Json<Mute>,
+) -> Response {
+    let until = chrono::Utc::now().naive_utc() + chrono::TimeDelta::minutes(minutes as i64);
+    set_muted_until(user_id, conversation_id, member, Some(until)).await
+}
+
+pub async fn unmute_member(
+    AuthUser { user_id, .. }: AuthUser,
+    Path((conversation_id, member)): Path<(i32, UserID)>,
+) -> Response {
+    set_muted_until(user_id, conversation_id, member, None).await
+}
+
+/// Lists users who have hit the chat rate limit on this node.
+pub async fn flood_report(_: AdminUser) -> Response {
+    (StatusCode::OK, Json(flood::report())).into_response()
+}
+
+/// Streams the whole moderation log, for audits.
+pub async fn export_moderation_log(_: AdminUser, Query(export): Query<ExportQuery>) -> Response {
+    stream_export(
+        "quick-chat-moderation-log",
+        moderation::Entity::find(),
+        moderation::Column::Id,
+        |entry| entry.id,
+        export.format,
+        db(),
+    )
+}
+
+pub async fn list_blocks(AuthUser { user_id, .. }: AuthUser) -> Response {
+    match blocks::Entity::find()
+        .filter(blocks::Column::UserId.eq(user_id))
+        .all(db())
+        .await
+    {
+        Ok(blocks) => (StatusCode::OK, Json(blocks)).into_response(),
+        Err(e) => internal_error("Error listing blocked users", e),
+    }
+}
+
+pub async fn block_user(
+    AuthUser { user_id, .. }: AuthUser,
+    Path(blocked): Path<UserID>,
+) -> Response {
+    if blocked == user_id {
+        return (
+            StatusCode::BAD_REQUEST,
+            i18n::t("quick_chat.cannot_block_self"),
+        )
+            .into_response();
+    }
+    match policy::is_blocked(user_id, blocked).await {
+        Ok(true) => return (StatusCode::OK, ()).into_response(),
+        Ok(false) => {}
+        Err(e) => return internal_error("Error reading blocked users", e),
+    }
+    let result = blocks::ActiveModel {
+        user_id: ActiveValue::set(user_id),
+        blocked: ActiveValue::set(blocked),
+        created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+    }
+    .insert(db())
+    .await;
+    match result {
+        Ok(_) => (StatusCode::OK, ()).into_response(),
+        Err(e) => internal_error("Error blocking user", e),
+    }
+}
+
+pub async fn unblock_user(
+    AuthUser { user_id, .. }: AuthUser,
+    Path(blocked): Path<UserID>,
+) -> Response {
+    match blocks::Entity::delete_by_id((user_id, blocked))
+        .exec(db())
+        .await
+    {
+        Ok(_) => (StatusCode::OK, ()).into_response(),
+        Err(e) => internal_error("Error unblocking user", e),
+    }
+}
@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use rand::{thread_rng, Rng};
+use sea_orm::{entity::prelude::*, ActiveValue};
+use serde::{Deserialize, Serialize};
+use teach_tech_core::{
+    auth::{AuthUser, UserID},
+    axum::{
+        body::Bytes,
+        extract::{Path, Query},
+        http::{header, HeaderMap, StatusCode},
+        response::{IntoResponse, Redirect, Response},
+        Json,
+    },
+    i18n,
+    storage::{get_storage, put_upload},
+};
+
+use crate::{api::internal_error, conversations, db};
+
+/// How long a download link handed to a member lasts
+const LINK_LIFETIME: Duration = Duration::from_secs(15 * 60);
+const MAX_FILE_NAME_LEN: usize = 255;
+
+/// A file uploaded to a conversation. Messages refer to it by its id.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "quick_chat_attachments")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub conversation_id: i32,
+    pub uploaded_by: UserID,
+    #[serde(skip_serializing)]
+    pub key: String,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: i64,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadQuery {
+    pub name: String,
+}
+
+fn storage_unavailable() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        i18n::t("error.storage_unavailable"),
+    )
+        .into_response()
+}
+
+pub async fn upload(
+    AuthUser { user_id, .. }: AuthUser,
+    Path(conversation_id): Path<i32>,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    match conversations::membership(conversation_id, user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => return internal_error("Error reading conversation members", e),
+    }
+    let Some(storage) = get_storage() else {
+        return storage_unavailable();
+    };
+    let file_name = query.name.trim();
+    if file_name.is_empty()
+        || file_name.len() > MAX_FILE_NAME_LEN
+        || file_name.contains(['/', '\\'])
+        || file_name.chars().any(char::is_control)
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            i18n::t("quick_chat.invalid_file_name"),
+        )
+            .into_response();
+    }
+    if body.is_empty() {
+        return (StatusCode::BAD_REQUEST, i18n::t("quick_chat.empty_file")).into_response();
+    }
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+
+    // The file name stays out of the key, which only allows a few characters
+    let key = format!(
+        "quick-chat/{conversation_id}/{:032x}",
+        thread_rng().gen::<u128>()
+    );
+    let size = body.len() as i64;
+    if let Err(e) = put_upload(&key, body.to_vec(), &content_type, user_id).await {
+        return e.into_response();
+    }
+    let result = ActiveModel {
+        id: ActiveValue::not_set(),
+        conversation_id: ActiveValue::set(conversation_id),
+        uploaded_by: ActiveValue::set(user_id),
+        key: ActiveValue::set(key.clone()),
+        file_name: ActiveValue::set(file_name.to_string()),
+        content_type: ActiveValue::set(content_type),
+        size: ActiveValue::set(size),
+        created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+    }
+    .insert(db())
+    .await;
+    match result {
+        Ok(attachment) => (StatusCode::CREATED, Json(attachment)).into_response(),
+        Err(e) => {
+            // Nothing refers to the file yet
+            let _ = storage.delete(&key).await;
+            internal_error("Error saving chat attachment", e)
+        }
+    }
+}
+
+/// Redirects members of the conversation to a short-lived download link.
+pub async fn download(AuthUser { user_id, .. }: AuthUser, Path(id): Path<i32>) -> Response {
+    let attachment = match Entity::find_by_id(id).one(db()).await {
+        Ok(Some(attachment)) => attachment,
+        Ok(None) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => return internal_error("Error reading chat attachment", e),
+    };
+    match conversations::membership(attachment.conversation_id, user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => return internal_error("Error reading conversation members", e),
+    }
+    let Some(storage) = get_storage() else {
+        return storage_unavailable();
+    };
+    match storage.signed_url(&attachment.key, LINK_LIFETIME) {
+        Ok(url) => Redirect::temporary(&url).into_response(),
+        Err(e) => internal_error("Error signing chat attachment link", e),
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    LazyLock, Mutex,
+};
+
+use fxhash::{FxHashMap, FxHashSet};
+use teach_tech_core::{auth::UserID, tokio::sync::mpsc::UnboundedSender};
+
+use crate::{presence, protocol::ServerFrame};
+
+static CONNECTIONS: LazyLock<Mutex<FxHashMap<UserID, Vec<Connection>>>> =
+    LazyLock::new(Default::default);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Connection {
+    id: u64,
+    sender: UnboundedSender<ServerFrame>,
+    /// Users whose presence changes are pushed to this socket
+    watching: FxHashSet<UserID>,
+}
+
+/// An open socket of a user. The socket is forgotten when this is dropped.
+pub struct ConnectionGuard {
+    user_id: UserID,
+    id: u64,
+}
+
+impl ConnectionGuard {
+    pub fn set_watching(&self, users: impl IntoIterator<Item = UserID>) {
+        let mut connections = CONNECTIONS.lock().unwrap();
+        let connection = connections
+            .get_mut(&self.user_id)
+            .and_then(|user_connections| {
+                user_connections
+                    .iter_mut()
+                    .find(|connection| connection.id == self.id)
+            });
+        if let Some(connection) = connection {
+            connection.watching = users.into_iter().collect();
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let went_offline = {
+            let mut connections = CONNECTIONS.lock().unwrap();
+            let Some(user_connections) = connections.get_mut(&self.user_id) else {
+                return;
+            };
+            user_connections.retain(|connection| connection.id != self.id);
+            if user_connections.is_empty() {
+                connections.remove(&self.user_id);
+                true
+            } else {
+                false
+            }
+        };
+        if went_offline {
+            presence::local_changed(self.user_id, false);
+        }
+    }
+}
+
+pub fn register(user_id: UserID, sender: UnboundedSender<ServerFrame>) -> ConnectionGuard {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let came_online = {
+        let mut connections = CONNECTIONS.lock().unwrap();
+        let user_connections = connections.entry(user_id).or_default();
+        user_connections.push(Connection {
+            id,
+            sender,
+            watching: FxHashSet::default(),
+        });
+        user_connections.len() == 1
+    };
+    if came_online {
+        presence::local_changed(user_id, true);
+    }
+    ConnectionGuard { user_id, id }
+}
+
+/// Sends a frame to every open socket of the user, returning whether any socket received it.
+pub fn deliver(user_id: UserID, frame: &ServerFrame) -> bool {
+    let connections = CONNECTIONS.lock().unwrap();
+    let Some(user_connections) = connections.get(&user_id) else {
+        return false;
+    };
+    let mut delivered = false;
+    for connection in user_connections {
+        delivered |= connection.sender.send(frame.clone()).is_ok();
+    }
+    delivered
+}
+
+pub fn is_connected(user_id: UserID) -> bool {
+    CONNECTIONS.lock().unwrap().contains_key(&user_id)
+}
+
+pub fn connected_users() -> Vec<UserID> {
+    CONNECTIONS.lock().unwrap().keys().copied().collect()
+}
+
+/// Sends a frame to every socket watching the user.
+pub fn notify_watchers(user_id: UserID, frame: &ServerFrame) {
+    for connection in CONNECTIONS.lock().unwrap().values().flatten() {
+        if connection.watching.contains(&user_id) {
+            let _ = connection.sender.send(frame.clone());
+        }
+    }
+}
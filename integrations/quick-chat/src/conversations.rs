@@ -0,0 +1,118 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+use teach_tech_core::auth::UserID;
+
+use crate::db;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "quick_chat_conversations")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub kind: ConversationKind,
+    pub name: Option<String>,
+    /// The course a channel belongs to
+    pub course: Option<String>,
+    /// Identifies the two users of a direct conversation, so that there is only one per pair
+    #[sea_orm(unique)]
+    #[serde(skip_serializing)]
+    pub direct_key: Option<String>,
+    pub created_by: UserID,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(EnumIter, DeriveActiveEnum, Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[sea_orm(rs_type = "i32", db_type = "Integer")]
+#[serde(rename_all = "snake_case")]
+pub enum ConversationKind {
+    /// Exactly two users
+    Direct = 0,
+    /// An ad-hoc group managed by its owners
+    Group = 1,
+    /// A channel created by an instructor or admin, usually for a course
+    Channel = 2,
+}
+
+pub fn direct_key(a: UserID, b: UserID) -> String {
+    let (a, b) = (
+        i32::from(a).min(i32::from(b)),
+        i32::from(a).max(i32::from(b)),
+    );
+    format!("{a}:{b}")
+}
+
+pub mod members {
+    use sea_orm::entity::prelude::*;
+    use serde::Serialize;
+    use teach_tech_core::auth::UserID;
+
+    #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+    #[sea_orm(table_name = "quick_chat_conversation_members")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub conversation_id: i32,
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub user_id: UserID,
+        pub role: MemberRole,
+        /// The latest message the member has read
+        pub last_read: Option<i32>,
+        /// The latest message pushed to an open socket of the member
+        #[serde(skip_serializing)]
+        pub last_delivered: Option<i32>,
+        pub joined_at: DateTime,
+        /// The member cannot send messages until then
+        pub muted_until: Option<DateTime>,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    #[derive(EnumIter, DeriveActiveEnum, Clone, Debug, Copy, PartialEq, Eq, Serialize)]
+    #[sea_orm(rs_type = "i32", db_type = "Integer")]
+    #[serde(rename_all = "snake_case")]
+    pub enum MemberRole {
+        Owner = 0,
+        Member = 1,
+    }
+}
+
+pub async fn membership(
+    conversation_id: i32,
+    user_id: UserID,
+) -> Result<Option<members::Model>, DbErr> {
+    members::Entity::find_by_id((conversation_id, user_id))
+        .one(db())
+        .await
+}
+
+pub async fn member_ids(conversation_id: i32) -> Result<Vec<UserID>, DbErr> {
+    members::Entity::find()
+        .filter(members::Column::ConversationId.eq(conversation_id))
+        .all(db())
+        .await
+        .map(|members| members.into_iter().map(|member| member.user_id).collect())
+}
+
+pub(crate) async fn set_last_delivered(
+    conversation_id: i32,
+    user_ids: &[UserID],
+    message_id: i32,
+) -> Result<(), DbErr> {
+    if user_ids.is_empty() {
+        return Ok(());
+    }
+    members::Entity::update_many()
+        .col_expr(members::Column::LastDelivered, Expr::value(message_id))
+        .filter(members::Column::ConversationId.eq(conversation_id))
+        .filter(members::Column::UserId.is_in(user_ids.iter().copied()))
+        .exec(db())
+        .await
+        .map(|_| ())
+}
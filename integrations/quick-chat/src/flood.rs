@@ -0,0 +1,247 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use fxhash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use teach_tech_core::{auth::UserID, metrics};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+static LIMITS: OnceLock<FloodOptions> = OnceLock::new();
+static USERS: LazyLock<Mutex<FxHashMap<UserID, FloodState>>> = LazyLock::new(Default::default);
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+static MUTES: AtomicU64 = AtomicU64::new(0);
+
+/// The `[quick_chat.flood]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FloodOptions {
+    /// How many messages a user may send per minute
+    #[serde(default = "default_messages_per_minute")]
+    pub messages_per_minute: u32,
+    /// How many consecutive minutes over the limit earn a mute
+    #[serde(default = "default_strikes")]
+    pub strikes: u32,
+    #[serde(default = "default_mute_minutes")]
+    pub mute_minutes: u32,
+}
+
+impl Default for FloodOptions {
+    fn default() -> Self {
+        Self {
+            messages_per_minute: default_messages_per_minute(),
+            strikes: default_strikes(),
+            mute_minutes: default_mute_minutes(),
+        }
+    }
+}
+
+fn default_messages_per_minute() -> u32 {
+    30
+}
+
+fn default_strikes() -> u32 {
+    3
+}
+
+fn default_mute_minutes() -> u32 {
+    10
+}
+
+struct FloodState {
+    window_start: Instant,
+    sent: u32,
+    /// Whether this window already counted as a strike
+    struck: bool,
+    strikes: u32,
+    rejected: u64,
+    muted_until: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    /// Over the per-minute limit
+    Limited,
+    Muted(Duration),
+}
+
+/// What admins see about a user who has been limited.
+#[derive(Debug, Serialize)]
+pub struct FloodReport {
+    pub user_id: UserID,
+    pub rejected: u64,
+    pub strikes: u32,
+    pub muted_for_secs: Option<u64>,
+}
+
+pub(crate) fn init(options: FloodOptions) {
+    let _ = LIMITS.set(options);
+    metrics::add_collector(|writer| {
+        writer.counter(
+            "teach_quick_chat_rate_limited_total",
+            "Chat messages rejected for exceeding the per-minute limit",
+            &[(vec![], REJECTED.load(Ordering::Relaxed) as f64)],
+        );
+        writer.counter(
+            "teach_quick_chat_flood_mutes_total",
+            "Users muted for repeatedly exceeding the per-minute limit",
+            &[(vec![], MUTES.load(Ordering::Relaxed) as f64)],
+        );
+    });
+}
+
+impl FloodState {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            sent: 0,
+            struck: false,
+            strikes: 0,
+            rejected: 0,
+            muted_until: None,
+        }
+    }
+
+    /// Counts a message sent at `now`.
+    fn count(&mut self, limits: &FloodOptions, now: Instant) -> Verdict {
+        if let Some(muted_until) = self.muted_until {
+            if muted_until > now {
+                REJECTED.fetch_add(1, Ordering::Relaxed);
+                self.rejected += 1;
+                return Verdict::Muted(muted_until - now);
+            }
+            self.muted_until = None;
+        }
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= WINDOW {
+            // A calm minute forgives earlier strikes, as does a minute without any messages
+            if !self.struck || elapsed >= 2 * WINDOW {
+                self.strikes = 0;
+            }
+            self.window_start = now;
+            self.sent = 0;
+            self.struck = false;
+        }
+        self.sent += 1;
+        if self.sent <= limits.messages_per_minute {
+            return Verdict::Allowed;
+        }
+
+        REJECTED.fetch_add(1, Ordering::Relaxed);
+        self.rejected += 1;
+        if !self.struck {
+            self.struck = true;
+            self.strikes += 1;
+        }
+        if self.strikes >= limits.strikes {
+            let mute = Duration::from_secs(limits.mute_minutes as u64 * 60);
+            self.muted_until = Some(now + mute);
+            self.strikes = 0;
+            MUTES.fetch_add(1, Ordering::Relaxed);
+            return Verdict::Muted(mute);
+        }
+        Verdict::Limited
+    }
+}
+
+/// Counts a message against the user's limit. Limits are tracked per node, so a user connected
+/// to several nodes gets a separate allowance on each.
+pub fn check(user_id: UserID) -> Verdict {
+    let Some(limits) = LIMITS.get() else {
+        return Verdict::Allowed;
+    };
+    let now = Instant::now();
+    USERS
+        .lock()
+        .unwrap()
+        .entry(user_id)
+        .or_insert_with(|| FloodState::new(now))
+        .count(limits, now)
+}
+
+pub fn report() -> Vec<FloodReport> {
+    let now = Instant::now();
+    USERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, state)| state.rejected > 0)
+        .map(|(&user_id, state)| FloodReport {
+            user_id,
+            rejected: state.rejected,
+            strikes: state.strikes,
+            muted_for_secs: state
+                .muted_until
+                .filter(|until| *until > now)
+                .map(|until| (until - now).as_secs()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: FloodOptions = FloodOptions {
+        messages_per_minute: 2,
+        strikes: 2,
+        mute_minutes: 10,
+    };
+
+    /// Sends `messages` at `at`, returning the last verdict.
+    fn flood(state: &mut FloodState, at: Instant, messages: u32) -> Verdict {
+        (0..messages)
+            .map(|_| state.count(&LIMITS, at))
+            .last()
+            .unwrap()
+    }
+
+    fn minutes(n: u64) -> Duration {
+        Duration::from_secs(n * 60)
+    }
+
+    #[test]
+    fn limits_each_minute() {
+        let start = Instant::now();
+        let mut state = FloodState::new(start);
+        assert_eq!(flood(&mut state, start, 2), Verdict::Allowed);
+        assert_eq!(flood(&mut state, start, 1), Verdict::Limited);
+        assert_eq!(state.rejected, 1);
+        assert_eq!(flood(&mut state, start + minutes(1), 2), Verdict::Allowed);
+    }
+
+    #[test]
+    fn consecutive_floods_mute() {
+        let start = Instant::now();
+        let mut state = FloodState::new(start);
+        assert_eq!(flood(&mut state, start, 3), Verdict::Limited);
+        let second = start + minutes(1);
+        assert_eq!(flood(&mut state, second, 3), Verdict::Muted(minutes(10)));
+        assert_eq!(
+            flood(&mut state, second + minutes(4), 1),
+            Verdict::Muted(minutes(6))
+        );
+        assert_eq!(flood(&mut state, second + minutes(10), 1), Verdict::Allowed);
+    }
+
+    #[test]
+    fn floods_apart_do_not_add_up() {
+        let start = Instant::now();
+        let mut state = FloodState::new(start);
+        assert_eq!(flood(&mut state, start, 3), Verdict::Limited);
+        assert_eq!(flood(&mut state, start + minutes(60), 3), Verdict::Limited);
+        assert_eq!(state.strikes, 1);
+
+        // A calm minute in between forgives too
+        let later = start + minutes(120);
+        assert_eq!(flood(&mut state, later, 3), Verdict::Limited);
+        assert_eq!(flood(&mut state, later + minutes(1), 1), Verdict::Allowed);
+        assert_eq!(flood(&mut state, later + minutes(2), 3), Verdict::Limited);
+        assert_eq!(state.strikes, 1);
+    }
+}
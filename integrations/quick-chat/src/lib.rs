@@ -1,12 +1,106 @@
+use std::time::Duration;
+
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
 use fxhash::FxHashMap;
-use sea_orm::prelude::*;
-use serde::Serialize;
+use sea_orm::DatabaseConnection;
+use serde::Deserialize;
 use teach_tech_core::{
     anyhow,
-    auth::UserID,
-    axum::{extract::WebSocketUpgrade, routing::get},
-    TeachCore,
+    auth::token::validate_token,
+    axum::{
+        extract::{DefaultBodyLimit, Query, WebSocketUpgrade},
+        http::StatusCode,
+        response::IntoResponse,
+        routing::{delete, get, post, put},
+    },
+    db::get_named_db,
+    i18n, storage, TeachCore,
 };
+use tracing::error;
+
+pub mod api;
+pub mod attachments;
+pub mod connections;
+pub mod conversations;
+pub mod flood;
+pub mod messages;
+pub mod moderation;
+pub mod policy;
+pub mod presence;
+pub mod protocol;
+mod relay;
+mod socket;
+
+const CONNECTION: &str = "chat";
+
+/// English messages, which other locales can translate under the same keys
+const MESSAGES: &[(&str, &str)] = &[
+    (
+        "quick_chat.direct_member_count",
+        "Direct conversations have exactly one other member",
+    ),
+    (
+        "quick_chat.must_be_staff",
+        "Only instructors and administrators can create channels",
+    ),
+    (
+        "quick_chat.course_needs_channel",
+        "Only channels can belong to a course",
+    ),
+    ("quick_chat.unknown_member", "Unknown member"),
+    (
+        "quick_chat.direct_members_fixed",
+        "Direct conversations cannot change members",
+    ),
+    (
+        "quick_chat.must_be_owner",
+        "Must be an owner of the conversation",
+    ),
+    (
+        "quick_chat.cannot_leave_direct",
+        "Cannot leave a direct conversation",
+    ),
+    ("quick_chat.invalid_user_id", "Invalid user ID"),
+    (
+        "quick_chat.query_length",
+        "Queries must have between 1 and {max} characters",
+    ),
+    (
+        "quick_chat.must_be_moderator",
+        "Must be a moderator of the conversation",
+    ),
+    ("quick_chat.cannot_block_self", "Cannot block yourself"),
+    ("quick_chat.invalid_file_name", "Invalid file name"),
+    ("quick_chat.empty_file", "The file is empty"),
+    ("quick_chat.new_message", "New message from {from}"),
+];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct QuickChatConfig {
+    #[serde(default)]
+    pub quick_chat: QuickChatOptions,
+}
+
+/// The `[quick_chat]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct QuickChatOptions {
+    #[serde(default)]
+    pub flood: flood::FloodOptions,
+    #[serde(default)]
+    pub contact: policy::ContactOptions,
+}
+
+#[derive(Deserialize)]
+struct UpgradeQuery {
+    token: Option<String>,
+}
+
+fn db() -> &'static DatabaseConnection {
+    get_named_db(CONNECTION)
+}
 
 pub async fn add_to_core<S: Clone + Send + Sync + 'static>(
     mut core: TeachCore<S>,
@@ -14,33 +108,103 @@ pub async fn add_to_core<S: Clone + Send + Sync + 'static>(
     let mut info = FxHashMap::default();
     info.insert("version", env!("CARGO_PKG_VERSION"));
     core.add_info("quick-chat", info);
-    core.add_db_reset_config(Entity);
+    core.declare_config::<QuickChatConfig>();
+    i18n::add_catalog(i18n::FALLBACK_LOCALE, MESSAGES.iter().copied());
+    let config: QuickChatConfig = toml::from_str(core.get_config_str())?;
+    flood::init(config.quick_chat.flood);
+    policy::init(config.quick_chat.contact);
+    core.add_named_db_reset_config(CONNECTION, conversations::Entity)?;
+    core.add_named_db_reset_config(CONNECTION, conversations::members::Entity)?;
+    core.add_named_db_reset_config(CONNECTION, messages::Entity)?;
+    core.add_named_db_reset_config(CONNECTION, moderation::Entity)?;
+    core.add_named_db_reset_config(CONNECTION, policy::blocks::Entity)?;
+    core.add_named_db_reset_config(CONNECTION, attachments::Entity)?;
+    core.add_named_retention(
+        CONNECTION,
+        messages::Entity,
+        messages::Column::Date,
+        Duration::from_secs(365 * 24 * 60 * 60),
+    );
 
     core = core.modify_router(|router| {
-        router.route(
-            "/quick-chat",
-            get(|ws: WebSocketUpgrade| async { ws.on_upgrade(|ws| async move {}) }),
-        )
+        router
+            .route(
+                "/quick-chat",
+                get(
+                    |bearer: Option<TypedHeader<Authorization<Bearer>>>,
+                     Query(query): Query<UpgradeQuery>,
+                     ws: WebSocketUpgrade| async move {
+                        // Browsers cannot set headers on WebSockets, so the token may also come in
+                        // the query or in the first frame
+                        let token = bearer
+                            .map(|TypedHeader(Authorization(bearer))| bearer.token().to_string())
+                            .or(query.token);
+                        let Some(token) = token else {
+                            return ws.on_upgrade(socket::handle_unauthenticated_socket);
+                        };
+                        let user_id = match validate_token(&token).await {
+                            Ok(Some(user_id)) => user_id,
+                            Ok(None) => return (StatusCode::UNAUTHORIZED, ()).into_response(),
+                            Err(e) => {
+                                error!("Error validating bearer token: {e:#}");
+                                return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+                            }
+                        };
+                        ws.on_upgrade(move |socket| socket::handle_socket(socket, user_id))
+                    },
+                ),
+            )
+            .route(
+                "/quick-chat/conversations",
+                get(api::list_conversations).post(api::create_conversation),
+            )
+            .route("/quick-chat/conversations/:id/messages", get(api::history))
+            .route(
+                "/quick-chat/conversations/:id/members",
+                post(api::add_members),
+            )
+            .route(
+                "/quick-chat/conversations/:id/members/:user_id",
+                delete(api::remove_member),
+            )
+            .route(
+                "/quick-chat/conversations/:id/members/:user_id/mute",
+                post(api::mute_member).delete(api::unmute_member),
+            )
+            .route(
+                "/quick-chat/conversations/:id/attachments",
+                post(attachments::upload).layer(DefaultBodyLimit::max(storage::max_upload_bytes())),
+            )
+            .route("/quick-chat/attachments/:id", get(attachments::download))
+            .route("/quick-chat/messages/:id", delete(api::redact_message))
+            .route("/quick-chat/unread", get(api::unread))
+            .route("/quick-chat/presence", get(api::presence))
+            .route("/quick-chat/search", get(api::search))
+            .route("/quick-chat/admin/flood", get(api::flood_report))
+            .route(
+                "/quick-chat/admin/moderation-log/export",
+                get(api::export_moderation_log),
+            )
+            .route("/quick-chat/blocks", get(api::list_blocks))
+            .route(
+                "/quick-chat/blocks/:user_id",
+                put(api::block_user).delete(api::unblock_user),
+            )
     });
 
-    core.add_on_serve(|| async move { Ok(()) });
+    core.add_on_serve_named("quick-chat", 0, || async move {
+        relay::add_sibling_handler().await;
+        presence::add_sibling_handler().await;
+        Ok(())
+    });
+    core.add_scheduled_task(
+        "quick-chat presence",
+        presence::SNAPSHOT_INTERVAL,
+        || async {
+            presence::broadcast_snapshot().await;
+            Ok(())
+        },
+    );
 
     Ok(core)
 }
-
-#[derive(Clone, Debug, DeriveEntityModel, Serialize)]
-#[sea_orm(table_name = "quick_chat_messages")]
-pub struct Model {
-    #[sea_orm(primary_key)]
-    pub user_id: i32,
-    pub from: UserID,
-    pub to: UserID,
-    pub date: DateTime,
-    pub message: String,
-    pub read: bool,
-}
-
-#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
-pub enum Relation {}
-
-impl ActiveModelBehavior for ActiveModel {}
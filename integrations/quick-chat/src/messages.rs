@@ -0,0 +1,22 @@
+use sea_orm::entity::prelude::*;
+use serde::Serialize;
+use teach_tech_core::auth::UserID;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "quick_chat_messages")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub conversation_id: i32,
+    pub from: UserID,
+    pub date: DateTime,
+    /// Emptied when the message is redacted
+    pub message: String,
+    pub redacted_at: Option<DateTime>,
+    pub redacted_by: Option<UserID>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,151 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::BoxFuture;
+use sea_orm::{entity::prelude::*, ActiveValue};
+use serde::Serialize;
+use teach_tech_core::auth::UserID;
+
+use crate::{
+    conversations::{self, members::MemberRole},
+    db,
+};
+
+type ContentFilter = Arc<dyn Fn(FilterInput) -> BoxFuture<'static, FilterDecision> + Send + Sync>;
+
+static CONTENT_FILTERS: Mutex<Vec<ContentFilter>> = Mutex::new(vec![]);
+
+/// A message about to be persisted.
+#[derive(Debug, Clone)]
+pub struct FilterInput {
+    pub from: UserID,
+    pub conversation_id: i32,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum FilterDecision {
+    Allow,
+    /// Persists this text instead of the original message
+    Replace(String),
+    /// Refuses the message, telling the sender why
+    Reject(String),
+}
+
+/// Registers a filter that every message passes through before it is persisted. Filters run in
+/// the order they were added, each seeing the output of the previous one.
+pub fn add_content_filter<F, Fut>(f: F)
+where
+    F: Fn(FilterInput) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = FilterDecision> + Send + 'static,
+{
+    CONTENT_FILTERS
+        .lock()
+        .unwrap()
+        .push(Arc::new(move |input| Box::pin(f(input))));
+}
+
+/// Runs every content filter, returning the text to persist or the reason for rejecting it.
+/// Filter decisions that change the message are recorded in the moderation log.
+pub(crate) async fn apply_content_filters(
+    mut input: FilterInput,
+) -> Result<Result<String, String>, DbErr> {
+    let filters = CONTENT_FILTERS.lock().unwrap().clone();
+    for filter in filters {
+        match filter(input.clone()).await {
+            FilterDecision::Allow => {}
+            FilterDecision::Replace(message) => {
+                record(
+                    None,
+                    ModerationAction::FilterReplace,
+                    input.conversation_id,
+                    None,
+                    Some(input.from),
+                    None,
+                )
+                .await?;
+                input.message = message;
+            }
+            FilterDecision::Reject(reason) => {
+                record(
+                    None,
+                    ModerationAction::FilterReject,
+                    input.conversation_id,
+                    None,
+                    Some(input.from),
+                    Some(reason.clone()),
+                )
+                .await?;
+                return Ok(Err(reason));
+            }
+        }
+    }
+    Ok(Ok(input.message))
+}
+
+/// Instructors and admins moderate every conversation, and owners moderate their own.
+pub(crate) async fn can_moderate(user_id: UserID, conversation_id: i32) -> Result<bool, DbErr> {
+    if crate::policy::is_staff(user_id).await? {
+        return Ok(true);
+    }
+    Ok(conversations::membership(conversation_id, user_id)
+        .await?
+        .is_some_and(|membership| membership.role == MemberRole::Owner))
+}
+
+pub(crate) async fn record(
+    actor: Option<UserID>,
+    action: ModerationAction,
+    conversation_id: i32,
+    message_id: Option<i32>,
+    target: Option<UserID>,
+    details: Option<String>,
+) -> Result<(), DbErr> {
+    ActiveModel {
+        id: ActiveValue::not_set(),
+        actor: ActiveValue::set(actor),
+        action: ActiveValue::set(action),
+        conversation_id: ActiveValue::set(conversation_id),
+        message_id: ActiveValue::set(message_id),
+        target: ActiveValue::set(target),
+        details: ActiveValue::set(details),
+        created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+    }
+    .insert(db())
+    .await
+    .map(|_| ())
+}
+
+/// Every moderation action taken in quick-chat.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "quick_chat_moderation_log")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    /// `None` for actions taken by content filters
+    pub actor: Option<UserID>,
+    pub action: ModerationAction,
+    pub conversation_id: i32,
+    pub message_id: Option<i32>,
+    pub target: Option<UserID>,
+    pub details: Option<String>,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(EnumIter, DeriveActiveEnum, Clone, Debug, Copy, PartialEq, Eq, Serialize)]
+#[sea_orm(rs_type = "i32", db_type = "Integer")]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    Redact = 0,
+    Mute = 1,
+    Unmute = 2,
+    FilterReplace = 3,
+    FilterReject = 4,
+}
@@ -0,0 +1,218 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+use teach_tech_core::{
+    anyhow,
+    auth::UserID,
+    cache,
+    db::{get_db, SoftDeletable},
+    users::{admins, instructors, students},
+};
+use tracing::error;
+
+use crate::db;
+
+const ROLE_CACHE_TTL: Duration = Duration::from_secs(60);
+
+type ContactPolicy = Arc<
+    dyn Fn(ContactRequest) -> BoxFuture<'static, anyhow::Result<ContactDecision>> + Send + Sync,
+>;
+
+static OPTIONS: OnceLock<ContactOptions> = OnceLock::new();
+static CONTACT_POLICIES: Mutex<Vec<ContactPolicy>> = Mutex::new(vec![]);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Student,
+    Instructor,
+    Admin,
+    /// A user with login credentials but no profile
+    Other,
+}
+
+impl Role {
+    fn plural(self) -> &'static str {
+        match self {
+            Role::Student => "Students",
+            Role::Instructor => "Instructors",
+            Role::Admin => "Admins",
+            Role::Other => "Other users",
+        }
+    }
+}
+
+/// The `[quick_chat.contact]` section of `teach-config.toml`. Each key lists the roles that users
+/// of that role may message; leaving a key out allows every role.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ContactOptions {
+    pub students: Option<Vec<Role>>,
+    pub instructors: Option<Vec<Role>>,
+    pub admins: Option<Vec<Role>>,
+}
+
+impl ContactOptions {
+    fn allowed(&self, from: Role) -> Option<&[Role]> {
+        match from {
+            Role::Student => self.students.as_deref(),
+            Role::Instructor => self.instructors.as_deref(),
+            Role::Admin => self.admins.as_deref(),
+            Role::Other => None,
+        }
+    }
+}
+
+/// A user about to message another user, given to contact policies.
+#[derive(Debug, Clone, Copy)]
+pub struct ContactRequest {
+    pub from: UserID,
+    pub to: UserID,
+    pub from_role: Role,
+    pub to_role: Role,
+}
+
+#[derive(Debug, Clone)]
+pub enum ContactDecision {
+    Allow,
+    /// Refuses the contact, telling the sender why
+    Deny(String),
+}
+
+pub(crate) fn init(options: ContactOptions) {
+    let _ = OPTIONS.set(options);
+}
+
+/// Registers a policy that is asked whether one user may message another, such as one that only
+/// lets students message the instructors of their courses. Every policy must allow a contact.
+pub fn add_contact_policy<F, Fut>(f: F)
+where
+    F: Fn(ContactRequest) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<ContactDecision>> + Send + 'static,
+{
+    CONTACT_POLICIES
+        .lock()
+        .unwrap()
+        .push(Arc::new(move |request| Box::pin(f(request))));
+}
+
+pub async fn role_of(user_id: UserID) -> Result<Role, DbErr> {
+    let key = format!("quick_chat_role:{user_id}");
+    match cache::get_json(&key).await {
+        Ok(Some(role)) => return Ok(role),
+        Ok(None) => {}
+        Err(e) => error!("Error reading cached role for {user_id}: {e:#}"),
+    }
+    let role = if admins::Entity::find_live_by_id(user_id)
+        .one(get_db())
+        .await?
+        .is_some()
+    {
+        Role::Admin
+    } else if instructors::Entity::find_live_by_id(user_id)
+        .one(get_db())
+        .await?
+        .is_some()
+    {
+        Role::Instructor
+    } else if students::Entity::find_live_by_id(user_id)
+        .one(get_db())
+        .await?
+        .is_some()
+    {
+        Role::Student
+    } else {
+        Role::Other
+    };
+    if let Err(e) = cache::set_json(&key, &role, Some(ROLE_CACHE_TTL)).await {
+        error!("Error caching role for {user_id}: {e:#}");
+    }
+    Ok(role)
+}
+
+pub async fn is_staff(user_id: UserID) -> Result<bool, DbErr> {
+    Ok(matches!(
+        role_of(user_id).await?,
+        Role::Instructor | Role::Admin
+    ))
+}
+
+/// Returns why `from` may not message `to`, if they may not.
+pub async fn check_contact(from: UserID, to: UserID) -> anyhow::Result<Option<String>> {
+    if is_blocked(to, from).await? {
+        return Ok(Some(format!("User {to} does not accept messages from you")));
+    }
+    let request = ContactRequest {
+        from,
+        to,
+        from_role: role_of(from).await?,
+        to_role: role_of(to).await?,
+    };
+    let allowed = OPTIONS
+        .get()
+        .and_then(|options| options.allowed(request.from_role));
+    if allowed.is_some_and(|allowed| !allowed.contains(&request.to_role)) {
+        return Ok(Some(format!(
+            "{} cannot message {}",
+            request.from_role.plural(),
+            request.to_role.plural().to_lowercase()
+        )));
+    }
+    let policies = CONTACT_POLICIES.lock().unwrap().clone();
+    for policy in policies {
+        if let ContactDecision::Deny(reason) = policy(request).await? {
+            return Ok(Some(reason));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the first reason `from` may not message one of `members`.
+pub async fn check_contacts(
+    from: UserID,
+    members: impl IntoIterator<Item = UserID>,
+) -> anyhow::Result<Option<String>> {
+    for member in members {
+        if member == from {
+            continue;
+        }
+        if let Some(reason) = check_contact(from, member).await? {
+            return Ok(Some(reason));
+        }
+    }
+    Ok(None)
+}
+
+pub async fn is_blocked(blocker: UserID, blocked: UserID) -> Result<bool, DbErr> {
+    Ok(blocks::Entity::find_by_id((blocker, blocked))
+        .one(db())
+        .await?
+        .is_some())
+}
+
+pub mod blocks {
+    use sea_orm::entity::prelude::*;
+    use serde::Serialize;
+    use teach_tech_core::auth::UserID;
+
+    /// `user_id` does not accept messages from `blocked`.
+    #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+    #[sea_orm(table_name = "quick_chat_blocks")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub user_id: UserID,
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub blocked: UserID,
+        pub created_at: DateTime,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
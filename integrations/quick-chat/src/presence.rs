@@ -0,0 +1,131 @@
+use std::{
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use fxhash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+use teach_tech_core::{
+    auth::UserID,
+    serde_json,
+    siblings::{add_sibling_message_handler_raw, send_to_siblings_raw},
+    tokio,
+};
+use tracing::error;
+
+use crate::{connections, protocol::ServerFrame};
+
+const SIBLING_SOURCE: &str = "quick-chat/presence";
+/// How often every node broadcasts its full list of online users
+pub(crate) const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(30);
+// Nodes that stop broadcasting, such as ones that crashed, are forgotten after this long
+const NODE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// Distinguishes this node's broadcasts from those of other nodes
+static NODE_ID: LazyLock<u64> = LazyLock::new(rand::random);
+static REMOTE: LazyLock<Mutex<FxHashMap<u64, RemoteNode>>> = LazyLock::new(Default::default);
+
+struct RemoteNode {
+    seen: Instant,
+    online: FxHashSet<UserID>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum PresenceMessage {
+    Changed {
+        node: u64,
+        user_id: UserID,
+        online: bool,
+    },
+    Snapshot {
+        node: u64,
+        online: Vec<UserID>,
+    },
+}
+
+fn online_remotely(remote: &FxHashMap<u64, RemoteNode>, user_id: UserID) -> bool {
+    remote
+        .values()
+        .any(|node| node.seen.elapsed() < NODE_TIMEOUT && node.online.contains(&user_id))
+}
+
+/// Whether the user has an open socket on any node.
+pub fn is_online(user_id: UserID) -> bool {
+    connections::is_connected(user_id) || online_remotely(&REMOTE.lock().unwrap(), user_id)
+}
+
+async fn broadcast(message: PresenceMessage) {
+    let bytes = serde_json::to_vec(&message).expect("Serializing presence");
+    if let Err(e) = send_to_siblings_raw(SIBLING_SOURCE, &bytes).await {
+        error!("Error broadcasting presence to siblings: {e:#}");
+    }
+}
+
+// Called when a user opens their first socket on this node or closes their last one
+pub(crate) fn local_changed(user_id: UserID, online: bool) {
+    let online_elsewhere = online_remotely(&REMOTE.lock().unwrap(), user_id);
+    if !online_elsewhere {
+        connections::notify_watchers(user_id, &ServerFrame::Presence { user_id, online });
+    }
+    tokio::spawn(broadcast(PresenceMessage::Changed {
+        node: *NODE_ID,
+        user_id,
+        online,
+    }));
+}
+
+pub(crate) async fn broadcast_snapshot() {
+    broadcast(PresenceMessage::Snapshot {
+        node: *NODE_ID,
+        online: connections::connected_users(),
+    })
+    .await;
+}
+
+fn remote_changed(node: u64, user_id: UserID, online: bool) {
+    let was_online = is_online(user_id);
+    {
+        let mut remote = REMOTE.lock().unwrap();
+        let node = remote.entry(node).or_insert_with(|| RemoteNode {
+            seen: Instant::now(),
+            online: FxHashSet::default(),
+        });
+        node.seen = Instant::now();
+        if online {
+            node.online.insert(user_id);
+        } else {
+            node.online.remove(&user_id);
+        }
+    }
+    if was_online != is_online(user_id) {
+        connections::notify_watchers(user_id, &ServerFrame::Presence { user_id, online });
+    }
+}
+
+pub(crate) async fn add_sibling_handler() {
+    add_sibling_message_handler_raw(|source, bytes| {
+        if source != SIBLING_SOURCE {
+            return;
+        }
+        match serde_json::from_slice(bytes) {
+            Ok(PresenceMessage::Changed {
+                node,
+                user_id,
+                online,
+            }) => remote_changed(node, user_id, online),
+            Ok(PresenceMessage::Snapshot { node, online }) => {
+                let mut remote = REMOTE.lock().unwrap();
+                remote.retain(|_, node| node.seen.elapsed() < NODE_TIMEOUT);
+                remote.insert(
+                    node,
+                    RemoteNode {
+                        seen: Instant::now(),
+                        online: online.into_iter().collect(),
+                    },
+                );
+            }
+            Err(e) => error!("Received an invalid presence message: {e}"),
+        }
+    })
+    .await;
+}
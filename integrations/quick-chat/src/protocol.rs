@@ -0,0 +1,96 @@
+use sea_orm::prelude::DateTime;
+use serde::{Deserialize, Serialize};
+use teach_tech_core::auth::UserID;
+
+use crate::messages;
+
+/// Frames sent by clients over `/quick-chat`, as JSON text messages.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// Must be the first frame when the upgrade request carried no token
+    Auth {
+        token: String,
+    },
+    Send {
+        conversation_id: i32,
+        message: String,
+        /// Echoed back in [`ServerFrame::Sent`] so clients can match acknowledgements
+        #[serde(default)]
+        client_id: Option<String>,
+    },
+    /// Marks every message up to and including `up_to` as read
+    MarkRead {
+        conversation_id: i32,
+        up_to: i32,
+    },
+    /// Relayed to the other members as a typing frame, and never persisted
+    TypingStart {
+        conversation_id: i32,
+    },
+    TypingStop {
+        conversation_id: i32,
+    },
+    /// Replaces the users whose presence changes are pushed to this socket
+    WatchPresence {
+        users: Vec<UserID>,
+    },
+}
+
+/// Frames sent by the server over `/quick-chat`, as JSON text messages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    /// Sent once the socket is bound to a user
+    Authenticated {
+        user_id: UserID,
+    },
+    Message {
+        id: i32,
+        conversation_id: i32,
+        from: UserID,
+        date: DateTime,
+        message: String,
+    },
+    Sent {
+        id: i32,
+        client_id: Option<String>,
+        /// How many other members had an open socket on the same node
+        delivered_to: usize,
+    },
+    /// `by` has read every message up to and including `up_to`
+    Read {
+        conversation_id: i32,
+        by: UserID,
+        up_to: i32,
+    },
+    /// The message's content was removed by a moderator
+    Redacted {
+        id: i32,
+        conversation_id: i32,
+    },
+    Typing {
+        conversation_id: i32,
+        from: UserID,
+        typing: bool,
+    },
+    Presence {
+        user_id: UserID,
+        online: bool,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl From<&messages::Model> for ServerFrame {
+    fn from(model: &messages::Model) -> Self {
+        Self::Message {
+            id: model.id,
+            conversation_id: model.conversation_id,
+            from: model.from,
+            date: model.date,
+            message: model.message.clone(),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use teach_tech_core::{
+    auth::UserID,
+    serde_json,
+    siblings::{add_sibling_message_handler_raw, send_to_siblings_raw},
+    tokio,
+};
+use tracing::error;
+
+use crate::{connections, conversations, protocol::ServerFrame};
+
+const SIBLING_SOURCE: &str = "quick-chat";
+
+/// A frame for members whose sockets may be open on another node.
+#[derive(Serialize, Deserialize)]
+struct Relay {
+    recipients: Vec<UserID>,
+    frame: ServerFrame,
+}
+
+pub async fn relay(recipients: Vec<UserID>, frame: &ServerFrame) {
+    if recipients.is_empty() {
+        return;
+    }
+    let relay = Relay {
+        recipients,
+        frame: frame.clone(),
+    };
+    let bytes = serde_json::to_vec(&relay).expect("Serializing chat relay");
+    if let Err(e) = send_to_siblings_raw(SIBLING_SOURCE, &bytes).await {
+        error!("Error relaying chat frame to siblings: {e:#}");
+    }
+}
+
+pub async fn add_sibling_handler() {
+    add_sibling_message_handler_raw(|source, bytes| {
+        if source != SIBLING_SOURCE {
+            return;
+        }
+        let relay: Relay = match serde_json::from_slice(bytes) {
+            Ok(relay) => relay,
+            Err(e) => {
+                error!("Received an invalid chat relay: {e}");
+                return;
+            }
+        };
+        let delivered: Vec<_> = relay
+            .recipients
+            .into_iter()
+            .filter(|&recipient| connections::deliver(recipient, &relay.frame))
+            .collect();
+        // The sending node cannot know which members were reached here
+        if let ServerFrame::Message {
+            id,
+            conversation_id,
+            ..
+        } = relay.frame
+        {
+            if delivered.is_empty() {
+                return;
+            }
+            tokio::spawn(async move {
+                if let Err(e) =
+                    conversations::set_last_delivered(conversation_id, &delivered, id).await
+                {
+                    error!("Error recording relayed chat delivery: {e}");
+                }
+            });
+        }
+    })
+    .await;
+}
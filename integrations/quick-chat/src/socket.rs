@@ -0,0 +1,433 @@
+use std::time::{Duration, Instant};
+
+use futures::{SinkExt, StreamExt};
+use fxhash::FxHashMap;
+use sea_orm::{entity::prelude::*, ActiveValue, QueryOrder};
+use teach_tech_core::{
+    anyhow,
+    auth::{token::validate_token, UserID},
+    axum::extract::ws::{close_code, CloseFrame, Message, WebSocket},
+    i18n,
+    notifications::{self, NewNotification},
+    serde_json,
+    tokio::{self, sync::mpsc::UnboundedSender},
+};
+use tracing::error;
+
+use crate::{
+    connections::{self, ConnectionGuard},
+    conversations::{self, members, ConversationKind},
+    db,
+    flood::{self, Verdict},
+    messages,
+    moderation::{self, FilterInput},
+    policy, presence,
+    protocol::{ClientFrame, ServerFrame},
+    relay,
+};
+
+const MAX_MESSAGE_LEN: usize = 4000;
+const MAX_WATCHED_USERS: usize = 500;
+const NOT_A_MEMBER: &str = "Not a member of this conversation";
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+// Clients tend to send a typing frame per keystroke, so repeats within this interval are dropped
+const TYPING_INTERVAL: Duration = Duration::from_secs(2);
+
+/// State that lives as long as one socket.
+#[derive(Default)]
+struct SocketState {
+    /// When a typing start was last relayed to each conversation
+    typing: FxHashMap<i32, Instant>,
+}
+
+async fn send_frame(socket: &mut WebSocket, frame: &ServerFrame) {
+    let text = serde_json::to_string(frame).expect("Serializing chat frame");
+    let _ = socket.send(Message::Text(text)).await;
+}
+
+async fn reject(mut socket: WebSocket, reason: &str) {
+    send_frame(
+        &mut socket,
+        &ServerFrame::Error {
+            message: reason.to_string(),
+        },
+    )
+    .await;
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code: close_code::POLICY,
+            reason: reason.to_string().into(),
+        })))
+        .await;
+}
+
+/// Waits for an auth frame before handling the socket, closing it if none arrives in time.
+pub async fn handle_unauthenticated_socket(mut socket: WebSocket) {
+    let first = match tokio::time::timeout(AUTH_TIMEOUT, socket.recv()).await {
+        Ok(Some(Ok(Message::Text(text)))) => text,
+        Ok(None | Some(Err(_)) | Some(Ok(Message::Close(_)))) => return,
+        Ok(Some(Ok(_))) => return reject(socket, "Expected an auth frame").await,
+        Err(_) => return reject(socket, "Timed out waiting for an auth frame").await,
+    };
+    let Ok(ClientFrame::Auth { token }) = serde_json::from_str(&first) else {
+        return reject(socket, "Expected an auth frame").await;
+    };
+    match validate_token(&token).await {
+        Ok(Some(user_id)) => handle_socket(socket, user_id).await,
+        Ok(None) => reject(socket, "Invalid token").await,
+        Err(e) => {
+            error!("Error validating bearer token: {e:#}");
+            reject(socket, "Internal server error").await;
+        }
+    }
+}
+
+pub async fn handle_socket(socket: WebSocket, user_id: UserID) {
+    let (mut sink, mut stream) = socket.split();
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<ServerFrame>();
+    let guard = connections::register(user_id, sender.clone());
+    let _ = sender.send(ServerFrame::Authenticated { user_id });
+
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = receiver.recv().await {
+            let text = serde_json::to_string(&frame).expect("Serializing chat frame");
+            if sink.send(Message::Text(text)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    if let Err(e) = deliver_pending(user_id, &sender).await {
+        error!("Error delivering pending chat messages to {user_id}: {e}");
+    }
+
+    let mut state = SocketState::default();
+    while let Some(Ok(message)) = stream.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let frame = match serde_json::from_str::<ClientFrame>(&text) {
+            Ok(frame) => frame,
+            Err(e) => {
+                let _ = sender.send(ServerFrame::Error {
+                    message: format!("Invalid frame: {e}"),
+                });
+                continue;
+            }
+        };
+        if let Err(e) = handle_frame(user_id, frame, &sender, &guard, &mut state).await {
+            error!("Error handling chat frame from {user_id}: {e:#}");
+            let _ = sender.send(ServerFrame::Error {
+                message: "Internal server error".to_string(),
+            });
+        }
+    }
+
+    // The writer stops once every sender is gone
+    drop(guard);
+    drop(sender);
+    let _ = writer.await;
+}
+
+// Sends messages that arrived while the user had no open socket
+async fn deliver_pending(
+    user_id: UserID,
+    sender: &UnboundedSender<ServerFrame>,
+) -> Result<(), DbErr> {
+    let memberships = members::Entity::find()
+        .filter(members::Column::UserId.eq(user_id))
+        .all(db())
+        .await?;
+    for membership in memberships {
+        let pending = messages::Entity::find()
+            .filter(messages::Column::ConversationId.eq(membership.conversation_id))
+            .filter(messages::Column::Id.gt(membership.last_delivered.unwrap_or(0)))
+            .filter(messages::Column::From.ne(user_id))
+            .filter(messages::Column::RedactedAt.is_null())
+            .order_by_asc(messages::Column::Id)
+            .all(db())
+            .await?;
+        let Some(last) = pending.last() else {
+            continue;
+        };
+        for model in &pending {
+            let _ = sender.send(model.into());
+        }
+        conversations::set_last_delivered(membership.conversation_id, &[user_id], last.id).await?;
+    }
+    Ok(())
+}
+
+/// Sends a frame to the open sockets of every member except `except`, returning the members
+/// that received it on this node. Members without a socket here are relayed to siblings.
+pub(crate) async fn fan_out(
+    conversation_id: i32,
+    except: UserID,
+    frame: &ServerFrame,
+) -> Result<Vec<UserID>, DbErr> {
+    let (delivered, elsewhere): (Vec<_>, Vec<_>) = conversations::member_ids(conversation_id)
+        .await?
+        .into_iter()
+        .filter(|&member| member != except)
+        .partition(|&member| connections::deliver(member, frame));
+    relay::relay(elsewhere, frame).await;
+    Ok(delivered)
+}
+
+// Policies may have changed since members joined, so they are checked again for every message
+async fn check_send_policy(
+    conversation_id: i32,
+    user_id: UserID,
+) -> anyhow::Result<Option<String>> {
+    let Some(conversation) = conversations::Entity::find_by_id(conversation_id)
+        .one(db())
+        .await?
+    else {
+        return Ok(None);
+    };
+    if conversation.kind == ConversationKind::Channel {
+        return Ok(None);
+    }
+    policy::check_contacts(user_id, conversations::member_ids(conversation_id).await?).await
+}
+
+const NOTIFICATION_SOURCE: &str = "quick-chat";
+const NOTIFICATION_PREVIEW_LEN: usize = 100;
+
+// Members without an open socket anywhere would not see the message until they next connect
+async fn notify_offline(model: messages::Model) {
+    let result: Result<(), DbErr> = async {
+        let link = format!("/quick-chat/conversations/{}", model.conversation_id);
+        for member in conversations::member_ids(model.conversation_id).await? {
+            if member == model.from || presence::is_online(member) {
+                continue;
+            }
+            // One unread notification per conversation is enough
+            if notifications::has_unread(member, NOTIFICATION_SOURCE, &link).await? {
+                continue;
+            }
+            let locale = i18n::locale_of(member).await;
+            notifications::notify(NewNotification {
+                user_id: member,
+                source: NOTIFICATION_SOURCE.to_string(),
+                title: i18n::translate_with(
+                    &locale,
+                    "quick_chat.new_message",
+                    &[("from", &model.from.to_string())],
+                ),
+                body: model
+                    .message
+                    .chars()
+                    .take(NOTIFICATION_PREVIEW_LEN)
+                    .collect(),
+                link: Some(link.clone()),
+                urgent: false,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        error!(
+            "Error notifying offline members of conversation {}: {e}",
+            model.conversation_id
+        );
+    }
+}
+
+fn send_error(sender: &UnboundedSender<ServerFrame>, message: impl Into<String>) {
+    let _ = sender.send(ServerFrame::Error {
+        message: message.into(),
+    });
+}
+
+async fn handle_frame(
+    user_id: UserID,
+    frame: ClientFrame,
+    sender: &UnboundedSender<ServerFrame>,
+    guard: &ConnectionGuard,
+    state: &mut SocketState,
+) -> anyhow::Result<()> {
+    match frame {
+        ClientFrame::Auth { .. } => send_error(sender, "Already authenticated"),
+        ClientFrame::Send {
+            conversation_id,
+            message,
+            client_id,
+        } => {
+            if message.trim().is_empty() || message.chars().count() > MAX_MESSAGE_LEN {
+                send_error(
+                    sender,
+                    format!("Messages must have between 1 and {MAX_MESSAGE_LEN} characters"),
+                );
+                return Ok(());
+            }
+            match flood::check(user_id) {
+                Verdict::Allowed => {}
+                Verdict::Limited => {
+                    send_error(sender, "Sending too many messages, slow down");
+                    return Ok(());
+                }
+                Verdict::Muted(remaining) => {
+                    send_error(
+                        sender,
+                        format!(
+                            "Muted for flooding, try again in {} seconds",
+                            remaining.as_secs().max(1)
+                        ),
+                    );
+                    return Ok(());
+                }
+            }
+            let Some(membership) = conversations::membership(conversation_id, user_id).await?
+            else {
+                send_error(sender, NOT_A_MEMBER);
+                return Ok(());
+            };
+            if let Some(reason) = check_send_policy(conversation_id, user_id).await? {
+                send_error(sender, reason);
+                return Ok(());
+            }
+            let now = chrono::Utc::now().naive_utc();
+            if let Some(muted_until) = membership.muted_until.filter(|until| *until > now) {
+                send_error(
+                    sender,
+                    format!("You are muted in this conversation until {muted_until}"),
+                );
+                return Ok(());
+            }
+            let message = match moderation::apply_content_filters(FilterInput {
+                from: user_id,
+                conversation_id,
+                message,
+            })
+            .await?
+            {
+                Ok(message) => message,
+                Err(reason) => {
+                    send_error(sender, format!("Message rejected: {reason}"));
+                    return Ok(());
+                }
+            };
+
+            let model = messages::ActiveModel {
+                id: ActiveValue::not_set(),
+                conversation_id: ActiveValue::set(conversation_id),
+                from: ActiveValue::set(user_id),
+                date: ActiveValue::set(now),
+                message: ActiveValue::set(message),
+                redacted_at: ActiveValue::set(None),
+                redacted_by: ActiveValue::set(None),
+            }
+            .insert(db())
+            .await?;
+
+            let delivered = fan_out(conversation_id, user_id, &(&model).into()).await?;
+            conversations::set_last_delivered(conversation_id, &delivered, model.id).await?;
+            tokio::spawn(notify_offline(model.clone()));
+            let _ = sender.send(ServerFrame::Sent {
+                id: model.id,
+                client_id,
+                delivered_to: delivered.len(),
+            });
+        }
+        ClientFrame::MarkRead {
+            conversation_id,
+            up_to,
+        } => {
+            let Some(membership) = conversations::membership(conversation_id, user_id).await?
+            else {
+                send_error(sender, NOT_A_MEMBER);
+                return Ok(());
+            };
+            if membership
+                .last_read
+                .is_some_and(|last_read| last_read >= up_to)
+            {
+                return Ok(());
+            }
+            members::ActiveModel {
+                conversation_id: ActiveValue::unchanged(conversation_id),
+                user_id: ActiveValue::unchanged(user_id),
+                last_read: ActiveValue::set(Some(up_to)),
+                ..Default::default()
+            }
+            .update(db())
+            .await?;
+            fan_out(
+                conversation_id,
+                user_id,
+                &ServerFrame::Read {
+                    conversation_id,
+                    by: user_id,
+                    up_to,
+                },
+            )
+            .await?;
+        }
+        ClientFrame::TypingStart { conversation_id } => {
+            let now = Instant::now();
+            if state
+                .typing
+                .get(&conversation_id)
+                .is_some_and(|last| now.duration_since(*last) < TYPING_INTERVAL)
+            {
+                return Ok(());
+            }
+            if conversations::membership(conversation_id, user_id)
+                .await?
+                .is_none()
+            {
+                send_error(sender, NOT_A_MEMBER);
+                return Ok(());
+            }
+            state.typing.insert(conversation_id, now);
+            fan_out(
+                conversation_id,
+                user_id,
+                &ServerFrame::Typing {
+                    conversation_id,
+                    from: user_id,
+                    typing: true,
+                },
+            )
+            .await?;
+        }
+        ClientFrame::TypingStop { conversation_id } => {
+            // Only members that were told about typing need to hear that it stopped
+            if state.typing.remove(&conversation_id).is_some() {
+                fan_out(
+                    conversation_id,
+                    user_id,
+                    &ServerFrame::Typing {
+                        conversation_id,
+                        from: user_id,
+                        typing: false,
+                    },
+                )
+                .await?;
+            }
+        }
+        ClientFrame::WatchPresence { users } => {
+            if users.len() > MAX_WATCHED_USERS {
+                send_error(
+                    sender,
+                    format!("Cannot watch more than {MAX_WATCHED_USERS} users"),
+                );
+                return Ok(());
+            }
+            guard.set_watching(users.iter().copied());
+            // Watchers need a starting point, since only changes are pushed afterwards
+            for user_id in users {
+                let _ = sender.send(ServerFrame::Presence {
+                    user_id,
+                    online: presence::is_online(user_id),
+                });
+            }
+        }
+    }
+    Ok(())
+}
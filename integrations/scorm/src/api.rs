@@ -0,0 +1,328 @@
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    rngs::OsRng,
+};
+use sea_orm::{entity::prelude::*, ActiveValue};
+use serde::{Deserialize, Serialize};
+use teach_tech_core::{
+    anyhow,
+    auth::{AuthUser, UserID},
+    axum::{
+        body::Bytes,
+        extract::{Path, Query},
+        http::StatusCode,
+        response::{IntoResponse, Response},
+        Json,
+    },
+    db::{get_db, get_read_db, insert_batched, paginate, transaction_with_retry, PageQuery},
+    i18n,
+    storage::{get_storage, put_upload, UploadError},
+    tokio,
+};
+use tracing::{error, info};
+
+use crate::{
+    authorize_staff, launches,
+    manifest::{self, Limits, PackageError},
+    options, packages, results, statements,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct UploadQuery {
+    /// Replaces the title from the manifest
+    pub title: Option<String>,
+}
+
+/// Stores a package sent as the body, along with every file in it.
+pub async fn upload_package(
+    AuthUser { user_id, .. }: AuthUser,
+    Query(query): Query<UploadQuery>,
+    body: Bytes,
+) -> Response {
+    if let Err(response) = authorize_staff(user_id).await {
+        return response;
+    }
+    let Some(storage) = get_storage() else {
+        return UploadError::Unavailable(anyhow::anyhow!("Storage is not configured"))
+            .into_response();
+    };
+
+    let options = options();
+    let limits = Limits {
+        max_files: options.max_files,
+        max_unpacked_bytes: options.max_unpacked_mb * 1024 * 1024,
+    };
+    let package =
+        match tokio::task::spawn_blocking(move || manifest::read_package(&body, limits)).await {
+            Ok(Ok(package)) => package,
+            Ok(Err(PackageError::Unrecognized)) => {
+                return (StatusCode::BAD_REQUEST, i18n::t("scorm.unrecognized")).into_response();
+            }
+            Ok(Err(PackageError::TooLarge)) => {
+                return (StatusCode::PAYLOAD_TOO_LARGE, i18n::t("scorm.too_large")).into_response();
+            }
+            Ok(Err(PackageError::Invalid(e))) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    i18n::t_with("scorm.invalid", &[("reason", &format!("{e:#}"))]),
+                )
+                    .into_response();
+            }
+            Err(e) => {
+                error!("Error reading SCORM package: {e:#}");
+                return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+            }
+        };
+
+    let key_prefix = format!(
+        "scorm/{}",
+        Alphanumeric
+            .sample_string(&mut OsRng, 24)
+            .to_ascii_lowercase()
+    );
+    let mut files: Vec<packages::files::Model> = vec![];
+    let mut size = 0;
+    for (i, file) in package.files.into_iter().enumerate() {
+        let key = format!("{key_prefix}/{i}");
+        let content_type = manifest::content_type(&file.path);
+        size += file.bytes.len() as i64;
+        if let Err(e) = put_upload(&key, file.bytes, content_type, user_id).await {
+            // Whatever was stored so far is of no use without the rest
+            for file in &files {
+                if let Err(e) = storage.delete(&file.key).await {
+                    error!("Error deleting {}: {e:#}", file.key);
+                }
+            }
+            return e.into_response();
+        }
+        files.push(packages::files::Model {
+            package_id: 0,
+            path: file.path,
+            key,
+            content_type: content_type.to_string(),
+        });
+    }
+
+    let package = packages::Model {
+        id: 0,
+        title: query
+            .title
+            .filter(|title| !title.trim().is_empty())
+            .unwrap_or(package.title),
+        kind: package.kind,
+        launch_path: package.launch_path,
+        activity_id: package.activity_id,
+        key_prefix,
+        size,
+        file_count: files.len() as i32,
+        uploaded_by: user_id,
+        created_at: chrono::Utc::now().naive_utc(),
+    };
+    let result = transaction_with_retry(get_db(), |txn| {
+        let package = package.clone();
+        let files = files.clone();
+        Box::pin(async move {
+            let package = packages::ActiveModel {
+                id: ActiveValue::not_set(),
+                ..package.into()
+            }
+            .insert(txn)
+            .await?;
+            insert_batched(
+                files.into_iter().map(|file| packages::files::ActiveModel {
+                    package_id: ActiveValue::set(package.id),
+                    ..file.into()
+                }),
+                txn,
+            )
+            .await?;
+            Ok(package)
+        })
+    })
+    .await;
+    match result {
+        Ok(package) => {
+            info!(
+                "{user_id} uploaded {:?} as SCORM package {}",
+                package.title, package.id
+            );
+            (StatusCode::CREATED, Json(package)).into_response()
+        }
+        Err(e) => {
+            error!("Error saving SCORM package: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+pub async fn list_packages(_: AuthUser, Query(page): Query<PageQuery>) -> Response {
+    let Ok(cursor) = page.cursor::<i32>() else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_cursor")).into_response();
+    };
+    match paginate(
+        packages::Entity::find(),
+        packages::Column::Id,
+        |package| package.id,
+        cursor,
+        page.limit(),
+        get_read_db(),
+    )
+    .await
+    {
+        Ok(packages) => (StatusCode::OK, Json(packages)).into_response(),
+        Err(e) => {
+            error!("Error listing SCORM packages: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+/// Deletes a package and its files. The statements and results it led to are kept.
+pub async fn delete_package(AuthUser { user_id, .. }: AuthUser, Path(id): Path<i32>) -> Response {
+    if let Err(response) = authorize_staff(user_id).await {
+        return response;
+    }
+    let result = transaction_with_retry(get_db(), |txn| {
+        Box::pin(async move {
+            if packages::Entity::find_by_id(id).one(txn).await?.is_none() {
+                return Ok(None);
+            }
+            let files = packages::files::Entity::find()
+                .filter(packages::files::Column::PackageId.eq(id))
+                .all(txn)
+                .await?;
+            packages::files::Entity::delete_many()
+                .filter(packages::files::Column::PackageId.eq(id))
+                .exec(txn)
+                .await?;
+            launches::Entity::delete_many()
+                .filter(launches::Column::PackageId.eq(id))
+                .exec(txn)
+                .await?;
+            statements::states::Entity::delete_many()
+                .filter(statements::states::Column::PackageId.eq(id))
+                .exec(txn)
+                .await?;
+            packages::Entity::delete_by_id(id).exec(txn).await?;
+            Ok(Some(files))
+        })
+    })
+    .await;
+    let files = match result {
+        Ok(Some(files)) => files,
+        Ok(None) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => {
+            error!("Error deleting SCORM package: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+    // The package is gone either way, so files that fail to delete are only logged
+    if let Some(storage) = get_storage() {
+        for file in files {
+            if let Err(e) = storage.delete(&file.key).await {
+                error!("Error deleting {}: {e:#}", file.key);
+            }
+        }
+    }
+    (StatusCode::OK, ()).into_response()
+}
+
+#[derive(Debug, Serialize)]
+pub struct Launch {
+    /// Where to open the player, relative to the API
+    pub url: String,
+    pub registration: String,
+    pub expires_at: DateTime,
+}
+
+/// Starts an attempt at a package for the user.
+pub async fn launch(AuthUser { user_id, .. }: AuthUser, Path(id): Path<i32>) -> Response {
+    match packages::Entity::find_by_id(id).one(get_read_db()).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => {
+            error!("Error reading SCORM package: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    }
+    let now = chrono::Utc::now().naive_utc();
+    let launch = launches::ActiveModel {
+        token: ActiveValue::set(Alphanumeric.sample_string(&mut OsRng, 32)),
+        package_id: ActiveValue::set(id),
+        user_id: ActiveValue::set(user_id),
+        registration: ActiveValue::set(uuid::Uuid::new_v4().to_string()),
+        created_at: ActiveValue::set(now),
+        expires_at: ActiveValue::set(now + chrono::TimeDelta::hours(options().launch_hours)),
+    };
+    match launch.insert(get_db()).await {
+        Ok(launch) => (
+            StatusCode::CREATED,
+            Json(Launch {
+                url: format!("/scorm/play/{}", launch.token),
+                registration: launch.registration,
+                expires_at: launch.expires_at,
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            error!("Error creating SCORM launch: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+/// The results of every user who played a package.
+pub async fn package_results(
+    AuthUser { user_id, .. }: AuthUser,
+    Path(id): Path<i32>,
+    Query(page): Query<PageQuery>,
+) -> Response {
+    if let Err(response) = authorize_staff(user_id).await {
+        return response;
+    }
+    let Ok(cursor) = page.cursor::<UserID>() else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_cursor")).into_response();
+    };
+    match paginate(
+        results::Entity::find().filter(results::Column::PackageId.eq(id)),
+        results::Column::UserId,
+        |result| result.user_id,
+        cursor,
+        page.limit(),
+        get_read_db(),
+    )
+    .await
+    {
+        Ok(results) => (StatusCode::OK, Json(results)).into_response(),
+        Err(e) => {
+            error!("Error listing SCORM results: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+/// The results of the user, for every package they played.
+pub async fn own_results(
+    AuthUser { user_id, .. }: AuthUser,
+    Query(page): Query<PageQuery>,
+) -> Response {
+    let Ok(cursor) = page.cursor::<i32>() else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_cursor")).into_response();
+    };
+    match paginate(
+        results::Entity::find().filter(results::Column::UserId.eq(user_id)),
+        results::Column::PackageId,
+        |result| result.package_id,
+        cursor,
+        page.limit(),
+        get_read_db(),
+    )
+    .await
+    {
+        Ok(results) => (StatusCode::OK, Json(results)).into_response(),
+        Err(e) => {
+            error!("Error listing SCORM results: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
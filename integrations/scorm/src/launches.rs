@@ -0,0 +1,30 @@
+use sea_orm::entity::prelude::*;
+use teach_tech_core::{auth::UserID, db::get_read_db};
+
+/// An attempt at a package. Its token authenticates the player, the content it serves, and the
+/// statements the content sends, as packages cannot be given bearer tokens.
+#[derive(Clone, Debug, DeriveEntityModel)]
+#[sea_orm(table_name = "scorm_launches")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub token: String,
+    pub package_id: i32,
+    pub user_id: UserID,
+    /// The xAPI registration of the attempt
+    pub registration: String,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// The launch with `token`, unless it expired.
+pub(crate) async fn find_live(token: &str) -> Result<Option<Model>, DbErr> {
+    Entity::find_by_id(token)
+        .filter(Column::ExpiresAt.gt(chrono::Utc::now().naive_utc()))
+        .one(get_read_db())
+        .await
+}
@@ -0,0 +1,183 @@
+//! SCORM 1.2, SCORM 2004 and xAPI (Tin Can) content packages.
+//!
+//! Packages are played inside an iframe on the API's origin, since SCORM content finds its
+//! runtime through `window.parent`. Content can therefore run scripts as the API, which is why
+//! only instructors and administrators can upload packages.
+
+use std::{sync::OnceLock, time::Duration};
+
+use serde::Deserialize;
+use teach_tech_core::{
+    anyhow,
+    auth::UserID,
+    axum::{
+        extract::DefaultBodyLimit,
+        http::StatusCode,
+        response::{IntoResponse, Response},
+        routing::{delete, get, post},
+    },
+    db::{get_read_db, SoftDeletable},
+    i18n,
+    users::{admins, instructors},
+    TeachCore,
+};
+use tracing::error;
+
+pub mod api;
+pub mod launches;
+pub mod manifest;
+pub mod packages;
+pub mod player;
+pub mod results;
+pub mod statements;
+pub mod xapi;
+
+static OPTIONS: OnceLock<ScormOptions> = OnceLock::new();
+
+const MESSAGES: &[(&str, &str)] = &[
+    (
+        "scorm.must_be_staff",
+        "Only instructors and administrators can manage content packages",
+    ),
+    (
+        "scorm.unrecognized",
+        "Expected a zip with an imsmanifest.xml or a tincan.xml",
+    ),
+    (
+        "scorm.too_large",
+        "The package unpacks to more than the allowed size",
+    ),
+    ("scorm.invalid", "The package could not be read: {reason}"),
+    ("scorm.invalid_statement", "Invalid statement: {reason}"),
+];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ScormConfig {
+    #[serde(default)]
+    pub scorm: ScormOptions,
+}
+
+/// The `[scorm]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScormOptions {
+    /// How long a launch can play and report statements for
+    #[serde(default = "default_launch_hours")]
+    pub launch_hours: i64,
+    #[serde(default = "default_max_package_mb")]
+    pub max_package_mb: usize,
+    #[serde(default = "default_max_unpacked_mb")]
+    pub max_unpacked_mb: u64,
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+impl Default for ScormOptions {
+    fn default() -> Self {
+        Self {
+            launch_hours: default_launch_hours(),
+            max_package_mb: default_max_package_mb(),
+            max_unpacked_mb: default_max_unpacked_mb(),
+            max_files: default_max_files(),
+        }
+    }
+}
+
+fn default_launch_hours() -> i64 {
+    8
+}
+
+fn default_max_package_mb() -> usize {
+    512
+}
+
+fn default_max_unpacked_mb() -> u64 {
+    1024
+}
+
+fn default_max_files() -> usize {
+    5000
+}
+
+pub(crate) fn options() -> &'static ScormOptions {
+    OPTIONS.get_or_init(ScormOptions::default)
+}
+
+/// Refuses users who are neither an admin nor an instructor with 403.
+pub(crate) async fn authorize_staff(user_id: UserID) -> Result<(), Response> {
+    let result = async {
+        if admins::Entity::find_live_by_id(user_id)
+            .one(get_read_db())
+            .await?
+            .is_some()
+        {
+            return Ok(true);
+        }
+        instructors::Entity::find_live_by_id(user_id)
+            .one(get_read_db())
+            .await
+            .map(|instructor| instructor.is_some())
+    }
+    .await;
+    match result {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((StatusCode::FORBIDDEN, i18n::t("scorm.must_be_staff")).into_response()),
+        Err(e) => {
+            error!("Error reading user roles: {e:#}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, ()).into_response())
+        }
+    }
+}
+
+pub async fn add_to_core<S: Clone + Send + Sync + 'static>(
+    mut core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    let mut info = fxhash::FxHashMap::default();
+    info.insert("version", env!("CARGO_PKG_VERSION"));
+    core.add_info("scorm", info);
+    i18n::add_catalog(i18n::FALLBACK_LOCALE, MESSAGES.iter().copied());
+    core.declare_config::<ScormConfig>();
+    let config: ScormConfig = toml::from_str(core.get_config_str())?;
+    core.add_db_reset_config(packages::Entity);
+    core.add_db_reset_config(packages::files::Entity);
+    core.add_db_reset_config(launches::Entity);
+    core.add_db_reset_config(statements::Entity);
+    core.add_db_reset_config(statements::states::Entity);
+    core.add_db_reset_config(results::Entity);
+    core.add_retention(
+        launches::Entity,
+        launches::Column::ExpiresAt,
+        Duration::from_secs(24 * 60 * 60),
+    );
+    let max_package_bytes = config.scorm.max_package_mb * 1024 * 1024;
+    let _ = OPTIONS.set(config.scorm);
+
+    core = core.modify_router(|router| {
+        router
+            .route(
+                "/scorm/packages",
+                get(api::list_packages)
+                    .post(api::upload_package)
+                    .layer(DefaultBodyLimit::max(max_package_bytes)),
+            )
+            .route("/scorm/packages/:id", delete(api::delete_package))
+            .route("/scorm/packages/:id/launches", post(api::launch))
+            .route("/scorm/packages/:id/results", get(api::package_results))
+            .route("/scorm/results", get(api::own_results))
+            .route("/scorm/play/:token", get(player::player))
+            .route("/scorm/play/:token/content/*path", get(player::content))
+            .route("/scorm/xapi/about", get(xapi::about))
+            .route(
+                "/scorm/xapi/statements",
+                post(xapi::post_statements).put(xapi::put_statement),
+            )
+            .route(
+                "/scorm/xapi/activities/state",
+                get(xapi::get_state)
+                    .put(xapi::put_state)
+                    .post(xapi::put_state)
+                    .delete(xapi::delete_state),
+            )
+    });
+
+    Ok(core)
+}
@@ -0,0 +1,307 @@
+//! Reading uploaded packages, which are zips with an `imsmanifest.xml` (SCORM) or a `tincan.xml`
+//! (xAPI) describing what to launch.
+
+use std::io::{Cursor, Read};
+
+use roxmltree::{Document, Node};
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+use teach_tech_core::anyhow::{self, Context};
+
+const SCORM_MANIFEST: &str = "imsmanifest.xml";
+const XAPI_MANIFEST: &str = "tincan.xml";
+
+#[derive(EnumIter, DeriveActiveEnum, Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[sea_orm(rs_type = "i32", db_type = "Integer")]
+#[serde(rename_all = "snake_case")]
+pub enum PackageKind {
+    Scorm12 = 0,
+    Scorm2004 = 1,
+    Xapi = 2,
+}
+
+/// Limits on what a package may unpack to, so that a small upload cannot fill memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_files: usize,
+    pub max_unpacked_bytes: u64,
+}
+
+#[derive(Debug)]
+pub enum PackageError {
+    /// Not a zip, or a zip without a manifest
+    Unrecognized,
+    TooLarge,
+    Invalid(anyhow::Error),
+}
+
+impl From<std::io::Error> for PackageError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Invalid(e.into())
+    }
+}
+
+impl From<zip::result::ZipError> for PackageError {
+    fn from(e: zip::result::ZipError) -> Self {
+        Self::Invalid(e.into())
+    }
+}
+
+#[derive(Debug)]
+pub struct PackageFile {
+    /// The path of the file relative to the manifest
+    pub path: String,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Package {
+    pub kind: PackageKind,
+    pub title: String,
+    /// The file the player opens first, which may have a query string
+    pub launch_path: String,
+    /// The activity ID from `tincan.xml`. SCORM packages get one from their package ID instead
+    pub activity_id: Option<String>,
+    pub files: Vec<PackageFile>,
+}
+
+/// Elements of `node` with the local name `name`, whatever their namespace.
+fn children<'a, 'i>(node: Node<'a, 'i>, name: &'static str) -> impl Iterator<Item = Node<'a, 'i>> {
+    node.children()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn child<'a, 'i>(node: Node<'a, 'i>, name: &'static str) -> Option<Node<'a, 'i>> {
+    children(node, name).next()
+}
+
+fn child_text(node: Node, name: &'static str) -> Option<String> {
+    child(node, name)
+        .and_then(|child| child.text())
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .map(str::to_string)
+}
+
+fn parse_xml<'i>(path: &str, text: &'i str) -> anyhow::Result<Document<'i>> {
+    Document::parse(text).with_context(|| format!("Parsing {path}"))
+}
+
+/// Joins a launch file and the parameters a manifest gives it, which may or may not start with
+/// `?` or `&`.
+fn with_parameters(href: &str, parameters: Option<&str>) -> String {
+    let parameters = parameters
+        .unwrap_or_default()
+        .trim_start_matches(['?', '&']);
+    if parameters.is_empty() {
+        href.to_string()
+    } else if href.contains('?') {
+        format!("{href}&{parameters}")
+    } else {
+        format!("{href}?{parameters}")
+    }
+}
+
+/// The first item of `item` and its descendants that launches a resource.
+fn first_launchable<'a, 'i>(item: Node<'a, 'i>) -> impl Iterator<Item = Node<'a, 'i>> {
+    item.descendants().filter(|node| {
+        node.is_element()
+            && node.tag_name().name() == "item"
+            && node.attribute("identifierref").is_some()
+    })
+}
+
+/// Reads a SCORM manifest. Packages with several SCOs are launched at their first, which is
+/// expected to lead to the others.
+fn scorm(text: &str) -> anyhow::Result<(PackageKind, String, String)> {
+    let doc = parse_xml(SCORM_MANIFEST, text)?;
+    let manifest = doc.root_element();
+    let version =
+        child(manifest, "metadata").and_then(|metadata| child_text(metadata, "schemaversion"));
+    let kind = match version.as_deref() {
+        Some("1.2") => PackageKind::Scorm12,
+        Some(_) => PackageKind::Scorm2004,
+        // Without a version, the namespace of the SCORM extensions tells them apart
+        None if text.contains("adlcp_rootv1p2") => PackageKind::Scorm12,
+        None => PackageKind::Scorm2004,
+    };
+
+    let organizations = child(manifest, "organizations")
+        .with_context(|| format!("{SCORM_MANIFEST} has no organizations"))?;
+    let default = organizations.attribute("default");
+    let organization = children(organizations, "organization")
+        .find(|organization| {
+            default.is_some_and(|id| organization.attribute("identifier") == Some(id))
+        })
+        .or_else(|| child(organizations, "organization"))
+        .with_context(|| format!("{SCORM_MANIFEST} has no organization"))?;
+    let title = child_text(organization, "title")
+        .or_else(|| manifest.attribute("identifier").map(str::to_string))
+        .unwrap_or_else(|| "Untitled package".into());
+
+    let resources: Vec<_> = child(manifest, "resources")
+        .into_iter()
+        .flat_map(|resources| children(resources, "resource"))
+        .collect();
+    let launch = first_launchable(organization)
+        .find_map(|item| {
+            let id = item.attribute("identifierref")?;
+            let resource = resources
+                .iter()
+                .find(|resource| resource.attribute("identifier") == Some(id))?;
+            let href = resource.attribute("href")?;
+            // Resources may sit under a base directory
+            let base = resource
+                .attributes()
+                .find(|attribute| attribute.name() == "base")
+                .map(|attribute| attribute.value())
+                .unwrap_or_default();
+            Some(with_parameters(
+                &format!("{base}{href}"),
+                item.attribute("parameters"),
+            ))
+        })
+        .with_context(|| format!("{SCORM_MANIFEST} has nothing to launch"))?;
+    Ok((kind, title, launch))
+}
+
+/// Reads a `tincan.xml`, launching the first activity that can be.
+fn xapi(text: &str) -> anyhow::Result<(String, String, String)> {
+    let doc = parse_xml(XAPI_MANIFEST, text)?;
+    let activity = child(doc.root_element(), "activities")
+        .into_iter()
+        .flat_map(|activities| children(activities, "activity"))
+        .find(|activity| child_text(*activity, "launch").is_some())
+        .with_context(|| format!("{XAPI_MANIFEST} has nothing to launch"))?;
+    let id = activity
+        .attribute("id")
+        .with_context(|| format!("{XAPI_MANIFEST} has an activity without an id"))?
+        .to_string();
+    let title = child_text(activity, "name").unwrap_or_else(|| id.clone());
+    Ok((
+        title,
+        child_text(activity, "launch").unwrap_or_default(),
+        id,
+    ))
+}
+
+/// Unpacks a package, keeping the files under the directory of its manifest, which is the root
+/// of most packages but a single folder in some.
+pub fn read_package(bytes: &[u8], limits: Limits) -> Result<Package, PackageError> {
+    if !bytes.starts_with(b"PK\x03\x04") {
+        return Err(PackageError::Unrecognized);
+    }
+    let mut zip = zip::ZipArchive::new(Cursor::new(bytes))?;
+    let mut paths = vec![];
+    for i in 0..zip.len() {
+        let file = zip.by_index(i)?;
+        // Entries that would escape the package, such as `../x`, are left out
+        if let (true, Some(path)) = (file.is_file(), file.enclosed_name()) {
+            let segments: Vec<_> = path
+                .iter()
+                .map(|segment| segment.to_string_lossy())
+                .collect();
+            paths.push((i, segments.join("/")));
+        }
+    }
+    let manifest = paths
+        .iter()
+        .filter(|(_, path)| {
+            let name = path.rsplit('/').next().unwrap_or_default();
+            name == SCORM_MANIFEST || name == XAPI_MANIFEST
+        })
+        .min_by_key(|(_, path)| (path.matches('/').count(), !path.ends_with(SCORM_MANIFEST)))
+        .map(|(_, path)| path.clone())
+        .ok_or(PackageError::Unrecognized)?;
+    let prefix =
+        &manifest[..manifest.len() - manifest.rsplit('/').next().unwrap_or_default().len()];
+
+    let mut files = vec![];
+    let mut unpacked = 0u64;
+    for (i, path) in &paths {
+        let Some(path) = path.strip_prefix(prefix) else {
+            continue;
+        };
+        if files.len() >= limits.max_files {
+            return Err(PackageError::TooLarge);
+        }
+        let file = zip.by_index(*i)?;
+        let remaining = limits.max_unpacked_bytes.saturating_sub(unpacked);
+        if file.size() > remaining {
+            return Err(PackageError::TooLarge);
+        }
+        // The declared size can lie, so reading stops at the limit either way
+        let mut bytes = vec![];
+        file.take(remaining + 1).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 > remaining {
+            return Err(PackageError::TooLarge);
+        }
+        unpacked += bytes.len() as u64;
+        files.push(PackageFile {
+            path: path.to_string(),
+            bytes,
+        });
+    }
+
+    let manifest_name = &manifest[prefix.len()..];
+    let text = files
+        .iter()
+        .find(|file| file.path == manifest_name)
+        .map(|file| String::from_utf8_lossy(&file.bytes).into_owned())
+        .unwrap_or_default();
+    let (kind, title, launch_path, activity_id) = if manifest_name == SCORM_MANIFEST {
+        let (kind, title, launch) = scorm(&text).map_err(PackageError::Invalid)?;
+        (kind, title, launch, None)
+    } else {
+        let (title, launch, id) = xapi(&text).map_err(PackageError::Invalid)?;
+        (PackageKind::Xapi, title, launch, Some(id))
+    };
+    let launch_file = launch_path.split(['?', '#']).next().unwrap_or_default();
+    if !files.iter().any(|file| file.path == launch_file) {
+        return Err(PackageError::Invalid(anyhow::anyhow!(
+            "The package launches {launch_file}, which it does not have"
+        )));
+    }
+    Ok(Package {
+        kind,
+        title,
+        launch_path,
+        activity_id,
+        files,
+    })
+}
+
+/// The type to serve a package file as, from its extension.
+pub fn content_type(path: &str) -> &'static str {
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" => "application/json",
+        "xml" | "xsd" => "application/xml",
+        "txt" => "text/plain; charset=utf-8",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "ogg" => "audio/ogg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "vtt" => "text/vtt",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,60 @@
+use sea_orm::entity::prelude::*;
+use serde::Serialize;
+use teach_tech_core::auth::UserID;
+
+use crate::manifest::PackageKind;
+
+#[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "scorm_packages")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub title: String,
+    pub kind: PackageKind,
+    /// The file the player opens first, relative to the package
+    pub launch_path: String,
+    /// Set by xAPI packages, which report statements about their own activity
+    pub activity_id: Option<String>,
+    /// Where the files of the package are stored
+    #[serde(skip_serializing)]
+    pub key_prefix: String,
+    pub size: i64,
+    pub file_count: i32,
+    pub uploaded_by: UserID,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    /// The activity statements about the package are for.
+    pub fn activity_id(&self) -> String {
+        self.activity_id
+            .clone()
+            .unwrap_or_else(|| format!("urn:teach-tech:scorm:package:{}", self.id))
+    }
+}
+
+/// The files of packages, whose paths can hold anything, so they are stored under numbered keys.
+pub mod files {
+    use super::*;
+
+    #[derive(Clone, Debug, DeriveEntityModel)]
+    #[sea_orm(table_name = "scorm_package_files")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub package_id: i32,
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub path: String,
+        pub key: String,
+        pub content_type: String,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
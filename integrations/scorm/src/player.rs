@@ -0,0 +1,290 @@
+//! The page that plays a package, with the SCORM runtime content looks for in its parent window.
+//!
+//! The runtime keeps the SCORM data model in the xAPI State API so that learners can resume, and
+//! reports progress as xAPI statements, which is all xAPI packages need.
+
+use std::fmt::Write;
+
+use sea_orm::{DbErr, EntityTrait};
+use teach_tech_core::{
+    axum::{
+        extract::Path,
+        http::{header, StatusCode},
+        response::{IntoResponse, Response},
+    },
+    db::get_read_db,
+    serde_json::json,
+    storage::get_storage,
+};
+use tracing::error;
+
+use crate::{launches, manifest::PackageKind, packages};
+
+const PLAYER: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>html, body, iframe { margin: 0; width: 100%; height: 100%; border: 0; display: block; }</style>
+</head>
+<body>
+<iframe id="content" title="{title}" allow="fullscreen; autoplay"></iframe>
+<script type="application/json" id="config">{config}</script>
+<script>
+(function () {
+  "use strict";
+  var config = JSON.parse(document.getElementById("config").textContent);
+  var endpoint = new URL(config.endpoint, location.href).href;
+  var auth = "Bearer " + config.token;
+  var headers = {
+    "Authorization": auth,
+    "X-Experience-API-Version": "1.0.3",
+    "Content-Type": "application/json"
+  };
+  var actor = { objectType: "Agent", account: { homePage: location.origin, name: config.learnerId } };
+  var stateUrl = endpoint + "activities/state?stateId=scorm-cmi" +
+    "&activityId=" + encodeURIComponent(config.activityId) +
+    "&agent=" + encodeURIComponent(JSON.stringify(actor)) +
+    "&registration=" + config.registration;
+  var scorm12 = config.kind === "scorm12";
+  var prefix = scorm12 ? "cmi.core." : "cmi.";
+  var cmi = {};
+  var initialized = false;
+  var lastSent = "";
+
+  function send(url, method, body) {
+    return fetch(url, { method: method, headers: headers, body: body, keepalive: true })
+      .catch(function () {});
+  }
+
+  function number(key) {
+    var value = parseFloat(cmi[key]);
+    return isNaN(value) ? null : value;
+  }
+
+  // SCORM 1.2 scores are raw, between a minimum and a maximum of 100 by default
+  function scaledScore() {
+    var scaled = scorm12 ? null : number("cmi.score.scaled");
+    var raw = number(prefix + "score.raw");
+    var min = number(prefix + "score.min") || 0;
+    var max = number(prefix + "score.max");
+    if (max === null && scorm12) max = 100;
+    if (scaled === null && raw !== null && max !== null && max > min) scaled = (raw - min) / (max - min);
+    return scaled === null ? null : Math.max(0, Math.min(1, scaled));
+  }
+
+  function statement() {
+    var status = scorm12 ? cmi["cmi.core.lesson_status"] : cmi["cmi.success_status"];
+    var success = status === "passed" ? true : status === "failed" ? false : null;
+    var completion = scorm12
+      ? status === "completed" || success !== null
+      : cmi["cmi.completion_status"] === "completed";
+    var verb = success === true ? "passed" : success === false ? "failed" : completion ? "completed" : "progressed";
+    var result = { completion: completion };
+    if (success !== null) result.success = success;
+    var scaled = scaledScore();
+    if (scaled !== null) result.score = { scaled: scaled };
+    return {
+      actor: actor,
+      verb: { id: "http://adlnet.gov/expapi/verbs/" + verb, display: { "en-US": verb } },
+      object: {
+        objectType: "Activity",
+        id: config.activityId,
+        definition: { name: { "en-US": document.title } }
+      },
+      result: result,
+      context: { registration: config.registration }
+    };
+  }
+
+  function commit() {
+    if (!initialized) return "false";
+    send(stateUrl, "PUT", JSON.stringify(cmi));
+    var next = statement();
+    var key = JSON.stringify([next.verb.id, next.result]);
+    if (key !== lastSent) {
+      lastSent = key;
+      send(endpoint + "statements", "POST", JSON.stringify(next));
+    }
+    return "true";
+  }
+  function initialize() { initialized = true; return "true"; }
+  function finish() { var result = commit(); initialized = false; return result; }
+  function getValue(key) { return key in cmi ? String(cmi[key]) : ""; }
+  function setValue(key, value) { cmi[key] = String(value); return "true"; }
+  function noError() { return "0"; }
+  function noText() { return ""; }
+
+  window.API = {
+    LMSInitialize: initialize, LMSFinish: finish, LMSCommit: commit,
+    LMSGetValue: getValue, LMSSetValue: setValue,
+    LMSGetLastError: noError, LMSGetErrorString: noText, LMSGetDiagnostic: noText
+  };
+  window.API_1484_11 = {
+    Initialize: initialize, Terminate: finish, Commit: commit,
+    GetValue: getValue, SetValue: setValue,
+    GetLastError: noError, GetErrorString: noText, GetDiagnostic: noText
+  };
+  window.addEventListener("pagehide", commit);
+
+  fetch(stateUrl, { headers: headers })
+    .then(function (response) { return response.ok ? response.json() : {}; })
+    .catch(function () { return {}; })
+    .then(function (saved) {
+      cmi = saved && typeof saved === "object" ? saved : {};
+      var entry = cmi[prefix + "exit"] === "suspend" ? "resume" : "ab-initio";
+      cmi[prefix + "entry"] = entry;
+      cmi[prefix + (scorm12 ? "lesson_mode" : "mode")] = "normal";
+      cmi[prefix + "credit"] = "credit";
+      cmi[prefix + (scorm12 ? "student_id" : "learner_id")] = config.learnerId;
+      cmi[prefix + (scorm12 ? "student_name" : "learner_name")] = config.learnerId;
+      if (scorm12 && !cmi["cmi.core.lesson_status"]) cmi["cmi.core.lesson_status"] = "not attempted";
+      if (!scorm12 && !cmi["cmi.completion_status"]) cmi["cmi.completion_status"] = "unknown";
+      if (!scorm12 && !cmi["cmi.success_status"]) cmi["cmi.success_status"] = "unknown";
+
+      var src = config.launch;
+      // xAPI packages are told where to send statements in the query string
+      if (config.kind === "xapi") {
+        src += (src.indexOf("?") < 0 ? "?" : "&") +
+          "endpoint=" + encodeURIComponent(endpoint) +
+          "&auth=" + encodeURIComponent(auth) +
+          "&actor=" + encodeURIComponent(JSON.stringify(actor)) +
+          "&registration=" + config.registration +
+          "&activity_id=" + encodeURIComponent(config.activityId);
+      }
+      document.getElementById("content").src = src;
+    });
+})();
+</script>
+</body>
+</html>
+"#;
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            // So that a title cannot hold the placeholders of the page
+            '{' => escaped.push_str("&#123;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encodes the path of a launch file, leaving its query string as the manifest gave it.
+fn encode_launch_path(launch_path: &str) -> String {
+    let (path, query) = match launch_path.find(['?', '#']) {
+        Some(i) => launch_path.split_at(i),
+        None => (launch_path, ""),
+    };
+    let mut encoded = String::new();
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'/' | b'.' | b'_' | b'-' | b'~') {
+            encoded.push(b as char);
+        } else {
+            let _ = write!(encoded, "%{b:02X}");
+        }
+    }
+    encoded + query
+}
+
+async fn find_launch(token: &str) -> Result<(launches::Model, packages::Model), Response> {
+    let result = async {
+        let Some(launch) = launches::find_live(token).await? else {
+            return Ok(None);
+        };
+        let package = packages::Entity::find_by_id(launch.package_id)
+            .one(get_read_db())
+            .await?;
+        Ok::<_, DbErr>(package.map(|package| (launch, package)))
+    }
+    .await;
+    match result {
+        Ok(Some(found)) => Ok(found),
+        Ok(None) => Err((StatusCode::NOT_FOUND, ()).into_response()),
+        Err(e) => {
+            error!("Error reading SCORM launch: {e:#}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, ()).into_response())
+        }
+    }
+}
+
+pub async fn player(Path(token): Path<String>) -> Response {
+    let (launch, package) = match find_launch(&token).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let kind = match package.kind {
+        PackageKind::Scorm12 => "scorm12",
+        PackageKind::Scorm2004 => "scorm2004",
+        PackageKind::Xapi => "xapi",
+    };
+    // Paths are relative to the page, which is /scorm/play/{token}
+    let config = json!({
+        "kind": kind,
+        "launch": format!("{token}/content/{}", encode_launch_path(&package.launch_path)),
+        "endpoint": "../xapi/",
+        "token": launch.token,
+        "registration": launch.registration,
+        "activityId": package.activity_id(),
+        "learnerId": launch.user_id.to_string(),
+    });
+    let html = PLAYER
+        .replace("{title}", &escape_html(&package.title))
+        .replace("{config}", &config.to_string().replace("</", "<\\/"));
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        html,
+    )
+        .into_response()
+}
+
+/// Serves a file of the package being played.
+pub async fn content(Path((token, path)): Path<(String, String)>) -> Response {
+    let (_, package) = match find_launch(&token).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let file = match packages::files::Entity::find_by_id((package.id, path))
+        .one(get_read_db())
+        .await
+    {
+        Ok(Some(file)) => file,
+        Ok(None) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => {
+            error!("Error reading SCORM package file: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+    let Some(storage) = get_storage() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, ()).into_response();
+    };
+    match storage.get(&file.key).await {
+        Ok(Some(object)) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, file.content_type),
+                (header::CACHE_CONTROL, "private, max-age=3600".to_string()),
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            ],
+            object.bytes,
+        )
+            .into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => {
+            error!("Error reading {} from storage: {e:#}", file.key);
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
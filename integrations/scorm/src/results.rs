@@ -0,0 +1,152 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::BoxFuture;
+use sea_orm::{entity::prelude::*, ActiveValue, TransactionError};
+use serde::Serialize;
+use teach_tech_core::{
+    anyhow,
+    auth::UserID,
+    db::{get_db, transaction_with_retry},
+    serde_json::Value,
+    tokio,
+};
+use tracing::error;
+
+type Sink = Arc<dyn Fn(Model) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>;
+
+static SINKS: Mutex<Vec<(String, Sink)>> = Mutex::new(vec![]);
+
+/// How far each user got with each package, as its statements reported.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "scorm_results")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub package_id: i32,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub user_id: UserID,
+    pub completed: bool,
+    /// Whether the user passed, for packages that say
+    pub success: Option<bool>,
+    /// The latest score, from 0 to 1
+    pub score_scaled: Option<f64>,
+    pub updated_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// Registers a function that is given every result that changes, such as one that copies scores
+/// into a gradebook. Sinks run in the background, and their errors are only logged.
+pub fn add_sink<F, Fut>(name: impl Into<String>, f: F)
+where
+    F: Fn(Model) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    SINKS
+        .lock()
+        .unwrap()
+        .push((name.into(), Arc::new(move |model| Box::pin(f(model)))));
+}
+
+/// What a statement says about the result of an attempt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Outcome {
+    completed: Option<bool>,
+    success: Option<bool>,
+    score_scaled: Option<f64>,
+}
+
+/// Reads the outcome of a statement from its result, or from its verb when it has none.
+pub(crate) fn outcome(statement: &Value) -> Option<Outcome> {
+    let verb = statement["verb"]["id"].as_str().unwrap_or_default();
+    let verb = verb.rsplit('/').next().unwrap_or_default();
+    let result = &statement["result"];
+    let score = &result["score"];
+    let score_scaled = score["scaled"].as_f64().or_else(|| {
+        let raw = score["raw"].as_f64()?;
+        let min = score["min"].as_f64().unwrap_or(0.0);
+        let max = score["max"].as_f64()?;
+        (max > min).then(|| (raw - min) / (max - min))
+    });
+    let outcome = Outcome {
+        completed: result["completion"]
+            .as_bool()
+            .or_else(|| matches!(verb, "completed" | "passed" | "failed").then_some(true)),
+        success: result["success"].as_bool().or(match verb {
+            "passed" => Some(true),
+            "failed" => Some(false),
+            _ => None,
+        }),
+        score_scaled: score_scaled.map(|score| score.clamp(0.0, 1.0)),
+    };
+    (outcome.completed.is_some() || outcome.success.is_some() || outcome.score_scaled.is_some())
+        .then_some(outcome)
+}
+
+/// Merges an outcome into the result of a user, handing the result to every sink if it changed.
+/// Completion is never taken back by later attempts.
+pub(crate) async fn record(
+    package_id: i32,
+    user_id: UserID,
+    outcome: Outcome,
+) -> Result<(), TransactionError<DbErr>> {
+    let changed = transaction_with_retry(get_db(), |txn| {
+        Box::pin(async move {
+            let existing = Entity::find_by_id((package_id, user_id)).one(txn).await?;
+            let now = chrono::Utc::now().naive_utc();
+            let result = Model {
+                package_id,
+                user_id,
+                completed: outcome.completed.unwrap_or_default()
+                    || existing.as_ref().is_some_and(|existing| existing.completed),
+                success: outcome
+                    .success
+                    .or(existing.as_ref().and_then(|existing| existing.success)),
+                score_scaled: outcome
+                    .score_scaled
+                    .or(existing.as_ref().and_then(|existing| existing.score_scaled)),
+                updated_at: now,
+            };
+            let active = ActiveModel {
+                package_id: ActiveValue::set(package_id),
+                user_id: ActiveValue::set(user_id),
+                completed: ActiveValue::set(result.completed),
+                success: ActiveValue::set(result.success),
+                score_scaled: ActiveValue::set(result.score_scaled),
+                updated_at: ActiveValue::set(now),
+            };
+            match existing {
+                Some(existing)
+                    if Model {
+                        updated_at: now,
+                        ..existing
+                    } == result =>
+                {
+                    Ok(None)
+                }
+                Some(_) => Ok(Some(active.update(txn).await?)),
+                None => Ok(Some(active.insert(txn).await?)),
+            }
+        })
+    })
+    .await?;
+
+    let Some(result) = changed else {
+        return Ok(());
+    };
+    let sinks = SINKS.lock().unwrap().clone();
+    for (name, sink) in sinks {
+        let result = result.clone();
+        tokio::spawn(async move {
+            if let Err(e) = sink(result).await {
+                error!("Error handing a SCORM result to {name}: {e:#}");
+            }
+        });
+    }
+    Ok(())
+}
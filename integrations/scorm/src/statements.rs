@@ -0,0 +1,49 @@
+use sea_orm::entity::prelude::*;
+use serde::Serialize;
+use teach_tech_core::auth::UserID;
+
+/// The xAPI statements packages sent, as they were sent.
+#[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "scorm_statements")]
+pub struct Model {
+    /// The statement ID, which is a UUID
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    pub package_id: i32,
+    pub user_id: UserID,
+    pub registration: String,
+    pub verb: String,
+    /// The statement as JSON
+    pub statement: String,
+    pub stored_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// Documents packages keep between attempts through the xAPI State API, which is also where the
+/// player keeps the SCORM data model, so that learners can resume.
+pub mod states {
+    use super::*;
+
+    #[derive(Clone, Debug, DeriveEntityModel)]
+    #[sea_orm(table_name = "scorm_states")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub package_id: i32,
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub user_id: UserID,
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub state_id: String,
+        pub document: Vec<u8>,
+        pub content_type: String,
+        pub updated_at: DateTime,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
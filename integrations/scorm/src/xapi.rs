@@ -0,0 +1,292 @@
+//! The parts of the xAPI 1.0.3 LRS that packages use: storing statements and keeping state.
+//! Packages authenticate with the token of their launch, so they can only report for its user
+//! and package.
+
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization, ContentType},
+    TypedHeader,
+};
+use fxhash::FxHashSet;
+use sea_orm::{entity::prelude::*, ActiveValue};
+use serde::Deserialize;
+use teach_tech_core::{
+    axum::{
+        body::Bytes,
+        extract::Query,
+        http::{header, StatusCode},
+        response::{IntoResponse, Response},
+        Json,
+    },
+    db::{get_db, get_read_db, insert_batched},
+    i18n,
+    serde_json::{self, json, Value},
+};
+use tracing::error;
+
+use crate::{
+    launches,
+    results::{self, outcome},
+    statements::{self, states},
+};
+
+const VERSION: &str = "1.0.3";
+const VERSION_HEADER: &str = "X-Experience-API-Version";
+
+async fn authenticate_launch(bearer: &Bearer) -> Result<launches::Model, Response> {
+    match launches::find_live(bearer.token()).await {
+        Ok(Some(launch)) => Ok(launch),
+        Ok(None) => Err((StatusCode::UNAUTHORIZED, ()).into_response()),
+        Err(e) => {
+            error!("Error reading SCORM launch: {e:#}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, ()).into_response())
+        }
+    }
+}
+
+fn invalid_statement(reason: &str) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        i18n::t_with("scorm.invalid_statement", &[("reason", reason)]),
+    )
+        .into_response()
+}
+
+pub async fn about() -> Response {
+    (
+        StatusCode::OK,
+        [(VERSION_HEADER, VERSION)],
+        Json(json!({ "version": [VERSION] })),
+    )
+        .into_response()
+}
+
+/// Stores statements for a launch, returning their IDs. Statements with an ID that was already
+/// stored are skipped, so that packages can safely resend them.
+async fn store(launch: &launches::Model, statements: Vec<Value>) -> Result<Vec<String>, Response> {
+    let now = chrono::Utc::now().naive_utc();
+    let mut new = vec![];
+    for mut statement in statements {
+        let Some(object) = statement.as_object_mut() else {
+            return Err(invalid_statement("statements must be objects"));
+        };
+        let id = match object.get("id") {
+            Some(Value::String(id)) => match uuid::Uuid::parse_str(id) {
+                Ok(id) => id.to_string(),
+                Err(_) => return Err(invalid_statement("id must be a UUID")),
+            },
+            Some(_) => return Err(invalid_statement("id must be a UUID")),
+            None => uuid::Uuid::new_v4().to_string(),
+        };
+        let Some(verb) = statement["verb"]["id"].as_str().map(str::to_string) else {
+            return Err(invalid_statement("verb.id is required"));
+        };
+        statement["id"] = Value::String(id.clone());
+        statement["stored"] = Value::String(now.and_utc().to_rfc3339());
+        new.push((id, verb, statement));
+    }
+
+    let ids: Vec<_> = new.iter().map(|(id, _, _)| id.clone()).collect();
+    let mut stored: FxHashSet<String> = match statements::Entity::find()
+        .filter(statements::Column::Id.is_in(ids.clone()))
+        .all(get_read_db())
+        .await
+    {
+        Ok(stored) => stored.into_iter().map(|statement| statement.id).collect(),
+        Err(e) => {
+            error!("Error reading xAPI statements: {e:#}");
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, ()).into_response());
+        }
+    };
+    // A batch may repeat an ID too, so only the first statement with each is kept
+    new.retain(|(id, _, _)| stored.insert(id.clone()));
+
+    let outcomes: Vec<_> = new
+        .iter()
+        .filter_map(|(_, _, statement)| outcome(statement))
+        .collect();
+    let models = new
+        .into_iter()
+        .map(|(id, verb, statement)| statements::ActiveModel {
+            id: ActiveValue::set(id),
+            package_id: ActiveValue::set(launch.package_id),
+            user_id: ActiveValue::set(launch.user_id),
+            registration: ActiveValue::set(launch.registration.clone()),
+            verb: ActiveValue::set(verb),
+            statement: ActiveValue::set(statement.to_string()),
+            stored_at: ActiveValue::set(now),
+        });
+    if let Err(e) = insert_batched(models, get_db()).await {
+        error!("Error storing xAPI statements: {e:#}");
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, ()).into_response());
+    }
+    for outcome in outcomes {
+        if let Err(e) = results::record(launch.package_id, launch.user_id, outcome).await {
+            error!("Error recording SCORM result: {e:#}");
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, ()).into_response());
+        }
+    }
+    Ok(ids)
+}
+
+/// Stores one statement or an array of them.
+pub async fn post_statements(
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(body): Json<Value>,
+) -> Response {
+    let launch = match authenticate_launch(&bearer).await {
+        Ok(launch) => launch,
+        Err(response) => return response,
+    };
+    let statements = match body {
+        Value::Array(statements) => statements,
+        statement => vec![statement],
+    };
+    match store(&launch, statements).await {
+        Ok(ids) => (StatusCode::OK, [(VERSION_HEADER, VERSION)], Json(ids)).into_response(),
+        Err(response) => response,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatementQuery {
+    pub statement_id: String,
+}
+
+/// Stores a statement under the ID in the query.
+pub async fn put_statement(
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(query): Query<StatementQuery>,
+    Json(mut statement): Json<Value>,
+) -> Response {
+    let launch = match authenticate_launch(&bearer).await {
+        Ok(launch) => launch,
+        Err(response) => return response,
+    };
+    match statement.get("id") {
+        Some(id) if id.as_str() != Some(&query.statement_id) => {
+            return invalid_statement("id does not match statementId");
+        }
+        Some(_) => {}
+        None if statement.is_object() => {
+            statement["id"] = Value::String(query.statement_id);
+        }
+        None => return invalid_statement("statements must be objects"),
+    }
+    match store(&launch, vec![statement]).await {
+        Ok(_) => (StatusCode::NO_CONTENT, [(VERSION_HEADER, VERSION)]).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Identifies a state document. The agent and activity are those of the launch, whatever the
+/// query says.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StateQuery {
+    pub state_id: String,
+}
+
+pub async fn get_state(
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(query): Query<StateQuery>,
+) -> Response {
+    let launch = match authenticate_launch(&bearer).await {
+        Ok(launch) => launch,
+        Err(response) => return response,
+    };
+    match states::Entity::find_by_id((launch.package_id, launch.user_id, query.state_id))
+        .one(get_read_db())
+        .await
+    {
+        Ok(Some(state)) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE.as_str(), state.content_type),
+                (VERSION_HEADER, VERSION.to_string()),
+            ],
+            state.document,
+        )
+            .into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => {
+            error!("Error reading xAPI state: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+/// Replaces a state document, or with POST merges a JSON object into the one stored, as xAPI
+/// describes.
+pub async fn put_state(
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    content_type: Option<TypedHeader<ContentType>>,
+    Query(query): Query<StateQuery>,
+    body: Bytes,
+) -> Response {
+    let launch = match authenticate_launch(&bearer).await {
+        Ok(launch) => launch,
+        Err(response) => return response,
+    };
+    let content_type = content_type
+        .map(|TypedHeader(content_type)| content_type.to_string())
+        .unwrap_or_else(|| "application/octet-stream".into());
+    let key = (launch.package_id, launch.user_id, query.state_id.clone());
+    let existing = match states::Entity::find_by_id(key).one(get_db()).await {
+        Ok(existing) => existing,
+        Err(e) => {
+            error!("Error reading xAPI state: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+    let mut document = body.to_vec();
+    // Only JSON objects can be merged, and anything else replaces what was stored
+    if let Some(existing) = &existing {
+        let old = serde_json::from_slice::<Value>(&existing.document);
+        let new = serde_json::from_slice::<Value>(&document);
+        if let (Ok(Value::Object(mut old)), Ok(Value::Object(new))) = (old, new) {
+            old.extend(new);
+            document = Value::Object(old).to_string().into_bytes();
+        }
+    }
+    let state = states::ActiveModel {
+        package_id: ActiveValue::set(launch.package_id),
+        user_id: ActiveValue::set(launch.user_id),
+        state_id: ActiveValue::set(query.state_id),
+        document: ActiveValue::set(document),
+        content_type: ActiveValue::set(content_type),
+        updated_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+    };
+    let result = if existing.is_some() {
+        state.update(get_db()).await.map(|_| ())
+    } else {
+        state.insert(get_db()).await.map(|_| ())
+    };
+    match result {
+        Ok(()) => (StatusCode::NO_CONTENT, [(VERSION_HEADER, VERSION)]).into_response(),
+        Err(e) => {
+            error!("Error storing xAPI state: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+pub async fn delete_state(
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(query): Query<StateQuery>,
+) -> Response {
+    let launch = match authenticate_launch(&bearer).await {
+        Ok(launch) => launch,
+        Err(response) => return response,
+    };
+    match states::Entity::delete_by_id((launch.package_id, launch.user_id, query.state_id))
+        .exec(get_db())
+        .await
+    {
+        Ok(_) => (StatusCode::NO_CONTENT, [(VERSION_HEADER, VERSION)]).into_response(),
+        Err(e) => {
+            error!("Error deleting xAPI state: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
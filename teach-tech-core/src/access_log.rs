@@ -0,0 +1,167 @@
+use std::{
+    cell::Cell,
+    fs::File,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{auth::UserID, client_ip::resolve_client_ip, request_id::RequestId, TeachCore};
+
+static OPTIONS: OnceLock<AccessLogOptions> = OnceLock::new();
+static FILE: OnceLock<Option<Mutex<File>>> = OnceLock::new();
+
+tokio::task_local! {
+    static USER: Cell<Option<UserID>>;
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub access_log: AccessLogOptions,
+}
+
+/// The `[access_log]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccessLogOptions {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Appends entries to this file as JSON lines. Otherwise they are logged as events with the
+    /// `access_log` target, which `LOG_LEVEL` can route separately from the rest
+    pub file: Option<PathBuf>,
+}
+
+impl Default for AccessLogOptions {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            file: None,
+        }
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// One request, as written to the access log.
+#[derive(Debug, Serialize)]
+pub struct AccessLogEntry {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub method: String,
+    /// The path without its query, which can carry tokens
+    pub path: String,
+    pub status: u16,
+    pub latency_ms: f64,
+    pub client_ip: Option<IpAddr>,
+    /// Who the request was authenticated as, if it had a valid bearer token
+    pub user_id: Option<UserID>,
+    pub request_id: Option<String>,
+}
+
+/// Attributes the request being handled to `user_id` in the access log.
+///
+/// Called by [`crate::auth::token::validate_token`], so handlers do not need to call it.
+pub fn record_user(user_id: UserID) {
+    let _ = USER.try_with(|user| user.set(Some(user_id)));
+}
+
+/// The user the request being handled was authenticated as so far.
+pub fn current_user() -> Option<UserID> {
+    USER.try_with(Cell::get).ok().flatten()
+}
+
+fn write_entry(entry: &AccessLogEntry) {
+    let file = FILE.get_or_init(|| {
+        let path = OPTIONS.get()?.file.as_ref()?;
+        match File::options().create(true).append(true).open(path) {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(e) => {
+                error!("Error opening access log {}: {e:#}", path.display());
+                None
+            }
+        }
+    });
+    let Some(file) = file else {
+        info!(
+            target: "access_log",
+            method = entry.method,
+            path = entry.path,
+            status = entry.status,
+            latency_ms = entry.latency_ms,
+            client_ip = entry.client_ip.map(|ip| ip.to_string()),
+            user_id = entry.user_id.map(i32::from),
+            request_id = entry.request_id,
+        );
+        return;
+    };
+    let mut line = match serde_json::to_vec(entry) {
+        Ok(line) => line,
+        Err(e) => {
+            error!("Error serializing access log entry: {e:#}");
+            return;
+        }
+    };
+    line.push(b'\n');
+    if let Err(e) = file.lock().unwrap().write_all(&line) {
+        error!("Error writing access log: {e:#}");
+    }
+}
+
+async fn log_access(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let timestamp = chrono::Utc::now();
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let client_ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| resolve_client_ip(peer.ip(), request.headers()));
+    let request_id = RequestId::from_extensions(request.extensions()).map(|RequestId(id)| id);
+
+    let (response, user_id) = USER
+        .scope(Cell::new(None), async {
+            let response = next.run(request).await;
+            (response, USER.with(Cell::get))
+        })
+        .await;
+
+    if !OPTIONS.get().is_some_and(|options| options.enabled) {
+        return response;
+    }
+    write_entry(&AccessLogEntry {
+        timestamp,
+        method,
+        path,
+        status: response.status().as_u16(),
+        latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+        client_ip,
+        user_id,
+        request_id,
+    });
+    response
+}
+
+// Added even when the access log is disabled, so that error reports can name the user
+pub(crate) fn add_layer(router: Router) -> Router {
+    router.layer(middleware::from_fn(log_access))
+}
+
+pub fn add_to_core<S>(core: TeachCore<S>) -> anyhow::Result<TeachCore<S>> {
+    let config: AccessLogConfig = toml::from_str(core.get_config_str())?;
+    OPTIONS
+        .set(config.access_log)
+        .expect("Access log options are already initialized");
+    Ok(core)
+}
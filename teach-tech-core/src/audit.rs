@@ -0,0 +1,204 @@
+//! A record of who did what, for compliance reviews.
+//!
+//! The core records logins, issued tokens, created students and instructors, changes to admin
+//! permissions and changes to reports. Integrations append their own events with [`record`],
+//! prefixing their actions with their name. Admins read the log under `/admin/audit`, oldest
+//! first.
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json,
+};
+use sea_orm::{entity::prelude::*, ActiveValue};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    auth::{AdminUser, UserID},
+    db::{get_db, get_read_db, insert_batched, paginate, PageQuery},
+    i18n, reports, TeachCore,
+};
+
+pub const LOGIN: &str = "login";
+pub const TOKEN_ISSUED: &str = "token.issued";
+pub const STUDENT_CREATED: &str = "student.created";
+pub const INSTRUCTOR_CREATED: &str = "instructor.created";
+pub const PERMISSIONS_CHANGED: &str = "admin.permissions_changed";
+pub const ADMIN_DELETED: &str = "admin.deleted";
+pub const REPORT_STATUS: &str = "report.status";
+
+/// Events are kept this long unless `retention.tables.audit_events` says otherwise
+const MAX_AGE: Duration = Duration::from_secs(3 * 365 * 24 * 60 * 60);
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "audit_events")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    #[sea_orm(indexed)]
+    pub action: String,
+    /// `None` for the command line and the server itself
+    #[sea_orm(indexed)]
+    pub actor: Option<UserID>,
+    /// What the action was done to, such as a user ID
+    #[sea_orm(indexed)]
+    pub subject: Option<String>,
+    pub detail: Option<String>,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// An event to append to the log.
+#[derive(Clone, Debug)]
+pub struct AuditEvent {
+    pub action: String,
+    pub actor: Option<UserID>,
+    pub subject: Option<String>,
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(action: impl Into<String>, actor: Option<UserID>) -> Self {
+        Self {
+            action: action.into(),
+            actor,
+            subject: None,
+            detail: None,
+        }
+    }
+
+    pub fn subject(mut self, subject: impl ToString) -> Self {
+        self.subject = Some(subject.to_string());
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    fn into_active_model(self) -> ActiveModel {
+        ActiveModel {
+            id: ActiveValue::not_set(),
+            action: ActiveValue::set(self.action),
+            actor: ActiveValue::set(self.actor),
+            subject: ActiveValue::set(self.subject),
+            detail: ActiveValue::set(self.detail),
+            created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+        }
+    }
+}
+
+/// Appends `event` to the log. Pass the transaction making the change being recorded, so that
+/// the event is only kept if the change is.
+pub async fn record(db: &impl ConnectionTrait, event: AuditEvent) -> Result<(), DbErr> {
+    Entity::insert(event.into_active_model())
+        .exec_without_returning(db)
+        .await?;
+    Ok(())
+}
+
+/// Appends many events at once, such as one for every user created by a request.
+pub async fn record_many(
+    db: &impl ConnectionTrait,
+    events: impl IntoIterator<Item = AuditEvent>,
+) -> Result<(), DbErr> {
+    insert_batched(events.into_iter().map(AuditEvent::into_active_model), db).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub action: Option<String>,
+    pub actor: Option<UserID>,
+    pub subject: Option<String>,
+    /// Only events at or after this time
+    pub since: Option<DateTime>,
+    /// Only events before this time
+    pub until: Option<DateTime>,
+}
+
+async fn list_events(
+    _: AdminUser,
+    Query(query): Query<AuditQuery>,
+    Query(page): Query<PageQuery>,
+) -> Response {
+    let Ok(cursor) = page.cursor::<i32>() else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_cursor")).into_response();
+    };
+    let mut select = Entity::find();
+    if let Some(action) = query.action {
+        select = select.filter(Column::Action.eq(action));
+    }
+    if let Some(actor) = query.actor {
+        select = select.filter(Column::Actor.eq(actor));
+    }
+    if let Some(subject) = query.subject {
+        select = select.filter(Column::Subject.eq(subject));
+    }
+    if let Some(since) = query.since {
+        select = select.filter(Column::CreatedAt.gte(since));
+    }
+    if let Some(until) = query.until {
+        select = select.filter(Column::CreatedAt.lt(until));
+    }
+    match paginate(
+        select,
+        Column::Id,
+        |model| model.id,
+        cursor,
+        page.limit(),
+        get_read_db(),
+    )
+    .await
+    {
+        Ok(events) => (StatusCode::OK, Json(events)).into_response(),
+        Err(e) => {
+            error!("Error listing audit events: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+async fn get_event(_: AdminUser, Path(id): Path<i32>) -> Response {
+    match Entity::find_by_id(id).one(get_read_db()).await {
+        Ok(Some(model)) => (StatusCode::OK, Json(model)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => {
+            error!("Error reading audit event {id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+pub fn add_to_core<S: Clone + Send + Sync + 'static>(mut core: TeachCore<S>) -> TeachCore<S> {
+    core.add_db_reset_config(Entity);
+    core.add_retention(Entity, Column::CreatedAt, MAX_AGE);
+
+    reports::add_sink("audit", |event| async move {
+        let status = format!("{:?}", event.status).to_lowercase();
+        let detail = match event.note {
+            Some(note) => format!("{status}: {note}"),
+            None => status,
+        };
+        let audit_event = AuditEvent::new(REPORT_STATUS, Some(event.actor))
+            .subject(event.report_id)
+            .detail(detail);
+        record(get_db(), audit_event).await?;
+        Ok(())
+    });
+
+    core.modify_router(|router| {
+        router
+            .route("/admin/audit", get(list_events))
+            .route("/admin/audit/:id", get(get_event))
+    })
+}
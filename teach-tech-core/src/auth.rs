@@ -1,15 +1,43 @@
+pub mod captcha;
+pub mod guard;
+pub mod ldap;
+pub mod links;
+pub mod oidc;
+pub mod password_policy;
+pub mod provision;
+pub mod rate_limit;
+pub mod saml;
 pub mod token;
 pub mod user_auth;
 
-use axum::{http::StatusCode, response::IntoResponse, routing::post, Form, Json};
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::post,
+    Form, Json,
+};
+use axum_extra::headers::{authorization::Bearer, Authorization, HeaderMapExt};
+use fxhash::FxHashMap;
 use rand::{thread_rng, Rng};
-use sea_orm::{entity::prelude::*, TryFromU64};
+use sea_orm::{entity::prelude::*, sea_query::Nullable, TransactionTrait, TryFromU64};
 use serde::{Deserialize, Serialize};
-use tracing::error;
+use tracing::{error, info, warn};
 
-use crate::{db::get_db, TeachCore};
+use crate::{
+    audit::{self, AuditEvent},
+    client_ip::ClientIp,
+    db::{get_db, get_read_db, SoftDeletable},
+    encryption, i18n,
+    security::{self, SecurityEvent},
+    users::{admins, instructors, students},
+    TeachCore,
+};
+use user_auth::LoginName;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, DeriveValueType, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, DeriveValueType, Serialize, Deserialize)]
 pub struct UserID(i32);
 
 impl TryFromU64 for UserID {
@@ -18,6 +46,12 @@ impl TryFromU64 for UserID {
     }
 }
 
+impl Nullable for UserID {
+    fn null() -> Value {
+        i32::null()
+    }
+}
+
 impl UserID {
     pub fn rand() -> Self {
         let n: i32 = thread_rng().gen();
@@ -59,70 +93,430 @@ impl std::fmt::Display for UserID {
     }
 }
 
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub auth: AuthOptions,
+    /// Checks passwords with a directory instead of the local hashes
+    pub ldap: Option<ldap::LdapOptions>,
+}
+
+/// The `[auth]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthOptions {
+    pub captcha: Option<captcha::CaptchaOptions>,
+    /// OpenID Connect providers, by the name used in their routes
+    #[serde(default)]
+    pub oidc: FxHashMap<String, oidc::OidcProviderOptions>,
+    pub saml: Option<saml::SamlOptions>,
+    /// Issues signed JWTs instead of opaque tokens
+    pub jwt: Option<token::jwt::JwtOptions>,
+    #[serde(default)]
+    pub rate_limit: rate_limit::RateLimitOptions,
+    #[serde(default)]
+    pub password_policy: password_policy::PasswordPolicy,
+    #[serde(default)]
+    pub argon2: user_auth::Argon2Options,
+    #[serde(default)]
+    pub token_validity: token::TokenValidity,
+    #[serde(default)]
+    pub token_cache: token::local_cache::TokenCacheOptions,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct LoginForm {
-    pub user_id: UserID,
+    pub user_id: Option<UserID>,
+    /// A user ID, username or email, for forms without `user_id`
+    pub identifier: Option<String>,
     pub password: String,
+    /// The answer to a challenge, required after too many failed logins
+    #[serde(
+        alias = "h-captcha-response",
+        alias = "cf-turnstile-response",
+        alias = "g-recaptcha-response"
+    )]
+    pub captcha_token: Option<String>,
+    /// Scopes separated by spaces, to issue a token limited to them instead of the one that can
+    /// be used for anything
+    pub scope: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct Token {
     pub token: String,
     pub expires_at: DateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+impl From<token::Model> for Token {
+    fn from(model: token::Model) -> Self {
+        Self {
+            expires_at: model.last_used + token::get_validity_duration(model.role),
+            token: model.token,
+            scope: model.scopes,
+        }
+    }
+}
+
+/// The user whose bearer token authorized a request, as checked by [`token::validate`] once per
+/// request.
+///
+/// Requests without a valid token are refused with 401, and scoped tokens that do not allow the
+/// request with 403. See [`guard::require_scope`].
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub user_id: UserID,
+    pub token: String,
+    /// `None` for tokens that can be used for anything
+    pub scopes: Option<Vec<String>>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AuthUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // Already checked by a layer such as guard::RequirePermission
+        if let Some(user) = parts.extensions.get::<Self>() {
+            return Ok(user.clone());
+        }
+        let Some(Authorization(bearer)) = parts.headers.typed_get::<Authorization<Bearer>>() else {
+            return Err((StatusCode::UNAUTHORIZED, ()).into_response());
+        };
+        match token::validate_bearer(&parts.headers, &mut parts.extensions).await {
+            Ok(Some(valid)) => {
+                let required = parts.extensions.get::<guard::RequiredScope>();
+                if !valid.allows(required.map(|scope| scope.0), &parts.method) {
+                    return Err(
+                        (StatusCode::FORBIDDEN, i18n::t("error.token_scope")).into_response()
+                    );
+                }
+                Ok(Self {
+                    user_id: valid.user_id,
+                    token: bearer.token().to_string(),
+                    scopes: valid.scopes,
+                })
+            }
+            Ok(None) => Err((StatusCode::UNAUTHORIZED, ()).into_response()),
+            Err(e) => {
+                error!("Error validating bearer token: {e:#}");
+                Err((StatusCode::INTERNAL_SERVER_ERROR, ()).into_response())
+            }
+        }
+    }
+}
+
+/// Reads the live row of `user_id` for one of the role extractors, refusing users without one
+/// with 403 and `refusal`.
+async fn resolve_role<E: SoftDeletable>(
+    user_id: UserID,
+    refusal: &str,
+) -> Result<E::Model, Response>
+where
+    UserID: Into<<E::PrimaryKey as PrimaryKeyTrait>::ValueType>,
+{
+    match E::find_live_by_id(user_id).one(get_read_db()).await {
+        Ok(Some(model)) => Ok(model),
+        Ok(None) => Err((StatusCode::FORBIDDEN, i18n::t(refusal)).into_response()),
+        Err(e) => {
+            error!("Error reading the role of {user_id}: {e:#}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, ()).into_response())
+        }
+    }
+}
+
+/// An [`AuthUser`] who is an admin that has not been deleted. Others are refused with 403.
+#[derive(Debug, Clone)]
+pub struct AdminUser {
+    pub user: AuthUser,
+    pub admin: admins::Model,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AdminUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = AuthUser::from_request_parts(parts, state).await?;
+        let admin = resolve_role::<admins::Entity>(user.user_id, "error.must_be_admin").await?;
+        Ok(Self { user, admin })
+    }
+}
+
+/// An [`AuthUser`] who is an instructor that has not been deleted. Others are refused with 403.
+#[derive(Debug, Clone)]
+pub struct InstructorUser {
+    pub user: AuthUser,
+    pub instructor: instructors::Model,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for InstructorUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = AuthUser::from_request_parts(parts, state).await?;
+        let instructor =
+            resolve_role::<instructors::Entity>(user.user_id, "error.must_be_instructor").await?;
+        Ok(Self { user, instructor })
+    }
+}
+
+/// An [`AuthUser`] who is a student that has not been deleted. Others are refused with 403.
+#[derive(Debug, Clone)]
+pub struct StudentUser {
+    pub user: AuthUser,
+    pub student: students::Model,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for StudentUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = AuthUser::from_request_parts(parts, state).await?;
+        let student =
+            resolve_role::<students::Entity>(user.user_id, "error.must_be_student").await?;
+        Ok(Self { user, student })
+    }
+}
+
+enum PasswordCheck {
+    Valid(UserID),
+    Invalid,
+    UnknownUser,
+    /// The directory accepted the password, but the user is not linked to it
+    Unlinked,
+}
+
+/// Checks the password with `[ldap]` if there is one, and with the local hash otherwise.
+async fn check_password(user_id: UserID, password: &str) -> anyhow::Result<PasswordCheck> {
+    match ldap::log_in(user_id, password).await? {
+        Some(ldap::DirectoryLogin::Accepted(user_id)) => return Ok(PasswordCheck::Valid(user_id)),
+        Some(ldap::DirectoryLogin::Unlinked) => return Ok(PasswordCheck::Unlinked),
+        Some(ldap::DirectoryLogin::Refused) if !ldap::local_fallback() => {
+            return Ok(PasswordCheck::Invalid)
+        }
+        Some(ldap::DirectoryLogin::Refused) | None => {}
+    }
+    let Some(auth_data) = user_auth::Entity::find_by_id(user_id).one(get_db()).await? else {
+        return Ok(PasswordCheck::UnknownUser);
+    };
+    if !auth_data.validate_password(password)? {
+        return Ok(PasswordCheck::Invalid);
+    }
+    if auth_data.needs_rehash() {
+        if let Err(e) = auth_data.rehash(password, get_db()).await {
+            error!("Error hashing the password of {user_id} again: {e:#}");
+        }
+    }
+    Ok(PasswordCheck::Valid(user_id))
 }
 
-pub async fn add_to_core<S: Clone + Send + Sync + 'static>(mut core: TeachCore<S>) -> TeachCore<S> {
+/// Checks a password, and the CAPTCHA once one is needed, and issues a token for the user.
+async fn login(
+    client_ip: ClientIp,
+    Form(LoginForm {
+        user_id,
+        identifier,
+        password,
+        captcha_token,
+        scope,
+    }): Form<LoginForm>,
+) -> Response {
+    let scopes = match scope.as_deref().map(token::parse_scopes) {
+        Some(Some(scopes)) => Some(scopes),
+        Some(None) => {
+            return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_scope")).into_response();
+        }
+        None => None,
+    };
+    let account = match user_auth::resolve_login(user_id, identifier.as_deref(), get_db()).await {
+        Ok(Some(account)) => account,
+        Ok(None) => {
+            return (StatusCode::BAD_REQUEST, i18n::t("error.missing_identifier")).into_response();
+        }
+        Err(e) => {
+            error!("Error looking up the user of a login: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+    if let Err(response) = captcha::check(client_ip.0, &account, captcha_token).await {
+        return response;
+    }
+    let user_id = match account {
+        LoginName::User(user_id) => user_id,
+        LoginName::Unknown(_) => {
+            warn!("Login attempt for unknown user {account} from {client_ip}");
+            security::record(SecurityEvent::FailedLogin);
+            captcha::record_failure(client_ip.0, &account).await;
+            return (StatusCode::UNAUTHORIZED, ()).into_response();
+        }
+    };
+    let user_id = match check_password(user_id, &password).await {
+        Ok(PasswordCheck::Valid(user_id)) => {
+            captcha::record_success(user_id).await;
+            user_id
+        }
+        Ok(PasswordCheck::UnknownUser) => {
+            warn!("Login attempt for unknown user {user_id} from {client_ip}");
+            security::record(SecurityEvent::FailedLogin);
+            captcha::record_failure(client_ip.0, &account).await;
+            return (StatusCode::UNAUTHORIZED, ()).into_response();
+        }
+        Ok(PasswordCheck::Invalid) => {
+            warn!("Failed login for {user_id} from {client_ip}");
+            security::record(SecurityEvent::FailedLogin);
+            captcha::record_failure(client_ip.0, &account).await;
+            return (StatusCode::UNAUTHORIZED, ()).into_response();
+        }
+        Ok(PasswordCheck::Unlinked) => {
+            return (StatusCode::FORBIDDEN, i18n::t("error.ldap_not_linked")).into_response();
+        }
+        Err(e) => {
+            error!("Error validating user: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+
+    let result = async {
+        audit::record(
+            get_db(),
+            AuditEvent::new(audit::LOGIN, Some(user_id))
+                .detail(format!("password from {client_ip}")),
+        )
+        .await?;
+        let (token, replaced) =
+            token::Model::gen_scoped(user_id, scopes.as_deref(), get_db()).await?;
+        let token = token.insert(get_db()).await?;
+        replaced.revoke();
+        Ok::<_, DbErr>(token)
+    }
+    .await;
+
+    match result {
+        Ok(token) => (StatusCode::OK, Json(Token::from(token))).into_response(),
+        Err(e) => {
+            error!("Error creating token for {user_id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+/// Revokes the token the request was made with.
+async fn logout(user: AuthUser) -> Response {
+    match token::revoke_token(&user.token).await {
+        Ok(_) => {
+            info!("{} logged out", user.user_id);
+            (StatusCode::NO_CONTENT, ()).into_response()
+        }
+        Err(e) => {
+            error!("Error revoking token for {}: {e:#}", user.user_id);
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+/// Swaps the token the request was made with for a new one with the same scopes, so that clients
+/// can keep a session going without asking for the password again.
+async fn refresh(user: AuthUser) -> Response {
+    let user_id = user.user_id;
+    let result = async {
+        let txn = get_db().begin().await?;
+        // Users have one unscoped token at a time, so this also revokes the one being refreshed
+        let (token, replaced) =
+            token::Model::gen_scoped(user_id, user.scopes.as_deref(), &txn).await?;
+        let token = token.insert(&txn).await?;
+        txn.commit().await?;
+        replaced.revoke();
+        if user.scopes.is_some() {
+            token::revoke_token(&user.token).await?;
+        }
+        Ok::<_, DbErr>(token)
+    }
+    .await;
+    match result {
+        Ok(token) => (StatusCode::OK, Json(Token::from(token))).into_response(),
+        Err(e) => {
+            error!("Error refreshing token for {user_id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MintToken {
+    /// Separated by spaces
+    pub scope: String,
+}
+
+/// Issues another token limited to some scopes, such as for a script that only reads grades. Only
+/// tokens that can be used for anything can mint others.
+async fn mint(user: AuthUser, Json(MintToken { scope }): Json<MintToken>) -> Response {
+    if user.scopes.is_some() {
+        return (StatusCode::FORBIDDEN, i18n::t("error.token_scope")).into_response();
+    }
+    let Some(scopes) = token::parse_scopes(&scope) else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_scope")).into_response();
+    };
+    let user_id = user.user_id;
+    let result = async {
+        let txn = get_db().begin().await?;
+        // Scoped tokens replace none
+        let (token, _) = token::Model::gen_scoped(user_id, Some(&scopes), &txn).await?;
+        let token = token.insert(&txn).await?;
+        txn.commit().await?;
+        Ok::<_, DbErr>(token)
+    }
+    .await;
+    match result {
+        Ok(token) => (StatusCode::CREATED, Json(Token::from(token))).into_response(),
+        Err(e) => {
+            error!("Error minting token for {user_id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+pub async fn add_to_core<S: Clone + Send + Sync + 'static>(
+    mut core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    let config: AuthConfig = toml::from_str(core.get_config_str())?;
+    password_policy::init(config.auth.password_policy)?;
+    user_auth::init(config.auth.argon2)?;
+    token::init(config.auth.token_validity)?;
+    captcha::init(config.auth.captcha, &mut core)?;
+    oidc::init(config.auth.oidc)?;
+    saml::init(config.auth.saml)?;
+    ldap::init(config.ldap)?;
+    token::jwt::init(config.auth.jwt, &mut core)?;
+    token::local_cache::init(config.auth.token_cache, &mut core);
+    rate_limit::init(config.auth.rate_limit, &mut core);
     core.add_db_reset_config(token::Entity);
     core.add_db_reset_config(user_auth::Entity);
+    core.add_encrypted_column(user_auth::Entity, user_auth::Column::Email);
+    encryption::add_step(
+        "user_auth",
+        "email_hash",
+        Box::new(|db| Box::pin(user_auth::rehash_emails(db))),
+    );
+    core.add_db_reset_config(links::Entity);
 
-    core.modify_router(|router| {
-        router.route(
-            "/auth/login",
-            post(
-                |Form(LoginForm { user_id, password }): Form<LoginForm>| async move {
-                    let auth_data = match user_auth::Entity::find_by_id(user_id).one(get_db()).await
-                    {
-                        Ok(Some(auth_data)) => auth_data,
-                        Ok(None) => return (StatusCode::UNAUTHORIZED, ()).into_response(),
-                        Err(e) => {
-                            error!("Error getting user auth data for {user_id}: {e:#}");
-                            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
-                        }
-                    };
-                    match auth_data.validate_password(&password) {
-                        Ok(true) => {}
-                        Ok(false) => return (StatusCode::UNAUTHORIZED, ()).into_response(),
-                        Err(e) => {
-                            error!("Error validating user: {e:#}");
-                            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
-                        }
-                    }
-
-                    let result = match token::Model::gen_new(user_id, get_db()).await {
-                        Ok(m) => Ok(m.insert(get_db()).await),
-                        Err(e) => Err(e),
-                    };
-
-                    match result {
-                        Ok(Ok(token)) => {
-                            let expiry = chrono::Utc::now().naive_utc()
-                                + token::get_token_validity_duration_std();
-                            (
-                                StatusCode::OK,
-                                Json(Token {
-                                    token: token.token,
-                                    expires_at: expiry,
-                                }),
-                            )
-                                .into_response()
-                        }
-                        Ok(Err(e)) | Err(e) => {
-                            error!("Error creating token for {user_id}: {e:#}");
-                            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
-                        }
-                    }
-                },
-            ),
-        )
-    })
+    Ok(core.modify_router(|router| {
+        links::add_routes(saml::add_routes(oidc::add_routes(router)))
+            .route(
+                "/auth/logout",
+                post(logout).layer(guard::require_scope(token::ANY_SCOPE)),
+            )
+            .route(
+                "/auth/refresh",
+                post(refresh).layer(guard::require_scope(token::ANY_SCOPE)),
+            )
+            .route("/auth/tokens", post(mint))
+            .route(
+                "/auth/login",
+                post(login).layer(middleware::from_fn(rate_limit::enforce)),
+            )
+    }))
 }
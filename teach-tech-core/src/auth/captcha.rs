@@ -0,0 +1,313 @@
+//! Challenges on `/auth/login` for addresses and accounts with too many recent failed logins.
+//!
+//! hCaptcha, Turnstile and reCAPTCHA are built in. Integrations can verify challenges of other
+//! providers by implementing [`CaptchaVerifier`] and registering it with [`add_verifier`].
+//!
+//! Failures are counted in the cache, so with a shared cache every sibling sees them.
+
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::Context;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use tracing::{error, warn};
+use zeroize::Zeroizing;
+
+use super::{user_auth::LoginName, UserID};
+use crate::{
+    cache,
+    http::{form_encode, Endpoint, HttpClient},
+    i18n, TeachCore,
+};
+
+static CAPTCHA: OnceLock<Captcha> = OnceLock::new();
+static VERIFIERS: Mutex<Vec<(String, Arc<dyn CaptchaVerifier>)>> = Mutex::new(vec![]);
+
+/// Verifies the tokens of solved challenges with a provider, such as hCaptcha.
+pub trait CaptchaVerifier: Send + Sync + 'static {
+    /// Whether `token` is a solved challenge, for a login from `client_ip`. Errors are for
+    /// providers that could not be asked, and fail the login with 503.
+    fn verify(&self, token: &str, client_ip: IpAddr) -> BoxFuture<'static, anyhow::Result<bool>>;
+}
+
+/// Registers a way of verifying challenges, chosen by setting `auth.captcha.provider` to `name`.
+pub fn add_verifier(name: impl Into<String>, verifier: impl CaptchaVerifier) {
+    VERIFIERS
+        .lock()
+        .unwrap()
+        .push((name.into(), Arc::new(verifier)));
+}
+
+fn verifier(name: &str) -> Option<Arc<dyn CaptchaVerifier>> {
+    VERIFIERS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(verifier, _)| verifier == name)
+        .map(|(_, verifier)| verifier.clone())
+}
+
+/// Where the built in providers verify tokens.
+fn builtin_verify_url(provider: &str) -> Option<&'static str> {
+    match provider {
+        "hcaptcha" => Some("https://api.hcaptcha.com/siteverify"),
+        "turnstile" => Some("https://challenges.cloudflare.com/turnstile/v0/siteverify"),
+        "recaptcha" => Some("https://www.google.com/recaptcha/api/siteverify"),
+        _ => None,
+    }
+}
+
+/// The `[auth.captcha]` section of `teach-config.toml`. Without it, logins are never challenged.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CaptchaOptions {
+    /// `hcaptcha`, `turnstile`, `recaptcha`, or the name of a verifier added by an integration
+    pub provider: String,
+    /// Required by the built in providers
+    pub secret: Option<Zeroizing<String>>,
+    /// Where tokens are verified, instead of the provider's own endpoint
+    pub verify_url: Option<String>,
+    /// The lowest reCAPTCHA v3 score accepted, from 0.0 to 1.0
+    pub min_score: Option<f64>,
+    /// Failed logins from one address within the window before its logins are challenged. 0
+    /// challenges every login
+    #[serde(default = "default_ip_threshold")]
+    pub ip_threshold: u32,
+    /// Failed logins for one account within the window before its logins are challenged
+    #[serde(default = "default_account_threshold")]
+    pub account_threshold: u32,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_ip_threshold() -> u32 {
+    10
+}
+
+fn default_account_threshold() -> u32 {
+    5
+}
+
+fn default_window_secs() -> u64 {
+    900
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+struct Captcha {
+    options: CaptchaOptions,
+    verifier: Arc<dyn CaptchaVerifier>,
+}
+
+#[derive(Deserialize)]
+struct SiteVerify {
+    success: bool,
+    /// Only given by reCAPTCHA v3
+    score: Option<f64>,
+    #[serde(default, rename = "error-codes")]
+    error_codes: Vec<String>,
+}
+
+/// Verifies tokens with the `siteverify` API that the built in providers share.
+struct SiteVerifier(Arc<SiteVerifierInner>);
+
+struct SiteVerifierInner {
+    url: String,
+    secret: Zeroizing<String>,
+    min_score: Option<f64>,
+    client: HttpClient,
+}
+
+impl SiteVerifier {
+    fn new(options: &CaptchaOptions, default_url: &str) -> anyhow::Result<Self> {
+        let secret = options
+            .secret
+            .clone()
+            .with_context(|| format!("auth.captcha.secret is required by {}", options.provider))?;
+        if options
+            .min_score
+            .is_some_and(|score| !(0.0..=1.0).contains(&score))
+        {
+            return Err(anyhow::anyhow!(
+                "auth.captcha.min_score must be between 0.0 and 1.0"
+            ));
+        }
+        let url = options
+            .verify_url
+            .clone()
+            .unwrap_or_else(|| default_url.to_string());
+        Endpoint::parse(&url).context("Checking auth.captcha.verify_url")?;
+        Ok(Self(Arc::new(SiteVerifierInner {
+            url,
+            secret,
+            min_score: options.min_score,
+            client: HttpClient::new(Duration::from_secs(options.timeout_secs))?,
+        })))
+    }
+}
+
+impl SiteVerifierInner {
+    fn verify(&self, token: &str, client_ip: IpAddr) -> anyhow::Result<bool> {
+        let form = Zeroizing::new(form_encode(&[
+            ("secret", &self.secret),
+            ("response", token),
+            ("remoteip", &client_ip.to_string()),
+        ]));
+        let verdict: SiteVerify = self
+            .client
+            .post_form(&self.url, &form)?
+            .json("The CAPTCHA provider")?;
+        if !verdict.success
+            && verdict
+                .error_codes
+                .iter()
+                .any(|code| code.contains("secret"))
+        {
+            return Err(anyhow::anyhow!(
+                "The CAPTCHA provider rejected the secret: {:?}",
+                verdict.error_codes
+            ));
+        }
+        if let (Some(min_score), Some(score)) = (self.min_score, verdict.score) {
+            return Ok(verdict.success && score >= min_score);
+        }
+        Ok(verdict.success)
+    }
+}
+
+impl CaptchaVerifier for SiteVerifier {
+    fn verify(&self, token: &str, client_ip: IpAddr) -> BoxFuture<'static, anyhow::Result<bool>> {
+        let inner = self.0.clone();
+        let token = token.to_string();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || inner.verify(&token, client_ip)).await?
+        })
+    }
+}
+
+fn ip_key(client_ip: IpAddr) -> String {
+    format!("auth/captcha/ip/{client_ip}")
+}
+
+/// Unknown usernames and emails are counted too, so that guessing them is challenged like guessing
+/// passwords
+fn account_key(account: &impl std::fmt::Display) -> String {
+    format!("auth/captcha/user/{account}")
+}
+
+async fn failures(key: &str) -> anyhow::Result<u32> {
+    Ok(cache::get_json(key).await?.unwrap_or_default())
+}
+
+async fn is_required(options: &CaptchaOptions, client_ip: IpAddr, account: &LoginName) -> bool {
+    let result = async {
+        Ok::<_, anyhow::Error>(
+            failures(&ip_key(client_ip)).await? >= options.ip_threshold
+                || failures(&account_key(account)).await? >= options.account_threshold,
+        )
+    }
+    .await;
+    result.unwrap_or_else(|e| {
+        // Challenging everyone is safer than challenging no one
+        error!("Error reading failed logins: {e:#}");
+        true
+    })
+}
+
+/// Checks the challenge a login answered, if the address or account it is from has to answer
+/// one. The error is the response to send instead of logging in.
+pub(crate) async fn check(
+    client_ip: IpAddr,
+    account: &LoginName,
+    token: Option<String>,
+) -> Result<(), Response> {
+    let Some(captcha) = CAPTCHA.get() else {
+        return Ok(());
+    };
+    if !is_required(&captcha.options, client_ip, account).await {
+        return Ok(());
+    }
+    let Some(token) = token.filter(|token| !token.is_empty()) else {
+        return Err((
+            StatusCode::PRECONDITION_REQUIRED,
+            i18n::t("error.captcha_required"),
+        )
+            .into_response());
+    };
+    match captcha.verifier.verify(&token, client_ip).await {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            warn!("Failed CAPTCHA for {account} from {client_ip}");
+            record_failure(client_ip, account).await;
+            Err((
+                StatusCode::PRECONDITION_REQUIRED,
+                i18n::t("error.captcha_invalid"),
+            )
+                .into_response())
+        }
+        Err(e) => {
+            error!("Error verifying CAPTCHA: {e:#}");
+            Err((StatusCode::SERVICE_UNAVAILABLE, ()).into_response())
+        }
+    }
+}
+
+/// Counts a failed login or challenge against both the address and the account.
+pub(crate) async fn record_failure(client_ip: IpAddr, account: &LoginName) {
+    let Some(captcha) = CAPTCHA.get() else {
+        return;
+    };
+    let ttl = Some(Duration::from_secs(captcha.options.window_secs));
+    for key in [ip_key(client_ip), account_key(account)] {
+        if let Err(e) = cache::get_cache().incr(&key, ttl).await {
+            error!("Error counting failed login: {e:#}");
+        }
+    }
+}
+
+/// Forgets the failed logins of an account that logged in. Those of the address are kept, so
+/// that one working password does not let an address keep guessing others unchallenged.
+pub(crate) async fn record_success(user_id: UserID) {
+    if CAPTCHA.get().is_none() {
+        return;
+    }
+    if let Err(e) = cache::get_cache().invalidate(&account_key(&user_id)).await {
+        error!("Error clearing failed logins: {e:#}");
+    }
+}
+
+pub(crate) fn init<S>(
+    options: Option<CaptchaOptions>,
+    core: &mut TeachCore<S>,
+) -> anyhow::Result<()> {
+    let Some(options) = options else {
+        return Ok(());
+    };
+    if let Some(url) = builtin_verify_url(&options.provider) {
+        add_verifier(options.provider.clone(), SiteVerifier::new(&options, url)?);
+    }
+    // Verifiers of integrations are only added after the core, so the provider is looked up once
+    // they are all in
+    core.add_on_serve_named("captcha", 0, move || async move {
+        let Some(verifier) = verifier(&options.provider) else {
+            return Err(anyhow::anyhow!(
+                "There is no CAPTCHA provider named {}",
+                options.provider
+            ));
+        };
+        let _ = CAPTCHA.set(Captcha { options, verifier });
+        Ok(())
+    });
+    Ok(())
+}
@@ -0,0 +1,234 @@
+//! Layers that declare what routes need, so that they do not check it themselves.
+//!
+//! ```ignore
+//! router.route(
+//!     "/instructor/create",
+//!     post(create).layer(RequirePermission(Permission::CreateInstructor)),
+//! )
+//! ```
+//!
+//! The user that was checked is kept in the request, so [`AuthUser`] in the handler does not look
+//! up the token again.
+//!
+//! Scoped tokens are only accepted by routes that [`require_scope`] one of their scopes, and by
+//! GET and HEAD routes if they have [`super::token::READ_SCOPE`]. Scopes must be added outside of
+//! [`RequirePermission`], which checks them as it reads the token:
+//!
+//! ```ignore
+//! post(create)
+//!     .layer(RequirePermission(Permission::CreateStudent))
+//!     .layer(require_scope("enrollment"))
+//! ```
+
+use std::{
+    convert::Infallible,
+    task::{Context, Poll},
+};
+
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use futures::future::BoxFuture;
+use tower::{Layer, Service};
+use tracing::error;
+
+use super::AuthUser;
+use crate::{
+    db::{get_read_db, SoftDeletable},
+    users::admins::{
+        self,
+        permissions::{self, Permission},
+    },
+};
+
+/// The scope a route accepts scoped tokens with, as added by [`require_scope`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequiredScope(pub &'static str);
+
+/// Lets tokens limited to `scope` use the route, as a layer.
+pub fn require_scope(scope: &'static str) -> Extension<RequiredScope> {
+    Extension(RequiredScope(scope))
+}
+
+/// Refuses requests without a valid token with 401, and those from users that are not live admins
+/// with the permission with 403 and [`Permission::refusal`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequirePermission(pub Permission);
+
+impl<S> Layer<S> for RequirePermission {
+    type Service = RequirePermissionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequirePermissionService {
+            inner,
+            permission: self.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequirePermissionService<S> {
+    inner: S,
+    permission: Permission,
+}
+
+impl<S> Service<Request> for RequirePermissionService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // The clone may not be ready, so the ready service is taken and the clone left behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let permission = self.permission;
+        Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+            let user = match AuthUser::from_request_parts(&mut parts, &()).await {
+                Ok(user) => user,
+                Err(response) => return Ok(response),
+            };
+            let allowed = async {
+                // Deleted admins lose their permissions, but may still be cached for a while
+                if admins::Entity::find_live_by_id(user.user_id)
+                    .one(get_read_db())
+                    .await?
+                    .is_none()
+                {
+                    return Ok(false);
+                }
+                permissions::has_permission(user.user_id, permission).await
+            };
+            match allowed.await {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Ok((StatusCode::FORBIDDEN, permission.refusal()).into_response());
+                }
+                Err(e) => {
+                    error!("Error reading admin data: {e:#}");
+                    return Ok((StatusCode::INTERNAL_SERVER_ERROR, ()).into_response());
+                }
+            }
+            parts.extensions.insert(user);
+            inner.call(Request::from_parts(parts, body)).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::header, routing::post, Router};
+    use sea_orm::{ActiveModelTrait, ActiveValue, ConnectionTrait, Schema};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        auth::{token, UserID},
+        db::{get_db, init_db},
+    };
+
+    async fn init() {
+        init_db(r#"database_url = "sqlite::memory:""#)
+            .await
+            .unwrap();
+        let backend = get_db().get_database_backend();
+        let schema = Schema::new(backend);
+        for create in [
+            schema.create_table_from_entity(token::Entity),
+            schema.create_table_from_entity(admins::Entity),
+            schema.create_table_from_entity(permissions::Entity),
+        ] {
+            get_db().execute(backend.build(&create)).await.unwrap();
+        }
+    }
+
+    /// Adds a user with a token and `permissions`, and returns the token. `deleted_admin` is
+    /// whether their admin row is deleted, or `None` without one.
+    async fn add_user(
+        user_id: i32,
+        deleted_admin: Option<bool>,
+        permissions: &[Permission],
+    ) -> String {
+        let user_id: UserID = user_id.try_into().unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        let token = format!("token-{user_id}");
+        token::ActiveModel {
+            user_id: ActiveValue::set(user_id),
+            token: ActiveValue::set(token.clone()),
+            last_used: ActiveValue::set(now),
+            scopes: ActiveValue::set(None),
+            role: ActiveValue::set(None),
+        }
+        .insert(get_db())
+        .await
+        .unwrap();
+        if let Some(deleted) = deleted_admin {
+            admins::ActiveModel {
+                user_id: ActiveValue::set(user_id),
+                username: ActiveValue::set(token.clone()),
+                created_at: ActiveValue::set(now),
+                updated_at: ActiveValue::set(now),
+                deleted_at: ActiveValue::set(deleted.then_some(now)),
+            }
+            .insert(get_db())
+            .await
+            .unwrap();
+        }
+        for &permission in permissions {
+            permissions::ActiveModel {
+                id: ActiveValue::not_set(),
+                user_id: ActiveValue::set(user_id),
+                permission: ActiveValue::set(permission),
+            }
+            .insert(get_db())
+            .await
+            .unwrap();
+        }
+        token
+    }
+
+    async fn status(token: Option<&str>) -> StatusCode {
+        let router = Router::new().route(
+            "/",
+            post(|| async { StatusCode::OK }).layer(RequirePermission(Permission::CreateStudent)),
+        );
+        let mut request = Request::post("/");
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        router
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    // One test, since the database is shared by the whole process
+    #[tokio::test]
+    async fn only_live_admins_with_the_permission_get_through() {
+        init().await;
+        let allowed = add_user(1, Some(false), &[Permission::CreateStudent]).await;
+        let other_permission = add_user(2, Some(false), &[Permission::CreateCourse]).await;
+        let deleted = add_user(3, Some(true), &[Permission::CreateStudent]).await;
+        // Permissions left behind by an admin row that no longer exists
+        let not_admin = add_user(4, None, &[Permission::CreateStudent]).await;
+
+        assert_eq!(status(Some(&allowed)).await, StatusCode::OK);
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("unknown")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some(&other_permission)).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(Some(&deleted)).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(Some(&not_admin)).await, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,420 @@
+//! Checking passwords on `/auth/login` with a bind to an LDAP directory, such as Active
+//! Directory, when `[ldap]` is in `teach-config.toml`.
+//!
+//! People the directory lets bind for the first time are linked under the `ldap` provider of
+//! [`links`](super::links), and created as students or instructors by the role their
+//! attributes map to. ldaps needs teach-tech-core to be built with its ldap-tls feature.
+
+use std::{io::Write, sync::OnceLock, time::Duration};
+
+use fxhash::FxHashMap;
+use sea_orm::{ActiveModelTrait, EntityTrait, TransactionTrait};
+use serde::Deserialize;
+use tracing::{info, warn};
+use zeroize::Zeroizing;
+
+use super::{
+    links,
+    provision::{self, ProvisionedRole, RoleMapping},
+    user_auth, UserID,
+};
+use crate::{
+    db::get_db,
+    http::{Connector, ReadWrite},
+};
+
+/// The provider name of directory logins in [`links`]
+pub const PROVIDER: &str = "ldap";
+/// Responses larger than this are refused
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+const SUCCESS: u8 = 0;
+const NO_SUCH_OBJECT: u8 = 32;
+const INVALID_CREDENTIALS: u8 = 49;
+
+static DIRECTORY: OnceLock<Directory> = OnceLock::new();
+
+/// The `[ldap]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LdapOptions {
+    /// Such as `ldaps://ldap.university.example`
+    pub url: String,
+    /// Who to bind as, with `{user_id}` replaced, such as
+    /// `uid={user_id},ou=people,dc=university,dc=example`, or `{user_id}@university.example` for
+    /// Active Directory
+    pub bind_dn: String,
+    /// Where people are looked up for their attributes after binding. Without it, people are
+    /// created with `default_role`
+    pub search_base: Option<String>,
+    /// The attribute that holds user IDs, such as `sAMAccountName` for Active Directory
+    #[serde(default = "default_user_attribute")]
+    pub user_attribute: String,
+    #[serde(default = "default_name_attribute")]
+    pub name_attribute: String,
+    /// Holds birthdates as `YYYYMMDD` or `YYYY-MM-DD`
+    pub birthdate_attribute: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<RoleMapping>,
+    /// The role of people that no entry of `roles` matches. Without it, they cannot log in until
+    /// an administrator links them
+    pub default_role: Option<ProvisionedRole>,
+    /// Lets users that the directory refuses log in with their local password, such as admins
+    /// made with `create-admin`
+    #[serde(default = "default_local_fallback")]
+    pub local_fallback: bool,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_user_attribute() -> String {
+    "uid".to_string()
+}
+
+fn default_name_attribute() -> String {
+    "displayName".to_string()
+}
+
+fn default_local_fallback() -> bool {
+    true
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+struct Directory {
+    options: LdapOptions,
+    host: String,
+    port: u16,
+    tls: bool,
+    connector: Connector,
+}
+
+/// How the directory answered a login.
+pub(crate) enum DirectoryLogin {
+    /// The password is right, and the user it belongs to exists
+    Accepted(UserID),
+    Refused,
+    /// The password is right, but no user can be made for it
+    Unlinked,
+}
+
+pub(crate) fn has_provider(name: &str) -> bool {
+    name == PROVIDER && DIRECTORY.get().is_some()
+}
+
+/// Whether users the directory refuses may still log in with their local password.
+pub(crate) fn local_fallback() -> bool {
+    DIRECTORY
+        .get()
+        .map_or(true, |directory| directory.options.local_fallback)
+}
+
+fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    if contents.len() < 0x80 {
+        out.push(contents.len() as u8);
+    } else {
+        let len = contents.len().to_be_bytes();
+        let skip = len.iter().take_while(|byte| **byte == 0).count();
+        out.push(0x80 | (len.len() - skip) as u8);
+        out.extend_from_slice(&len[skip..]);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+fn integer(tag: u8, n: u8) -> Vec<u8> {
+    // Keeps the value positive in two's complement
+    if n >= 0x80 {
+        tlv(tag, &[0, n])
+    } else {
+        tlv(tag, &[n])
+    }
+}
+
+fn octets(value: &str) -> Vec<u8> {
+    tlv(0x04, value.as_bytes())
+}
+
+fn message(id: u8, operation: Vec<u8>) -> Vec<u8> {
+    let mut contents = integer(0x02, id);
+    contents.extend(operation);
+    tlv(0x30, &contents)
+}
+
+/// Splits the BER element at the start of `input` into its tag, its contents and the rest.
+fn read_ber(input: &[u8]) -> anyhow::Result<(u8, &[u8], &[u8])> {
+    let invalid = || anyhow::anyhow!("Invalid response from the directory");
+    let (&tag, input) = input.split_first().ok_or_else(invalid)?;
+    let (&first, mut input) = input.split_first().ok_or_else(invalid)?;
+    let len = if first < 0x80 {
+        usize::from(first)
+    } else {
+        let count = usize::from(first & 0x7f);
+        if count == 0 || count > 4 || input.len() < count {
+            return Err(invalid());
+        }
+        let (bytes, rest) = input.split_at(count);
+        input = rest;
+        bytes
+            .iter()
+            .fold(0usize, |len, &byte| (len << 8) | usize::from(byte))
+    };
+    if input.len() < len {
+        return Err(invalid());
+    }
+    let (contents, rest) = input.split_at(len);
+    Ok((tag, contents, rest))
+}
+
+/// Reads one `LDAPMessage` and returns its operation's tag and contents.
+fn read_message(stream: &mut dyn ReadWrite) -> anyhow::Result<(u8, Vec<u8>)> {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head)?;
+    if head[0] != 0x30 {
+        return Err(anyhow::anyhow!("Invalid response from the directory"));
+    }
+    let len = if head[1] < 0x80 {
+        usize::from(head[1])
+    } else {
+        let count = usize::from(head[1] & 0x7f);
+        if count == 0 || count > 4 {
+            return Err(anyhow::anyhow!("Invalid response from the directory"));
+        }
+        let mut bytes = [0u8; 4];
+        stream.read_exact(&mut bytes[4 - count..])?;
+        u32::from_be_bytes(bytes) as usize
+    };
+    if len > MAX_MESSAGE_LEN {
+        return Err(anyhow::anyhow!("The directory's response is too large"));
+    }
+    let mut contents = vec![0u8; len];
+    stream.read_exact(&mut contents)?;
+    let (_, _, operation) = read_ber(&contents)?;
+    let (tag, operation, _) = read_ber(operation)?;
+    Ok((tag, operation.to_vec()))
+}
+
+/// Reads the result code and diagnostic message of an `LDAPResult`.
+fn read_result(contents: &[u8]) -> anyhow::Result<(u8, String)> {
+    let (_, code, rest) = read_ber(contents)?;
+    let (_, _matched_dn, rest) = read_ber(rest)?;
+    let (_, diagnostic, _) = read_ber(rest)?;
+    // Every code that matters here fits in one byte
+    let code = match code {
+        [code] => *code,
+        _ => u8::MAX,
+    };
+    Ok((code, String::from_utf8_lossy(diagnostic).into_owned()))
+}
+
+/// Reads the attributes of a `SearchResultEntry`, by their names in lowercase.
+fn read_entry(contents: &[u8]) -> anyhow::Result<FxHashMap<String, Vec<String>>> {
+    let (_, _dn, rest) = read_ber(contents)?;
+    let (_, mut list, _) = read_ber(rest)?;
+    let mut attributes = FxHashMap::default();
+    while !list.is_empty() {
+        let (_, attribute, rest) = read_ber(list)?;
+        list = rest;
+        let (_, name, rest) = read_ber(attribute)?;
+        let (_, mut set, _) = read_ber(rest)?;
+        let mut values = vec![];
+        while !set.is_empty() {
+            let (_, value, rest) = read_ber(set)?;
+            set = rest;
+            values.push(String::from_utf8_lossy(value).into_owned());
+        }
+        attributes.insert(String::from_utf8_lossy(name).to_lowercase(), values);
+    }
+    Ok(attributes)
+}
+
+impl Directory {
+    fn new(options: LdapOptions) -> anyhow::Result<Self> {
+        let invalid = || anyhow::anyhow!("ldap.url is not an ldap:// or ldaps:// url");
+        let (scheme, address) = options.url.split_once("://").ok_or_else(invalid)?;
+        let tls = match scheme {
+            "ldaps" => true,
+            "ldap" => false,
+            _ => return Err(invalid()),
+        };
+        #[cfg(not(feature = "ldap-tls"))]
+        if tls {
+            return Err(anyhow::anyhow!(
+                "ldap.url uses ldaps, but teach-tech-core was built without the ldap-tls feature"
+            ));
+        }
+        let address = address.trim_end_matches('/');
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (address, if tls { 636 } else { 389 }),
+        };
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            tls,
+            connector: Connector::new(Duration::from_secs(options.timeout_secs))?,
+            options,
+        })
+    }
+
+    /// Binds as `user_id` and looks up their attributes. `None` when the directory refuses the
+    /// password.
+    fn authenticate(
+        &self,
+        user_id: UserID,
+        password: &str,
+    ) -> anyhow::Result<Option<FxHashMap<String, Vec<String>>>> {
+        // An empty password is an unauthenticated bind, which directories let anyone make
+        if password.is_empty() {
+            return Ok(None);
+        }
+        let user_id = user_id.to_string();
+        let mut stream = self.connector.connect(&self.host, self.port, self.tls)?;
+
+        let mut bind = integer(0x02, 3);
+        bind.extend(octets(&self.options.bind_dn.replace("{user_id}", &user_id)));
+        bind.extend(tlv(0x80, password.as_bytes()));
+        stream.write_all(&Zeroizing::new(message(1, tlv(0x60, &bind))))?;
+        let (tag, contents) = read_message(&mut *stream)?;
+        if tag != 0x61 {
+            return Err(anyhow::anyhow!("The directory did not answer the bind"));
+        }
+        match read_result(&contents)? {
+            (SUCCESS, _) => {}
+            (INVALID_CREDENTIALS, _) => return Ok(None),
+            (code, diagnostic) => {
+                return Err(anyhow::anyhow!(
+                    "The directory refused to bind with {code}: {diagnostic}"
+                ))
+            }
+        }
+
+        let mut attributes = FxHashMap::default();
+        if let Some(search_base) = &self.options.search_base {
+            let mut filter = octets(&self.options.user_attribute);
+            filter.extend(octets(&user_id));
+            let mut wanted = octets(&self.options.name_attribute);
+            wanted.extend(
+                self.options
+                    .birthdate_attribute
+                    .iter()
+                    .flat_map(|name| octets(name)),
+            );
+            for mapping in &self.options.roles {
+                wanted.extend(octets(&mapping.attribute));
+            }
+            let mut search = octets(search_base);
+            // The whole subtree, never dereferencing aliases, for up to 2 entries
+            search.extend(tlv(0x0a, &[2]));
+            search.extend(tlv(0x0a, &[0]));
+            search.extend(integer(0x02, 2));
+            search.extend(integer(0x02, self.options.timeout_secs.min(127) as u8));
+            search.extend(tlv(0x01, &[0]));
+            search.extend(tlv(0xa3, &filter));
+            search.extend(tlv(0x30, &wanted));
+            stream.write_all(&message(2, tlv(0x63, &search)))?;
+
+            let mut entries = 0;
+            loop {
+                let (tag, contents) = read_message(&mut *stream)?;
+                match tag {
+                    0x64 => {
+                        entries += 1;
+                        attributes = read_entry(&contents)?;
+                    }
+                    0x65 => match read_result(&contents)? {
+                        (SUCCESS | NO_SUCH_OBJECT, _) => break,
+                        (code, diagnostic) => {
+                            return Err(anyhow::anyhow!(
+                                "The directory refused to search with {code}: {diagnostic}"
+                            ))
+                        }
+                    },
+                    // Referrals to other directories are not followed
+                    _ => {}
+                }
+            }
+            if entries > 1 {
+                return Err(anyhow::anyhow!(
+                    "More than one entry in the directory has {} {user_id}",
+                    self.options.user_attribute
+                ));
+            }
+        }
+
+        let _ = stream.write_all(&message(3, vec![0x42, 0]));
+        Ok(Some(attributes))
+    }
+}
+
+/// Checks `password` with the directory, creating the user on their first login. `None` without
+/// `[ldap]`.
+pub(crate) async fn log_in(
+    user_id: UserID,
+    password: &str,
+) -> anyhow::Result<Option<DirectoryLogin>> {
+    let Some(directory) = DIRECTORY.get() else {
+        return Ok(None);
+    };
+    let password = Zeroizing::new(password.to_string());
+    let Some(attributes) =
+        tokio::task::spawn_blocking(move || directory.authenticate(user_id, &password)).await??
+    else {
+        return Ok(Some(DirectoryLogin::Refused));
+    };
+
+    let options = &directory.options;
+    let attribute = |name: &str| {
+        attributes
+            .get(&name.to_lowercase())
+            .and_then(|values| values.first())
+            .map(String::as_str)
+    };
+    let role = provision::role_for(&attributes, &options.roles).or(options.default_role);
+    let subject = user_id.to_string();
+    let txn = get_db().begin().await?;
+    if let Some(link) = links::find(PROVIDER, &subject, &txn).await? {
+        txn.commit().await?;
+        return Ok(Some(DirectoryLogin::Accepted(link.user_id)));
+    }
+    if user_auth::Entity::find_by_id(user_id)
+        .one(&txn)
+        .await?
+        .is_some()
+    {
+        warn!("Directory user {user_id} has the ID of a local user, so they must be linked");
+        return Ok(Some(DirectoryLogin::Unlinked));
+    }
+    let Some(role) = role else {
+        warn!("Directory user {user_id} has no role");
+        return Ok(Some(DirectoryLogin::Unlinked));
+    };
+
+    // Nobody knows the local password, which only keeps the ID from being given to anyone else
+    let (auth, _) = user_auth::new_generated(user_id)
+        .await
+        .map_err(|e| anyhow::anyhow!("Hashing password for {user_id}: {e:#}"))?;
+    auth.insert(&txn).await?;
+    let name = attribute(&options.name_attribute)
+        .unwrap_or(&subject)
+        .to_string();
+    let birthdate =
+        provision::birthdate(options.birthdate_attribute.as_deref().and_then(attribute));
+    provision::create(user_id, role, name, birthdate, &txn).await?;
+    links::create(PROVIDER.to_string(), subject, user_id, user_id, &txn).await?;
+    txn.commit().await?;
+    info!("Created {user_id} as a {role:?} from the directory");
+    Ok(Some(DirectoryLogin::Accepted(user_id)))
+}
+
+pub(crate) fn init(options: Option<LdapOptions>) -> anyhow::Result<()> {
+    if let Some(mut options) = options {
+        // Attribute names are not case sensitive
+        for mapping in &mut options.roles {
+            mapping.attribute = mapping.attribute.to_lowercase();
+        }
+        let _ = DIRECTORY.set(Directory::new(options)?);
+    }
+    Ok(())
+}
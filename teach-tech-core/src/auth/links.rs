@@ -0,0 +1,231 @@
+//! External logins, such as through OpenID Connect or SAML, and the users they stand for.
+//!
+//! Administrators manage links under `/admin/login-links`.
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    routing::{delete, get},
+    Json,
+};
+use sea_orm::{entity::prelude::*, ActiveValue, TransactionTrait};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use super::{guard::RequirePermission, ldap, oidc, saml, token, AuthUser, Token, UserID};
+use crate::{
+    audit::{self, AuditEvent},
+    db::{get_db, get_read_db, paginate, PageQuery},
+    http::form_encode,
+    i18n,
+    routes::TrackedRouter,
+    users::admins::permissions::Permission,
+};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "login_links")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    /// The name of an `[auth.oidc.<name>]` provider, `saml` or `ldap`
+    pub provider: String,
+    /// What the provider identifies the person by, such as the `sub` claim of OpenID Connect
+    pub subject: String,
+    pub user_id: UserID,
+    pub created_at: DateTime,
+    /// The user themself, when the link was made on their first login
+    pub created_by: UserID,
+    pub last_login_at: Option<DateTime>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+fn is_provider(name: &str) -> bool {
+    oidc::has_provider(name) || saml::has_provider(name) || ldap::has_provider(name)
+}
+
+pub async fn find(
+    provider: &str,
+    subject: &str,
+    conn: &impl ConnectionTrait,
+) -> Result<Option<Model>, DbErr> {
+    Entity::find()
+        .filter(Column::Provider.eq(provider))
+        .filter(Column::Subject.eq(subject))
+        .one(conn)
+        .await
+}
+
+pub async fn create(
+    provider: String,
+    subject: String,
+    user_id: UserID,
+    created_by: UserID,
+    conn: &impl ConnectionTrait,
+) -> Result<Model, DbErr> {
+    ActiveModel {
+        id: ActiveValue::not_set(),
+        provider: ActiveValue::set(provider),
+        subject: ActiveValue::set(subject),
+        user_id: ActiveValue::set(user_id),
+        created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+        created_by: ActiveValue::set(created_by),
+        last_login_at: ActiveValue::set(None),
+    }
+    .insert(conn)
+    .await
+}
+
+/// Issues a token to the user a link stands for.
+pub async fn log_in(
+    link: &Model,
+    conn: &impl ConnectionTrait,
+) -> Result<(token::Model, token::Replaced), DbErr> {
+    ActiveModel {
+        id: ActiveValue::unchanged(link.id),
+        last_login_at: ActiveValue::set(Some(chrono::Utc::now().naive_utc())),
+        ..Default::default()
+    }
+    .update(conn)
+    .await?;
+    audit::record(
+        conn,
+        AuditEvent::new(audit::LOGIN, Some(link.user_id)).detail(&link.provider),
+    )
+    .await?;
+    let (token, replaced) = token::Model::gen_new(link.user_id, conn).await?;
+    Ok((token.insert(conn).await?, replaced))
+}
+
+/// Sends the browser back to the frontend with the token in the fragment, such as
+/// `#token=...&expires_at=...`, or responds with the token as JSON without a frontend.
+pub(crate) fn login_response(frontend_url: Option<&str>, token: token::Model) -> Response {
+    let token = Token::from(token);
+    match frontend_url {
+        Some(frontend_url) => Redirect::to(&format!(
+            "{frontend_url}#{}",
+            form_encode(&[
+                ("token", &token.token),
+                ("expires_at", &token.expires_at.and_utc().to_rfc3339()),
+            ])
+        ))
+        .into_response(),
+        None => (StatusCode::OK, Json(token)).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LinkQuery {
+    pub provider: Option<String>,
+    pub user_id: Option<UserID>,
+}
+
+async fn list_links(Query(query): Query<LinkQuery>, Query(page): Query<PageQuery>) -> Response {
+    let Ok(cursor) = page.cursor::<i32>() else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_cursor")).into_response();
+    };
+    let mut select = Entity::find();
+    if let Some(provider) = query.provider {
+        select = select.filter(Column::Provider.eq(provider));
+    }
+    if let Some(user_id) = query.user_id {
+        select = select.filter(Column::UserId.eq(user_id));
+    }
+    match paginate(
+        select,
+        Column::Id,
+        |model| model.id,
+        cursor,
+        page.limit(),
+        get_read_db(),
+    )
+    .await
+    {
+        Ok(links) => (StatusCode::OK, Json(links)).into_response(),
+        Err(e) => {
+            error!("Error listing login links: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewLink {
+    pub provider: String,
+    pub subject: String,
+    pub user_id: UserID,
+}
+
+async fn create_link(
+    AuthUser {
+        user_id: created_by,
+        ..
+    }: AuthUser,
+    Json(link): Json<NewLink>,
+) -> Response {
+    if !is_provider(&link.provider) {
+        return (
+            StatusCode::BAD_REQUEST,
+            i18n::t("error.unknown_login_provider"),
+        )
+            .into_response();
+    }
+    let result = async {
+        let txn = get_db().begin().await?;
+        if find(&link.provider, &link.subject, &txn).await?.is_some() {
+            return Ok(None);
+        }
+        let model = create(link.provider, link.subject, link.user_id, created_by, &txn).await?;
+        txn.commit().await?;
+        Ok::<_, DbErr>(Some(model))
+    }
+    .await;
+    match result {
+        Ok(Some(model)) => {
+            info!(
+                "{created_by} linked {} {} to {}",
+                model.provider, model.subject, model.user_id
+            );
+            (StatusCode::CREATED, Json(model)).into_response()
+        }
+        Ok(None) => (StatusCode::CONFLICT, i18n::t("error.login_already_linked")).into_response(),
+        Err(e) => {
+            error!("Error linking login: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+async fn delete_link(AuthUser { user_id, .. }: AuthUser, Path(id): Path<i32>) -> Response {
+    match Entity::delete_by_id(id).exec(get_db()).await {
+        Ok(result) if result.rows_affected == 0 => (StatusCode::NOT_FOUND, ()).into_response(),
+        Ok(_) => {
+            info!("Login link {id} deleted by {user_id}");
+            (StatusCode::NO_CONTENT, ()).into_response()
+        }
+        Err(e) => {
+            error!("Error deleting login link {id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+pub(crate) fn add_routes<S: Clone + Send + Sync + 'static>(
+    router: TrackedRouter<S>,
+) -> TrackedRouter<S> {
+    router
+        .route(
+            "/admin/login-links",
+            get(list_links)
+                .post(create_link)
+                .layer(RequirePermission(Permission::LinkLogins)),
+        )
+        .route(
+            "/admin/login-links/:id",
+            delete(delete_link).layer(RequirePermission(Permission::LinkLogins)),
+        )
+}
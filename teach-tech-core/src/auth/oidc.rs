@@ -0,0 +1,494 @@
+//! Logging in through OpenID Connect providers, such as Google Workspace or Microsoft Entra ID.
+//!
+//! Each provider under `[auth.oidc.<name>]` gets `/auth/oidc/<name>/login`, which sends the
+//! browser to the provider, and `/auth/oidc/<name>/callback`, which the provider sends it back to.
+//! Administrators link the subjects that providers identify people by to users under
+//! `/admin/login-links`.
+
+use std::{
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    routing::get,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use fxhash::FxHashMap;
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    rngs::OsRng,
+};
+use sea_orm::{DbErr, TransactionTrait};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+use zeroize::Zeroizing;
+
+use super::links;
+use crate::{
+    cache,
+    client_ip::ClientIp,
+    db::get_db,
+    http::{form_encode, Endpoint, HttpClient},
+    i18n,
+    routes::TrackedRouter,
+    security::{self, SecurityEvent},
+};
+
+/// How long a browser has to come back from the provider
+const STATE_TTL: Duration = Duration::from_secs(600);
+const METADATA_TTL: Duration = Duration::from_secs(3600);
+
+static PROVIDERS: OnceLock<FxHashMap<String, Provider>> = OnceLock::new();
+
+/// An `[auth.oidc.<name>]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OidcProviderOptions {
+    /// Such as `https://accounts.google.com`, or
+    /// `https://login.microsoftonline.com/<tenant>/v2.0` for Entra ID
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Zeroizing<String>,
+    /// The url of `/auth/oidc/<name>/callback` as the provider knows it
+    pub redirect_url: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// Where the browser is sent after logging in, with the token and its expiry in the
+    /// fragment, such as `#token=...&expires_at=...`. Without it, the callback responds with the
+    /// token as JSON
+    pub frontend_url: Option<String>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".to_string(), "email".to_string()]
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+struct Provider {
+    options: OidcProviderOptions,
+    client: HttpClient,
+    metadata: Mutex<Option<(Instant, Arc<Metadata>)>>,
+}
+
+impl Provider {
+    /// The provider's discovery document, fetched at most once an hour.
+    async fn metadata(&'static self) -> anyhow::Result<Arc<Metadata>> {
+        if let Some((fetched_at, metadata)) = &*self.metadata.lock().unwrap() {
+            if fetched_at.elapsed() < METADATA_TTL {
+                return Ok(metadata.clone());
+            }
+        }
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.options.issuer.trim_end_matches('/')
+        );
+        let metadata: Metadata =
+            tokio::task::spawn_blocking(move || self.client.get(&url)?.json("The OpenID provider"))
+                .await??;
+        if metadata.issuer != self.options.issuer {
+            return Err(anyhow::anyhow!(
+                "The OpenID provider says its issuer is {}, not {}",
+                metadata.issuer,
+                self.options.issuer
+            ));
+        }
+        check_https(&metadata.token_endpoint)
+            .context("Checking the token endpoint of the OpenID provider")?;
+        let metadata = Arc::new(metadata);
+        *self.metadata.lock().unwrap() = Some((Instant::now(), metadata.clone()));
+        Ok(metadata)
+    }
+}
+
+/// A login that was sent to a provider and has not come back yet.
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingLogin {
+    provider: String,
+    nonce: String,
+    /// The PKCE code verifier
+    verifier: String,
+}
+
+fn state_key(state: &str) -> String {
+    format!("oidc_state:{state}")
+}
+
+fn random_string() -> String {
+    let mut string = String::new();
+    Alphanumeric.append_string(&mut OsRng, &mut string, 43);
+    string
+}
+
+pub(crate) fn has_provider(name: &str) -> bool {
+    PROVIDERS
+        .get()
+        .is_some_and(|providers| providers.contains_key(name))
+}
+
+fn provider(name: &str) -> Result<&'static Provider, Response> {
+    PROVIDERS
+        .get()
+        .and_then(|providers| providers.get(name))
+        .ok_or_else(|| (StatusCode::NOT_FOUND, ()).into_response())
+}
+
+async fn login(Path(name): Path<String>) -> Response {
+    let provider = match self::provider(&name) {
+        Ok(provider) => provider,
+        Err(response) => return response,
+    };
+    let metadata = match provider.metadata().await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            error!("Error discovering OpenID provider {name}: {e:#}");
+            return (StatusCode::BAD_GATEWAY, ()).into_response();
+        }
+    };
+    let state = random_string();
+    let pending = PendingLogin {
+        provider: name,
+        nonce: random_string(),
+        verifier: random_string(),
+    };
+    let challenge = URL_SAFE_NO_PAD.encode(ring::digest::digest(
+        &ring::digest::SHA256,
+        pending.verifier.as_bytes(),
+    ));
+    let options = &provider.options;
+    let query = form_encode(&[
+        ("response_type", "code"),
+        ("client_id", &options.client_id),
+        ("redirect_uri", &options.redirect_url),
+        ("scope", &options.scopes.join(" ")),
+        ("state", &state),
+        ("nonce", &pending.nonce),
+        ("code_challenge", &challenge),
+        ("code_challenge_method", "S256"),
+    ]);
+    if let Err(e) = cache::set_json(&state_key(&state), &pending, Some(STATE_TTL)).await {
+        error!("Error saving OpenID login state: {e:#}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+    }
+    let separator = if metadata.authorization_endpoint.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    Redirect::to(&format!(
+        "{}{separator}{query}",
+        metadata.authorization_endpoint
+    ))
+    .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+}
+
+/// Exchanges the code for an ID token and returns the subject it is about.
+///
+/// The token comes straight from the provider over TLS, so its signature is not checked, as
+/// OpenID Connect Core 1.0 allows in section 3.1.3.7. [`check_https`] makes sure of the TLS.
+async fn subject_of(
+    provider: &'static Provider,
+    code: String,
+    pending: &PendingLogin,
+) -> anyhow::Result<Result<String, &'static str>> {
+    let metadata = provider.metadata().await?;
+    let options = &provider.options;
+    let form = Zeroizing::new(form_encode(&[
+        ("grant_type", "authorization_code"),
+        ("code", &code),
+        ("redirect_uri", &options.redirect_url),
+        ("client_id", &options.client_id),
+        ("client_secret", &options.client_secret),
+        ("code_verifier", &pending.verifier),
+    ]));
+    let url = metadata.token_endpoint.clone();
+    let response: TokenResponse = tokio::task::spawn_blocking(move || {
+        provider
+            .client
+            .post_form(&url, &form)?
+            .json("The OpenID provider")
+    })
+    .await??;
+    read_claims(
+        &response.id_token,
+        &metadata.issuer,
+        &options.client_id,
+        &pending.nonce,
+    )
+}
+
+/// The subject of `id_token`, or the claim that does not match the login.
+fn read_claims(
+    id_token: &str,
+    issuer: &str,
+    client_id: &str,
+    nonce: &str,
+) -> anyhow::Result<Result<String, &'static str>> {
+    let payload = id_token
+        .split('.')
+        .nth(1)
+        .context("The ID token is not a JWT")?;
+    let claims: Claims = serde_json::from_slice(
+        &URL_SAFE_NO_PAD
+            .decode(payload)
+            .context("Decoding the ID token")?,
+    )
+    .context("Reading the claims of the ID token")?;
+    if claims.iss != issuer {
+        return Ok(Err("issuer"));
+    }
+    let audience_matches = match &claims.aud {
+        Audience::One(audience) => audience == client_id,
+        Audience::Many(audiences) => audiences.iter().any(|audience| audience == client_id),
+    };
+    if !audience_matches {
+        return Ok(Err("audience"));
+    }
+    if claims.exp < chrono::Utc::now().timestamp() {
+        return Ok(Err("expiry"));
+    }
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Ok(Err("nonce"));
+    }
+    Ok(Ok(claims.sub))
+}
+
+async fn callback(
+    client_ip: ClientIp,
+    Path(name): Path<String>,
+    Query(query): Query<CallbackQuery>,
+) -> Response {
+    let provider = match self::provider(&name) {
+        Ok(provider) => provider,
+        Err(response) => return response,
+    };
+    if let Some(error) = query.error {
+        warn!(
+            "OpenID provider {name} refused a login from {client_ip}: {error} {}",
+            query.error_description.unwrap_or_default()
+        );
+        return (StatusCode::UNAUTHORIZED, i18n::t("error.oidc_refused")).into_response();
+    }
+    let (Some(code), Some(state)) = (query.code, query.state) else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.oidc_invalid_state")).into_response();
+    };
+
+    // States are used once, so that a leaked callback url cannot be replayed
+    let key = state_key(&state);
+    let pending: Option<PendingLogin> = match cache::get_json(&key).await {
+        Ok(pending) => pending,
+        Err(e) => {
+            error!("Error reading OpenID login state: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+    if let Err(e) = cache::get_cache().invalidate(&key).await {
+        error!("Error removing OpenID login state: {e:#}");
+    }
+    let Some(pending) = pending.filter(|pending| pending.provider == name) else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.oidc_invalid_state")).into_response();
+    };
+
+    let subject = match subject_of(provider, code, &pending).await {
+        Ok(Ok(subject)) => subject,
+        Ok(Err(claim)) => {
+            warn!("ID token from OpenID provider {name} has an invalid {claim}, from {client_ip}");
+            security::record(SecurityEvent::FailedLogin);
+            return (StatusCode::UNAUTHORIZED, i18n::t("error.oidc_refused")).into_response();
+        }
+        Err(e) => {
+            error!("Error completing login through OpenID provider {name}: {e:#}");
+            return (StatusCode::BAD_GATEWAY, ()).into_response();
+        }
+    };
+
+    let result = async {
+        let txn = get_db().begin().await?;
+        let Some(link) = links::find(&name, &subject, &txn).await? else {
+            return Ok(None);
+        };
+        let (token, replaced) = links::log_in(&link, &txn).await?;
+        txn.commit().await?;
+        replaced.revoke();
+        Ok::<_, DbErr>(Some(token))
+    }
+    .await;
+    let token = match result {
+        Ok(Some(token)) => token,
+        Ok(None) => {
+            warn!("Login from {client_ip} through OpenID provider {name} for unlinked {subject}");
+            security::record(SecurityEvent::FailedLogin);
+            return (
+                StatusCode::FORBIDDEN,
+                i18n::t_with(
+                    "error.oidc_not_linked",
+                    &[("provider", &name), ("subject", &subject)],
+                ),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            error!("Error logging in {subject} through OpenID provider {name}: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+
+    info!(
+        "{} logged in through OpenID provider {name} from {client_ip}",
+        token.user_id
+    );
+    links::login_response(provider.options.frontend_url.as_deref(), token)
+}
+
+/// Refuses urls other than https ones, as ID tokens are only trusted for coming over TLS.
+fn check_https(url: &str) -> anyhow::Result<()> {
+    if !url.starts_with("https://") {
+        return Err(anyhow::anyhow!(
+            "{url} is not an https url, which OpenID providers must have"
+        ));
+    }
+    Endpoint::parse(url)?;
+    Ok(())
+}
+
+pub(crate) fn init(options: FxHashMap<String, OidcProviderOptions>) -> anyhow::Result<()> {
+    let mut providers = FxHashMap::default();
+    for (name, options) in options {
+        check_https(&options.issuer)
+            .with_context(|| format!("Checking auth.oidc.{name}.issuer"))?;
+        providers.insert(
+            name,
+            Provider {
+                client: HttpClient::new(Duration::from_secs(options.timeout_secs))?,
+                options,
+                metadata: Mutex::new(None),
+            },
+        );
+    }
+    let _ = PROVIDERS.set(providers);
+    Ok(())
+}
+
+pub(crate) fn add_routes<S: Clone + Send + Sync + 'static>(
+    router: TrackedRouter<S>,
+) -> TrackedRouter<S> {
+    router
+        .route("/auth/oidc/:provider/login", get(login))
+        .route("/auth/oidc/:provider/callback", get(callback))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ISSUER: &str = "https://idp.example";
+
+    fn id_token(claims: serde_json::Value) -> String {
+        format!("e30.{}.", URL_SAFE_NO_PAD.encode(claims.to_string()))
+    }
+
+    fn claims(aud: serde_json::Value, nonce: &str) -> serde_json::Value {
+        serde_json::json!({
+            "iss": ISSUER,
+            "sub": "alice",
+            "aud": aud,
+            "exp": chrono::Utc::now().timestamp() + 60,
+            "nonce": nonce,
+        })
+    }
+
+    #[test]
+    fn matching_claims_give_the_subject() {
+        let token = id_token(claims("client".into(), "nonce"));
+        assert_eq!(
+            read_claims(&token, ISSUER, "client", "nonce").unwrap(),
+            Ok("alice".to_string())
+        );
+        let token = id_token(claims(serde_json::json!(["other", "client"]), "nonce"));
+        assert_eq!(
+            read_claims(&token, ISSUER, "client", "nonce").unwrap(),
+            Ok("alice".to_string())
+        );
+    }
+
+    #[test]
+    fn mismatched_claims_are_refused() {
+        let token = id_token(claims("client".into(), "nonce"));
+        let read =
+            |issuer, client_id, nonce| read_claims(&token, issuer, client_id, nonce).unwrap();
+        assert_eq!(
+            read("https://evil.example", "client", "nonce"),
+            Err("issuer")
+        );
+        assert_eq!(read(ISSUER, "other", "nonce"), Err("audience"));
+        assert_eq!(read(ISSUER, "client", "replayed"), Err("nonce"));
+
+        let mut expired = claims("client".into(), "nonce");
+        expired["exp"] = (chrono::Utc::now().timestamp() - 1).into();
+        assert_eq!(
+            read_claims(&id_token(expired), ISSUER, "client", "nonce").unwrap(),
+            Err("expiry")
+        );
+
+        let mut no_nonce = claims("client".into(), "nonce");
+        no_nonce.as_object_mut().unwrap().remove("nonce");
+        assert_eq!(
+            read_claims(&id_token(no_nonce), ISSUER, "client", "nonce").unwrap(),
+            Err("nonce")
+        );
+        assert!(read_claims("not a jwt", ISSUER, "client", "nonce").is_err());
+    }
+
+    #[test]
+    fn refuses_plain_http() {
+        let error = check_https("http://idp.example").unwrap_err();
+        assert!(error.to_string().contains("not an https url"), "{error:#}");
+        assert!(check_https("idp.example").is_err());
+        #[cfg(feature = "https")]
+        check_https("https://idp.example/tenant").unwrap();
+    }
+}
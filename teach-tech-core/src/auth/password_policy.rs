@@ -0,0 +1,251 @@
+//! The rules passwords must follow, and the passwords generated for new users.
+//!
+//! Integrations can refuse passwords known from breaches with [`add_breach_check`], such as by
+//! asking a k-anonymity range API.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use rand::{rngs::OsRng, seq::SliceRandom};
+use serde::Deserialize;
+use tracing::error;
+use zeroize::Zeroizing;
+
+use crate::i18n;
+
+const LOWERCASE: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+const UPPERCASE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &[u8] = b"0123456789";
+const SYMBOLS: &[u8] = b"!#$%&*+-.:=?@^_~";
+
+type BreachCheck = Arc<
+    dyn Fn(Zeroizing<String>) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>> + Send>>
+        + Send
+        + Sync,
+>;
+
+static POLICY: OnceLock<PasswordPolicy> = OnceLock::new();
+static BREACH_CHECKS: Mutex<Vec<(String, BreachCheck)>> = Mutex::new(vec![]);
+
+/// The `[auth.password_policy]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PasswordPolicy {
+    /// In characters
+    #[serde(default = "default_min_length")]
+    pub min_length: usize,
+    #[serde(default = "default_max_length")]
+    pub max_length: usize,
+    #[serde(default)]
+    pub require_lowercase: bool,
+    #[serde(default)]
+    pub require_uppercase: bool,
+    #[serde(default)]
+    pub require_digit: bool,
+    #[serde(default)]
+    pub require_symbol: bool,
+    /// The length of passwords generated for new users
+    #[serde(default = "default_generated_length")]
+    pub generated_length: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: default_min_length(),
+            max_length: default_max_length(),
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            generated_length: default_generated_length(),
+        }
+    }
+}
+
+fn default_min_length() -> usize {
+    12
+}
+
+fn default_max_length() -> usize {
+    128
+}
+
+fn default_generated_length() -> usize {
+    18
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CharacterClass {
+    Lowercase,
+    Uppercase,
+    Digit,
+    Symbol,
+}
+
+impl CharacterClass {
+    fn matches(self, c: char) -> bool {
+        match self {
+            Self::Lowercase => c.is_lowercase(),
+            Self::Uppercase => c.is_uppercase(),
+            Self::Digit => c.is_numeric(),
+            Self::Symbol => !c.is_alphanumeric() && !c.is_whitespace(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PasswordError {
+    /// Shorter than the minimum length, which is given
+    TooShort(usize),
+    /// Longer than the maximum length, which is given
+    TooLong(usize),
+    Missing(CharacterClass),
+    /// A breach check found the password
+    Breached,
+    /// The password could not be checked or hashed
+    Failed(anyhow::Error),
+}
+
+impl IntoResponse for PasswordError {
+    fn into_response(self) -> Response {
+        let message = match self {
+            Self::TooShort(min) => {
+                i18n::t_with("error.password_too_short", &[("min", &min.to_string())])
+            }
+            Self::TooLong(max) => {
+                i18n::t_with("error.password_too_long", &[("max", &max.to_string())])
+            }
+            Self::Missing(CharacterClass::Lowercase) => i18n::t("error.password_needs_lowercase"),
+            Self::Missing(CharacterClass::Uppercase) => i18n::t("error.password_needs_uppercase"),
+            Self::Missing(CharacterClass::Digit) => i18n::t("error.password_needs_digit"),
+            Self::Missing(CharacterClass::Symbol) => i18n::t("error.password_needs_symbol"),
+            Self::Breached => i18n::t("error.password_breached"),
+            Self::Failed(e) => {
+                error!("Error checking password: {e:#}");
+                return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+            }
+        };
+        (StatusCode::BAD_REQUEST, message).into_response()
+    }
+}
+
+impl PasswordPolicy {
+    fn required_classes(&self) -> Vec<CharacterClass> {
+        [
+            (self.require_lowercase, CharacterClass::Lowercase),
+            (self.require_uppercase, CharacterClass::Uppercase),
+            (self.require_digit, CharacterClass::Digit),
+            (self.require_symbol, CharacterClass::Symbol),
+        ]
+        .into_iter()
+        .filter_map(|(required, class)| required.then_some(class))
+        .collect()
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.min_length == 0 || self.min_length > self.max_length {
+            return Err(anyhow::anyhow!(
+                "auth.password_policy.min_length must be between 1 and max_length"
+            ));
+        }
+        if self.generated_length < self.min_length
+            || self.generated_length > self.max_length
+            || self.generated_length < self.required_classes().len()
+        {
+            return Err(anyhow::anyhow!(
+                "auth.password_policy.generated_length must be between min_length and max_length"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the length and character classes of `password`, but not the breach checks.
+    pub fn check_rules(&self, password: &str) -> Result<(), PasswordError> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(PasswordError::TooShort(self.min_length));
+        }
+        if length > self.max_length {
+            return Err(PasswordError::TooLong(self.max_length));
+        }
+        for class in self.required_classes() {
+            if !password.chars().any(|c| class.matches(c)) {
+                return Err(PasswordError::Missing(class));
+            }
+        }
+        Ok(())
+    }
+
+    /// A random password that follows the rules.
+    pub fn generate(&self) -> Zeroizing<String> {
+        let mut alphabet = [LOWERCASE, UPPERCASE, DIGITS].concat();
+        if self.require_symbol {
+            alphabet.extend_from_slice(SYMBOLS);
+        }
+        let mut password = Zeroizing::new(String::with_capacity(self.generated_length));
+        // Short passwords may miss a required class, so those are drawn again
+        loop {
+            password.clear();
+            for _ in 0..self.generated_length {
+                password.push(char::from(*alphabet.choose(&mut OsRng).unwrap()));
+            }
+            if self.check_rules(&password).is_ok() {
+                return password;
+            }
+        }
+    }
+}
+
+pub fn get_policy() -> &'static PasswordPolicy {
+    POLICY.get_or_init(PasswordPolicy::default)
+}
+
+/// Registers a function that is given every password being set, and returns whether it is known
+/// from a breach. Passwords any check finds are refused.
+pub fn add_breach_check<F, Fut>(name: impl Into<String>, f: F)
+where
+    F: Fn(Zeroizing<String>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<bool>> + Send + 'static,
+{
+    BREACH_CHECKS
+        .lock()
+        .unwrap()
+        .push((name.into(), Arc::new(move |password| Box::pin(f(password)))));
+}
+
+/// Checks `password` against the policy and every breach check. Call this wherever users choose a
+/// password.
+pub async fn check(password: &str) -> Result<(), PasswordError> {
+    get_policy().check_rules(password)?;
+    let checks = BREACH_CHECKS.lock().unwrap().clone();
+    for (name, check) in checks {
+        match check(Zeroizing::new(password.to_string())).await {
+            Ok(false) => {}
+            Ok(true) => return Err(PasswordError::Breached),
+            Err(e) => {
+                return Err(PasswordError::Failed(
+                    e.context(format!("Breach check {name}")),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A random password that follows the policy.
+pub fn generate() -> Zeroizing<String> {
+    get_policy().generate()
+}
+
+pub(crate) fn init(policy: PasswordPolicy) -> anyhow::Result<()> {
+    policy.validate()?;
+    let _ = POLICY.set(policy);
+    Ok(())
+}
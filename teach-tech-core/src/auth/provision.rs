@@ -0,0 +1,116 @@
+//! Creating students and instructors for people who log in through an identity provider or a
+//! directory before anyone made them users.
+
+use fxhash::FxHashMap;
+use sea_orm::{ActiveModelTrait, ActiveValue, ConnectionTrait, DbErr};
+use serde::Deserialize;
+
+use super::UserID;
+use crate::{
+    audit::{self, AuditEvent},
+    encryption::Encrypted,
+    users::{instructors, students},
+};
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProvisionedRole {
+    Student,
+    Instructor,
+}
+
+/// An entry of the `roles` of `[auth.saml]` or `[ldap]`. People are created with the role of the
+/// first entry that one of their attributes matches.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoleMapping {
+    /// Such as `eduPersonAffiliation` or `memberOf`
+    pub attribute: String,
+    pub value: String,
+    pub role: ProvisionedRole,
+}
+
+pub fn role_for(
+    attributes: &FxHashMap<String, Vec<String>>,
+    roles: &[RoleMapping],
+) -> Option<ProvisionedRole> {
+    roles
+        .iter()
+        .find(|mapping| {
+            attributes
+                .get(&mapping.attribute)
+                .is_some_and(|values| values.contains(&mapping.value))
+        })
+        .map(|mapping| mapping.role)
+}
+
+/// Reads a birthdate written as `YYYYMMDD` or `YYYY-MM-DD`. Without one, people are born on
+/// 1970-01-01 until it is corrected.
+pub fn birthdate(value: Option<&str>) -> chrono::NaiveDateTime {
+    value
+        .and_then(|value| {
+            chrono::NaiveDate::parse_from_str(value, "%Y%m%d")
+                .or_else(|_| chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d"))
+                .ok()
+        })
+        .unwrap_or_default()
+        .and_time(chrono::NaiveTime::MIN)
+}
+
+/// Creates the student or instructor row of `user_id`, who created themself by logging in.
+pub async fn create(
+    user_id: UserID,
+    role: ProvisionedRole,
+    name: String,
+    birthdate: chrono::NaiveDateTime,
+    conn: &impl ConnectionTrait,
+) -> Result<(), DbErr> {
+    match role {
+        ProvisionedRole::Student => {
+            students::ActiveModel {
+                user_id: ActiveValue::set(user_id),
+                name: ActiveValue::set(name),
+                pronouns: ActiveValue::set(String::new()),
+                birthdate: ActiveValue::set(Encrypted::new(
+                    birthdate,
+                    students::Column::Birthdate,
+                    user_id,
+                )),
+                created_at: ActiveValue::not_set(),
+                updated_at: ActiveValue::not_set(),
+                created_by: ActiveValue::set(user_id),
+                deleted_at: ActiveValue::set(None),
+            }
+            .insert(conn)
+            .await?;
+            audit::record(
+                conn,
+                AuditEvent::new(audit::STUDENT_CREATED, Some(user_id)).subject(user_id),
+            )
+            .await?;
+        }
+        ProvisionedRole::Instructor => {
+            instructors::ActiveModel {
+                user_id: ActiveValue::set(user_id),
+                name: ActiveValue::set(name),
+                pronouns: ActiveValue::set(String::new()),
+                birthdate: ActiveValue::set(Encrypted::new(
+                    birthdate,
+                    instructors::Column::Birthdate,
+                    user_id,
+                )),
+                created_at: ActiveValue::not_set(),
+                updated_at: ActiveValue::not_set(),
+                created_by: ActiveValue::set(user_id),
+                deleted_at: ActiveValue::set(None),
+            }
+            .insert(conn)
+            .await?;
+            audit::record(
+                conn,
+                AuditEvent::new(audit::INSTRUCTOR_CREATED, Some(user_id)).subject(user_id),
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
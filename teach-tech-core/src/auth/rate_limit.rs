@@ -0,0 +1,467 @@
+//! Token buckets that limit how often `/auth/login` can be tried, per address and per account.
+//!
+//! Buckets are kept in memory by default. With `store = "db"`, they are kept in the
+//! `login_rate_limits` table instead, so that every sibling draws from the same buckets.
+
+use std::{
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Form,
+};
+use fxhash::FxHashMap;
+use sea_orm::{entity::prelude::*, ActiveValue, QuerySelect, TransactionTrait};
+use serde::Deserialize;
+use tracing::{error, warn};
+
+use super::{user_auth, UserID};
+use crate::{
+    client_ip::ClientIp,
+    db::get_db,
+    i18n,
+    security::{self, SecurityEvent},
+    TeachCore,
+};
+
+/// Login forms larger than this are refused
+const MAX_FORM_LEN: usize = 16 * 1024;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+static LIMITER: OnceLock<Limiter> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitStore {
+    #[default]
+    Memory,
+    Db,
+}
+
+/// The `[auth.rate_limit]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitOptions {
+    #[serde(default)]
+    pub store: RateLimitStore,
+    /// Logins one address can try at once. 0 stops limiting addresses
+    #[serde(default = "default_ip_burst")]
+    pub ip_burst: u32,
+    /// How many of those come back every minute
+    #[serde(default = "default_ip_per_minute")]
+    pub ip_per_minute: u32,
+    /// Logins that can be tried for one account at once. 0 stops limiting accounts
+    #[serde(default = "default_account_burst")]
+    pub account_burst: u32,
+    #[serde(default = "default_account_per_minute")]
+    pub account_per_minute: u32,
+}
+
+impl Default for RateLimitOptions {
+    fn default() -> Self {
+        Self {
+            store: RateLimitStore::default(),
+            ip_burst: default_ip_burst(),
+            ip_per_minute: default_ip_per_minute(),
+            account_burst: default_account_burst(),
+            account_per_minute: default_account_per_minute(),
+        }
+    }
+}
+
+fn default_ip_burst() -> u32 {
+    20
+}
+
+fn default_ip_per_minute() -> u32 {
+    10
+}
+
+fn default_account_burst() -> u32 {
+    10
+}
+
+fn default_account_per_minute() -> u32 {
+    3
+}
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "login_rate_limits")]
+pub struct Model {
+    /// `ip/<address>`, `user/<user ID>`, or `user/<name>` for unknown usernames and emails
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub key: String,
+    pub tokens: f64,
+    /// Whether the last login tried from this bucket was refused
+    pub rejected: bool,
+    pub updated_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    burst: u32,
+    per_minute: u32,
+}
+
+impl Limit {
+    /// How long an empty bucket takes to fill up, after which it can be forgotten
+    fn refill_time(self) -> chrono::Duration {
+        // Buckets that never fill up are still forgotten after a day
+        if self.per_minute == 0 {
+            return chrono::Duration::days(1);
+        }
+        chrono::Duration::seconds(i64::from(self.burst) * 60 / i64::from(self.per_minute) + 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    rejected: bool,
+    updated_at: DateTime,
+}
+
+enum Outcome {
+    Allowed,
+    Rejected {
+        retry_after: u64,
+        /// Whether this is the first login refused since the bucket last allowed one
+        locked_out: bool,
+    },
+}
+
+impl Bucket {
+    fn full(limit: Limit, now: DateTime) -> Self {
+        Self {
+            tokens: f64::from(limit.burst),
+            rejected: false,
+            updated_at: now,
+        }
+    }
+
+    fn take(&mut self, limit: Limit, now: DateTime) -> Outcome {
+        let elapsed = (now - self.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        let per_sec = f64::from(limit.per_minute) / 60.0;
+        self.tokens = (self.tokens + elapsed * per_sec).min(f64::from(limit.burst));
+        self.updated_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.rejected = false;
+            return Outcome::Allowed;
+        }
+        let retry_after = if per_sec > 0.0 {
+            ((1.0 - self.tokens) / per_sec).ceil() as u64
+        } else {
+            limit.refill_time().num_seconds() as u64
+        };
+        let locked_out = !self.rejected;
+        self.rejected = true;
+        Outcome::Rejected {
+            retry_after,
+            locked_out,
+        }
+    }
+}
+
+struct Limiter {
+    store: RateLimitStore,
+    ip: Limit,
+    account: Limit,
+    buckets: Mutex<FxHashMap<String, Bucket>>,
+}
+
+impl Limiter {
+    async fn take(&self, key: String, limit: Limit) -> Result<Outcome, DbErr> {
+        let now = chrono::Utc::now().naive_utc();
+        if self.store == RateLimitStore::Memory {
+            let mut buckets = self.buckets.lock().unwrap();
+            return Ok(buckets
+                .entry(key)
+                .or_insert_with(|| Bucket::full(limit, now))
+                .take(limit, now));
+        }
+
+        let txn = get_db().begin().await?;
+        let model = Entity::find_by_id(&key).lock_exclusive().one(&txn).await?;
+        let exists = model.is_some();
+        let mut bucket = model.map_or_else(
+            || Bucket::full(limit, now),
+            |model| Bucket {
+                tokens: model.tokens,
+                rejected: model.rejected,
+                updated_at: model.updated_at,
+            },
+        );
+        let outcome = bucket.take(limit, now);
+        let model = ActiveModel {
+            key: ActiveValue::set(key),
+            tokens: ActiveValue::set(bucket.tokens),
+            rejected: ActiveValue::set(bucket.rejected),
+            updated_at: ActiveValue::set(bucket.updated_at),
+        };
+        if exists {
+            model.update(&txn).await?;
+        } else {
+            model.insert(&txn).await?;
+        }
+        txn.commit().await?;
+        Ok(outcome)
+    }
+
+    /// Forgets buckets that have filled up again.
+    async fn prune(&self) -> Result<(), DbErr> {
+        let now = chrono::Utc::now().naive_utc();
+        let ip_cutoff = now - self.ip.refill_time();
+        let account_cutoff = now - self.account.refill_time();
+        if self.store == RateLimitStore::Memory {
+            self.buckets.lock().unwrap().retain(|key, bucket| {
+                let cutoff = if key.starts_with("ip/") {
+                    ip_cutoff
+                } else {
+                    account_cutoff
+                };
+                bucket.updated_at >= cutoff
+            });
+            return Ok(());
+        }
+        Entity::delete_many()
+            .filter(Column::Key.starts_with("ip/"))
+            .filter(Column::UpdatedAt.lt(ip_cutoff))
+            .exec(get_db())
+            .await?;
+        Entity::delete_many()
+            .filter(Column::Key.starts_with("user/"))
+            .filter(Column::UpdatedAt.lt(account_cutoff))
+            .exec(get_db())
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginUser {
+    user_id: Option<UserID>,
+    identifier: Option<String>,
+}
+
+fn too_many_logins(retry_after: u64) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after.to_string())],
+        i18n::t("error.too_many_logins"),
+    )
+        .into_response()
+}
+
+/// Takes from the bucket at `key`, returning the response to refuse the login with once it is
+/// empty.
+async fn take(
+    limiter: &Limiter,
+    key: String,
+    limit: Limit,
+    client_ip: ClientIp,
+) -> Option<Response> {
+    match limiter.take(key.clone(), limit).await {
+        Ok(Outcome::Allowed) => None,
+        Ok(Outcome::Rejected {
+            retry_after,
+            locked_out,
+        }) => {
+            if locked_out {
+                warn!("Logins for {key} are rate limited, from {client_ip}");
+                security::record(SecurityEvent::Lockout);
+            }
+            Some(too_many_logins(retry_after))
+        }
+        Err(e) => {
+            // An unreachable database fails the login anyway, so this only logs
+            error!("Error rate limiting {key}: {e:#}");
+            None
+        }
+    }
+}
+
+/// Refuses logins with 429 and `Retry-After` once their address or account used up its bucket.
+///
+/// The address is checked first, before the form is read, so that refused addresses neither
+/// look accounts up nor drain the buckets of the accounts they try.
+pub async fn enforce(request: Request, next: Next) -> Response {
+    let Some(limiter) = LIMITER.get() else {
+        return next.run(request).await;
+    };
+    let (mut parts, body) = request.into_parts();
+    let client_ip = match ClientIp::from_request_parts(&mut parts, &()).await {
+        Ok(client_ip) => client_ip,
+        Err(status) => return (status, ()).into_response(),
+    };
+    if limiter.ip.burst > 0 {
+        let key = format!("ip/{client_ip}");
+        if let Some(response) = take(limiter, key, limiter.ip, client_ip).await {
+            return response;
+        }
+    }
+    let Ok(bytes) = axum::body::to_bytes(body, MAX_FORM_LEN).await else {
+        return (StatusCode::PAYLOAD_TOO_LARGE, ()).into_response();
+    };
+
+    if limiter.account.burst > 0 {
+        let mut form = Request::new(Body::from(bytes.clone()));
+        *form.method_mut() = parts.method.clone();
+        *form.headers_mut() = parts.headers.clone();
+        // Forms without a user are left for the handler to refuse
+        if let Ok(Form(LoginUser {
+            user_id,
+            identifier,
+        })) = Form::from_request(form, &()).await
+        {
+            // Usernames and emails share the bucket of the user they name
+            match user_auth::resolve_login(user_id, identifier.as_deref(), get_db()).await {
+                Ok(Some(account)) => {
+                    let key = format!("user/{account}");
+                    if let Some(response) = take(limiter, key, limiter.account, client_ip).await {
+                        return response;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => error!("Error looking up the user of a login: {e:#}"),
+            }
+        }
+    }
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+pub(crate) fn init<S>(options: RateLimitOptions, core: &mut TeachCore<S>) {
+    core.add_db_reset_config(Entity);
+    let _ = LIMITER.set(Limiter {
+        store: options.store,
+        ip: Limit {
+            burst: options.ip_burst,
+            per_minute: options.ip_per_minute,
+        },
+        account: Limit {
+            burst: options.account_burst,
+            per_minute: options.account_per_minute,
+        },
+        buckets: Mutex::new(FxHashMap::default()),
+    });
+    core.add_on_serve_named("rate_limit", 0, || async move {
+        tokio::spawn(async {
+            loop {
+                tokio::time::sleep(PRUNE_INTERVAL).await;
+                if let Some(limiter) = LIMITER.get() {
+                    if let Err(e) = limiter.prune().await {
+                        error!("Failed to prune login rate limits: {e:#}");
+                    }
+                }
+            }
+        });
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{extract::ConnectInfo, middleware, routing::post, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        burst: 3,
+        per_minute: 6,
+    };
+
+    fn is_allowed(outcome: Outcome) -> bool {
+        matches!(outcome, Outcome::Allowed)
+    }
+
+    #[test]
+    fn bucket_refuses_after_its_burst_until_it_refills() {
+        let start = chrono::Utc::now().naive_utc();
+        let mut bucket = Bucket::full(LIMIT, start);
+        for _ in 0..3 {
+            assert!(is_allowed(bucket.take(LIMIT, start)));
+        }
+        let Outcome::Rejected {
+            retry_after,
+            locked_out,
+        } = bucket.take(LIMIT, start)
+        else {
+            panic!("The fourth login was allowed");
+        };
+        // One login every 10 seconds
+        assert_eq!(retry_after, 10);
+        assert!(locked_out);
+        assert!(matches!(
+            bucket.take(LIMIT, start),
+            Outcome::Rejected {
+                locked_out: false,
+                ..
+            }
+        ));
+
+        let later = start + chrono::Duration::seconds(10);
+        assert!(is_allowed(bucket.take(LIMIT, later)));
+        assert!(!is_allowed(bucket.take(LIMIT, later)));
+    }
+
+    #[test]
+    fn bucket_does_not_fill_past_its_burst() {
+        let start = chrono::Utc::now().naive_utc();
+        let mut bucket = Bucket::full(LIMIT, start);
+        let later = start + chrono::Duration::hours(1);
+        for _ in 0..3 {
+            assert!(is_allowed(bucket.take(LIMIT, later)));
+        }
+        assert!(!is_allowed(bucket.take(LIMIT, later)));
+    }
+
+    // Limited addresses are refused before their form is read, so an oversized form gets 429 and
+    // not 413
+    #[tokio::test]
+    async fn addresses_are_limited_before_the_form_is_read() {
+        let _ = LIMITER.set(Limiter {
+            store: RateLimitStore::Memory,
+            ip: Limit {
+                burst: 1,
+                per_minute: 1,
+            },
+            account: Limit {
+                burst: 0,
+                per_minute: 0,
+            },
+            buckets: Mutex::default(),
+        });
+        let router = Router::new().route(
+            "/",
+            post(|| async { StatusCode::OK }).layer(middleware::from_fn(enforce)),
+        );
+        let peer = SocketAddr::from(([192, 0, 2, 1], 1234));
+        let login = |body: Body| {
+            let mut request = Request::post("/").body(body).unwrap();
+            request.extensions_mut().insert(ConnectInfo(peer));
+            router.clone().oneshot(request)
+        };
+
+        let response = login(Body::empty()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = login(Body::from(vec![b'a'; MAX_FORM_LEN + 1]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+    }
+}
@@ -0,0 +1,668 @@
+//! Logging in through a SAML 2.0 identity provider, such as a university's Shibboleth.
+//!
+//! With `[auth.saml]`, this API is a service provider with its metadata at `/auth/saml/metadata`.
+//! Logins start at `/auth/saml/login` or at the identity provider, which posts its response to
+//! `/auth/saml/acs`. People are linked to users under the `saml` provider of
+//! [`links`](super::links), and those without a link are created as students or instructors by
+//! the role their attributes map to.
+
+mod dsig;
+
+use std::{io::Write, sync::OnceLock, time::Duration};
+
+use anyhow::Context;
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::{write::DeflateEncoder, Compression};
+use fxhash::FxHashMap;
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    rngs::OsRng,
+};
+use roxmltree::Node;
+use sea_orm::{DbErr, TransactionTrait};
+use serde::Deserialize;
+use tracing::{error, info, warn};
+
+use super::{
+    links,
+    provision::{self, ProvisionedRole, RoleMapping},
+    user_auth,
+};
+use crate::{
+    cache,
+    client_ip::ClientIp,
+    db::get_db,
+    http::url_encode,
+    i18n,
+    routes::TrackedRouter,
+    security::{self, SecurityEvent},
+};
+
+/// The provider name of SAML logins in [`links`]
+pub const PROVIDER: &str = "saml";
+/// How long a browser has to come back from the identity provider
+const REQUEST_TTL: Duration = Duration::from_secs(600);
+
+const PROTOCOL_NS: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const ASSERTION_NS: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const BEARER: &str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+const POST_BINDING: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST";
+
+static SERVICE_PROVIDER: OnceLock<ServiceProvider> = OnceLock::new();
+
+/// The `[auth.saml]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SamlOptions {
+    /// The entity ID of this API, usually the url of `/auth/saml/metadata`
+    pub entity_id: String,
+    /// The url of `/auth/saml/acs` as browsers reach it
+    pub acs_url: String,
+    pub idp_entity_id: String,
+    /// Where `/auth/saml/login` sends browsers. Without it, logins can only start at the identity
+    /// provider
+    pub idp_sso_url: Option<String>,
+    /// The identity provider's signing certificate, in PEM or as the base64 in its metadata
+    pub idp_certificate: String,
+    #[serde(default = "default_name_id_format")]
+    pub name_id_format: String,
+    /// The attribute that identifies people, instead of the `NameID`
+    pub subject_attribute: Option<String>,
+    #[serde(default = "default_name_attribute")]
+    pub name_attribute: String,
+    /// Holds birthdates as `YYYYMMDD` or `YYYY-MM-DD`, such as `schacDateOfBirth`
+    pub birthdate_attribute: Option<String>,
+    /// Accepts responses that no `/auth/saml/login` asked for
+    #[serde(default)]
+    pub allow_idp_initiated: bool,
+    #[serde(default)]
+    pub roles: Vec<RoleMapping>,
+    /// Where the browser is sent after logging in, with the token and its expiry in the
+    /// fragment. Without it, `/auth/saml/acs` responds with the token as JSON
+    pub frontend_url: Option<String>,
+    #[serde(default = "default_clock_skew_secs")]
+    pub clock_skew_secs: i64,
+}
+
+fn default_name_id_format() -> String {
+    "urn:oasis:names:tc:SAML:2.0:nameid-format:persistent".to_string()
+}
+
+fn default_name_attribute() -> String {
+    "displayName".to_string()
+}
+
+fn default_clock_skew_secs() -> i64 {
+    180
+}
+
+struct ServiceProvider {
+    options: SamlOptions,
+    key: dsig::SigningKey,
+}
+
+pub(crate) fn has_provider(name: &str) -> bool {
+    name == PROVIDER && SERVICE_PROVIDER.get().is_some()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn request_key(id: &str) -> String {
+    format!("saml_request:{id}")
+}
+
+fn assertion_key(id: &str) -> String {
+    format!("saml_assertion:{id}")
+}
+
+fn service_provider() -> Result<&'static ServiceProvider, Response> {
+    SERVICE_PROVIDER
+        .get()
+        .ok_or_else(|| (StatusCode::NOT_FOUND, ()).into_response())
+}
+
+async fn metadata() -> Response {
+    let options = match service_provider() {
+        Ok(sp) => &sp.options,
+        Err(response) => return response,
+    };
+    let metadata = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<md:EntityDescriptor xmlns:md="urn:oasis:names:tc:SAML:2.0:metadata" entityID="{}">
+  <md:SPSSODescriptor AuthnRequestsSigned="false" WantAssertionsSigned="true" protocolSupportEnumeration="{PROTOCOL_NS}">
+    <md:NameIDFormat>{}</md:NameIDFormat>
+    <md:AssertionConsumerService Binding="{POST_BINDING}" Location="{}" index="0" isDefault="true"/>
+  </md:SPSSODescriptor>
+</md:EntityDescriptor>
+"#,
+        escape(&options.entity_id),
+        escape(&options.name_id_format),
+        escape(&options.acs_url),
+    );
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/samlmetadata+xml")],
+        metadata,
+    )
+        .into_response()
+}
+
+fn deflate(xml: &str) -> std::io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+    encoder.write_all(xml.as_bytes())?;
+    encoder.finish()
+}
+
+async fn login() -> Response {
+    let options = match service_provider() {
+        Ok(sp) => &sp.options,
+        Err(response) => return response,
+    };
+    let Some(sso_url) = &options.idp_sso_url else {
+        return (StatusCode::NOT_FOUND, ()).into_response();
+    };
+    // IDs may not start with a digit
+    let mut id = "_".to_string();
+    Alphanumeric.append_string(&mut OsRng, &mut id, 40);
+    let request = format!(
+        r#"<samlp:AuthnRequest xmlns:samlp="{PROTOCOL_NS}" xmlns:saml="{ASSERTION_NS}" ID="{id}" Version="2.0" IssueInstant="{}" Destination="{}" AssertionConsumerServiceURL="{}" ProtocolBinding="{POST_BINDING}"><saml:Issuer>{}</saml:Issuer><samlp:NameIDPolicy Format="{}" AllowCreate="true"/></samlp:AuthnRequest>"#,
+        chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        escape(sso_url),
+        escape(&options.acs_url),
+        escape(&options.entity_id),
+        escape(&options.name_id_format),
+    );
+    let request = match deflate(&request) {
+        Ok(request) => STANDARD.encode(request),
+        Err(e) => {
+            error!("Error encoding SAML request: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+    if let Err(e) = cache::set_json(&request_key(&id), &true, Some(REQUEST_TTL)).await {
+        error!("Error saving SAML request: {e:#}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+    }
+    let separator = if sso_url.contains('?') { '&' } else { '?' };
+    Redirect::to(&format!(
+        "{sso_url}{separator}SAMLRequest={}",
+        url_encode(&request)
+    ))
+    .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AcsForm {
+    #[serde(rename = "SAMLResponse")]
+    pub saml_response: String,
+}
+
+/// What a verified response says about who logged in.
+struct Login {
+    subject: String,
+    attributes: FxHashMap<String, Vec<String>>,
+    in_response_to: Option<String>,
+    assertion_id: String,
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Login {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .get(name)
+            .and_then(|values| values.first())
+            .map(String::as_str)
+    }
+}
+
+fn is_saml(node: &Node, namespace: &str, name: &str) -> bool {
+    node.is_element()
+        && node.tag_name().namespace() == Some(namespace)
+        && node.tag_name().name() == name
+}
+
+fn saml_child<'a, 'input>(
+    node: Node<'a, 'input>,
+    namespace: &str,
+    name: &str,
+) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|child| is_saml(child, namespace, name))
+}
+
+fn parse_time(
+    node: Node,
+    attribute: &str,
+) -> anyhow::Result<Option<chrono::DateTime<chrono::Utc>>> {
+    node.attribute(attribute)
+        .map(|time| {
+            chrono::DateTime::parse_from_rfc3339(time)
+                .map(|time| time.to_utc())
+                .with_context(|| format!("{attribute} is not a time: {time}"))
+        })
+        .transpose()
+}
+
+impl ServiceProvider {
+    /// Verifies a `SAMLResponse` and reads the assertion in it.
+    fn read_response(&self, encoded: &str) -> anyhow::Result<Login> {
+        let options = &self.options;
+        let encoded: String = encoded.chars().filter(|c| !c.is_whitespace()).collect();
+        let xml = String::from_utf8(STANDARD.decode(encoded)?)?;
+        // DTDs are refused, so entities cannot reach outside the document
+        let document = roxmltree::Document::parse(&xml)?;
+        let response = document.root_element();
+        if !is_saml(&response, PROTOCOL_NS, "Response") {
+            return Err(anyhow::anyhow!("The document is not a Response"));
+        }
+        if let Some(destination) = response.attribute("Destination") {
+            if destination != options.acs_url {
+                return Err(anyhow::anyhow!("The response is for {destination}"));
+            }
+        }
+        let status = saml_child(response, PROTOCOL_NS, "Status")
+            .and_then(|status| saml_child(status, PROTOCOL_NS, "StatusCode"))
+            .and_then(|code| code.attribute("Value"));
+        if status != Some(SUCCESS) {
+            return Err(anyhow::anyhow!("The response has status {status:?}"));
+        }
+        if response
+            .descendants()
+            .any(|node| is_saml(&node, ASSERTION_NS, "EncryptedAssertion"))
+        {
+            return Err(anyhow::anyhow!(
+                "Encrypted assertions are not supported. Have the identity provider sign them instead"
+            ));
+        }
+
+        // Exactly one assertion, directly in the response, so that a signed one cannot be
+        // wrapped around an unsigned one
+        let mut assertions = response
+            .descendants()
+            .filter(|node| is_saml(node, ASSERTION_NS, "Assertion"));
+        let assertion = assertions.next().context("The response has no assertion")?;
+        if assertions.next().is_some() || assertion.parent() != Some(response) {
+            return Err(anyhow::anyhow!("The response has more than one assertion"));
+        }
+        let response_signed = dsig::signature_of(response).is_some();
+        if response_signed {
+            dsig::verify(response, &self.key).context("Checking the response's signature")?;
+        }
+        if dsig::signature_of(assertion).is_some() {
+            dsig::verify(assertion, &self.key).context("Checking the assertion's signature")?;
+        } else if !response_signed {
+            return Err(anyhow::anyhow!(
+                "Neither the response nor the assertion is signed"
+            ));
+        }
+
+        let issuer = saml_child(assertion, ASSERTION_NS, "Issuer").map(dsig::text_content);
+        if issuer.as_deref().map(str::trim) != Some(options.idp_entity_id.as_str()) {
+            return Err(anyhow::anyhow!("The assertion is issued by {issuer:?}"));
+        }
+        let assertion_id = assertion
+            .attribute("ID")
+            .context("The assertion has no ID")?
+            .to_string();
+        let in_response_to = response.attribute("InResponseTo").map(str::to_string);
+
+        let now = chrono::Utc::now();
+        let skew = chrono::Duration::seconds(options.clock_skew_secs);
+        let conditions = saml_child(assertion, ASSERTION_NS, "Conditions")
+            .context("The assertion has no conditions")?;
+        if parse_time(conditions, "NotBefore")?.is_some_and(|not_before| now + skew < not_before) {
+            return Err(anyhow::anyhow!("The assertion is not valid yet"));
+        }
+        let conditions_expiry = parse_time(conditions, "NotOnOrAfter")?;
+        let audience_restrictions: Vec<_> = conditions
+            .children()
+            .filter(|node| is_saml(node, ASSERTION_NS, "AudienceRestriction"))
+            .collect();
+        if audience_restrictions.is_empty()
+            || !audience_restrictions.iter().all(|restriction| {
+                restriction
+                    .children()
+                    .filter(|node| is_saml(node, ASSERTION_NS, "Audience"))
+                    .any(|audience| dsig::text_content(audience).trim() == options.entity_id)
+            })
+        {
+            return Err(anyhow::anyhow!("The assertion is not meant for this API"));
+        }
+
+        let subject = saml_child(assertion, ASSERTION_NS, "Subject")
+            .context("The assertion has no subject")?;
+        let confirmation = subject
+            .children()
+            .filter(|node| is_saml(node, ASSERTION_NS, "SubjectConfirmation"))
+            .find(|node| node.attribute("Method") == Some(BEARER))
+            .and_then(|node| saml_child(node, ASSERTION_NS, "SubjectConfirmationData"))
+            .context("The assertion has no bearer confirmation")?;
+        if confirmation.attribute("Recipient") != Some(&options.acs_url) {
+            return Err(anyhow::anyhow!(
+                "The assertion is for {:?}",
+                confirmation.attribute("Recipient")
+            ));
+        }
+        if confirmation.attribute("InResponseTo") != in_response_to.as_deref() {
+            return Err(anyhow::anyhow!("The assertion answers another request"));
+        }
+        let confirmation_expiry = parse_time(confirmation, "NotOnOrAfter")?
+            .context("The bearer confirmation does not expire")?;
+        let expires_at = conditions_expiry.map_or(confirmation_expiry, |expires_at| {
+            expires_at.min(confirmation_expiry)
+        });
+        if now - skew >= expires_at {
+            return Err(anyhow::anyhow!("The assertion has expired"));
+        }
+
+        let mut attributes: FxHashMap<String, Vec<String>> = FxHashMap::default();
+        for attribute in assertion
+            .children()
+            .filter(|node| is_saml(node, ASSERTION_NS, "AttributeStatement"))
+            .flat_map(|statement| statement.children())
+            .filter(|node| is_saml(node, ASSERTION_NS, "Attribute"))
+        {
+            let values: Vec<_> = attribute
+                .children()
+                .filter(|node| is_saml(node, ASSERTION_NS, "AttributeValue"))
+                .map(|value| dsig::text_content(value).trim().to_string())
+                .collect();
+            for name in [
+                attribute.attribute("Name"),
+                attribute.attribute("FriendlyName"),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                attributes
+                    .entry(name.to_string())
+                    .or_default()
+                    .extend(values.iter().cloned());
+            }
+        }
+
+        let subject = match &options.subject_attribute {
+            Some(name) => attributes
+                .get(name)
+                .and_then(|values| values.first())
+                .cloned(),
+            None => saml_child(subject, ASSERTION_NS, "NameID")
+                .map(|name_id| dsig::text_content(name_id).trim().to_string()),
+        }
+        .filter(|subject| !subject.is_empty())
+        .context("The assertion does not identify anyone")?;
+
+        Ok(Login {
+            subject,
+            attributes,
+            in_response_to,
+            assertion_id,
+            expires_at,
+        })
+    }
+}
+
+/// Makes sure the response answers a request this API made and has not been used before.
+async fn is_fresh(options: &SamlOptions, login: &Login) -> anyhow::Result<bool> {
+    match &login.in_response_to {
+        Some(id) => {
+            let key = request_key(id);
+            let requested = cache::get_json::<bool>(&key).await?.is_some();
+            // Responses posted at once can all find the request, but only one counts first. The
+            // request then expires on its own
+            let uses = cache::get_cache()
+                .incr(&format!("{key}:uses"), Some(REQUEST_TTL))
+                .await?;
+            if !requested || uses != 1 {
+                return Ok(false);
+            }
+        }
+        None if options.allow_idp_initiated => {}
+        None => return Ok(false),
+    }
+    let ttl = (login.expires_at - chrono::Utc::now()
+        + chrono::Duration::seconds(options.clock_skew_secs))
+    .to_std()
+    .unwrap_or_default()
+    .max(Duration::from_secs(1));
+    let uses = cache::get_cache()
+        .incr(&assertion_key(&login.assertion_id), Some(ttl))
+        .await?;
+    Ok(uses == 1)
+}
+
+/// Creates a user for someone without a link.
+async fn provision(
+    options: &SamlOptions,
+    login: &Login,
+    role: ProvisionedRole,
+    conn: &impl sea_orm::ConnectionTrait,
+) -> Result<links::Model, DbErr> {
+    let (auth, _) = user_auth::new_rand(conn).await?;
+    let user_id = auth.user_id;
+    let name = login
+        .attribute(&options.name_attribute)
+        .unwrap_or(&login.subject)
+        .to_string();
+    let birthdate = provision::birthdate(
+        options
+            .birthdate_attribute
+            .as_deref()
+            .and_then(|attribute| login.attribute(attribute)),
+    );
+    provision::create(user_id, role, name, birthdate, conn).await?;
+    links::create(
+        PROVIDER.to_string(),
+        login.subject.clone(),
+        user_id,
+        user_id,
+        conn,
+    )
+    .await
+}
+
+async fn acs(client_ip: ClientIp, Form(form): Form<AcsForm>) -> Response {
+    let sp = match service_provider() {
+        Ok(sp) => sp,
+        Err(response) => return response,
+    };
+    let options = &sp.options;
+    let login = match sp.read_response(&form.saml_response) {
+        Ok(login) => login,
+        Err(e) => {
+            warn!("Refused SAML response from {client_ip}: {e:#}");
+            security::record(SecurityEvent::FailedLogin);
+            return (StatusCode::UNAUTHORIZED, i18n::t("error.saml_refused")).into_response();
+        }
+    };
+    match is_fresh(options, &login).await {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!(
+                "Unrequested or replayed SAML assertion {} from {client_ip}",
+                login.assertion_id
+            );
+            return (StatusCode::BAD_REQUEST, i18n::t("error.saml_expired")).into_response();
+        }
+        Err(e) => {
+            error!("Error reading SAML requests: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    }
+
+    let role = provision::role_for(&login.attributes, &options.roles);
+    let result = async {
+        let txn = get_db().begin().await?;
+        let link = match links::find(PROVIDER, &login.subject, &txn).await? {
+            Some(link) => link,
+            None => {
+                let Some(role) = role else {
+                    return Ok(None);
+                };
+                let link = provision(options, &login, role, &txn).await?;
+                info!(
+                    "Created {} as a {role:?} for SAML subject {}",
+                    link.user_id, login.subject
+                );
+                link
+            }
+        };
+        let (token, replaced) = links::log_in(&link, &txn).await?;
+        txn.commit().await?;
+        replaced.revoke();
+        Ok::<_, DbErr>(Some(token))
+    }
+    .await;
+    match result {
+        Ok(Some(token)) => {
+            info!("{} logged in through SAML from {client_ip}", token.user_id);
+            links::login_response(options.frontend_url.as_deref(), token)
+        }
+        Ok(None) => {
+            warn!(
+                "SAML login from {client_ip} for {} has no role",
+                login.subject
+            );
+            (StatusCode::FORBIDDEN, i18n::t("error.saml_no_role")).into_response()
+        }
+        Err(e) => {
+            error!("Error logging in SAML subject {}: {e:#}", login.subject);
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+pub(crate) fn init(options: Option<SamlOptions>) -> anyhow::Result<()> {
+    if let Some(options) = options {
+        let key = dsig::SigningKey::from_certificate(&options.idp_certificate)
+            .context("Reading auth.saml.idp_certificate")?;
+        let _ = SERVICE_PROVIDER.set(ServiceProvider { options, key });
+    }
+    Ok(())
+}
+
+pub(crate) fn add_routes<S: Clone + Send + Sync + 'static>(
+    router: TrackedRouter<S>,
+) -> TrackedRouter<S> {
+    router
+        .route("/auth/saml/metadata", get(metadata))
+        .route("/auth/saml/login", get(login))
+        .route("/auth/saml/acs", post(acs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACS_URL: &str = "https://api.test/auth/saml/acs";
+
+    fn service_provider() -> ServiceProvider {
+        let options: SamlOptions = toml::from_str(&format!(
+            r#"
+            entity_id = "https://api.test"
+            acs_url = "{ACS_URL}"
+            idp_entity_id = "https://idp.test"
+            idp_certificate = """{}"""
+            "#,
+            dsig::tests::CERTIFICATE
+        ))
+        .unwrap();
+        let key = dsig::SigningKey::from_certificate(&options.idp_certificate).unwrap();
+        ServiceProvider { options, key }
+    }
+
+    /// A response whose assertion identifies `name_id` and has a place for its signature.
+    fn response(name_id: &str) -> String {
+        let now = chrono::Utc::now();
+        let not_before = (now - chrono::Duration::minutes(1)).to_rfc3339();
+        let not_on_or_after = (now + chrono::Duration::minutes(5)).to_rfc3339();
+        format!(
+            r#"<samlp:Response xmlns:samlp="{PROTOCOL_NS}" xmlns:saml="{ASSERTION_NS}" ID="response" Destination="{ACS_URL}"><samlp:Status><samlp:StatusCode Value="{SUCCESS}"/></samlp:Status><saml:Assertion ID="assertion"><saml:Issuer>https://idp.test</saml:Issuer><!--Signature--><saml:Subject><saml:NameID>{name_id}</saml:NameID><saml:SubjectConfirmation Method="{BEARER}"><saml:SubjectConfirmationData Recipient="{ACS_URL}" NotOnOrAfter="{not_on_or_after}"/></saml:SubjectConfirmation></saml:Subject><saml:Conditions NotBefore="{not_before}" NotOnOrAfter="{not_on_or_after}"><saml:AudienceRestriction><saml:Audience>https://api.test</saml:Audience></saml:AudienceRestriction></saml:Conditions></saml:Assertion></samlp:Response>"#
+        )
+    }
+
+    fn read(xml: &str) -> anyhow::Result<Login> {
+        service_provider().read_response(&STANDARD.encode(xml))
+    }
+
+    #[test]
+    fn signed_assertion_is_read() {
+        let login = read(&dsig::tests::sign(&response("alice@uni.edu"), "assertion")).unwrap();
+        assert_eq!(login.subject, "alice@uni.edu");
+        assert_eq!(login.assertion_id, "assertion");
+    }
+
+    #[test]
+    fn unsigned_assertion_is_refused() {
+        assert!(read(&response("alice@uni.edu")).is_err());
+    }
+
+    #[test]
+    fn changed_name_id_is_refused() {
+        let signed = dsig::tests::sign(&response("alice@uni.edu"), "assertion");
+        assert!(read(&signed.replace("alice@uni.edu", "admin@uni.edu")).is_err());
+    }
+
+    #[test]
+    fn comment_does_not_cut_name_id_short() {
+        // The comment is not covered by the signature, so the signature is still valid, but the
+        // subject must be everything that was signed
+        let signed = dsig::tests::sign(&response("victim@uni.edu.evil.com"), "assertion")
+            .replace("victim@uni.edu.evil.com", "victim@uni.edu<!---->.evil.com");
+        let login = read(&signed).unwrap();
+        assert_eq!(login.subject, "victim@uni.edu.evil.com");
+    }
+
+    fn login(in_response_to: Option<&str>, assertion_id: &str) -> Login {
+        Login {
+            subject: "alice@uni.edu".to_string(),
+            attributes: FxHashMap::default(),
+            in_response_to: in_response_to.map(str::to_string),
+            assertion_id: assertion_id.to_string(),
+            expires_at: chrono::Utc::now() + chrono::Duration::minutes(5),
+        }
+    }
+
+    async fn fresh_at_once(options: &SamlOptions, login: &Login) -> usize {
+        let checks = (0..8).map(|_| is_fresh(options, login));
+        futures::future::join_all(checks)
+            .await
+            .into_iter()
+            .filter(|fresh| *fresh.as_ref().unwrap())
+            .count()
+    }
+
+    #[tokio::test]
+    async fn responses_are_used_once() {
+        let mut options = service_provider().options;
+        cache::set_json(&request_key("request"), &true, Some(REQUEST_TTL))
+            .await
+            .unwrap();
+        let answer = login(Some("request"), "answer");
+        assert_eq!(fresh_at_once(&options, &answer).await, 1);
+        assert!(!is_fresh(&options, &login(Some("request"), "again"))
+            .await
+            .unwrap());
+        assert!(!is_fresh(&options, &login(Some("unknown"), "other"))
+            .await
+            .unwrap());
+
+        assert!(!is_fresh(&options, &login(None, "unrequested"))
+            .await
+            .unwrap());
+        options.allow_idp_initiated = true;
+        assert_eq!(fresh_at_once(&options, &login(None, "pushed")).await, 1);
+    }
+}
@@ -0,0 +1,505 @@
+//! Checks the enveloped XML signatures that identity providers put on SAML responses and
+//! assertions.
+//!
+//! Only what identity providers use in practice is supported: exclusive canonicalization without
+//! comments, RSA with SHA-256, and one reference to the signed element.
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use roxmltree::{Node, NodeType};
+
+pub(crate) const NS: &str = "http://www.w3.org/2000/09/xmldsig#";
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const SHA256: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+/// rsaEncryption, 1.2.840.113549.1.1.1
+const RSA_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+
+/// The RSA key of an identity provider's signing certificate.
+pub(crate) struct SigningKey {
+    /// `RSAPublicKey` in DER
+    public_key: Vec<u8>,
+}
+
+/// Splits the DER element at the start of `input` into its tag, its contents and the rest.
+fn read_der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let len = if first < 0x80 {
+        usize::from(first)
+    } else {
+        let count = usize::from(first & 0x7f);
+        if count == 0 || count > 4 || input.len() < count {
+            return None;
+        }
+        let (bytes, rest) = input.split_at(count);
+        input = rest;
+        bytes
+            .iter()
+            .fold(0usize, |len, &byte| (len << 8) | usize::from(byte))
+    };
+    if input.len() < len {
+        return None;
+    }
+    let (contents, rest) = input.split_at(len);
+    Some((tag, contents, rest))
+}
+
+impl SigningKey {
+    /// Reads the key out of an X.509 certificate, in PEM or as the bare base64 that metadata
+    /// files hold.
+    pub(crate) fn from_certificate(text: &str) -> anyhow::Result<Self> {
+        let base64: String = text
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .flat_map(|line| line.chars())
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        let der = STANDARD
+            .decode(base64)
+            .context("The certificate is not base64")?;
+        Self::from_der(&der).context("The certificate does not hold an RSA key")
+    }
+
+    fn from_der(der: &[u8]) -> Option<Self> {
+        const SEQUENCE: u8 = 0x30;
+        let (SEQUENCE, certificate, _) = read_der(der)? else {
+            return None;
+        };
+        let (SEQUENCE, mut tbs, _) = read_der(certificate)? else {
+            return None;
+        };
+        // The explicitly tagged version is optional
+        if tbs.first() == Some(&0xa0) {
+            tbs = read_der(tbs)?.2;
+        }
+        // The serial number, signature algorithm, issuer, validity and subject come before the key
+        for _ in 0..5 {
+            tbs = read_der(tbs)?.2;
+        }
+        let (SEQUENCE, key_info, _) = read_der(tbs)? else {
+            return None;
+        };
+        let (SEQUENCE, algorithm, rest) = read_der(key_info)? else {
+            return None;
+        };
+        let (0x06, oid, _) = read_der(algorithm)? else {
+            return None;
+        };
+        if oid != RSA_OID {
+            return None;
+        }
+        let (0x03, bits, _) = read_der(rest)? else {
+            return None;
+        };
+        let (0, public_key) = bits.split_first()? else {
+            return None;
+        };
+        Some(Self {
+            public_key: public_key.to_vec(),
+        })
+    }
+}
+
+fn is_dsig(node: Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().namespace() == Some(NS) && node.tag_name().name() == name
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> anyhow::Result<Node<'a, 'input>> {
+    node.children()
+        .find(|child| is_dsig(*child, name))
+        .with_context(|| format!("The signature has no {name}"))
+}
+
+/// Every text node under `node` joined together, which is the text a signature covers. Comments are
+/// left out of the canonical form, so reading only the first text node would let a comment hide
+/// signed text, such as in `<NameID>victim@uni.edu<!---->.evil.com</NameID>`.
+pub(crate) fn text_content(node: Node) -> String {
+    node.descendants()
+        .filter(|node| node.is_text())
+        .filter_map(|node| node.text())
+        .collect()
+}
+
+fn decode_base64(node: Node) -> anyhow::Result<Vec<u8>> {
+    let text: String = text_content(node)
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    Ok(STANDARD.decode(text)?)
+}
+
+/// The prefixes that an exclusive canonicalization method treats inclusively.
+fn inclusive_prefixes<'a>(method: Node<'a, '_>) -> Vec<&'a str> {
+    method
+        .children()
+        .find(|child| child.is_element() && child.tag_name().name() == "InclusiveNamespaces")
+        .and_then(|child| child.attribute("PrefixList"))
+        .map(|list| list.split_whitespace().collect())
+        .unwrap_or_default()
+}
+
+/// The `ds:Signature` that is a direct child of `element`, if there is one.
+pub(crate) fn signature_of<'a, 'input>(element: Node<'a, 'input>) -> Option<Node<'a, 'input>> {
+    element
+        .children()
+        .find(|child| is_dsig(*child, "Signature"))
+}
+
+/// Checks that `element` is signed by `key` with an enveloped signature that covers all of it.
+pub(crate) fn verify(element: Node, key: &SigningKey) -> anyhow::Result<()> {
+    let mut signatures = element
+        .children()
+        .filter(|child| is_dsig(*child, "Signature"));
+    let signature = signatures.next().context("The element is not signed")?;
+    if signatures.next().is_some() {
+        return Err(anyhow::anyhow!("The element has more than one signature"));
+    }
+    let id = element
+        .attribute("ID")
+        .context("The signed element has no ID")?;
+
+    let signed_info = child(signature, "SignedInfo")?;
+    let method = child(signed_info, "CanonicalizationMethod")?;
+    if method.attribute("Algorithm") != Some(EXC_C14N) {
+        return Err(anyhow::anyhow!(
+            "Unsupported canonicalization {:?}",
+            method.attribute("Algorithm")
+        ));
+    }
+    let signed_info_prefixes = inclusive_prefixes(method);
+    let algorithm = child(signed_info, "SignatureMethod")?.attribute("Algorithm");
+    if algorithm != Some(RSA_SHA256) {
+        return Err(anyhow::anyhow!(
+            "Unsupported signature method {algorithm:?}"
+        ));
+    }
+
+    let mut references = signed_info
+        .children()
+        .filter(|child| is_dsig(*child, "Reference"));
+    let reference = references
+        .next()
+        .context("The signature has no Reference")?;
+    if references.next().is_some() {
+        return Err(anyhow::anyhow!("The signature has more than one Reference"));
+    }
+    if reference.attribute("URI") != Some(&format!("#{id}")) {
+        return Err(anyhow::anyhow!(
+            "The signature references {:?}, not the element",
+            reference.attribute("URI")
+        ));
+    }
+    let mut enveloped = false;
+    let mut prefixes = vec![];
+    for transform in child(reference, "Transforms")?
+        .children()
+        .filter(|child| is_dsig(*child, "Transform"))
+    {
+        match transform.attribute("Algorithm") {
+            Some(ENVELOPED) => enveloped = true,
+            Some(EXC_C14N) => prefixes = inclusive_prefixes(transform),
+            algorithm => return Err(anyhow::anyhow!("Unsupported transform {algorithm:?}")),
+        }
+    }
+    if !enveloped {
+        return Err(anyhow::anyhow!("The signature is not enveloped"));
+    }
+    let algorithm = child(reference, "DigestMethod")?.attribute("Algorithm");
+    if algorithm != Some(SHA256) {
+        return Err(anyhow::anyhow!("Unsupported digest method {algorithm:?}"));
+    }
+
+    let digest = ring::digest::digest(
+        &ring::digest::SHA256,
+        canonicalize(element, Some(signature), &prefixes).as_bytes(),
+    );
+    if digest.as_ref() != decode_base64(child(reference, "DigestValue")?)? {
+        return Err(anyhow::anyhow!("The digest does not match the element"));
+    }
+    ring::signature::UnparsedPublicKey::new(
+        &ring::signature::RSA_PKCS1_2048_8192_SHA256,
+        &key.public_key,
+    )
+    .verify(
+        canonicalize(signed_info, None, &signed_info_prefixes).as_bytes(),
+        &decode_base64(child(signature, "SignatureValue")?)?,
+    )
+    .map_err(|_| anyhow::anyhow!("The signature is not from the identity provider"))
+}
+
+/// The qualified name of an element as written, which roxmltree does not keep apart from the
+/// input.
+fn qname<'input>(node: Node<'_, 'input>) -> &'input str {
+    let text = &node.document().input_text()[node.range()];
+    let end = text
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .unwrap_or(text.len());
+    &text[1..end]
+}
+
+fn prefix_of(qname: &str) -> &str {
+    qname.split_once(':').map_or("", |(prefix, _)| prefix)
+}
+
+fn escape_text(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn escape_attribute(value: &str, out: &mut String) {
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '"' => out.push_str("&quot;"),
+            '\t' => out.push_str("&#x9;"),
+            '\n' => out.push_str("&#xA;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Exclusive XML canonicalization without comments of `node`, leaving out `exclude`.
+fn canonicalize<'a>(node: Node<'a, '_>, exclude: Option<Node>, inclusive: &[&'a str]) -> String {
+    let mut out = String::new();
+    write_element(node, exclude, inclusive, &mut vec![], &mut out);
+    out
+}
+
+fn write_element<'a>(
+    node: Node<'a, '_>,
+    exclude: Option<Node>,
+    inclusive: &[&'a str],
+    rendered: &mut Vec<(&'a str, &'a str)>,
+    out: &mut String,
+) {
+    let input = node.document().input_text();
+    let name = qname(node);
+
+    // Only the namespaces that the element and its attributes use are rendered, unless the
+    // inclusive prefix list names them
+    let mut prefixes = vec![prefix_of(name)];
+    for attribute in node.attributes() {
+        let prefix = prefix_of(&input[attribute.range_qname()]);
+        if !prefix.is_empty() {
+            prefixes.push(prefix);
+        }
+    }
+    for &prefix in inclusive {
+        let prefix = if prefix == "#default" { "" } else { prefix };
+        prefixes.push(prefix);
+    }
+    prefixes.retain(|prefix| *prefix != "xml");
+    prefixes.sort_unstable();
+    prefixes.dedup();
+
+    let rendered_len = rendered.len();
+    out.push('<');
+    out.push_str(name);
+    for prefix in prefixes {
+        let uri = node
+            .namespaces()
+            .find(|namespace| namespace.name().unwrap_or_default() == prefix)
+            .map_or("", |namespace| namespace.uri());
+        let in_output = rendered
+            .iter()
+            .rev()
+            .find(|(rendered_prefix, _)| *rendered_prefix == prefix)
+            .map_or("", |(_, uri)| *uri);
+        if uri == in_output {
+            continue;
+        }
+        // Unused prefixes that are not in scope are not declared
+        if uri.is_empty() && !prefix.is_empty() {
+            continue;
+        }
+        if prefix.is_empty() {
+            out.push_str(" xmlns=\"");
+        } else {
+            out.push_str(" xmlns:");
+            out.push_str(prefix);
+            out.push_str("=\"");
+        }
+        escape_attribute(uri, out);
+        out.push('"');
+        rendered.push((prefix, uri));
+    }
+
+    let mut attributes: Vec<_> = node.attributes().collect();
+    attributes.sort_unstable_by_key(|attribute| {
+        (attribute.namespace().unwrap_or_default(), attribute.name())
+    });
+    for attribute in attributes {
+        out.push(' ');
+        out.push_str(&input[attribute.range_qname()]);
+        out.push_str("=\"");
+        escape_attribute(attribute.value(), out);
+        out.push('"');
+    }
+    out.push('>');
+
+    for child in node.children() {
+        if exclude.is_some_and(|exclude| exclude == child) {
+            continue;
+        }
+        match child.node_type() {
+            NodeType::Element => write_element(child, exclude, inclusive, rendered, out),
+            NodeType::Text => escape_text(child.text().unwrap_or_default(), out),
+            NodeType::PI => {
+                if let Some(pi) = child.pi() {
+                    out.push_str("<?");
+                    out.push_str(pi.target);
+                    if let Some(value) = pi.value {
+                        out.push(' ');
+                        out.push_str(value);
+                    }
+                    out.push_str("?>");
+                }
+            }
+            NodeType::Comment | NodeType::Root => {}
+        }
+    }
+
+    out.push_str("</");
+    out.push_str(name);
+    out.push('>');
+    rendered.truncate(rendered_len);
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use ring::{rand::SystemRandom, signature::RsaKeyPair};
+
+    use super::*;
+
+    /// The certificate of the key that [`sign`] signs with
+    pub(in crate::auth::saml) const CERTIFICATE: &str = include_str!("testdata/idp.crt");
+
+    /// Signs the element of `xml` with `ID="{id}"` the way identity providers do, putting the
+    /// signature where `<!--Signature-->` is.
+    pub(in crate::auth::saml) fn sign(xml: &str, id: &str) -> String {
+        let document = roxmltree::Document::parse(xml).unwrap();
+        let element = document
+            .descendants()
+            .find(|node| node.attribute("ID") == Some(id))
+            .unwrap();
+        // The marker is a comment, so it is not part of what is digested
+        let digest = ring::digest::digest(
+            &ring::digest::SHA256,
+            canonicalize(element, None, &[]).as_bytes(),
+        );
+        let signed_info = format!(
+            r##"<ds:SignedInfo xmlns:ds="{NS}"><ds:CanonicalizationMethod Algorithm="{EXC_C14N}"/><ds:SignatureMethod Algorithm="{RSA_SHA256}"/><ds:Reference URI="#{id}"><ds:Transforms><ds:Transform Algorithm="{ENVELOPED}"/><ds:Transform Algorithm="{EXC_C14N}"/></ds:Transforms><ds:DigestMethod Algorithm="{SHA256}"/><ds:DigestValue>{}</ds:DigestValue></ds:Reference></ds:SignedInfo>"##,
+            STANDARD.encode(digest)
+        );
+        let signed_info_document = roxmltree::Document::parse(&signed_info).unwrap();
+        let key = RsaKeyPair::from_pkcs8(include_bytes!("testdata/idp.pk8")).unwrap();
+        let mut signature = vec![0; key.public().modulus_len()];
+        key.sign(
+            &ring::signature::RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            canonicalize(signed_info_document.root_element(), None, &[]).as_bytes(),
+            &mut signature,
+        )
+        .unwrap();
+        xml.replacen(
+            "<!--Signature-->",
+            &format!(
+                r#"<ds:Signature xmlns:ds="{NS}">{signed_info}<ds:SignatureValue>{}</ds:SignatureValue></ds:Signature>"#,
+                STANDARD.encode(signature)
+            ),
+            1,
+        )
+    }
+
+    fn verify_signed(xml: &str, id: &str) -> anyhow::Result<()> {
+        let document = roxmltree::Document::parse(xml).unwrap();
+        let element = document
+            .descendants()
+            .find(|node| node.attribute("ID") == Some(id))
+            .unwrap();
+        verify(element, &SigningKey::from_certificate(CERTIFICATE).unwrap())
+    }
+
+    #[test]
+    fn canonicalization_drops_unused_namespaces_and_comments() {
+        let document = roxmltree::Document::parse(
+            r#"<a:root xmlns:a="urn:a" xmlns:b="urn:b" a:y="2" z="1"><a:child/><!--note--><b:other b:x="&amp;"/></a:root>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            canonicalize(document.root_element(), None, &[]),
+            r#"<a:root xmlns:a="urn:a" z="1" a:y="2"><a:child></a:child><b:other xmlns:b="urn:b" b:x="&amp;"></b:other></a:root>"#
+        );
+    }
+
+    #[test]
+    fn canonicalization_keeps_inclusive_prefixes() {
+        let document = roxmltree::Document::parse(
+            r#"<root xmlns:b="urn:b"><child>1 &lt; 2 &gt; 0</child></root>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            canonicalize(document.root_element(), None, &["b"]),
+            r#"<root xmlns:b="urn:b"><child>1 &lt; 2 &gt; 0</child></root>"#
+        );
+    }
+
+    #[test]
+    fn signed_element_is_verified() {
+        let xml = sign(
+            r#"<root ID="signed"><!--Signature--><value>1</value></root>"#,
+            "signed",
+        );
+        verify_signed(&xml, "signed").unwrap();
+    }
+
+    #[test]
+    fn changed_signature_value_is_refused() {
+        let xml = sign(
+            r#"<root ID="signed"><!--Signature--><value>1</value></root>"#,
+            "signed",
+        );
+        let start = xml.find("<ds:SignatureValue>").unwrap() + "<ds:SignatureValue>".len();
+        let mut xml = xml.into_bytes();
+        xml[start] = if xml[start] == b'A' { b'B' } else { b'A' };
+        let xml = String::from_utf8(xml).unwrap();
+        let error = verify_signed(&xml, "signed").unwrap_err();
+        assert!(error.to_string().contains("not from the identity provider"));
+    }
+
+    #[test]
+    fn signature_of_another_element_is_refused() {
+        // A signature moved from the element it covers to one with a different ID
+        let xml = sign(
+            r#"<root><a ID="signed"><!--Signature--></a><b ID="other"/></root>"#,
+            "signed",
+        );
+        let signature = &xml[xml.find("<ds:Signature ").unwrap()..xml.find("</a>").unwrap()];
+        let moved = xml.replace(signature, "").replace(
+            r#"<b ID="other"/>"#,
+            &format!(r#"<b ID="other">{signature}</b>"#),
+        );
+        let error = verify_signed(&moved, "other").unwrap_err();
+        assert!(error.to_string().contains("not the element"));
+    }
+
+    #[test]
+    fn text_content_joins_text_around_comments() {
+        let document = roxmltree::Document::parse("<a>victim@uni.edu<!---->.evil.com</a>").unwrap();
+        assert_eq!(
+            text_content(document.root_element()),
+            "victim@uni.edu.evil.com"
+        );
+    }
+}
@@ -1,34 +1,148 @@
+use std::sync::OnceLock;
+
 use anyhow::Context;
-use crossbeam::atomic::AtomicCell;
+use axum::http::{Extensions, HeaderMap, Method};
+use axum_extra::headers::{authorization::Bearer, Authorization, HeaderMapExt};
 use rand::{
     distributions::{Alphanumeric, DistString},
     rngs::OsRng,
 };
 use sea_orm::{entity::prelude::*, ActiveValue};
+use serde::{Deserialize, Serialize};
 
-use crate::db::get_db;
+use crate::{
+    access_log,
+    audit::{self, AuditEvent},
+    db::{get_db, SoftDeletable},
+    security::{self, SecurityEvent},
+    users::{admins, instructors, students},
+};
 
 use super::UserID;
 
-static VALIDITY_DURATION: AtomicCell<std::time::Duration> =
-    AtomicCell::new(std::time::Duration::from_days(3));
+pub mod jwt;
+pub mod local_cache;
+
+const MAX_SCOPES: usize = 16;
+const MAX_SCOPE_LEN: usize = 64;
+// Ten years
+const MAX_VALIDITY_HOURS: u64 = 10 * 365 * 24;
+
+/// Lets a scoped token make GET and HEAD requests to any route that accepts tokens.
+pub const READ_SCOPE: &str = "read";
+/// Routes that require this accept every scoped token, such as `/auth/logout`.
+pub const ANY_SCOPE: &str = "*";
+
+static VALIDITY: OnceLock<TokenValidity> = OnceLock::new();
 
+/// The `[auth.token_validity]` section of `teach-config.toml`. Tokens expire after going unused
+/// for this long, or this long after they were issued for JWTs.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenValidity {
+    /// For users without a role, and roles without their own
+    #[serde(default = "default_hours")]
+    pub hours: u64,
+    pub student_hours: Option<u64>,
+    pub instructor_hours: Option<u64>,
+    pub admin_hours: Option<u64>,
+}
+
+impl Default for TokenValidity {
+    fn default() -> Self {
+        Self {
+            hours: default_hours(),
+            student_hours: None,
+            instructor_hours: None,
+            admin_hours: None,
+        }
+    }
+}
+
+fn default_hours() -> u64 {
+    72
+}
+
+impl TokenValidity {
+    fn hours_for(&self, role: Option<Role>) -> u64 {
+        let hours = match role {
+            Some(Role::Student) => self.student_hours,
+            Some(Role::Instructor) => self.instructor_hours,
+            Some(Role::Admin) => self.admin_hours,
+            None => None,
+        };
+        hours.unwrap_or(self.hours)
+    }
+}
+
+/// The roles a token can be issued for, which quotas are also set by. Users with several get the
+/// lifetime of the last one they have, in the order here.
+#[derive(EnumIter, DeriveActiveEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[sea_orm(rs_type = "i32", db_type = "Integer")]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Student = 0,
+    Instructor = 1,
+    Admin = 2,
+}
+
+/// How long tokens of users without a role last.
 pub fn get_token_validity_duration() -> chrono::Duration {
-    chrono::Duration::from_std(VALIDITY_DURATION.load()).unwrap()
+    get_validity_duration(None)
 }
 
 pub fn get_token_validity_duration_std() -> std::time::Duration {
-    VALIDITY_DURATION.load()
+    get_token_validity_duration().to_std().unwrap()
+}
+
+/// How long tokens issued for `role` last.
+pub fn get_validity_duration(role: Option<Role>) -> chrono::Duration {
+    let hours = VALIDITY.get_or_init(TokenValidity::default).hours_for(role);
+    chrono::Duration::hours(hours as i64)
+}
+
+/// The roles of `user_id`, least privileged first.
+pub(crate) async fn roles_of(
+    user_id: UserID,
+    db: &impl ConnectionTrait,
+) -> Result<Vec<Role>, DbErr> {
+    let mut roles = vec![];
+    if students::Entity::find_live_by_id(user_id)
+        .one(db)
+        .await?
+        .is_some()
+    {
+        roles.push(Role::Student);
+    }
+    if instructors::Entity::find_live_by_id(user_id)
+        .one(db)
+        .await?
+        .is_some()
+    {
+        roles.push(Role::Instructor);
+    }
+    if admins::Entity::find_live_by_id(user_id)
+        .one(db)
+        .await?
+        .is_some()
+    {
+        roles.push(Role::Admin);
+    }
+    Ok(roles)
 }
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "user_auth_tokens")]
 pub struct Model {
-    #[sea_orm(unique)]
+    #[sea_orm(indexed)]
     pub user_id: UserID,
     #[sea_orm(primary_key, auto_increment = false)]
     pub token: String,
     pub last_used: DateTime,
+    /// Separated by spaces. Tokens without scopes can be used for anything, and users have one
+    /// of those at a time
+    pub scopes: Option<String>,
+    /// The role the token was issued for, which decides how long it lasts
+    pub role: Option<Role>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -36,24 +150,83 @@ pub enum Relation {}
 
 impl ActiveModelBehavior for ActiveModel {}
 
-impl Model {
-    pub async fn gen_new(user_id: UserID, db: &impl ConnectionTrait) -> Result<ActiveModel, DbErr> {
-        if let Some(model) = Entity::find()
-            .filter(Column::UserId.eq(user_id))
-            .one(db)
-            .await?
-        {
-            model.delete(db).await?;
+/// The unscoped token a new one replaced. Its row is deleted along with the rest of the caller's
+/// transaction, but caches still accept it until [`Self::revoke`] is called after the commit.
+#[must_use]
+pub struct Replaced(Option<String>);
+
+impl Replaced {
+    pub fn revoke(self) {
+        if let Some(token) = self.0 {
+            jwt::forget(&token);
+            local_cache::invalidate(&token);
         }
+    }
+}
+
+impl Model {
+    /// A token that can be used for anything, replacing the previous one of the user.
+    pub async fn gen_new(
+        user_id: UserID,
+        db: &impl ConnectionTrait,
+    ) -> Result<(ActiveModel, Replaced), DbErr> {
+        Self::gen_scoped(user_id, None, db).await
+    }
+
+    /// A token limited to `scopes`, which users can have any number of, or one like
+    /// [`Self::gen_new`] without scopes.
+    pub async fn gen_scoped(
+        user_id: UserID,
+        scopes: Option<&[String]>,
+        db: &impl ConnectionTrait,
+    ) -> Result<(ActiveModel, Replaced), DbErr> {
+        let previous = if scopes.is_none() {
+            Entity::find()
+                .filter(Column::UserId.eq(user_id))
+                .filter(Column::Scopes.is_null())
+                .one(db)
+                .await?
+        } else {
+            None
+        };
+        let replaced = match previous {
+            Some(model) => {
+                let token = model.token.clone();
+                model.delete(db).await?;
+                jwt::store_revoked(&token, db).await?;
+                Replaced(Some(token))
+            }
+            None => Replaced(None),
+        };
 
-        let mut token = String::new();
-        Alphanumeric.append_string(&mut OsRng, &mut token, 32);
+        let scopes = scopes.map(|scopes| scopes.join(" "));
+        let roles = roles_of(user_id, db).await?;
+        let role = roles.last().copied();
+        let now = chrono::Utc::now().naive_utc();
+        let token = if jwt::is_enabled() {
+            let expires_at = now + get_validity_duration(role);
+            jwt::sign(user_id, roles, scopes.clone(), now, expires_at)
+        } else {
+            let mut token = String::new();
+            Alphanumeric.append_string(&mut OsRng, &mut token, 32);
+            token
+        };
 
-        Ok(ActiveModel {
+        let mut event = AuditEvent::new(audit::TOKEN_ISSUED, Some(user_id));
+        if let Some(scopes) = &scopes {
+            event = event.detail(scopes);
+        }
+        audit::record(db, event).await?;
+
+        // JWTs are stored too, so that users still have one unscoped token at a time
+        let model = ActiveModel {
             user_id: ActiveValue::set(user_id),
             token: ActiveValue::set(token),
-            last_used: ActiveValue::set(chrono::Utc::now().naive_utc()),
-        })
+            last_used: ActiveValue::set(now),
+            scopes: ActiveValue::set(scopes),
+            role: ActiveValue::set(role),
+        };
+        Ok((model, replaced))
     }
 
     pub async fn update_last_used(self, db: &impl ConnectionTrait) -> Result<(), DbErr> {
@@ -61,6 +234,8 @@ impl Model {
             user_id: ActiveValue::not_set(),
             token: ActiveValue::unchanged(self.token),
             last_used: ActiveValue::set(chrono::Utc::now().naive_utc()),
+            scopes: ActiveValue::not_set(),
+            role: ActiveValue::not_set(),
         }
         .update(db)
         .await
@@ -68,29 +243,178 @@ impl Model {
     }
 }
 
+/// Reads scopes separated by spaces, as given to `/auth/login` and `/auth/tokens`. Scopes are made
+/// of letters, digits, `_`, `-`, `.` and `:`.
+pub fn parse_scopes(scope: &str) -> Option<Vec<String>> {
+    let mut scopes: Vec<String> = vec![];
+    for scope in scope.split_whitespace() {
+        let valid = scope.len() <= MAX_SCOPE_LEN
+            && scope
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | ':'));
+        if !valid {
+            return None;
+        }
+        if !scopes.iter().any(|existing| existing == scope) {
+            scopes.push(scope.to_string());
+        }
+    }
+    if scopes.is_empty() || scopes.len() > MAX_SCOPES {
+        return None;
+    }
+    Some(scopes)
+}
+
+/// Who a valid token belongs to, and what it can be used for.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidToken {
+    pub user_id: UserID,
+    /// `None` for tokens that can be used for anything
+    pub scopes: Option<Vec<String>>,
+}
+
+impl ValidToken {
+    fn new(user_id: UserID, scopes: Option<&str>) -> Self {
+        Self {
+            user_id,
+            scopes: scopes.map(|scopes| scopes.split(' ').map(str::to_string).collect()),
+        }
+    }
+
+    /// Whether the token can make a `method` request to a route that requires `required`, or
+    /// that requires no scope if `None`.
+    pub fn allows(&self, required: Option<&str>, method: &Method) -> bool {
+        let Some(scopes) = &self.scopes else {
+            return true;
+        };
+        let has = |scope: &str| scopes.iter().any(|existing| existing == scope);
+        required.is_some_and(|required| required == ANY_SCOPE || has(required))
+            || (has(READ_SCOPE) && (method == Method::GET || method == Method::HEAD))
+    }
+}
+
+/// Deletes a token so that it can no longer be used, returning who it belonged to.
+pub async fn revoke_token(token: &str) -> Result<Option<UserID>, DbErr> {
+    let Some(model) = Entity::find_by_id(token).one(get_db()).await? else {
+        // A JWT can outlive its row, such as after the database is restored from a backup
+        jwt::revoke(token, get_db()).await?;
+        return Ok(None);
+    };
+    let user_id = model.user_id;
+    model.delete(get_db()).await?;
+    jwt::revoke(token, get_db()).await?;
+    local_cache::invalidate(token);
+    Ok(Some(user_id))
+}
+
+/// The user `token` belongs to, if it can be used for anything. Scoped tokens are refused, since
+/// callers do not say which scope they need; use [`crate::auth::AuthUser`] to accept those.
 pub async fn validate_token(token: &str) -> anyhow::Result<Option<UserID>> {
+    Ok(validate(token)
+        .await?
+        .filter(|valid| valid.scopes.is_none())
+        .map(|valid| valid.user_id))
+}
+
+/// The user `token` belongs to and its scopes. JWTs are checked without the database, and expire
+/// a fixed time after they were issued rather than after they were last used.
+pub async fn validate(token: &str) -> anyhow::Result<Option<ValidToken>> {
+    if jwt::is_enabled() && jwt::is_jwt(token) {
+        let Some(claims) = jwt::validate(token) else {
+            security::record(SecurityEvent::InvalidToken);
+            return Ok(None);
+        };
+        let Some(user_id) = claims.user_id() else {
+            security::record(SecurityEvent::InvalidToken);
+            return Ok(None);
+        };
+        access_log::record_user(user_id);
+        return Ok(Some(ValidToken::new(user_id, claims.scope.as_deref())));
+    }
+
+    if let Some(valid) = local_cache::get(token) {
+        access_log::record_user(valid.user_id);
+        return Ok(Some(valid));
+    }
+
     let Some(model) = Entity::find_by_id(token).one(get_db()).await? else {
+        security::record(SecurityEvent::InvalidToken);
         return Ok(None);
     };
 
     let now = chrono::Utc::now().naive_utc();
     let elapsed = now - model.last_used;
-    if elapsed > get_token_validity_duration() {
+    if elapsed > get_validity_duration(model.role) {
         let user_id = model.user_id;
         model
             .delete(get_db())
             .await
             .with_context(|| format!("Deleting expired token for {user_id}"))?;
+        local_cache::invalidate(token);
+        security::record(SecurityEvent::InvalidToken);
         return Ok(None);
     }
     ActiveModel {
-        user_id: ActiveValue::unchanged(model.user_id),
-        token: ActiveValue::not_set(),
+        user_id: ActiveValue::not_set(),
+        token: ActiveValue::unchanged(model.token.clone()),
         last_used: ActiveValue::set(now),
+        scopes: ActiveValue::not_set(),
+        role: ActiveValue::not_set(),
     }
     .update(get_db())
     .await
     .with_context(|| format!("Updating token for {}", model.user_id))?;
 
-    Ok(Some(model.user_id))
+    let valid = ValidToken::new(model.user_id, model.scopes.as_deref());
+    local_cache::insert(token, valid.clone());
+    access_log::record_user(model.user_id);
+    Ok(Some(valid))
+}
+
+/// The outcome of validating a request's bearer token, kept in its extensions.
+#[derive(Clone)]
+struct Validated {
+    token: String,
+    valid: Option<ValidToken>,
+}
+
+/// Like [`validate`] for the bearer token of a request, which is only validated once however many
+/// middleware and extractors ask, since validating touches the token. `None` without a token.
+pub(crate) async fn validate_bearer(
+    headers: &HeaderMap,
+    extensions: &mut Extensions,
+) -> anyhow::Result<Option<ValidToken>> {
+    let Some(Authorization(bearer)) = headers.typed_get::<Authorization<Bearer>>() else {
+        return Ok(None);
+    };
+    if let Some(validated) = extensions
+        .get::<Validated>()
+        .filter(|validated| validated.token == bearer.token())
+    {
+        return Ok(validated.valid.clone());
+    }
+    let valid = validate(bearer.token()).await?;
+    extensions.insert(Validated {
+        token: bearer.token().to_string(),
+        valid: valid.clone(),
+    });
+    Ok(valid)
+}
+
+pub(crate) fn init(validity: TokenValidity) -> anyhow::Result<()> {
+    for role in [
+        None,
+        Some(Role::Student),
+        Some(Role::Instructor),
+        Some(Role::Admin),
+    ] {
+        let hours = validity.hours_for(role);
+        if hours == 0 || hours > MAX_VALIDITY_HOURS {
+            return Err(anyhow::anyhow!(
+                "auth.token_validity hours must be between 1 and {MAX_VALIDITY_HOURS}"
+            ));
+        }
+    }
+    let _ = VALIDITY.set(validity);
+    Ok(())
 }
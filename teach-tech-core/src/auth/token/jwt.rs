@@ -0,0 +1,257 @@
+//! Tokens that carry their own claims, signed with HMAC-SHA256, so that validating them needs no
+//! database roundtrip.
+//!
+//! Logging out or swapping a token adds its ID to `revoked_tokens` until it would have expired.
+//! Every sibling keeps the list in memory, reloading it on serve, when told of a revocation, and
+//! every minute in case it missed one.
+
+use std::{
+    collections::BTreeSet,
+    sync::{OnceLock, RwLock},
+    time::Duration,
+};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    rngs::OsRng,
+};
+use ring::hmac;
+use sea_orm::{entity::prelude::*, ActiveValue};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::super::UserID;
+pub use super::Role;
+use crate::{
+    db::get_db,
+    siblings::{add_sibling_message_handler_raw, send_to_siblings_raw},
+    TeachCore,
+};
+
+const KEY_ENV_VAR: &str = "JWT_KEY";
+const SIBLING_SOURCE: &str = "teach-tech-core/jwt";
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+// {"alg":"HS256","typ":"JWT"}
+const HEADER: &str = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9";
+
+static KEY: OnceLock<hmac::Key> = OnceLock::new();
+/// The IDs of revoked tokens that have not expired yet
+static REVOKED: RwLock<BTreeSet<String>> = RwLock::new(BTreeSet::new());
+
+/// The `[auth.jwt]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct JwtOptions {
+    /// A 256 bit key as 64 hex characters. The `JWT_KEY` environment variable takes precedence,
+    /// so that the key can be kept out of the config file.
+    pub key: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Claims {
+    /// The user ID
+    pub sub: String,
+    /// The roles of the user when the token was issued
+    pub roles: Vec<Role>,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: String,
+    /// Separated by spaces, for scoped tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+impl Claims {
+    pub fn user_id(&self) -> Option<UserID> {
+        self.sub.parse::<i32>().ok()?.try_into().ok()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "revoked_tokens")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub jti: String,
+    pub expires_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// Whether tokens are issued as JWTs.
+pub fn is_enabled() -> bool {
+    KEY.get().is_some()
+}
+
+/// Whether `token` looks like a JWT rather than an opaque token, which are still accepted after
+/// switching to JWTs until they expire.
+pub(crate) fn is_jwt(token: &str) -> bool {
+    token.contains('.')
+}
+
+/// Signs a token for `user_id` that expires at `expires_at`. Call [`is_enabled`] first.
+pub(crate) fn sign(
+    user_id: UserID,
+    roles: Vec<Role>,
+    scope: Option<String>,
+    issued_at: DateTime,
+    expires_at: DateTime,
+) -> String {
+    let mut jti = String::new();
+    Alphanumeric.append_string(&mut OsRng, &mut jti, 16);
+    let claims = Claims {
+        sub: user_id.to_string(),
+        roles,
+        iat: issued_at.and_utc().timestamp(),
+        exp: expires_at.and_utc().timestamp(),
+        jti,
+        scope,
+    };
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+    let signing_input = format!("{HEADER}.{payload}");
+    let key = KEY.get().expect("JWTs were not enabled");
+    let signature = URL_SAFE_NO_PAD.encode(hmac::sign(key, signing_input.as_bytes()));
+    format!("{signing_input}.{signature}")
+}
+
+/// The claims of `token` if it was signed with the key, whether or not it expired.
+fn verify(token: &str) -> Option<Claims> {
+    let key = KEY.get()?;
+    let (signing_input, signature) = token.rsplit_once('.')?;
+    let (header, payload) = signing_input.split_once('.')?;
+    if header != HEADER {
+        return None;
+    }
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    hmac::verify(key, signing_input.as_bytes(), &signature).ok()?;
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+}
+
+/// The claims of `token` if it is signed, unexpired and not revoked.
+pub fn validate(token: &str) -> Option<Claims> {
+    let claims = verify(token)?;
+    if claims.exp <= chrono::Utc::now().timestamp() {
+        return None;
+    }
+    if REVOKED.read().unwrap().contains(&claims.jti) {
+        return None;
+    }
+    Some(claims)
+}
+
+/// Revokes `token` until it would have expired. Tokens that are not JWTs are ignored.
+pub(crate) async fn revoke(token: &str, db: &impl ConnectionTrait) -> Result<(), DbErr> {
+    store_revoked(token, db).await?;
+    forget(token);
+    Ok(())
+}
+
+/// Stores that `token` is revoked without refusing it yet, so that this can be done in a
+/// transaction that may still roll back. [`forget`] has to follow once it commits.
+pub(crate) async fn store_revoked(token: &str, db: &impl ConnectionTrait) -> Result<(), DbErr> {
+    let Some(claims) = verify(token) else {
+        return Ok(());
+    };
+    let Some(expires_at) = chrono::DateTime::from_timestamp(claims.exp, 0) else {
+        return Ok(());
+    };
+    let expires_at = expires_at.naive_utc();
+    if expires_at <= chrono::Utc::now().naive_utc() {
+        return Ok(());
+    }
+    if Entity::find_by_id(&claims.jti).one(db).await?.is_none() {
+        ActiveModel {
+            jti: ActiveValue::set(claims.jti.clone()),
+            expires_at: ActiveValue::set(expires_at),
+        }
+        .insert(db)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Refuses a token stored as revoked on this node and its siblings.
+pub(crate) fn forget(token: &str) {
+    let Some(claims) = verify(token) else {
+        return;
+    };
+    REVOKED.write().unwrap().insert(claims.jti.clone());
+    tokio::spawn(async move {
+        if let Err(e) = send_to_siblings_raw(SIBLING_SOURCE, claims.jti.as_bytes()).await {
+            error!("Error notifying siblings of a revoked token: {e:#}");
+        }
+    });
+}
+
+/// Reloads the revoked tokens, forgetting those that have expired since.
+async fn reload() -> Result<(), DbErr> {
+    let now = chrono::Utc::now().naive_utc();
+    Entity::delete_many()
+        .filter(Column::ExpiresAt.lte(now))
+        .exec(get_db())
+        .await?;
+    // Not the read replica, which may not have a revocation that a sibling was just told of
+    let revoked = Entity::find()
+        .all(get_db())
+        .await?
+        .into_iter()
+        .map(|model| model.jti)
+        .collect();
+    *REVOKED.write().unwrap() = revoked;
+    Ok(())
+}
+
+fn parse_key(key: &str) -> anyhow::Result<hmac::Key> {
+    let key = key.trim();
+    if key.len() != 64 {
+        return Err(anyhow::anyhow!("JWT key must be 64 hex characters"));
+    }
+    let bytes = (0..key.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&key[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()
+        .context("JWT key must be 64 hex characters")?;
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, &bytes))
+}
+
+pub(crate) fn init<S>(options: Option<JwtOptions>, core: &mut TeachCore<S>) -> anyhow::Result<()> {
+    core.add_db_reset_config(Entity);
+    let Some(options) = options else {
+        return Ok(());
+    };
+    let key = match std::env::var(KEY_ENV_VAR) {
+        Ok(key) => key,
+        Err(_) => options.key.with_context(|| {
+            format!(
+                "Set auth.jwt.key in teach-config.toml or the {KEY_ENV_VAR} environment variable"
+            )
+        })?,
+    };
+    let _ = KEY.set(parse_key(&key)?);
+
+    core.add_on_serve_named("jwt", 0, || async move {
+        reload().await?;
+        add_sibling_message_handler_raw(|source, bytes| {
+            if source != SIBLING_SOURCE {
+                return;
+            }
+            let jti = String::from_utf8_lossy(bytes).into_owned();
+            REVOKED.write().unwrap().insert(jti);
+        })
+        .await;
+        // Catches siblings that missed a notification
+        tokio::spawn(async {
+            loop {
+                tokio::time::sleep(REFRESH_INTERVAL).await;
+                if let Err(e) = reload().await {
+                    error!("Failed to reload revoked tokens: {e:#}");
+                }
+            }
+        });
+        Ok(())
+    });
+    Ok(())
+}
@@ -0,0 +1,179 @@
+//! Opaque tokens that were validated recently, kept in memory so that most requests skip
+//! `user_auth_tokens`.
+//!
+//! Entries are kept for `ttl_secs`, so `last_used` may lag behind by as much, and the least
+//! recently used are evicted past `capacity`. Revoking, replacing or expiring a token removes it
+//! here and tells every sibling to do the same. Tokens are kept and sent by their SHA-256 digest.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use fxhash::FxHashMap;
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use tracing::error;
+
+use super::ValidToken;
+use crate::{
+    siblings::{add_sibling_message_handler_raw, send_to_siblings_raw},
+    TeachCore,
+};
+
+const SIBLING_SOURCE: &str = "teach-tech-core/token-cache";
+
+static CACHE: OnceLock<TokenCache> = OnceLock::new();
+
+type Digest = [u8; 32];
+
+/// The `[auth.token_cache]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenCacheOptions {
+    /// Tokens kept at once. 0 turns the cache off
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+    /// 0 turns the cache off
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+impl Default for TokenCacheOptions {
+    fn default() -> Self {
+        Self {
+            capacity: default_capacity(),
+            ttl_secs: default_ttl_secs(),
+        }
+    }
+}
+
+fn default_capacity() -> usize {
+    10_000
+}
+
+fn default_ttl_secs() -> u64 {
+    60
+}
+
+struct Entry {
+    valid: ValidToken,
+    expires_at: Instant,
+    /// The key of the entry in [`Lru::order`]
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: FxHashMap<Digest, Entry>,
+    /// Digests by when they were last used, oldest first
+    order: BTreeMap<u64, Digest>,
+    clock: u64,
+}
+
+impl Lru {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, key: &Digest) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.last_used);
+        }
+    }
+}
+
+struct TokenCache {
+    capacity: usize,
+    ttl: Duration,
+    lru: Mutex<Lru>,
+}
+
+fn key(token: &str) -> Digest {
+    let mut key = Digest::default();
+    key.copy_from_slice(digest(&SHA256, token.as_bytes()).as_ref());
+    key
+}
+
+/// The cached validation of `token`, if it has not expired.
+pub(super) fn get(token: &str) -> Option<ValidToken> {
+    let cache = CACHE.get()?;
+    let key = key(token);
+    let mut lru = cache.lru.lock().unwrap();
+    if lru.entries.get(&key)?.expires_at <= Instant::now() {
+        lru.remove(&key);
+        return None;
+    }
+    let tick = lru.tick();
+    let Lru { entries, order, .. } = &mut *lru;
+    let entry = entries.get_mut(&key)?;
+    order.remove(&entry.last_used);
+    order.insert(tick, key);
+    entry.last_used = tick;
+    Some(entry.valid.clone())
+}
+
+pub(super) fn insert(token: &str, valid: ValidToken) {
+    let Some(cache) = CACHE.get() else {
+        return;
+    };
+    let key = key(token);
+    let mut lru = cache.lru.lock().unwrap();
+    lru.remove(&key);
+    while lru.entries.len() >= cache.capacity {
+        let Some((_, oldest)) = lru.order.pop_first() else {
+            break;
+        };
+        lru.entries.remove(&oldest);
+    }
+    let last_used = lru.tick();
+    lru.order.insert(last_used, key);
+    lru.entries.insert(
+        key,
+        Entry {
+            valid,
+            expires_at: Instant::now() + cache.ttl,
+            last_used,
+        },
+    );
+}
+
+/// Forgets `token` here and on every sibling.
+pub(super) fn invalidate(token: &str) {
+    let Some(cache) = CACHE.get() else {
+        return;
+    };
+    let key = key(token);
+    cache.lru.lock().unwrap().remove(&key);
+    // Callers may be in a transaction, which should not wait on siblings
+    tokio::spawn(async move {
+        if let Err(e) = send_to_siblings_raw(SIBLING_SOURCE, &key).await {
+            error!("Error notifying siblings of an invalidated token: {e:#}");
+        }
+    });
+}
+
+pub(crate) fn init<S>(options: TokenCacheOptions, core: &mut TeachCore<S>) {
+    if options.capacity == 0 || options.ttl_secs == 0 {
+        return;
+    }
+    let _ = CACHE.set(TokenCache {
+        capacity: options.capacity,
+        ttl: Duration::from_secs(options.ttl_secs),
+        lru: Mutex::new(Lru::default()),
+    });
+    core.add_on_serve_named("token_cache", 0, || async move {
+        add_sibling_message_handler_raw(|source, bytes| {
+            if source != SIBLING_SOURCE {
+                return;
+            }
+            let (Some(cache), Ok(key)) = (CACHE.get(), Digest::try_from(bytes)) else {
+                return;
+            };
+            cache.lru.lock().unwrap().remove(&key);
+        })
+        .await;
+        Ok(())
+    });
+}
@@ -1,12 +1,71 @@
+use std::{fmt, sync::OnceLock};
+
 use argon2::{
     password_hash::{self, rand_core::OsRng, PasswordHasher, SaltString},
-    Argon2, PasswordHash, PasswordVerifier,
+    Algorithm, Argon2, Params, PasswordHash, PasswordVerifier, Version,
 };
-use rand::distributions::{Alphanumeric, DistString};
-use sea_orm::{entity::prelude::*, ActiveValue};
+use fxhash::FxHashSet;
+use sea_orm::{entity::prelude::*, ActiveValue, IntoActiveModel, PaginatorTrait, QueryOrder};
+use serde::Deserialize;
+use tracing::warn;
 use zeroize::Zeroizing;
 
-use super::UserID;
+use super::{
+    password_policy::{self, PasswordError},
+    UserID,
+};
+use crate::{
+    db::insert_batched,
+    encryption::{self, Encrypted},
+    mail,
+};
+
+const MIN_USERNAME_LEN: usize = 3;
+const MAX_USERNAME_LEN: usize = 32;
+
+static PARAMS: OnceLock<Params> = OnceLock::new();
+
+/// The `[auth.argon2]` section of `teach-config.toml`. Passwords hashed with other parameters are
+/// hashed again the next time their users log in.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Argon2Options {
+    #[serde(default = "default_memory_kib")]
+    pub memory_kib: u32,
+    #[serde(default = "default_iterations")]
+    pub iterations: u32,
+    #[serde(default = "default_parallelism")]
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Options {
+    fn default() -> Self {
+        Self {
+            memory_kib: default_memory_kib(),
+            iterations: default_iterations(),
+            parallelism: default_parallelism(),
+        }
+    }
+}
+
+fn default_memory_kib() -> u32 {
+    Params::DEFAULT_M_COST
+}
+
+fn default_iterations() -> u32 {
+    Params::DEFAULT_T_COST
+}
+
+fn default_parallelism() -> u32 {
+    Params::DEFAULT_P_COST
+}
+
+fn argon2() -> Argon2<'static> {
+    Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        PARAMS.get().cloned().unwrap_or_default(),
+    )
+}
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "user_auth")]
@@ -14,6 +73,14 @@ pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
     pub user_id: UserID,
     pub password_hash: String,
+    /// Normalized with [`normalize_username`]
+    #[sea_orm(unique)]
+    pub username: Option<String>,
+    /// [`encryption::keyed_hash`] of the email, to look logins up by
+    #[sea_orm(unique)]
+    pub email_hash: Option<String>,
+    /// An address the user can log in with, trimmed and lowercased
+    pub email: Option<Encrypted<String, Entity>>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -25,7 +92,8 @@ impl Model {
     pub fn validate_password(&self, password: &str) -> anyhow::Result<bool> {
         let parsed_hash = PasswordHash::new(&self.password_hash)
             .map_err(|e| anyhow::anyhow!("Parsing password hash for {}: {e:#}", self.user_id))?;
-        match Argon2::default().verify_password(password.as_bytes(), &parsed_hash) {
+        // The parameters of the hash itself are used, so older hashes still verify
+        match argon2().verify_password(password.as_bytes(), &parsed_hash) {
             Ok(()) => Ok(true),
             Err(password_hash::Error::Password) => Ok(false),
             Err(e) => Err(anyhow::anyhow!(
@@ -34,21 +102,39 @@ impl Model {
             )),
         }
     }
+
+    /// Whether the hash was made with other parameters than `[auth.argon2]`.
+    pub fn needs_rehash(&self) -> bool {
+        let Ok(parsed_hash) = PasswordHash::new(&self.password_hash) else {
+            return false;
+        };
+        let Ok(params) = Params::try_from(&parsed_hash) else {
+            return true;
+        };
+        let current = PARAMS.get().cloned().unwrap_or_default();
+        parsed_hash.algorithm != Algorithm::Argon2id.ident()
+            || parsed_hash.version != Some(Version::V0x13.into())
+            || params.m_cost() != current.m_cost()
+            || params.t_cost() != current.t_cost()
+            || params.p_cost() != current.p_cost()
+    }
+
+    /// Hashes `password`, which must have been validated, again with the current parameters.
+    pub async fn rehash(&self, password: &str, conn: &impl ConnectionTrait) -> anyhow::Result<()> {
+        hash(self.user_id, password)
+            .map_err(|e| anyhow::anyhow!("Hashing password for {}: {e:#}", self.user_id))?
+            .update(conn)
+            .await?;
+        Ok(())
+    }
 }
 
 pub async fn new_rand(conn: &impl ConnectionTrait) -> Result<(Model, Zeroizing<String>), DbErr> {
-    let mut user_id;
-    let mut password = Zeroizing::new(String::new());
     loop {
-        user_id = UserID::rand();
-        password.clear();
-        Alphanumeric.append_string(&mut OsRng, &mut password, 18);
-        match new_from_password(user_id, &password)
+        let (model, password) = new_generated(UserID::rand())
             .await
-            .expect("Hashing admin password")
-            .insert(conn)
-            .await
-        {
+            .expect("Hashing admin password");
+        match model.insert(conn).await {
             Ok(m) => break Ok((m, password)),
             Err(DbErr::RecordNotInserted) => continue,
             Err(e) => return Err(e),
@@ -56,17 +142,290 @@ pub async fn new_rand(conn: &impl ConnectionTrait) -> Result<(Model, Zeroizing<S
     }
 }
 
+/// The names a user can log in with besides their ID.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoginNames {
+    pub username: Option<String>,
+    pub email: Option<String>,
+}
+
+impl LoginNames {
+    /// Normalizes both names, or `None` if the username is not valid. Check the email with
+    /// [`mail::is_valid_address`] first.
+    pub fn new(username: Option<&str>, email: Option<&str>) -> Option<Self> {
+        let username = match username {
+            Some(username) => Some(normalize_username(username)?),
+            None => None,
+        };
+        Some(Self {
+            username,
+            email: email.map(mail::normalize),
+        })
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &String> {
+        self.username.iter().chain(&self.email)
+    }
+}
+
+/// Lowercases `username`, or `None` if it is not 3 to 32 letters, digits, `.`, `_` and `-` with
+/// at least one letter, so that it cannot be mistaken for a user ID or an email.
+pub fn normalize_username(username: &str) -> Option<String> {
+    let username = username.trim().to_lowercase();
+    let len = username.chars().count();
+    if !(MIN_USERNAME_LEN..=MAX_USERNAME_LEN).contains(&len)
+        || !username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        || !username.chars().any(|c| c.is_ascii_alphabetic())
+    {
+        return None;
+    }
+    Some(username)
+}
+
+/// The first of `names` that is given twice, or that a user can already log in with.
+pub async fn find_taken(
+    names: &[LoginNames],
+    conn: &impl ConnectionTrait,
+) -> Result<Option<String>, DbErr> {
+    let mut seen = FxHashSet::default();
+    for name in names.iter().flat_map(LoginNames::iter) {
+        if !seen.insert(name) {
+            return Ok(Some(name.clone()));
+        }
+    }
+    let usernames = names.iter().filter_map(|names| names.username.clone());
+    let email_hashes = names
+        .iter()
+        .filter_map(|names| names.email.as_deref().map(encryption::keyed_hash));
+    let Some(taken) = Entity::find()
+        .filter(
+            Column::Username
+                .is_in(usernames)
+                .or(Column::EmailHash.is_in(email_hashes)),
+        )
+        .one(conn)
+        .await?
+    else {
+        return Ok(None);
+    };
+    // Either one may be the match
+    Ok(names
+        .iter()
+        .flat_map(LoginNames::iter)
+        .find(|name| {
+            taken.username.as_ref() == Some(*name)
+                || taken.email_hash.as_deref() == Some(encryption::keyed_hash(name).as_str())
+        })
+        .cloned())
+}
+
+/// Who a login is for, as named by its form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoginName {
+    /// Given as an ID, or a username or email that was found. The user may still not exist
+    User(UserID),
+    /// A normalized username or email that no user has
+    Unknown(String),
+}
+
+impl fmt::Display for LoginName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User(user_id) => user_id.fmt(f),
+            Self::Unknown(name) => name.fmt(f),
+        }
+    }
+}
+
+/// Looks up the user a login names, by `user_id` or by an `identifier` that is an ID, a username
+/// or an email. `None` if neither is given.
+pub async fn resolve_login(
+    user_id: Option<UserID>,
+    identifier: Option<&str>,
+    conn: &impl ConnectionTrait,
+) -> Result<Option<LoginName>, DbErr> {
+    if let Some(user_id) = user_id {
+        return Ok(Some(LoginName::User(user_id)));
+    }
+    let Some(identifier) = identifier.map(str::trim).filter(|id| !id.is_empty()) else {
+        return Ok(None);
+    };
+    // IDs are not looked up, so that directory users without a local row can still log in
+    if let Some(user_id) = identifier
+        .parse::<i32>()
+        .ok()
+        .and_then(|id| UserID::try_from(id).ok())
+    {
+        return Ok(Some(LoginName::User(user_id)));
+    }
+    let name = identifier.to_lowercase();
+    let filter = if name.contains('@') {
+        Column::EmailHash.eq(encryption::keyed_hash(&name))
+    } else {
+        Column::Username.eq(name.as_str())
+    };
+    Ok(Some(
+        match Entity::find().filter(filter).one(conn).await? {
+            Some(model) => LoginName::User(model.user_id),
+            None => LoginName::Unknown(name),
+        },
+    ))
+}
+
+/// Generates `count` random passwords and their hashes.
+///
+/// Hashing is slow on purpose, so the work is split across the blocking thread pool instead of
+/// stalling the runtime one password at a time.
+pub async fn rand_passwords(count: usize) -> Vec<(Zeroizing<String>, String)> {
+    let threads = std::thread::available_parallelism().map_or(1, usize::from);
+    let chunk_size = count.div_ceil(threads).max(1);
+    let mut remaining = count;
+    let mut tasks = vec![];
+    while remaining > 0 {
+        let chunk = remaining.min(chunk_size);
+        remaining -= chunk;
+        tasks.push(tokio::task::spawn_blocking(move || {
+            let argon2 = argon2();
+            (0..chunk)
+                .map(|_| {
+                    let password = password_policy::generate();
+                    let salt = SaltString::generate(&mut OsRng);
+                    let hash = argon2
+                        .hash_password(password.as_bytes(), &salt)
+                        .expect("Hashing password")
+                        .to_string();
+                    (password, hash)
+                })
+                .collect::<Vec<_>>()
+        }));
+    }
+    let mut passwords = Vec::with_capacity(count);
+    for chunk in futures::future::join_all(tasks).await {
+        passwords.extend(chunk.expect("Hashing passwords"));
+    }
+    passwords
+}
+
+/// Like [`new_rand`], but creates a user for each of `passwords` (from [`rand_passwords`]) with
+/// one query for taken ids and batched inserts.
+///
+/// The user at each index gets the `names` at that index, which should have been checked with
+/// [`find_taken`].
+pub async fn new_rand_many(
+    passwords: Vec<(Zeroizing<String>, String)>,
+    mut names: Vec<LoginNames>,
+    conn: &impl ConnectionTrait,
+) -> Result<Vec<(Model, Zeroizing<String>)>, DbErr> {
+    let count = passwords.len();
+    let mut user_ids = FxHashSet::default();
+    while user_ids.len() < count {
+        let candidates: FxHashSet<_> = (user_ids.len()..count)
+            .map(|_| UserID::rand())
+            .filter(|user_id| !user_ids.contains(user_id))
+            .collect();
+        let taken: FxHashSet<_> = Entity::find()
+            .filter(Column::UserId.is_in(candidates.iter().copied()))
+            .all(conn)
+            .await?
+            .into_iter()
+            .map(|model| model.user_id)
+            .collect();
+        user_ids.extend(candidates.difference(&taken));
+    }
+
+    names.resize_with(count, LoginNames::default);
+    let mut created = vec![];
+    let mut models = vec![];
+    for ((user_id, (password, password_hash)), names) in
+        user_ids.into_iter().zip(passwords).zip(names)
+    {
+        let model = Model {
+            user_id,
+            password_hash,
+            username: names.username,
+            email_hash: names.email.as_deref().map(encryption::keyed_hash),
+            email: names
+                .email
+                .map(|email| Encrypted::new(email, Column::Email, user_id)),
+        };
+        models.push(model.clone().into_active_model());
+        created.push((model, password));
+    }
+    insert_batched(models, conn).await?;
+    Ok(created)
+}
+
+/// Hashes a password chosen by a user, after checking it with [`password_policy::check`].
 pub async fn new_from_password(
     user_id: UserID,
     password: &str,
-) -> password_hash::Result<ActiveModel> {
+) -> Result<ActiveModel, PasswordError> {
+    password_policy::check(password).await?;
+    hash(user_id, password).map_err(|e| PasswordError::Failed(anyhow::anyhow!("{e:#}")))
+}
+
+/// Like [`new_from_password`], but with a password from [`password_policy::generate`], which is
+/// not given to breach checks.
+pub async fn new_generated(
+    user_id: UserID,
+) -> password_hash::Result<(ActiveModel, Zeroizing<String>)> {
+    let password = password_policy::generate();
+    Ok((hash(user_id, &password)?, password))
+}
+
+fn hash(user_id: UserID, password: &str) -> password_hash::Result<ActiveModel> {
     let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
+    let argon2 = argon2();
     let hash = argon2.hash_password(password.as_bytes(), &salt)?;
     let password_hash = hash.to_string();
 
     Ok(ActiveModel {
-        user_id: ActiveValue::set(user_id.into()),
+        user_id: ActiveValue::set(user_id),
         password_hash: ActiveValue::set(password_hash.clone()),
+        ..Default::default()
     })
 }
+
+/// Recomputes the hash of every login email, since hashes change with the encryption key.
+pub(crate) async fn rehash_emails(db: &'static DatabaseConnection) -> Result<u64, DbErr> {
+    let mut pages = Entity::find()
+        .filter(Column::Email.is_not_null())
+        .order_by_asc(Column::UserId)
+        .paginate(db, 500);
+    let mut rows = 0;
+    while let Some(models) = pages.fetch_and_next().await? {
+        for model in models {
+            ActiveModel {
+                user_id: ActiveValue::unchanged(model.user_id),
+                email_hash: ActiveValue::set(
+                    model
+                        .email
+                        .as_ref()
+                        .map(|email| encryption::keyed_hash(&email.0)),
+                ),
+                ..Default::default()
+            }
+            .update(db)
+            .await?;
+            rows += 1;
+        }
+    }
+    Ok(rows)
+}
+
+pub(crate) fn init(options: Argon2Options) -> anyhow::Result<()> {
+    let params = Params::new(
+        options.memory_kib,
+        options.iterations,
+        options.parallelism,
+        None,
+    )
+    .map_err(|e| anyhow::anyhow!("Checking auth.argon2: {e}"))?;
+    if params.m_cost() < Params::DEFAULT_M_COST || params.t_cost() < Params::DEFAULT_T_COST {
+        warn!("auth.argon2 is weaker than the defaults, which makes passwords easier to crack");
+    }
+    let _ = PARAMS.set(params);
+    Ok(())
+}
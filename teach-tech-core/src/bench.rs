@@ -0,0 +1,559 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::ConnectInfo,
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use clap::{Args, ValueEnum};
+use sea_orm::{ColumnTrait, DbErr, EntityTrait, QueryFilter, TransactionTrait};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tower::ServiceExt;
+
+use crate::{
+    auth::{token, user_auth, UserID},
+    db::get_db,
+    http::form_encode,
+    on_serve::{self, OnServeEntry},
+    users::students,
+};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Scenario {
+    /// Students logging in over and over
+    LoginStorm,
+    /// Logged in students polling `/student/home`
+    HomePolling,
+    /// Creating students in batches through `/student/create`
+    BulkCreate,
+}
+
+/// Every scenario creates real students, so benchmarks should be run against a disposable
+/// database.
+///
+/// In-process benchmarks delete the students they created afterwards, while the audit log keeps
+/// its records of them.
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    scenario: Scenario,
+    /// Confirms that the database may be written to, since students are created in it
+    #[arg(long)]
+    disposable_db: bool,
+    /// A bearer token of an admin that can create students
+    #[arg(long)]
+    token: String,
+    /// A running instance to benchmark, such as `http://127.0.0.1:8080`. Without it, requests
+    /// are handled in-process by this executable's router
+    #[arg(long)]
+    url: Option<String>,
+    /// How many requests to time
+    #[arg(long, default_value_t = 1000)]
+    requests: usize,
+    /// How many requests are in flight at once
+    #[arg(long, default_value_t = 16)]
+    concurrency: usize,
+    /// How many students log in or poll
+    #[arg(long, default_value_t = 20)]
+    users: usize,
+    /// How many students each bulk-create request creates
+    #[arg(long, default_value_t = 50)]
+    batch_size: usize,
+}
+
+impl BenchArgs {
+    pub(crate) fn is_remote(&self) -> bool {
+        self.url.is_some()
+    }
+
+    /// Checks that the database was confirmed to be disposable, before connecting to it.
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        if !self.disposable_db {
+            return Err(anyhow::anyhow!(
+                "Benchmarks create students in the database, so pass --disposable-db to confirm that it can be written to"
+            ));
+        }
+        Ok(())
+    }
+}
+
+struct BenchRequest {
+    method: Method,
+    path: String,
+    token: Option<String>,
+    content_type: Option<&'static str>,
+    body: Vec<u8>,
+}
+
+impl BenchRequest {
+    fn get(path: &str, token: &str) -> Self {
+        Self {
+            method: Method::GET,
+            path: path.to_string(),
+            token: Some(token.to_string()),
+            content_type: None,
+            body: vec![],
+        }
+    }
+
+    fn login(user_id: UserID, password: &str) -> Self {
+        Self {
+            method: Method::POST,
+            path: "/auth/login".into(),
+            token: None,
+            content_type: Some("application/x-www-form-urlencoded"),
+            body: form_encode(&[("user_id", &user_id.to_string()), ("password", password)])
+                .into_bytes(),
+        }
+    }
+
+    fn create_students(count: usize, token: &str) -> Self {
+        let students: Vec<_> = (0..count)
+            .map(|i| {
+                json!({
+                    "name": format!("Bench student {i}"),
+                    "birthdate": "2000-01-01T00:00:00Z",
+                    "pronouns": "they/them",
+                })
+            })
+            .collect();
+        Self {
+            method: Method::POST,
+            path: "/student/create".into(),
+            token: Some(token.to_string()),
+            content_type: Some("application/json"),
+            body: serde_json::to_vec(&json!({ "students": students })).unwrap(),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Target {
+    InProcess(Router),
+    Remote { host: String, port: u16 },
+}
+
+impl Target {
+    fn parse_url(url: &str) -> anyhow::Result<Self> {
+        let address = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow::anyhow!("Only http:// urls can be benchmarked, not {url}"))?
+            .trim_end_matches('/');
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().context("Invalid port")?),
+            None => (address, 80),
+        };
+        Ok(Self::Remote {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// One client of the target. Remote clients keep their connection open between requests.
+struct Client {
+    target: Target,
+    stream: Option<BufReader<TcpStream>>,
+}
+
+impl Client {
+    fn new(target: Target) -> Self {
+        Self {
+            target,
+            stream: None,
+        }
+    }
+
+    async fn send(&mut self, request: &BenchRequest) -> anyhow::Result<(StatusCode, Bytes)> {
+        let (host, port) = match &self.target {
+            Target::InProcess(router) => {
+                let mut builder = Request::builder()
+                    .method(request.method.clone())
+                    .uri(&request.path);
+                if let Some(token) = &request.token {
+                    builder = builder.header(header::AUTHORIZATION, format!("Bearer {token}"));
+                }
+                if let Some(content_type) = request.content_type {
+                    builder = builder.header(header::CONTENT_TYPE, content_type);
+                }
+                let mut http_request = builder.body(Body::from(request.body.clone()))?;
+                http_request
+                    .extensions_mut()
+                    .insert(ConnectInfo(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))));
+                let response = router.clone().oneshot(http_request).await?;
+                let status = response.status();
+                let body = to_bytes(response.into_body(), usize::MAX).await?;
+                return Ok((status, body));
+            }
+            Target::Remote { host, port } => (host.clone(), *port),
+        };
+
+        // A kept-alive connection may have been closed by the server since the last request
+        let reused = self.stream.is_some();
+        match self.exchange(&host, port, request).await {
+            Err(_) if reused => {
+                self.stream = None;
+                self.exchange(&host, port, request).await
+            }
+            result => result,
+        }
+    }
+
+    async fn exchange(
+        &mut self,
+        host: &str,
+        port: u16,
+        request: &BenchRequest,
+    ) -> anyhow::Result<(StatusCode, Bytes)> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => {
+                let stream = TcpStream::connect((host, port))
+                    .await
+                    .with_context(|| format!("Connecting to {host}:{port}"))?;
+                stream.set_nodelay(true)?;
+                self.stream.insert(BufReader::new(stream))
+            }
+        };
+
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {host}\r\nContent-Length: {}\r\n",
+            request.method,
+            request.path,
+            request.body.len()
+        );
+        if let Some(token) = &request.token {
+            head.push_str(&format!("Authorization: Bearer {token}\r\n"));
+        }
+        if let Some(content_type) = request.content_type {
+            head.push_str(&format!("Content-Type: {content_type}\r\n"));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&request.body).await?;
+        stream.flush().await?;
+
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        let status = line
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .ok_or_else(|| anyhow::anyhow!("Invalid status line {line:?}"))?;
+        let mut content_length = None;
+        let mut chunked = false;
+        let mut close = false;
+        loop {
+            line.clear();
+            stream.read_line(&mut line).await?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse::<usize>().ok(),
+                "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+                "connection" => close = value.eq_ignore_ascii_case("close"),
+                _ => {}
+            }
+        }
+
+        let mut body = vec![];
+        if chunked {
+            loop {
+                line.clear();
+                stream.read_line(&mut line).await?;
+                let size = usize::from_str_radix(line.trim_end(), 16)
+                    .with_context(|| format!("Invalid chunk size {line:?}"))?;
+                if size == 0 {
+                    line.clear();
+                    stream.read_line(&mut line).await?;
+                    break;
+                }
+                let start = body.len();
+                body.resize(start + size + 2, 0);
+                stream.read_exact(&mut body[start..]).await?;
+                body.truncate(start + size);
+            }
+        } else if let Some(content_length) = content_length {
+            body.resize(content_length, 0);
+            stream.read_exact(&mut body).await?;
+        } else {
+            stream.read_to_end(&mut body).await?;
+            close = true;
+        }
+        if close {
+            self.stream = None;
+        }
+        Ok((status, body.into()))
+    }
+
+    /// Sends a request that has to succeed for the benchmark to go on.
+    async fn send_ok(&mut self, request: &BenchRequest) -> anyhow::Result<Bytes> {
+        let (status, body) = self.send(request).await?;
+        if !status.is_success() {
+            return Err(anyhow::anyhow!(
+                "{} {} responded with {status}: {}",
+                request.method,
+                request.path,
+                String::from_utf8_lossy(&body)
+            ));
+        }
+        Ok(body)
+    }
+}
+
+#[derive(Deserialize)]
+struct CreatedStudents {
+    students: Vec<CreatedStudent>,
+}
+
+#[derive(Deserialize)]
+struct CreatedStudent {
+    user_id: UserID,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct LoggedIn {
+    token: String,
+}
+
+#[derive(Default)]
+struct Results {
+    created: Vec<UserID>,
+    latencies: Vec<Duration>,
+    failures: usize,
+    first_failure: Option<String>,
+}
+
+fn percentile(sorted: &[Duration], percent: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let index = ((sorted.len() - 1) as f64 * percent / 100.0).round() as usize;
+    sorted[index].as_secs_f64() * 1000.0
+}
+
+// Deletes the students a benchmark created, with their logins and tokens
+async fn delete_students(user_ids: &[UserID]) -> Result<(), DbErr> {
+    let txn = get_db().begin().await?;
+    for user_ids in user_ids.chunks(500) {
+        let user_ids = user_ids.iter().copied();
+        token::Entity::delete_many()
+            .filter(token::Column::UserId.is_in(user_ids.clone()))
+            .exec(&txn)
+            .await?;
+        students::Entity::delete_many()
+            .filter(students::Column::UserId.is_in(user_ids.clone()))
+            .exec(&txn)
+            .await?;
+        user_auth::Entity::delete_many()
+            .filter(user_auth::Column::UserId.is_in(user_ids))
+            .exec(&txn)
+            .await?;
+    }
+    txn.commit().await
+}
+
+async fn bench(target: Target, args: BenchArgs) -> anyhow::Result<ExitCode> {
+    let results = Arc::new(Mutex::new(Results::default()));
+    let result = bench_with(target.clone(), args, results.clone()).await;
+    let created = std::mem::take(&mut results.lock().unwrap().created);
+    match target {
+        Target::InProcess(_) => {
+            delete_students(&created)
+                .await
+                .context("Deleting the benchmark's students")?;
+            println!("Deleted the {} students that were created", created.len());
+        }
+        Target::Remote { .. } => {
+            println!(
+                "{} students were created and are left in place",
+                created.len()
+            )
+        }
+    }
+    result
+}
+
+fn created_students(body: &[u8]) -> anyhow::Result<Vec<CreatedStudent>> {
+    let created: CreatedStudents =
+        serde_json::from_slice(body).context("Parsing created students")?;
+    Ok(created.students)
+}
+
+async fn bench_with(
+    target: Target,
+    args: BenchArgs,
+    results: Arc<Mutex<Results>>,
+) -> anyhow::Result<ExitCode> {
+    let mut setup = Client::new(target.clone());
+    // Cycled through until args.requests have been sent
+    let requests: Vec<BenchRequest> = match args.scenario {
+        Scenario::BulkCreate => vec![BenchRequest::create_students(args.batch_size, &args.token)],
+        Scenario::LoginStorm | Scenario::HomePolling => {
+            println!("Creating {} students", args.users);
+            let body = setup
+                .send_ok(&BenchRequest::create_students(args.users, &args.token))
+                .await?;
+            let created = created_students(&body)?;
+            results
+                .lock()
+                .unwrap()
+                .created
+                .extend(created.iter().map(|student| student.user_id));
+            let logins: Vec<_> = created
+                .iter()
+                .map(|student| BenchRequest::login(student.user_id, &student.password))
+                .collect();
+            if let Scenario::LoginStorm = args.scenario {
+                logins
+            } else {
+                let mut homes = vec![];
+                for login in &logins {
+                    let body = setup.send_ok(login).await?;
+                    let LoggedIn { token } =
+                        serde_json::from_slice(&body).context("Parsing login")?;
+                    homes.push(BenchRequest::get("/student/home", &token));
+                }
+                homes
+            }
+        }
+    };
+
+    let requests = Arc::new(requests);
+    let total = args.requests;
+    let next = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    let concurrency = args.concurrency.clamp(1, total.max(1));
+    let scenario = args.scenario;
+    let workers: Vec<_> = (0..concurrency)
+        .map(|_| {
+            let requests = requests.clone();
+            let next = next.clone();
+            let results = results.clone();
+            let mut client = Client::new(target.clone());
+            tokio::spawn(async move {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    if index >= total || requests.is_empty() {
+                        break;
+                    }
+                    let request = &requests[index % requests.len()];
+                    let sent = Instant::now();
+                    let mut created = vec![];
+                    let failure = match client.send(request).await {
+                        Ok((status, body)) if status.is_success() => {
+                            if let Scenario::BulkCreate = scenario {
+                                created = created_students(&body).unwrap_or_default();
+                            }
+                            None
+                        }
+                        Ok((status, body)) => Some(format!(
+                            "responded with {status}: {}",
+                            String::from_utf8_lossy(&body)
+                        )),
+                        Err(e) => Some(format!("{e:#}")),
+                    };
+                    let latency = sent.elapsed();
+                    let mut results = results.lock().unwrap();
+                    results
+                        .created
+                        .extend(created.iter().map(|student| student.user_id));
+                    results.latencies.push(latency);
+                    if let Some(failure) = failure {
+                        results.failures += 1;
+                        results.first_failure.get_or_insert(failure);
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.await.context("Benchmark worker panicked")?;
+    }
+    let elapsed = start.elapsed();
+
+    let mut results = results.lock().unwrap();
+    results.latencies.sort();
+    let scenario = args
+        .scenario
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default();
+    println!(
+        "{scenario}: {} requests with {} in flight in {:.2}s ({:.1} requests/s)",
+        results.latencies.len(),
+        concurrency,
+        elapsed.as_secs_f64(),
+        results.latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "latency: p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms, max {:.1}ms",
+        percentile(&results.latencies, 50.0),
+        percentile(&results.latencies, 90.0),
+        percentile(&results.latencies, 99.0),
+        percentile(&results.latencies, 100.0),
+    );
+    if results.failures == 0 {
+        return Ok(ExitCode::SUCCESS);
+    }
+    println!(
+        "{} requests failed, the first {}",
+        results.failures,
+        results.first_failure.as_deref().unwrap_or_default()
+    );
+    Ok(ExitCode::FAILURE)
+}
+
+/// Runs the benchmark on a multi-threaded runtime, like the API is served on.
+///
+/// `router` is benchmarked in-process when `args` has no url.
+pub(crate) async fn run(
+    args: BenchArgs,
+    router: Router,
+    on_serve: Vec<OnServeEntry>,
+) -> anyhow::Result<ExitCode> {
+    let target = match &args.url {
+        Some(url) => Target::parse_url(url)?,
+        None => Target::InProcess(router),
+    };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("Creating runtime")?;
+    let (finished_tx, finished_rx) = tokio::sync::oneshot::channel();
+    // Awaited instead of joined, since the database pool needs the current runtime to keep running
+    std::thread::spawn(move || {
+        let result = runtime.block_on(async move {
+            if let Target::InProcess(_) = target {
+                on_serve::run_all(on_serve)
+                    .await
+                    .context("Calling on_serve API")?;
+            }
+            bench(target, args).await
+        });
+        let _ = finished_tx.send(result);
+    });
+    finished_rx.await.context("Panicked while benchmarking")?
+}
@@ -0,0 +1,366 @@
+use std::{
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use axum::async_trait;
+use fxhash::FxHashMap;
+use redis::{aio::MultiplexedConnection, AsyncCommands};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    siblings::{add_sibling_message_handler_raw, send_to_siblings_raw},
+    TeachCore,
+};
+
+static CACHE: OnceLock<&'static dyn Cache> = OnceLock::new();
+const SIBLING_SOURCE: &str = "teach-tech-core/cache";
+
+/// A key-value cache shared by the API. Values are opaque bytes; use [`get_json`] and
+/// [`set_json`] for typed access.
+#[async_trait]
+pub trait Cache: Send + Sync {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+    async fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> anyhow::Result<()>;
+    async fn expire(&self, key: &str, ttl: Duration) -> anyhow::Result<()>;
+    async fn invalidate(&self, key: &str) -> anyhow::Result<()>;
+    /// Adds one to the counter at `key` in a single step, returning the new count. Counters that
+    /// do not exist yet start at 1 and expire after `ttl`. Counters read with [`get_json`] as
+    /// numbers.
+    ///
+    /// [`MemoryCache`] counts on each node separately, so limits built on its counters are not
+    /// shared between replicas. [`RedisCache`] counts once for every replica.
+    async fn incr(&self, key: &str, ttl: Option<Duration>) -> anyhow::Result<u64>;
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub cache: CacheOptions,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum CacheOptions {
+    Memory {
+        #[serde(default = "default_max_entries")]
+        max_entries: usize,
+    },
+    Redis {
+        url: String,
+        #[serde(default = "default_key_prefix")]
+        key_prefix: String,
+    },
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self::Memory {
+            max_entries: default_max_entries(),
+        }
+    }
+}
+
+fn default_max_entries() -> usize {
+    100_000
+}
+
+fn default_key_prefix() -> String {
+    "teach-tech:".to_string()
+}
+
+pub fn get_cache() -> &'static dyn Cache {
+    *CACHE.get_or_init(|| Box::leak(Box::new(MemoryCache::new(default_max_entries()))))
+}
+
+pub async fn get_json<T: DeserializeOwned>(key: &str) -> anyhow::Result<Option<T>> {
+    match get_cache().get(key).await? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn set_json<T: Serialize>(
+    key: &str,
+    value: &T,
+    ttl: Option<Duration>,
+) -> anyhow::Result<()> {
+    get_cache().set(key, serde_json::to_vec(value)?, ttl).await
+}
+
+struct MemoryEntry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+}
+
+impl MemoryEntry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// A cache local to this process. Invalidations are broadcast to siblings so that they do not
+/// keep serving stale entries.
+pub struct MemoryCache {
+    entries: Mutex<FxHashMap<String, MemoryEntry>>,
+    max_entries: usize,
+}
+
+impl MemoryCache {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            entries: Mutex::new(FxHashMap::default()),
+            max_entries,
+        }
+    }
+
+    fn remove_local(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    fn insert(&self, entries: &mut FxHashMap<String, MemoryEntry>, key: &str, entry: MemoryEntry) {
+        if entries.len() >= self.max_entries && !entries.contains_key(key) {
+            let now = Instant::now();
+            entries.retain(|_, entry| !entry.is_expired(now));
+            if entries.len() >= self.max_entries {
+                let evicted = entries.keys().next().cloned();
+                if let Some(evicted) = evicted {
+                    entries.remove(&evicted);
+                }
+            }
+        }
+        entries.insert(key.to_string(), entry);
+    }
+}
+
+fn parse_count(value: &[u8]) -> anyhow::Result<u64> {
+    Ok(std::str::from_utf8(value)?.trim().parse()?)
+}
+
+#[async_trait]
+impl Cache for MemoryCache {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.is_expired(Instant::now()) => {
+                entries.remove(key);
+                Ok(None)
+            }
+            Some(entry) => Ok(Some(entry.value.clone())),
+            None => Ok(None),
+        }
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> anyhow::Result<()> {
+        let entry = MemoryEntry {
+            value,
+            expires_at: ttl.map(|ttl| Instant::now() + ttl),
+        };
+        self.insert(&mut self.entries.lock().unwrap(), key, entry);
+        Ok(())
+    }
+
+    async fn expire(&self, key: &str, ttl: Duration) -> anyhow::Result<()> {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+            entry.expires_at = Some(Instant::now() + ttl);
+        }
+        Ok(())
+    }
+
+    async fn invalidate(&self, key: &str) -> anyhow::Result<()> {
+        self.remove_local(key);
+        send_to_siblings_raw(SIBLING_SOURCE, key.as_bytes()).await
+    }
+
+    async fn incr(&self, key: &str, ttl: Option<Duration>) -> anyhow::Result<u64> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(key) {
+            Some(entry) if !entry.is_expired(now) => {
+                let count = parse_count(&entry.value)?.saturating_add(1);
+                entry.value = count.to_string().into_bytes();
+                Ok(count)
+            }
+            _ => {
+                let entry = MemoryEntry {
+                    value: b"1".to_vec(),
+                    expires_at: ttl.map(|ttl| now + ttl),
+                };
+                self.insert(&mut entries, key, entry);
+                Ok(1)
+            }
+        }
+    }
+}
+
+/// A cache shared by every sibling through a Redis server.
+pub struct RedisCache {
+    client: redis::Client,
+    conn: tokio::sync::Mutex<Option<MultiplexedConnection>>,
+    key_prefix: String,
+}
+
+impl RedisCache {
+    pub fn new(url: &str, key_prefix: String) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: redis::Client::open(url)?,
+            conn: tokio::sync::Mutex::new(None),
+            key_prefix,
+        })
+    }
+
+    // Connections are shared between requests and replaced after a failure
+    async fn conn(&self) -> anyhow::Result<MultiplexedConnection> {
+        let mut conn = self.conn.lock().await;
+        if let Some(conn) = conn.as_ref() {
+            return Ok(conn.clone());
+        }
+        let new_conn = self.client.get_multiplexed_async_connection().await?;
+        *conn = Some(new_conn.clone());
+        Ok(new_conn)
+    }
+
+    async fn on_error<T>(&self, result: redis::RedisResult<T>) -> anyhow::Result<T> {
+        match result {
+            Ok(value) => Ok(value),
+            Err(e) => {
+                if e.is_io_error() || e.is_connection_dropped() {
+                    *self.conn.lock().await = None;
+                }
+                Err(e.into())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Cache for RedisCache {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let key = format!("{}{key}", self.key_prefix);
+        let result = self.conn().await?.get(key).await;
+        self.on_error(result).await
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> anyhow::Result<()> {
+        let key = format!("{}{key}", self.key_prefix);
+        let mut conn = self.conn().await?;
+        let result = match ttl {
+            Some(ttl) => conn.pset_ex(key, value, ttl.as_millis() as u64).await,
+            None => conn.set(key, value).await,
+        };
+        self.on_error(result).await
+    }
+
+    async fn expire(&self, key: &str, ttl: Duration) -> anyhow::Result<()> {
+        let key = format!("{}{key}", self.key_prefix);
+        let result: redis::RedisResult<bool> = self
+            .conn()
+            .await?
+            .pexpire(key, ttl.as_millis() as i64)
+            .await;
+        self.on_error(result).await.map(|_| ())
+    }
+
+    async fn invalidate(&self, key: &str) -> anyhow::Result<()> {
+        let key = format!("{}{key}", self.key_prefix);
+        let result: redis::RedisResult<usize> = self.conn().await?.del(key).await;
+        self.on_error(result).await.map(|_| ())
+    }
+
+    async fn incr(&self, key: &str, ttl: Option<Duration>) -> anyhow::Result<u64> {
+        let key = format!("{}{key}", self.key_prefix);
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        // Only the increment that creates the counter starts its window. Creating it with its
+        // expiry in the same transaction means a counter can not be left without one
+        if let Some(ttl) = ttl {
+            pipe.cmd("SET")
+                .arg(&key)
+                .arg(0)
+                .arg("NX")
+                .arg("PX")
+                .arg(ttl.as_millis() as u64)
+                .ignore();
+        }
+        pipe.incr(&key, 1u64);
+        let result: redis::RedisResult<(u64,)> = pipe.query_async(&mut self.conn().await?).await;
+        self.on_error(result).await.map(|(count,)| count)
+    }
+}
+
+pub fn add_to_core<S>(mut core: TeachCore<S>) -> anyhow::Result<TeachCore<S>> {
+    let cache_config: CacheConfig = toml::from_str(core.get_config_str())?;
+    let cache: &'static dyn Cache = match cache_config.cache {
+        CacheOptions::Memory { max_entries } => {
+            let cache: &'static MemoryCache = Box::leak(Box::new(MemoryCache::new(max_entries)));
+            core.add_on_serve_named("cache", 0, move || async move {
+                add_sibling_message_handler_raw(move |source, bytes| {
+                    if source != SIBLING_SOURCE {
+                        return;
+                    }
+                    match std::str::from_utf8(bytes) {
+                        Ok(key) => cache.remove_local(key),
+                        Err(_) => error!("Received a cache invalidation with an invalid key"),
+                    }
+                })
+                .await;
+                Ok(())
+            });
+            cache
+        }
+        CacheOptions::Redis { url, key_prefix } => {
+            Box::leak(Box::new(RedisCache::new(&url, key_prefix)?))
+        }
+    };
+    if CACHE.set(cache).is_err() {
+        return Err(anyhow::anyhow!("Cache is already initialized"));
+    }
+    Ok(core)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    async fn counts_up_and_restarts_after_ttl(cache: Arc<dyn Cache>, key: &str) {
+        let ttl = Some(Duration::from_millis(200));
+        let counts: Vec<_> = (0..50)
+            .map(|_| {
+                let cache = cache.clone();
+                let key = key.to_string();
+                tokio::spawn(async move { cache.incr(&key, ttl).await.unwrap() })
+            })
+            .collect();
+        let mut seen = vec![];
+        for count in counts {
+            seen.push(count.await.unwrap());
+        }
+        seen.sort_unstable();
+        // No increment was lost or counted twice
+        assert_eq!(seen, (1..=50).collect::<Vec<_>>());
+        assert_eq!(cache.get(key).await.unwrap(), Some(b"50".to_vec()));
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(cache.incr(key, ttl).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn memory_counter_counts_up_and_expires() {
+        counts_up_and_restarts_after_ttl(Arc::new(MemoryCache::new(10)), "counter").await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at REDIS_URL"]
+    async fn redis_counter_counts_up_and_expires() {
+        let url = std::env::var("REDIS_URL").unwrap();
+        let cache = RedisCache::new(&url, "teach-tech-test:".to_string()).unwrap();
+        let key = format!(
+            "counter:{}",
+            chrono::Utc::now().timestamp_nanos_opt().unwrap()
+        );
+        counts_up_and_restarts_after_ttl(Arc::new(cache), &key).await;
+    }
+}
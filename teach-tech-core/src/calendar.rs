@@ -0,0 +1,385 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::Path,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Json,
+};
+use futures::future::BoxFuture;
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    rngs::OsRng,
+};
+use sea_orm::{entity::prelude::*, sea_query::OnConflict, ActiveValue};
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::{
+    auth::{AuthUser, UserID},
+    db::{get_db, get_read_db},
+    TeachCore,
+};
+
+const PRODUCT_ID: &str = "-//teach-tech//calendar//EN";
+
+type EventSource =
+    Arc<dyn Fn(UserID) -> BoxFuture<'static, anyhow::Result<Vec<CalendarEvent>>> + Send + Sync>;
+
+static EVENT_SOURCES: Mutex<Vec<(String, EventSource)>> = Mutex::new(vec![]);
+
+/// The secret token of each user's calendar feed. Anyone with the token can read the feed, which
+/// is what lets calendar apps subscribe to it without signing in.
+#[derive(Clone, Debug, DeriveEntityModel)]
+#[sea_orm(table_name = "calendar_feeds")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub user_id: UserID,
+    #[sea_orm(unique)]
+    pub token: String,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Assignment,
+    Exam,
+    ClassMeeting,
+    Other,
+}
+
+impl EventKind {
+    fn category(self) -> &'static str {
+        match self {
+            EventKind::Assignment => "ASSIGNMENT",
+            EventKind::Exam => "EXAM",
+            EventKind::ClassMeeting => "CLASS",
+            EventKind::Other => "OTHER",
+        }
+    }
+}
+
+/// One event in a calendar feed. Times are in UTC.
+#[derive(Debug, Clone)]
+pub struct CalendarEvent {
+    /// Must stay the same across feeds for calendar apps to update the event instead of adding it
+    /// again. Prefixing it with the name of the event source keeps it unique
+    pub uid: String,
+    pub kind: EventKind,
+    pub summary: String,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub start: DateTime,
+    /// Deadlines such as assignments due dates have no end
+    pub end: Option<DateTime>,
+    /// An RFC 5545 recurrence rule without the `RRULE:` prefix, such as
+    /// `FREQ=WEEKLY;BYDAY=MO,WE;UNTIL=20261218T000000Z` for class meetings
+    pub recurrence: Option<String>,
+    pub link: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeedInfo {
+    /// The path of the feed, to be appended to the public address of the API
+    pub path: String,
+    pub created_at: DateTime,
+}
+
+impl From<Model> for FeedInfo {
+    fn from(model: Model) -> Self {
+        Self {
+            path: format!("/calendar/feed/{}.ics", model.token),
+            created_at: model.created_at,
+        }
+    }
+}
+
+/// Registers a function giving the events of a user, such as the due dates of their assignments.
+/// Every source is asked whenever a feed is fetched.
+pub fn add_event_source<F, Fut>(name: impl Into<String>, f: F)
+where
+    F: Fn(UserID) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<Vec<CalendarEvent>>> + Send + 'static,
+{
+    EVENT_SOURCES
+        .lock()
+        .unwrap()
+        .push((name.into(), Arc::new(move |user_id| Box::pin(f(user_id)))));
+}
+
+fn new_token() -> String {
+    Alphanumeric.sample_string(&mut OsRng, 32)
+}
+
+/// Replaces the user's feed token, so that the old feed address stops working.
+pub async fn regenerate_feed(user_id: UserID) -> Result<Model, DbErr> {
+    let model = Model {
+        user_id,
+        token: new_token(),
+        created_at: chrono::Utc::now().naive_utc(),
+    };
+    Entity::insert(ActiveModel {
+        user_id: ActiveValue::set(model.user_id),
+        token: ActiveValue::set(model.token.clone()),
+        created_at: ActiveValue::set(model.created_at),
+    })
+    .on_conflict(
+        OnConflict::column(Column::UserId)
+            .update_columns([Column::Token, Column::CreatedAt])
+            .to_owned(),
+    )
+    .exec_without_returning(get_db())
+    .await?;
+    Ok(model)
+}
+
+/// The user's feed, created on first use. Concurrent first requests end up with the same token.
+async fn feed_of(user_id: UserID) -> Result<Model, DbErr> {
+    if let Some(model) = Entity::find_by_id(user_id).one(get_db()).await? {
+        return Ok(model);
+    }
+    Entity::insert(ActiveModel {
+        user_id: ActiveValue::set(user_id),
+        token: ActiveValue::set(new_token()),
+        created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+    })
+    .on_conflict(OnConflict::column(Column::UserId).do_nothing().to_owned())
+    .exec_without_returning(get_db())
+    .await?;
+    Entity::find_by_id(user_id)
+        .one(get_db())
+        .await?
+        .ok_or_else(|| DbErr::RecordNotFound(format!("calendar feed of {user_id}")))
+}
+
+/// Escapes text for an iCalendar property value.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Whether `recurrence` only has the characters of an RFC 5545 recurrence rule, so that it cannot
+/// end the property and start another.
+fn is_valid_recurrence(recurrence: &str) -> bool {
+    !recurrence.is_empty()
+        && recurrence
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '=' | ';' | ',' | '-' | '+'))
+}
+
+/// Whether `link` can be written as a URI value, which is not escaped.
+fn is_valid_link(link: &str) -> bool {
+    !link.is_empty() && !link.chars().any(|c| c.is_control() || c.is_whitespace())
+}
+
+fn format_time(time: DateTime) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Appends a content line, folding it so that no line is longer than 75 octets.
+fn push_line(ics: &mut String, line: &str) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            ics.push_str("\r\n ");
+            // The space starting the continuation counts towards its length
+            width = 1;
+        }
+        ics.push(c);
+        width += c.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
+
+pub fn render(events: &[CalendarEvent]) -> String {
+    let stamp = format_time(chrono::Utc::now().naive_utc());
+    let mut ics = String::new();
+    push_line(&mut ics, "BEGIN:VCALENDAR");
+    push_line(&mut ics, "VERSION:2.0");
+    push_line(&mut ics, &format!("PRODID:{PRODUCT_ID}"));
+    push_line(&mut ics, "CALSCALE:GREGORIAN");
+    for event in events {
+        push_line(&mut ics, "BEGIN:VEVENT");
+        push_line(&mut ics, &format!("UID:{}", escape(&event.uid)));
+        push_line(&mut ics, &format!("DTSTAMP:{stamp}"));
+        push_line(&mut ics, &format!("DTSTART:{}", format_time(event.start)));
+        if let Some(end) = event.end {
+            push_line(&mut ics, &format!("DTEND:{}", format_time(end)));
+        }
+        if let Some(recurrence) = &event.recurrence {
+            if is_valid_recurrence(recurrence) {
+                push_line(&mut ics, &format!("RRULE:{recurrence}"));
+            } else {
+                warn!("Leaving out the invalid recurrence rule of {}", event.uid);
+            }
+        }
+        push_line(&mut ics, &format!("SUMMARY:{}", escape(&event.summary)));
+        push_line(&mut ics, &format!("CATEGORIES:{}", event.kind.category()));
+        if let Some(description) = &event.description {
+            push_line(&mut ics, &format!("DESCRIPTION:{}", escape(description)));
+        }
+        if let Some(location) = &event.location {
+            push_line(&mut ics, &format!("LOCATION:{}", escape(location)));
+        }
+        if let Some(link) = &event.link {
+            if is_valid_link(link) {
+                push_line(&mut ics, &format!("URL:{link}"));
+            } else {
+                warn!("Leaving out the invalid link of {}", event.uid);
+            }
+        }
+        push_line(&mut ics, "END:VEVENT");
+    }
+    push_line(&mut ics, "END:VCALENDAR");
+    ics
+}
+
+/// Every event of the user, from every event source.
+pub async fn events_of(user_id: UserID) -> anyhow::Result<Vec<CalendarEvent>> {
+    let sources = EVENT_SOURCES.lock().unwrap().clone();
+    let mut events = vec![];
+    for (name, source) in sources {
+        events.extend(
+            source(user_id)
+                .await
+                .map_err(|e| e.context(format!("Reading events from {name}")))?,
+        );
+    }
+    events.sort_by_key(|event| event.start);
+    Ok(events)
+}
+
+pub fn add_to_core<S: Clone + Send + Sync + 'static>(mut core: TeachCore<S>) -> TeachCore<S> {
+    core.add_db_reset_config(Entity);
+
+    core.modify_router(|router| {
+        router
+            .route(
+                "/calendar/feed",
+                get(|AuthUser { user_id, .. }: AuthUser| async move {
+                    match feed_of(user_id).await {
+                        Ok(model) => Json(FeedInfo::from(model)).into_response(),
+                        Err(e) => {
+                            error!("Error reading calendar feed of {user_id}: {e:#}");
+                            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+                        }
+                    }
+                }),
+            )
+            .route(
+                "/calendar/feed/regenerate",
+                post(|AuthUser { user_id, .. }: AuthUser| async move {
+                    match regenerate_feed(user_id).await {
+                        Ok(model) => Json(FeedInfo::from(model)).into_response(),
+                        Err(e) => {
+                            error!("Error regenerating calendar feed of {user_id}: {e:#}");
+                            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+                        }
+                    }
+                }),
+            )
+            .route(
+                "/calendar/feed/:file",
+                get(|Path(file): Path<String>| async move {
+                    let Some(token) = file.strip_suffix(".ics") else {
+                        return (StatusCode::NOT_FOUND, ()).into_response();
+                    };
+                    let feed = match Entity::find()
+                        .filter(Column::Token.eq(token))
+                        .one(get_read_db())
+                        .await
+                    {
+                        Ok(Some(feed)) => feed,
+                        Ok(None) => return (StatusCode::NOT_FOUND, ()).into_response(),
+                        Err(e) => {
+                            error!("Error reading calendar feed: {e:#}");
+                            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+                        }
+                    };
+                    // Failing keeps the events calendar apps already have, where leaving out a
+                    // source would make its events disappear until the next refresh
+                    match events_of(feed.user_id).await {
+                        Ok(events) => (
+                            StatusCode::OK,
+                            [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+                            render(&events),
+                        )
+                            .into_response(),
+                        Err(e) => {
+                            error!("Error building calendar feed of {}: {e:#}", feed.user_id);
+                            (StatusCode::SERVICE_UNAVAILABLE, ()).into_response()
+                        }
+                    }
+                }),
+            )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> CalendarEvent {
+        CalendarEvent {
+            uid: "test:1".to_string(),
+            kind: EventKind::ClassMeeting,
+            summary: "Algebra; room 2, \\ north\r\nX-INJECTED:1".to_string(),
+            description: None,
+            location: None,
+            start: chrono::NaiveDate::from_ymd_opt(2026, 9, 1)
+                .unwrap()
+                .and_hms_opt(9, 0, 0)
+                .unwrap(),
+            end: None,
+            recurrence: Some("FREQ=WEEKLY;BYDAY=MO,WE;UNTIL=20261218T000000Z".to_string()),
+            link: Some("https://school.example/courses/1?tab=work".to_string()),
+        }
+    }
+
+    #[test]
+    fn escapes_text() {
+        let ics = render(&[event()]);
+        assert!(ics.contains("SUMMARY:Algebra\\; room 2\\, \\\\ north\\nX-INJECTED:1\r\n"));
+        assert!(ics.contains("RRULE:FREQ=WEEKLY;BYDAY=MO,WE;UNTIL=20261218T000000Z\r\n"));
+        assert!(ics.contains("URL:https://school.example/courses/1?tab=work\r\n"));
+    }
+
+    #[test]
+    fn leaves_out_values_that_would_add_lines() {
+        let ics = render(&[CalendarEvent {
+            recurrence: Some("FREQ=DAILY\r\nX-INJECTED:1".to_string()),
+            link: Some("https://school.example/\nX-INJECTED:1".to_string()),
+            ..event()
+        }]);
+        assert!(!ics.contains("\nX-INJECTED"), "{ics}");
+        assert!(!ics.contains("RRULE") && !ics.contains("URL"), "{ics}");
+    }
+
+    #[test]
+    fn folds_long_lines() {
+        let ics = render(&[CalendarEvent {
+            summary: "é".repeat(100),
+            ..event()
+        }]);
+        assert!(ics.split("\r\n").all(|line| line.len() <= 75));
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap, StatusCode},
+};
+use ipnet::IpNet;
+use tracing::error;
+
+use crate::{ApiConfig, TeachCore};
+
+static TRUSTED_PROXIES: OnceLock<Vec<IpNet>> = OnceLock::new();
+
+/// The address of the client that made a request.
+///
+/// When the direct peer is one of the `trusted_proxies` in `teach-config.toml`, the address is
+/// read from the `Forwarded` header (or `X-Forwarded-For` if that is absent), skipping every hop
+/// that is itself a trusted proxy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+impl std::fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+pub fn is_trusted_proxy(ip: IpAddr) -> bool {
+    TRUSTED_PROXIES
+        .get()
+        .is_some_and(|proxies| proxies.iter().any(|net| net.contains(&ip)))
+}
+
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    // Bracketed IPv6 without a port, eg. `[2001:db8::1]`
+    node.strip_prefix('[')
+        .and_then(|node| node.strip_suffix(']'))
+        .and_then(|node| node.parse().ok())
+}
+
+// Ordered from the original client to the most recent proxy. Entries that cannot be parsed
+// (obfuscated identifiers, `unknown`) are kept as `None` so the chain is not silently shortened.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<_> = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| parse_forwarded_node(node))
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(parse_forwarded_node)
+        .collect()
+}
+
+pub fn resolve_client_ip(peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    if !is_trusted_proxy(peer) {
+        return peer;
+    }
+    let mut client = peer;
+    for hop in forwarded_chain(headers).into_iter().rev() {
+        let Some(hop) = hop else {
+            break;
+        };
+        client = hop;
+        if !is_trusted_proxy(hop) {
+            break;
+        }
+    }
+    client
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(ConnectInfo(peer)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() else {
+            error!("Connection info is missing; was the router served with connect info?");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        Ok(Self(resolve_client_ip(peer.ip(), &parts.headers)))
+    }
+}
+
+pub fn add_to_core<S>(core: TeachCore<S>) -> anyhow::Result<TeachCore<S>> {
+    let api_config: ApiConfig = toml::from_str(core.get_config_str())?;
+    TRUSTED_PROXIES
+        .set(api_config.trusted_proxies)
+        .expect("Trusted proxies are already initialized");
+    Ok(core)
+}
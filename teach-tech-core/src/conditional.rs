@@ -0,0 +1,70 @@
+use std::{fmt::Write, time::SystemTime};
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use axum_extra::headers::{ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, LastModified};
+use ring::digest::{digest, SHA256};
+use sea_orm::prelude::DateTime;
+use serde::Serialize;
+use tracing::error;
+
+/// Responds with `value` as JSON, or with 304 Not Modified when the client's `If-None-Match` or
+/// `If-Modified-Since` shows that it already has this response.
+///
+/// The ETag is a hash of the body, so it changes with anything in the response. `last_modified`
+/// should be the latest change to the data in `value`, as UTC.
+pub fn json<T: Serialize>(
+    request_headers: &HeaderMap,
+    last_modified: Option<DateTime>,
+    value: &T,
+) -> Response {
+    let body = match serde_json::to_vec(value) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Error serializing response: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+    let mut etag = String::from("\"");
+    for byte in &digest(&SHA256, &body).as_ref()[..16] {
+        let _ = write!(etag, "{byte:02x}");
+    }
+    etag.push('"');
+    let etag: ETag = etag.parse().expect("Hex ETags are valid");
+    let last_modified = last_modified.map(|time| SystemTime::from(time.and_utc()));
+
+    // If-Modified-Since is only used when there is no If-None-Match, as RFC 9110 asks
+    let not_modified = match request_headers.typed_get::<IfNoneMatch>() {
+        Some(if_none_match) => !if_none_match.precondition_passes(&etag),
+        None => match (
+            request_headers.typed_get::<IfModifiedSince>(),
+            last_modified,
+        ) {
+            (Some(if_modified_since), Some(last_modified)) => {
+                !if_modified_since.is_modified(last_modified)
+            }
+            _ => false,
+        },
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.typed_insert(etag);
+    if let Some(last_modified) = last_modified {
+        headers.typed_insert(LastModified::from(last_modified));
+    }
+    // Responses are per user, and should be revalidated every time
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, no-cache"),
+    );
+    if not_modified {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    (StatusCode::OK, headers, body).into_response()
+}
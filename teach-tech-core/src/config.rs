@@ -0,0 +1,111 @@
+use std::any::type_name;
+
+use serde::de::DeserializeOwned;
+
+type Check = Box<dyn Fn(&str) -> SchemaCheck + Send>;
+
+/// A config struct that some part of the API deserializes from `teach-config.toml`.
+pub(crate) struct ConfigSchema {
+    pub name: &'static str,
+    check: Check,
+}
+
+struct SchemaCheck {
+    error: Option<String>,
+    ignored: Vec<String>,
+}
+
+impl ConfigSchema {
+    pub fn new<T: DeserializeOwned>() -> Self {
+        Self {
+            name: type_name::<T>(),
+            check: Box::new(|config| {
+                let mut ignored = vec![];
+                let result: Result<T, _> =
+                    serde_ignored::deserialize(toml::Deserializer::new(config), |path| {
+                        ignored.push(path.to_string())
+                    });
+                SchemaCheck {
+                    error: result.err().map(|e| e.to_string()),
+                    ignored,
+                }
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConfigReport {
+    pub errors: Vec<String>,
+    pub unknown_keys: Vec<String>,
+}
+
+impl ConfigReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty() && self.unknown_keys.is_empty()
+    }
+}
+
+impl std::fmt::Display for ConfigReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_ok() {
+            return writeln!(f, "teach-config.toml is valid");
+        }
+        for error in &self.errors {
+            writeln!(f, "error: {}", error.trim_end())?;
+        }
+        for key in &self.unknown_keys {
+            writeln!(f, "error: unknown key `{key}`")?;
+        }
+        Ok(())
+    }
+}
+
+fn covers(ignored: &str, path: &str) -> bool {
+    path == ignored
+        || path
+            .strip_prefix(ignored)
+            .is_some_and(|rest| rest.starts_with('.'))
+}
+
+pub(crate) fn check_config(config: &str, schemas: &[ConfigSchema]) -> ConfigReport {
+    let mut report = ConfigReport::default();
+    if let Err(e) = config.parse::<toml::Table>() {
+        report
+            .errors
+            .push(format!("teach-config.toml is not valid TOML: {e}"));
+        return report;
+    }
+
+    let checks: Vec<_> = schemas
+        .iter()
+        .map(|schema| (schema.name, (schema.check)(config)))
+        .collect();
+    for (name, check) in &checks {
+        if let Some(error) = &check.error {
+            report.errors.push(format!("{name}: {error}"));
+        }
+    }
+
+    // Every schema only reads some of the keys, so a key is unknown when all schemas ignore it
+    for (_, check) in &checks {
+        for path in &check.ignored {
+            let unknown = checks
+                .iter()
+                .all(|(_, other)| other.ignored.iter().any(|ignored| covers(ignored, path)));
+            if unknown && !report.unknown_keys.contains(path) {
+                report.unknown_keys.push(path.clone());
+            }
+        }
+    }
+    // Only report the most specific path when a whole table is unknown to some schemas
+    let unknown_keys = report.unknown_keys.clone();
+    report.unknown_keys.retain(|key| {
+        !unknown_keys
+            .iter()
+            .any(|other| other != key && covers(key, other))
+    });
+    report.unknown_keys.sort();
+
+    report
+}
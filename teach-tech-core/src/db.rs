@@ -1,10 +1,59 @@
-use std::sync::OnceLock;
+mod export;
+mod pagination;
+mod pool_stats;
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
 
 use anyhow::Context;
-use sea_orm::{ConnectOptions, Database, DatabaseConnection};
+use axum::{
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use fxhash::FxHashMap;
+use rand::{thread_rng, Rng};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, ConnectOptions, ConnectionTrait, Database, DatabaseBackend,
+    DatabaseConnection, DatabaseTransaction, DbErr, EntityTrait, IntoActiveModel, PrimaryKeyTrait,
+    QueryFilter, RuntimeErr, Select, Statement, TransactionError, TransactionTrait, Value,
+};
 use serde::Deserialize;
+use tracing::{error, info, warn};
+
+use crate::TeachCore;
+
+pub use export::{stream_export, ExportFormat, ExportQuery};
+pub use pagination::{paginate, PageQuery, Paginated};
+pub use pool_stats::{pool_stats, PoolStats};
 
 static MAIN_DB: OnceLock<DatabaseConnection> = OnceLock::new();
+static REPLICAS: OnceLock<Vec<Replica>> = OnceLock::new();
+static NEXT_REPLICA: AtomicUsize = AtomicUsize::new(0);
+static NAMED_DBS: OnceLock<FxHashMap<String, DatabaseConnection>> = OnceLock::new();
+static AVAILABLE: AtomicBool = AtomicBool::new(true);
+const REPLICA_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const TRANSACTION_ATTEMPTS: u32 = 5;
+const TRANSACTION_BACKOFF: Duration = Duration::from_millis(20);
+// Keeps statements well under the bind parameter limits of every backend
+const INSERT_BATCH_SIZE: usize = 500;
+
+type TransactionFuture<'c, T> = Pin<Box<dyn Future<Output = Result<T, DbErr>> + Send + 'c>>;
+
+struct Replica {
+    url: String,
+    conn: DatabaseConnection,
+    healthy: AtomicBool,
+}
 
 pub fn get_db() -> &'static DatabaseConnection {
     MAIN_DB
@@ -12,18 +61,551 @@ pub fn get_db() -> &'static DatabaseConnection {
         .expect("Database was not initialized. Call init_db first")
 }
 
+/// Returns the connection configured under `[database.connections]` with the given name.
+///
+/// Falls back to the main database when no such connection is configured, so integrations can
+/// always ask for their own database and leave it to operators whether to split it out.
+pub fn get_named_db(name: &str) -> &'static DatabaseConnection {
+    NAMED_DBS
+        .get()
+        .and_then(|dbs| dbs.get(name))
+        .unwrap_or_else(get_db)
+}
+
+/// Returns a connection for reads that can tolerate some replication lag.
+///
+/// Replicas are used round-robin while their lag is within `max_replica_lag_secs`, falling back
+/// to the primary database when none are healthy or none are configured.
+pub fn get_read_db() -> &'static DatabaseConnection {
+    let replicas = REPLICAS.get().map(Vec::as_slice).unwrap_or_default();
+    let start = NEXT_REPLICA.fetch_add(1, Ordering::Relaxed);
+    (0..replicas.len())
+        .map(|i| &replicas[(start + i) % replicas.len()])
+        .find(|replica| replica.healthy.load(Ordering::Relaxed))
+        .map(|replica| &replica.conn)
+        .unwrap_or_else(get_db)
+}
+
+/// An entity whose rows are marked with a `deleted_at` timestamp instead of being removed.
+///
+/// `find` and `find_by_id` cannot be made to skip deleted rows, so they must not be used on these
+/// entities. Use `find_live` and `find_live_by_id` instead, and `with_deleted` where deleted rows
+/// must still be visible.
+pub trait SoftDeletable: EntityTrait {
+    /// A nullable `DateTime` column that is set when the row is deleted
+    const DELETED_AT: Self::Column;
+
+    fn find_live() -> Select<Self> {
+        Self::find().filter(Self::DELETED_AT.is_null())
+    }
+
+    fn find_live_by_id<T>(id: T) -> Select<Self>
+    where
+        T: Into<<Self::PrimaryKey as PrimaryKeyTrait>::ValueType>,
+    {
+        Self::find_by_id(id).filter(Self::DELETED_AT.is_null())
+    }
+
+    fn with_deleted() -> Select<Self> {
+        Self::find()
+    }
+}
+
+/// An active model whose `created_at` and `updated_at` columns are kept up to date on save.
+///
+/// Implement this with [`timestamped_active_model!`](crate::timestamped_active_model), which also
+/// provides the `ActiveModelBehavior` that calls [`Timestamped::touch`].
+pub trait Timestamped: ActiveModelTrait {
+    const CREATED_AT: <Self::Entity as EntityTrait>::Column;
+    const UPDATED_AT: <Self::Entity as EntityTrait>::Column;
+
+    /// Sets `updated_at` to now, and `created_at` too when inserting without one
+    fn touch(mut self, insert: bool) -> Self {
+        let now = Value::from(chrono::Utc::now().naive_utc());
+        if insert && self.is_not_set(Self::CREATED_AT) {
+            self.set(Self::CREATED_AT, now.clone());
+        }
+        self.set(Self::UPDATED_AT, now);
+        self
+    }
+}
+
+/// Implements [`Timestamped`] and `ActiveModelBehavior` for the `ActiveModel` in scope, which
+/// must have `CreatedAt` and `UpdatedAt` columns.
+#[macro_export]
+macro_rules! timestamped_active_model {
+    () => {
+        impl $crate::db::Timestamped for ActiveModel {
+            const CREATED_AT: Column = Column::CreatedAt;
+            const UPDATED_AT: Column = Column::UpdatedAt;
+        }
+
+        #[$crate::sea_orm::prelude::async_trait::async_trait]
+        impl $crate::sea_orm::ActiveModelBehavior for ActiveModel {
+            async fn before_save<C>(
+                self,
+                _db: &C,
+                insert: bool,
+            ) -> Result<Self, $crate::sea_orm::DbErr>
+            where
+                C: $crate::sea_orm::ConnectionTrait,
+            {
+                Ok($crate::db::Timestamped::touch(self, insert))
+            }
+        }
+    };
+}
+
+/// Inserts `models` with as few multi-row `INSERT` statements as possible.
+///
+/// Unlike `ActiveModel::insert`, this does not call `ActiveModelBehavior::before_save`, so columns
+/// it sets, such as timestamps, must be set beforehand (see [`Timestamped::touch`]).
+pub async fn insert_batched<A>(
+    models: impl IntoIterator<Item = A>,
+    db: &impl ConnectionTrait,
+) -> Result<(), DbErr>
+where
+    A: ActiveModelTrait,
+    <A::Entity as EntityTrait>::Model: IntoActiveModel<A>,
+{
+    let mut models: Vec<A> = models.into_iter().collect();
+    while !models.is_empty() {
+        let rest = models.split_off(models.len().min(INSERT_BATCH_SIZE));
+        let batch = std::mem::replace(&mut models, rest);
+        A::Entity::insert_many(batch)
+            .exec_without_returning(db)
+            .await?;
+    }
+    Ok(())
+}
+
+// Serialization failures and deadlocks on Postgres and MySQL, and busy or locked databases on SQLite
+fn is_retryable(e: &DbErr) -> bool {
+    let (DbErr::Exec(RuntimeErr::SqlxError(sea_orm::sqlx::Error::Database(e)))
+    | DbErr::Query(RuntimeErr::SqlxError(sea_orm::sqlx::Error::Database(e)))) = e
+    else {
+        return false;
+    };
+    matches!(
+        e.code().as_deref(),
+        Some("40001" | "40P01" | "5" | "6" | "517")
+    )
+}
+
+/// Runs `f` in a transaction, running it again in a new transaction when the database aborts it
+/// because of a conflict with another transaction.
+///
+/// `f` may be called several times, so it should not have side effects outside the transaction.
+pub async fn transaction_with_retry<F, T>(
+    db: &DatabaseConnection,
+    f: F,
+) -> Result<T, TransactionError<DbErr>>
+where
+    F: for<'c> Fn(&'c DatabaseTransaction) -> TransactionFuture<'c, T> + Send + Sync,
+    T: Send,
+{
+    let mut attempt = 1;
+    loop {
+        let result = db.transaction(|txn| f(txn)).await;
+        let retryable = match &result {
+            Ok(_) => false,
+            Err(TransactionError::Connection(e) | TransactionError::Transaction(e)) => {
+                is_retryable(e)
+            }
+        };
+        if !retryable || attempt >= TRANSACTION_ATTEMPTS {
+            return result;
+        }
+        let backoff = TRANSACTION_BACKOFF * 2u32.pow(attempt - 1);
+        let jitter = thread_rng().gen_range(Duration::ZERO..backoff);
+        warn!("Transaction conflicted with another (attempt {attempt}). Retrying");
+        tokio::time::sleep(backoff + jitter).await;
+        attempt += 1;
+    }
+}
+
+/// Whether the last health check could reach the main database.
+pub fn is_available() -> bool {
+    AVAILABLE.load(Ordering::Relaxed)
+}
+
+/// Responds with 503 instead of running handlers while the main database is unreachable.
+pub async fn reject_while_unavailable(request: Request, next: Next) -> Response {
+    if is_available() || matches!(request.uri().path(), "/info" | "/health" | "/metrics") {
+        return next.run(request).await;
+    }
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(
+            header::RETRY_AFTER,
+            HEALTH_CHECK_INTERVAL.as_secs().to_string(),
+        )],
+    )
+        .into_response()
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct DBConfig {
+    /// A Postgres, MySQL or SQLite url.
+    ///
+    /// For local development, `sqlite://teach.db?mode=rwc` keeps everything in one file that is
+    /// created on first use, with no database server to run.
     pub database_url: String,
+    #[serde(default)]
+    pub database: DatabaseOptions,
+}
+
+/// The `[database]` section of `teach-config.toml`. Unset options keep the SQLx defaults.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DatabaseOptions {
+    pub max_connections: Option<u32>,
+    pub min_connections: Option<u32>,
+    pub connect_timeout_secs: Option<u64>,
+    pub acquire_timeout_secs: Option<u64>,
+    pub idle_timeout_secs: Option<u64>,
+    pub max_lifetime_secs: Option<u64>,
+    /// Only supported on Postgres, where it is passed as a connection option
+    pub statement_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub replica_urls: Vec<String>,
+    #[serde(default = "default_max_replica_lag_secs")]
+    pub max_replica_lag_secs: f64,
+    #[serde(default)]
+    pub connections: FxHashMap<String, String>,
+    /// How many times to try connecting at startup before giving up
+    #[serde(default = "default_connect_attempts")]
+    pub connect_attempts: u32,
+    pub logging: Option<QueryLogging>,
+}
+
+/// The `[database.logging]` section. Statements that take longer than the threshold are logged
+/// under the `sqlx::query` target with their duration and row counts.
+#[derive(Debug, Clone, Deserialize)]
+pub struct QueryLogging {
+    pub slow_query_threshold_ms: u64,
+    #[serde(default = "default_slow_query_level")]
+    pub level: log::LevelFilter,
+}
+
+fn default_slow_query_level() -> log::LevelFilter {
+    log::LevelFilter::Warn
+}
+
+fn default_max_replica_lag_secs() -> f64 {
+    10.0
+}
+
+fn default_connect_attempts() -> u32 {
+    5
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            min_connections: None,
+            connect_timeout_secs: None,
+            acquire_timeout_secs: None,
+            idle_timeout_secs: None,
+            max_lifetime_secs: None,
+            statement_timeout_ms: None,
+            replica_urls: vec![],
+            max_replica_lag_secs: default_max_replica_lag_secs(),
+            connections: FxHashMap::default(),
+            connect_attempts: default_connect_attempts(),
+            logging: None,
+        }
+    }
+}
+
+impl DatabaseOptions {
+    pub fn connect_options(&self, url: &str) -> anyhow::Result<ConnectOptions> {
+        let mut url = url.to_string();
+        if let Some(timeout) = self.statement_timeout_ms {
+            if backend_from_url(&url)? == DatabaseBackend::Postgres {
+                url.push(if url.contains('?') { '&' } else { '?' });
+                url.push_str(&format!("options=-c%20statement_timeout%3D{timeout}"));
+            } else {
+                warn!("statement_timeout_ms is only supported on Postgres and will be ignored");
+            }
+        }
+
+        if backend_from_url(&url)? == DatabaseBackend::Sqlite {
+            url = prepare_sqlite_url(&url)?;
+        }
+
+        let mut opt = ConnectOptions::new(url);
+        match &self.logging {
+            Some(logging) => {
+                opt.sqlx_logging(true)
+                    .sqlx_logging_level(log::LevelFilter::Off)
+                    .sqlx_slow_statements_logging_settings(
+                        logging.level,
+                        Duration::from_millis(logging.slow_query_threshold_ms),
+                    );
+            }
+            None => {
+                opt.sqlx_logging(false);
+            }
+        }
+        if let Some(n) = self.max_connections {
+            opt.max_connections(n);
+        }
+        if let Some(n) = self.min_connections {
+            opt.min_connections(n);
+        }
+        if let Some(secs) = self.connect_timeout_secs {
+            opt.connect_timeout(Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.acquire_timeout_secs {
+            opt.acquire_timeout(Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.idle_timeout_secs {
+            opt.idle_timeout(Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.max_lifetime_secs {
+            opt.max_lifetime(Duration::from_secs(secs));
+        }
+        Ok(opt)
+    }
+
+    async fn connect(&self, url: &str) -> anyhow::Result<DatabaseConnection> {
+        let opt = self.connect_options(url)?;
+        let mut delay = Duration::from_secs(1);
+        let mut attempt = 1;
+        loop {
+            match Database::connect(opt.clone()).await {
+                Ok(conn) => {
+                    if conn.get_database_backend() == DatabaseBackend::Sqlite {
+                        enable_sqlite_wal(&conn).await?;
+                    }
+                    return Ok(conn);
+                }
+                Err(e) if attempt < self.connect_attempts => {
+                    warn!(
+                        "Failed to connect to {} (attempt {attempt}): {e}. Retrying in {}s",
+                        redact_url(url),
+                        delay.as_secs()
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+fn is_sqlite_memory(path: &str) -> bool {
+    path.is_empty() || path.starts_with(":memory:")
+}
+
+// Creates the database file (and the folder it is in) on first use when no mode is given
+fn prepare_sqlite_url(url: &str) -> anyhow::Result<String> {
+    let rest = url
+        .strip_prefix("sqlite://")
+        .or_else(|| url.strip_prefix("sqlite:"))
+        .unwrap_or(url);
+    let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+    if is_sqlite_memory(path) {
+        return Ok(url.to_string());
+    }
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Creating folder for {path}"))?;
+        }
+    }
+    if query.split('&').any(|param| param.starts_with("mode=")) {
+        return Ok(url.to_string());
+    }
+    Ok(format!(
+        "{url}{}mode=rwc",
+        if query.is_empty() { "?" } else { "&" }
+    ))
+}
+
+// WAL lets reads continue while another connection in the pool writes. It is stored in the
+// database file, unlike foreign key enforcement, which SQLx already turns on for every connection.
+async fn enable_sqlite_wal(conn: &DatabaseConnection) -> Result<(), DbErr> {
+    let row = conn
+        .query_one(Statement::from_string(
+            DatabaseBackend::Sqlite,
+            "PRAGMA journal_mode = WAL",
+        ))
+        .await?;
+    let mode = row
+        .map(|row| row.try_get::<String>("", "journal_mode"))
+        .transpose()?
+        .unwrap_or_default();
+    // In-memory databases cannot use WAL and keep their own journal mode
+    if mode != "wal" && mode != "memory" {
+        warn!("SQLite database is using journal mode {mode} instead of WAL");
+    }
+    Ok(())
+}
+
+// Lets commands that never connect (such as `routes`) build the schema for the configured database
+pub fn backend_from_config(config: &str) -> anyhow::Result<DatabaseBackend> {
+    let db_config: DBConfig = toml::from_str(config)?;
+    backend_from_url(&db_config.database_url)
+}
+
+pub fn backend_from_url(url: &str) -> anyhow::Result<DatabaseBackend> {
+    let scheme = url
+        .split_once(':')
+        .map(|(scheme, _)| scheme)
+        .unwrap_or_default();
+    match scheme {
+        "postgres" | "postgresql" => Ok(DatabaseBackend::Postgres),
+        "mysql" | "mariadb" => Ok(DatabaseBackend::MySql),
+        "sqlite" => Ok(DatabaseBackend::Sqlite),
+        _ => Err(anyhow::anyhow!("Unsupported database url scheme: {scheme}")),
+    }
 }
 
 pub async fn init_db(config: &str) -> anyhow::Result<()> {
     let db_config: DBConfig = toml::from_str(config)?;
-    let mut opt = ConnectOptions::new(db_config.database_url);
-    opt.sqlx_logging(false);
-    let conn = Database::connect(opt)
+    let conn = db_config
+        .database
+        .connect(&db_config.database_url)
         .await
         .context("Connecting to database")?;
     MAIN_DB.set(conn).expect("Database is already initialized");
+
+    let mut replicas = vec![];
+    for url in &db_config.database.replica_urls {
+        let conn = db_config
+            .database
+            .connect(url)
+            .await
+            .with_context(|| format!("Connecting to replica {}", redact_url(url)))?;
+        replicas.push(Replica {
+            url: redact_url(url),
+            conn,
+            // Replicas are only used once the monitor has confirmed that they are caught up
+            healthy: AtomicBool::new(false),
+        });
+    }
+    let _ = REPLICAS.set(replicas);
+
+    let mut named = FxHashMap::default();
+    for (name, url) in &db_config.database.connections {
+        let conn = db_config
+            .database
+            .connect(url)
+            .await
+            .with_context(|| format!("Connecting to database {name}"))?;
+        named.insert(name.clone(), conn);
+    }
+    let _ = NAMED_DBS.set(named);
     Ok(())
 }
+
+fn redact_url(url: &str) -> String {
+    match url.split_once('@') {
+        Some((credentials, host)) => match credentials.split_once("://") {
+            Some((scheme, _)) => format!("{scheme}://***@{host}"),
+            None => format!("***@{host}"),
+        },
+        None => url.to_string(),
+    }
+}
+
+async fn replica_lag_secs(conn: &DatabaseConnection) -> Result<f64, DbErr> {
+    let backend = conn.get_database_backend();
+    let lag = match backend {
+        DatabaseBackend::Postgres => {
+            let row = conn
+                .query_one(Statement::from_string(
+                    backend,
+                    "SELECT CASE WHEN pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0 \
+                     ELSE COALESCE(EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()), 0) \
+                     END::float8 AS lag",
+                ))
+                .await?;
+            row.map(|row| row.try_get::<f64>("", "lag")).transpose()?
+        }
+        DatabaseBackend::MySql => {
+            let row = conn
+                .query_one(Statement::from_string(backend, "SHOW REPLICA STATUS"))
+                .await?;
+            row.map(|row| row.try_get::<Option<i64>>("", "Seconds_Behind_Source"))
+                .transpose()?
+                .flatten()
+                .map(|lag| lag as f64)
+        }
+        // SQLite has no replication, so the replica is whatever file the url points to
+        DatabaseBackend::Sqlite => Some(0.0),
+    };
+    lag.ok_or_else(|| DbErr::Custom("Replication is not running".into()))
+}
+
+async fn monitor_replicas(max_lag_secs: f64) {
+    let Some(replicas) = REPLICAS.get() else {
+        return;
+    };
+    loop {
+        for replica in replicas {
+            let healthy = match replica_lag_secs(&replica.conn).await {
+                Ok(lag) => {
+                    if lag > max_lag_secs {
+                        warn!("Replica {} is {lag:.1}s behind", replica.url);
+                    }
+                    lag <= max_lag_secs
+                }
+                Err(e) => {
+                    error!("Error checking lag of replica {}: {e:#}", replica.url);
+                    false
+                }
+            };
+            if replica.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                info!(
+                    "Replica {} is now {}",
+                    replica.url,
+                    if healthy { "in use" } else { "bypassed" }
+                );
+            }
+        }
+        tokio::time::sleep(REPLICA_CHECK_INTERVAL).await;
+    }
+}
+
+// The pool replaces broken connections by itself, so this only decides whether to short-circuit
+// requests until a connection succeeds again
+async fn monitor_health() {
+    loop {
+        let available = match get_db().ping().await {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Error pinging database: {e}");
+                false
+            }
+        };
+        if AVAILABLE.swap(available, Ordering::Relaxed) != available {
+            if available {
+                info!("Database is reachable again");
+            } else {
+                warn!("Database is unreachable. Requests will be rejected until it recovers");
+            }
+        }
+        pool_stats::sample_all().await;
+        tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+    }
+}
+
+pub fn add_to_core<S>(mut core: TeachCore<S>) -> anyhow::Result<TeachCore<S>> {
+    let db_config: DBConfig = toml::from_str(core.get_config_str())?;
+    core.add_on_serve_named("db-monitor", 0, || async {
+        tokio::spawn(monitor_health());
+        Ok(())
+    });
+    if !db_config.database.replica_urls.is_empty() {
+        core.add_on_serve_named("replica-monitor", 0, move || async move {
+            tokio::spawn(monitor_replicas(db_config.database.max_replica_lag_secs));
+            Ok(())
+        });
+    }
+    Ok(core)
+}
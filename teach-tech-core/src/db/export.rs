@@ -0,0 +1,188 @@
+use std::io;
+
+use axum::{
+    body::Body,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use sea_orm::{ColumnTrait, DatabaseConnection, DbErr, EntityTrait, IntoIdentity, Select, Value};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+// Rows fetched per query, which bounds how much of an export is in memory at once
+const EXPORT_PAGE_SIZE: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    /// One JSON object per line
+    #[default]
+    Ndjson,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// The query parameters of an export endpoint.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+struct ExportState<E: EntityTrait, K, F> {
+    select: Select<E>,
+    key: E::Column,
+    key_of: F,
+    cursor: Option<K>,
+    format: ExportFormat,
+    /// The CSV columns, taken from the first row
+    columns: Option<Vec<String>>,
+    done: bool,
+    db: &'static DatabaseConnection,
+}
+
+fn csv_field(value: &serde_json::Value) -> String {
+    let field = match value {
+        serde_json::Value::Null => return String::new(),
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+fn csv_line(fields: impl Iterator<Item = String>, out: &mut Vec<u8>) {
+    let line: Vec<_> = fields.collect();
+    out.extend_from_slice(line.join(",").as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+impl<E, K, F> ExportState<E, K, F>
+where
+    E: EntityTrait,
+    E::Model: Serialize + Sync,
+    E::Column: ColumnTrait + IntoIdentity,
+    K: Into<Value>,
+    F: Fn(&E::Model) -> K,
+{
+    async fn next_chunk(&mut self) -> Result<Vec<u8>, DbErr> {
+        let mut query = self.select.clone().cursor_by(self.key);
+        if let Some(cursor) = self.cursor.take() {
+            query.after(cursor);
+        }
+        let models = query.first(EXPORT_PAGE_SIZE).all(self.db).await?;
+        self.done = (models.len() as u64) < EXPORT_PAGE_SIZE;
+        self.cursor = models.last().map(&self.key_of);
+
+        let mut chunk = vec![];
+        for model in &models {
+            match self.format {
+                ExportFormat::Ndjson => {
+                    serde_json::to_writer(&mut chunk, model)
+                        .map_err(|e| DbErr::Custom(e.to_string()))?;
+                    chunk.push(b'\n');
+                }
+                ExportFormat::Csv => {
+                    let serde_json::Value::Object(row) =
+                        serde_json::to_value(model).map_err(|e| DbErr::Custom(e.to_string()))?
+                    else {
+                        return Err(DbErr::Custom("Exported rows must be structs".into()));
+                    };
+                    let columns = self.columns.get_or_insert_with(|| {
+                        let columns: Vec<_> = row.keys().cloned().collect();
+                        csv_line(
+                            columns
+                                .iter()
+                                .map(|column| csv_field(&column.as_str().into())),
+                            &mut chunk,
+                        );
+                        columns
+                    });
+                    csv_line(
+                        columns
+                            .iter()
+                            .map(|column| row.get(column).map(csv_field).unwrap_or_default()),
+                        &mut chunk,
+                    );
+                }
+            }
+        }
+        Ok(chunk)
+    }
+}
+
+/// Streams every row of `select` as a download named `name`, ordered by the unique column `key`.
+///
+/// Rows are read a page at a time as the client receives them, so the export is never held in
+/// memory as a whole. An error part way through ends the response early, which clients see as a
+/// truncated download.
+pub fn stream_export<E, K>(
+    name: &str,
+    select: Select<E>,
+    key: E::Column,
+    key_of: impl Fn(&E::Model) -> K + Send + 'static,
+    format: ExportFormat,
+    db: &'static DatabaseConnection,
+) -> Response
+where
+    E: EntityTrait,
+    E::Model: Serialize + Sync,
+    E::Column: ColumnTrait + IntoIdentity,
+    K: Into<Value> + Send + 'static,
+{
+    let state = ExportState {
+        select,
+        key,
+        key_of,
+        cursor: None,
+        format,
+        columns: None,
+        done: false,
+        db,
+    };
+    let stream = futures::stream::unfold(state, |mut state| async move {
+        if state.done {
+            return None;
+        }
+        match state.next_chunk().await {
+            Ok(chunk) => Some((Ok(chunk), state)),
+            Err(e) => {
+                error!("Error streaming export: {e:#}");
+                state.done = true;
+                Some((Err(io::Error::other(e)), state))
+            }
+        }
+    })
+    // Compression polls the body again after it ends
+    .fuse();
+    (
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{name}.{}\"", format.extension()),
+            ),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response()
+}
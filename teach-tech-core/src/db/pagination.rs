@@ -0,0 +1,90 @@
+use std::fmt::Write;
+
+use anyhow::Context;
+use sea_orm::{ColumnTrait, ConnectionTrait, DbErr, EntityTrait, IntoIdentity, Select, Value};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+const DEFAULT_PAGE_SIZE: u64 = 50;
+const MAX_PAGE_SIZE: u64 = 500;
+
+/// The query parameters of a paginated listing endpoint.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PageQuery {
+    pub cursor: Option<String>,
+    pub limit: Option<u64>,
+}
+
+impl PageQuery {
+    pub fn limit(&self) -> u64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// Decodes the cursor returned with the previous page. Clients should respond with 400 Bad
+    /// Request when this fails.
+    pub fn cursor<K: DeserializeOwned>(&self) -> anyhow::Result<Option<K>> {
+        self.cursor.as_deref().map(decode_cursor).transpose()
+    }
+}
+
+/// A page of a listing. `next_cursor` is `None` on the last page.
+#[derive(Debug, Clone, Serialize)]
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+// Cursors are opaque to clients, so they are hex encoded to discourage building them by hand
+fn encode_cursor(key: &impl Serialize) -> String {
+    let mut cursor = String::new();
+    for byte in serde_json::to_vec(key).expect("Serializing cursor") {
+        let _ = write!(cursor, "{byte:02x}");
+    }
+    cursor
+}
+
+fn decode_cursor<K: DeserializeOwned>(cursor: &str) -> anyhow::Result<K> {
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| {
+            cursor
+                .get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect::<Option<Vec<_>>>()
+        .context("Invalid cursor")?;
+    serde_json::from_slice(&bytes).context("Invalid cursor")
+}
+
+/// Returns the page of `select` that follows `cursor`, ordered by the unique column `key`.
+///
+/// `key_of` must return the value of `key` for a model, which becomes the cursor of the next page.
+pub async fn paginate<E, K>(
+    select: Select<E>,
+    key: E::Column,
+    key_of: impl Fn(&E::Model) -> K,
+    cursor: Option<K>,
+    limit: u64,
+    db: &impl ConnectionTrait,
+) -> Result<Paginated<E::Model>, DbErr>
+where
+    E: EntityTrait,
+    E::Model: Sync,
+    E::Column: ColumnTrait + IntoIdentity,
+    K: Into<Value> + Serialize,
+{
+    let mut query = select.cursor_by(key);
+    if let Some(cursor) = cursor {
+        query.after(cursor);
+    }
+    // Fetching one extra row tells whether there is another page without a count query
+    let mut items = query.first(limit + 1).all(db).await?;
+    let next_cursor = if items.len() as u64 > limit {
+        items.truncate(limit as usize);
+        items.last().map(|model| encode_cursor(&key_of(model)))
+    } else {
+        None
+    };
+    Ok(Paginated { items, next_cursor })
+}
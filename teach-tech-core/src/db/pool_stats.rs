@@ -0,0 +1,125 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use fxhash::FxHashMap;
+use sea_orm::{ConnectionTrait, DatabaseBackend, DatabaseConnection};
+use serde::Serialize;
+use tracing::warn;
+
+use super::{get_db, NAMED_DBS, REPLICAS};
+
+static SAMPLES: Mutex<Option<FxHashMap<String, AcquireSample>>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Default)]
+struct AcquireSample {
+    wait: Duration,
+    errors: u64,
+}
+
+/// The state of one connection pool, as reported by `/health` and `/metrics`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStats {
+    pub pool: String,
+    pub size: u32,
+    pub idle: usize,
+    pub max_size: u32,
+    /// How long the last health check waited for a connection
+    pub acquire_wait_ms: f64,
+    /// How many health checks could not get a connection
+    pub acquire_errors: u64,
+}
+
+fn connections() -> Vec<(String, &'static DatabaseConnection)> {
+    let mut connections = vec![("primary".to_string(), get_db())];
+    for replica in REPLICAS.get().into_iter().flatten() {
+        connections.push((format!("replica {}", replica.url), &replica.conn));
+    }
+    for (name, conn) in NAMED_DBS.get().into_iter().flatten() {
+        connections.push((name.clone(), conn));
+    }
+    connections
+}
+
+// Returns the size, idle count and maximum size of the pool
+fn pool_size(conn: &DatabaseConnection) -> (u32, usize, u32) {
+    match conn.get_database_backend() {
+        DatabaseBackend::Postgres => {
+            let pool = conn.get_postgres_connection_pool();
+            (
+                pool.size(),
+                pool.num_idle(),
+                pool.options().get_max_connections(),
+            )
+        }
+        DatabaseBackend::MySql => {
+            let pool = conn.get_mysql_connection_pool();
+            (
+                pool.size(),
+                pool.num_idle(),
+                pool.options().get_max_connections(),
+            )
+        }
+        DatabaseBackend::Sqlite => {
+            let pool = conn.get_sqlite_connection_pool();
+            (
+                pool.size(),
+                pool.num_idle(),
+                pool.options().get_max_connections(),
+            )
+        }
+    }
+}
+
+async fn acquire(conn: &DatabaseConnection) -> Result<(), sea_orm::sqlx::Error> {
+    match conn.get_database_backend() {
+        DatabaseBackend::Postgres => drop(conn.get_postgres_connection_pool().acquire().await?),
+        DatabaseBackend::MySql => drop(conn.get_mysql_connection_pool().acquire().await?),
+        DatabaseBackend::Sqlite => drop(conn.get_sqlite_connection_pool().acquire().await?),
+    }
+    Ok(())
+}
+
+/// Times how long every pool takes to hand out a connection. Called by the health monitor, so
+/// the wait times in [`PoolStats`] are at most one health check interval old.
+pub(super) async fn sample_all() {
+    for (name, conn) in connections() {
+        let start = Instant::now();
+        let result = acquire(conn).await;
+        let wait = start.elapsed();
+        let mut samples = SAMPLES.lock().unwrap();
+        let sample = samples
+            .get_or_insert_with(FxHashMap::default)
+            .entry(name.clone())
+            .or_default();
+        sample.wait = wait;
+        if let Err(e) = result {
+            warn!("Error acquiring a connection from pool {name}: {e}");
+            sample.errors += 1;
+        }
+    }
+}
+
+pub fn pool_stats() -> Vec<PoolStats> {
+    let samples = SAMPLES.lock().unwrap();
+    connections()
+        .into_iter()
+        .map(|(pool, conn)| {
+            let (size, idle, max_size) = pool_size(conn);
+            let sample = samples
+                .as_ref()
+                .and_then(|samples| samples.get(&pool))
+                .copied()
+                .unwrap_or_default();
+            PoolStats {
+                pool,
+                size,
+                idle,
+                max_size,
+                acquire_wait_ms: sample.wait.as_secs_f64() * 1000.0,
+                acquire_errors: sample.errors,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,575 @@
+use std::{
+    fmt::Write,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Once, OnceLock,
+    },
+};
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    digest::{digest, SHA256},
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use sea_orm::{
+    entity::prelude::*,
+    sea_query::{
+        self, Alias, ArrayType, IntoValueTuple, Nullable, Query, Table, ValueType, ValueTypeErr,
+    },
+    ActiveModelBehavior, ColIdx, IntoActiveModel, Iterable, PaginatorTrait, PrimaryKeyToColumn,
+    QueryOrder, QueryResult, TransactionTrait, TryGetError, TryGetable,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize, Serializer};
+use tracing::{info, warn};
+
+use crate::db::get_db;
+
+static KEY: OnceLock<LessSafeKey> = OnceLock::new();
+static HASH_KEY: OnceLock<hmac::Key> = OnceLock::new();
+const KEY_ENV_VAR: &str = "ENCRYPTION_KEY";
+static PLAINTEXT_WARNING: Once = Once::new();
+/// Whether values that do not decrypt are read as plain text even though there is a key
+static READ_PLAINTEXT: AtomicBool = AtomicBool::new(false);
+
+static COLUMNS: Mutex<Vec<Arc<EncryptedColumn>>> = Mutex::new(vec![]);
+
+pub(crate) type EncryptRows = Box<
+    dyn Fn(&'static DatabaseConnection) -> BoxFuture<'static, Result<u64, DbErr>> + Send + Sync,
+>;
+
+struct EncryptedColumn {
+    table: String,
+    column: String,
+    encrypt_rows: EncryptRows,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EncryptionConfig {
+    #[serde(default)]
+    pub encryption: EncryptionOptions,
+}
+
+/// The `[encryption]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EncryptionOptions {
+    /// A 256 bit key as 64 hex characters. The `ENCRYPTION_KEY` environment variable takes
+    /// precedence, so that the key can be kept out of the config file. Without a key, [`Encrypted`]
+    /// columns are stored in plain text.
+    pub key: Option<String>,
+    /// Reads values stored in plain text even though there is a key, for the time between setting
+    /// a key for an existing database and running `encrypt-columns`. Anyone who can write to the
+    /// database could otherwise swap an encrypted value for a plain one, so leave it off after.
+    #[serde(default)]
+    pub read_plaintext: bool,
+}
+
+fn parse_key(key: &str) -> anyhow::Result<Vec<u8>> {
+    let key = key.trim();
+    if key.len() != 64 {
+        return Err(anyhow::anyhow!("Encryption key must be 64 hex characters"));
+    }
+    let bytes = (0..key.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&key[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Encryption key must be 64 hex characters")?;
+    Ok(bytes)
+}
+
+pub fn init(config: &str) -> anyhow::Result<()> {
+    let encryption_config: EncryptionConfig = toml::from_str(config)?;
+    READ_PLAINTEXT.store(
+        encryption_config.encryption.read_plaintext,
+        Ordering::Relaxed,
+    );
+    let key = match std::env::var(KEY_ENV_VAR) {
+        Ok(key) => key,
+        Err(_) => match encryption_config.encryption.key {
+            Some(key) => key,
+            None => return Ok(()),
+        },
+    };
+    let bytes = parse_key(&key)?;
+    let key = UnboundKey::new(&AES_256_GCM, &bytes)
+        .map_err(|_| anyhow::anyhow!("Encryption key must be 64 hex characters"))?;
+    let _ = KEY.set(LessSafeKey::new(key));
+    // Hashes get their own key, so that the encryption key is only used to encrypt
+    let hash_key = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &bytes), b"keyed hash");
+    let _ = HASH_KEY.set(hmac::Key::new(hmac::HMAC_SHA256, hash_key.as_ref()));
+    Ok(())
+}
+
+/// Whether a key was configured, so that [`Encrypted`] columns are written encrypted.
+pub fn is_enabled() -> bool {
+    KEY.get().is_some()
+}
+
+/// A hex digest of `value`, for looking up a column that is stored [`Encrypted`].
+///
+/// It is keyed by the encryption key, or a plain SHA-256 digest without one, so `encrypt-columns`
+/// has to recompute hashes after a key is set.
+pub fn keyed_hash(value: &str) -> String {
+    let digest = match HASH_KEY.get() {
+        Some(key) => hmac::sign(key, value.as_bytes()).as_ref().to_vec(),
+        None => digest(&SHA256, value.as_bytes()).as_ref().to_vec(),
+    };
+    let mut hash = String::new();
+    for byte in digest {
+        let _ = write!(hash, "{byte:02x}");
+    }
+    hash
+}
+
+fn key() -> anyhow::Result<&'static LessSafeKey> {
+    KEY.get().with_context(|| {
+        format!(
+            "Set encryption.key in teach-config.toml or the {KEY_ENV_VAR} environment variable to read encrypted columns"
+        )
+    })
+}
+
+// Values are stored as the random nonce followed by the ciphertext and tag
+fn encrypt(key: &LessSafeKey, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut nonce = [0; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .expect("Generating encryption nonce");
+    let mut bytes = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(aad),
+        &mut bytes,
+    )
+    .expect("Encrypting column value");
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend(bytes);
+    encrypted
+}
+
+fn decrypt(aad: &[u8], bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if bytes.len() < NONCE_LEN {
+        return Err(anyhow::anyhow!("Encrypted value is too short"));
+    }
+    let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).unwrap();
+    let mut plaintext = ciphertext.to_vec();
+    let len = key()?
+        .open_in_place(nonce, Aad::from(aad), &mut plaintext)
+        .map_err(|_| anyhow::anyhow!("Encrypted value could not be decrypted"))?
+        .len();
+    plaintext.truncate(len);
+    Ok(plaintext)
+}
+
+// The table, column and primary key of a value, so that a value copied to another row or column
+// does not decrypt
+fn binding<E: EntityTrait>(
+    column: &str,
+    primary_key: <E::PrimaryKey as PrimaryKeyTrait>::ValueType,
+) -> Vec<u8> {
+    let primary_key: Vec<_> = primary_key
+        .into_value_tuple()
+        .into_iter()
+        .map(|value| sea_query::sea_value_to_json_value(&value))
+        .collect();
+    serde_json::to_vec(&(E::default().table_name(), column, primary_key))
+        .expect("Serializing encryption binding")
+}
+
+/// A column of `E` that is stored encrypted with AES-256-GCM, so that it is not readable from a
+/// database dump without the encryption key.
+///
+/// The value is bound to its table, column and primary key, so it can not be moved to another
+/// row or column. Reading it needs the primary key columns, which [`EntityTrait::find`] always
+/// selects. The value can not be compared or sorted by the database. `Debug` does not show the
+/// value, so that it does not end up in logs. Without a key the value is stored as plain JSON.
+/// Values written that way, or before a column was encrypted, are read with a key only while
+/// `encryption.read_plaintext` is set. Register the column with
+/// [`crate::TeachCore::add_encrypted_column`] so that `encrypt-columns` encrypts those values.
+pub struct Encrypted<T, E>(pub T, Vec<u8>, PhantomData<E>);
+
+impl<T, E: EntityTrait> Encrypted<T, E> {
+    /// A value to write to `column` of the row with `primary_key`.
+    pub fn new(
+        value: T,
+        column: E::Column,
+        primary_key: <E::PrimaryKey as PrimaryKeyTrait>::ValueType,
+    ) -> Self {
+        Self(
+            value,
+            binding::<E>(&column.to_string(), primary_key),
+            PhantomData,
+        )
+    }
+}
+
+impl<T, E> Encrypted<T, E> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: Clone, E> Clone for Encrypted<T, E> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), self.1.clone(), PhantomData)
+    }
+}
+
+impl<T: PartialEq, E> PartialEq for Encrypted<T, E> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<T: Eq, E> Eq for Encrypted<T, E> {}
+
+impl<T, E> std::fmt::Debug for Encrypted<T, E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Encrypted(..)")
+    }
+}
+
+impl<T: Serialize, E> Serialize for Encrypted<T, E> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<T: Serialize, E> From<Encrypted<T, E>> for Value {
+    fn from(value: Encrypted<T, E>) -> Self {
+        let plaintext = serde_json::to_vec(&value.0).expect("Serializing encrypted column");
+        let Some(key) = KEY.get() else {
+            PLAINTEXT_WARNING.call_once(|| {
+                warn!("encryption.key is not set, so encrypted columns are stored in plain text")
+            });
+            return Value::Bytes(Some(Box::new(plaintext)));
+        };
+        Value::Bytes(Some(Box::new(encrypt(key, &value.1, &plaintext))))
+    }
+}
+
+// Finds the column and primary key that `idx` was read with, which may be prefixed when models
+// are selected together
+fn row_binding<E: EntityTrait, I: ColIdx>(res: &QueryResult, idx: I) -> Result<Vec<u8>, DbErr> {
+    let idx = idx
+        .as_str()
+        .ok_or_else(|| DbErr::Type("Encrypted columns have to be read by name".to_owned()))?;
+    let column = E::Column::iter()
+        .map(|column| column.to_string())
+        .filter(|column| idx.ends_with(column.as_str()))
+        .max_by_key(String::len)
+        .ok_or_else(|| {
+            DbErr::Type(format!(
+                "{idx} is not a column of {}",
+                E::default().table_name()
+            ))
+        })?;
+    let prefix = &idx[..idx.len() - column.len()];
+    let primary_key: Vec<_> = E::PrimaryKey::iter()
+        .map(|key| key.into_column().to_string())
+        .collect();
+    Ok(binding::<E>(
+        &column,
+        res.try_get_many(prefix, &primary_key)?,
+    ))
+}
+
+impl<T, E> TryGetable for Encrypted<T, E>
+where
+    T: DeserializeOwned + TryGetable,
+    E: EntityTrait,
+{
+    fn try_get_by<I: ColIdx>(res: &QueryResult, idx: I) -> Result<Self, TryGetError> {
+        let bytes = match <Vec<u8> as TryGetable>::try_get_by(res, idx) {
+            Err(TryGetError::Null(column)) => return Err(TryGetError::Null(column)),
+            bytes => bytes.ok(),
+        };
+        let aad = row_binding::<E, I>(res, idx).map_err(TryGetError::DbErr)?;
+        let read_plaintext = !is_enabled() || READ_PLAINTEXT.load(Ordering::Relaxed);
+        let value = match bytes {
+            Some(bytes) if is_enabled() => match decrypt(&aad, &bytes) {
+                Ok(plaintext) => serde_json::from_slice(&plaintext)
+                    .map_err(|e| TryGetError::DbErr(DbErr::Type(e.to_string())))?,
+                Err(e) if !read_plaintext => {
+                    return Err(TryGetError::DbErr(DbErr::Type(format!("{e:#}"))))
+                }
+                // Values stored in plain text
+                Err(e) => match serde_json::from_slice(&bytes) {
+                    Ok(value) => value,
+                    Err(_) => T::try_get_by(res, idx)
+                        .map_err(|_| TryGetError::DbErr(DbErr::Type(format!("{e:#}"))))?,
+                },
+            },
+            Some(bytes) => match serde_json::from_slice(&bytes) {
+                Ok(value) => value,
+                Err(_) => T::try_get_by(res, idx)?,
+            },
+            None if read_plaintext => T::try_get_by(res, idx)?,
+            None => {
+                return Err(TryGetError::DbErr(DbErr::Type(
+                    "Encrypted value is stored in plain text".to_owned(),
+                )))
+            }
+        };
+        Ok(Self(value, aad, PhantomData))
+    }
+}
+
+impl<T, E> ValueType for Encrypted<T, E>
+where
+    T: DeserializeOwned + ValueType,
+    E: EntityTrait,
+{
+    // A value alone does not say which row it belongs to, so use Encrypted::new instead
+    fn try_from(_: Value) -> Result<Self, ValueTypeErr> {
+        Err(ValueTypeErr)
+    }
+
+    fn type_name() -> String {
+        "Encrypted".to_owned()
+    }
+
+    fn array_type() -> ArrayType {
+        ArrayType::Bytes
+    }
+
+    // The same with or without a key, so that the schema does not depend on the config
+    fn column_type() -> ColumnType {
+        ColumnType::Blob
+    }
+}
+
+impl<T, E> Nullable for Encrypted<T, E> {
+    fn null() -> Value {
+        Value::Bytes(None)
+    }
+}
+
+/// Warns that registered columns are written in plain text, when no key is configured.
+pub(crate) fn warn_if_plaintext() {
+    if is_enabled() {
+        return;
+    }
+    let columns = COLUMNS.lock().unwrap();
+    if columns.is_empty() {
+        return;
+    }
+    let names: Vec<_> = columns
+        .iter()
+        .map(|column| format!("{}.{}", column.table, column.column))
+        .collect();
+    warn!(
+        "encryption.key is not set, so {} are stored in plain text. Set encryption.key in teach-config.toml or the {KEY_ENV_VAR} environment variable and run encrypt-columns",
+        names.join(", ")
+    );
+}
+
+pub(crate) fn add_column<E>(entity: E, column: E::Column)
+where
+    E: EntityTrait,
+    E::Model: IntoActiveModel<E::ActiveModel> + Sync,
+    E::ActiveModel: ActiveModelBehavior + Send,
+{
+    add_step(
+        entity.table_name(),
+        &column.to_string(),
+        Box::new(move |db| Box::pin(encrypt_rows::<E>(db, column))),
+    );
+}
+
+/// Adds a step to `encrypt-columns` for a column that needs more than [`encrypt_rows`], such as
+/// one that is looked up by [`keyed_hash`]. Steps run in the order they were added.
+pub(crate) fn add_step(table: &str, column: &str, encrypt_rows: EncryptRows) {
+    COLUMNS.lock().unwrap().push(Arc::new(EncryptedColumn {
+        table: table.to_string(),
+        column: column.to_string(),
+        encrypt_rows,
+    }));
+}
+
+// Writes every value to a new binary column, which then replaces the old one, since the old
+// column may have the type of the plain value
+async fn encrypt_rows<E>(db: &'static DatabaseConnection, column: E::Column) -> Result<u64, DbErr>
+where
+    E: EntityTrait,
+    E::Model: IntoActiveModel<E::ActiveModel> + Sync,
+    E::ActiveModel: ActiveModelBehavior + Send,
+{
+    let backend = db.get_database_backend();
+    let staging = Alias::new(format!("{}_encrypted", column.to_string()));
+    let txn = db.begin().await?;
+    txn.execute(
+        backend.build(
+            Table::alter()
+                .table(E::default())
+                .add_column(sea_query::ColumnDef::new(staging.clone()).blob()),
+        ),
+    )
+    .await?;
+    let mut query = E::find();
+    for key in E::PrimaryKey::iter() {
+        query = query.order_by_asc(key.into_column());
+    }
+    let mut pages = query.paginate(&txn, 500);
+    let mut rows = 0;
+    while let Some(models) = pages.fetch_and_next().await? {
+        for model in models {
+            let mut update = Query::update();
+            update
+                .table(E::default())
+                .value(staging.clone(), model.get(column));
+            for key in E::PrimaryKey::iter() {
+                let key = key.into_column();
+                update.and_where(Expr::col(key).eq(model.get(key)));
+            }
+            txn.execute(backend.build(&update)).await?;
+            rows += 1;
+        }
+    }
+    txn.execute(backend.build(Table::alter().table(E::default()).drop_column(column)))
+        .await?;
+    txn.execute(
+        backend.build(
+            Table::alter()
+                .table(E::default())
+                .rename_column(staging, column),
+        ),
+    )
+    .await?;
+    txn.commit().await?;
+    Ok(rows)
+}
+
+/// Encrypts every registered column, including values stored in plain text.
+///
+/// Without a key the columns are only moved to the binary column type, which [`Encrypted`]
+/// values are written to.
+pub(crate) async fn encrypt_columns() -> anyhow::Result<()> {
+    warn_if_plaintext();
+    READ_PLAINTEXT.store(true, Ordering::Relaxed);
+    let columns = COLUMNS.lock().unwrap().clone();
+    for column in columns {
+        let rows = (column.encrypt_rows)(get_db())
+            .await
+            .with_context(|| format!("Encrypting {}.{}", column.table, column.column))?;
+        info!(
+            "Encrypted {rows} rows of {}.{}",
+            column.table, column.column
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{ActiveModelTrait, ActiveValue, ConnectionTrait, Database, Schema, Statement};
+
+    use super::*;
+    use crate::{auth::UserID, db::SoftDeletable, users::students};
+
+    async fn students_db() -> DatabaseConnection {
+        init(&format!("[encryption]\nkey = \"{}\"", "ab".repeat(32))).unwrap();
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        let backend = db.get_database_backend();
+        let create = Schema::new(backend).create_table_from_entity(students::Entity);
+        db.execute(backend.build(&create)).await.unwrap();
+        db
+    }
+
+    async fn insert_student(db: &DatabaseConnection, user_id: i32, birthdate: &str) {
+        let user_id: UserID = user_id.try_into().unwrap();
+        let birthdate = birthdate.parse().unwrap();
+        students::ActiveModel {
+            user_id: ActiveValue::set(user_id),
+            name: ActiveValue::set("Student".to_string()),
+            pronouns: ActiveValue::set(String::new()),
+            birthdate: ActiveValue::set(Encrypted::new(
+                birthdate,
+                students::Column::Birthdate,
+                user_id,
+            )),
+            created_at: ActiveValue::not_set(),
+            updated_at: ActiveValue::not_set(),
+            created_by: ActiveValue::set(user_id),
+            deleted_at: ActiveValue::set(None),
+        }
+        .insert(db)
+        .await
+        .unwrap();
+    }
+
+    async fn read_student(db: &DatabaseConnection, user_id: i32) -> Result<students::Model, DbErr> {
+        let user_id: UserID = user_id.try_into().unwrap();
+        students::Entity::find_live_by_id(user_id)
+            .one(db)
+            .await
+            .map(Option::unwrap)
+    }
+
+    async fn stored_birthdate(db: &DatabaseConnection, user_id: i32) -> Vec<u8> {
+        let row = db
+            .query_one(Statement::from_string(
+                db.get_database_backend(),
+                format!("SELECT birthdate FROM students WHERE user_id = {user_id}"),
+            ))
+            .await
+            .unwrap()
+            .unwrap();
+        row.try_get("", "birthdate").unwrap()
+    }
+
+    #[tokio::test]
+    async fn values_round_trip_encrypted() {
+        let db = students_db().await;
+        insert_student(&db, 1, "2000-01-02T03:04:05").await;
+        let stored = stored_birthdate(&db, 1).await;
+        assert!(!String::from_utf8_lossy(&stored).contains("2000"));
+        assert_eq!(
+            read_student(&db, 1).await.unwrap().birthdate.0,
+            "2000-01-02T03:04:05".parse().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn values_copied_to_another_row_do_not_decrypt() {
+        let db = students_db().await;
+        insert_student(&db, 1, "2000-01-01T00:00:00").await;
+        insert_student(&db, 2, "2010-01-01T00:00:00").await;
+        assert!(read_student(&db, 2).await.is_ok());
+        let stored = stored_birthdate(&db, 1).await;
+        db.execute(Statement::from_sql_and_values(
+            db.get_database_backend(),
+            "UPDATE students SET birthdate = ? WHERE user_id = 2",
+            [stored.into()],
+        ))
+        .await
+        .unwrap();
+        assert!(read_student(&db, 1).await.is_ok());
+        assert!(read_student(&db, 2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn values_in_plain_text_are_refused_with_a_key() {
+        let db = students_db().await;
+        insert_student(&db, 1, "2000-01-01T00:00:00").await;
+        let plaintext = serde_json::to_vec("2000-01-01T00:00:00").unwrap();
+        db.execute(Statement::from_sql_and_values(
+            db.get_database_backend(),
+            "UPDATE students SET birthdate = ? WHERE user_id = 1",
+            [plaintext.into()],
+        ))
+        .await
+        .unwrap();
+        assert!(read_student(&db, 1).await.is_err());
+    }
+
+    #[test]
+    fn values_without_a_row_are_not_converted() {
+        let value = Value::Bytes(Some(Box::new(b"\"2000-01-01T00:00:00\"".to_vec())));
+        assert!(<Encrypted<DateTime, students::Entity> as ValueType>::try_from(value).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+#[cfg(feature = "sentry")]
+mod sentry;
+
+use std::{
+    cell::Cell,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    access_log, auth::UserID, client_ip::resolve_client_ip, request_id::RequestId, TeachCore,
+};
+
+static REPORTERS: Mutex<Vec<Arc<dyn ErrorReporter>>> = Mutex::new(vec![]);
+
+tokio::task_local! {
+    static REQUEST: RequestContext;
+}
+
+thread_local! {
+    // Set while reporting, so that a reporter that panics is not asked to report its own panic
+    static REPORTING: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ErrorReportingConfig {
+    #[serde(default)]
+    pub error_reporting: ErrorReportingOptions,
+}
+
+/// The `[error_reporting]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ErrorReportingOptions {
+    /// Sends reports to Sentry. Requires teach-tech-core's `sentry` feature
+    pub sentry_dsn: Option<String>,
+    /// Reported to Sentry as the environment, such as `production`
+    pub environment: Option<String>,
+}
+
+/// What went wrong.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum ErrorSource {
+    Panic,
+    /// A handler responded with a 5xx status
+    ServerError {
+        status: u16,
+    },
+    /// A scheduled task returned an error
+    BackgroundTask {
+        name: String,
+    },
+}
+
+/// The request that was being handled when an error happened.
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestContext {
+    pub method: String,
+    /// The path without its query, which can carry tokens
+    pub path: String,
+    pub request_id: Option<String>,
+    pub client_ip: Option<IpAddr>,
+    pub user_id: Option<UserID>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorReport {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub source: ErrorSource,
+    pub message: String,
+    pub request: Option<RequestContext>,
+}
+
+/// Something that error reports are sent to, registered with [`TeachCore::add_error_reporter`].
+///
+/// Reports can be sent from a panicking thread, so reporters should hand them off quickly
+/// instead of blocking.
+pub trait ErrorReporter: Send + Sync + 'static {
+    fn report(&self, report: &ErrorReport);
+}
+
+impl<F: Fn(&ErrorReport) + Send + Sync + 'static> ErrorReporter for F {
+    fn report(&self, report: &ErrorReport) {
+        self(report)
+    }
+}
+
+pub(crate) fn add_reporter(reporter: impl ErrorReporter) {
+    REPORTERS.lock().unwrap().push(Arc::new(reporter));
+}
+
+/// Sends a report to every reporter, attaching the request being handled if there is one.
+pub fn report(source: ErrorSource, message: impl Into<String>) {
+    let reporters = REPORTERS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    if reporters.is_empty() || REPORTING.get() {
+        return;
+    }
+    let request = REQUEST
+        .try_with(|request| RequestContext {
+            user_id: access_log::current_user(),
+            ..request.clone()
+        })
+        .ok();
+    let report = ErrorReport {
+        timestamp: chrono::Utc::now(),
+        source,
+        message: message.into(),
+        request,
+    };
+    REPORTING.set(true);
+    for reporter in reporters {
+        reporter.report(&report);
+    }
+    REPORTING.set(false);
+}
+
+async fn report_server_errors(request: Request, next: Next) -> Response {
+    let context = RequestContext {
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        request_id: RequestId::from_extensions(request.extensions()).map(|RequestId(id)| id),
+        client_ip: request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(peer)| resolve_client_ip(peer.ip(), request.headers())),
+        user_id: None,
+    };
+    let message = format!("{} {}", context.method, context.path);
+    REQUEST
+        .scope(context, async {
+            let response = next.run(request).await;
+            let status = response.status();
+            if status.is_server_error() {
+                report(
+                    ErrorSource::ServerError {
+                        status: status.as_u16(),
+                    },
+                    format!("{message} responded with {status}"),
+                );
+            }
+            response
+        })
+        .await
+}
+
+pub(crate) fn add_layer(router: Router) -> Router {
+    router.layer(middleware::from_fn(report_server_errors))
+}
+
+fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let payload = info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("Box<dyn Any>");
+        let message = match info.location() {
+            Some(location) => format!("{payload} at {location}"),
+            None => payload.to_string(),
+        };
+        report(ErrorSource::Panic, message);
+        previous(info);
+    }));
+}
+
+pub fn add_to_core<S>(core: TeachCore<S>) -> anyhow::Result<TeachCore<S>> {
+    let config: ErrorReportingConfig = toml::from_str(core.get_config_str())?;
+    install_panic_hook();
+    if let Some(dsn) = config.error_reporting.sentry_dsn {
+        #[cfg(feature = "sentry")]
+        add_reporter(sentry::SentryReporter::new(
+            &dsn,
+            config.error_reporting.environment,
+        )?);
+        #[cfg(not(feature = "sentry"))]
+        {
+            let _ = dsn;
+            return Err(anyhow::anyhow!(
+                "error_reporting.sentry_dsn is set, but teach-tech-core was built without the \
+                 sentry feature"
+            ));
+        }
+    }
+    Ok(core)
+}
@@ -0,0 +1,134 @@
+use std::{sync::mpsc, time::Duration};
+
+use anyhow::Context;
+use rand::{thread_rng, Rng};
+use serde_json::json;
+use tracing::error;
+
+use super::{ErrorReport, ErrorReporter, ErrorSource};
+use crate::http::{Endpoint, HttpClient};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a Sentry DSN of the form `https://<key>@<host>/<project>` sends events.
+#[derive(Debug)]
+struct Dsn {
+    key: String,
+    /// The url of the store endpoint
+    url: String,
+}
+
+impl Dsn {
+    fn parse(dsn: &str) -> anyhow::Result<Self> {
+        let invalid = || anyhow::anyhow!("{dsn} is not a valid Sentry DSN");
+        let (scheme, rest) = dsn.split_once("://").ok_or_else(invalid)?;
+        let (key, rest) = rest.split_once('@').ok_or_else(invalid)?;
+        // Older DSNs also carry a secret after the key, which is no longer needed
+        let key = key.split(':').next().unwrap_or_default();
+        let (address, path) = rest.split_once('/').ok_or_else(invalid)?;
+        let (prefix, project) = match path.trim_end_matches('/').rsplit_once('/') {
+            Some((prefix, project)) => (format!("/{prefix}"), project),
+            None => (String::new(), path.trim_end_matches('/')),
+        };
+        if key.is_empty() || project.is_empty() {
+            return Err(invalid());
+        }
+        let url = format!("{scheme}://{address}{prefix}/api/{project}/store/");
+        Endpoint::parse(&url).map_err(|_| invalid())?;
+        Ok(Self {
+            key: key.to_string(),
+            url,
+        })
+    }
+}
+
+fn send(dsn: &Dsn, client: &HttpClient, body: &[u8]) -> anyhow::Result<()> {
+    let auth = format!(
+        "Sentry sentry_version=7, sentry_key={}, sentry_client=teach-tech/{}",
+        dsn.key,
+        env!("CARGO_PKG_VERSION"),
+    );
+    let response = client.send(
+        "POST",
+        &dsn.url,
+        &[("X-Sentry-Auth", &auth)],
+        Some(("application/json", body)),
+    )?;
+    if !response.is_success() {
+        return Err(anyhow::anyhow!("Sentry responded with {}", response.status));
+    }
+    Ok(())
+}
+
+fn event(report: &ErrorReport, environment: Option<&str>) -> serde_json::Value {
+    let event_id = format!("{:032x}", thread_rng().gen::<u128>());
+    let (logger, mut tags) = match &report.source {
+        ErrorSource::Panic => ("panic", json!({})),
+        ErrorSource::ServerError { status } => {
+            ("server_error", json!({ "status": status.to_string() }))
+        }
+        ErrorSource::BackgroundTask { name } => ("background_task", json!({ "task": name })),
+    };
+    let mut event = json!({
+        "event_id": event_id,
+        "timestamp": report.timestamp.to_rfc3339(),
+        "platform": "other",
+        "level": "error",
+        "logger": logger,
+        "message": { "formatted": report.message },
+        "environment": environment,
+    });
+    if let Some(request) = &report.request {
+        if let Some(request_id) = &request.request_id {
+            tags["request_id"] = json!(request_id);
+        }
+        event["request"] = json!({ "method": request.method, "url": request.path });
+        event["user"] = json!({
+            "id": request.user_id.map(|user_id| user_id.to_string()),
+            "ip_address": request.client_ip,
+        });
+    }
+    event["tags"] = tags;
+    event
+}
+
+/// Sends reports to Sentry from a thread of its own, so that reporting never blocks.
+pub struct SentryReporter {
+    sender: mpsc::Sender<Vec<u8>>,
+    environment: Option<String>,
+}
+
+impl SentryReporter {
+    pub fn new(dsn: &str, environment: Option<String>) -> anyhow::Result<Self> {
+        let dsn = Dsn::parse(dsn)?;
+        let client = HttpClient::new(TIMEOUT)?;
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+        std::thread::Builder::new()
+            .name("sentry".into())
+            .spawn(move || {
+                for body in receiver {
+                    if let Err(e) = send(&dsn, &client, &body) {
+                        error!("Error sending a report to Sentry: {e:#}");
+                    }
+                }
+            })
+            .context("Starting the Sentry thread")?;
+        Ok(Self {
+            sender,
+            environment,
+        })
+    }
+}
+
+impl ErrorReporter for SentryReporter {
+    fn report(&self, report: &ErrorReport) {
+        let body = match serde_json::to_vec(&event(report, self.environment.as_deref())) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Error serializing a report for Sentry: {e:#}");
+                return;
+            }
+        };
+        let _ = self.sender.send(body);
+    }
+}
@@ -0,0 +1,422 @@
+use std::{
+    fmt::Display,
+    sync::{Mutex, OnceLock},
+};
+
+use async_graphql::{
+    dynamic::{
+        Field, FieldFuture, FieldValue, InputValue, Object, ResolverContext, Schema, TypeRef,
+    },
+    ErrorExtensions, Request as GraphqlRequest, Value,
+};
+use axum::{
+    extract::Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::post,
+};
+use fxhash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    auth::{AuthUser, UserID},
+    db::{get_read_db, paginate, PageQuery, SoftDeletable},
+    i18n,
+    users::{admins, instructors, students},
+    TeachCore,
+};
+
+static OBJECTS: Mutex<Vec<Object>> = Mutex::new(Vec::new());
+static FIELDS: Mutex<Vec<(String, Field)>> = Mutex::new(Vec::new());
+static SCHEMA: OnceLock<Schema> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GraphqlConfig {
+    #[serde(default)]
+    pub graphql: GraphqlOptions,
+}
+
+/// The `[graphql]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GraphqlOptions {
+    #[serde(default = "default_max_depth")]
+    pub max_depth: usize,
+    #[serde(default = "default_max_complexity")]
+    pub max_complexity: usize,
+    #[serde(default = "default_introspection")]
+    pub introspection: bool,
+}
+
+impl Default for GraphqlOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: default_max_depth(),
+            max_complexity: default_max_complexity(),
+            introspection: default_introspection(),
+        }
+    }
+}
+
+fn default_max_depth() -> usize {
+    10
+}
+
+fn default_max_complexity() -> usize {
+    1000
+}
+
+fn default_introspection() -> bool {
+    true
+}
+
+/// The user a query is run for.
+#[derive(Debug, Clone, Copy)]
+pub struct Viewer(pub UserID);
+
+/// Adds an object type to the schema. Fields added with [`add_field`] apply to it too.
+pub fn add_object(object: Object) {
+    OBJECTS.lock().unwrap().push(object);
+}
+
+/// Adds a field to an object type, such as `Query` or `Student`.
+pub fn add_field(type_name: impl Into<String>, field: Field) {
+    FIELDS.lock().unwrap().push((type_name.into(), field));
+}
+
+pub fn viewer(ctx: &ResolverContext) -> UserID {
+    ctx.data_unchecked::<Viewer>().0
+}
+
+pub fn bad_request(message: impl Into<String>) -> async_graphql::Error {
+    async_graphql::Error::new(message).extend_with(|_, e| e.set("code", "BAD_REQUEST"))
+}
+
+pub fn forbidden(message: impl Into<String>) -> async_graphql::Error {
+    async_graphql::Error::new(message).extend_with(|_, e| e.set("code", "FORBIDDEN"))
+}
+
+/// Logs `e`, keeping its details out of the response like the REST endpoints do.
+pub fn internal_error(context: &str, e: impl Display) -> async_graphql::Error {
+    error!("{context}: {e:#}");
+    async_graphql::Error::new("Internal server error")
+        .extend_with(|_, e| e.set("code", "INTERNAL_SERVER_ERROR"))
+}
+
+/// The same check as the admin-only REST endpoints.
+pub async fn require_admin(ctx: &ResolverContext<'_>) -> async_graphql::Result<UserID> {
+    let user_id = viewer(ctx);
+    match admins::Entity::find_live_by_id(user_id)
+        .one(get_read_db())
+        .await
+    {
+        Ok(Some(_)) => Ok(user_id),
+        Ok(None) => Err(forbidden(i18n::t("error.must_be_admin"))),
+        Err(e) => Err(internal_error("Error reading admin data", e)),
+    }
+}
+
+/// Wraps anything serializable, such as a model, so that [`json_field`] and [`json_list_field`]
+/// can read it.
+pub fn json_value(value: &impl Serialize) -> async_graphql::Result<FieldValue<'static>> {
+    Ok(FieldValue::owned_any(serde_json::to_value(value)?))
+}
+
+/// The JSON of the object a field is resolved on.
+pub fn parent<'a>(ctx: &ResolverContext<'a>) -> async_graphql::Result<&'a serde_json::Value> {
+    ctx.parent_value.try_downcast_ref::<serde_json::Value>()
+}
+
+/// The `user_id` of the object a field is resolved on, such as a student.
+pub fn parent_user_id(ctx: &ResolverContext) -> async_graphql::Result<UserID> {
+    let user_id = parent(ctx)?
+        .get("user_id")
+        .cloned()
+        .ok_or_else(|| async_graphql::Error::new("The parent has no user_id"))?;
+    Ok(serde_json::from_value(user_id)?)
+}
+
+/// A field that reads `key` from the JSON of its parent.
+pub fn json_field(name: &str, key: &'static str, ty: TypeRef) -> Field {
+    Field::new(name, ty, move |ctx| {
+        FieldFuture::new(async move {
+            let Some(value) = parent(&ctx)?.get(key).filter(|value| !value.is_null()) else {
+                return Ok(None);
+            };
+            Ok(Some(Value::from_json(value.clone())?))
+        })
+    })
+}
+
+/// A field that reads a list of objects from `key` in the JSON of its parent.
+pub fn json_list_field(name: &str, key: &'static str, ty: TypeRef) -> Field {
+    Field::new(name, ty, move |ctx| {
+        FieldFuture::new(async move {
+            let items = parent(&ctx)?
+                .get(key)
+                .and_then(|value| value.as_array())
+                .cloned()
+                .unwrap_or_default();
+            Ok(Some(FieldValue::list(
+                items.into_iter().map(FieldValue::owned_any),
+            )))
+        })
+    })
+}
+
+/// An object whose fields are read from JSON, given as `(name, key, type)`.
+pub fn json_object(
+    name: &str,
+    fields: impl IntoIterator<Item = (&'static str, &'static str, TypeRef)>,
+) -> Object {
+    fields
+        .into_iter()
+        .fold(Object::new(name), |object, (name, key, ty)| {
+            object.field(json_field(name, key, ty))
+        })
+}
+
+fn person_object(name: &str) -> Object {
+    json_object(
+        name,
+        [
+            ("userId", "user_id", TypeRef::named_nn(TypeRef::INT)),
+            ("name", "name", TypeRef::named_nn(TypeRef::STRING)),
+            ("pronouns", "pronouns", TypeRef::named_nn(TypeRef::STRING)),
+            ("birthdate", "birthdate", TypeRef::named_nn(TypeRef::STRING)),
+            (
+                "createdAt",
+                "created_at",
+                TypeRef::named_nn(TypeRef::STRING),
+            ),
+            (
+                "updatedAt",
+                "updated_at",
+                TypeRef::named_nn(TypeRef::STRING),
+            ),
+        ],
+    )
+}
+
+fn page_object(name: &str, item: &str) -> Object {
+    Object::new(name)
+        .field(json_list_field(
+            "items",
+            "items",
+            TypeRef::named_nn_list_nn(item),
+        ))
+        .field(json_field(
+            "nextCursor",
+            "next_cursor",
+            TypeRef::named(TypeRef::STRING),
+        ))
+}
+
+fn page_arguments(field: Field) -> Field {
+    field
+        .argument(InputValue::new("cursor", TypeRef::named(TypeRef::STRING)))
+        .argument(InputValue::new("limit", TypeRef::named(TypeRef::INT)))
+}
+
+fn page_query(ctx: &ResolverContext) -> async_graphql::Result<PageQuery> {
+    let cursor = match ctx.args.get("cursor") {
+        Some(cursor) => Some(cursor.string()?.to_string()),
+        None => None,
+    };
+    let limit = match ctx.args.get("limit") {
+        Some(limit) => Some(limit.u64()?),
+        None => None,
+    };
+    Ok(PageQuery { cursor, limit })
+}
+
+fn user_id_argument(ctx: &ResolverContext) -> async_graphql::Result<UserID> {
+    i32::try_from(ctx.args.try_get("userId")?.i64()?)
+        .ok()
+        .and_then(|user_id| UserID::try_from(user_id).ok())
+        .ok_or_else(|| bad_request(i18n::t("error.invalid_user_id")))
+}
+
+/// `students`, `student`, `instructors` and `instructor`, which differ only in their entity.
+macro_rules! person_queries {
+    (
+        $query:ident,
+        $module:ident,
+        $list:literal,
+        $single:literal,
+        $ty:literal,
+        $page:literal,
+        $what:literal
+    ) => {
+        $query
+            .field(page_arguments(Field::new(
+                $list,
+                TypeRef::named_nn($page),
+                |ctx| {
+                    FieldFuture::new(async move {
+                        require_admin(&ctx).await?;
+                        let page = page_query(&ctx)?;
+                        let cursor = page
+                            .cursor::<UserID>()
+                            .map_err(|_| bad_request(i18n::t("error.invalid_cursor")))?;
+                        let page = paginate(
+                            $module::Entity::find_live(),
+                            $module::Column::UserId,
+                            |model| model.user_id,
+                            cursor,
+                            page.limit(),
+                            get_read_db(),
+                        )
+                        .await
+                        .map_err(|e| internal_error(concat!("Error listing ", $what), e))?;
+                        Ok(Some(json_value(&page)?))
+                    })
+                },
+            )))
+            .field(
+                Field::new($single, TypeRef::named($ty), |ctx| {
+                    FieldFuture::new(async move {
+                        let user_id = user_id_argument(&ctx)?;
+                        // Like the home endpoints, everyone can read their own record
+                        if user_id != viewer(&ctx) {
+                            require_admin(&ctx).await?;
+                        }
+                        let model = $module::Entity::find_live_by_id(user_id)
+                            .one(get_read_db())
+                            .await
+                            .map_err(|e| internal_error(concat!("Error reading ", $what), e))?;
+                        model.map(|model| json_value(&model)).transpose()
+                    })
+                })
+                .argument(InputValue::new("userId", TypeRef::named_nn(TypeRef::INT))),
+            )
+    };
+}
+
+/// The signed in user, with whichever roles they have.
+fn viewer_object() -> Object {
+    Object::new("Viewer")
+        .field(Field::new(
+            "userId",
+            TypeRef::named_nn(TypeRef::INT),
+            |ctx| FieldFuture::from_value(Some(Value::from(i32::from(viewer(&ctx))))),
+        ))
+        .field(Field::new("student", TypeRef::named("Student"), |ctx| {
+            FieldFuture::new(async move {
+                let model = students::Entity::find_live_by_id(viewer(&ctx))
+                    .one(get_read_db())
+                    .await
+                    .map_err(|e| internal_error("Error reading student data", e))?;
+                model.map(|model| json_value(&model)).transpose()
+            })
+        }))
+        .field(Field::new(
+            "instructor",
+            TypeRef::named("Instructor"),
+            |ctx| {
+                FieldFuture::new(async move {
+                    let model = instructors::Entity::find_live_by_id(viewer(&ctx))
+                        .one(get_read_db())
+                        .await
+                        .map_err(|e| internal_error("Error reading instructor data", e))?;
+                    model.map(|model| json_value(&model)).transpose()
+                })
+            },
+        ))
+        .field(Field::new(
+            "isAdmin",
+            TypeRef::named_nn(TypeRef::BOOLEAN),
+            |ctx| {
+                FieldFuture::new(async move {
+                    let is_admin = admins::Entity::find_live_by_id(viewer(&ctx))
+                        .one(get_read_db())
+                        .await
+                        .map_err(|e| internal_error("Error reading admin data", e))?
+                        .is_some();
+                    Ok(Some(Value::from(is_admin)))
+                })
+            },
+        ))
+}
+
+fn build(options: &GraphqlOptions) -> anyhow::Result<Schema> {
+    let query = Object::new("Query").field(Field::new(
+        "viewer",
+        TypeRef::named_nn("Viewer"),
+        // Its fields only need the viewer from the request
+        |_| FieldFuture::Value(Some(FieldValue::owned_any(()))),
+    ));
+    let query = person_queries!(
+        query,
+        students,
+        "students",
+        "student",
+        "Student",
+        "StudentPage",
+        "students"
+    );
+    let query = person_queries!(
+        query,
+        instructors,
+        "instructors",
+        "instructor",
+        "Instructor",
+        "InstructorPage",
+        "instructors"
+    );
+
+    let mut objects = vec![
+        query,
+        viewer_object(),
+        person_object("Student"),
+        person_object("Instructor"),
+        page_object("StudentPage", "Student"),
+        page_object("InstructorPage", "Instructor"),
+    ];
+    objects.append(&mut OBJECTS.lock().unwrap());
+
+    let mut fields: FxHashMap<String, Vec<Field>> = FxHashMap::default();
+    for (type_name, field) in FIELDS.lock().unwrap().drain(..) {
+        fields.entry(type_name).or_default().push(field);
+    }
+
+    let mut builder = Schema::build("Query", None, None)
+        .limit_depth(options.max_depth)
+        .limit_complexity(options.max_complexity);
+    if !options.introspection {
+        builder = builder.disable_introspection();
+    }
+    for object in objects {
+        let extra = fields.remove(object.type_name()).unwrap_or_default();
+        builder = builder.register(extra.into_iter().fold(object, Object::field));
+    }
+    if let Some(type_name) = fields.keys().next() {
+        return Err(anyhow::anyhow!(
+            "A GraphQL field was added to {type_name}, which is not an object type"
+        ));
+    }
+    Ok(builder.finish()?)
+}
+
+async fn execute(
+    AuthUser { user_id, .. }: AuthUser,
+    Json(request): Json<GraphqlRequest>,
+) -> Response {
+    let Some(schema) = SCHEMA.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, ()).into_response();
+    };
+    let response = schema.execute(request.data(Viewer(user_id))).await;
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+pub fn add_to_core<S: Clone + Send + Sync + 'static>(
+    mut core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    let config: GraphqlConfig = toml::from_str(core.get_config_str())?;
+    // Integrations add their fields before serving, so the schema is built last
+    core.add_on_serve_named("graphql", 0, move || async move {
+        let _ = SCHEMA.set(build(&config.graphql)?);
+        Ok(())
+    });
+    Ok(core.modify_router(|router| router.route("/graphql", post(execute))))
+}
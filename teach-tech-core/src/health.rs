@@ -0,0 +1,28 @@
+use axum::{http::StatusCode, response::IntoResponse, routing::get, Json};
+use serde_json::json;
+
+use crate::{db, TeachCore};
+
+pub fn add_to_core<S: Clone + Send + Sync + 'static>(core: TeachCore<S>) -> TeachCore<S> {
+    core.modify_router(|router| {
+        router.route(
+            "/health",
+            get(|| async {
+                let available = db::is_available();
+                let status = if available {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                let body = json!({
+                    "status": if available { "ok" } else { "unavailable" },
+                    "database": {
+                        "available": available,
+                        "pools": db::pool_stats(),
+                    },
+                });
+                (status, Json(body)).into_response()
+            }),
+        )
+    })
+}
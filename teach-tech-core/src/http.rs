@@ -0,0 +1,533 @@
+//! A small blocking HTTP client, shared by the services teach-tech talks to and integrations.
+//!
+//! Logins go through it to reach identity and CAPTCHA providers, and the LDAP client shares its
+//! connections. Each request has to finish within the timeout of its client, and responses
+//! longer than [`HttpClient::max_body`] are refused.
+//!
+//! https needs teach-tech-core to be built with its https feature, which the features of those
+//! services turn on, and ldaps with ldap-tls.
+
+use std::{
+    borrow::Cow,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+#[cfg(any(feature = "https", feature = "ldap-tls"))]
+use std::sync::Arc;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use zeroize::Zeroizing;
+
+/// How much of a response body a client reads unless told otherwise
+pub const DEFAULT_MAX_BODY: usize = 4 << 20;
+/// The longest status or header line read
+const MAX_LINE: u64 = 64 << 10;
+
+#[derive(Debug)]
+pub(crate) struct Endpoint {
+    tls: bool,
+    host: String,
+    port: u16,
+    /// The host, with the port when the url has one. Sent as the Host header
+    authority: String,
+    /// With the query
+    path: String,
+}
+
+impl Endpoint {
+    pub(crate) fn parse(url: &str) -> anyhow::Result<Self> {
+        let invalid = || anyhow::anyhow!("{url} is not a valid url");
+        let (scheme, rest) = url.split_once("://").ok_or_else(invalid)?;
+        let tls = match scheme {
+            "https" => true,
+            "http" => false,
+            _ => return Err(invalid()),
+        };
+        #[cfg(not(feature = "https"))]
+        if tls {
+            return Err(anyhow::anyhow!(
+                "{url} uses https, but teach-tech-core was built without the https feature"
+            ));
+        }
+        let (address, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (address, if tls { 443 } else { 80 }),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            tls,
+            host: host.to_string(),
+            port,
+            authority: address.to_string(),
+            path,
+        })
+    }
+}
+
+pub(crate) trait ReadWrite: Read + Write + Send {}
+
+impl<T: Read + Write + Send> ReadWrite for T {}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// The value of the first header called `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> Cow<str> {
+        String::from_utf8_lossy(&self.body)
+    }
+
+    /// Reads a successful response as JSON. `from` names the server in errors.
+    pub fn json<T: DeserializeOwned>(&self, from: &str) -> anyhow::Result<T> {
+        if self.status != 200 {
+            return Err(anyhow::anyhow!(
+                "{from} responded with {}: {}",
+                self.status,
+                self.text()
+            ));
+        }
+        serde_json::from_slice(&self.body)
+            .with_context(|| format!("Invalid response from {from}: {}", self.text()))
+    }
+}
+
+/// Opens connections, over TLS when asked to, that fail once the timeout has passed since they
+/// were opened.
+pub(crate) struct Connector {
+    timeout: Duration,
+    #[cfg(any(feature = "https", feature = "ldap-tls"))]
+    tls: Arc<rustls::ClientConfig>,
+}
+
+/// Sends each request on a connection of its own.
+pub struct HttpClient {
+    connector: Connector,
+    max_body: usize,
+}
+
+/// A socket that gives up once its deadline passes, however slowly the other side sends.
+struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl DeadlineStream {
+    /// The time left, which bounds the next read or write.
+    fn time_left(&self) -> io::Result<Duration> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "The server did not finish in time",
+            ));
+        }
+        Ok(left)
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.time_left()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.time_left()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(any(feature = "https", feature = "ldap-tls"))]
+fn tls_config() -> anyhow::Result<Arc<rustls::ClientConfig>> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .context("Configuring TLS")?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Escapes a value for a query string or an `application/x-www-form-urlencoded` body.
+pub(crate) fn url_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Joins pairs into a query string or form body.
+pub(crate) fn form_encode(pairs: &[(&str, &str)]) -> String {
+    let mut encoded = String::new();
+    for (name, value) in pairs {
+        if !encoded.is_empty() {
+            encoded.push('&');
+        }
+        encoded.push_str(name);
+        encoded.push('=');
+        encoded.push_str(&url_encode(value));
+    }
+    encoded
+}
+
+impl Connector {
+    pub(crate) fn new(timeout: Duration) -> anyhow::Result<Self> {
+        Ok(Self {
+            timeout,
+            #[cfg(any(feature = "https", feature = "ldap-tls"))]
+            tls: tls_config()?,
+        })
+    }
+
+    pub(crate) fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        tls: bool,
+    ) -> anyhow::Result<Box<dyn ReadWrite>> {
+        let deadline = Instant::now() + self.timeout;
+        let stream =
+            connect(host, port, deadline).with_context(|| format!("Connecting to {host}"))?;
+        let stream = DeadlineStream { stream, deadline };
+        if tls {
+            return self.tls_stream(host, stream);
+        }
+        Ok(Box::new(stream))
+    }
+
+    #[cfg(any(feature = "https", feature = "ldap-tls"))]
+    fn tls_stream(&self, host: &str, stream: DeadlineStream) -> anyhow::Result<Box<dyn ReadWrite>> {
+        let server_name = rustls::pki_types::ServerName::try_from(host.to_string())
+            .with_context(|| format!("{host} is not a valid server name"))?;
+        let connection = rustls::ClientConnection::new(self.tls.clone(), server_name)?;
+        Ok(Box::new(rustls::StreamOwned::new(connection, stream)))
+    }
+
+    #[cfg(not(any(feature = "https", feature = "ldap-tls")))]
+    fn tls_stream(&self, _: &str, _: DeadlineStream) -> anyhow::Result<Box<dyn ReadWrite>> {
+        Err(anyhow::anyhow!("teach-tech-core was built without TLS"))
+    }
+}
+
+/// Tries each address of `host` in turn until one accepts before `deadline`.
+fn connect(host: &str, port: u16, deadline: Instant) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for address in (host, port).to_socket_addrs()? {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        match TcpStream::connect_timeout(&address, left) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "No address")))
+}
+
+impl HttpClient {
+    /// Each request has to be sent and answered within `timeout`.
+    pub fn new(timeout: Duration) -> anyhow::Result<Self> {
+        Ok(Self {
+            connector: Connector::new(timeout)?,
+            max_body: DEFAULT_MAX_BODY,
+        })
+    }
+
+    /// Refuses responses with bodies longer than `bytes`, instead of [`DEFAULT_MAX_BODY`].
+    pub fn max_body(mut self, bytes: usize) -> Self {
+        self.max_body = bytes;
+        self
+    }
+
+    /// Sends `body` with its content type, and any extra `headers`, which may hold secrets.
+    pub fn send(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<(&str, &[u8])>,
+    ) -> anyhow::Result<HttpResponse> {
+        let endpoint = Endpoint::parse(url)?;
+        let mut head = Zeroizing::new(format!(
+            "{method} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+            endpoint.path, endpoint.authority,
+        ));
+        for (name, value) in headers {
+            if value.contains(['\r', '\n']) {
+                return Err(anyhow::anyhow!("The {name} header has a line break"));
+            }
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        let (content_type, bytes) = body.unwrap_or_default();
+        if !content_type.is_empty() {
+            head.push_str(&format!("Content-Type: {content_type}\r\n"));
+        }
+        if body.is_some() || method != "GET" {
+            head.push_str(&format!("Content-Length: {}\r\n", bytes.len()));
+        }
+        head.push_str("\r\n");
+        let mut stream = self
+            .connector
+            .connect(&endpoint.host, endpoint.port, endpoint.tls)?;
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(bytes)?;
+        stream.flush()?;
+
+        read_response(BufReader::new(stream), self.max_body)
+            .with_context(|| format!("Reading the response from {}", endpoint.host))
+    }
+
+    pub fn get(&self, url: &str) -> anyhow::Result<HttpResponse> {
+        self.send("GET", url, &[("Accept", "application/json")], None)
+    }
+
+    pub fn post_form(&self, url: &str, form: &str) -> anyhow::Result<HttpResponse> {
+        self.send(
+            "POST",
+            url,
+            &[("Accept", "application/json")],
+            Some(("application/x-www-form-urlencoded", form.as_bytes())),
+        )
+    }
+}
+
+/// A line of the response without its line break, failing if the connection closes first.
+fn read_line(reader: &mut impl BufRead) -> anyhow::Result<String> {
+    let mut line = vec![];
+    reader.take(MAX_LINE).read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\n") {
+        if line.len() as u64 == MAX_LINE {
+            return Err(anyhow::anyhow!("A line of the response is too long"));
+        }
+        return Err(anyhow::anyhow!("The connection closed mid-response"));
+    }
+    let line = String::from_utf8_lossy(&line);
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Reads exactly `len` bytes onto `body`, failing if the connection closes first or the body
+/// would grow past `max_body`.
+fn read_body(
+    reader: &mut impl Read,
+    len: usize,
+    body: &mut Vec<u8>,
+    max_body: usize,
+) -> anyhow::Result<()> {
+    if len > max_body.saturating_sub(body.len()) {
+        return Err(anyhow::anyhow!(
+            "The response is longer than {max_body} bytes"
+        ));
+    }
+    let read = reader.take(len as u64).read_to_end(body)?;
+    if read < len {
+        return Err(anyhow::anyhow!(
+            "The connection closed after {read} of {len} bytes"
+        ));
+    }
+    Ok(())
+}
+
+fn read_response(mut reader: impl BufRead, max_body: usize) -> anyhow::Result<HttpResponse> {
+    let status_line = read_line(&mut reader)?;
+    let status = status_line
+        .strip_prefix("HTTP/1.")
+        .and_then(|rest| rest.get(2..5))
+        .and_then(|status| status.parse().ok())
+        .with_context(|| format!("Invalid status line {status_line:?}"))?;
+    let mut headers = vec![];
+    loop {
+        let line = read_line(&mut reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .with_context(|| format!("Invalid header {line:?}"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    let mut response = HttpResponse {
+        status,
+        headers,
+        body: vec![],
+    };
+
+    if matches!(status, 100..=199 | 204 | 304) {
+        return Ok(response);
+    }
+    let chunked = response
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| {
+            encoding
+                .rsplit(',')
+                .next()
+                .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"))
+        });
+    if chunked {
+        loop {
+            let line = read_line(&mut reader)?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16)
+                .with_context(|| format!("Invalid chunk size {line:?}"))?;
+            if size == 0 {
+                break;
+            }
+            read_body(&mut reader, size, &mut response.body, max_body)?;
+            if !read_line(&mut reader)?.is_empty() {
+                return Err(anyhow::anyhow!("A chunk is longer than its size"));
+            }
+        }
+        // Trailers, which are not needed
+        while !read_line(&mut reader)?.is_empty() {}
+    } else if let Some(length) = response.header("Content-Length") {
+        let length = length
+            .parse()
+            .with_context(|| format!("Invalid Content-Length {length:?}"))?;
+        read_body(&mut reader, length, &mut response.body, max_body)?;
+    } else {
+        // Without either, the body lasts until the connection closes
+        reader
+            .take(max_body as u64 + 1)
+            .read_to_end(&mut response.body)?;
+        if response.body.len() > max_body {
+            return Err(anyhow::anyhow!(
+                "The response is longer than {max_body} bytes"
+            ));
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(response: &str) -> anyhow::Result<HttpResponse> {
+        read_response(response.as_bytes(), DEFAULT_MAX_BODY)
+    }
+
+    #[test]
+    fn reads_content_length() {
+        let response =
+            parse("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello, and more").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello");
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let response = parse(
+            "HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5\r\n{\"a\":\r\n3;name=value\r\n12}\r\n0\r\nTrailer: yes\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.text(), r#"{"a":12}"#);
+    }
+
+    #[test]
+    fn reads_until_closed_without_a_length() {
+        let response = parse("HTTP/1.0 404 Not Found\r\n\r\nmissing").unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, b"missing");
+    }
+
+    #[test]
+    fn refuses_short_reads() {
+        assert!(parse("HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello").is_err());
+        assert!(parse("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel").is_err());
+        assert!(
+            parse("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n").is_err()
+        );
+        assert!(parse("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n").is_err());
+        assert!(parse("HTTP/1.1 20").is_err());
+    }
+
+    #[test]
+    fn refuses_line_breaks_in_headers() {
+        let client = HttpClient::new(Duration::from_secs(1)).unwrap();
+        let error = client
+            .send(
+                "GET",
+                "http://127.0.0.1:9/",
+                &[("Authorization", "Bearer a\r\nX-Injected: yes")],
+                None,
+            )
+            .unwrap_err();
+        assert!(error.to_string().contains("line break"), "{error:#}");
+    }
+
+    #[test]
+    fn refuses_long_bodies() {
+        let long = |response: &str| read_response(response.as_bytes(), 4).unwrap_err();
+        long("HTTP/1.1 200 OK\r\nContent-Length: 4000000000\r\n\r\nhello");
+        long("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n");
+        long("HTTP/1.0 200 OK\r\n\r\nhello");
+        let response = read_response("HTTP/1.0 200 OK\r\n\r\nhell".as_bytes(), 4).unwrap();
+        assert_eq!(response.body, b"hell");
+        let header = format!("HTTP/1.1 200 OK\r\nX-Long: {}\r\n\r\n", "a".repeat(1 << 17));
+        assert!(parse(&header).is_err());
+    }
+
+    #[test]
+    fn gives_up_on_slow_servers() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n");
+            // A byte at a time, each well within the timeout
+            for _ in 0..100 {
+                std::thread::sleep(Duration::from_millis(50));
+                if stream.write_all(b"a").is_err() {
+                    break;
+                }
+            }
+        });
+        let client = HttpClient::new(Duration::from_millis(500)).unwrap();
+        let started = Instant::now();
+        assert!(client.get(&url).is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
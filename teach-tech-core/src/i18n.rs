@@ -0,0 +1,590 @@
+use std::{
+    path::PathBuf,
+    sync::{OnceLock, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Json, Request},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use fxhash::FxHashMap;
+use sea_orm::{entity::prelude::*, sea_query::OnConflict, ActiveValue};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    auth::{token, AuthUser, UserID},
+    cache,
+    db::{self, get_db, get_read_db},
+    TeachCore,
+};
+
+/// What every lookup falls back to, so built-in catalogs are always complete in it
+pub const FALLBACK_LOCALE: &str = "en";
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+type Catalogs = FxHashMap<String, FxHashMap<String, String>>;
+
+/// Catalogs added by the core and integrations
+static BUILT_IN: RwLock<Catalogs> =
+    RwLock::new(Catalogs::with_hasher(fxhash::FxBuildHasher::new()));
+/// Catalogs read from `i18n.catalog_dir`, which take precedence
+static CONFIGURED: RwLock<Catalogs> =
+    RwLock::new(Catalogs::with_hasher(fxhash::FxBuildHasher::new()));
+static DEFAULT_LOCALE: OnceLock<String> = OnceLock::new();
+
+tokio::task_local! {
+    static LOCALE: String;
+}
+
+const ENGLISH: &[(&str, &str)] = &[
+    ("error.invalid_cursor", "Invalid cursor"),
+    ("error.must_be_admin", "Must be an administrator"),
+    ("error.must_be_instructor", "Must be an instructor"),
+    ("error.must_be_student", "Must be a student"),
+    (
+        "error.must_create_students",
+        "Must be an administrator that can create students",
+    ),
+    (
+        "error.must_create_instructors",
+        "Must be an administrator that can create instructors",
+    ),
+    (
+        "error.must_manage_maintenance",
+        "Must be an administrator that can manage maintenance mode",
+    ),
+    (
+        "error.token_scope",
+        "This token is limited to scopes that do not allow this",
+    ),
+    (
+        "error.invalid_scope",
+        "Scopes must be separated by spaces, and made of letters, digits, _, -, . and :",
+    ),
+    (
+        "error.missing_permission",
+        "Must be an administrator with the {permission} permission",
+    ),
+    ("error.invalid_email", "Invalid email address"),
+    (
+        "error.invalid_username",
+        "Usernames must be 3 to 32 letters, digits, ., _ and -, with at least one letter",
+    ),
+    ("error.login_taken", "{name} is already used to log in"),
+    (
+        "error.missing_identifier",
+        "Give a user ID, username or email to log in with",
+    ),
+    ("error.invalid_user_id", "Invalid user ID"),
+    (
+        "error.invalid_phone_number",
+        "The phone number must be in E.164 form, such as +14155550100",
+    ),
+    (
+        "error.unknown_locale",
+        "There is no catalog for that locale",
+    ),
+    (
+        "error.storage_unavailable",
+        "File storage is not configured",
+    ),
+    (
+        "error.upload_quarantined",
+        "The file was flagged by the malware scanner",
+    ),
+    (
+        "error.upload_unavailable",
+        "Uploads cannot be accepted right now",
+    ),
+    (
+        "error.photo_type",
+        "The photo must be a PNG, JPEG, GIF or WebP image",
+    ),
+    (
+        "error.captcha_required",
+        "Too many failed logins. Complete the challenge to log in",
+    ),
+    ("error.captcha_invalid", "The challenge was not completed"),
+    (
+        "error.quota_exceeded",
+        "Too many requests. Try again after the time in Retry-After",
+    ),
+    (
+        "error.too_many_logins",
+        "Too many login attempts. Try again after the time in Retry-After",
+    ),
+    (
+        "error.must_manage_quotas",
+        "Must be an administrator that can manage quotas",
+    ),
+    (
+        "error.unknown_route_group",
+        "There is no route group by that name in quotas.groups",
+    ),
+    (
+        "error.invalid_quota",
+        "The quota must allow a whole number of requests within a window of at least a second",
+    ),
+    (
+        "error.must_be_student_or_instructor",
+        "Must be a student or an instructor",
+    ),
+    (
+        "error.invalid_report",
+        "A report needs the content it is about and a reason of at most 2000 characters",
+    ),
+    (
+        "error.already_reported",
+        "You already have an open report of this content",
+    ),
+    (
+        "error.must_moderate_reports",
+        "Must be an administrator that can moderate reports",
+    ),
+    (
+        "error.invalid_report_transition",
+        "The report is {status}, so it cannot be moved to that status",
+    ),
+    (
+        "error.oidc_refused",
+        "The identity provider did not log you in",
+    ),
+    (
+        "error.oidc_invalid_state",
+        "The login expired or was already used. Start it again",
+    ),
+    (
+        "error.oidc_not_linked",
+        "No account is linked to this {provider} login. Ask an administrator to link {subject}",
+    ),
+    (
+        "error.must_link_logins",
+        "Must be an administrator that can link logins",
+    ),
+    (
+        "error.unknown_login_provider",
+        "There is no OpenID, SAML or LDAP provider by that name in auth",
+    ),
+    (
+        "error.login_already_linked",
+        "That login is already linked to an account",
+    ),
+    (
+        "error.saml_refused",
+        "The identity provider did not log you in",
+    ),
+    (
+        "error.saml_expired",
+        "The login expired or was already used. Start it again",
+    ),
+    (
+        "error.saml_no_role",
+        "Your account is not a student or instructor here. Ask an administrator to link it",
+    ),
+    (
+        "error.ldap_not_linked",
+        "Your directory account is not linked to a user here. Ask an administrator to link it",
+    ),
+    (
+        "error.password_too_short",
+        "Passwords must be at least {min} characters long",
+    ),
+    (
+        "error.password_too_long",
+        "Passwords must be at most {max} characters long",
+    ),
+    (
+        "error.password_needs_lowercase",
+        "Passwords must contain a lowercase letter",
+    ),
+    (
+        "error.password_needs_uppercase",
+        "Passwords must contain an uppercase letter",
+    ),
+    (
+        "error.password_needs_digit",
+        "Passwords must contain a digit",
+    ),
+    (
+        "error.password_needs_symbol",
+        "Passwords must contain a symbol",
+    ),
+    (
+        "error.password_breached",
+        "That password has appeared in a data breach. Choose another",
+    ),
+];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct I18nConfig {
+    #[serde(default)]
+    pub i18n: I18nOptions,
+}
+
+/// The `[i18n]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct I18nOptions {
+    /// Used when neither the user nor their `Accept-Language` picks a locale with a catalog
+    #[serde(default = "default_locale")]
+    pub default_locale: String,
+    /// A directory of catalogs such as `fr.toml`, each a flat table of keys to messages. They
+    /// add locales, or override messages of the built-in ones
+    pub catalog_dir: Option<PathBuf>,
+}
+
+impl Default for I18nOptions {
+    fn default() -> Self {
+        Self {
+            default_locale: default_locale(),
+            catalog_dir: None,
+        }
+    }
+}
+
+fn default_locale() -> String {
+    FALLBACK_LOCALE.into()
+}
+
+/// The locale each user picked, which takes precedence over `Accept-Language`.
+pub mod user_locales {
+    use super::*;
+
+    #[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+    #[sea_orm(table_name = "user_locales")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub user_id: UserID,
+        pub locale: String,
+        pub updated_at: DateTime,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+/// Lowercases a language tag and replaces underscores, so that `pt_BR` and `pt-br` match.
+fn normalize(locale: &str) -> String {
+    locale.trim().replace('_', "-").to_ascii_lowercase()
+}
+
+/// Adds messages to the catalog of `locale`, replacing any with the same keys.
+pub fn add_catalog<K, V>(locale: &str, messages: impl IntoIterator<Item = (K, V)>)
+where
+    K: Into<String>,
+    V: Into<String>,
+{
+    BUILT_IN
+        .write()
+        .unwrap()
+        .entry(normalize(locale))
+        .or_default()
+        .extend(
+            messages
+                .into_iter()
+                .map(|(key, message)| (key.into(), message.into())),
+        );
+}
+
+/// Every locale with a catalog.
+pub fn locales() -> Vec<String> {
+    let mut locales: Vec<_> = BUILT_IN.read().unwrap().keys().cloned().collect();
+    for locale in CONFIGURED.read().unwrap().keys() {
+        if !locales.contains(locale) {
+            locales.push(locale.clone());
+        }
+    }
+    locales.sort();
+    locales
+}
+
+/// The locale with a catalog that best matches `locale`, trying its primary language after it.
+pub fn supported(locale: &str) -> Option<String> {
+    let locale = normalize(locale);
+    let has_catalog = |locale: &str| {
+        BUILT_IN.read().unwrap().contains_key(locale)
+            || CONFIGURED.read().unwrap().contains_key(locale)
+    };
+    if has_catalog(&locale) {
+        return Some(locale);
+    }
+    let (primary, _) = locale.split_once('-')?;
+    has_catalog(primary).then(|| primary.to_string())
+}
+
+pub fn get_default_locale() -> &'static str {
+    DEFAULT_LOCALE
+        .get()
+        .map(String::as_str)
+        .unwrap_or(FALLBACK_LOCALE)
+}
+
+fn lookup(locale: &str, key: &str) -> Option<String> {
+    for catalogs in [&CONFIGURED, &BUILT_IN] {
+        if let Some(message) = catalogs
+            .read()
+            .unwrap()
+            .get(locale)
+            .and_then(|catalog| catalog.get(key))
+        {
+            return Some(message.clone());
+        }
+    }
+    None
+}
+
+/// The message for `key` in `locale`, falling back to its primary language, the default locale
+/// and English, in that order. Keys without any message are returned as they are.
+pub fn translate(locale: &str, key: &str) -> String {
+    let locale = normalize(locale);
+    let primary = locale.split_once('-').map(|(primary, _)| primary);
+    let message = [
+        Some(locale.as_str()),
+        primary,
+        Some(get_default_locale()),
+        Some(FALLBACK_LOCALE),
+    ]
+    .into_iter()
+    .flatten()
+    .find_map(|locale| lookup(locale, key));
+    message.unwrap_or_else(|| key.to_string())
+}
+
+/// Like [`translate`], filling in placeholders written as `{name}`.
+pub fn translate_with(locale: &str, key: &str, values: &[(&str, &str)]) -> String {
+    let mut message = translate(locale, key);
+    for (name, value) in values {
+        message = message.replace(&format!("{{{name}}}"), value);
+    }
+    message
+}
+
+/// The locale of the request being handled, or the default locale outside of one.
+pub fn current_locale() -> String {
+    LOCALE
+        .try_with(Clone::clone)
+        .unwrap_or_else(|_| get_default_locale().to_string())
+}
+
+/// The message for `key` in the locale of the current request.
+pub fn t(key: &str) -> String {
+    translate(&current_locale(), key)
+}
+
+/// Like [`t`], filling in placeholders written as `{name}`.
+pub fn t_with(key: &str, values: &[(&str, &str)]) -> String {
+    translate_with(&current_locale(), key, values)
+}
+
+/// The locale messages to `user_id` should be in, such as mails and notifications.
+pub async fn locale_of(user_id: UserID) -> String {
+    match user_locales::Entity::find_by_id(user_id)
+        .one(get_read_db())
+        .await
+    {
+        Ok(Some(stored)) => stored.locale,
+        Ok(None) => get_default_locale().to_string(),
+        Err(e) => {
+            error!("Error reading locale of {user_id}: {e:#}");
+            get_default_locale().to_string()
+        }
+    }
+}
+
+/// The best supported locale in an `Accept-Language` header, such as `fr-CH, fr;q=0.9, en;q=0.8`.
+fn from_accept_language(header: &str) -> Option<String> {
+    let mut ranges: Vec<(&str, f32)> = header
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let q = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse().ok())?;
+            (!tag.is_empty() && tag != "*" && q > 0.0).then_some((tag, q))
+        })
+        .collect();
+    // Stable, so that ranges with the same weight keep their order
+    ranges.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    ranges.into_iter().find_map(|(tag, _)| supported(tag))
+}
+
+fn cache_key(user_id: UserID) -> String {
+    format!("locale:{user_id}")
+}
+
+/// The locale `user_id` picked, if any.
+async fn stored_locale(user_id: UserID) -> Option<String> {
+    match cache::get_json::<Option<String>>(&cache_key(user_id)).await {
+        Ok(Some(locale)) => return locale,
+        Ok(None) => {}
+        Err(e) => error!("Error reading cached locale: {e:#}"),
+    }
+    let locale = match user_locales::Entity::find_by_id(user_id)
+        .one(get_read_db())
+        .await
+    {
+        Ok(stored) => stored.map(|stored| stored.locale),
+        Err(e) => {
+            error!("Error reading stored locale of {user_id}: {e:#}");
+            return None;
+        }
+    };
+    if let Err(e) = cache::set_json(&cache_key(user_id), &locale, Some(CACHE_TTL)).await {
+        error!("Error caching locale: {e:#}");
+    }
+    locale
+}
+
+/// Picks the locale of the request from the user's stored locale, then `Accept-Language`.
+///
+/// Only valid tokens are looked up, so made-up tokens cannot fill the cache.
+pub(crate) async fn resolve_locale(request: Request, next: Next) -> Response {
+    let (mut parts, body) = request.into_parts();
+    let mut locale = None;
+    if db::is_available() {
+        match token::validate_bearer(&parts.headers, &mut parts.extensions).await {
+            Ok(Some(valid)) => locale = stored_locale(valid.user_id).await,
+            Ok(None) => {}
+            Err(e) => error!("Error validating bearer token: {e:#}"),
+        }
+    }
+    let locale = locale
+        .or_else(|| {
+            parts
+                .headers
+                .get(header::ACCEPT_LANGUAGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(from_accept_language)
+        })
+        .unwrap_or_else(|| get_default_locale().to_string());
+    let request = Request::from_parts(parts, body);
+
+    let mut response = LOCALE.scope(locale.clone(), next.run(request)).await;
+    if let Ok(value) = locale.parse() {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LANGUAGE, value);
+    }
+    response
+}
+
+fn load_catalogs(dir: &PathBuf) -> anyhow::Result<()> {
+    let mut configured = CONFIGURED.write().unwrap();
+    for entry in std::fs::read_dir(dir)
+        .map_err(|e| anyhow::anyhow!("Reading i18n.catalog_dir {}: {e}", dir.display()))?
+    {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "toml") {
+            continue;
+        }
+        let Some(locale) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let catalog: FxHashMap<String, String> = toml::from_str(
+            &std::fs::read_to_string(&path)
+                .map_err(|e| anyhow::anyhow!("Reading {}: {e}", path.display()))?,
+        )
+        .map_err(|e| anyhow::anyhow!("Parsing {}: {e}", path.display()))?;
+        configured
+            .entry(normalize(locale))
+            .or_default()
+            .extend(catalog);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetLocale {
+    pub locale: String,
+}
+
+#[derive(Debug, Serialize)]
+struct LocaleInfo {
+    locale: String,
+    /// Whether the user picked it, as opposed to it coming from the request
+    stored: bool,
+}
+
+async fn get_locale(AuthUser { user_id, .. }: AuthUser) -> Response {
+    let stored = stored_locale(user_id).await.is_some();
+    (
+        StatusCode::OK,
+        Json(LocaleInfo {
+            locale: current_locale(),
+            stored,
+        }),
+    )
+        .into_response()
+}
+
+async fn set_locale(AuthUser { user_id, .. }: AuthUser, Json(set): Json<SetLocale>) -> Response {
+    let Some(locale) = supported(&set.locale) else {
+        return (StatusCode::BAD_REQUEST, t("error.unknown_locale")).into_response();
+    };
+    let result = user_locales::Entity::insert(user_locales::ActiveModel {
+        user_id: ActiveValue::set(user_id),
+        locale: ActiveValue::set(locale),
+        updated_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+    })
+    .on_conflict(
+        OnConflict::column(user_locales::Column::UserId)
+            .update_columns([
+                user_locales::Column::Locale,
+                user_locales::Column::UpdatedAt,
+            ])
+            .to_owned(),
+    )
+    .exec_without_returning(get_db())
+    .await;
+    if let Err(e) = result {
+        error!("Error saving locale of {user_id}: {e:#}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+    }
+    if let Err(e) = cache::get_cache().invalidate(&cache_key(user_id)).await {
+        error!("Error invalidating cached locale of {user_id}: {e:#}");
+    }
+    (StatusCode::OK, ()).into_response()
+}
+
+/// Goes back to following `Accept-Language`.
+async fn clear_locale(AuthUser { user_id, .. }: AuthUser) -> Response {
+    if let Err(e) = user_locales::Entity::delete_by_id(user_id)
+        .exec(get_db())
+        .await
+    {
+        error!("Error deleting locale of {user_id}: {e:#}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+    }
+    if let Err(e) = cache::get_cache().invalidate(&cache_key(user_id)).await {
+        error!("Error invalidating cached locale of {user_id}: {e:#}");
+    }
+    (StatusCode::OK, ()).into_response()
+}
+
+pub fn add_to_core<S: Clone + Send + Sync + 'static>(
+    mut core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    let config: I18nConfig = toml::from_str(core.get_config_str())?;
+    core.add_db_reset_config(user_locales::Entity);
+    add_catalog(FALLBACK_LOCALE, ENGLISH.iter().copied());
+    if let Some(dir) = &config.i18n.catalog_dir {
+        load_catalogs(dir)?;
+    }
+    let _ = DEFAULT_LOCALE.set(normalize(&config.i18n.default_locale));
+
+    Ok(core.modify_router(|router| {
+        router
+            .route("/i18n/locales", get(|| async { Json(locales()) }))
+            .route(
+                "/i18n/locale",
+                get(get_locale).put(set_locale).delete(clear_locale),
+            )
+    }))
+}
@@ -5,55 +5,125 @@
 #![feature(try_blocks)]
 
 use std::{
-    future::Future, net::{IpAddr, Ipv4Addr, SocketAddr}, path::Path, pin::Pin, process::ExitCode, sync::Arc
+    any::{type_name, TypeId},
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    pin::Pin,
+    process::ExitCode,
+    sync::Arc,
 };
 
 use anyhow::Context;
-use axum::{body::Body, response::Response, routing::get, Router};
+use axum::{body::Body, middleware, response::Response, routing::get, Extension, Router};
 use clap::{Parser, Subcommand};
-use db::{get_db, init_db};
+use config::ConfigSchema;
+use db::init_db;
 use fxhash::FxHashMap;
+use on_serve::OnServeEntry;
+use routes::{RouteInfo, TrackedRouter};
+use scheduler::ScheduledTask;
+use schema_diff::EntityTable;
 use sea_orm::{
-    sea_query::{IntoTableRef, Table, TableCreateStatement, TableDropStatement},
+    sea_query::{IntoTableRef, Table},
     ConnectionTrait, EntityTrait, Schema,
 };
 use sea_orm_migration::SchemaManager;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::to_value;
+use state::{StateMap, StateRegistry};
 use tokio::sync::Notify;
-use tower_http::{compression, cors, decompression, trace};
-use tracing::error;
+use tower_http::{cors, decompression};
+use tracing::{error, info};
 use tracing_subscriber::EnvFilter;
-use users::admins::create_admin;
+use users::admins::{create_admin, delete_admin};
 
 pub use anyhow;
+#[cfg(feature = "graphql")]
+pub use async_graphql;
 pub use axum;
+pub use sea_orm;
 pub use serde_json;
 pub use tokio;
 
+pub mod access_log;
+pub mod audit;
 pub mod auth;
+pub mod bench;
+pub mod cache;
+pub mod calendar;
+pub mod client_ip;
+pub mod conditional;
+pub mod config;
 pub mod db;
+pub mod encryption;
+pub mod error_reporting;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+pub mod health;
+pub mod http;
+pub mod i18n;
+pub mod mail;
+pub mod maintenance;
+pub mod metrics;
+pub mod notifications;
+mod on_serve;
+pub mod openapi;
+pub mod quotas;
+pub mod reports;
+pub mod request_id;
+pub mod response_compression;
+pub mod retention;
+pub mod routes;
+mod scheduler;
+pub mod schema_diff;
+pub mod security;
+pub mod server;
 pub mod siblings;
+pub mod sms;
+pub mod state;
+pub mod storage;
 pub mod users;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ApiConfig {
     #[serde(default = "default_server_address")]
     pub server_address: SocketAddr,
+    #[serde(default)]
+    pub trusted_proxies: Vec<ipnet::IpNet>,
+    #[serde(default)]
+    pub api: ApiOptions,
+}
+
+/// The `[api]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ApiOptions {
+    #[serde(default)]
+    pub compression: response_compression::CompressionOptions,
+    #[serde(default)]
+    pub http: server::HttpOptions,
 }
 
 fn default_server_address() -> SocketAddr {
     SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 80)
 }
 
+type ToDrop = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()>>> + Send>;
+type Seed = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> + Send>;
+
 pub struct TeachCore<S = ()> {
     router: Router<S>,
     schema: Schema,
-    reset_db: Vec<(TableDropStatement, TableCreateStatement)>,
+    reset_db: Vec<EntityTable>,
     config: String,
     info: FxHashMap<String, serde_json::Value>,
-    on_serve: Vec<Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> + Send>>,
-    to_drop: Vec<Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()>>> + Send>>,
+    on_serve: Vec<OnServeEntry>,
+    to_drop: Vec<ToDrop>,
+    states: StateMap,
+    routes: Vec<RouteInfo>,
+    config_schemas: Vec<ConfigSchema>,
+    seeds: Vec<(String, Seed)>,
+    scheduled_tasks: Vec<ScheduledTask>,
 }
 
 impl<S> TeachCore<S> {
@@ -61,11 +131,83 @@ impl<S> TeachCore<S> {
         &self.config
     }
 
+    pub fn get_routes(&self) -> &[RouteInfo] {
+        &self.routes
+    }
+
+    /// Declares a struct that is deserialized from `teach-config.toml`, so that the
+    /// `check-config` command can validate it and recognize its keys.
+    pub fn declare_config<T: DeserializeOwned>(&mut self) {
+        self.config_schemas.push(ConfigSchema::new::<T>());
+    }
+
     pub fn add_db_reset_config(&mut self, entity: impl IntoTableRef + EntityTrait) {
         let mut drop = Table::drop();
         drop.table(entity).if_exists();
-        let create = self.schema.create_table_from_entity(entity);
-        self.reset_db.push((drop, create));
+        self.reset_db.push(EntityTable {
+            connection: None,
+            drop,
+            create: self.schema.create_table_from_entity(entity),
+            indexes: self.schema.create_index_from_entity(entity),
+        });
+    }
+
+    /// Like [`Self::add_db_reset_config`], but for an entity stored in the connection returned by
+    /// [`db::get_named_db`].
+    pub fn add_named_db_reset_config(
+        &mut self,
+        connection: &str,
+        entity: impl IntoTableRef + EntityTrait,
+    ) -> anyhow::Result<()> {
+        let db_config: db::DBConfig = toml::from_str(self.get_config_str())?;
+        let mut drop = Table::drop();
+        drop.table(entity).if_exists();
+        let named_schema = match db_config.database.connections.get(connection) {
+            Some(url) => Some(Schema::new(db::backend_from_url(url)?)),
+            None => None,
+        };
+        let schema = named_schema.as_ref().unwrap_or(&self.schema);
+        self.reset_db.push(EntityTable {
+            connection: Some(connection.to_string()),
+            drop,
+            create: schema.create_table_from_entity(entity),
+            indexes: schema.create_index_from_entity(entity),
+        });
+        Ok(())
+    }
+
+    /// Deletes rows of `entity` whose `column` is older than `max_age`. Rules run with the other
+    /// `[retention]` policies, and `retention.tables.<table name>` overrides `max_age` in days.
+    pub fn add_retention<E: EntityTrait>(
+        &mut self,
+        entity: E,
+        column: E::Column,
+        max_age: std::time::Duration,
+    ) {
+        retention::add_rule(None, entity, column, max_age);
+    }
+
+    /// Like [`Self::add_retention`], but for an entity stored in the connection returned by
+    /// [`db::get_named_db`].
+    pub fn add_named_retention<E: EntityTrait>(
+        &mut self,
+        connection: &str,
+        entity: E,
+        column: E::Column,
+        max_age: std::time::Duration,
+    ) {
+        retention::add_rule(Some(connection.to_string()), entity, column, max_age);
+    }
+
+    /// Registers a column of type [`encryption::Encrypted`], so that `encrypt-columns` encrypts
+    /// the values it holds in plain text.
+    pub fn add_encrypted_column<E>(&mut self, entity: E, column: E::Column)
+    where
+        E: EntityTrait,
+        E::Model: sea_orm::IntoActiveModel<E::ActiveModel> + Sync,
+        E::ActiveModel: sea_orm::ActiveModelBehavior + Send,
+    {
+        encryption::add_column(entity, column);
     }
 
     pub fn add_info(&mut self, name: impl Into<String>, value: impl Serialize) {
@@ -76,23 +218,111 @@ impl<S> TeachCore<S> {
         }
     }
 
-    pub fn modify_router<T>(self, f: impl FnOnce(Router<S>) -> Router<T>) -> TeachCore<T> {
+    pub fn insert_state<T: Clone + Send + Sync + 'static>(&mut self, value: T) {
+        if self
+            .states
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .is_some()
+        {
+            panic!("Duplicate state type: {}", type_name::<T>());
+        }
+    }
+
+    pub fn get_state<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.states
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    #[track_caller]
+    pub fn modify_router<T>(
+        mut self,
+        f: impl FnOnce(TrackedRouter<S>) -> TrackedRouter<T>,
+    ) -> TeachCore<T> {
+        let integration = routes::integration_name(std::panic::Location::caller());
+        let (router, routes) = f(TrackedRouter::new(self.router, integration)).into_parts();
+        self.routes.extend(routes);
         TeachCore {
-            router: f(self.router),
+            router,
+            routes: self.routes,
             info: self.info,
             schema: self.schema,
             reset_db: self.reset_db,
             config: self.config,
             on_serve: self.on_serve,
             to_drop: self.to_drop,
+            states: self.states,
+            config_schemas: self.config_schemas,
+            seeds: self.seeds,
+            scheduled_tasks: self.scheduled_tasks,
         }
     }
 
+    #[track_caller]
     pub fn add_on_serve<Fut>(&mut self, f: impl FnOnce() -> Fut + Send + 'static)
     where
         Fut: Future<Output = anyhow::Result<()>> + 'static,
     {
-        self.on_serve.push(Box::new(|| Box::pin(f())));
+        let caller = std::panic::Location::caller();
+        self.add_on_serve_named(format!("{}:{}", caller.file(), caller.line()), 0, f);
+    }
+
+    /// Registers a callback that runs before the API starts serving.
+    ///
+    /// Callbacks run in ascending `priority`, with ties running in registration order.
+    pub fn add_on_serve_named<Fut>(
+        &mut self,
+        name: impl Into<String>,
+        priority: i32,
+        f: impl FnOnce() -> Fut + Send + 'static,
+    ) where
+        Fut: Future<Output = anyhow::Result<()>> + 'static,
+    {
+        self.on_serve.push(OnServeEntry {
+            name: name.into(),
+            priority,
+            parallel: false,
+            f: Box::new(|| Box::pin(f())),
+        });
+    }
+
+    /// Like [`Self::add_on_serve_named`], but the callback runs concurrently with every other
+    /// parallel callback of the same priority.
+    pub fn add_on_serve_parallel<Fut>(
+        &mut self,
+        name: impl Into<String>,
+        priority: i32,
+        f: impl FnOnce() -> Fut + Send + 'static,
+    ) where
+        Fut: Future<Output = anyhow::Result<()>> + 'static,
+    {
+        self.on_serve.push(OnServeEntry {
+            name: name.into(),
+            priority,
+            parallel: true,
+            f: Box::new(|| Box::pin(f())),
+        });
+    }
+
+    /// Registers a task that runs when the API starts serving and then every `interval`.
+    pub fn add_scheduled_task<Fut>(
+        &mut self,
+        name: impl Into<String>,
+        interval: std::time::Duration,
+        f: impl Fn() -> Fut + Send + Sync + 'static,
+    ) where
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.scheduled_tasks.push(ScheduledTask {
+            name: name.into(),
+            interval,
+            f: Box::new(move || Box::pin(f())),
+        });
+    }
+
+    /// Registers a reporter for panics, 5xx responses and failed scheduled tasks.
+    pub fn add_error_reporter(&mut self, reporter: impl error_reporting::ErrorReporter) {
+        error_reporting::add_reporter(reporter);
     }
 
     pub fn add_to_drop<Fut>(&mut self, f: impl FnOnce() -> Fut + Send + 'static)
@@ -102,14 +332,63 @@ impl<S> TeachCore<S> {
         self.to_drop.push(Box::new(|| Box::pin(f())));
     }
 
-    pub async fn reset_db(self) -> anyhow::Result<ExitCode> {
-        let manager = SchemaManager::new(get_db());
-        let builder = get_db().get_database_backend();
+    /// Registers a callback that inserts default data after `reset-db` creates the tables, and
+    /// whenever the `seed` command is run.
+    ///
+    /// Seeds run in registration order. Since `seed` can run against a database that already has
+    /// data, seeds should skip rows that already exist.
+    pub fn add_seed<Fut>(
+        &mut self,
+        name: impl Into<String>,
+        f: impl FnOnce() -> Fut + Send + 'static,
+    ) where
+        Fut: Future<Output = anyhow::Result<()>> + 'static,
+    {
+        self.seeds.push((name.into(), Box::new(|| Box::pin(f()))));
+    }
+
+    /// Drops and creates every table given to [`Self::add_db_reset_config`].
+    async fn reset_tables(&mut self) -> anyhow::Result<()> {
+        for table in std::mem::take(&mut self.reset_db) {
+            let db = table.db();
+            SchemaManager::new(db).drop_table(table.drop).await?;
+            db.execute(db.get_database_backend().build(&table.create))
+                .await?;
+            for index in table.indexes {
+                db.execute(db.get_database_backend().build(&index)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_seeds(&mut self) -> anyhow::Result<()> {
+        for (name, seed) in std::mem::take(&mut self.seeds) {
+            seed().await.with_context(|| format!("Seeding {name}"))?;
+            info!("Seeded {name}");
+        }
+        Ok(())
+    }
 
-        for (drop, create) in self.reset_db {
-            manager.drop_table(drop).await?;
-            get_db().execute(builder.build(&create)).await?;
+    pub async fn schema_diff(self) -> anyhow::Result<ExitCode> {
+        let drift = schema_diff::schema_diff(&self.reset_db).await?;
+        if drift.is_empty() {
+            println!("The database schema matches every entity");
+            return Ok(ExitCode::SUCCESS);
         }
+        for drift in drift {
+            println!("{drift}");
+        }
+        Ok(ExitCode::FAILURE)
+    }
+
+    pub async fn seed(mut self) -> anyhow::Result<ExitCode> {
+        self.run_seeds().await?;
+        Ok(ExitCode::SUCCESS)
+    }
+
+    pub async fn reset_db(mut self) -> anyhow::Result<ExitCode> {
+        self.reset_tables().await?;
+        self.run_seeds().await?;
 
         let _ = std::thread::spawn(move || {
             tokio::runtime::Builder::new_multi_thread()
@@ -119,28 +398,62 @@ impl<S> TeachCore<S> {
                 .block_on(async {
                     drop(self.to_drop);
                 });
-        }).join();
+        })
+        .join();
 
         Ok(ExitCode::SUCCESS)
     }
 }
 
 impl TeachCore<()> {
+    fn new(config: String) -> anyhow::Result<Self> {
+        let builder = db::backend_from_config(&config)?;
+        Ok(Self {
+            router: Router::new(),
+            routes: vec![],
+            info: FxHashMap::default(),
+            schema: Schema::new(builder),
+            reset_db: vec![],
+            config,
+            on_serve: vec![],
+            to_drop: vec![],
+            states: StateMap::default(),
+            config_schemas: vec![],
+            seeds: vec![],
+            scheduled_tasks: vec![],
+        })
+    }
+
+    /// Builds a core on `config` without any of the core's own routes, for testing integrations
+    /// with.
+    ///
+    /// The databases in `config` are connected, which can only happen once per process.
+    pub async fn for_tests(config: &str) -> anyhow::Result<Self> {
+        init_db(config).await?;
+        encryption::init(config)?;
+        Self::new(config.to_string())
+    }
+
+    /// Recreates every table given to [`Self::add_db_reset_config`] and returns the router
+    /// without the layers it is served with, for tests to send requests to.
+    pub async fn into_test_router(mut self) -> anyhow::Result<Router> {
+        self.reset_tables().await?;
+        Ok(self
+            .router
+            .layer(Extension(StateRegistry::new(self.states))))
+    }
+
     pub async fn serve(self) -> anyhow::Result<ExitCode> {
         let api_config: ApiConfig =
             toml::from_str(self.get_config_str()).context("Parsing teach-config.toml")?;
+        api_config.api.http.validate()?;
+        encryption::warn_if_plaintext();
 
         let listener = tokio::net::TcpListener::bind(api_config.server_address)
             .await
             .with_context(|| format!("Binding to {}", api_config.server_address))?;
 
-        let cors = cors::CorsLayer::new().allow_methods(cors::Any);
-
-        #[cfg(debug_assertions)]
-        let cors = cors.allow_origin(cors::Any).allow_headers(cors::Any);
-        let router = self.router;
-        #[cfg(debug_assertions)]
-        let router = router.layer(hot_reload::HotReloadLayer::default());
+        let router = layered_router(self.router, self.states, &api_config);
 
         let (finished_tx, finished_rx) = tokio::sync::oneshot::channel();
 
@@ -152,23 +465,18 @@ impl TeachCore<()> {
         let cancel_clone = cancel.clone();
         let service_handle = std::thread::spawn(move || {
             runtime.block_on(async {
-                for on_serve in self.on_serve {
-                    if let Err(e) = on_serve().await {
-                        let _ = finished_tx.send(Err(e).context("Calling on_serve API"));
-                        return;
-                    }
+                if let Err(e) = on_serve::run_all(self.on_serve).await {
+                    let _ = finished_tx.send(Err(e).context("Calling on_serve API"));
+                    return;
                 }
+                scheduler::start_all(self.scheduled_tasks);
                 tokio::select! {
-                    result = axum::serve(
+                    _ = server::serve(
                         listener,
-                        router
-                            .layer(cors)
-                            .layer(trace::TraceLayer::new_for_http())
-                            .layer(compression::CompressionLayer::new())
-                            .layer(decompression::DecompressionLayer::new())
-                            .into_make_service_with_connect_info::<SocketAddr>(),
+                        router,
+                        api_config.api.http,
                     ) => {
-                        let _ = finished_tx.send(result.context("Serving API"));
+                        let _ = finished_tx.send(Ok(()));
                     }
                     _ = cancel_clone.notified() => { }
                 }
@@ -212,6 +520,43 @@ impl TeachCore<()> {
 
         Ok(ExitCode::SUCCESS)
     }
+
+    pub async fn bench(self, args: bench::BenchArgs) -> anyhow::Result<ExitCode> {
+        let api_config: ApiConfig =
+            toml::from_str(self.get_config_str()).context("Parsing teach-config.toml")?;
+        let remote = args.is_remote();
+        let router = layered_router(self.router, self.states, &api_config);
+        let result = bench::run(args, router, self.on_serve).await;
+        // Nothing was set up to clean up when benchmarking another process
+        if !remote {
+            for to_drop in self.to_drop {
+                to_drop().await;
+            }
+        }
+        result
+    }
+}
+
+/// Wraps `router` in every layer the core serves it with.
+fn layered_router(router: Router, states: StateMap, api_config: &ApiConfig) -> Router {
+    let cors = cors::CorsLayer::new().allow_methods(cors::Any);
+
+    #[cfg(debug_assertions)]
+    let cors = cors.allow_origin(cors::Any).allow_headers(cors::Any);
+    let router = router
+        .layer(Extension(StateRegistry::new(states)))
+        .layer(middleware::from_fn(quotas::enforce))
+        .layer(middleware::from_fn(maintenance::reject_during_maintenance))
+        .layer(middleware::from_fn(db::reject_while_unavailable))
+        .layer(middleware::from_fn(i18n::resolve_locale));
+    #[cfg(debug_assertions)]
+    let router = router.layer(hot_reload::HotReloadLayer::default());
+    let router = security::add_layer(router);
+    let router = error_reporting::add_layer(router);
+    let router = access_log::add_layer(router);
+    let router = request_id::add_layers(router.layer(cors));
+    let router = response_compression::add_layer(router, &api_config.api.compression);
+    router.layer(decompression::RequestDecompressionLayer::new())
 }
 
 #[derive(Subcommand)]
@@ -222,8 +567,36 @@ pub enum Command {
         user_id: i32,
         permissions: Vec<users::admins::permissions::Permission>,
     },
+    /// Marks an admin as deleted and takes away their permissions
+    DeleteAdmin {
+        #[arg(value_parser = clap::value_parser!(i32).range(0..))]
+        user_id: i32,
+    },
     Run,
     ResetDB,
+    Seed,
+    Maintenance {
+        #[command(subcommand)]
+        command: maintenance::MaintenanceCommand,
+    },
+    Routes,
+    /// Prints an OpenAPI document describing every route, for generating API clients
+    #[command(name = "openapi")]
+    OpenApi {
+        /// Writes the document to this file instead
+        #[arg(long)]
+        output: Option<std::path::PathBuf>,
+    },
+    CheckConfig,
+    SchemaDiff,
+    /// Encrypts columns holding values that were written in plain text, such as before
+    /// encryption.key was set
+    EncryptColumns,
+    /// Prints the version, git commit, build time and integrations this executable was built with
+    Version,
+    /// Times a scenario against a running instance or an in-process router and prints latency
+    /// percentiles
+    Bench(bench::BenchArgs),
 }
 
 #[derive(Parser)]
@@ -247,7 +620,17 @@ where
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_env("LOG_LEVEL"))
         .init();
-    init_db(&config).await?;
+    if let Command::Bench(args) = &command {
+        args.check()?;
+    }
+    if !matches!(
+        command,
+        Command::Routes | Command::OpenApi { .. } | Command::CheckConfig | Command::Version
+    ) && !matches!(&command, Command::Bench(args) if args.is_remote())
+    {
+        init_db(&config).await?;
+        encryption::init(&config)?;
+    }
     match command {
         Command::CreateAdmin {
             username,
@@ -258,45 +641,149 @@ where
                 .await
                 .map(|()| ExitCode::SUCCESS);
         }
+        Command::DeleteAdmin { user_id } => {
+            return delete_admin(user_id.try_into().unwrap())
+                .await
+                .map(|()| ExitCode::SUCCESS);
+        }
+        Command::Maintenance { command } => {
+            return maintenance::run_command(command, &config)
+                .await
+                .map(|()| ExitCode::SUCCESS);
+        }
         Command::Run => {}
         Command::ResetDB => {}
+        Command::Seed => {}
+        Command::Routes => {}
+        Command::OpenApi { .. } => {}
+        Command::CheckConfig => {}
+        Command::SchemaDiff => {}
+        Command::EncryptColumns => {}
+        Command::Version => {}
+        Command::Bench(_) => {}
     }
 
-    let builder = get_db().get_database_backend();
-    let core = TeachCore {
-        router: Router::new(),
-        info: FxHashMap::default(),
-        schema: Schema::new(builder),
-        reset_db: vec![],
-        config,
-        on_serve: vec![],
-        to_drop: vec![],
-    };
-    let core = auth::add_to_core(core).await;
+    let mut core = TeachCore::new(config)?;
+    core.declare_config::<ApiConfig>();
+    core.declare_config::<db::DBConfig>();
+    core.declare_config::<maintenance::MaintenanceConfig>();
+    core.declare_config::<cache::CacheConfig>();
+    core.declare_config::<encryption::EncryptionConfig>();
+    core.declare_config::<retention::RetentionConfig>();
+    core.declare_config::<metrics::MetricsConfig>();
+    core.declare_config::<access_log::AccessLogConfig>();
+    core.declare_config::<error_reporting::ErrorReportingConfig>();
+    core.declare_config::<security::SecurityAlertConfig>();
+    core.declare_config::<mail::MailConfig>();
+    core.declare_config::<sms::SmsConfig>();
+    core.declare_config::<storage::StorageConfig>();
+    core.declare_config::<i18n::I18nConfig>();
+    core.declare_config::<auth::AuthConfig>();
+    core.declare_config::<quotas::QuotaConfig>();
+    #[cfg(feature = "graphql")]
+    core.declare_config::<graphql::GraphqlConfig>();
+    // Report problems with the core's own config before any of it is parsed while building
+    if let Command::CheckConfig = command {
+        let report = config::check_config(core.get_config_str(), &core.config_schemas);
+        if !report.is_ok() {
+            print!("{report}");
+            return Ok(ExitCode::FAILURE);
+        }
+    }
+    let core = db::add_to_core(core)?;
+    let core = client_ip::add_to_core(core)?;
+    let core = access_log::add_to_core(core)?;
+    let core = error_reporting::add_to_core(core)?;
+    let core = cache::add_to_core(core)?;
+    let core = i18n::add_to_core(core)?;
+    let core = auth::add_to_core(core).await?;
     let core = users::admins::add_to_core(core);
     let core = users::students::add_to_core(core);
     let core = users::instructors::add_to_core(core);
+    let core = users::photos::add_to_core(core);
+    let core = notifications::add_to_core(core);
+    let core = calendar::add_to_core(core);
+    let core = reports::add_to_core(core);
+    let core = audit::add_to_core(core);
+    let core = mail::add_to_core(core)?;
+    let core = sms::add_to_core(core)?;
+    let core = storage::add_to_core(core)?;
+    #[cfg(feature = "graphql")]
+    let core = graphql::add_to_core(core)?;
     let core = siblings::add_to_core(core)?;
+    let core = maintenance::add_to_core(core)?;
+    let core = quotas::add_to_core(core)?;
+    let core = retention::add_to_core(core)?;
+    let core = metrics::add_to_core(core)?;
+    let core = security::add_to_core(core)?;
+    let core = health::add_to_core(core);
     let mut core = f(core).await?;
     let info = std::mem::take(&mut core.info);
+    let version = info
+        .get("version")
+        .and_then(|version| version.as_str())
+        .unwrap_or("0.0.0")
+        .to_string();
+    if let Command::Version = command {
+        let info: std::collections::BTreeMap<_, _> = info.into_iter().collect();
+        println!("{}", serde_json::to_string_pretty(&info).unwrap());
+        return Ok(ExitCode::SUCCESS);
+    }
     let info = serde_json::to_string(&info).unwrap();
     let info: &_ = Box::leak(info.into_boxed_str());
-    core.router = core.router.route(
-        "/info",
-        get(move || {
-            std::future::ready(
-                Response::builder()
-                    .header("Content-Type", "application/json")
-                    .body(Body::from(info))
-                    .unwrap(),
-            )
-        }),
-    );
+    let core = core.modify_router(|router| {
+        router.route(
+            "/info",
+            get(move || {
+                std::future::ready(
+                    Response::builder()
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(info))
+                        .unwrap(),
+                )
+            }),
+        )
+    });
 
     match command {
-        Command::CreateAdmin { .. } => unreachable!(),
+        Command::CreateAdmin { .. }
+        | Command::DeleteAdmin { .. }
+        | Command::Maintenance { .. }
+        | Command::Version => {
+            unreachable!()
+        }
         Command::Run => core.serve().await,
         Command::ResetDB => core.reset_db().await,
+        Command::Seed => core.seed().await,
+        Command::SchemaDiff => core.schema_diff().await,
+        Command::EncryptColumns => {
+            encryption::encrypt_columns().await?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Bench(args) => core.bench(args).await,
+        Command::Routes => {
+            routes::print_routes(core.get_routes());
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::OpenApi { output } => {
+            let spec = openapi::openapi_spec(core.get_routes(), &version);
+            let spec = serde_json::to_string_pretty(&spec).unwrap();
+            match output {
+                Some(output) => std::fs::write(&output, spec)
+                    .with_context(|| format!("Writing {}", output.display()))?,
+                None => println!("{spec}"),
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::CheckConfig => {
+            let report = config::check_config(core.get_config_str(), &core.config_schemas);
+            print!("{report}");
+            if report.is_ok() {
+                Ok(ExitCode::SUCCESS)
+            } else {
+                Ok(ExitCode::FAILURE)
+            }
+        }
     }
 }
 
@@ -0,0 +1,748 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Json, Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+};
+use futures::future::BoxFuture;
+use fxhash::FxHashMap;
+use sea_orm::{
+    entity::prelude::*,
+    sea_query::{self, OnConflict, Table},
+    ActiveValue, QueryOrder, QuerySelect, Schema, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::{
+    auth::{AdminUser, AuthUser, UserID},
+    db::{get_db, get_read_db, paginate, PageQuery},
+    encryption::{self, Encrypted},
+    i18n, notifications, TeachCore,
+};
+
+pub mod smtp;
+pub mod templates;
+
+pub use templates::{Rendered, TemplateKind, TemplateOverride};
+
+const QUEUE_MAX_AGE: Duration = Duration::from_days(30);
+// A claimed mail is left alone by other siblings for this long, which must outlast sending it
+const CLAIM_SECS: i64 = 600;
+const BATCH_SIZE: u64 = 100;
+
+type Provider =
+    Arc<dyn Fn(OutgoingMail) -> BoxFuture<'static, Result<(), SendError>> + Send + Sync>;
+
+static OPTIONS: OnceLock<MailOptions> = OnceLock::new();
+static PROVIDERS: Mutex<Vec<(String, Provider)>> = Mutex::new(vec![]);
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MailConfig {
+    pub mail: Option<MailOptions>,
+}
+
+/// The `[mail]` section of `teach-config.toml`. Without it, mail is dropped instead of queued.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MailOptions {
+    /// The sender of every mail, such as `School <noreply@school.example>`
+    pub from: String,
+    /// `smtp`, or the name of a provider added by an integration
+    #[serde(default = "default_provider")]
+    pub provider: String,
+    pub smtp: Option<smtp::SmtpOptions>,
+    /// How often the queue is checked for mail to send
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    /// Attempts before a mail is given up on
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: i32,
+    /// The wait before the first retry, doubling after every failed attempt
+    #[serde(default = "default_retry_delay_secs")]
+    pub retry_delay_secs: u64,
+    /// How often users are mailed their unread notifications. 0 turns digests off
+    #[serde(default = "default_digest_interval_hours")]
+    pub digest_interval_hours: u64,
+    /// Overrides of the built-in templates, by template name
+    #[serde(default)]
+    pub templates: FxHashMap<String, TemplateOverride>,
+}
+
+fn default_provider() -> String {
+    "smtp".into()
+}
+
+fn default_poll_interval_secs() -> u64 {
+    30
+}
+
+fn default_max_attempts() -> i32 {
+    5
+}
+
+fn default_retry_delay_secs() -> u64 {
+    60
+}
+
+fn default_digest_interval_hours() -> u64 {
+    24
+}
+
+/// A mail handed to a provider.
+#[derive(Debug, Clone)]
+pub struct OutgoingMail {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    /// Plain text
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    /// The mail may go through later, such as when the server could not be reached
+    Transient(anyhow::Error),
+    /// The recipient was rejected, which puts them on the suppression list
+    Permanent(anyhow::Error),
+}
+
+/// Registers a way of sending mail, chosen by setting `mail.provider` to `name`.
+pub fn add_provider<F, Fut>(name: impl Into<String>, f: F)
+where
+    F: Fn(OutgoingMail) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), SendError>> + Send + 'static,
+{
+    PROVIDERS
+        .lock()
+        .unwrap()
+        .push((name.into(), Arc::new(move |mail| Box::pin(f(mail)))));
+}
+
+fn provider(name: &str) -> Option<Provider> {
+    PROVIDERS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(provider, _)| provider == name)
+        .map(|(_, f)| f.clone())
+}
+
+/// Mail waiting to be sent, or that was sent or given up on recently.
+pub mod queue {
+    use super::*;
+
+    #[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+    #[sea_orm(table_name = "mail_queue")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i32,
+        pub user_id: Option<UserID>,
+        pub to_address: String,
+        pub subject: String,
+        #[serde(skip_serializing)]
+        pub body: String,
+        pub attempts: i32,
+        pub next_attempt_at: DateTime,
+        pub last_error: Option<String>,
+        pub created_at: DateTime,
+        pub sent_at: Option<DateTime>,
+        pub failed_at: Option<DateTime>,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+/// Addresses that rejected mail or reported a bounce, which are never mailed again until an
+/// admin removes them. `encrypt-columns` rebuilds the table, including one made before addresses
+/// were encrypted.
+pub mod suppressions {
+    use super::*;
+
+    #[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+    #[sea_orm(table_name = "mail_suppressions")]
+    pub struct Model {
+        /// [`encryption::keyed_hash`] of the normalized address, to look suppressions up by
+        #[sea_orm(primary_key, auto_increment = false)]
+        #[serde(skip_serializing)]
+        pub address_hash: String,
+        pub address: Encrypted<String, Entity>,
+        pub reason: String,
+        pub created_at: DateTime,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+/// The address each user is mailed at.
+pub mod addresses {
+    use super::*;
+
+    #[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+    #[sea_orm(table_name = "mail_addresses")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub user_id: UserID,
+        pub address: Encrypted<String, Entity>,
+        /// Whether the user gets notification digests
+        pub digest: bool,
+        #[serde(skip_serializing)]
+        pub last_digest_at: Option<DateTime>,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+pub(crate) fn normalize(address: &str) -> String {
+    address.trim().to_lowercase()
+}
+
+/// A loose check that catches typos, leaving real validation to the mail server.
+pub fn is_valid_address(address: &str) -> bool {
+    let Some((local, domain)) = address.rsplit_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !address
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '<' | '>' | ',' | ';'))
+}
+
+pub async fn set_address(user_id: UserID, address: &str, digest: bool) -> Result<(), DbErr> {
+    addresses::Entity::insert(addresses::ActiveModel {
+        user_id: ActiveValue::set(user_id),
+        address: ActiveValue::set(Encrypted::new(
+            normalize(address),
+            addresses::Column::Address,
+            user_id,
+        )),
+        digest: ActiveValue::set(digest),
+        last_digest_at: ActiveValue::set(None),
+    })
+    .on_conflict(
+        OnConflict::column(addresses::Column::UserId)
+            .update_columns([addresses::Column::Address, addresses::Column::Digest])
+            .to_owned(),
+    )
+    .exec_without_returning(get_db())
+    .await?;
+    Ok(())
+}
+
+pub async fn is_suppressed(address: &str) -> Result<bool, DbErr> {
+    Ok(
+        suppressions::Entity::find_by_id(encryption::keyed_hash(&normalize(address)))
+            .one(get_db())
+            .await?
+            .is_some(),
+    )
+}
+
+/// Stops mailing `address`, such as when a provider reports that mail to it bounced.
+pub async fn suppress(address: &str, reason: &str) -> Result<(), DbErr> {
+    let address = normalize(address);
+    let address_hash = encryption::keyed_hash(&address);
+    suppressions::Entity::insert(suppressions::ActiveModel {
+        address_hash: ActiveValue::set(address_hash.clone()),
+        address: ActiveValue::set(Encrypted::new(
+            address,
+            suppressions::Column::Address,
+            address_hash,
+        )),
+        reason: ActiveValue::set(reason.to_string()),
+        created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+    })
+    .on_conflict(
+        OnConflict::column(suppressions::Column::AddressHash)
+            .do_nothing()
+            .to_owned(),
+    )
+    .exec_without_returning(get_db())
+    .await?;
+    Ok(())
+}
+
+// The table is rebuilt, since the hashes change with the key and older tables were keyed by the
+// address itself
+async fn rehash_suppressions(db: &'static DatabaseConnection) -> Result<u64, DbErr> {
+    let backend = db.get_database_backend();
+    let txn = db.begin().await?;
+    let rows = txn
+        .query_all(
+            backend.build(
+                sea_query::Query::select()
+                    .columns([
+                        suppressions::Column::AddressHash,
+                        suppressions::Column::Address,
+                        suppressions::Column::Reason,
+                        suppressions::Column::CreatedAt,
+                    ])
+                    .from(suppressions::Entity),
+            ),
+        )
+        .await?;
+    let models = rows
+        .iter()
+        .map(|row| {
+            // Read with the old hash, which the address is bound to
+            let address: Encrypted<String, suppressions::Entity> = row.try_get("", "address")?;
+            let address_hash = encryption::keyed_hash(&address.0);
+            Ok(suppressions::ActiveModel {
+                address_hash: ActiveValue::set(address_hash.clone()),
+                address: ActiveValue::set(Encrypted::new(
+                    address.into_inner(),
+                    suppressions::Column::Address,
+                    address_hash,
+                )),
+                reason: ActiveValue::set(row.try_get("", "reason")?),
+                created_at: ActiveValue::set(row.try_get("", "created_at")?),
+            })
+        })
+        .collect::<Result<Vec<_>, DbErr>>()?;
+    let rows = models.len() as u64;
+    txn.execute(backend.build(Table::drop().table(suppressions::Entity)))
+        .await?;
+    let create = Schema::new(backend).create_table_from_entity(suppressions::Entity);
+    txn.execute(backend.build(&create)).await?;
+    if !models.is_empty() {
+        suppressions::Entity::insert_many(models)
+            .exec_without_returning(&txn)
+            .await?;
+    }
+    txn.commit().await?;
+    Ok(rows)
+}
+
+/// Queues a mail, returning `None` without queueing it when mail is not configured or the
+/// address is suppressed.
+pub async fn queue_mail(
+    user_id: Option<UserID>,
+    to: &str,
+    subject: &str,
+    body: &str,
+) -> Result<Option<queue::Model>, DbErr> {
+    if OPTIONS.get().is_none() || is_suppressed(to).await? {
+        return Ok(None);
+    }
+    let now = chrono::Utc::now().naive_utc();
+    queue::ActiveModel {
+        id: ActiveValue::not_set(),
+        user_id: ActiveValue::set(user_id),
+        to_address: ActiveValue::set(normalize(to)),
+        subject: ActiveValue::set(subject.to_string()),
+        body: ActiveValue::set(body.to_string()),
+        attempts: ActiveValue::set(0),
+        next_attempt_at: ActiveValue::set(now),
+        last_error: ActiveValue::set(None),
+        created_at: ActiveValue::set(now),
+        sent_at: ActiveValue::set(None),
+        failed_at: ActiveValue::set(None),
+    }
+    .insert(get_db())
+    .await
+    .map(Some)
+}
+
+/// Renders a template in the user's locale and queues it for them, if they have an address.
+pub async fn queue_template(
+    user_id: UserID,
+    kind: TemplateKind,
+    values: &[(&str, &str)],
+) -> Result<Option<queue::Model>, DbErr> {
+    let Some(options) = OPTIONS.get() else {
+        return Ok(None);
+    };
+    let Some(address) = addresses::Entity::find_by_id(user_id).one(get_db()).await? else {
+        return Ok(None);
+    };
+    let locale = i18n::locale_of(user_id).await;
+    let rendered = templates::render(&options.templates, kind, &locale, values);
+    queue_mail(
+        Some(user_id),
+        &address.address.0,
+        &rendered.subject,
+        &rendered.body,
+    )
+    .await
+}
+
+/// Mails a new user their credentials.
+pub async fn queue_credentials(
+    user_id: UserID,
+    name: &str,
+    password: &str,
+) -> Result<Option<queue::Model>, DbErr> {
+    queue_template(
+        user_id,
+        TemplateKind::Credentials,
+        &[
+            ("name", name),
+            ("user_id", &user_id.to_string()),
+            ("password", password),
+        ],
+    )
+    .await
+}
+
+pub async fn queue_password_reset(
+    user_id: UserID,
+    link: &str,
+    expires_at: DateTime,
+) -> Result<Option<queue::Model>, DbErr> {
+    queue_template(
+        user_id,
+        TemplateKind::PasswordReset,
+        &[
+            ("link", link),
+            (
+                "expires_at",
+                &format!("{} UTC", expires_at.format("%Y-%m-%d %H:%M")),
+            ),
+        ],
+    )
+    .await
+}
+
+fn retry_delay(options: &MailOptions, attempts: i32) -> chrono::Duration {
+    let secs = options
+        .retry_delay_secs
+        .saturating_mul(1 << attempts.clamp(1, 16).saturating_sub(1))
+        .min(24 * 60 * 60);
+    chrono::Duration::seconds(secs as i64)
+}
+
+/// Takes a mail for this sibling, so that no other sends it at the same time.
+async fn claim(mail: &queue::Model) -> Result<bool, DbErr> {
+    let until = chrono::Utc::now().naive_utc() + chrono::Duration::seconds(CLAIM_SECS);
+    let result = queue::Entity::update_many()
+        .col_expr(queue::Column::NextAttemptAt, Expr::value(until))
+        .filter(queue::Column::Id.eq(mail.id))
+        .filter(queue::Column::NextAttemptAt.eq(mail.next_attempt_at))
+        .exec(get_db())
+        .await?;
+    Ok(result.rows_affected == 1)
+}
+
+async fn deliver(
+    options: &MailOptions,
+    provider: &Provider,
+    mail: queue::Model,
+) -> Result<(), DbErr> {
+    if !claim(&mail).await? {
+        return Ok(());
+    }
+    let now = chrono::Utc::now().naive_utc();
+    let attempts = mail.attempts + 1;
+    let mut model = queue::ActiveModel {
+        id: ActiveValue::unchanged(mail.id),
+        attempts: ActiveValue::set(attempts),
+        next_attempt_at: ActiveValue::set(now),
+        ..Default::default()
+    };
+    let result = if is_suppressed(&mail.to_address).await? {
+        Err(SendError::Permanent(anyhow::anyhow!(
+            "The address is suppressed"
+        )))
+    } else {
+        provider(OutgoingMail {
+            from: options.from.clone(),
+            to: mail.to_address.clone(),
+            subject: mail.subject,
+            body: mail.body,
+        })
+        .await
+    };
+    match result {
+        Ok(()) => {
+            model.sent_at = ActiveValue::set(Some(now));
+            model.last_error = ActiveValue::set(None);
+        }
+        Err(SendError::Permanent(e)) => {
+            warn!(
+                "Mail {} to {} was rejected: {e:#}",
+                mail.id, mail.to_address
+            );
+            suppress(&mail.to_address, &format!("{e:#}")).await?;
+            model.failed_at = ActiveValue::set(Some(now));
+            model.last_error = ActiveValue::set(Some(format!("{e:#}")));
+        }
+        Err(SendError::Transient(e)) => {
+            warn!("Error sending mail {} (attempt {attempts}): {e:#}", mail.id);
+            if attempts >= options.max_attempts {
+                model.failed_at = ActiveValue::set(Some(now));
+            } else {
+                model.next_attempt_at = ActiveValue::set(now + retry_delay(options, attempts));
+            }
+            model.last_error = ActiveValue::set(Some(format!("{e:#}")));
+        }
+    }
+    model.update(get_db()).await?;
+    Ok(())
+}
+
+async fn deliver_due() -> anyhow::Result<()> {
+    let Some(options) = OPTIONS.get() else {
+        return Ok(());
+    };
+    let Some(provider) = provider(&options.provider) else {
+        return Err(anyhow::anyhow!(
+            "There is no mail provider named {}",
+            options.provider
+        ));
+    };
+    let due = queue::Entity::find()
+        .filter(queue::Column::SentAt.is_null())
+        .filter(queue::Column::FailedAt.is_null())
+        .filter(queue::Column::NextAttemptAt.lte(chrono::Utc::now().naive_utc()))
+        .order_by_asc(queue::Column::NextAttemptAt)
+        .limit(BATCH_SIZE)
+        .all(get_db())
+        .await?;
+    for mail in due {
+        deliver(options, &provider, mail).await?;
+    }
+    Ok(())
+}
+
+/// Queues a digest of unread notifications for every user that wants one and has some.
+async fn queue_digests(interval: chrono::Duration) -> anyhow::Result<()> {
+    let recipients = addresses::Entity::find()
+        .filter(addresses::Column::Digest.eq(true))
+        .all(get_db())
+        .await?;
+    for recipient in recipients {
+        let now = chrono::Utc::now().naive_utc();
+        // Restarting runs the task straight away, which must not mail anyone early
+        if recipient
+            .last_digest_at
+            .is_some_and(|last| now - last < interval - chrono::Duration::minutes(5))
+        {
+            continue;
+        }
+        let mut unread = notifications::Entity::find()
+            .filter(notifications::Column::UserId.eq(recipient.user_id))
+            .filter(notifications::Column::ReadAt.is_null())
+            .order_by_asc(notifications::Column::CreatedAt);
+        if let Some(last) = recipient.last_digest_at {
+            unread = unread.filter(notifications::Column::CreatedAt.gt(last));
+        }
+        let unread = unread.all(get_read_db()).await?;
+        if unread.is_empty() {
+            continue;
+        }
+        // Only the sibling that moves last_digest_at forward queues the digest
+        let mut claim = addresses::Entity::update_many()
+            .col_expr(addresses::Column::LastDigestAt, Expr::value(now))
+            .filter(addresses::Column::UserId.eq(recipient.user_id));
+        claim = match recipient.last_digest_at {
+            Some(last) => claim.filter(addresses::Column::LastDigestAt.eq(last)),
+            None => claim.filter(addresses::Column::LastDigestAt.is_null()),
+        };
+        if claim.exec(get_db()).await?.rows_affected != 1 {
+            continue;
+        }
+        let list: Vec<_> = unread
+            .iter()
+            .map(|notification| format!("- {}: {}", notification.title, notification.body))
+            .collect();
+        queue_template(
+            recipient.user_id,
+            TemplateKind::NotificationDigest,
+            &[
+                ("count", &unread.len().to_string()),
+                ("notifications", &list.join("\n")),
+            ],
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetAddress {
+    pub address: String,
+    #[serde(default = "default_digest")]
+    pub digest: bool,
+}
+
+fn default_digest() -> bool {
+    true
+}
+
+async fn get_address(AuthUser { user_id, .. }: AuthUser) -> Response {
+    match addresses::Entity::find_by_id(user_id)
+        .one(get_read_db())
+        .await
+    {
+        Ok(Some(address)) => (StatusCode::OK, Json(address)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => {
+            error!("Error reading mail address of {user_id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+async fn put_address(AuthUser { user_id, .. }: AuthUser, Json(set): Json<SetAddress>) -> Response {
+    if !is_valid_address(&set.address) {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_email")).into_response();
+    }
+    match set_address(user_id, &set.address, set.digest).await {
+        Ok(()) => (StatusCode::OK, ()).into_response(),
+        Err(e) => {
+            error!("Error setting mail address of {user_id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+async fn delete_address(AuthUser { user_id, .. }: AuthUser) -> Response {
+    match addresses::Entity::delete_by_id(user_id)
+        .exec(get_db())
+        .await
+    {
+        Ok(_) => (StatusCode::OK, ()).into_response(),
+        Err(e) => {
+            error!("Error deleting mail address of {user_id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+async fn list_suppressions(_: AdminUser, Query(page): Query<PageQuery>) -> Response {
+    let Ok(cursor) = page.cursor::<String>() else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_cursor")).into_response();
+    };
+    match paginate(
+        suppressions::Entity::find(),
+        suppressions::Column::AddressHash,
+        |model| model.address_hash.clone(),
+        cursor,
+        page.limit(),
+        get_read_db(),
+    )
+    .await
+    {
+        Ok(suppressions) => (StatusCode::OK, Json(suppressions)).into_response(),
+        Err(e) => {
+            error!("Error listing mail suppressions: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+async fn remove_suppression(_: AdminUser, Path(address): Path<String>) -> Response {
+    match suppressions::Entity::delete_by_id(encryption::keyed_hash(&normalize(&address)))
+        .exec(get_db())
+        .await
+    {
+        Ok(result) if result.rows_affected == 0 => (StatusCode::NOT_FOUND, ()).into_response(),
+        Ok(_) => (StatusCode::OK, ()).into_response(),
+        Err(e) => {
+            error!("Error removing mail suppression: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+pub fn add_to_core<S: Clone + Send + Sync + 'static>(
+    mut core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    let config: MailConfig = toml::from_str(core.get_config_str())?;
+    core.add_db_reset_config(queue::Entity);
+    core.add_db_reset_config(suppressions::Entity);
+    core.add_db_reset_config(addresses::Entity);
+    core.add_encrypted_column(addresses::Entity, addresses::Column::Address);
+    encryption::add_step(
+        "mail_suppressions",
+        "address",
+        Box::new(|db| Box::pin(rehash_suppressions(db))),
+    );
+    core.add_retention(queue::Entity, queue::Column::CreatedAt, QUEUE_MAX_AGE);
+    i18n::add_catalog(i18n::FALLBACK_LOCALE, templates::english_catalog());
+
+    core = core.modify_router(|router| {
+        router
+            .route(
+                "/mail/address",
+                get(get_address).put(put_address).delete(delete_address),
+            )
+            .route("/mail/suppressions", get(list_suppressions))
+            .route("/mail/suppressions/:address", delete(remove_suppression))
+    });
+
+    let Some(options) = config.mail else {
+        return Ok(core);
+    };
+    if let Some(name) = options.templates.keys().find(|name| {
+        !TemplateKind::ALL
+            .iter()
+            .any(|kind| kind.name() == name.as_str())
+    }) {
+        return Err(anyhow::anyhow!(
+            "mail.templates has an unknown template {name}"
+        ));
+    }
+    if options.poll_interval_secs == 0 || options.max_attempts < 1 {
+        return Err(anyhow::anyhow!(
+            "mail.poll_interval_secs and mail.max_attempts must be at least 1"
+        ));
+    }
+    if let Some(smtp_options) = options.smtp.clone() {
+        smtp_options.validate()?;
+        let smtp_options = Arc::new(smtp_options);
+        add_provider("smtp", move |mail| {
+            let smtp_options = smtp_options.clone();
+            async move {
+                tokio::task::spawn_blocking(move || smtp::send(&smtp_options, &mail))
+                    .await
+                    .map_err(|e| SendError::Transient(e.into()))?
+            }
+        });
+    } else if options.provider == "smtp" {
+        return Err(anyhow::anyhow!(
+            "mail.provider is smtp, but there is no [mail.smtp] section"
+        ));
+    }
+
+    let poll_interval = Duration::from_secs(options.poll_interval_secs);
+    let digest_interval = Duration::from_secs(options.digest_interval_hours * 60 * 60);
+    let provider_name = options.provider.clone();
+    OPTIONS
+        .set(options)
+        .expect("Mail options are already initialized");
+    // Providers of integrations are only added after the core, so they are checked once all are in
+    core.add_on_serve_named("mail", 0, move || async move {
+        if provider(&provider_name).is_none() {
+            return Err(anyhow::anyhow!(
+                "There is no mail provider named {provider_name}"
+            ));
+        }
+        Ok(())
+    });
+    core.add_scheduled_task("mail delivery", poll_interval, deliver_due);
+    if !digest_interval.is_zero() {
+        let interval = chrono::Duration::from_std(digest_interval)?;
+        core.add_scheduled_task("mail digests", digest_interval, move || {
+            queue_digests(interval)
+        });
+    }
+    Ok(core)
+}
@@ -0,0 +1,312 @@
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+#[cfg(feature = "smtp-tls")]
+use std::sync::Arc;
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use zeroize::Zeroizing;
+
+use super::{OutgoingMail, SendError};
+
+/// The `[mail.smtp]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SmtpOptions {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<Zeroizing<String>>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_port() -> u16 {
+    587
+}
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Upgrades the connection with STARTTLS, usually on port 587
+    #[default]
+    StartTls,
+    /// Connects over TLS from the start, usually on port 465
+    Tls,
+    /// Sends everything in the clear, only for relays on the same machine or network
+    None,
+}
+
+enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "smtp-tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "smtp-tls")]
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "smtp-tls")]
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            #[cfg(feature = "smtp-tls")]
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+struct Session {
+    stream: Stream,
+    /// Bytes read past the end of the last reply
+    buffer: Vec<u8>,
+}
+
+/// A reply of the server, with the text of every line joined.
+struct Reply {
+    code: u16,
+    text: String,
+}
+
+impl Reply {
+    fn error(&self, command: &str) -> SendError {
+        let e = anyhow::anyhow!("{command} was answered with {} {}", self.code, self.text);
+        if self.code >= 500 {
+            SendError::Permanent(e)
+        } else {
+            SendError::Transient(e)
+        }
+    }
+}
+
+impl Session {
+    fn read_line(&mut self) -> anyhow::Result<String> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|w| w == b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buffer[..end]).to_string();
+                self.buffer.drain(..end + 2);
+                return Ok(line);
+            }
+            let mut chunk = [0; 1024];
+            let read = self.stream.read(&mut chunk)?;
+            if read == 0 {
+                return Err(anyhow::anyhow!("The SMTP server closed the connection"));
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    fn read_reply(&mut self) -> anyhow::Result<Reply> {
+        let mut text = vec![];
+        loop {
+            let line = self.read_line()?;
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse().ok())
+                .ok_or_else(|| anyhow::anyhow!("Invalid SMTP reply {line:?}"))?;
+            text.push(line.get(4..).unwrap_or_default().to_string());
+            // Every line but the last has a dash after the code
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(Reply {
+                    code,
+                    text: text.join(" "),
+                });
+            }
+        }
+    }
+
+    /// Sends a command, expecting a reply starting with `expected`.
+    fn command(&mut self, command: &str, expected: u16) -> Result<Reply, SendError> {
+        self.stream
+            .write_all(format!("{command}\r\n").as_bytes())
+            .map_err(|e| SendError::Transient(e.into()))?;
+        let reply = self.read_reply().map_err(SendError::Transient)?;
+        if reply.code / 100 != expected / 100 {
+            // Keeps credentials out of errors
+            let command = command.split(' ').next().unwrap_or_default();
+            return Err(reply.error(command));
+        }
+        Ok(reply)
+    }
+
+    #[cfg(feature = "smtp-tls")]
+    fn start_tls(&mut self, host: &str) -> anyhow::Result<()> {
+        let Stream::Plain(stream) = &self.stream else {
+            return Ok(());
+        };
+        let stream = stream.try_clone()?;
+        self.stream = Stream::Tls(Box::new(tls_stream(host, stream)?));
+        Ok(())
+    }
+}
+
+#[cfg(feature = "smtp-tls")]
+fn tls_stream(
+    host: &str,
+    stream: TcpStream,
+) -> anyhow::Result<rustls::StreamOwned<rustls::ClientConnection, TcpStream>> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .context("Configuring TLS")?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    let server_name = rustls::pki_types::ServerName::try_from(host.to_string())
+        .with_context(|| format!("{host} is not a valid server name"))?;
+    let connection = rustls::ClientConnection::new(Arc::new(config), server_name)?;
+    Ok(rustls::StreamOwned::new(connection, stream))
+}
+
+impl SmtpOptions {
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        #[cfg(not(feature = "smtp-tls"))]
+        if self.security != SmtpSecurity::None {
+            return Err(anyhow::anyhow!(
+                "mail.smtp.security needs TLS, but teach-tech-core was built without the smtp-tls \
+                 feature"
+            ));
+        }
+        if self.username.is_some() != self.password.is_some() {
+            return Err(anyhow::anyhow!(
+                "mail.smtp.username and mail.smtp.password must be set together"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The bare address in `Name <address>`.
+fn address(mailbox: &str) -> &str {
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+/// Encodes a header value as an RFC 2047 encoded word when it is not plain ASCII.
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(value))
+    }
+}
+
+/// Encodes the name in `Name <address>`.
+fn encode_mailbox(mailbox: &str) -> String {
+    match mailbox.rfind('<') {
+        Some(start) if !mailbox.is_ascii() => format!(
+            "{} {}",
+            encode_header(mailbox[..start].trim()),
+            &mailbox[start..]
+        ),
+        _ => mailbox.to_string(),
+    }
+}
+
+fn message(mail: &OutgoingMail) -> String {
+    let domain = address(&mail.from)
+        .rsplit('@')
+        .next()
+        .unwrap_or("localhost");
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{:032x}@{domain}>\r\n\
+         MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+         Content-Transfer-Encoding: base64\r\n\r\n",
+        encode_mailbox(&mail.from),
+        encode_mailbox(&mail.to),
+        encode_header(&mail.subject),
+        chrono::Utc::now().to_rfc2822(),
+        thread_rng().gen::<u128>(),
+    );
+    // Base64 lines never start with a dot, so the body needs no dot-stuffing
+    let body = STANDARD.encode(mail.body.replace("\r\n", "\n").replace('\n', "\r\n"));
+    for line in body.as_bytes().chunks(76) {
+        message.push_str(std::str::from_utf8(line).unwrap());
+        message.push_str("\r\n");
+    }
+    message.push('.');
+    message
+}
+
+/// Delivers one mail over its own SMTP session.
+pub(crate) fn send(options: &SmtpOptions, mail: &OutgoingMail) -> Result<(), SendError> {
+    let transient = |e: anyhow::Error| SendError::Transient(e);
+    let timeout = Duration::from_secs(options.timeout_secs);
+    let stream = TcpStream::connect((options.host.as_str(), options.port))
+        .with_context(|| format!("Connecting to {}", options.host))
+        .map_err(transient)?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .map_err(|e| transient(e.into()))?;
+    let stream = match options.security {
+        #[cfg(feature = "smtp-tls")]
+        SmtpSecurity::Tls => Stream::Tls(Box::new(
+            tls_stream(&options.host, stream).map_err(transient)?,
+        )),
+        _ => Stream::Plain(stream),
+    };
+    let mut session = Session {
+        stream,
+        buffer: vec![],
+    };
+
+    let greeting = session.read_reply().map_err(transient)?;
+    if greeting.code != 220 {
+        return Err(greeting.error("Connecting"));
+    }
+    let hostname = address(&mail.from)
+        .rsplit('@')
+        .next()
+        .unwrap_or("localhost");
+    session.command(&format!("EHLO {hostname}"), 250)?;
+    #[cfg(feature = "smtp-tls")]
+    if options.security == SmtpSecurity::StartTls {
+        session.command("STARTTLS", 220)?;
+        session.start_tls(&options.host).map_err(transient)?;
+        session.command(&format!("EHLO {hostname}"), 250)?;
+    }
+    if let (Some(username), Some(password)) = (&options.username, &options.password) {
+        let credentials = Zeroizing::new(STANDARD.encode(format!("\0{username}\0{}", &**password)));
+        session.command(&format!("AUTH PLAIN {}", &*credentials), 235)?;
+    }
+    session.command(&format!("MAIL FROM:<{}>", address(&mail.from)), 250)?;
+    session.command(&format!("RCPT TO:<{}>", address(&mail.to)), 250)?;
+    session.command("DATA", 354)?;
+    session.command(&message(mail), 250)?;
+    // The mail is accepted, so a failing QUIT changes nothing
+    let _ = session.command("QUIT", 221);
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use fxhash::FxHashMap;
+use serde::Deserialize;
+
+use crate::i18n;
+
+/// Overrides the subject or body of a built-in template in every locale. Placeholders are written
+/// as `{name}`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TemplateOverride {
+    pub subject: Option<String>,
+    pub body: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplateKind {
+    /// Placeholders: `name`, `user_id`, `password`
+    Credentials,
+    /// Placeholders: `link`, `expires_at`
+    PasswordReset,
+    /// Placeholders: `count`, `notifications`
+    NotificationDigest,
+}
+
+impl TemplateKind {
+    pub const ALL: [Self; 3] = [
+        Self::Credentials,
+        Self::PasswordReset,
+        Self::NotificationDigest,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Credentials => "credentials",
+            Self::PasswordReset => "password_reset",
+            Self::NotificationDigest => "notification_digest",
+        }
+    }
+
+    /// The catalog key of the subject, such as `mail.credentials.subject`.
+    pub fn subject_key(self) -> String {
+        format!("mail.{}.subject", self.name())
+    }
+
+    pub fn body_key(self) -> String {
+        format!("mail.{}.body", self.name())
+    }
+
+    fn default_subject(self) -> &'static str {
+        match self {
+            Self::Credentials => "Your new account",
+            Self::PasswordReset => "Resetting your password",
+            Self::NotificationDigest => "You have {count} unread notifications",
+        }
+    }
+
+    fn default_body(self) -> &'static str {
+        match self {
+            Self::Credentials => {
+                "Hello {name},\n\nAn account has been created for you.\n\nUser ID: {user_id}\n\
+                 Password: {password}\n\nKeep this password to yourself, and delete this email \
+                 once you have signed in.\n"
+            }
+            Self::PasswordReset => {
+                "Someone asked to reset the password of your account. If it was you, follow this \
+                 link before {expires_at}:\n\n{link}\n\nOtherwise you can ignore this email.\n"
+            }
+            Self::NotificationDigest => "Since the last digest you got:\n\n{notifications}\n",
+        }
+    }
+}
+
+/// A subject and body ready to be queued.
+#[derive(Debug, Clone)]
+pub struct Rendered {
+    pub subject: String,
+    pub body: String,
+}
+
+/// The English templates, which other locales fall back to.
+pub(crate) fn english_catalog() -> impl Iterator<Item = (String, &'static str)> {
+    TemplateKind::ALL.into_iter().flat_map(|kind| {
+        [
+            (kind.subject_key(), kind.default_subject()),
+            (kind.body_key(), kind.default_body()),
+        ]
+    })
+}
+
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = template.to_string();
+    for (name, value) in values {
+        filled = filled.replace(&format!("{{{name}}}"), value);
+    }
+    filled
+}
+
+pub(crate) fn render(
+    overrides: &FxHashMap<String, TemplateOverride>,
+    kind: TemplateKind,
+    locale: &str,
+    values: &[(&str, &str)],
+) -> Rendered {
+    let custom = overrides.get(kind.name());
+    let subject = custom
+        .and_then(|custom| custom.subject.clone())
+        .unwrap_or_else(|| i18n::translate(locale, &kind.subject_key()));
+    let body = custom
+        .and_then(|custom| custom.body.clone())
+        .unwrap_or_else(|| i18n::translate(locale, &kind.body_key()));
+    Rendered {
+        // Headers cannot span lines
+        subject: fill(&subject, values).replace(['\r', '\n'], " "),
+        body: fill(&body, values),
+    }
+}
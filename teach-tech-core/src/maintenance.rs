@@ -0,0 +1,273 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::Request,
+    http::{request::Parts, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json,
+};
+use clap::Subcommand;
+use sea_orm::{entity::prelude::*, ActiveValue};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    auth::{guard::RequirePermission, token, AdminUser, AuthUser, UserID},
+    db::{get_db, get_read_db, SoftDeletable},
+    siblings::{add_sibling_message_handler_raw, send_to_siblings_raw},
+    users::admins::{self, permissions::Permission},
+    TeachCore,
+};
+
+const MAINTENANCE_ROW_ID: i32 = 0;
+const SIBLING_SOURCE: &str = "teach-tech-core/maintenance";
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+// Routes that stay reachable for everyone, so that probes keep working and administrators can log
+// in. Admins are let through everywhere else
+const EXEMPT_PATHS: &[&str] = &["/info", "/health", "/metrics", "/auth/login"];
+const EXEMPT_PREFIXES: &[&str] = &["/auth/oidc/", "/auth/saml/"];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static MESSAGE: Mutex<String> = Mutex::new(String::new());
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MaintenanceConfig {
+    #[serde(default = "default_maintenance_message")]
+    pub maintenance_message: String,
+}
+
+fn default_maintenance_message() -> String {
+    "The system is down for maintenance. Please try again later.".to_string()
+}
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "maintenance")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    #[serde(skip_serializing)]
+    pub id: i32,
+    pub enabled: bool,
+    pub message: String,
+    pub updated_at: DateTime,
+    pub updated_by: Option<UserID>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(Subcommand)]
+pub enum MaintenanceCommand {
+    Enable { message: Option<String> },
+    Disable,
+    Status,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetMaintenance {
+    pub enabled: bool,
+    pub message: Option<String>,
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn apply(model: Option<Model>) {
+    match model {
+        Some(model) => {
+            *MESSAGE.lock().unwrap() = model.message;
+            ENABLED.store(model.enabled, Ordering::Relaxed);
+        }
+        None => ENABLED.store(false, Ordering::Relaxed),
+    }
+}
+
+async fn reload() -> Result<(), DbErr> {
+    apply(Entity::find_by_id(MAINTENANCE_ROW_ID).one(get_db()).await?);
+    Ok(())
+}
+
+pub async fn set_maintenance(
+    enabled: bool,
+    message: String,
+    updated_by: Option<UserID>,
+) -> Result<Model, DbErr> {
+    let model = ActiveModel {
+        id: ActiveValue::set(MAINTENANCE_ROW_ID),
+        enabled: ActiveValue::set(enabled),
+        message: ActiveValue::set(message),
+        updated_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+        updated_by: ActiveValue::set(updated_by),
+    };
+    let model = if Entity::find_by_id(MAINTENANCE_ROW_ID)
+        .one(get_db())
+        .await?
+        .is_some()
+    {
+        model.update(get_db()).await?
+    } else {
+        model.insert(get_db()).await?
+    };
+    apply(Some(model.clone()));
+    Ok(model)
+}
+
+pub async fn run_command(command: MaintenanceCommand, config: &str) -> anyhow::Result<()> {
+    let config: MaintenanceConfig = toml::from_str(config)?;
+    match command {
+        MaintenanceCommand::Enable { message } => {
+            let model =
+                set_maintenance(true, message.unwrap_or(config.maintenance_message), None).await?;
+            println!("Maintenance mode enabled: {}", model.message);
+        }
+        MaintenanceCommand::Disable => {
+            set_maintenance(false, config.maintenance_message, None).await?;
+            println!("Maintenance mode disabled");
+        }
+        MaintenanceCommand::Status => {
+            match Entity::find_by_id(MAINTENANCE_ROW_ID).one(get_db()).await? {
+                Some(model) if model.enabled => {
+                    println!("Maintenance mode is enabled: {}", model.message)
+                }
+                _ => println!("Maintenance mode is disabled"),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the request has the token of an admin that has not been deleted.
+async fn is_admin(parts: &mut Parts) -> bool {
+    let user_id = match token::validate_bearer(&parts.headers, &mut parts.extensions).await {
+        Ok(Some(valid)) => valid.user_id,
+        Ok(None) => return false,
+        Err(e) => {
+            error!("Error validating bearer token: {e:#}");
+            return false;
+        }
+    };
+    match admins::Entity::find_live_by_id(user_id)
+        .one(get_read_db())
+        .await
+    {
+        Ok(admin) => admin.is_some(),
+        Err(e) => {
+            error!("Error reading the role of {user_id}: {e:#}");
+            false
+        }
+    }
+}
+
+pub async fn reject_during_maintenance(request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if !is_enabled()
+        || EXEMPT_PATHS.contains(&path)
+        || EXEMPT_PREFIXES
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+    {
+        return next.run(request).await;
+    }
+    let (mut parts, body) = request.into_parts();
+    if is_admin(&mut parts).await {
+        return next.run(Request::from_parts(parts, body)).await;
+    }
+    let message = MESSAGE.lock().unwrap().clone();
+    (StatusCode::SERVICE_UNAVAILABLE, message).into_response()
+}
+
+pub fn add_to_core<S: Clone + Send + Sync + 'static>(
+    mut core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    core.add_db_reset_config(Entity);
+    let config: MaintenanceConfig = toml::from_str(core.get_config_str())?;
+
+    core.add_on_serve_named("maintenance", 0, || async move {
+        reload().await?;
+        add_sibling_message_handler_raw(|source, _| {
+            if source != SIBLING_SOURCE {
+                return;
+            }
+            tokio::spawn(async {
+                if let Err(e) = reload().await {
+                    error!("Failed to reload maintenance mode: {e:#}");
+                }
+            });
+        })
+        .await;
+        // Catches changes made through the CLI, which cannot notify running siblings
+        tokio::spawn(async {
+            loop {
+                tokio::time::sleep(REFRESH_INTERVAL).await;
+                if let Err(e) = reload().await {
+                    error!("Failed to reload maintenance mode: {e:#}");
+                }
+            }
+        });
+        Ok(())
+    });
+
+    Ok(core.modify_router(|router| {
+        router.route(
+            "/admin/maintenance",
+            get(get_status).merge(
+                post(move |user: AuthUser, set: Json<SetMaintenance>| {
+                    update(user, set, config.maintenance_message.clone())
+                })
+                .layer(RequirePermission(Permission::ManageMaintenance)),
+            ),
+        )
+    }))
+}
+
+async fn get_status(_: AdminUser) -> Response {
+    match Entity::find_by_id(MAINTENANCE_ROW_ID).one(get_db()).await {
+        Ok(Some(model)) => (StatusCode::OK, Json(model)).into_response(),
+        Ok(None) => (
+            StatusCode::OK,
+            Json(Model {
+                id: MAINTENANCE_ROW_ID,
+                enabled: false,
+                message: String::new(),
+                updated_at: DateTime::default(),
+                updated_by: None,
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            error!("Error reading maintenance mode: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+async fn update(
+    AuthUser { user_id, .. }: AuthUser,
+    Json(SetMaintenance { enabled, message }): Json<SetMaintenance>,
+    default_message: String,
+) -> Response {
+    let message = message.unwrap_or(default_message);
+    match set_maintenance(enabled, message, Some(user_id)).await {
+        Ok(model) => {
+            info!("Maintenance mode set to {enabled} by {user_id}");
+            if let Err(e) = send_to_siblings_raw(SIBLING_SOURCE, &[]).await {
+                error!("Error notifying siblings of maintenance mode: {e:#}");
+            }
+            (StatusCode::OK, Json(model)).into_response()
+        }
+        Err(e) => {
+            error!("Error setting maintenance mode: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
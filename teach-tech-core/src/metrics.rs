@@ -0,0 +1,161 @@
+use std::{fmt::Write, sync::Mutex};
+
+use axum::{
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+
+use crate::{db, TeachCore};
+
+type Collector = Box<dyn Fn(&mut MetricsWriter) + Send + Sync>;
+
+static COLLECTORS: Mutex<Vec<Collector>> = Mutex::new(vec![]);
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub metrics: MetricsOptions,
+}
+
+/// The `[metrics]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MetricsOptions {
+    /// When set, `/metrics` requires this bearer token
+    pub bearer_token: Option<String>,
+}
+
+/// Writes metrics in the Prometheus text format.
+#[derive(Default)]
+pub struct MetricsWriter {
+    out: String,
+}
+
+/// A sample's labels and value
+pub type Sample = (Vec<(&'static str, String)>, f64);
+
+impl MetricsWriter {
+    fn write(&mut self, kind: &str, name: &str, help: &str, samples: &[Sample]) {
+        let _ = writeln!(self.out, "# HELP {name} {help}");
+        let _ = writeln!(self.out, "# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = write!(self.out, "{name}");
+            if !labels.is_empty() {
+                let labels: Vec<_> = labels
+                    .iter()
+                    .map(|(key, value)| {
+                        let value = value
+                            .replace('\\', "\\\\")
+                            .replace('"', "\\\"")
+                            .replace('\n', "\\n");
+                        format!("{key}=\"{value}\"")
+                    })
+                    .collect();
+                let _ = write!(self.out, "{{{}}}", labels.join(","));
+            }
+            let _ = writeln!(self.out, " {value}");
+        }
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, samples: &[Sample]) {
+        self.write("gauge", name, help, samples);
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, samples: &[Sample]) {
+        self.write("counter", name, help, samples);
+    }
+}
+
+/// Registers a function that writes metrics every time `/metrics` is scraped.
+pub fn add_collector(f: impl Fn(&mut MetricsWriter) + Send + Sync + 'static) {
+    COLLECTORS.lock().unwrap().push(Box::new(f));
+}
+
+pub fn render() -> String {
+    let mut writer = MetricsWriter::default();
+    for collector in COLLECTORS.lock().unwrap().iter() {
+        collector(&mut writer);
+    }
+    writer.out
+}
+
+fn collect_db(writer: &mut MetricsWriter) {
+    writer.gauge(
+        "teach_db_available",
+        "Whether the last health check reached the primary database",
+        &[(vec![], db::is_available() as u8 as f64)],
+    );
+    let stats = db::pool_stats();
+    let samples = |f: fn(&db::PoolStats) -> f64| -> Vec<Sample> {
+        stats
+            .iter()
+            .map(|stats| (vec![("pool", stats.pool.clone())], f(stats)))
+            .collect()
+    };
+    writer.gauge(
+        "teach_db_pool_connections",
+        "Open connections in the pool",
+        &samples(|stats| stats.size as f64),
+    );
+    writer.gauge(
+        "teach_db_pool_idle_connections",
+        "Idle connections in the pool",
+        &samples(|stats| stats.idle as f64),
+    );
+    writer.gauge(
+        "teach_db_pool_max_connections",
+        "Maximum connections in the pool",
+        &samples(|stats| stats.max_size as f64),
+    );
+    writer.gauge(
+        "teach_db_pool_acquire_wait_seconds",
+        "How long the last health check waited for a connection",
+        &samples(|stats| stats.acquire_wait_ms / 1000.0),
+    );
+    writer.counter(
+        "teach_db_pool_acquire_errors_total",
+        "Health checks that could not get a connection",
+        &samples(|stats| stats.acquire_errors as f64),
+    );
+}
+
+pub fn add_to_core<S: Clone + Send + Sync + 'static>(
+    core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    let metrics_config: MetricsConfig = toml::from_str(core.get_config_str())?;
+    let bearer_token: Option<&'static str> = metrics_config
+        .metrics
+        .bearer_token
+        .map(|token| &*Box::leak(token.into_boxed_str()));
+    add_collector(collect_db);
+
+    Ok(core.modify_router(move |router| {
+        router.route(
+            "/metrics",
+            get(
+                move |bearer: Option<TypedHeader<Authorization<Bearer>>>| async move {
+                    if let Some(expected) = bearer_token {
+                        let authorized =
+                            bearer.is_some_and(|TypedHeader(Authorization(bearer))| {
+                                bool::from(bearer.token().as_bytes().ct_eq(expected.as_bytes()))
+                            });
+                        if !authorized {
+                            return (StatusCode::UNAUTHORIZED, ()).into_response();
+                        }
+                    }
+                    (
+                        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                        render(),
+                    )
+                        .into_response()
+                },
+            ),
+        )
+    }))
+}
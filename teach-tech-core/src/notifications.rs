@@ -0,0 +1,253 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Json,
+};
+use futures::future::BoxFuture;
+use fxhash::FxHashSet;
+use sea_orm::{entity::prelude::*, ActiveValue, QuerySelect};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    auth::{AdminUser, AuthUser, UserID},
+    db::{get_db, get_read_db, paginate, PageQuery, SoftDeletable},
+    i18n,
+    users::{admins, instructors, students},
+    TeachCore,
+};
+
+const MAX_AGE: Duration = Duration::from_days(180);
+
+type Sink = Arc<dyn Fn(Model) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>;
+
+static SINKS: Mutex<Vec<(String, Sink)>> = Mutex::new(vec![]);
+
+/// A notification for any user, as opposed to the admin-only `admin_notifications`.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "user_notifications")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub user_id: UserID,
+    /// The integration that created the notification
+    pub source: String,
+    pub title: String,
+    pub body: String,
+    /// Where a client should take the user to act on the notification
+    pub link: Option<String>,
+    /// Urgent notifications, such as school closures, are also sent by text message
+    pub urgent: bool,
+    pub created_at: DateTime,
+    pub read_at: Option<DateTime>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(Debug, Clone)]
+pub struct NewNotification {
+    pub user_id: UserID,
+    pub source: String,
+    pub title: String,
+    pub body: String,
+    pub link: Option<String>,
+    pub urgent: bool,
+}
+
+/// Registers a function that is given every new notification, such as one that emails it.
+/// Sinks run in the background, and their errors are only logged.
+pub fn add_sink<F, Fut>(name: impl Into<String>, f: F)
+where
+    F: Fn(Model) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    SINKS
+        .lock()
+        .unwrap()
+        .push((name.into(), Arc::new(move |model| Box::pin(f(model)))));
+}
+
+/// Stores a notification and hands it to every sink.
+pub async fn notify(notification: NewNotification) -> Result<Model, DbErr> {
+    let model = ActiveModel {
+        id: ActiveValue::not_set(),
+        user_id: ActiveValue::set(notification.user_id),
+        source: ActiveValue::set(notification.source),
+        title: ActiveValue::set(notification.title),
+        body: ActiveValue::set(notification.body),
+        link: ActiveValue::set(notification.link),
+        urgent: ActiveValue::set(notification.urgent),
+        created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+        read_at: ActiveValue::set(None),
+    }
+    .insert(get_db())
+    .await?;
+
+    let sinks = SINKS.lock().unwrap().clone();
+    for (name, sink) in sinks {
+        let model = model.clone();
+        tokio::spawn(async move {
+            if let Err(e) = sink(model).await {
+                error!("Error sending notification through {name}: {e:#}");
+            }
+        });
+    }
+    Ok(model)
+}
+
+/// Whether the user has an unread notification from `source` with the given link, so that
+/// integrations can avoid piling up notifications about the same thing.
+pub async fn has_unread(user_id: UserID, source: &str, link: &str) -> Result<bool, DbErr> {
+    Ok(Entity::find()
+        .filter(Column::UserId.eq(user_id))
+        .filter(Column::Source.eq(source))
+        .filter(Column::Link.eq(link))
+        .filter(Column::ReadAt.is_null())
+        .one(get_db())
+        .await?
+        .is_some())
+}
+
+/// A notification for every student, instructor and administrator, such as a school closure.
+#[derive(Debug, Deserialize)]
+pub struct Broadcast {
+    pub title: String,
+    pub body: String,
+    pub link: Option<String>,
+    #[serde(default)]
+    pub urgent: bool,
+}
+
+async fn live_user_ids() -> Result<Vec<UserID>, DbErr> {
+    let db = get_read_db();
+    let mut user_ids: Vec<UserID> = students::Entity::find_live()
+        .select_only()
+        .column(students::Column::UserId)
+        .into_tuple()
+        .all(db)
+        .await?;
+    user_ids.extend(
+        instructors::Entity::find_live()
+            .select_only()
+            .column(instructors::Column::UserId)
+            .into_tuple::<UserID>()
+            .all(db)
+            .await?,
+    );
+    user_ids.extend(
+        admins::Entity::find_live()
+            .select_only()
+            .column(admins::Column::UserId)
+            .into_tuple::<UserID>()
+            .all(db)
+            .await?,
+    );
+    // Someone can be both an instructor and an administrator
+    let mut seen = FxHashSet::default();
+    user_ids.retain(|user_id| seen.insert(*user_id));
+    Ok(user_ids)
+}
+
+async fn broadcast(_: AdminUser, Json(broadcast): Json<Broadcast>) -> axum::response::Response {
+    let user_ids = match live_user_ids().await {
+        Ok(user_ids) => user_ids,
+        Err(e) => {
+            error!("Error listing users to broadcast to: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+    let count = user_ids.len();
+    // Large schools take a while, so the notifications are stored after responding
+    tokio::spawn(async move {
+        for user_id in user_ids {
+            let result = notify(NewNotification {
+                user_id,
+                source: "broadcast".to_string(),
+                title: broadcast.title.clone(),
+                body: broadcast.body.clone(),
+                link: broadcast.link.clone(),
+                urgent: broadcast.urgent,
+            })
+            .await;
+            if let Err(e) = result {
+                error!("Error broadcasting notification to {user_id}: {e:#}");
+            }
+        }
+    });
+    (
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({ "recipients": count })),
+    )
+        .into_response()
+}
+
+pub fn add_to_core<S: Clone + Send + Sync + 'static>(mut core: TeachCore<S>) -> TeachCore<S> {
+    core.add_db_reset_config(Entity);
+    core.add_retention(Entity, Column::CreatedAt, MAX_AGE);
+
+    core.modify_router(|router| {
+        router
+            .route(
+                "/notifications",
+                get(
+                    |AuthUser { user_id, .. }: AuthUser, Query(page): Query<PageQuery>| async move {
+                        let Ok(cursor) = page.cursor::<i32>() else {
+                            return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_cursor"))
+                                .into_response();
+                        };
+                        match paginate(
+                            Entity::find().filter(Column::UserId.eq(user_id)),
+                            Column::Id,
+                            |model| model.id,
+                            cursor,
+                            page.limit(),
+                            get_read_db(),
+                        )
+                        .await
+                        {
+                            Ok(notifications) => {
+                                (StatusCode::OK, Json(notifications)).into_response()
+                            }
+                            Err(e) => {
+                                error!("Error listing notifications: {e:#}");
+                                (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+                            }
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/notifications/:id/read",
+                post(
+                    |AuthUser { user_id, .. }: AuthUser, Path(id): Path<i32>| async move {
+                        let result = Entity::update_many()
+                            .col_expr(Column::ReadAt, Expr::value(chrono::Utc::now().naive_utc()))
+                            .filter(Column::Id.eq(id))
+                            .filter(Column::UserId.eq(user_id))
+                            .filter(Column::ReadAt.is_null())
+                            .exec(get_db())
+                            .await;
+                        match result {
+                            Ok(_) => (StatusCode::OK, ()).into_response(),
+                            Err(e) => {
+                                error!("Error marking notification as read: {e:#}");
+                                (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+                            }
+                        }
+                    },
+                ),
+            )
+            .route("/notifications/broadcast", post(broadcast))
+    })
+}
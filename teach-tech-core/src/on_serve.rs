@@ -0,0 +1,53 @@
+use std::{future::Future, pin::Pin, time::Instant};
+
+use anyhow::Context;
+use futures::future::join_all;
+use tracing::info;
+
+pub(crate) type OnServe =
+    Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> + Send>;
+
+pub(crate) struct OnServeEntry {
+    pub name: String,
+    pub priority: i32,
+    pub parallel: bool,
+    pub f: OnServe,
+}
+
+async fn run_timed(name: String, f: OnServe) -> anyhow::Result<()> {
+    let start = Instant::now();
+    f().await
+        .with_context(|| format!("Calling on_serve callback {name}"))?;
+    info!("on_serve callback {name} finished in {:?}", start.elapsed());
+    Ok(())
+}
+
+// Entries run in ascending priority, ties keep registration order. Consecutive parallel entries of
+// the same priority are awaited concurrently.
+pub(crate) async fn run_all(mut entries: Vec<OnServeEntry>) -> anyhow::Result<()> {
+    entries.sort_by_key(|entry| entry.priority);
+    let mut entries = entries.into_iter().peekable();
+
+    while let Some(entry) = entries.next() {
+        if !entry.parallel {
+            run_timed(entry.name, entry.f).await?;
+            continue;
+        }
+        let priority = entry.priority;
+        let mut group = vec![run_timed(entry.name, entry.f)];
+        while let Some(next) = entries.next_if(|next| next.parallel && next.priority == priority) {
+            group.push(run_timed(next.name, next.f));
+        }
+        let start = Instant::now();
+        let count = group.len();
+        for result in join_all(group).await {
+            result?;
+        }
+        info!(
+            "{count} parallel on_serve callbacks at priority {priority} finished in {:?}",
+            start.elapsed()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+
+use fxhash::FxHashMap;
+use serde_json::{json, Value};
+
+use crate::routes::RouteInfo;
+
+/// Turns `/quick-chat/conversations/:id` into `/quick-chat/conversations/{id}`, along with the
+/// names of the path parameters.
+fn openapi_path(path: &str) -> (String, Vec<String>) {
+    let mut parameters = vec![];
+    let segments: Vec<_> = path
+        .split('/')
+        .map(|segment| match segment.strip_prefix([':', '*']) {
+            Some(name) => {
+                parameters.push(name.to_string());
+                format!("{{{name}}}")
+            }
+            None => segment.to_string(),
+        })
+        .collect();
+    (segments.join("/"), parameters)
+}
+
+/// `GET /quick-chat/conversations/:id` becomes `getQuickChatConversationsById`.
+fn operation_id(method: &str, path: &str) -> String {
+    let mut id = method.to_lowercase();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        let (prefix, segment) = match segment.strip_prefix([':', '*']) {
+            Some(name) => ("By", name),
+            None => ("", segment),
+        };
+        id.push_str(prefix);
+        for word in segment
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|word| !word.is_empty())
+        {
+            let mut chars = word.chars();
+            id.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+            id.extend(chars);
+        }
+    }
+    id
+}
+
+/// An OpenAPI 3.0 document listing every route, for generating API clients.
+///
+/// Handlers do not describe their bodies, so requests and responses are left as any JSON value.
+pub fn openapi_spec(routes: &[RouteInfo], version: &str) -> Value {
+    let mut routes: Vec<_> = routes
+        .iter()
+        // OpenAPI has no way to describe a route that accepts any method
+        .filter(|route| route.method != "ANY")
+        .collect();
+    routes.sort_by(|a, b| (&a.path, &a.method).cmp(&(&b.path, &b.method)));
+
+    let mut paths: BTreeMap<String, serde_json::Map<String, Value>> = BTreeMap::new();
+    let mut operation_ids: FxHashMap<String, usize> = FxHashMap::default();
+    for route in routes {
+        let (path, parameters) = openapi_path(&route.path);
+        let mut id = operation_id(&route.method, &route.path);
+        let count = operation_ids.entry(id.clone()).or_default();
+        *count += 1;
+        if *count > 1 {
+            id.push_str(&count.to_string());
+        }
+        let parameters: Vec<_> = parameters
+            .into_iter()
+            .map(|name| {
+                json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" }
+                })
+            })
+            .collect();
+        let mut operation = json!({
+            "operationId": id,
+            "tags": [route.integration],
+            "parameters": parameters,
+            "responses": {
+                "default": {
+                    "description": "Any response",
+                    "content": { "application/json": { "schema": {} } }
+                }
+            }
+        });
+        if matches!(route.method.as_str(), "POST" | "PUT" | "PATCH") {
+            operation["requestBody"] = json!({
+                "required": false,
+                "content": { "application/json": { "schema": {} } }
+            });
+        }
+        paths
+            .entry(path)
+            .or_default()
+            .insert(route.method.to_lowercase(), operation);
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "teach-tech",
+            "version": version
+        },
+        "components": {
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" }
+            }
+        },
+        "security": [{ "bearer": [] }],
+        "paths": paths
+    })
+}
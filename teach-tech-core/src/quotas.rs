@@ -0,0 +1,434 @@
+//! Limits on how many requests each user can make to a group of routes within a window, such as
+//! 100 writes an hour for students and more for instructors.
+//!
+//! Policies are kept in the database and managed under `/admin/quotas`. Usage is counted in the
+//! cache, so with a shared cache quotas hold across siblings and restarts.
+
+use std::{
+    sync::{OnceLock, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, Request},
+    handler::Handler,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Json,
+};
+use fxhash::FxHashMap;
+use sea_orm::{entity::prelude::*, ActiveValue, QueryOrder};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    auth::{
+        guard::RequirePermission,
+        token::{self, Role},
+        AdminUser, AuthUser, UserID,
+    },
+    cache,
+    db::{get_db, get_read_db},
+    i18n,
+    siblings::{add_sibling_message_handler_raw, send_to_siblings_raw},
+    users::admins::permissions::Permission,
+    TeachCore,
+};
+
+const SIBLING_SOURCE: &str = "teach-tech-core/quotas";
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const ROLES_TTL: Duration = Duration::from_secs(60);
+const EXEMPT_PREFIXES: &[&str] = &["/info", "/health", "/metrics"];
+
+static GROUPS: OnceLock<FxHashMap<String, RouteGroup>> = OnceLock::new();
+static POLICIES: RwLock<Vec<Model>> = RwLock::new(vec![]);
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub quotas: QuotaOptions,
+}
+
+/// The `[quotas]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuotaOptions {
+    /// The groups of routes that policies limit, by name
+    #[serde(default = "default_groups")]
+    pub groups: FxHashMap<String, RouteGroup>,
+}
+
+impl Default for QuotaOptions {
+    fn default() -> Self {
+        Self {
+            groups: default_groups(),
+        }
+    }
+}
+
+/// The routes a request must match to count against a quota.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteGroup {
+    /// Every method if empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Path prefixes, such as `/students/`
+    #[serde(default = "default_paths")]
+    pub paths: Vec<String>,
+}
+
+impl RouteGroup {
+    fn matches(&self, method: &str, path: &str) -> bool {
+        (self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(method)))
+            && self.paths.iter().any(|prefix| path.starts_with(prefix))
+    }
+}
+
+fn default_paths() -> Vec<String> {
+    vec!["/".to_string()]
+}
+
+fn default_groups() -> FxHashMap<String, RouteGroup> {
+    let group = |methods: &[&str]| RouteGroup {
+        methods: methods.iter().map(|method| method.to_string()).collect(),
+        paths: default_paths(),
+    };
+    let mut groups = FxHashMap::default();
+    groups.insert("reads".to_string(), group(&["GET", "HEAD"]));
+    groups.insert(
+        "writes".to_string(),
+        group(&["POST", "PUT", "PATCH", "DELETE"]),
+    );
+    groups
+}
+
+/// A limit on one route group, for users with a role or, without one, for everyone.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "quota_policies")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub route_group: String,
+    pub role: Option<Role>,
+    pub max_requests: i32,
+    pub window_secs: i64,
+    pub updated_at: DateTime,
+    pub updated_by: Option<UserID>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(Debug, Deserialize)]
+pub struct SetQuota {
+    pub route_group: String,
+    pub role: Option<Role>,
+    pub max_requests: u32,
+    pub window_secs: u64,
+}
+
+async fn reload() -> Result<(), DbErr> {
+    let policies = Entity::find()
+        .order_by_asc(Column::Id)
+        .all(get_read_db())
+        .await?;
+    *POLICIES.write().unwrap() = policies;
+    Ok(())
+}
+
+async fn notify_siblings() {
+    if let Err(e) = send_to_siblings_raw(SIBLING_SOURCE, &[]).await {
+        error!("Error notifying siblings of quota policies: {e:#}");
+    }
+}
+
+/// The roles of a user, cached for a minute.
+async fn roles_of(user_id: UserID) -> Result<Vec<Role>, DbErr> {
+    let key = format!("quota_roles:{user_id}");
+    match cache::get_json(&key).await {
+        Ok(Some(roles)) => return Ok(roles),
+        Ok(None) => {}
+        Err(e) => error!("Error reading cached roles for {user_id}: {e:#}"),
+    }
+    let roles = token::roles_of(user_id, get_read_db()).await?;
+    if let Err(e) = cache::set_json(&key, &roles, Some(ROLES_TTL)).await {
+        error!("Error caching roles for {user_id}: {e:#}");
+    }
+    Ok(roles)
+}
+
+/// The policy of `group` that applies to a user with `roles`. Of those for their roles, the most
+/// generous wins, and the one for everyone applies only when none are for their roles.
+fn policy_for(policies: &[Model], group: &str, roles: &[Role]) -> Option<Model> {
+    let in_group = || policies.iter().filter(|policy| policy.route_group == group);
+    in_group()
+        .filter(|policy| policy.role.is_some_and(|role| roles.contains(&role)))
+        .max_by(|a, b| {
+            // Compared as requests per second, so that windows of different lengths compare
+            let a_rate = a.max_requests as f64 / a.window_secs as f64;
+            let b_rate = b.max_requests as f64 / b.window_secs as f64;
+            a_rate.total_cmp(&b_rate)
+        })
+        .or_else(|| in_group().find(|policy| policy.role.is_none()))
+        .cloned()
+}
+
+struct Usage {
+    /// Including the request being counted
+    count: u64,
+    /// Seconds until the window resets
+    resets_in: u64,
+}
+
+/// Counts a request against `policy`, in one step so that parallel requests are all counted.
+async fn count_request(user_id: UserID, policy: &Model) -> anyhow::Result<Usage> {
+    let window = policy.window_secs.max(1) as u64;
+    let now = chrono::Utc::now().timestamp().max(0) as u64;
+    let start = now - now % window;
+    let key = format!("quota:{}:{user_id}:{window}:{start}", policy.id);
+    let resets_in = start + window - now;
+    Ok(Usage {
+        count: cache::get_cache()
+            .incr(&key, Some(Duration::from_secs(resets_in)))
+            .await?,
+        resets_in,
+    })
+}
+
+/// Rejects requests from users who have used up a quota of a route group the request is in, with
+/// 429 and `Retry-After`.
+///
+/// Requests without a valid bearer token are left to their handlers. Scoped tokens count against
+/// the quotas of their user, whether or not the route accepts them.
+pub async fn enforce(request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if EXEMPT_PREFIXES
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+    {
+        return next.run(request).await;
+    }
+    let policies: Vec<_> = {
+        let Some(groups) = GROUPS.get() else {
+            return next.run(request).await;
+        };
+        let method = request.method().as_str();
+        POLICIES
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|policy| {
+                groups
+                    .get(&policy.route_group)
+                    .is_some_and(|group| group.matches(method, path))
+            })
+            .cloned()
+            .collect()
+    };
+    if policies.is_empty() {
+        return next.run(request).await;
+    }
+    let (mut parts, body) = request.into_parts();
+    let valid = token::validate_bearer(&parts.headers, &mut parts.extensions).await;
+    let request = Request::from_parts(parts, body);
+    let user_id = match valid {
+        Ok(Some(valid)) => valid.user_id,
+        Ok(None) => return next.run(request).await,
+        Err(e) => {
+            error!("Error validating bearer token: {e:#}");
+            return next.run(request).await;
+        }
+    };
+
+    let result = async {
+        let roles = roles_of(user_id).await?;
+        let mut groups: Vec<_> = policies
+            .iter()
+            .map(|policy| policy.route_group.as_str())
+            .collect();
+        groups.sort_unstable();
+        groups.dedup();
+        // Counting and checking are one step, so rejected requests use up the quota as well
+        let mut retry_after = None;
+        for group in groups {
+            let Some(policy) = policy_for(&policies, group, &roles) else {
+                continue;
+            };
+            let usage = count_request(user_id, &policy).await?;
+            if usage.count > policy.max_requests.max(0) as u64 {
+                retry_after = retry_after.max(Some(usage.resets_in));
+            }
+        }
+        Ok::<_, anyhow::Error>(retry_after)
+    }
+    .await;
+    match result {
+        Ok(None) => next.run(request).await,
+        Ok(Some(retry_after)) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+            i18n::t("error.quota_exceeded"),
+        )
+            .into_response(),
+        Err(e) => {
+            // An unreachable cache should not take the API down with it
+            error!("Error checking quotas of {user_id}: {e:#}");
+            next.run(request).await
+        }
+    }
+}
+
+async fn list_policies(_: AdminUser) -> Response {
+    match Entity::find()
+        .order_by_asc(Column::Id)
+        .all(get_read_db())
+        .await
+    {
+        Ok(policies) => (StatusCode::OK, Json(policies)).into_response(),
+        Err(e) => {
+            error!("Error reading quota policies: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+/// Sets the policy for a route group and role, replacing any it had.
+async fn set_policy(AuthUser { user_id, .. }: AuthUser, Json(quota): Json<SetQuota>) -> Response {
+    if !GROUPS
+        .get()
+        .is_some_and(|groups| groups.contains_key(&quota.route_group))
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            i18n::t("error.unknown_route_group"),
+        )
+            .into_response();
+    }
+    let (Ok(max_requests), Ok(window_secs)) = (
+        i32::try_from(quota.max_requests),
+        i64::try_from(quota.window_secs),
+    ) else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_quota")).into_response();
+    };
+    if window_secs == 0 {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_quota")).into_response();
+    }
+
+    let result = async {
+        let existing = Entity::find()
+            .filter(Column::RouteGroup.eq(&quota.route_group))
+            .filter(match quota.role {
+                Some(role) => Column::Role.eq(role),
+                None => Column::Role.is_null(),
+            })
+            .one(get_db())
+            .await?;
+        let model = ActiveModel {
+            id: existing
+                .as_ref()
+                .map_or(ActiveValue::not_set(), |existing| {
+                    ActiveValue::unchanged(existing.id)
+                }),
+            route_group: ActiveValue::set(quota.route_group.clone()),
+            role: ActiveValue::set(quota.role),
+            max_requests: ActiveValue::set(max_requests),
+            window_secs: ActiveValue::set(window_secs),
+            updated_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+            updated_by: ActiveValue::set(Some(user_id)),
+        };
+        if existing.is_some() {
+            model.update(get_db()).await
+        } else {
+            model.insert(get_db()).await
+        }
+    }
+    .await;
+    match result {
+        Ok(model) => {
+            info!(
+                "Quota for {} set to {max_requests} per {window_secs}s by {user_id}",
+                model.route_group
+            );
+            if let Err(e) = reload().await {
+                error!("Error reloading quota policies: {e:#}");
+            }
+            notify_siblings().await;
+            (StatusCode::OK, Json(model)).into_response()
+        }
+        Err(e) => {
+            error!("Error setting quota policy: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+async fn delete_policy(AuthUser { user_id, .. }: AuthUser, Path(id): Path<i32>) -> Response {
+    match Entity::delete_by_id(id).exec(get_db()).await {
+        Ok(result) if result.rows_affected == 0 => (StatusCode::NOT_FOUND, ()).into_response(),
+        Ok(_) => {
+            info!("Quota policy {id} deleted by {user_id}");
+            if let Err(e) = reload().await {
+                error!("Error reloading quota policies: {e:#}");
+            }
+            notify_siblings().await;
+            (StatusCode::NO_CONTENT, ()).into_response()
+        }
+        Err(e) => {
+            error!("Error deleting quota policy {id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+pub fn add_to_core<S: Clone + Send + Sync + 'static>(
+    mut core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    core.add_db_reset_config(Entity);
+    let config: QuotaConfig = toml::from_str(core.get_config_str())?;
+    let _ = GROUPS.set(config.quotas.groups);
+
+    core.add_on_serve_named("quotas", 0, || async move {
+        reload().await?;
+        add_sibling_message_handler_raw(|source, _| {
+            if source != SIBLING_SOURCE {
+                return;
+            }
+            tokio::spawn(async {
+                if let Err(e) = reload().await {
+                    error!("Failed to reload quota policies: {e:#}");
+                }
+            });
+        })
+        .await;
+        // Catches siblings that missed a notification
+        tokio::spawn(async {
+            loop {
+                tokio::time::sleep(REFRESH_INTERVAL).await;
+                if let Err(e) = reload().await {
+                    error!("Failed to reload quota policies: {e:#}");
+                }
+            }
+        });
+        Ok(())
+    });
+
+    Ok(core.modify_router(|router| {
+        router
+            .route(
+                "/admin/quotas",
+                get(list_policies)
+                    .put(set_policy.layer(RequirePermission(Permission::ManageQuotas))),
+            )
+            .route(
+                "/admin/quotas/:id",
+                delete(delete_policy).layer(RequirePermission(Permission::ManageQuotas)),
+            )
+    }))
+}
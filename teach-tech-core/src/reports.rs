@@ -0,0 +1,444 @@
+//! Reports of content that students and instructors flag for review, such as chat messages,
+//! discussion posts and submissions.
+//!
+//! Reports start `open` and are moved to `reviewed` or `actioned` by administrators. Every change
+//! is kept in `content_report_events` and handed to the sinks added with [`add_sink`], such as
+//! one that writes it to an audit log.
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json,
+};
+use futures::future::BoxFuture;
+use sea_orm::{entity::prelude::*, ActiveValue, QueryOrder, TransactionTrait};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    auth::{guard::RequirePermission, AdminUser, AuthUser, UserID},
+    db::{get_db, get_read_db, paginate, PageQuery, SoftDeletable},
+    i18n,
+    users::{admins::permissions::Permission, instructors, students},
+    TeachCore,
+};
+
+/// Reasons longer than this are refused
+const MAX_REASON_CHARS: usize = 2000;
+
+type Sink = Arc<dyn Fn(events::Model) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>;
+
+static SINKS: Mutex<Vec<(String, Sink)>> = Mutex::new(vec![]);
+
+#[derive(EnumIter, DeriveActiveEnum, Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[sea_orm(rs_type = "i32", db_type = "Integer")]
+#[serde(rename_all = "snake_case")]
+pub enum ContentKind {
+    ChatMessage = 0,
+    DiscussionPost = 1,
+    Submission = 2,
+}
+
+#[derive(EnumIter, DeriveActiveEnum, Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[sea_orm(rs_type = "i32", db_type = "Integer")]
+#[serde(rename_all = "snake_case")]
+pub enum ReportStatus {
+    Open = 0,
+    /// Looked at, and left as it is
+    Reviewed = 1,
+    /// Acted on, such as by removing the content
+    Actioned = 2,
+}
+
+impl ReportStatus {
+    /// Reports only move forward, and actioned reports are closed for good.
+    pub fn can_become(self, next: Self) -> bool {
+        matches!(
+            (self, next),
+            (Self::Open, Self::Reviewed | Self::Actioned) | (Self::Reviewed, Self::Actioned)
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "content_reports")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub kind: ContentKind,
+    /// However the integration holding the content identifies it
+    pub content_id: String,
+    pub reported_by: UserID,
+    pub reason: String,
+    pub status: ReportStatus,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// The history of each report, from its creation on.
+pub mod events {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+    #[sea_orm(table_name = "content_report_events")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i32,
+        pub report_id: i32,
+        pub status: ReportStatus,
+        pub actor: UserID,
+        pub note: Option<String>,
+        pub created_at: DateTime,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+/// Registers a function that is given every change to a report, including its creation. Sinks
+/// run in the background, and their errors are only logged.
+pub fn add_sink<F, Fut>(name: impl Into<String>, f: F)
+where
+    F: Fn(events::Model) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    SINKS
+        .lock()
+        .unwrap()
+        .push((name.into(), Arc::new(move |event| Box::pin(f(event)))));
+}
+
+fn send_to_sinks(event: events::Model) {
+    let sinks = SINKS.lock().unwrap().clone();
+    for (name, sink) in sinks {
+        let event = event.clone();
+        tokio::spawn(async move {
+            if let Err(e) = sink(event).await {
+                error!("Error sending report event through {name}: {e:#}");
+            }
+        });
+    }
+}
+
+async fn add_event(
+    db: &impl ConnectionTrait,
+    report: &Model,
+    actor: UserID,
+    note: Option<String>,
+) -> Result<events::Model, DbErr> {
+    events::ActiveModel {
+        id: ActiveValue::not_set(),
+        report_id: ActiveValue::set(report.id),
+        status: ActiveValue::set(report.status),
+        actor: ActiveValue::set(actor),
+        note: ActiveValue::set(note),
+        created_at: ActiveValue::set(report.updated_at),
+    }
+    .insert(db)
+    .await
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewReport {
+    pub kind: ContentKind,
+    pub content_id: String,
+    pub reason: String,
+}
+
+#[derive(Debug)]
+pub enum ReportError {
+    /// The reporter already has an open report of the content
+    AlreadyReported,
+    Db(DbErr),
+}
+
+impl From<DbErr> for ReportError {
+    fn from(e: DbErr) -> Self {
+        Self::Db(e)
+    }
+}
+
+/// Files a report, such as from an integration that lets users flag content in its own UI.
+pub async fn report(report: NewReport, reported_by: UserID) -> Result<Model, ReportError> {
+    let txn = get_db().begin().await?;
+    let existing = Entity::find()
+        .filter(Column::Kind.eq(report.kind))
+        .filter(Column::ContentId.eq(&report.content_id))
+        .filter(Column::ReportedBy.eq(reported_by))
+        .filter(Column::Status.eq(ReportStatus::Open))
+        .one(&txn)
+        .await?;
+    if existing.is_some() {
+        return Err(ReportError::AlreadyReported);
+    }
+    let now = chrono::Utc::now().naive_utc();
+    let model = ActiveModel {
+        id: ActiveValue::not_set(),
+        kind: ActiveValue::set(report.kind),
+        content_id: ActiveValue::set(report.content_id),
+        reported_by: ActiveValue::set(reported_by),
+        reason: ActiveValue::set(report.reason),
+        status: ActiveValue::set(ReportStatus::Open),
+        created_at: ActiveValue::set(now),
+        updated_at: ActiveValue::set(now),
+    }
+    .insert(&txn)
+    .await?;
+    let event = add_event(&txn, &model, reported_by, None).await?;
+    txn.commit().await?;
+    send_to_sinks(event);
+    Ok(model)
+}
+
+#[derive(Debug)]
+pub enum TransitionError {
+    NotFound,
+    /// The report cannot move from its status to the requested one
+    Invalid(ReportStatus),
+    Db(DbErr),
+}
+
+impl From<DbErr> for TransitionError {
+    fn from(e: DbErr) -> Self {
+        Self::Db(e)
+    }
+}
+
+/// Moves a report to `status`, noting who did so and why.
+pub async fn set_status(
+    id: i32,
+    status: ReportStatus,
+    actor: UserID,
+    note: Option<String>,
+) -> Result<Model, TransitionError> {
+    let txn = get_db().begin().await?;
+    let Some(report) = Entity::find_by_id(id).one(&txn).await? else {
+        return Err(TransitionError::NotFound);
+    };
+    if !report.status.can_become(status) {
+        return Err(TransitionError::Invalid(report.status));
+    }
+    let model = ActiveModel {
+        id: ActiveValue::unchanged(report.id),
+        status: ActiveValue::set(status),
+        updated_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+        ..Default::default()
+    }
+    .update(&txn)
+    .await?;
+    let event = add_event(&txn, &model, actor, note).await?;
+    txn.commit().await?;
+    send_to_sinks(event);
+    Ok(model)
+}
+
+async fn create_report(
+    AuthUser { user_id, .. }: AuthUser,
+    Json(new_report): Json<NewReport>,
+) -> Response {
+    let result = async {
+        if students::Entity::find_live_by_id(user_id)
+            .one(get_read_db())
+            .await?
+            .is_some()
+        {
+            return Ok(true);
+        }
+        instructors::Entity::find_live_by_id(user_id)
+            .one(get_read_db())
+            .await
+            .map(|instructor| instructor.is_some())
+    }
+    .await;
+    match result {
+        Ok(true) => {}
+        Ok(false) => {
+            return (
+                StatusCode::FORBIDDEN,
+                i18n::t("error.must_be_student_or_instructor"),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            error!("Error reading user roles: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    }
+    if new_report.content_id.is_empty()
+        || new_report.reason.trim().is_empty()
+        || new_report.reason.chars().count() > MAX_REASON_CHARS
+    {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_report")).into_response();
+    }
+
+    match report(new_report, user_id).await {
+        Ok(model) => {
+            info!("{user_id} reported {:?} {}", model.kind, model.content_id);
+            (StatusCode::CREATED, Json(model)).into_response()
+        }
+        Err(ReportError::AlreadyReported) => {
+            (StatusCode::CONFLICT, i18n::t("error.already_reported")).into_response()
+        }
+        Err(ReportError::Db(e)) => {
+            error!("Error creating report: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+/// The reports the caller filed, oldest first.
+async fn own_reports(
+    AuthUser { user_id, .. }: AuthUser,
+    Query(page): Query<PageQuery>,
+) -> Response {
+    let Ok(cursor) = page.cursor::<i32>() else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_cursor")).into_response();
+    };
+    match paginate(
+        Entity::find().filter(Column::ReportedBy.eq(user_id)),
+        Column::Id,
+        |model| model.id,
+        cursor,
+        page.limit(),
+        get_read_db(),
+    )
+    .await
+    {
+        Ok(reports) => (StatusCode::OK, Json(reports)).into_response(),
+        Err(e) => {
+            error!("Error listing reports of {user_id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueueQuery {
+    /// Open reports if not given
+    pub status: Option<ReportStatus>,
+    pub kind: Option<ContentKind>,
+}
+
+/// The moderation queue, oldest first.
+async fn list_reports(
+    _: AdminUser,
+    Query(query): Query<QueueQuery>,
+    Query(page): Query<PageQuery>,
+) -> Response {
+    let Ok(cursor) = page.cursor::<i32>() else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_cursor")).into_response();
+    };
+    let mut select =
+        Entity::find().filter(Column::Status.eq(query.status.unwrap_or(ReportStatus::Open)));
+    if let Some(kind) = query.kind {
+        select = select.filter(Column::Kind.eq(kind));
+    }
+    match paginate(
+        select,
+        Column::Id,
+        |model| model.id,
+        cursor,
+        page.limit(),
+        get_read_db(),
+    )
+    .await
+    {
+        Ok(reports) => (StatusCode::OK, Json(reports)).into_response(),
+        Err(e) => {
+            error!("Error listing reports: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportWithHistory {
+    #[serde(flatten)]
+    pub report: Model,
+    pub history: Vec<events::Model>,
+}
+
+async fn get_report(_: AdminUser, Path(id): Path<i32>) -> Response {
+    let result = async {
+        let Some(report) = Entity::find_by_id(id).one(get_read_db()).await? else {
+            return Ok(None);
+        };
+        let history = events::Entity::find()
+            .filter(events::Column::ReportId.eq(id))
+            .order_by_asc(events::Column::Id)
+            .all(get_read_db())
+            .await?;
+        Ok::<_, DbErr>(Some(ReportWithHistory { report, history }))
+    }
+    .await;
+    match result {
+        Ok(Some(report)) => (StatusCode::OK, Json(report)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => {
+            error!("Error reading report {id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetStatus {
+    pub status: ReportStatus,
+    pub note: Option<String>,
+}
+
+async fn update_status(
+    AuthUser { user_id, .. }: AuthUser,
+    Path(id): Path<i32>,
+    Json(SetStatus { status, note }): Json<SetStatus>,
+) -> Response {
+    match set_status(id, status, user_id, note).await {
+        Ok(model) => {
+            info!("Report {id} set to {status:?} by {user_id}");
+            (StatusCode::OK, Json(model)).into_response()
+        }
+        Err(TransitionError::NotFound) => (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(TransitionError::Invalid(current)) => (
+            StatusCode::CONFLICT,
+            i18n::t_with(
+                "error.invalid_report_transition",
+                &[("status", &format!("{current:?}").to_lowercase())],
+            ),
+        )
+            .into_response(),
+        Err(TransitionError::Db(e)) => {
+            error!("Error updating report {id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+pub fn add_to_core<S: Clone + Send + Sync + 'static>(mut core: TeachCore<S>) -> TeachCore<S> {
+    core.add_db_reset_config(Entity);
+    core.add_db_reset_config(events::Entity);
+
+    core.modify_router(|router| {
+        router
+            .route("/reports", post(create_report).get(own_reports))
+            .route("/admin/reports", get(list_reports))
+            .route("/admin/reports/:id", get(get_report))
+            .route(
+                "/admin/reports/:id/status",
+                post(update_status).layer(RequirePermission(Permission::ModerateReports)),
+            )
+    })
+}
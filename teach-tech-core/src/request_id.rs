@@ -0,0 +1,106 @@
+use axum::{
+    async_trait,
+    body::{Body, HttpBody},
+    extract::{FromRequestParts, Request},
+    http::{header, request::Parts, HeaderName, HeaderValue},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use serde::Serialize;
+use tower_http::{
+    request_id::{self, MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::{error, info_span, Span};
+
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The ID assigned to the current request, either taken from an incoming `X-Request-Id` header or
+/// freshly generated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl RequestId {
+    pub(crate) fn from_extensions(extensions: &axum::http::Extensions) -> Option<Self> {
+        extensions
+            .get::<request_id::RequestId>()
+            .and_then(|id| id.header_value().to_str().ok())
+            .map(|id| Self(id.to_string()))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequestId {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_extensions(&parts.extensions).unwrap_or_else(|| Self("unknown".into())))
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    request_id: &'a str,
+}
+
+// Error responses without a body get the request id so that it can be quoted in support tickets
+async fn attach_to_errors(request: Request, next: Next) -> Response {
+    let request_id = RequestId::from_extensions(request.extensions());
+    let mut response = next.run(request).await;
+    let status = response.status();
+    if !(status.is_client_error() || status.is_server_error())
+        || response.body().size_hint().exact() != Some(0)
+    {
+        return response;
+    }
+    let Some(RequestId(request_id)) = request_id else {
+        return response;
+    };
+    let body = match serde_json::to_vec(&ErrorBody {
+        request_id: &request_id,
+    }) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Error serializing request id body: {e:#}");
+            return response;
+        }
+    };
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    response.headers_mut().remove(header::CONTENT_LENGTH);
+    *response.body_mut() = Body::from(body);
+    response
+}
+
+fn make_span(request: &Request) -> Span {
+    let request_id = RequestId::from_extensions(request.extensions())
+        .map(|RequestId(id)| id)
+        .unwrap_or_default();
+    info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        request_id,
+    )
+}
+
+pub(crate) fn add_layers(router: Router) -> Router {
+    router
+        .layer(middleware::from_fn(attach_to_errors))
+        .layer(TraceLayer::new_for_http().make_span_with(make_span))
+        .layer(PropagateRequestIdLayer::new(REQUEST_ID_HEADER.clone()))
+        .layer(SetRequestIdLayer::new(
+            REQUEST_ID_HEADER.clone(),
+            MakeRequestUuid,
+        ))
+}
@@ -0,0 +1,112 @@
+use std::sync::Arc;
+
+use axum::{
+    body::HttpBody,
+    extract::{MatchedPath, Request},
+    http,
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use serde::Deserialize;
+use tower_http::compression::{
+    predicate::{NotForContentType, Predicate, SizeAbove},
+    CompressionLayer,
+};
+
+/// The `[api.compression]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompressionOptions {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Responses smaller than this many bytes are sent as they are
+    #[serde(default = "default_min_size")]
+    pub min_size: u16,
+    /// The encodings offered to clients, from those teach-tech-core was built with
+    #[serde(default = "default_algorithms")]
+    pub algorithms: Vec<CompressionAlgorithm>,
+    /// Routes whose responses are never compressed, as they appear in `routes`, such as
+    /// `/quick-chat/conversations/:id/messages`
+    #[serde(default)]
+    pub excluded_routes: Vec<String>,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            min_size: default_min_size(),
+            algorithms: default_algorithms(),
+            excluded_routes: vec![],
+        }
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_min_size() -> u16 {
+    32
+}
+
+fn default_algorithms() -> Vec<CompressionAlgorithm> {
+    vec![CompressionAlgorithm::Br]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum CompressionAlgorithm {
+    #[serde(rename = "br")]
+    Br,
+}
+
+/// Put on responses of excluded routes, so that the compression predicate can see them.
+#[derive(Clone, Copy)]
+struct Uncompressed;
+
+#[derive(Clone, Copy)]
+struct NotExcluded;
+
+impl Predicate for NotExcluded {
+    fn should_compress<B>(&self, response: &http::Response<B>) -> bool
+    where
+        B: HttpBody,
+    {
+        response.extensions().get::<Uncompressed>().is_none()
+    }
+}
+
+async fn mark_excluded(excluded: Arc<[String]>, request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let excluded = route.is_some_and(|route| excluded.contains(&route));
+    let mut response = next.run(request).await;
+    if excluded {
+        response.extensions_mut().insert(Uncompressed);
+    }
+    response
+}
+
+pub(crate) fn add_layer(router: Router, options: &CompressionOptions) -> Router {
+    if !options.enabled || options.algorithms.is_empty() {
+        return router;
+    }
+    let excluded: Arc<[String]> = options.excluded_routes.clone().into();
+    // Event streams are never compressed, since compression buffers the events they send
+    let predicate = SizeAbove::new(options.min_size)
+        .and(NotForContentType::GRPC)
+        .and(NotForContentType::IMAGES)
+        .and(NotForContentType::SSE)
+        .and(NotExcluded);
+    router
+        .layer(middleware::from_fn(move |request, next| {
+            mark_excluded(excluded.clone(), request, next)
+        }))
+        .layer(
+            CompressionLayer::new()
+                .br(options.algorithms.contains(&CompressionAlgorithm::Br))
+                .compress_when(predicate),
+        )
+}
@@ -0,0 +1,223 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use fxhash::FxHashMap;
+use sea_orm::{entity::prelude::*, ActiveValue};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+    auth::token,
+    db::{get_db, get_named_db, transaction_with_retry},
+    users::admins::{notifications, notifications_archive},
+    TeachCore,
+};
+
+const DAY: chrono::TimeDelta = chrono::TimeDelta::days(1);
+
+static RULES: Mutex<Vec<Arc<RetentionRule>>> = Mutex::new(vec![]);
+
+type DeleteOlderThan = Box<
+    dyn Fn(&'static DatabaseConnection, DateTime) -> BoxFuture<'static, Result<u64, DbErr>>
+        + Send
+        + Sync,
+>;
+
+struct RetentionRule {
+    table: String,
+    connection: Option<String>,
+    max_age: Duration,
+    delete_older_than: DeleteOlderThan,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RetentionConfig {
+    #[serde(default)]
+    pub retention: RetentionOptions,
+}
+
+/// The `[retention]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetentionOptions {
+    /// Tokens that have not been used for this many days are deleted
+    pub token_max_idle_days: Option<u32>,
+    /// Admin notifications older than this many days are removed
+    pub notification_max_age_days: Option<u32>,
+    /// Moves old notifications to `admin_notifications_archive` instead of deleting them.
+    /// Tokens are always deleted, since archiving them would keep credentials around.
+    #[serde(default)]
+    pub archive: bool,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// Overrides the maximum age in days of rows in tables registered with
+    /// [`TeachCore::add_retention`]. A value of 0 keeps rows forever.
+    #[serde(default)]
+    pub tables: FxHashMap<String, u32>,
+}
+
+impl Default for RetentionOptions {
+    fn default() -> Self {
+        Self {
+            token_max_idle_days: Some(30),
+            notification_max_age_days: None,
+            archive: false,
+            interval_secs: default_interval_secs(),
+            tables: FxHashMap::default(),
+        }
+    }
+}
+
+fn default_interval_secs() -> u64 {
+    60 * 60
+}
+
+fn cutoff(days: u32) -> DateTime {
+    chrono::Utc::now().naive_utc() - DAY * days as i32
+}
+
+async fn delete_idle_tokens(days: u32) -> Result<u64, DbErr> {
+    token::Entity::delete_many()
+        .filter(token::Column::LastUsed.lt(cutoff(days)))
+        .exec(get_db())
+        .await
+        .map(|result| result.rows_affected)
+}
+
+async fn remove_old_notifications(days: u32, archive: bool) -> anyhow::Result<u64> {
+    let cutoff = cutoff(days);
+    if !archive {
+        return Ok(notifications::Entity::delete_many()
+            .filter(notifications::Column::CreatedAt.lt(cutoff))
+            .exec(get_db())
+            .await?
+            .rows_affected);
+    }
+    let archived = transaction_with_retry(get_db(), |txn| {
+        Box::pin(async move {
+            let old = notifications::Entity::find()
+                .filter(notifications::Column::CreatedAt.lt(cutoff))
+                .all(txn)
+                .await?;
+            if old.is_empty() {
+                return Ok(0);
+            }
+            let archived_at = chrono::Utc::now().naive_utc();
+            let ids: Vec<_> = old.iter().map(|model| model.id).collect();
+            notifications_archive::Entity::insert_many(old.into_iter().map(|model| {
+                notifications_archive::ActiveModel {
+                    id: ActiveValue::set(model.id),
+                    user_id: ActiveValue::set(model.user_id),
+                    severity: ActiveValue::set(model.severity),
+                    message: ActiveValue::set(model.message),
+                    created_at: ActiveValue::set(model.created_at),
+                    archived_at: ActiveValue::set(archived_at),
+                }
+            }))
+            .exec(txn)
+            .await?;
+            notifications::Entity::delete_many()
+                .filter(notifications::Column::Id.is_in(ids))
+                .exec(txn)
+                .await
+                .map(|result| result.rows_affected)
+        })
+    })
+    .await?;
+    Ok(archived)
+}
+
+pub(crate) fn add_rule<E: EntityTrait>(
+    connection: Option<String>,
+    entity: E,
+    column: E::Column,
+    max_age: Duration,
+) {
+    RULES.lock().unwrap().push(Arc::new(RetentionRule {
+        table: entity.table_name().to_string(),
+        connection,
+        max_age,
+        delete_older_than: Box::new(move |db, cutoff| {
+            Box::pin(async move {
+                E::delete_many()
+                    .filter(column.lt(cutoff))
+                    .exec(db)
+                    .await
+                    .map(|result| result.rows_affected)
+            })
+        }),
+    }));
+}
+
+async fn apply_rules(options: &RetentionOptions) {
+    let rules = RULES.lock().unwrap().clone();
+    for table in options.tables.keys() {
+        if !rules.iter().any(|rule| &rule.table == table) {
+            warn!("retention.tables.{table} does not match any table with a retention rule");
+        }
+    }
+    for rule in rules {
+        let max_age = match options.tables.get(&rule.table) {
+            Some(0) => continue,
+            Some(&days) => DAY * days as i32,
+            // Out of range durations never expire
+            None => match chrono::TimeDelta::from_std(rule.max_age) {
+                Ok(max_age) => max_age,
+                Err(_) => continue,
+            },
+        };
+        let Some(cutoff) = chrono::Utc::now().naive_utc().checked_sub_signed(max_age) else {
+            continue;
+        };
+        let db = match &rule.connection {
+            Some(name) => get_named_db(name),
+            None => get_db(),
+        };
+        match (rule.delete_older_than)(db, cutoff).await {
+            Ok(0) => {}
+            Ok(deleted) => info!(
+                "Deleted {deleted} rows from {} older than {cutoff}",
+                rule.table
+            ),
+            Err(e) => warn!("Error applying retention to {}: {e}", rule.table),
+        }
+    }
+}
+
+async fn apply(options: &RetentionOptions) -> anyhow::Result<()> {
+    if let Some(days) = options.token_max_idle_days {
+        let deleted = delete_idle_tokens(days).await?;
+        if deleted > 0 {
+            info!("Deleted {deleted} tokens unused for {days} days");
+        }
+    }
+    if let Some(days) = options.notification_max_age_days {
+        let removed = remove_old_notifications(days, options.archive).await?;
+        if removed > 0 {
+            let action = if options.archive {
+                "Archived"
+            } else {
+                "Deleted"
+            };
+            info!("{action} {removed} admin notifications older than {days} days");
+        }
+    }
+    apply_rules(options).await;
+    Ok(())
+}
+
+pub fn add_to_core<S>(mut core: TeachCore<S>) -> anyhow::Result<TeachCore<S>> {
+    let config: RetentionConfig = toml::from_str(core.get_config_str())?;
+    let options = config.retention;
+    core.add_scheduled_task(
+        "retention",
+        Duration::from_secs(options.interval_secs),
+        move || {
+            let options = options.clone();
+            async move { apply(&options).await }
+        },
+    );
+    Ok(core)
+}
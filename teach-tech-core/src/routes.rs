@@ -0,0 +1,184 @@
+use std::{convert::Infallible, panic::Location, path::Path};
+
+use axum::{
+    extract::Request,
+    handler::Handler,
+    response::IntoResponse,
+    routing::{MethodRouter, Route},
+    Router,
+};
+use serde::Serialize;
+use tower::{Layer, Service};
+
+const METHODS: &[&str] = &[
+    "get", "head", "delete", "options", "patch", "post", "put", "trace",
+];
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RouteInfo {
+    pub method: String,
+    pub path: String,
+    pub integration: String,
+}
+
+// axum does not expose which methods a MethodRouter handles, but its Debug output lists every
+// method endpoint, with `None` for the missing ones.
+fn methods_of<S>(method_router: &MethodRouter<S>) -> Vec<String> {
+    let debug = format!("{method_router:?}");
+    let methods: Vec<_> = METHODS
+        .iter()
+        .filter(|method| {
+            debug.contains(&format!("{method}: ")) && !debug.contains(&format!("{method}: None"))
+        })
+        .map(|method| method.to_uppercase())
+        .collect();
+    if methods.is_empty() {
+        vec!["ANY".to_string()]
+    } else {
+        methods
+    }
+}
+
+// The crate that registered a route is the folder containing its `src` folder, with any
+// `-<version>` suffix that cargo adds to registry sources removed.
+pub(crate) fn integration_name(location: &Location) -> String {
+    let path = Path::new(location.file());
+    let crate_dir = path
+        .ancestors()
+        .find(|ancestor| ancestor.file_name().is_some_and(|name| name == "src"))
+        .and_then(Path::parent)
+        .and_then(Path::file_name)
+        .and_then(|name| name.to_str());
+    let Some(crate_dir) = crate_dir else {
+        return location.file().to_string();
+    };
+    match crate_dir.rsplit_once('-') {
+        Some((name, version)) if version.starts_with(|c: char| c.is_ascii_digit()) => {
+            name.to_string()
+        }
+        _ => crate_dir.to_string(),
+    }
+}
+
+/// A [`Router`] that records every route added through it, so that the `routes` command can list
+/// them without starting the server.
+pub struct TrackedRouter<S> {
+    router: Router<S>,
+    routes: Vec<RouteInfo>,
+    integration: String,
+}
+
+impl<S> TrackedRouter<S> {
+    pub(crate) fn new(router: Router<S>, integration: String) -> Self {
+        Self {
+            router,
+            routes: vec![],
+            integration,
+        }
+    }
+
+    pub(crate) fn into_parts(self) -> (Router<S>, Vec<RouteInfo>) {
+        (self.router, self.routes)
+    }
+}
+
+impl<S: Clone + Send + Sync + 'static> TrackedRouter<S> {
+    fn record(&mut self, methods: Vec<String>, path: &str) {
+        self.routes
+            .extend(methods.into_iter().map(|method| RouteInfo {
+                method,
+                path: path.to_string(),
+                integration: self.integration.clone(),
+            }));
+    }
+
+    pub fn route(mut self, path: &str, method_router: MethodRouter<S>) -> Self {
+        self.record(methods_of(&method_router), path);
+        self.router = self.router.route(path, method_router);
+        self
+    }
+
+    pub fn route_service<T>(mut self, path: &str, service: T) -> Self
+    where
+        T: Service<Request, Error = Infallible> + Clone + Send + 'static,
+        T::Response: IntoResponse,
+        T::Future: Send + 'static,
+    {
+        self.record(vec!["ANY".to_string()], path);
+        self.router = self.router.route_service(path, service);
+        self
+    }
+
+    pub fn layer<L>(self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + 'static,
+        L::Service: Service<Request> + Clone + Send + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        Self {
+            router: self.router.layer(layer),
+            ..self
+        }
+    }
+
+    pub fn route_layer<L>(self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + 'static,
+        L::Service: Service<Request> + Clone + Send + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        Self {
+            router: self.router.route_layer(layer),
+            ..self
+        }
+    }
+
+    pub fn fallback<H, T>(self, handler: H) -> Self
+    where
+        H: Handler<T, S>,
+        T: 'static,
+    {
+        Self {
+            router: self.router.fallback(handler),
+            ..self
+        }
+    }
+
+    pub fn with_state<S2>(self, state: S) -> TrackedRouter<S2> {
+        TrackedRouter {
+            router: self.router.with_state(state),
+            routes: self.routes,
+            integration: self.integration,
+        }
+    }
+
+    /// Applies `f` to the underlying router. Routes added this way are not listed by the
+    /// `routes` command.
+    pub fn map<S2>(self, f: impl FnOnce(Router<S>) -> Router<S2>) -> TrackedRouter<S2> {
+        TrackedRouter {
+            router: f(self.router),
+            routes: self.routes,
+            integration: self.integration,
+        }
+    }
+}
+
+pub fn print_routes(routes: &[RouteInfo]) {
+    let mut routes: Vec<_> = routes.iter().collect();
+    routes.sort_by(|a, b| (&a.path, &a.method).cmp(&(&b.path, &b.method)));
+    let path_width = routes
+        .iter()
+        .map(|route| route.path.len())
+        .max()
+        .unwrap_or(0);
+    for route in routes {
+        println!(
+            "{:<7} {:<path_width$} {}",
+            route.method, route.path, route.integration
+        );
+    }
+}
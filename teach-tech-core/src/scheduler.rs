@@ -0,0 +1,48 @@
+use std::{future::Future, pin::Pin, time::Duration};
+
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{debug, error};
+
+use crate::error_reporting::{self, ErrorSource};
+
+pub(crate) type Task =
+    Box<dyn Fn() -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>> + Send + Sync>;
+
+pub(crate) struct ScheduledTask {
+    pub name: String,
+    pub interval: Duration,
+    pub f: Task,
+}
+
+async fn run(task: ScheduledTask) {
+    let mut interval = tokio::time::interval(task.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let start = Instant::now();
+        match (task.f)().await {
+            Ok(()) => debug!(
+                "Scheduled task {} finished in {:?}",
+                task.name,
+                start.elapsed()
+            ),
+            Err(e) => {
+                error!("Scheduled task {} failed: {e:#}", task.name);
+                error_reporting::report(
+                    ErrorSource::BackgroundTask {
+                        name: task.name.clone(),
+                    },
+                    format!("{e:#}"),
+                );
+            }
+        }
+    }
+}
+
+// Every task runs once when the server starts and then once per interval. A failed run is logged
+// and retried at the next interval.
+pub(crate) fn start_all(tasks: Vec<ScheduledTask>) {
+    for task in tasks {
+        tokio::spawn(run(task));
+    }
+}
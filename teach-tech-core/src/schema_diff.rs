@@ -0,0 +1,187 @@
+use std::fmt::Display;
+
+use sea_orm::{
+    sea_query::{
+        ColumnSpec, IndexCreateStatement, TableCreateStatement, TableDropStatement, TableRef,
+    },
+    ConnectionTrait, DatabaseBackend, DatabaseConnection, DbErr, Statement,
+};
+
+use crate::db::{get_db, get_named_db};
+
+/// The tables `reset-db` creates for an entity.
+pub(crate) struct EntityTable {
+    pub connection: Option<String>,
+    pub drop: TableDropStatement,
+    pub create: TableCreateStatement,
+    pub indexes: Vec<IndexCreateStatement>,
+}
+
+impl EntityTable {
+    pub fn db(&self) -> &'static DatabaseConnection {
+        match &self.connection {
+            Some(name) => get_named_db(name),
+            None => get_db(),
+        }
+    }
+
+    fn table_name(&self) -> String {
+        match self.create.get_table_name() {
+            Some(TableRef::Table(table) | TableRef::SchemaTable(_, table)) => table.to_string(),
+            _ => unreachable!("Entities always have a table name"),
+        }
+    }
+
+    // Unique columns are enforced with an index on every backend, so they are checked like indexes
+    fn expected_indexes(&self) -> Vec<Vec<String>> {
+        let unique_columns = self.create.get_columns().iter().filter(|column| {
+            column
+                .get_column_spec()
+                .iter()
+                .any(|spec| matches!(spec, ColumnSpec::UniqueKey))
+        });
+        unique_columns
+            .map(|column| vec![column.get_column_name()])
+            .chain(
+                self.create
+                    .get_indexes()
+                    .iter()
+                    .chain(&self.indexes)
+                    .map(|index| index.get_index_spec().get_column_names()),
+            )
+            .map(|mut columns| {
+                columns.sort();
+                columns
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum Drift {
+    MissingTable { table: String },
+    MissingColumn { table: String, column: String },
+    MissingIndex { table: String, columns: Vec<String> },
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Drift::MissingTable { table } => write!(f, "missing table {table}"),
+            Drift::MissingColumn { table, column } => {
+                write!(f, "missing column {table}.{column}")
+            }
+            Drift::MissingIndex { table, columns } => {
+                write!(f, "missing index on {table} ({})", columns.join(", "))
+            }
+        }
+    }
+}
+
+async fn query_strings(
+    db: &DatabaseConnection,
+    sql: &str,
+    table: &str,
+    columns: &[&str],
+) -> Result<Vec<Vec<String>>, DbErr> {
+    let backend = db.get_database_backend();
+    let rows = db
+        .query_all(Statement::from_sql_and_values(backend, sql, [table.into()]))
+        .await?;
+    rows.iter()
+        .map(|row| {
+            columns
+                .iter()
+                .map(|column| row.try_get::<String>("", column))
+                .collect()
+        })
+        .collect()
+}
+
+async fn live_columns(db: &DatabaseConnection, table: &str) -> Result<Vec<String>, DbErr> {
+    let sql = match db.get_database_backend() {
+        DatabaseBackend::Postgres => {
+            "SELECT column_name::text AS column_name FROM information_schema.columns \
+             WHERE table_schema = current_schema() AND table_name = $1"
+        }
+        DatabaseBackend::MySql => {
+            "SELECT column_name AS column_name FROM information_schema.columns \
+             WHERE table_schema = DATABASE() AND table_name = ?"
+        }
+        DatabaseBackend::Sqlite => "SELECT name AS column_name FROM pragma_table_info(?)",
+    };
+    let rows = query_strings(db, sql, table, &["column_name"]).await?;
+    Ok(rows.into_iter().flatten().collect())
+}
+
+// Indexes are compared by the columns they cover, since their names differ between backends
+async fn live_indexes(db: &DatabaseConnection, table: &str) -> Result<Vec<Vec<String>>, DbErr> {
+    let sql = match db.get_database_backend() {
+        DatabaseBackend::Postgres => {
+            "SELECT i.relname::text AS index_name, a.attname::text AS column_name \
+             FROM pg_index ix \
+             JOIN pg_class t ON t.oid = ix.indrelid \
+             JOIN pg_class i ON i.oid = ix.indexrelid \
+             JOIN pg_attribute a ON a.attrelid = t.oid AND a.attnum = ANY(ix.indkey) \
+             WHERE t.relname = $1 AND t.relnamespace = current_schema()::regnamespace"
+        }
+        DatabaseBackend::MySql => {
+            "SELECT index_name AS index_name, column_name AS column_name \
+             FROM information_schema.statistics \
+             WHERE table_schema = DATABASE() AND table_name = ?"
+        }
+        DatabaseBackend::Sqlite => {
+            "SELECT il.name AS index_name, ii.name AS column_name \
+             FROM pragma_index_list(?) il JOIN pragma_index_info(il.name) ii"
+        }
+    };
+    let rows = query_strings(db, sql, table, &["index_name", "column_name"]).await?;
+    let mut indexes: Vec<(String, Vec<String>)> = vec![];
+    for row in rows {
+        let [index, column] = <[String; 2]>::try_from(row).unwrap();
+        match indexes.iter_mut().find(|(name, _)| *name == index) {
+            Some((_, columns)) => columns.push(column),
+            None => indexes.push((index, vec![column])),
+        }
+    }
+    Ok(indexes
+        .into_iter()
+        .map(|(_, mut columns)| {
+            columns.sort();
+            columns
+        })
+        .collect())
+}
+
+/// Compares the live database with the tables that `reset-db` would create.
+pub(crate) async fn schema_diff(tables: &[EntityTable]) -> Result<Vec<Drift>, DbErr> {
+    let mut drift = vec![];
+    for entity in tables {
+        let db = entity.db();
+        let table = entity.table_name();
+        let columns = live_columns(db, &table).await?;
+        if columns.is_empty() {
+            drift.push(Drift::MissingTable { table });
+            continue;
+        }
+        for column in entity.create.get_columns() {
+            let column = column.get_column_name();
+            if !columns.contains(&column) {
+                drift.push(Drift::MissingColumn {
+                    table: table.clone(),
+                    column,
+                });
+            }
+        }
+        let indexes = live_indexes(db, &table).await?;
+        for columns in entity.expected_indexes() {
+            if !indexes.contains(&columns) {
+                drift.push(Drift::MissingIndex {
+                    table: table.clone(),
+                    columns,
+                });
+            }
+        }
+    }
+    Ok(drift)
+}
@@ -0,0 +1,233 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::Request,
+    http::StatusCode,
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use fxhash::FxHashMap;
+use serde::Deserialize;
+use tracing::{error, warn};
+
+use crate::{metrics, users::admins::notifications, TeachCore};
+
+const EVENTS: usize = SecurityEvent::ALL.len();
+
+static OPTIONS: OnceLock<SecurityAlertOptions> = OnceLock::new();
+static COUNTS: [AtomicU64; EVENTS] = [const { AtomicU64::new(0) }; EVENTS];
+static WINDOWS: Mutex<[Window; EVENTS]> = Mutex::new(
+    [const {
+        Window {
+            start: None,
+            count: 0,
+            last_alert: None,
+        }
+    }; EVENTS],
+);
+
+/// Something that may be part of an attack, counted in `/metrics` and alerted on when it happens
+/// too often.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecurityEvent {
+    FailedLogin,
+    /// An account or address was locked out after too many failed attempts
+    Lockout,
+    /// A response with status 401
+    Unauthorized,
+    /// A response with status 403
+    Forbidden,
+    /// A bearer token that does not exist or has expired
+    InvalidToken,
+}
+
+impl SecurityEvent {
+    const ALL: [Self; 5] = [
+        Self::FailedLogin,
+        Self::Lockout,
+        Self::Unauthorized,
+        Self::Forbidden,
+        Self::InvalidToken,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::FailedLogin => "failed_login",
+            Self::Lockout => "lockout",
+            Self::Unauthorized => "unauthorized",
+            Self::Forbidden => "forbidden",
+            Self::InvalidToken => "invalid_token",
+        }
+    }
+
+    fn default_threshold(self) -> u64 {
+        match self {
+            Self::FailedLogin => 50,
+            Self::Lockout => 10,
+            Self::Unauthorized => 500,
+            Self::Forbidden => 200,
+            Self::InvalidToken => 200,
+        }
+    }
+}
+
+struct Window {
+    start: Option<Instant>,
+    count: u64,
+    last_alert: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SecurityAlertConfig {
+    #[serde(default)]
+    pub security_alerts: SecurityAlertOptions,
+}
+
+/// The `[security_alerts]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecurityAlertOptions {
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// The least time between two alerts about the same event
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: u64,
+    /// Overrides how many of an event within the window notify the admins, by event name. A value
+    /// of 0 turns the alert off
+    #[serde(default)]
+    pub thresholds: FxHashMap<String, u64>,
+}
+
+impl Default for SecurityAlertOptions {
+    fn default() -> Self {
+        Self {
+            window_secs: default_window_secs(),
+            cooldown_secs: default_cooldown_secs(),
+            thresholds: FxHashMap::default(),
+        }
+    }
+}
+
+fn default_window_secs() -> u64 {
+    300
+}
+
+fn default_cooldown_secs() -> u64 {
+    3600
+}
+
+impl SecurityAlertOptions {
+    fn threshold(&self, event: SecurityEvent) -> u64 {
+        self.thresholds
+            .get(event.name())
+            .copied()
+            .unwrap_or_else(|| event.default_threshold())
+    }
+}
+
+/// Counts an occurrence of `event`, notifying every admin once it crosses its threshold.
+pub fn record(event: SecurityEvent) {
+    let index = event as usize;
+    COUNTS[index].fetch_add(1, Ordering::Relaxed);
+
+    let Some(options) = OPTIONS.get() else {
+        return;
+    };
+    let threshold = options.threshold(event);
+    if threshold == 0 {
+        return;
+    }
+    let now = Instant::now();
+    let window_length = Duration::from_secs(options.window_secs);
+    let count = {
+        let mut windows = WINDOWS.lock().unwrap();
+        let window = &mut windows[index];
+        if window
+            .start
+            .is_none_or(|start| now.duration_since(start) > window_length)
+        {
+            window.start = Some(now);
+            window.count = 0;
+        }
+        window.count += 1;
+        let cooled_down = window.last_alert.is_none_or(|last| {
+            now.duration_since(last) > Duration::from_secs(options.cooldown_secs)
+        });
+        if window.count < threshold || !cooled_down {
+            return;
+        }
+        window.last_alert = Some(now);
+        window.count
+    };
+
+    let message = format!(
+        "{count} {} events in the last {} seconds, which is at least the alert threshold of \
+         {threshold}",
+        event.name(),
+        options.window_secs
+    );
+    warn!("{message}");
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    runtime.spawn(async move {
+        if let Err(e) = notifications::notify_all("warning", &message).await {
+            error!("Error notifying admins of a security alert: {e:#}");
+        }
+    });
+}
+
+async fn count_rejections(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    match response.status() {
+        StatusCode::UNAUTHORIZED => record(SecurityEvent::Unauthorized),
+        StatusCode::FORBIDDEN => record(SecurityEvent::Forbidden),
+        _ => {}
+    }
+    response
+}
+
+pub(crate) fn add_layer(router: Router) -> Router {
+    router.layer(middleware::from_fn(count_rejections))
+}
+
+fn collect(writer: &mut metrics::MetricsWriter) {
+    let samples: Vec<_> = SecurityEvent::ALL
+        .iter()
+        .map(|&event| {
+            (
+                vec![("event", event.name().to_string())],
+                COUNTS[event as usize].load(Ordering::Relaxed) as f64,
+            )
+        })
+        .collect();
+    writer.counter(
+        "teach_security_events_total",
+        "Failed logins, lockouts, 401 and 403 responses and invalid tokens",
+        &samples,
+    );
+}
+
+pub fn add_to_core<S>(core: TeachCore<S>) -> anyhow::Result<TeachCore<S>> {
+    let config: SecurityAlertConfig = toml::from_str(core.get_config_str())?;
+    if let Some(name) = config.security_alerts.thresholds.keys().find(|name| {
+        !SecurityEvent::ALL
+            .iter()
+            .any(|event| event.name() == name.as_str())
+    }) {
+        return Err(anyhow::anyhow!(
+            "security_alerts.thresholds has an unknown event {name}"
+        ));
+    }
+    OPTIONS
+        .set(config.security_alerts)
+        .expect("Security alert options are already initialized");
+    metrics::add_collector(collect);
+    Ok(core)
+}
@@ -0,0 +1,322 @@
+use std::{
+    convert::Infallible,
+    io::{self, IoSlice},
+    net::SocketAddr,
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{body::Body, extract::ConnectInfo, Router};
+use hyper::{
+    body::{Bytes, Frame, Incoming, SizeHint},
+    service::service_fn,
+    Request,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+use tower::ServiceExt;
+use tracing::{debug, error};
+
+/// The `[api.http]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpOptions {
+    /// Also accepts HTTP/2 without TLS (h2c), for proxies that speak it to the API. Connections
+    /// are HTTP/1.1 otherwise
+    #[serde(default)]
+    pub http2: bool,
+    /// Whether connections are kept open between requests
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: bool,
+    /// How long a connection may wait for the headers of its next request, whether it is idle
+    /// between requests or sending them slowly, before it is closed
+    #[serde(default = "default_keep_alive_timeout_secs")]
+    pub keep_alive_timeout_secs: u64,
+    /// Limits the requests in flight on one HTTP/2 connection
+    pub max_concurrent_streams: Option<u32>,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            http2: false,
+            keep_alive: default_keep_alive(),
+            keep_alive_timeout_secs: default_keep_alive_timeout_secs(),
+            max_concurrent_streams: None,
+        }
+    }
+}
+
+fn default_keep_alive() -> bool {
+    true
+}
+
+fn default_keep_alive_timeout_secs() -> u64 {
+    30
+}
+
+impl HttpOptions {
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if self.max_concurrent_streams.is_some() && !self.http2 {
+            return Err(anyhow::anyhow!(
+                "api.http.max_concurrent_streams only applies when api.http.http2 is set"
+            ));
+        }
+        if self.max_concurrent_streams == Some(0) {
+            return Err(anyhow::anyhow!(
+                "api.http.max_concurrent_streams must be at least 1"
+            ));
+        }
+        if self.keep_alive_timeout_secs == 0 {
+            return Err(anyhow::anyhow!(
+                "api.http.keep_alive_timeout_secs must be at least 1"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A connection that remembers when it last read or wrote anything.
+struct Tracked {
+    stream: TcpStream,
+    start: Instant,
+    /// Milliseconds from `start` to the last read or write
+    last_active: Arc<AtomicU64>,
+}
+
+impl Tracked {
+    fn touch(&self) {
+        self.last_active
+            .store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+/// How long a [`Tracked`] connection has gone without reading or writing. Connections are not
+/// idle while a request is in flight, however long its handler takes.
+#[derive(Clone)]
+struct Idle {
+    start: Instant,
+    last_active: Arc<AtomicU64>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Idle {
+    fn duration(&self) -> Duration {
+        if self.in_flight.load(Ordering::Relaxed) > 0 {
+            return Duration::ZERO;
+        }
+        self.start.elapsed().saturating_sub(Duration::from_millis(
+            self.last_active.load(Ordering::Relaxed),
+        ))
+    }
+}
+
+/// Counts a request as in flight on its connection until dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(in_flight: &Arc<AtomicUsize>) -> Self {
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(in_flight.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A response body that keeps its request in flight until it has been sent, so that a streamed
+/// response that pauses is not taken for an idle connection.
+struct InFlightBody {
+    body: Body,
+    _in_flight: InFlight,
+}
+
+impl hyper::body::Body for InFlightBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl AsyncRead for Tracked {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let result = Pin::new(&mut self.stream).poll_read(cx, buf);
+        if result.is_ready() {
+            self.touch();
+        }
+        result
+    }
+}
+
+impl AsyncWrite for Tracked {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if result.is_ready() {
+            self.touch();
+        }
+        result
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.stream).poll_write_vectored(cx, bufs);
+        if result.is_ready() {
+            self.touch();
+        }
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Serves `router` on `listener` like `axum::serve`, with the connection settings in `options`.
+pub(crate) async fn serve(listener: TcpListener, router: Router, options: HttpOptions) {
+    let keep_alive = options.keep_alive;
+    let keep_alive_timeout = Duration::from_secs(options.keep_alive_timeout_secs);
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    if !options.http2 {
+        builder = builder.http1_only();
+    }
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .keep_alive(options.keep_alive)
+        .header_read_timeout(keep_alive_timeout);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(options.max_concurrent_streams);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                // Usually running out of file descriptors, which waiting may fix
+                error!("Error accepting connection: {e:#}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let router = router.clone();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let requests = in_flight.clone();
+        let service = service_fn(move |mut request: Request<Incoming>| {
+            request
+                .extensions_mut()
+                .insert(ConnectInfo::<SocketAddr>(peer));
+            let in_flight = InFlight::new(&requests);
+            let router = router.clone();
+            async move {
+                let response = router.oneshot(request).await?;
+                Ok::<_, Infallible>(response.map(|body| InFlightBody {
+                    body,
+                    _in_flight: in_flight,
+                }))
+            }
+        });
+        let stream = Tracked {
+            stream,
+            start: Instant::now(),
+            last_active: Arc::new(AtomicU64::new(0)),
+        };
+        let idle = Idle {
+            start: stream.start,
+            last_active: stream.last_active.clone(),
+            in_flight,
+        };
+        let connection = builder
+            .serve_connection_with_upgrades(TokioIo::new(stream), service)
+            .into_owned();
+        tokio::spawn(async move {
+            let mut connection = pin!(connection);
+            // hyper only times out the wait for the first request, so connections that go idle
+            // between requests are closed here. Requests in flight keep them open
+            let mut watch_idle = keep_alive;
+            let result = loop {
+                let idle_left = keep_alive_timeout.saturating_sub(idle.duration());
+                tokio::select! {
+                    result = connection.as_mut() => break result,
+                    _ = tokio::time::sleep(idle_left), if watch_idle => {
+                        if idle.duration() >= keep_alive_timeout {
+                            connection.as_mut().graceful_shutdown();
+                            watch_idle = false;
+                        }
+                    }
+                }
+            };
+            if let Err(e) = result {
+                debug!("Connection from {peer} ended with an error: {e:#}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_are_not_idle_while_responding() {
+        let idle = Idle {
+            start: Instant::now() - Duration::from_secs(10),
+            last_active: Arc::new(AtomicU64::new(0)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        };
+        assert!(idle.duration() >= Duration::from_secs(10));
+        let body = InFlightBody {
+            body: Body::from("streaming"),
+            _in_flight: InFlight::new(&idle.in_flight),
+        };
+        assert_eq!(idle.duration(), Duration::ZERO);
+        drop(body);
+        assert!(idle.duration() >= Duration::from_secs(10));
+    }
+}
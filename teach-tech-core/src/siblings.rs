@@ -8,10 +8,12 @@ use futures::{stream::FuturesUnordered, StreamExt};
 use fxhash::{FxBuildHasher, FxHashMap};
 use sea_orm::{prelude::*, ActiveValue};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter}, net::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf, ReuniteError},
         TcpListener, TcpStream,
-    }, sync::Mutex
+    },
+    sync::Mutex,
 };
 use tracing::error;
 
@@ -19,8 +21,8 @@ use crate::{db::get_db, ApiConfig, TeachCore};
 
 static CURRENT_ADDRESS: OnceLock<SocketAddr> = OnceLock::new();
 const SIBLING_PORT: u16 = 22114;
-static SIBLING_MESSAGE_HANDLERS: Mutex<Vec<Box<dyn FnMut(&str, &[u8]) + Send>>> =
-    Mutex::const_new(vec![]);
+type SiblingMessageHandler = Box<dyn FnMut(&str, &[u8]) + Send>;
+static SIBLING_MESSAGE_HANDLERS: Mutex<Vec<SiblingMessageHandler>> = Mutex::const_new(vec![]);
 static SIBLING_CONNS: Mutex<FxHashMap<IpAddr, BufWriter<OwnedWriteHalf>>> =
     Mutex::const_new(HashMap::with_hasher(FxBuildHasher::new()));
 
@@ -104,20 +106,20 @@ pub fn add_to_core<S: Clone + Send + Sync + 'static>(
         .expect("Server address is already initialized");
     core.add_to_drop(move || async move {
         println!("Deleting server address from database");
-        if let Err(e) = Entity::delete_by_id(&api_config.server_address.to_string())
+        if let Err(e) = Entity::delete_by_id(api_config.server_address.to_string())
             .exec(get_db())
             .await
         {
             error!("Failed to remove server address from database: {}", e);
         }
     });
-    core.add_on_serve(move || async move {
+    core.add_on_serve_named("siblings", 0, move || async move {
         // if !api_config.server_address.ip().is_unspecified() && !api_config.server_address.ip().is_loopback() {
-            ActiveModel {
-                address: ActiveValue::set(api_config.server_address.to_string()),
-            }
-            .insert(get_db())
-            .await?;
+        ActiveModel {
+            address: ActiveValue::set(api_config.server_address.to_string()),
+        }
+        .insert(get_db())
+        .await?;
         // }
         let mut addr = api_config.server_address;
         addr.set_port(SIBLING_PORT);
@@ -151,7 +153,11 @@ pub async fn send_to_siblings_raw(source: &str, bytes: &[u8]) -> anyhow::Result<
     {
         let mut futures = FuturesUnordered::new();
         for backend_data in Entity::find().all(get_db()).await?.into_iter() {
-            if backend_data.address == CURRENT_ADDRESS.get().unwrap().to_string() {
+            // Commands that do not serve have no address of their own but can still notify siblings
+            if CURRENT_ADDRESS
+                .get()
+                .is_some_and(|current| backend_data.address == current.to_string())
+            {
                 continue;
             }
             let mut addr: SocketAddr = match backend_data.address.parse() {
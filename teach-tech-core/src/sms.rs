@@ -0,0 +1,238 @@
+#[cfg(feature = "sms-twilio")]
+pub mod twilio;
+
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use futures::future::BoxFuture;
+use sea_orm::{entity::prelude::*, sea_query::OnConflict, ActiveValue};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    auth::{AuthUser, UserID},
+    db::{get_db, get_read_db},
+    encryption::Encrypted,
+    i18n, notifications, TeachCore,
+};
+
+// Two SMS segments, so that urgent messages are not cut off too early
+const MAX_LENGTH: usize = 306;
+
+static SENDERS: Mutex<Vec<(String, Arc<dyn SmsSender>)>> = Mutex::new(vec![]);
+
+/// Sends text messages through a provider, such as Twilio.
+pub trait SmsSender: Send + Sync + 'static {
+    /// Sends `body` to `to`, a phone number in E.164 form.
+    fn send(&self, to: &str, body: &str) -> BoxFuture<'static, anyhow::Result<()>>;
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SmsConfig {
+    pub sms: Option<SmsOptions>,
+}
+
+/// The `[sms]` section of `teach-config.toml`. Without it, no text messages are sent.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SmsOptions {
+    /// `twilio`, or the name of a sender added by an integration
+    pub provider: String,
+    #[cfg(feature = "sms-twilio")]
+    pub twilio: Option<twilio::TwilioOptions>,
+    #[cfg(not(feature = "sms-twilio"))]
+    pub twilio: Option<toml::Table>,
+}
+
+/// Registers a way of sending text messages, chosen by setting `sms.provider` to `name`.
+pub fn add_sender(name: impl Into<String>, sender: impl SmsSender) {
+    SENDERS
+        .lock()
+        .unwrap()
+        .push((name.into(), Arc::new(sender)));
+}
+
+fn sender(name: &str) -> Option<Arc<dyn SmsSender>> {
+    SENDERS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(sender, _)| sender == name)
+        .map(|(_, sender)| sender.clone())
+}
+
+/// The users who asked to get urgent notifications by text message.
+pub mod subscriptions {
+    use super::*;
+
+    #[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+    #[sea_orm(table_name = "sms_subscriptions")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub user_id: UserID,
+        pub phone_number: Encrypted<String, Entity>,
+        pub created_at: DateTime,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+/// Whether `number` is in E.164 form, such as `+14155550100`.
+pub fn is_valid_number(number: &str) -> bool {
+    let Some(digits) = number.strip_prefix('+') else {
+        return false;
+    };
+    (8..=15).contains(&digits.len())
+        && !digits.starts_with('0')
+        && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn message(notification: &notifications::Model) -> String {
+    let message = format!("{}: {}", notification.title, notification.body);
+    if message.chars().count() <= MAX_LENGTH {
+        return message;
+    }
+    let mut message: String = message.chars().take(MAX_LENGTH - 1).collect();
+    message.push('…');
+    message
+}
+
+async fn send_urgent(
+    sender: Arc<dyn SmsSender>,
+    notification: notifications::Model,
+) -> anyhow::Result<()> {
+    if !notification.urgent {
+        return Ok(());
+    }
+    let Some(subscription) = subscriptions::Entity::find_by_id(notification.user_id)
+        .one(get_read_db())
+        .await?
+    else {
+        return Ok(());
+    };
+    sender
+        .send(&subscription.phone_number.0, &message(&notification))
+        .await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Subscribe {
+    pub phone_number: String,
+}
+
+async fn get_subscription(AuthUser { user_id, .. }: AuthUser) -> Response {
+    match subscriptions::Entity::find_by_id(user_id)
+        .one(get_read_db())
+        .await
+    {
+        Ok(Some(subscription)) => (StatusCode::OK, Json(subscription)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => {
+            error!("Error reading SMS subscription of {user_id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+async fn subscribe(
+    AuthUser { user_id, .. }: AuthUser,
+    Json(subscribe): Json<Subscribe>,
+) -> Response {
+    if !is_valid_number(&subscribe.phone_number) {
+        return (
+            StatusCode::BAD_REQUEST,
+            i18n::t("error.invalid_phone_number"),
+        )
+            .into_response();
+    }
+    let result = subscriptions::Entity::insert(subscriptions::ActiveModel {
+        user_id: ActiveValue::set(user_id),
+        phone_number: ActiveValue::set(Encrypted::new(
+            subscribe.phone_number,
+            subscriptions::Column::PhoneNumber,
+            user_id,
+        )),
+        created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+    })
+    .on_conflict(
+        OnConflict::column(subscriptions::Column::UserId)
+            .update_columns([
+                subscriptions::Column::PhoneNumber,
+                subscriptions::Column::CreatedAt,
+            ])
+            .to_owned(),
+    )
+    .exec_without_returning(get_db())
+    .await;
+    match result {
+        Ok(_) => (StatusCode::OK, ()).into_response(),
+        Err(e) => {
+            error!("Error saving SMS subscription of {user_id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+async fn unsubscribe(AuthUser { user_id, .. }: AuthUser) -> Response {
+    match subscriptions::Entity::delete_by_id(user_id)
+        .exec(get_db())
+        .await
+    {
+        Ok(_) => (StatusCode::OK, ()).into_response(),
+        Err(e) => {
+            error!("Error deleting SMS subscription of {user_id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+pub fn add_to_core<S: Clone + Send + Sync + 'static>(
+    mut core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    let config: SmsConfig = toml::from_str(core.get_config_str())?;
+    core.add_db_reset_config(subscriptions::Entity);
+    core.add_encrypted_column(subscriptions::Entity, subscriptions::Column::PhoneNumber);
+
+    core = core.modify_router(|router| {
+        router.route(
+            "/sms/subscription",
+            get(get_subscription).put(subscribe).delete(unsubscribe),
+        )
+    });
+
+    let Some(options) = config.sms else {
+        return Ok(core);
+    };
+    if let Some(twilio) = options.twilio {
+        #[cfg(feature = "sms-twilio")]
+        add_sender("twilio", twilio::TwilioSender::new(twilio)?);
+        #[cfg(not(feature = "sms-twilio"))]
+        {
+            let _ = twilio;
+            return Err(anyhow::anyhow!(
+                "sms.twilio is set, but teach-tech-core was built without the sms-twilio feature"
+            ));
+        }
+    }
+
+    // Senders of integrations are only added after the core, so the sink looks the provider up
+    // once they are all in
+    let provider = options.provider;
+    core.add_on_serve_named("sms", 0, move || async move {
+        let Some(sender) = sender(&provider) else {
+            return Err(anyhow::anyhow!("There is no SMS provider named {provider}"));
+        };
+        notifications::add_sink("sms", move |notification| {
+            send_urgent(sender.clone(), notification)
+        });
+        Ok(())
+    });
+    Ok(core)
+}
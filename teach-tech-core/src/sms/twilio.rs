@@ -0,0 +1,94 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use zeroize::Zeroizing;
+
+use super::SmsSender;
+use crate::http::{Endpoint, HttpClient};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The `[sms.twilio]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TwilioOptions {
+    pub account_sid: String,
+    pub auth_token: Zeroizing<String>,
+    /// The number messages are sent from, in E.164 form
+    pub from: String,
+    /// Services with a Twilio-compatible API, such as SignalWire, are used by changing this
+    #[serde(default = "default_api_url")]
+    pub api_url: String,
+}
+
+fn default_api_url() -> String {
+    "https://api.twilio.com".into()
+}
+
+struct Inner {
+    options: TwilioOptions,
+    /// The url of the Messages resource
+    url: String,
+    client: HttpClient,
+}
+
+/// Sends messages through Twilio's Messages API, or any API that mimics it.
+pub struct TwilioSender(Arc<Inner>);
+
+impl TwilioSender {
+    pub fn new(options: TwilioOptions) -> anyhow::Result<Self> {
+        let url = format!(
+            "{}/2010-04-01/Accounts/{}/Messages.json",
+            options.api_url.trim_end_matches('/'),
+            options.account_sid
+        );
+        Endpoint::parse(&url).context("Checking sms.twilio.api_url")?;
+        Ok(Self(Arc::new(Inner {
+            url,
+            client: HttpClient::new(TIMEOUT)?,
+            options,
+        })))
+    }
+}
+
+impl Inner {
+    fn send(&self, to: &str, body: &str) -> anyhow::Result<()> {
+        let form = serde_urlencoded::to_string([
+            ("To", to),
+            ("From", &self.options.from),
+            ("Body", body),
+        ])?;
+        let authorization = Zeroizing::new(format!(
+            "Basic {}",
+            STANDARD.encode(format!(
+                "{}:{}",
+                self.options.account_sid, &*self.options.auth_token
+            ))
+        ));
+        let response = self.client.send(
+            "POST",
+            &self.url,
+            &[("Authorization", &authorization)],
+            Some(("application/x-www-form-urlencoded", form.as_bytes())),
+        )?;
+        if !response.is_success() {
+            return Err(anyhow::anyhow!(
+                "Twilio responded with {}: {}",
+                response.status,
+                response.text()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl SmsSender for TwilioSender {
+    fn send(&self, to: &str, body: &str) -> BoxFuture<'static, anyhow::Result<()>> {
+        let inner = self.0.clone();
+        let to = to.to_string();
+        let body = body.to_string();
+        Box::pin(async move { tokio::task::spawn_blocking(move || inner.send(&to, &body)).await? })
+    }
+}
@@ -0,0 +1,59 @@
+use std::{
+    any::{type_name, Any, TypeId},
+    sync::Arc,
+};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+};
+use fxhash::FxHashMap;
+use tracing::error;
+
+pub(crate) type StateMap = FxHashMap<TypeId, Box<dyn Any + Send + Sync>>;
+
+/// Typed values shared between integrations, inserted with [`TeachCore::insert_state`].
+///
+/// [`TeachCore::insert_state`]: crate::TeachCore::insert_state
+#[derive(Clone, Default)]
+pub struct StateRegistry(Arc<StateMap>);
+
+impl StateRegistry {
+    pub(crate) fn new(states: StateMap) -> Self {
+        Self(Arc::new(states))
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.0
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+}
+
+/// Extracts a clone of the state of type `T` that was registered with `TeachCore::insert_state`.
+///
+/// Unlike `axum::extract::State`, this does not require every integration to agree on the
+/// router's state type.
+pub struct State<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequestParts<S> for State<T>
+where
+    S: Send + Sync,
+    T: Clone + Send + Sync + 'static,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts
+            .extensions
+            .get::<StateRegistry>()
+            .and_then(|registry| registry.get::<T>())
+        else {
+            error!("State {} was not inserted into TeachCore", type_name::<T>());
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        Ok(Self(value.clone()))
+    }
+}
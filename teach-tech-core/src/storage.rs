@@ -0,0 +1,412 @@
+mod local;
+#[cfg(feature = "storage-s3")]
+pub mod s3;
+pub mod scan;
+
+use std::{
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, Query},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Json,
+};
+use futures::future::BoxFuture;
+use sea_orm::{entity::prelude::*, ActiveValue};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+use zeroize::Zeroizing;
+
+use crate::{
+    auth::{AdminUser, UserID},
+    db::{get_db, get_read_db, paginate, PageQuery},
+    i18n,
+    users::admins,
+    TeachCore,
+};
+
+static BACKENDS: Mutex<Vec<(String, Arc<dyn StorageBackend>)>> = Mutex::new(vec![]);
+static STORAGE: OnceLock<Arc<dyn StorageBackend>> = OnceLock::new();
+static MAX_UPLOAD_BYTES: OnceLock<usize> = OnceLock::new();
+
+/// A stored file and the type it was stored with.
+#[derive(Debug, Clone)]
+pub struct StoredObject {
+    pub bytes: Vec<u8>,
+    pub content_type: String,
+}
+
+/// Somewhere to keep files, such as a directory or an S3 bucket.
+///
+/// Keys are `/` separated paths made of ASCII letters, digits, `.`, `_` and `-`, as checked by
+/// [`is_valid_key`].
+pub trait StorageBackend: Send + Sync + 'static {
+    /// Stores `bytes` under `key`, replacing whatever was there.
+    fn put(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        content_type: &str,
+    ) -> BoxFuture<'static, anyhow::Result<()>>;
+
+    /// Reads the file under `key`, if there is one.
+    fn get(&self, key: &str) -> BoxFuture<'static, anyhow::Result<Option<StoredObject>>>;
+
+    /// Deletes the file under `key`. Deleting a missing file is not an error.
+    fn delete(&self, key: &str) -> BoxFuture<'static, anyhow::Result<()>>;
+
+    /// A URL anyone can download the file under `key` from until `expires_in` passes, so that
+    /// large files do not go through the API.
+    fn signed_url(&self, key: &str, expires_in: Duration) -> anyhow::Result<String>;
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StorageConfig {
+    pub storage: Option<StorageOptions>,
+}
+
+/// The `[storage]` section of `teach-config.toml`. Without it, uploads are refused.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StorageOptions {
+    /// `local`, `s3`, or the name of a backend added by an integration
+    pub backend: String,
+    #[serde(default = "default_max_upload_mb")]
+    pub max_upload_mb: usize,
+    pub local: Option<LocalOptions>,
+    #[cfg(feature = "storage-s3")]
+    pub s3: Option<s3::S3Options>,
+    #[cfg(not(feature = "storage-s3"))]
+    pub s3: Option<toml::Table>,
+    pub scan: Option<scan::ScanOptions>,
+}
+
+fn default_max_upload_mb() -> usize {
+    25
+}
+
+/// The `[storage.local]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LocalOptions {
+    pub root: std::path::PathBuf,
+    /// Signs download URLs. Every sibling must share it and the same `root`
+    pub signing_key: Zeroizing<String>,
+    /// Prepended to download URLs, such as `https://school.example`. They are relative otherwise
+    #[serde(default)]
+    pub public_url: String,
+}
+
+/// Registers a backend, chosen by setting `storage.backend` to `name`.
+pub fn add_backend(name: impl Into<String>, backend: impl StorageBackend) {
+    BACKENDS
+        .lock()
+        .unwrap()
+        .push((name.into(), Arc::new(backend)));
+}
+
+/// The configured backend, or `None` when `teach-config.toml` has no `[storage]` section.
+pub fn get_storage() -> Option<Arc<dyn StorageBackend>> {
+    STORAGE.get().cloned()
+}
+
+/// The largest upload the configured backend is meant to take.
+pub fn max_upload_bytes() -> usize {
+    MAX_UPLOAD_BYTES
+        .get()
+        .copied()
+        .unwrap_or(default_max_upload_mb() * 1024 * 1024)
+}
+
+pub fn is_valid_key(key: &str) -> bool {
+    key.len() <= 512
+        && key.split('/').all(|segment| {
+            !segment.is_empty()
+                && segment != "."
+                && segment != ".."
+                && segment
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+        })
+}
+
+pub(crate) fn check_key(key: &str) -> anyhow::Result<()> {
+    if is_valid_key(key) {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("{key:?} is not a valid storage key"))
+    }
+}
+
+/// Uploads a scanner flagged. Their files are kept under `quarantine/` and never served.
+pub mod quarantine {
+    use super::*;
+
+    #[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+    #[sea_orm(table_name = "storage_quarantine")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i32,
+        /// Where the file is kept now
+        pub key: String,
+        /// Where the file was meant to be stored
+        pub original_key: String,
+        pub content_type: String,
+        pub size: i64,
+        pub uploaded_by: UserID,
+        pub threat: String,
+        pub created_at: DateTime,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    /// The scanner flagged the upload, with the name of what it found
+    Quarantined(String),
+    /// Storage is not configured, or the upload could not be scanned
+    Unavailable(anyhow::Error),
+    Failed(anyhow::Error),
+}
+
+impl IntoResponse for UploadError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Quarantined(_) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                i18n::t("error.upload_quarantined"),
+            )
+                .into_response(),
+            Self::Unavailable(e) => {
+                warn!("Refusing upload: {e:#}");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    i18n::t("error.upload_unavailable"),
+                )
+                    .into_response()
+            }
+            Self::Failed(e) => {
+                error!("Error storing upload: {e:#}");
+                (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+            }
+        }
+    }
+}
+
+async fn quarantine(
+    storage: &dyn StorageBackend,
+    key: &str,
+    bytes: Vec<u8>,
+    content_type: &str,
+    uploaded_by: UserID,
+    threat: &str,
+) -> anyhow::Result<()> {
+    let quarantine_key = format!("quarantine/{key}");
+    let size = bytes.len() as i64;
+    storage.put(&quarantine_key, bytes, content_type).await?;
+    quarantine::ActiveModel {
+        id: ActiveValue::not_set(),
+        key: ActiveValue::set(quarantine_key),
+        original_key: ActiveValue::set(key.to_string()),
+        content_type: ActiveValue::set(content_type.to_string()),
+        size: ActiveValue::set(size),
+        uploaded_by: ActiveValue::set(uploaded_by),
+        threat: ActiveValue::set(threat.to_string()),
+        created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+    }
+    .insert(get_db())
+    .await?;
+    Ok(())
+}
+
+/// Stores a file a user uploaded, after the configured scanner, if any, finds it clean.
+///
+/// Flagged files are quarantined instead, and every admin is notified.
+pub async fn put_upload(
+    key: &str,
+    bytes: Vec<u8>,
+    content_type: &str,
+    uploaded_by: UserID,
+) -> Result<(), UploadError> {
+    let Some(storage) = get_storage() else {
+        return Err(UploadError::Unavailable(anyhow::anyhow!(
+            "Storage is not configured"
+        )));
+    };
+    if let Some((scanner, fail_open)) = scan::get() {
+        match scanner.scan(&bytes).await {
+            Ok(scan::Verdict::Clean) => {}
+            Ok(scan::Verdict::Infected(threat)) => {
+                if let Err(e) =
+                    quarantine(&*storage, key, bytes, content_type, uploaded_by, &threat).await
+                {
+                    error!("Error quarantining {key}: {e:#}");
+                }
+                let message =
+                    format!("An upload by {uploaded_by} was quarantined for containing {threat}");
+                if let Err(e) = admins::notifications::notify_all("warning", &message).await {
+                    error!("Error notifying admins of a quarantined upload: {e:#}");
+                }
+                return Err(UploadError::Quarantined(threat));
+            }
+            Err(e) if fail_open => warn!("Storing {key} without scanning it: {e:#}"),
+            Err(e) => return Err(UploadError::Unavailable(e.context("Scanning upload"))),
+        }
+    }
+    storage
+        .put(key, bytes, content_type)
+        .await
+        .map_err(UploadError::Failed)
+}
+
+async fn list_quarantine(_: AdminUser, Query(page): Query<PageQuery>) -> Response {
+    let Ok(cursor) = page.cursor::<i32>() else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_cursor")).into_response();
+    };
+    match paginate(
+        quarantine::Entity::find(),
+        quarantine::Column::Id,
+        |model| model.id,
+        cursor,
+        page.limit(),
+        get_read_db(),
+    )
+    .await
+    {
+        Ok(quarantined) => (StatusCode::OK, Json(quarantined)).into_response(),
+        Err(e) => {
+            error!("Error listing quarantined uploads: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+/// Deletes a quarantined upload for good.
+async fn purge_quarantined(_: AdminUser, Path(id): Path<i32>) -> Response {
+    let quarantined = match quarantine::Entity::find_by_id(id).one(get_db()).await {
+        Ok(Some(quarantined)) => quarantined,
+        Ok(None) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => {
+            error!("Error reading quarantined upload: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+    if let Some(storage) = get_storage() {
+        if let Err(e) = storage.delete(&quarantined.key).await {
+            error!(
+                "Error deleting quarantined upload {}: {e:#}",
+                quarantined.key
+            );
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    }
+    match quarantine::Entity::delete_by_id(id).exec(get_db()).await {
+        Ok(_) => (StatusCode::OK, ()).into_response(),
+        Err(e) => {
+            error!("Error deleting quarantined upload: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Signature {
+    expires: i64,
+    signature: String,
+}
+
+async fn download(Path(key): Path<String>, Query(signature): Query<Signature>) -> Response {
+    let Some(local) = local::get() else {
+        return (StatusCode::NOT_FOUND, ()).into_response();
+    };
+    if !is_valid_key(&key)
+        || key.starts_with("quarantine/")
+        || !local.verify(&key, signature.expires, &signature.signature)
+    {
+        return (StatusCode::FORBIDDEN, ()).into_response();
+    }
+    match local.read(&key).await {
+        Ok(Some(object)) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, object.content_type),
+                (header::CACHE_CONTROL, "private".to_string()),
+                // Uploads come from users, so they must not run as pages of the API's origin
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+                (header::CONTENT_SECURITY_POLICY, "sandbox".to_string()),
+            ],
+            object.bytes,
+        )
+            .into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => {
+            error!("Error reading {key} from storage: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+pub fn add_to_core<S: Clone + Send + Sync + 'static>(
+    mut core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    let config: StorageConfig = toml::from_str(core.get_config_str())?;
+    core.add_db_reset_config(quarantine::Entity);
+    let Some(options) = config.storage else {
+        return Ok(core);
+    };
+    let _ = MAX_UPLOAD_BYTES.set(options.max_upload_mb * 1024 * 1024);
+    core = core.modify_router(|router| {
+        router
+            .route("/storage/quarantine", get(list_quarantine))
+            .route("/storage/quarantine/:id", delete(purge_quarantined))
+    });
+
+    if let Some(local) = options.local {
+        add_backend("local", local::LocalBackend::new(local)?);
+        core = core.modify_router(|router| router.route("/storage/objects/*key", get(download)));
+    }
+    if let Some(s3) = options.s3 {
+        #[cfg(feature = "storage-s3")]
+        add_backend("s3", s3::S3Backend::new(s3)?);
+        #[cfg(not(feature = "storage-s3"))]
+        {
+            let _ = s3;
+            return Err(anyhow::anyhow!(
+                "storage.s3 is set, but teach-tech-core was built without the storage-s3 feature"
+            ));
+        }
+    }
+
+    let scanner = match options.scan {
+        Some(scan) => {
+            let scanner = (scan.scanner.clone(), scan.fail_open);
+            scan::init(scan)?;
+            Some(scanner)
+        }
+        None => None,
+    };
+
+    // Backends and scanners of integrations are only added after the core
+    let backend = options.backend;
+    core.add_on_serve_named("storage", 0, move || async move {
+        if let Some((scanner, fail_open)) = scanner {
+            scan::select(&scanner, fail_open)?;
+        }
+        let storage = BACKENDS
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(name, _)| *name == backend)
+            .map(|(_, storage)| storage.clone())
+            .ok_or_else(|| anyhow::anyhow!("There is no storage backend named {backend}"))?;
+        let _ = STORAGE.set(storage);
+        Ok(())
+    });
+    Ok(core)
+}
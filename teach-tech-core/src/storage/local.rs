@@ -0,0 +1,186 @@
+use std::{
+    fmt::Write as _,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use rand::{thread_rng, Rng};
+use ring::hmac;
+
+use super::{check_key, LocalOptions, StorageBackend, StoredObject};
+
+static LOCAL: OnceLock<Arc<Inner>> = OnceLock::new();
+
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+pub(super) struct Inner {
+    /// Files are kept under `root/objects`, and their content types under `root/types`
+    root: PathBuf,
+    key: hmac::Key,
+    public_url: String,
+}
+
+/// Keeps files in a directory, serving them under `/storage/objects`.
+pub struct LocalBackend(Arc<Inner>);
+
+/// The local backend, if one was configured, whether or not it is the one in use.
+pub(super) fn get() -> Option<Arc<Inner>> {
+    LOCAL.get().cloned()
+}
+
+impl LocalBackend {
+    pub fn new(options: LocalOptions) -> anyhow::Result<Self> {
+        if options.signing_key.len() < 32 {
+            return Err(anyhow::anyhow!(
+                "storage.local.signing_key must be at least 32 characters"
+            ));
+        }
+        for dir in ["objects", "types"] {
+            let path = options.root.join(dir);
+            std::fs::create_dir_all(&path)
+                .with_context(|| format!("Creating {}", path.display()))?;
+        }
+        let inner = Arc::new(Inner {
+            root: options.root,
+            key: hmac::Key::new(hmac::HMAC_SHA256, options.signing_key.as_bytes()),
+            public_url: options.public_url.trim_end_matches('/').to_string(),
+        });
+        let _ = LOCAL.set(inner.clone());
+        Ok(Self(inner))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl Inner {
+    fn message(key: &str, expires: i64) -> String {
+        format!("{key}\n{expires}")
+    }
+
+    pub(super) fn verify(&self, key: &str, expires: i64, signature: &str) -> bool {
+        if expires < chrono::Utc::now().timestamp() {
+            return false;
+        }
+        let Some(signature) = unhex(signature) else {
+            return false;
+        };
+        hmac::verify(
+            &self.key,
+            Self::message(key, expires).as_bytes(),
+            &signature,
+        )
+        .is_ok()
+    }
+
+    pub(super) async fn read(&self, key: &str) -> anyhow::Result<Option<StoredObject>> {
+        let bytes = match tokio::fs::read(self.root.join("objects").join(key)).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let content_type = match tokio::fs::read_to_string(self.root.join("types").join(key)).await
+        {
+            Ok(content_type) => content_type,
+            Err(e) if e.kind() == ErrorKind::NotFound => DEFAULT_CONTENT_TYPE.to_string(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(StoredObject {
+            bytes,
+            content_type,
+        }))
+    }
+
+    /// Writes next to `path` first, so that readers never see half a file.
+    async fn write(path: PathBuf, bytes: &[u8]) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(format!(".{:016x}.tmp", thread_rng().gen::<u64>()));
+        tokio::fs::write(&temporary, bytes).await?;
+        tokio::fs::rename(&temporary, &path).await?;
+        Ok(())
+    }
+
+    async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> anyhow::Result<()> {
+        Self::write(self.root.join("types").join(key), content_type.as_bytes()).await?;
+        Self::write(self.root.join("objects").join(key), &bytes).await
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        for dir in ["objects", "types"] {
+            match tokio::fs::remove_file(self.root.join(dir).join(key)).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    fn put(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        content_type: &str,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        let inner = self.0.clone();
+        let key = key.to_string();
+        let content_type = content_type.to_string();
+        Box::pin(async move {
+            check_key(&key)?;
+            inner.put(&key, bytes, &content_type).await
+        })
+    }
+
+    fn get(&self, key: &str) -> BoxFuture<'static, anyhow::Result<Option<StoredObject>>> {
+        let inner = self.0.clone();
+        let key = key.to_string();
+        Box::pin(async move {
+            check_key(&key)?;
+            inner.read(&key).await
+        })
+    }
+
+    fn delete(&self, key: &str) -> BoxFuture<'static, anyhow::Result<()>> {
+        let inner = self.0.clone();
+        let key = key.to_string();
+        Box::pin(async move {
+            check_key(&key)?;
+            inner.delete(&key).await
+        })
+    }
+
+    fn signed_url(&self, key: &str, expires_in: Duration) -> anyhow::Result<String> {
+        check_key(key)?;
+        let expires = chrono::Utc::now().timestamp() + expires_in.as_secs() as i64;
+        let signature = hmac::sign(&self.0.key, Inner::message(key, expires).as_bytes());
+        Ok(format!(
+            "{}/storage/objects/{key}?expires={expires}&signature={}",
+            self.0.public_url,
+            hex(signature.as_ref())
+        ))
+    }
+}
@@ -0,0 +1,301 @@
+use std::{fmt::Write as _, sync::Arc, time::Duration};
+
+use futures::future::BoxFuture;
+use ring::{digest, hmac};
+use serde::Deserialize;
+use zeroize::Zeroizing;
+
+use super::{check_key, StorageBackend, StoredObject};
+use crate::http::{HttpClient, HttpResponse};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// The longest a presigned URL may last, as set by S3
+const MAX_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The `[storage.s3]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct S3Options {
+    /// Such as `https://s3.eu-west-1.amazonaws.com`, or the address of an S3-compatible service
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub access_key_id: String,
+    pub secret_access_key: Zeroizing<String>,
+    /// Puts the bucket in the path instead of the host name, as most S3-compatible services
+    /// expect
+    #[serde(default)]
+    pub path_style: bool,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    tls: bool,
+    /// The value of the Host header, with the port when it is not the default one
+    authority: String,
+    /// Prepended to the key in every path
+    prefix: String,
+}
+
+impl Endpoint {
+    fn parse(options: &S3Options) -> anyhow::Result<Self> {
+        let url = &options.endpoint;
+        let invalid = || anyhow::anyhow!("storage.s3.endpoint {url} is not a valid url");
+        let (scheme, rest) = url.split_once("://").ok_or_else(invalid)?;
+        let tls = match scheme {
+            "https" => true,
+            "http" => false,
+            _ => return Err(invalid()),
+        };
+        let address = rest.trim_end_matches('/');
+        if address.contains('/') {
+            return Err(invalid());
+        }
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port.parse::<u16>().map_err(|_| invalid())?)),
+            None => (address, None),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let (host, prefix) = if options.path_style {
+            (host.to_string(), format!("/{}", options.bucket))
+        } else {
+            (format!("{}.{host}", options.bucket), String::new())
+        };
+        let authority = match port {
+            Some(port) => format!("{host}:{port}"),
+            None => host.clone(),
+        };
+        Ok(Self {
+            tls,
+            authority,
+            prefix,
+        })
+    }
+
+    fn url(&self) -> String {
+        format!(
+            "{}://{}",
+            if self.tls { "https" } else { "http" },
+            self.authority
+        )
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, bytes).as_ref())
+}
+
+fn hmac(key: &[u8], message: &str) -> hmac::Tag {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), message.as_bytes())
+}
+
+/// Percent-encodes everything but unreserved characters, as Signature Version 4 expects.
+fn uri_encode(value: &str, keep_slashes: bool) -> String {
+    let mut encoded = String::new();
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric()
+            || matches!(b, b'-' | b'_' | b'.' | b'~')
+            || (keep_slashes && b == b'/')
+        {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    encoded
+}
+
+fn error(response: &HttpResponse, action: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "{action} was answered with {}: {}",
+        response.status,
+        response.text()
+    )
+}
+
+struct Inner {
+    options: S3Options,
+    endpoint: Endpoint,
+    client: HttpClient,
+}
+
+/// Keeps files in an S3 bucket, or one of a service with the same API.
+pub struct S3Backend(Arc<Inner>);
+
+impl S3Backend {
+    pub fn new(options: S3Options) -> anyhow::Result<Self> {
+        Ok(Self(Arc::new(Inner {
+            endpoint: Endpoint::parse(&options)?,
+            // Downloads are as large as the uploads they hold
+            client: HttpClient::new(TIMEOUT)?.max_body(super::max_upload_bytes()),
+            options,
+        })))
+    }
+}
+
+impl Inner {
+    fn path(&self, key: &str) -> String {
+        format!("{}/{}", self.endpoint.prefix, uri_encode(key, true))
+    }
+
+    fn scope(&self, date: &str) -> String {
+        format!("{date}/{}/s3/aws4_request", self.options.region)
+    }
+
+    fn signature(&self, date: &str, timestamp: &str, canonical_request: &str) -> String {
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{}\n{}",
+            self.scope(date),
+            sha256(canonical_request.as_bytes())
+        );
+        let secret = Zeroizing::new(format!("AWS4{}", &*self.options.secret_access_key));
+        let key = hmac(secret.as_bytes(), date);
+        let key = hmac(key.as_ref(), &self.options.region);
+        let key = hmac(key.as_ref(), "s3");
+        let key = hmac(key.as_ref(), "aws4_request");
+        hex(hmac(key.as_ref(), &string_to_sign).as_ref())
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        key: &str,
+        body: &[u8],
+        content_type: Option<&str>,
+    ) -> anyhow::Result<HttpResponse> {
+        let now = chrono::Utc::now();
+        let date = now.format("%Y%m%d").to_string();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let path = self.path(key);
+        let payload_hash = sha256(body);
+        let endpoint = &self.endpoint;
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{method}\n{path}\n\nhost:{}\nx-amz-content-sha256:{payload_hash}\n\
+             x-amz-date:{timestamp}\n\n{signed_headers}\n{payload_hash}",
+            endpoint.authority
+        );
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={signed_headers}, Signature={}",
+            self.options.access_key_id,
+            self.scope(&date),
+            self.signature(&date, &timestamp, &canonical_request)
+        );
+
+        self.client.send(
+            method,
+            &format!("{}{path}", endpoint.url()),
+            &[
+                ("Authorization", &authorization),
+                ("x-amz-content-sha256", &payload_hash),
+                ("x-amz-date", &timestamp),
+            ],
+            Some((content_type.unwrap_or_default(), body)),
+        )
+    }
+
+    fn put(&self, key: &str, bytes: &[u8], content_type: &str) -> anyhow::Result<()> {
+        let response = self.request("PUT", key, bytes, Some(content_type))?;
+        if !response.is_success() {
+            return Err(error(&response, &format!("Storing {key}")));
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<StoredObject>> {
+        let response = self.request("GET", key, &[], None)?;
+        match response.status {
+            200 => Ok(Some(StoredObject {
+                content_type: response
+                    .header("Content-Type")
+                    .unwrap_or("application/octet-stream")
+                    .to_string(),
+                bytes: response.body,
+            })),
+            404 => Ok(None),
+            _ => Err(error(&response, &format!("Reading {key}"))),
+        }
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let response = self.request("DELETE", key, &[], None)?;
+        if !response.is_success() && response.status != 404 {
+            return Err(error(&response, &format!("Deleting {key}")));
+        }
+        Ok(())
+    }
+
+    fn presign(&self, key: &str, expires_in: Duration) -> String {
+        let now = chrono::Utc::now();
+        let date = now.format("%Y%m%d").to_string();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let path = self.path(key);
+        let credential = format!("{}/{}", self.options.access_key_id, self.scope(&date));
+        // Already in the sorted order the signature needs
+        let query = format!(
+            "X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Credential={}&X-Amz-Date={timestamp}&\
+             X-Amz-Expires={}&X-Amz-SignedHeaders=host",
+            uri_encode(&credential, false),
+            expires_in.min(MAX_EXPIRY).as_secs().max(1),
+        );
+        let canonical_request = format!(
+            "GET\n{path}\n{query}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD",
+            self.endpoint.authority
+        );
+        format!(
+            "{}{path}?{query}&X-Amz-Signature={}",
+            self.endpoint.url(),
+            self.signature(&date, &timestamp, &canonical_request)
+        )
+    }
+}
+
+impl StorageBackend for S3Backend {
+    fn put(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        content_type: &str,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        let inner = self.0.clone();
+        let key = key.to_string();
+        let content_type = content_type.to_string();
+        Box::pin(async move {
+            check_key(&key)?;
+            tokio::task::spawn_blocking(move || inner.put(&key, &bytes, &content_type)).await?
+        })
+    }
+
+    fn get(&self, key: &str) -> BoxFuture<'static, anyhow::Result<Option<StoredObject>>> {
+        let inner = self.0.clone();
+        let key = key.to_string();
+        Box::pin(async move {
+            check_key(&key)?;
+            tokio::task::spawn_blocking(move || inner.get(&key)).await?
+        })
+    }
+
+    fn delete(&self, key: &str) -> BoxFuture<'static, anyhow::Result<()>> {
+        let inner = self.0.clone();
+        let key = key.to_string();
+        Box::pin(async move {
+            check_key(&key)?;
+            tokio::task::spawn_blocking(move || inner.delete(&key)).await?
+        })
+    }
+
+    fn signed_url(&self, key: &str, expires_in: Duration) -> anyhow::Result<String> {
+        check_key(key)?;
+        Ok(self.0.presign(key, expires_in))
+    }
+}
@@ -0,0 +1,242 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use zeroize::Zeroizing;
+
+use crate::http::{Endpoint, HttpClient};
+
+/// Sent to ClamAV in chunks of this size
+const CHUNK_SIZE: usize = 64 * 1024;
+
+static SCANNERS: Mutex<Vec<(String, Arc<dyn Scanner>)>> = Mutex::new(vec![]);
+static SCANNER: OnceLock<(Arc<dyn Scanner>, bool)> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Clean,
+    /// Flagged, with the name of what was found
+    Infected(String),
+}
+
+/// Checks uploads for malware before they are stored.
+pub trait Scanner: Send + Sync + 'static {
+    fn scan(&self, bytes: &[u8]) -> BoxFuture<'static, anyhow::Result<Verdict>>;
+}
+
+/// The `[storage.scan]` section of `teach-config.toml`. Without it, uploads are not scanned.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScanOptions {
+    /// `clamav`, `http`, or the name of a scanner added by an integration
+    pub scanner: String,
+    /// Stores uploads that could not be scanned instead of refusing them
+    #[serde(default)]
+    pub fail_open: bool,
+    pub clamav: Option<ClamAvOptions>,
+    pub http: Option<HttpScannerOptions>,
+}
+
+/// The `[storage.scan.clamav]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClamAvOptions {
+    /// The path of clamd's socket, such as `/run/clamav/clamd.ctl`, or its `host:port`
+    pub address: String,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+/// The `[storage.scan.http]` section of `teach-config.toml`.
+///
+/// Uploads are posted as the body of the request, and the scanner answers with JSON such as
+/// `{"infected": true, "threat": "Eicar-Test-Signature"}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpScannerOptions {
+    pub url: String,
+    /// Sent as a bearer token, when set
+    pub token: Option<zeroize::Zeroizing<String>>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+/// Registers a scanner, chosen by setting `storage.scan.scanner` to `name`.
+pub fn add_scanner(name: impl Into<String>, scanner: impl Scanner) {
+    SCANNERS
+        .lock()
+        .unwrap()
+        .push((name.into(), Arc::new(scanner)));
+}
+
+/// The configured scanner, and whether uploads it fails on are stored anyway.
+pub(super) fn get() -> Option<(Arc<dyn Scanner>, bool)> {
+    SCANNER.get().cloned()
+}
+
+pub(super) fn init(options: ScanOptions) -> anyhow::Result<()> {
+    if let Some(clamav) = options.clamav {
+        add_scanner("clamav", ClamAv(Arc::new(clamav)));
+    }
+    if let Some(http) = options.http {
+        add_scanner("http", HttpScanner::new(http)?);
+    }
+    Ok(())
+}
+
+/// Picks the configured scanner once integrations have added theirs.
+pub(super) fn select(name: &str, fail_open: bool) -> anyhow::Result<()> {
+    let scanner = SCANNERS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(scanner, _)| scanner == name)
+        .map(|(_, scanner)| scanner.clone())
+        .ok_or_else(|| anyhow::anyhow!("There is no upload scanner named {name}"))?;
+    let _ = SCANNER.set((scanner, fail_open));
+    Ok(())
+}
+
+trait ReadWrite: Read + Write {}
+
+impl<T: Read + Write> ReadWrite for T {}
+
+struct ClamAv(Arc<ClamAvOptions>);
+
+impl ClamAvOptions {
+    fn connect(&self) -> anyhow::Result<Box<dyn ReadWrite>> {
+        let timeout = Some(Duration::from_secs(self.timeout_secs));
+        if self.address.starts_with('/') {
+            #[cfg(unix)]
+            {
+                let stream = UnixStream::connect(&self.address)
+                    .with_context(|| format!("Connecting to clamd at {}", self.address))?;
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)?;
+                return Ok(Box::new(stream));
+            }
+            #[cfg(not(unix))]
+            return Err(anyhow::anyhow!(
+                "clamd sockets are only supported on Unix, use host:port instead"
+            ));
+        }
+        let stream = TcpStream::connect(&self.address)
+            .with_context(|| format!("Connecting to clamd at {}", self.address))?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        Ok(Box::new(stream))
+    }
+
+    /// Streams the bytes with the INSTREAM command.
+    fn scan(&self, bytes: &[u8]) -> anyhow::Result<Verdict> {
+        let mut stream = self.connect()?;
+        stream.write_all(b"zINSTREAM\0")?;
+        for chunk in bytes.chunks(CHUNK_SIZE) {
+            stream.write_all(&(chunk.len() as u32).to_be_bytes())?;
+            stream.write_all(chunk)?;
+        }
+        stream.write_all(&0u32.to_be_bytes())?;
+        stream.flush()?;
+
+        // Replies to z-prefixed commands end with a NUL
+        let mut reply = vec![];
+        let mut chunk = [0; 256];
+        while !reply.ends_with(b"\0") {
+            let read = stream.read(&mut chunk)?;
+            if read == 0 {
+                break;
+            }
+            reply.extend_from_slice(&chunk[..read]);
+        }
+        let reply = String::from_utf8_lossy(&reply);
+        let reply = reply.trim_end_matches(['\0', '\n']);
+        // Such as "stream: OK" or "stream: Eicar-Test-Signature FOUND"
+        let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+        if result == "OK" {
+            Ok(Verdict::Clean)
+        } else if let Some(threat) = result.strip_suffix(" FOUND") {
+            Ok(Verdict::Infected(threat.to_string()))
+        } else {
+            Err(anyhow::anyhow!("clamd answered {reply:?}"))
+        }
+    }
+}
+
+impl Scanner for ClamAv {
+    fn scan(&self, bytes: &[u8]) -> BoxFuture<'static, anyhow::Result<Verdict>> {
+        let options = self.0.clone();
+        let bytes = bytes.to_vec();
+        Box::pin(async move { tokio::task::spawn_blocking(move || options.scan(&bytes)).await? })
+    }
+}
+
+#[derive(Deserialize)]
+struct HttpVerdict {
+    infected: bool,
+    threat: Option<String>,
+}
+
+struct HttpInner {
+    options: HttpScannerOptions,
+    client: HttpClient,
+}
+
+struct HttpScanner(Arc<HttpInner>);
+
+impl HttpScanner {
+    fn new(options: HttpScannerOptions) -> anyhow::Result<Self> {
+        Endpoint::parse(&options.url).context("Checking storage.scan.http.url")?;
+        Ok(Self(Arc::new(HttpInner {
+            client: HttpClient::new(Duration::from_secs(options.timeout_secs))?,
+            options,
+        })))
+    }
+}
+
+impl HttpInner {
+    fn scan(&self, bytes: &[u8]) -> anyhow::Result<Verdict> {
+        let authorization = self
+            .options
+            .token
+            .as_ref()
+            .map(|token| Zeroizing::new(format!("Bearer {}", &**token)));
+        let mut headers = vec![("Accept", "application/json")];
+        if let Some(authorization) = &authorization {
+            headers.push(("Authorization", authorization.as_str()));
+        }
+        let verdict: HttpVerdict = self
+            .client
+            .send(
+                "POST",
+                &self.options.url,
+                &headers,
+                Some(("application/octet-stream", bytes)),
+            )?
+            .json("The scanner")?;
+        if verdict.infected {
+            Ok(Verdict::Infected(
+                verdict.threat.unwrap_or_else(|| "unknown".to_string()),
+            ))
+        } else {
+            Ok(Verdict::Clean)
+        }
+    }
+}
+
+impl Scanner for HttpScanner {
+    fn scan(&self, bytes: &[u8]) -> BoxFuture<'static, anyhow::Result<Verdict>> {
+        let inner = self.0.clone();
+        let bytes = bytes.to_vec();
+        Box::pin(async move { tokio::task::spawn_blocking(move || inner.scan(&bytes)).await? })
+    }
+}
@@ -1,3 +1,4 @@
 pub mod admins;
 pub mod instructors;
+pub mod photos;
 pub mod students;
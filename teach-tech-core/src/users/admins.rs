@@ -1,23 +1,21 @@
 use anyhow::Context;
-use axum::http::StatusCode;
-use axum::{response::IntoResponse, routing::get, Json};
-use axum_extra::{
-    headers::{authorization::Bearer, Authorization},
-    TypedHeader,
+use axum::http::{HeaderMap, StatusCode};
+use axum::{
+    response::{IntoResponse, Response},
+    routing::get,
 };
 use notifications::Notification;
-use rand::distributions::{Alphanumeric, DistString};
-use rand::rngs::OsRng;
-use sea_orm::{entity::prelude::*, ActiveValue, TransactionTrait};
+use sea_orm::{entity::prelude::*, ActiveValue, DatabaseTransaction, TransactionTrait};
 use serde::Serialize;
 use tracing::error;
-use zeroize::Zeroizing;
 
-use crate::auth::user_auth::{self, new_from_password};
+use crate::auth::user_auth::{self, new_generated};
 use crate::{
-    auth::{token, UserID},
-    db::get_db,
-    users, TeachCore,
+    audit::{self, AuditEvent},
+    auth::{AdminUser, AuthUser, UserID},
+    conditional,
+    db::{get_db, get_read_db, insert_batched, SoftDeletable},
+    timestamped_active_model, users, TeachCore,
 };
 
 #[derive(Clone, Debug, DeriveEntityModel, Serialize)]
@@ -28,12 +26,19 @@ pub struct Model {
     #[sea_orm(unique)]
     pub username: String,
     pub created_at: DateTime,
+    pub updated_at: DateTime,
+    #[serde(skip_serializing)]
+    pub deleted_at: Option<DateTime>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {}
 
-impl ActiveModelBehavior for ActiveModel {}
+timestamped_active_model!();
+
+impl SoftDeletable for Entity {
+    const DELETED_AT: Column = Column::DeletedAt;
+}
 
 pub async fn create_admin(
     username: String,
@@ -42,64 +47,128 @@ pub async fn create_admin(
 ) -> anyhow::Result<()> {
     get_db()
         .transaction::<_, _, DbErr>(|txn| {
-            Box::pin(async move {
-                if let Some(_) = user_auth::Entity::find_by_id(user_id).one(get_db()).await? {
-                    users::admins::ActiveModel {
-                        user_id: ActiveValue::unchanged(user_id),
-                        username: ActiveValue::set(username.clone()),
-                        created_at: ActiveValue::not_set(),
-                    }
-                    .update(txn).await?;
-
-                    println!(
-                        "Created admin with user_id: {user_id}, username: {username}",
-                    );
-                } else {
-                    let mut password = Zeroizing::new(String::new());
-                    loop {
-                        password.clear();
-                        Alphanumeric.append_string(&mut OsRng, &mut password, 18);
-                        match new_from_password(user_id, &password)
-                            .await
-                            .expect("Hashing admin password")
-                            .insert(get_db())
-                            .await
-                        {
-                            Ok(_) => break,
-                            Err(DbErr::RecordNotInserted) => continue,
-                            Err(e) => return Err(e),
-                        }
-                    }
-                    users::admins::ActiveModel {
-                        user_id: ActiveValue::set(user_id),
-                        username: ActiveValue::set(username.clone()),
-                        created_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
-                    }
-                    .insert(txn).await?;
-
-                    println!(
-                        "Created admin with new user_id: {user_id}, username: {username}, password: {}",
-                        &*password
-                    );
-                }
+            Box::pin(upsert_admin(username, user_id, permissions, txn))
+        })
+        .await
+        .context("Creating admin")?;
+    permissions::invalidate_cache(user_id).await;
+    Ok(())
+}
+
+async fn upsert_admin(
+    username: String,
+    user_id: UserID,
+    permissions: Vec<permissions::Permission>,
+    txn: &DatabaseTransaction,
+) -> Result<(), DbErr> {
+    if user_auth::Entity::find_by_id(user_id)
+        .one(txn)
+        .await?
+        .is_some()
+    {
+        users::admins::ActiveModel {
+            user_id: ActiveValue::unchanged(user_id),
+            username: ActiveValue::set(username.clone()),
+            created_at: ActiveValue::not_set(),
+            updated_at: ActiveValue::not_set(),
+            deleted_at: ActiveValue::set(None),
+        }
+        .update(txn)
+        .await?;
+
+        println!("Created admin with user_id: {user_id}, username: {username}",);
+    } else {
+        let password = loop {
+            let (model, password) = new_generated(user_id)
+                .await
+                .map_err(|e| DbErr::Custom(format!("Hashing admin password: {e}")))?;
+            match model.insert(txn).await {
+                Ok(_) => break password,
+                Err(DbErr::RecordNotInserted) => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        users::admins::ActiveModel {
+            user_id: ActiveValue::set(user_id),
+            username: ActiveValue::set(username.clone()),
+            created_at: ActiveValue::not_set(),
+            updated_at: ActiveValue::not_set(),
+            deleted_at: ActiveValue::set(None),
+        }
+        .insert(txn)
+        .await?;
+
+        println!(
+            "Created admin with new user_id: {user_id}, username: {username}, password: {}",
+            &*password
+        );
+    }
+
+    permissions::Entity::delete_many()
+        .filter(permissions::Column::UserId.eq(user_id))
+        .exec(txn)
+        .await?;
+
+    let detail = format!("{permissions:?}");
+    insert_batched(
+        permissions
+            .into_iter()
+            .map(|permission| permissions::ActiveModel {
+                id: ActiveValue::not_set(),
+                user_id: ActiveValue::set(user_id),
+                permission: ActiveValue::set(permission),
+            }),
+        txn,
+    )
+    .await?;
+    audit::record(
+        txn,
+        AuditEvent::new(audit::PERMISSIONS_CHANGED, None)
+            .subject(user_id)
+            .detail(detail),
+    )
+    .await?;
 
-                permissions::Entity::delete_many().filter(permissions::Column::UserId.eq(user_id)).exec(txn).await?;
+    Ok(())
+}
 
-                for permission in permissions {
-                    permissions::ActiveModel {
-                        id: ActiveValue::not_set(),
-                        user_id: ActiveValue::set(user_id),
-                        permission: ActiveValue::set(permission),
-                    }
-                    .insert(txn)
+/// Marks the admin as deleted and takes away their permissions, on every sibling.
+pub async fn delete_admin(user_id: UserID) -> anyhow::Result<()> {
+    let deleted = get_db()
+        .transaction::<_, _, DbErr>(|txn| {
+            Box::pin(async move {
+                let deleted = Entity::update_many()
+                    .col_expr(
+                        Column::DeletedAt,
+                        Expr::value(chrono::Utc::now().naive_utc()),
+                    )
+                    .filter(Column::UserId.eq(user_id))
+                    .filter(Column::DeletedAt.is_null())
+                    .exec(txn)
                     .await?;
+                if deleted.rows_affected == 0 {
+                    return Ok(false);
                 }
-
-                Ok(())
+                permissions::Entity::delete_many()
+                    .filter(permissions::Column::UserId.eq(user_id))
+                    .exec(txn)
+                    .await?;
+                audit::record(
+                    txn,
+                    AuditEvent::new(audit::ADMIN_DELETED, None).subject(user_id),
+                )
+                .await?;
+                Ok(true)
             })
         })
         .await
-        .context("Creating admin")
+        .context("Deleting admin")?;
+    if !deleted {
+        return Err(anyhow::anyhow!("There is no admin with user_id {user_id}"));
+    }
+    permissions::invalidate_cache(user_id).await;
+    println!("Deleted admin with user_id: {user_id}");
+    Ok(())
 }
 
 #[derive(Debug, Serialize)]
@@ -112,45 +181,45 @@ pub struct AdminHome {
 pub fn add_to_core<S: Clone + Send + Sync + 'static>(mut core: TeachCore<S>) -> TeachCore<S> {
     core.add_db_reset_config(Entity);
     core.add_db_reset_config(notifications::Entity);
+    core.add_db_reset_config(notifications_archive::Entity);
     core.add_db_reset_config(permissions::Entity);
 
-    core.modify_router(|router| {
-        router.route("/admin/home", get(|TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>| async move {
-            let token = match token::Entity::find_by_id(bearer.token()).one(get_db()).await {
-                Ok(Some(t)) => t,
-                Ok(None) => return (StatusCode::UNAUTHORIZED, ()).into_response(),
-                Err(e) => {
-                    error!("Error validating bearer token: {e:#}");
-                    return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
-                }
-            };
-            let model = match Entity::find_by_id(token.user_id).one(get_db()).await {
-                Ok(Some(m)) => m,
-                Ok(None) => {
-                    return (StatusCode::FORBIDDEN, ()).into_response();
-                }
-                Err(e) => {
-                    error!("Error reading admin data: {e:#}");
-                    return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
-                }
-            };
-
-            let user_id = token.user_id;
-            if let Err(e) = token.update_last_used(get_db()).await {
-                error!("Error updating token last used time for {user_id}: {e:#}");
-            }
+    core.modify_router(|router| router.route("/admin/home", get(home)))
+}
 
-            let notifications: Vec<_> = match notifications::Entity::find_by_id(user_id).all(get_db()).await {
-                Ok(n) => n.into_iter().map(Notification::from).collect(),
-                Err(e) => {
-                    error!("Error reading admin notifications: {e:#}");
-                    return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
-                }
-            };
+async fn home(
+    headers: HeaderMap,
+    AdminUser {
+        user: AuthUser { user_id, .. },
+        admin: model,
+    }: AdminUser,
+) -> Response {
+    let mut last_modified = model.updated_at;
+    let notifications: Vec<_> = match notifications::Entity::find_by_id(user_id)
+        .all(get_read_db())
+        .await
+    {
+        Ok(n) => n
+            .into_iter()
+            .map(|n| {
+                last_modified = last_modified.max(n.created_at);
+                Notification::from(n)
+            })
+            .collect(),
+        Err(e) => {
+            error!("Error reading admin notifications: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
 
-            (StatusCode::OK, Json(AdminHome { model, notifications })).into_response()
-        }))
-    })
+    conditional::json(
+        &headers,
+        Some(last_modified),
+        &AdminHome {
+            model,
+            notifications,
+        },
+    )
 }
 
 pub mod notifications {
@@ -181,6 +250,46 @@ pub mod notifications {
         pub user_id: UserID,
         pub severity: String,
         pub message: String,
+        pub created_at: DateTime,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    /// Adds a notification for every admin that has not been deleted.
+    pub async fn notify_all(severity: &str, message: &str) -> Result<(), DbErr> {
+        let admins = super::Entity::find_live().all(get_db()).await?;
+        let now = chrono::Utc::now().naive_utc();
+        insert_batched(
+            admins.into_iter().map(|admin| ActiveModel {
+                id: ActiveValue::not_set(),
+                user_id: ActiveValue::set(admin.user_id),
+                severity: ActiveValue::set(severity.to_string()),
+                message: ActiveValue::set(message.to_string()),
+                created_at: ActiveValue::set(now),
+            }),
+            get_db(),
+        )
+        .await
+    }
+}
+
+/// Notifications moved out of `admin_notifications` by the retention policy.
+pub mod notifications_archive {
+    use super::*;
+
+    #[derive(Clone, Debug, DeriveEntityModel)]
+    #[sea_orm(table_name = "admin_notifications_archive")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub id: i32,
+        pub user_id: UserID,
+        pub severity: String,
+        pub message: String,
+        pub created_at: DateTime,
+        pub archived_at: DateTime,
     }
 
     #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -190,9 +299,15 @@ pub mod notifications {
 }
 
 pub mod permissions {
+    use std::time::Duration;
+
     use sea_orm::entity::prelude::*;
+    use serde::{Deserialize, Serialize};
+    use tracing::error;
+
+    use crate::{auth::UserID, cache, db::get_db, i18n};
 
-    use crate::auth::UserID;
+    const CACHE_TTL: Duration = Duration::from_secs(60);
 
     #[derive(Clone, Debug, DeriveEntityModel)]
     #[sea_orm(table_name = "admin_permissions")]
@@ -208,7 +323,18 @@ pub mod permissions {
 
     impl ActiveModelBehavior for ActiveModel {}
 
-    #[derive(EnumIter, DeriveActiveEnum, Clone, Debug, Copy, PartialEq, Eq, clap::ValueEnum)]
+    #[derive(
+        EnumIter,
+        DeriveActiveEnum,
+        Clone,
+        Debug,
+        Copy,
+        PartialEq,
+        Eq,
+        clap::ValueEnum,
+        Serialize,
+        Deserialize,
+    )]
     #[sea_orm(rs_type = "i32", db_type = "Integer")]
     pub enum Permission {
         CreateStudent = 0,
@@ -220,5 +346,68 @@ pub mod permissions {
         AssignInstructor = 6,
         CreateAdmin = 7,
         DeleteAdmin = 8,
+        ManageMaintenance = 9,
+        ManageQuotas = 10,
+        ModerateReports = 11,
+        LinkLogins = 12,
+    }
+
+    impl Permission {
+        /// The message that users without this permission are refused with.
+        pub fn refusal(self) -> String {
+            let key = match self {
+                Self::CreateStudent => "error.must_create_students",
+                Self::CreateInstructor => "error.must_create_instructors",
+                Self::ManageMaintenance => "error.must_manage_maintenance",
+                Self::ManageQuotas => "error.must_manage_quotas",
+                Self::ModerateReports => "error.must_moderate_reports",
+                Self::LinkLogins => "error.must_link_logins",
+                _ => {
+                    let name = clap::ValueEnum::to_possible_value(&self)
+                        .map(|value| value.get_name().to_string())
+                        .unwrap_or_default();
+                    return i18n::t_with("error.missing_permission", &[("permission", &name)]);
+                }
+            };
+            i18n::t(key)
+        }
+    }
+
+    fn cache_key(user_id: UserID) -> String {
+        format!("admin_permissions:{user_id}")
+    }
+
+    /// Every permission the admin has, cached for a minute or until [`invalidate_cache`] is
+    /// called for them.
+    pub async fn permissions_of(user_id: UserID) -> Result<Vec<Permission>, DbErr> {
+        let key = cache_key(user_id);
+        match cache::get_json(&key).await {
+            Ok(Some(permissions)) => return Ok(permissions),
+            Ok(None) => {}
+            Err(e) => error!("Error reading cached permissions for {user_id}: {e:#}"),
+        }
+        let permissions: Vec<_> = Entity::find()
+            .filter(Column::UserId.eq(user_id))
+            .all(get_db())
+            .await?
+            .into_iter()
+            .map(|model| model.permission)
+            .collect();
+        if let Err(e) = cache::set_json(&key, &permissions, Some(CACHE_TTL)).await {
+            error!("Error caching permissions for {user_id}: {e:#}");
+        }
+        Ok(permissions)
+    }
+
+    pub async fn has_permission(user_id: UserID, permission: Permission) -> Result<bool, DbErr> {
+        Ok(permissions_of(user_id).await?.contains(&permission))
+    }
+
+    /// Must be called after changing the permissions of an admin, so that every sibling stops
+    /// using the cached ones.
+    pub async fn invalidate_cache(user_id: UserID) {
+        if let Err(e) = cache::get_cache().invalidate(&cache_key(user_id)).await {
+            error!("Error invalidating cached permissions for {user_id}: {e:#}");
+        }
     }
 }
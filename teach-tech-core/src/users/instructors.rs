@@ -1,25 +1,27 @@
 use axum::{
-    extract::Json,
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Json, Query},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, post},
 };
-use axum_extra::{
-    headers::{authorization::Bearer, Authorization},
-    TypedHeader,
-};
-use sea_orm::{entity::prelude::*, ActiveValue, TransactionTrait};
+use sea_orm::{entity::prelude::*, ActiveValue};
 use serde::{Deserialize, Serialize};
 use tracing::error;
 use zeroize::Zeroizing;
 
 use crate::{
-    auth::{token, user_auth, UserID},
-    db::get_db,
-    TeachCore,
+    audit::{self, AuditEvent},
+    auth::{guard::RequirePermission, user_auth, AdminUser, AuthUser, InstructorUser, UserID},
+    conditional,
+    db::{
+        get_db, get_read_db, insert_batched, stream_export, transaction_with_retry, ExportQuery,
+        SoftDeletable, Timestamped,
+    },
+    encryption::Encrypted,
+    i18n, mail, timestamped_active_model, TeachCore,
 };
 
-use super::admins;
+use super::admins::permissions::Permission;
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
 #[sea_orm(table_name = "instructors")]
@@ -28,22 +30,36 @@ pub struct Model {
     pub user_id: UserID,
     pub name: String,
     pub pronouns: String,
-    pub birthdate: DateTime,
+    pub birthdate: Encrypted<DateTime, Entity>,
     pub created_at: DateTime,
+    pub updated_at: DateTime,
     #[serde(skip_serializing)]
     pub created_by: UserID,
+    #[serde(skip_serializing)]
+    pub deleted_at: Option<DateTime>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {}
 
-impl ActiveModelBehavior for ActiveModel {}
+timestamped_active_model!();
 
-#[derive(Debug, Deserialize)]
+impl SoftDeletable for Entity {
+    const DELETED_AT: Column = Column::DeletedAt;
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct CreateInstructor {
     pub name: String,
     pub birthdate: chrono::DateTime<chrono::Utc>,
     pub pronouns: String,
+    /// Where the credentials are mailed, when mail is configured. The instructor can also log in with
+    /// it, so no two users can share one
+    #[serde(default)]
+    pub email: Option<String>,
+    /// A name the instructor can log in with instead of their ID
+    #[serde(default)]
+    pub username: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -70,95 +86,154 @@ pub struct InstructorHome {
 
 pub fn add_to_core<S: Clone + Send + Sync + 'static>(mut core: TeachCore<S>) -> TeachCore<S> {
     core.add_db_reset_config(Entity);
+    core.add_encrypted_column(Entity, Column::Birthdate);
     core.add_db_reset_config(permissions::Entity);
 
     core.modify_router(|router| {
-        router.route("/instructor/home", get(|TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>| async move {
-            let token = match token::Entity::find_by_id(bearer.token()).one(get_db()).await {
-                Ok(Some(t)) => t,
-                Ok(None) => return (StatusCode::UNAUTHORIZED, ()).into_response(),
-                Err(e) => {
-                    error!("Error validating bearer token: {e:#}");
-                    return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
-                }
-            };
-            let model = match Entity::find_by_id(token.user_id).one(get_db()).await {
-                Ok(Some(m)) => m,
-                Ok(None) => {
-                    return (StatusCode::FORBIDDEN, ()).into_response();
-                }
-                Err(e) => {
-                    error!("Error reading instructor data: {e:#}");
-                    return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
-                }
-            };
+        router
+            .route("/instructor/home", get(home))
+            .route(
+                "/instructor/create",
+                post(create).layer(RequirePermission(Permission::CreateInstructor)),
+            )
+            .route("/instructor/export", get(export))
+    })
+}
 
-            let user_id = token.user_id;
-            if let Err(e) = token.update_last_used(get_db()).await {
-                error!("Error updating token last used time for {user_id}: {e:#}");
-            }
+async fn home(
+    headers: HeaderMap,
+    InstructorUser {
+        instructor: model, ..
+    }: InstructorUser,
+) -> Response {
+    let last_modified = model.updated_at;
+    conditional::json(&headers, Some(last_modified), &InstructorHome { model })
+}
 
-            (StatusCode::OK, Json(InstructorHome { model })).into_response()
-        }))
-        .route("/instructor/create", post(|TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>, Json(CreateInstructors { instructors }): Json<CreateInstructors>| async move {
-            let token = match token::Entity::find_by_id(bearer.token()).one(get_db()).await {
-                Ok(Some(t)) => t,
-                Ok(None) => return (StatusCode::UNAUTHORIZED, ()).into_response(),
-                Err(e) => {
-                    error!("Error validating bearer token: {e:#}");
-                    return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
-                }
-            };
+async fn create(
+    AuthUser { user_id, .. }: AuthUser,
+    Json(CreateInstructors { instructors }): Json<CreateInstructors>,
+) -> Response {
+    if instructors.iter().any(|instructor| {
+        instructor
+            .email
+            .as_deref()
+            .is_some_and(|email| !mail::is_valid_address(email))
+    }) {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_email")).into_response();
+    }
+    let Some(names) = instructors
+        .iter()
+        .map(|instructor| {
+            user_auth::LoginNames::new(instructor.username.as_deref(), instructor.email.as_deref())
+        })
+        .collect::<Option<Vec<_>>>()
+    else {
+        return (StatusCode::BAD_REQUEST, i18n::t("error.invalid_username")).into_response();
+    };
+    match user_auth::find_taken(&names, get_db()).await {
+        Ok(None) => {}
+        Ok(Some(name)) => {
+            return (
+                StatusCode::CONFLICT,
+                i18n::t_with("error.login_taken", &[("name", &name)]),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            error!("Error checking login names: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    }
 
-            match admins::permissions::Entity::find().filter(admins::permissions::Column::UserId.eq(token.user_id)).filter(admins::permissions::Column::Permission.eq(admins::permissions::Permission::CreateInstructor)).one(get_db()).await {
-                Ok(Some(_)) => {}
-                Ok(None) => {
-                    return (StatusCode::FORBIDDEN, "Must be an administrator that can create instructors").into_response();
-                }
-                Err(e) => {
-                    error!("Error reading admin data: {e:#}");
-                    return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
-                }
-            }
+    // Hashed once up front, so that retrying the transaction does not hash them again
+    let passwords = user_auth::rand_passwords(instructors.len()).await;
+    let result = transaction_with_retry(get_db(), |txn| {
+        let instructors = instructors.clone();
+        let passwords = passwords.clone();
+        let names = names.clone();
+        Box::pin(async move {
+            let auths = user_auth::new_rand_many(passwords, names, txn).await?;
+            let mut created_instructors = vec![];
+            let mut models = vec![];
+            for (instructor, (instructor_auth, password)) in instructors.into_iter().zip(auths) {
+                models.push(
+                    ActiveModel {
+                        user_id: ActiveValue::Set(instructor_auth.user_id),
+                        name: ActiveValue::Set(instructor.name),
+                        pronouns: ActiveValue::Set(instructor.pronouns),
+                        birthdate: ActiveValue::Set(Encrypted::new(
+                            instructor.birthdate.naive_utc(),
+                            Column::Birthdate,
+                            instructor_auth.user_id,
+                        )),
+                        created_at: ActiveValue::NotSet,
+                        updated_at: ActiveValue::NotSet,
+                        created_by: ActiveValue::Set(user_id),
+                        deleted_at: ActiveValue::Set(None),
+                    }
+                    .touch(true),
+                );
 
-            let user_id = token.user_id;
-            if let Err(e) = token.update_last_used(get_db()).await {
-                error!("Error updating token last used time for {user_id}: {e:#}");
+                created_instructors.push(CreatedInstructor {
+                    user_id: instructor_auth.user_id,
+                    password,
+                });
             }
-
-            let result = get_db().transaction::<_, _, DbErr>(|txn| {
-                Box::pin(async move {
-                    let mut created_instructors = vec![];
-                    let created_at = chrono::Utc::now().naive_utc();
-                    for instructor in instructors {
-                        let (instructor_auth, password) = user_auth::new_rand(txn).await?;
-
-                        ActiveModel {
-                            user_id: ActiveValue::Set(instructor_auth.user_id),
-                            name: ActiveValue::Set(instructor.name),
-                            pronouns: ActiveValue::Set(instructor.pronouns),
-                            birthdate: ActiveValue::Set(instructor.birthdate.naive_utc()),
-                            created_at: ActiveValue::Set(created_at),
-                            created_by: ActiveValue::Set(user_id),
-                        }.insert(txn).await?;
-
-                        created_instructors.push(CreatedInstructor { user_id: instructor_auth.user_id, password });
-                    }
-                    Ok(created_instructors)
+            insert_batched(models, txn).await?;
+            let events: Vec<_> = created_instructors
+                .iter()
+                .map(|created| {
+                    AuditEvent::new(audit::INSTRUCTOR_CREATED, Some(user_id))
+                        .subject(created.user_id)
                 })
-            }).await;
+                .collect();
+            audit::record_many(txn, events).await?;
+            Ok(created_instructors)
+        })
+    })
+    .await;
 
-            match result {
-                Ok(instructors) => {
-                    (StatusCode::OK, Json(CreatedInstructors { instructors })).into_response()
+    match result {
+        Ok(created) => {
+            for (instructor, created) in instructors.iter().zip(&created) {
+                let Some(email) = &instructor.email else {
+                    continue;
+                };
+                let result = async {
+                    mail::set_address(created.user_id, email, true).await?;
+                    mail::queue_credentials(created.user_id, &instructor.name, &created.password)
+                        .await
                 }
-                Err(e) => {
-                    error!("Error creating instructors: {e:#}");
-                    (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+                .await;
+                if let Err(e) = result {
+                    error!("Error mailing credentials to {}: {e:#}", created.user_id);
                 }
             }
-        }))
-    })
+            (
+                StatusCode::OK,
+                Json(CreatedInstructors {
+                    instructors: created,
+                }),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            error!("Error creating instructors: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+async fn export(_: AdminUser, Query(export): Query<ExportQuery>) -> Response {
+    stream_export(
+        "instructors",
+        Entity::find_live(),
+        Column::UserId,
+        |model| model.user_id,
+        export.format,
+        get_read_db(),
+    )
 }
 
 pub mod permissions {
@@ -0,0 +1,170 @@
+use std::time::Duration;
+
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Path},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, put},
+};
+use rand::{thread_rng, Rng};
+use sea_orm::{entity::prelude::*, sea_query::OnConflict, ActiveValue};
+use tracing::error;
+
+use crate::{
+    auth::{AuthUser, UserID},
+    db::{get_db, get_read_db},
+    i18n,
+    storage::{get_storage, put_upload},
+    TeachCore,
+};
+
+const MAX_SIZE: usize = 5 * 1024 * 1024;
+/// Long enough for clients to cache the photo for a while
+const LINK_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "profile_photos")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub user_id: UserID,
+    /// Changes with every upload, so that old links stop showing the new photo
+    pub key: String,
+    pub updated_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// The type of an image from its first bytes, so that nothing but images is stored as a photo.
+fn image_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+fn storage_unavailable() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        i18n::t("error.storage_unavailable"),
+    )
+        .into_response()
+}
+
+async fn set_photo(AuthUser { user_id, .. }: AuthUser, body: Bytes) -> Response {
+    let Some(storage) = get_storage() else {
+        return storage_unavailable();
+    };
+    let Some(content_type) = image_type(&body) else {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            i18n::t("error.photo_type"),
+        )
+            .into_response();
+    };
+    let previous = match Entity::find_by_id(user_id).one(get_db()).await {
+        Ok(previous) => previous,
+        Err(e) => {
+            error!("Error reading profile photo of {user_id}: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+    let key = format!(
+        "profile-photos/{user_id}/{:032x}",
+        thread_rng().gen::<u128>()
+    );
+    if let Err(e) = put_upload(&key, body.to_vec(), content_type, user_id).await {
+        return e.into_response();
+    }
+    let result = Entity::insert(ActiveModel {
+        user_id: ActiveValue::set(user_id),
+        key: ActiveValue::set(key.clone()),
+        updated_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+    })
+    .on_conflict(
+        OnConflict::column(Column::UserId)
+            .update_columns([Column::Key, Column::UpdatedAt])
+            .to_owned(),
+    )
+    .exec_without_returning(get_db())
+    .await;
+    if let Err(e) = result {
+        error!("Error saving profile photo of {user_id}: {e:#}");
+        let _ = storage.delete(&key).await;
+        return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+    }
+    if let Some(previous) = previous {
+        if let Err(e) = storage.delete(&previous.key).await {
+            error!("Error deleting old profile photo of {user_id}: {e:#}");
+        }
+    }
+    (StatusCode::OK, ()).into_response()
+}
+
+async fn delete_photo(AuthUser { user_id, .. }: AuthUser) -> Response {
+    let Some(storage) = get_storage() else {
+        return storage_unavailable();
+    };
+    let photo = match Entity::find_by_id(user_id).one(get_db()).await {
+        Ok(Some(photo)) => photo,
+        Ok(None) => return (StatusCode::OK, ()).into_response(),
+        Err(e) => {
+            error!("Error reading profile photo of {user_id}: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+    if let Err(e) = Entity::delete_by_id(user_id).exec(get_db()).await {
+        error!("Error deleting profile photo of {user_id}: {e:#}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+    }
+    if let Err(e) = storage.delete(&photo.key).await {
+        error!("Error deleting profile photo of {user_id}: {e:#}");
+    }
+    (StatusCode::OK, ()).into_response()
+}
+
+/// Redirects any signed in user to a download link for the photo.
+async fn get_photo(_: AuthUser, Path(user_id): Path<UserID>) -> Response {
+    let Some(storage) = get_storage() else {
+        return storage_unavailable();
+    };
+    let photo = match Entity::find_by_id(user_id).one(get_read_db()).await {
+        Ok(Some(photo)) => photo,
+        Ok(None) => return (StatusCode::NOT_FOUND, ()).into_response(),
+        Err(e) => {
+            error!("Error reading profile photo of {user_id}: {e:#}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response();
+        }
+    };
+    match storage.signed_url(&photo.key, LINK_LIFETIME) {
+        Ok(url) => Redirect::temporary(&url).into_response(),
+        Err(e) => {
+            error!("Error signing profile photo link of {user_id}: {e:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+        }
+    }
+}
+
+pub fn add_to_core<S: Clone + Send + Sync + 'static>(mut core: TeachCore<S>) -> TeachCore<S> {
+    core.add_db_reset_config(Entity);
+    core.modify_router(|router| {
+        router
+            .route(
+                "/users/photo",
+                put(set_photo)
+                    .delete(delete_photo)
+                    .layer(DefaultBodyLimit::max(MAX_SIZE)),
+            )
+            .route("/users/:user_id/photo", get(get_photo))
+    })
+}
@@ -1,6 +1,7 @@
 use std::{
+    collections::BTreeMap,
     io::{BufWriter, Write},
-    path::Path,
+    path::{Path, PathBuf},
     process::ExitCode,
 };
 
@@ -8,7 +9,18 @@ use anyhow::Context;
 use fxhash::FxHashMap;
 use serde::{Deserialize, Serialize};
 use toml::from_str;
-use tracing::{span, Level};
+use tracing::{info, span, warn, Level};
+
+use crate::{
+    check, config_example,
+    deploy::DeployConfig,
+    generated_files::GeneratedFiles,
+    lockfile, metadata,
+    profile::ReleaseConfig,
+    registry::{self, RegistryConfig, RegistryCrate},
+    target::{self, TargetConfig},
+    wiring,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BuildConfig {
@@ -16,12 +28,291 @@ pub struct BuildConfig {
     #[serde(alias = "executable-name")]
     pub executable_name: String,
     #[serde(default)]
-    pub integrations: FxHashMap<String, String>,
+    pub integrations: FxHashMap<String, Integration>,
     #[serde(default = "default_version")]
     pub version: semver::Version,
     #[serde(default = "default_teach_tech_core")]
     #[serde(alias = "teach-tech-core")]
     pub teach_tech_core: String,
+    /// When not empty, each executable is generated into a cargo workspace named `executable_name`
+    #[serde(default)]
+    pub executables: BTreeMap<String, ExecutableConfig>,
+    /// Where `registry:` integrations are looked up, instead of crates.io
+    pub registry: Option<RegistryConfig>,
+    #[serde(default)]
+    pub deploy: DeployConfig,
+    /// Settings for each target triple that can be given to `--target`
+    #[serde(default)]
+    pub targets: BTreeMap<String, TargetConfig>,
+    #[serde(default)]
+    pub release: ReleaseConfig,
+    /// Overrides for crates.io dependencies anywhere in the dependency tree, given as a path or git
+    /// repository like an integration
+    #[serde(default)]
+    pub patches: BTreeMap<String, Integration>,
+}
+
+/// An `[executables.<name>]` section of `build-config.toml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecutableConfig {
+    /// Added to the integrations every executable shares
+    #[serde(default)]
+    pub integrations: FxHashMap<String, Integration>,
+    pub version: Option<semver::Version>,
+}
+
+/// How an integration is fetched, as written under `[integrations]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Integration {
+    /// A version, a path, a git URL, or `registry:<crate>@<requirement>`
+    Short(String),
+    Detailed(DetailedIntegration),
+}
+
+/// An integration written as a table, such as `{ version = "1.0", features = ["s3"] }`.
+///
+/// Exactly one of `version`, `path` and `git` must be set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DetailedIntegration {
+    /// A version requirement
+    pub version: Option<String>,
+    pub path: Option<String>,
+    pub git: Option<String>,
+    pub branch: Option<String>,
+    pub tag: Option<String>,
+    pub rev: Option<String>,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default = "default_true")]
+    #[serde(alias = "default-features")]
+    pub default_features: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Integration {
+    /// The folder of an integration given as a path.
+    pub fn local_path(&self) -> Option<&Path> {
+        match self {
+            Self::Short(metadata) => {
+                if metadata.parse::<semver::Version>().is_ok()
+                    || metadata.starts_with("http")
+                    || metadata.starts_with("registry:")
+                {
+                    return None;
+                }
+                Some(Path::new(metadata))
+            }
+            Self::Detailed(detailed) => detailed.path.as_deref().map(Path::new),
+        }
+    }
+
+    /// The part of a `registry:` integration after the prefix.
+    pub fn registry_spec(&self) -> Option<&str> {
+        match self {
+            Self::Short(metadata) => metadata.strip_prefix("registry:"),
+            Self::Detailed(_) => None,
+        }
+    }
+}
+
+/// Checks that an integration's path holds a crate.
+pub(crate) fn check_integration_path(metadata: &str) -> anyhow::Result<()> {
+    let metadata_path = Path::new(&metadata);
+    if !metadata_path.exists() {
+        return Err(anyhow::anyhow!("Path {metadata} does not exist"));
+    }
+    if !metadata_path.is_dir() {
+        return Err(anyhow::anyhow!("Path {metadata} is not a folder"));
+    }
+    if metadata_path.join("Cargo.toml").exists() {
+        if !metadata_path.join("Cargo.toml").is_file() {
+            return Err(anyhow::anyhow!("Path {metadata}/Cargo.toml is not a file"));
+        }
+    } else {
+        return Err(anyhow::anyhow!("Path {metadata}/Cargo.toml does not exist"));
+    }
+    if metadata_path.join("src").exists() {
+        if !metadata_path.join("src").is_dir() {
+            return Err(anyhow::anyhow!("Path {metadata}/src is not a folder"));
+        }
+    } else {
+        return Err(anyhow::anyhow!("Path {metadata}/src does not exist"));
+    }
+    Ok(())
+}
+
+impl DetailedIntegration {
+    /// Checks that the fields set make sense together, without looking at the file system.
+    pub fn validate(&self, name: &str) -> anyhow::Result<()> {
+        match (&self.version, &self.path, &self.git) {
+            (Some(version), None, None) => {
+                version
+                    .parse::<semver::VersionReq>()
+                    .with_context(|| format!("Parsing the version of {name}"))?;
+            }
+            (None, Some(_), None) | (None, None, Some(_)) => {}
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "{name} must set exactly one of version, path and git"
+                ))
+            }
+        }
+        let pins = [&self.branch, &self.tag, &self.rev]
+            .into_iter()
+            .filter(|pin| pin.is_some())
+            .count();
+        if pins > 0 && self.git.is_none() {
+            return Err(anyhow::anyhow!(
+                "{name} can only set branch, tag or rev alongside git"
+            ));
+        }
+        if pins > 1 {
+            return Err(anyhow::anyhow!(
+                "{name} may only set one of branch, tag and rev"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The inside of an inline table describing a detailed integration in `Cargo.toml`.
+fn detailed_dependency(
+    name: &str,
+    detailed: &DetailedIntegration,
+    up: &str,
+) -> anyhow::Result<String> {
+    detailed.validate(name)?;
+    let DetailedIntegration {
+        version,
+        path,
+        git,
+        branch,
+        tag,
+        rev,
+        features,
+        default_features,
+    } = detailed;
+    let mut fields = vec![];
+    if let Some(version) = version {
+        fields.push(format!("version = \"{version}\""));
+    } else if let Some(path) = path {
+        check_integration_path(path)?;
+        if Path::new(path).is_absolute() {
+            fields.push(format!("path = \"{path}\""));
+        } else {
+            fields.push(format!("path = \"{up}{path}\""));
+        }
+    } else if let Some(git) = git {
+        fields.push(format!("git = \"{git}\""));
+    }
+    for (key, value) in [("branch", branch), ("tag", tag), ("rev", rev)] {
+        if let Some(value) = value {
+            fields.push(format!("{key} = \"{value}\""));
+        }
+    }
+
+    if !features.is_empty() {
+        let features: Vec<_> = features
+            .iter()
+            .map(|feature| format!("\"{feature}\""))
+            .collect();
+        fields.push(format!("features = [{}]", features.join(", ")));
+    }
+    if !default_features {
+        fields.push("default-features = false".to_string());
+    }
+    Ok(fields.join(", "))
+}
+
+/// The inside of an inline table describing a `[patches]` entry in `Cargo.toml`.
+pub(crate) fn patch_dependency(
+    name: &str,
+    patch: &Integration,
+    up: &str,
+) -> anyhow::Result<String> {
+    match patch {
+        Integration::Short(metadata) => {
+            if metadata.parse::<semver::Version>().is_ok() || metadata.starts_with("registry:") {
+                return Err(anyhow::anyhow!(
+                    "The patch for {name} must be a path or a git repository"
+                ));
+            }
+            if metadata.starts_with("http") {
+                return Ok(format!("git = \"{metadata}\""));
+            }
+            check_integration_path(metadata)?;
+            if Path::new(metadata).is_absolute() {
+                Ok(format!("path = \"{metadata}\""))
+            } else {
+                Ok(format!("path = \"{up}{metadata}\""))
+            }
+        }
+        Integration::Detailed(detailed) => {
+            if detailed.version.is_some() {
+                return Err(anyhow::anyhow!(
+                    "The patch for {name} must set path or git instead of version"
+                ));
+            }
+            detailed_dependency(name, detailed, up)
+        }
+    }
+}
+
+impl BuildConfig {
+    /// The sections that cargo only reads from the `Cargo.toml` it is run from.
+    fn root_sections(&self) -> anyhow::Result<String> {
+        let mut sections = self.release.cargo_profile();
+        if !self.patches.is_empty() {
+            sections.push_str("\n[patch.crates-io]\n");
+            for (name, patch) in &self.patches {
+                // The root is always one folder below build-config.toml
+                sections.push_str(&format!(
+                    "{name} = {{ {} }}\n",
+                    patch_dependency(name, patch, "../")?
+                ));
+            }
+        }
+        Ok(sections)
+    }
+
+    /// The folder the executables are generated into.
+    pub fn root(&self) -> PathBuf {
+        PathBuf::from(&self.executable_name)
+    }
+
+    /// The executables described by this config, which is one unless `[executables]` is used.
+    pub fn executables(&self) -> Vec<Executable> {
+        let root = self.root();
+        if self.executables.is_empty() {
+            return vec![Executable {
+                name: self.executable_name.clone(),
+                version: self.version.clone(),
+                path: root,
+                integrations: self.integrations.clone(),
+            }];
+        }
+        self.executables
+            .iter()
+            .map(|(name, exe_config)| {
+                let mut integrations = self.integrations.clone();
+                integrations.extend(exe_config.integrations.clone());
+                Executable {
+                    name: name.clone(),
+                    version: exe_config
+                        .version
+                        .clone()
+                        .unwrap_or_else(|| self.version.clone()),
+                    path: root.join(name),
+                    integrations,
+                }
+            })
+            .collect()
+    }
 }
 
 fn default_executable_name() -> String {
@@ -36,53 +327,110 @@ fn default_teach_tech_core() -> String {
     "0.1.0".to_string()
 }
 
-pub fn build_at_path(path: &Path) -> anyhow::Result<ExitCode> {
-    let BuildConfig {
-        executable_name,
-        integrations,
-        version,
-        teach_tech_core,
-    } = from_str(
+/// An executable crate generated from `build-config.toml`.
+#[derive(Debug, Clone)]
+pub struct Executable {
+    pub name: String,
+    pub version: semver::Version,
+    /// The crate's folder, which is where `teach-config.toml` is read from when it is run
+    pub path: PathBuf,
+    pub integrations: FxHashMap<String, Integration>,
+}
+
+/// Everything generated from `build-config.toml`.
+#[derive(Debug, Clone)]
+pub struct Generated {
+    pub config: BuildConfig,
+    /// The folder holding the `Cargo.toml` and `Cargo.lock` cargo should be run from
+    pub root: PathBuf,
+    pub executables: Vec<Executable>,
+    /// What teach-tech created, for `teach-tech clean`
+    pub files: GeneratedFiles,
+}
+
+/// The executables matching `name`, or all of them if no name was given.
+pub fn select<'a>(
+    executables: &'a [Executable],
+    name: Option<&str>,
+) -> anyhow::Result<Vec<&'a Executable>> {
+    let Some(name) = name else {
+        return Ok(executables.iter().collect());
+    };
+    let Some(executable) = executables.iter().find(|exe| exe.name == name) else {
+        return Err(anyhow::anyhow!(
+            "{name} is not an executable in build-config.toml"
+        ));
+    };
+    Ok(vec![executable])
+}
+
+impl Generated {
+    /// The executables matching `name`, or all of them if no name was given.
+    pub fn select(&self, name: Option<&str>) -> anyhow::Result<Vec<&Executable>> {
+        select(&self.executables, name)
+    }
+
+    /// The single executable matching `name`, for commands that can only handle one at a time.
+    pub fn select_one(&self, name: Option<&str>) -> anyhow::Result<&Executable> {
+        let mut selected = self.select(name)?;
+        if selected.len() > 1 {
+            return Err(anyhow::anyhow!(
+                "build-config.toml defines several executables; choose one with --executable"
+            ));
+        }
+        Ok(selected.remove(0))
+    }
+}
+
+pub fn read_build_config(path: &Path) -> anyhow::Result<BuildConfig> {
+    from_str(
         &std::fs::read_to_string(path.join("build-config.toml"))
             .context("Reading build-config.toml")?,
     )
-    .context("Parsing build-config.toml")?;
-    let mut span = span!(Level::INFO, "Setting up {executable_name}");
-    let mut _enter = span.enter();
-    let executable_path = Path::new(&executable_name);
+    .context("Parsing build-config.toml")
+}
 
-    if executable_path.exists() {
-        if executable_path.is_file() {
-            return Err(anyhow::anyhow!(
-                "{executable_name} already exists and is a file"
-            ));
+/// Creates `path` and its `src` folder if they do not exist yet.
+fn create_crate_dirs(path: &Path, files: &mut GeneratedFiles) -> anyhow::Result<()> {
+    let display = path.display();
+    if path.exists() {
+        if path.is_file() {
+            return Err(anyhow::anyhow!("{display} already exists and is a file"));
         }
-        if executable_path.join("src").exists() {
-            if executable_path.join("src").is_file() {
+        if path.join("src").exists() {
+            if path.join("src").is_file() {
                 return Err(anyhow::anyhow!(
-                    "{executable_name}/src already exists and is a file"
+                    "{display}/src already exists and is a file"
                 ));
             }
         } else {
-            std::fs::create_dir(executable_path.join("src"))
-                .with_context(|| format!("Creating {executable_name}/src folder"))?;
+            files.create_dir_all(&path.join("src"))?;
         }
     } else {
-        std::fs::create_dir(executable_path)
-            .with_context(|| format!("Creating {executable_name} folder"))?;
-        std::fs::create_dir(executable_path.join("src"))
-            .with_context(|| format!("Creating {executable_name}/src folder"))?;
+        files.create_dir_all(&path.join("src"))?;
     }
+    Ok(())
+}
 
-    std::fs::write(executable_path.join(".gitignore"), "/target")
-        .with_context(|| format!("Creating {executable_name}/.gitignore"))?;
-    let file = std::fs::File::create(executable_path.join("Cargo.toml"))
-        .with_context(|| format!("Creating {executable_name}/Cargo.toml"))?;
+/// Writes an executable's `Cargo.toml`.
+///
+/// `up` leads from the crate's folder back to the folder relative paths in `build-config.toml`
+/// are written against.
+fn write_cargo_toml(
+    executable: &Executable,
+    config: &BuildConfig,
+    registry_crates: &FxHashMap<String, RegistryCrate>,
+    up: &str,
+) -> anyhow::Result<()> {
+    let teach_tech_core = &config.teach_tech_core;
+    let display = executable.path.display();
+    let file = std::fs::File::create(executable.path.join("Cargo.toml"))
+        .with_context(|| format!("Creating {display}/Cargo.toml"))?;
     let mut file = BufWriter::new(file);
-    let write_result: std::io::Result<()> = try {
+    let write_result: anyhow::Result<()> = try {
         writeln!(file, "[package]")?;
-        writeln!(file, "name = \"{executable_name}\"")?;
-        writeln!(file, "version = \"{version}\"")?;
+        writeln!(file, "name = \"{}\"", executable.name)?;
+        writeln!(file, "version = \"{}\"", executable.version)?;
         writeln!(file, "edition = \"2021\"")?;
         writeln!(file, "\n[dependencies]")?;
 
@@ -91,93 +439,407 @@ pub fn build_at_path(path: &Path) -> anyhow::Result<ExitCode> {
         } else if Path::new(&teach_tech_core).is_absolute() {
             writeln!(file, "teach-tech-core.path = \"{teach_tech_core}\"")?;
         } else {
-            writeln!(file, "teach-tech-core.path = \"../{teach_tech_core}\"")?;
+            writeln!(file, "teach-tech-core.path = \"{up}{teach_tech_core}\"")?;
         }
         writeln!(file, "anyhow = \"1.0.93\"")?;
 
-        for (name, metadata) in &integrations {
+        for (name, integration) in &executable.integrations {
+            let metadata = match integration {
+                Integration::Short(metadata) => metadata,
+                Integration::Detailed(detailed) => {
+                    writeln!(
+                        file,
+                        "{name} = {{ {} }}",
+                        detailed_dependency(name, detailed, up)?
+                    )?;
+                    continue;
+                }
+            };
             if let Ok(version) = metadata.parse::<semver::Version>() {
                 writeln!(file, "{name} = \"{version}\"")?;
+            } else if let Some(spec) = metadata.strip_prefix("registry:") {
+                let resolved = &registry_crates[spec];
+                write!(file, "{name} = {{ version = \"={}\"", resolved.version)?;
+                if resolved.package != *name {
+                    write!(file, ", package = \"{}\"", resolved.package)?;
+                }
+                if let Some(registry) = &config.registry {
+                    write!(file, ", registry = \"{}\"", registry.name)?;
+                }
+                writeln!(file, " }}")?;
             } else if metadata.starts_with("http") {
                 writeln!(file, "{name}.git = \"{metadata}\"")?;
             } else {
-                let metadata_path = Path::new(&metadata);
-                if !metadata_path.exists() {
-                    return Err(anyhow::anyhow!("Path {metadata} does not exist"));
-                }
-                if !metadata_path.is_dir() {
-                    return Err(anyhow::anyhow!("Path {metadata} is not a folder"));
-                }
-                if metadata_path.join("Cargo.toml").exists() {
-                    if !metadata_path.join("Cargo.toml").is_file() {
-                        return Err(anyhow::anyhow!("Path {metadata}/Cargo.toml is not a file"));
-                    }
-                } else {
-                    return Err(anyhow::anyhow!("Path {metadata}/Cargo.toml does not exist"));
-                }
-                if metadata_path.join("src").exists() {
-                    if !metadata_path.join("src").is_dir() {
-                        return Err(anyhow::anyhow!("Path {metadata}/src is not a folder"));
-                    }
-                } else {
-                    return Err(anyhow::anyhow!("Path {metadata}/src does not exist"));
-                }
-                if metadata_path.is_absolute() {
+                check_integration_path(metadata)?;
+                if Path::new(metadata).is_absolute() {
                     writeln!(file, "{name}.path = \"{metadata}\"")?;
                 } else {
-                    writeln!(file, "{name}.path = \"../{metadata}\"")?;
+                    writeln!(file, "{name}.path = \"{up}{metadata}\"")?;
                 }
             }
         }
+        if config.executables.is_empty() {
+            write!(file, "{}", config.root_sections()?)?;
+        }
+        file.flush()?;
     };
-    write_result.with_context(|| format!("Writing to {executable_name}/Cargo.toml"))?;
-    file.flush()
-        .with_context(|| format!("Writing to {executable_name}/Cargo.toml"))?;
-    drop(file);
+    write_result.with_context(|| format!("Writing to {display}/Cargo.toml"))
+}
+
+/// The `main.rs` cargo needs to resolve dependencies before the real one can be written.
+const STUB_MAIN_RS: &str = "fn main() {}\n";
+
+/// The `main.rs` written for new executables, which is left alone afterwards.
+const MAIN_RS: &str =
+    "// This file is yours to edit: `teach-tech build` only creates it when it is missing.
+// The integrations are added in generated.rs, which is rewritten on every build.
+use teach_tech_core::prelude::*;
+
+mod generated;
 
-    let file = std::fs::File::create(executable_path.join("src").join("main.rs"))
-        .with_context(|| format!("Creating {executable_name}/src/main.rs"))?;
+fn main() -> anyhow::Result<std::process::ExitCode> {
+\tinit_core(|core| async move {
+\t\tlet core = generated::add_integrations(core).await?;
+\t\tOk(core)
+\t})
+}
+";
+
+/// Writes `src/generated.rs`, and `src/main.rs` if the user does not own one yet.
+/// Where and when the executables were generated, reported by `/info` and their `version` command.
+struct BuildInfo {
+    git_commit: Option<String>,
+    built_at: String,
+}
+
+impl BuildInfo {
+    fn collect() -> Self {
+        let git_commit = std::process::Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|commit| commit.trim().to_string());
+        if git_commit.is_none() {
+            warn!("Not in a git repository, so the executables will not report a git commit");
+        }
+        Self {
+            git_commit,
+            built_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        }
+    }
+}
+
+fn write_main_rs(
+    executable: &Executable,
+    manifest: &lockfile::BuildManifest,
+    build_info: &BuildInfo,
+    order: &[String],
+    files: &mut GeneratedFiles,
+) -> anyhow::Result<()> {
+    let display = executable.path.display();
+    let generated_path = executable.path.join("src").join("generated.rs");
+    let file = std::fs::File::create(&generated_path)
+        .with_context(|| format!("Creating {display}/src/generated.rs"))?;
     let mut file = BufWriter::new(file);
     let write_result: std::io::Result<()> = try {
-        writeln!(file, "use teach_tech_core::prelude::*;")?;
         writeln!(
             file,
-            "\nfn main() -> anyhow::Result<std::process::ExitCode> {{"
+            "// Generated by `teach-tech build` from build-config.toml. Edits are lost on the next build."
+        )?;
+        writeln!(file, "use teach_tech_core::TeachCore;")?;
+        writeln!(
+            file,
+            "\npub async fn add_integrations(mut core: TeachCore) -> anyhow::Result<TeachCore> {{"
         )?;
-        writeln!(file, "\tinit_core(|mut core| async move {{")?;
         writeln!(
             file,
-            "\t\tcore.add_info(\"version\", env!(\"CARGO_PKG_VERSION\"));"
+            "\tcore.add_info(\"version\", env!(\"CARGO_PKG_VERSION\"));"
+        )?;
+        writeln!(
+            file,
+            "\tcore.add_info(\"build-manifest\", std::collections::BTreeMap::from(["
+        )?;
+        for (name, dependency) in &manifest.dependencies {
+            if name == "teach-tech-core" || executable.integrations.contains_key(name) {
+                writeln!(file, "\t\t(\"{name}\", \"{}\"),", dependency.version)?;
+            }
+        }
+        writeln!(file, "\t]));")?;
+        if let Some(git_commit) = &build_info.git_commit {
+            writeln!(file, "\tcore.add_info(\"git-commit\", \"{git_commit}\");")?;
+        }
+        writeln!(
+            file,
+            "\tcore.add_info(\"built-at\", \"{}\");",
+            build_info.built_at
+        )?;
+        let integrations: Vec<_> = order.iter().map(|name| format!("\"{name}\"")).collect();
+        writeln!(
+            file,
+            "\tcore.add_info(\"integrations\", &[{}] as &[&str]);",
+            integrations.join(", ")
         )?;
 
-        for (name, _) in &integrations {
+        for name in order {
             let name = name.replace("-", "_");
-            // writeln!(file, "\t\tlet core = AddToCore::call({name}::add_to_core, core).await?;")?;
-            writeln!(file, "\t\tlet core = {name}::add_to_core(core).await?;")?;
+            writeln!(file, "\tlet core = {name}::add_to_core(core).await?;")?;
         }
 
-        writeln!(file, "\t\tOk(core)")?;
-        writeln!(file, "\t}})")?;
+        writeln!(file, "\tOk(core)")?;
         writeln!(file, "}}")?;
+        file.flush()?;
     };
-    write_result.with_context(|| format!("Writing to {executable_name}/Cargo.toml"))?;
-    file.flush()
-        .with_context(|| format!("Writing to {executable_name}/Cargo.toml"))?;
-    drop(file);
-
-    drop(_enter);
-    span = span!(Level::INFO, "Building {executable_name}");
-    _enter = span.enter();
-
-    let status = std::process::Command::new("cargo")
-        .arg("build")
-        .current_dir(executable_path)
-        .status()
-        .with_context(|| format!("Building {executable_name}"))?;
-
-    if status.success() {
-        Ok(ExitCode::SUCCESS)
+    write_result.with_context(|| format!("Writing to {display}/src/generated.rs"))?;
+    files.record(&generated_path)?;
+
+    let main_path = executable.path.join("src").join("main.rs");
+    let main_rs = std::fs::read_to_string(&main_path).unwrap_or_default();
+    // Before generated.rs existed, the whole of main.rs was rewritten on every build
+    let old_generated = main_rs.starts_with("use teach_tech_core::prelude::*;\n\nfn main()")
+        && main_rs.contains("core.add_info(\"build-manifest\"");
+    if main_rs.is_empty() || main_rs == STUB_MAIN_RS || old_generated {
+        files.write(&main_path, MAIN_RS)?;
+    } else if !main_rs.contains("generated::add_integrations") {
+        warn!(
+            "{} does not call generated::add_integrations, so the integrations in \
+             build-config.toml are not added",
+            main_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Generates the executables' crates from `build-config.toml` without building them.
+///
+/// With `locked`, the existing `Cargo.lock` is used as is instead of being updated.
+pub fn generate_at_path(path: &Path, locked: bool) -> anyhow::Result<Generated> {
+    let report = check::check_build_config(path)?;
+    if !report.is_ok() {
+        eprint!("{report}");
+        return Err(anyhow::anyhow!("build-config.toml is not valid"));
+    }
+    let config = read_build_config(path)?;
+    let span = span!(Level::INFO, "Setting up {}", config.executable_name);
+    let _enter = span.enter();
+
+    let mut files = GeneratedFiles::load(&config.root())?;
+    let result = generate(config, locked, &mut files);
+    // Saved even when generation stops halfway, so that clean can remove what was created
+    files.save()?;
+    let mut generated = result?;
+    generated.files = files;
+    Ok(generated)
+}
+
+fn generate(
+    config: BuildConfig,
+    locked: bool,
+    files: &mut GeneratedFiles,
+) -> anyhow::Result<Generated> {
+    let root = config.root();
+    let executables = config.executables();
+    let up = if config.executables.is_empty() {
+        "../"
     } else {
-        Ok(ExitCode::FAILURE)
+        "../../"
+    };
+
+    files.create_dir_all(&root)?;
+    files.write(&root.join(".gitignore"), "/target")?;
+    if !config.executables.is_empty() {
+        let members: Vec<_> = config
+            .executables
+            .keys()
+            .map(|name| format!("\"{name}\""))
+            .collect();
+        files.write(
+            &root.join("Cargo.toml"),
+            format!(
+                "[workspace]\nmembers = [{}]\nresolver = \"2\"\n{}",
+                members.join(", "),
+                config.root_sections()?
+            ),
+        )?;
+    }
+
+    let mut registry_crates: FxHashMap<String, RegistryCrate> = FxHashMap::default();
+    for executable in &executables {
+        for integration in executable.integrations.values() {
+            let Some(spec) = integration.registry_spec() else {
+                continue;
+            };
+            if !registry_crates.contains_key(spec) {
+                let resolved = registry::resolve(spec, config.registry.as_ref())?;
+                registry_crates.insert(spec.to_string(), resolved);
+            }
+        }
+    }
+    let mut cargo_config = String::new();
+    if let Some(registry) = &config.registry {
+        cargo_config.push_str(&format!(
+            "[registries.{}]\nindex = \"{}\"\n",
+            registry.name, registry.index
+        ));
+    }
+    cargo_config.push_str(&target::cargo_config(&config.targets));
+    if !cargo_config.is_empty() {
+        files.create_dir_all(&root.join(".cargo"))?;
+        files.write(&root.join(".cargo").join("config.toml"), cargo_config)?;
+    }
+
+    for executable in &executables {
+        create_crate_dirs(&executable.path, files)?;
+        write_cargo_toml(executable, &config, &registry_crates, up)?;
+        files.record(&executable.path.join("Cargo.toml"))?;
+        // Cargo refuses to read a manifest without targets, and main.rs needs the resolved versions
+        let main_path = executable.path.join("src").join("main.rs");
+        if !main_path.exists() {
+            files.write(&main_path, STUB_MAIN_RS)?;
+        }
+    }
+
+    let mut dependencies: Vec<(&str, &str)> = vec![("teach-tech-core", "teach-tech-core")];
+    for executable in &executables {
+        for (name, integration) in &executable.integrations {
+            let package = match integration.registry_spec() {
+                Some(spec) => &registry_crates[spec].package,
+                None => name,
+            };
+            dependencies.push((name, package));
+        }
+    }
+    dependencies.sort_unstable();
+    dependencies.dedup();
+    if !root.join("Cargo.lock").exists() {
+        files.record_artifact(&root.join("Cargo.lock"));
+    }
+    files.record_artifact(&root.join("target"));
+    let manifest = lockfile::resolve(&root, dependencies, locked)?;
+    // Cargo verifies downloads against Cargo.lock, so this ties them back to the index we resolved
+    for executable in &executables {
+        for (name, integration) in &executable.integrations {
+            let Some(spec) = integration.registry_spec() else {
+                continue;
+            };
+            let expected = &registry_crates[spec];
+            let locked = &manifest.dependencies[name];
+            if locked.version != expected.version
+                || locked.checksum.as_deref() != Some(&expected.checksum)
+            {
+                return Err(anyhow::anyhow!(
+                    "Cargo.lock pins {name} to a different release than the registry published as {} {}",
+                    expected.package,
+                    expected.version
+                ));
+            }
+        }
+    }
+    files.write(
+        &root.join("build-manifest.toml"),
+        toml::to_string(&manifest).context("Serializing build manifest")?,
+    )?;
+
+    let metadata = metadata::read(&root)?;
+    let build_info = BuildInfo::collect();
+    for executable in &executables {
+        let order = wiring::integration_order(&metadata, executable)
+            .with_context(|| format!("Ordering the integrations of {}", executable.name))?;
+        write_main_rs(executable, &manifest, &build_info, &order, files)?;
+    }
+
+    let generated = Generated {
+        config,
+        root,
+        executables,
+        files: GeneratedFiles::default(),
+    };
+    if let Err(e) = config_example::write_config_examples(&generated, files) {
+        warn!("Could not write teach-config.example.toml: {e:#}");
+    }
+    Ok(generated)
+}
+
+/// Generates every executable, then builds `executable` or all of them, for `target` if given.
+///
+/// With `out`, the built executables are copied into that folder.
+pub fn build_at_path(
+    path: &Path,
+    locked: bool,
+    executable: Option<&str>,
+    target: Option<&str>,
+    release: bool,
+    out: Option<&Path>,
+) -> anyhow::Result<ExitCode> {
+    let mut generated = generate_at_path(path, locked)?;
+    let selected = select(&generated.executables, executable)?;
+    let span = span!(Level::INFO, "Building {}", generated.config.executable_name);
+    let _enter = span.enter();
+
+    let target_config = target.map(|triple| {
+        generated
+            .config
+            .targets
+            .get(triple)
+            .cloned()
+            .unwrap_or_default()
+    });
+    let program = match &target_config {
+        Some(target_config) if target_config.cross => "cross",
+        _ => "cargo",
+    };
+    let mut command = std::process::Command::new(program);
+    command.arg("build").current_dir(&generated.root);
+    if executable.is_some() {
+        for executable in &selected {
+            command.args(["-p", &executable.name]);
+        }
+    }
+    if locked {
+        command.arg("--locked");
+    }
+    if release {
+        command.arg("--release");
+    }
+    if let Some(triple) = target {
+        if program == "cargo" {
+            target::check_installed(&generated.root, triple)?;
+        }
+        command.args(["--target", triple]);
+    }
+    let status = command.status().with_context(|| {
+        format!(
+            "Running {program} to build {}",
+            generated.config.executable_name
+        )
+    })?;
+    if !status.success() {
+        return Ok(ExitCode::FAILURE);
+    }
+
+    let mut artifacts = generated.root.join("target");
+    if let Some(triple) = target {
+        artifacts.push(triple);
+    }
+    artifacts.push(if release { "release" } else { "debug" });
+    if let Some(out) = out {
+        generated.files.create_dir_all(out)?;
+    }
+    for executable in selected {
+        let built = artifacts.join(&executable.name);
+        match out {
+            Some(out) => {
+                let copied = out.join(&executable.name);
+                std::fs::copy(&built, &copied).with_context(|| {
+                    format!("Copying {} to {}", built.display(), copied.display())
+                })?;
+                generated.files.record_artifact(&copied);
+                info!("Built {}", copied.display());
+            }
+            None => info!("Built {}", built.display()),
+        }
     }
+    generated.files.save()?;
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,502 @@
+use std::{fmt::Display, ops::Range, path::Path, process::ExitCode};
+
+use anyhow::Context;
+use fxhash::FxHashMap;
+use toml_edit::{ImDocument, Item};
+
+use crate::{
+    build::{check_integration_path, patch_dependency, BuildConfig, Integration},
+    registry, scaffold, target,
+};
+
+/// A problem in `build-config.toml`, with the line and column it was found at.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_ok() {
+            return writeln!(f, "build-config.toml is valid");
+        }
+        for diagnostic in &self.diagnostics {
+            match diagnostic.location {
+                Some((line, column)) => write!(f, "build-config.toml:{line}:{column}: ")?,
+                None => write!(f, "build-config.toml: ")?,
+            }
+            writeln!(f, "error: {}", diagnostic.message.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// The 1-based line and column of a byte offset.
+fn location(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// The keys serde_ignored reports a path as, skipping the levels that are not keys.
+fn segments(path: &serde_ignored::Path, keys: &mut Vec<String>) {
+    match path {
+        serde_ignored::Path::Root => {}
+        serde_ignored::Path::Seq { parent, index } => {
+            segments(parent, keys);
+            keys.push(index.to_string());
+        }
+        serde_ignored::Path::Map { parent, key } => {
+            segments(parent, keys);
+            keys.push(key.clone());
+        }
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => segments(parent, keys),
+    }
+}
+
+/// The keys a table integration may set, as in [`crate::build::DetailedIntegration`].
+const DETAILED_KEYS: &[&str] = &[
+    "version",
+    "path",
+    "git",
+    "branch",
+    "tag",
+    "rev",
+    "features",
+    "default_features",
+    "default-features",
+];
+
+struct Checker<'a> {
+    source: &'a str,
+    document: ImDocument<&'a str>,
+    report: CheckReport,
+}
+
+impl Checker<'_> {
+    fn error(&mut self, span: Option<Range<usize>>, message: impl Into<String>) {
+        self.report.diagnostics.push(Diagnostic {
+            location: span.map(|span| location(self.source, span.start)),
+            message: message.into(),
+        });
+    }
+
+    /// The span of the last key in `keys`, or of the deepest one that exists.
+    fn key_span<S: AsRef<str>>(&self, keys: &[S]) -> Option<Range<usize>> {
+        let mut item = self.document.as_item();
+        let mut span = None;
+        for key in keys {
+            let Some((key, value)) = item
+                .as_table_like()
+                .and_then(|table| table.get_key_value(key.as_ref()))
+            else {
+                break;
+            };
+            span = key.span().or(span);
+            item = value;
+        }
+        span
+    }
+
+    fn check_integration(&mut self, keys: &[&str], name: &str, integration: &Integration) {
+        let span = self.key_span(keys);
+        let metadata = match integration {
+            Integration::Short(metadata) => metadata,
+            Integration::Detailed(detailed) => {
+                if let Err(e) = detailed.validate(name) {
+                    self.error(span, format!("{e:#}"));
+                } else if let Some(path) = &detailed.path {
+                    if let Err(e) = check_integration_path(path) {
+                        self.error(span, format!("{name}: {e}"));
+                    }
+                }
+                return;
+            }
+        };
+        if metadata.parse::<semver::Version>().is_ok() || metadata.starts_with("http") {
+            return;
+        }
+        if let Some(spec) = metadata.strip_prefix("registry:") {
+            if let Err(e) = registry::parse_spec(spec) {
+                self.error(span, format!("{e:#}"));
+            }
+            return;
+        }
+        if let Err(e) = check_integration_path(metadata) {
+            if metadata.parse::<semver::VersionReq>().is_ok() {
+                self.error(
+                    span,
+                    format!(
+                        "{name} = \"{metadata}\" is read as a path; write a full version such as \
+                         \"{metadata}.0\", or {{ version = \"{metadata}\" }} for a requirement"
+                    ),
+                );
+            } else {
+                self.error(span, format!("{name}: {e}"));
+            }
+        }
+    }
+
+    fn check_integration_keys(&mut self) {
+        let mut tables = vec![
+            vec!["integrations".to_string()],
+            vec!["patches".to_string()],
+        ];
+        if let Some(executables) = self
+            .document
+            .get("executables")
+            .and_then(Item::as_table_like)
+        {
+            for (executable, _) in executables.iter() {
+                tables.push(vec![
+                    "executables".to_string(),
+                    executable.to_string(),
+                    "integrations".to_string(),
+                ]);
+            }
+        }
+        for table in tables {
+            let mut item = Some(self.document.as_item());
+            for key in &table {
+                item = item.and_then(|item| item.get(key));
+            }
+            let Some(integrations) = item.and_then(Item::as_table_like) else {
+                continue;
+            };
+            let mut unknown = vec![];
+            for (name, integration) in integrations.iter() {
+                let Some(detailed) = integration.as_table_like() else {
+                    continue;
+                };
+                for (key, _) in detailed.iter() {
+                    if !DETAILED_KEYS.contains(&key) {
+                        let mut keys = table.clone();
+                        keys.extend([name.to_string(), key.to_string()]);
+                        unknown.push(keys);
+                    }
+                }
+            }
+            for keys in unknown {
+                self.error(
+                    self.key_span(&keys),
+                    format!("Unknown key `{}`", keys.join(".")),
+                );
+            }
+        }
+    }
+
+    /// The span of a top level key that may also be written with dashes.
+    fn top_level_span(&self, key: &str) -> Option<Range<usize>> {
+        self.key_span(&[key])
+            .or_else(|| self.key_span(&[key.replace('_', "-")]))
+    }
+
+    fn check_executable_name(&mut self, span: Option<Range<usize>>, name: &str, workspace: bool) {
+        if let Err(e) = scaffold::validate_name(name) {
+            self.error(span, format!("Executable {e}"));
+        } else if name == "anyhow" || (workspace && name == "target") {
+            self.error(span, format!("Executable {name} is a reserved name"));
+        }
+    }
+
+    fn check(&mut self, config: &BuildConfig) {
+        let workspace = !config.executables.is_empty();
+        if !workspace {
+            self.check_executable_name(
+                self.top_level_span("executable_name"),
+                &config.executable_name,
+                false,
+            );
+        }
+
+        if config.teach_tech_core.parse::<semver::Version>().is_err() {
+            if let Err(e) = check_integration_path(&config.teach_tech_core) {
+                self.error(
+                    self.top_level_span("teach_tech_core"),
+                    format!("teach-tech-core: {e}"),
+                );
+            }
+        }
+
+        if let Some(registry) = &config.registry {
+            if !registry.index.starts_with("sparse+") {
+                self.error(
+                    self.key_span(&["registry", "index"]),
+                    format!(
+                        "Only sparse registry indices are supported, but {} is not one",
+                        registry.index
+                    ),
+                );
+            }
+        }
+
+        for (name, patch) in &config.patches {
+            if let Err(e) = patch_dependency(name, patch, "") {
+                self.error(self.key_span(&["patches", name]), format!("{e:#}"));
+            }
+        }
+
+        if let Err(e) = config.release.validate() {
+            self.error(self.key_span(&["release"]), format!("release: {e}"));
+        }
+
+        for triple in config.targets.keys() {
+            if !target::is_triple(triple) {
+                self.error(
+                    self.key_span(&["targets", triple]),
+                    format!("{triple} is not a target triple, such as aarch64-unknown-linux-gnu"),
+                );
+            }
+        }
+
+        let mut shared: Vec<_> = config.integrations.iter().collect();
+        shared.sort_unstable_by_key(|(name, _)| *name);
+        for (name, integration) in &shared {
+            self.check_integration(&["integrations", name], name, integration);
+        }
+        self.check_collisions(&["integrations"], &shared, &[]);
+
+        for (executable, exe_config) in &config.executables {
+            self.check_executable_name(
+                self.key_span(&["executables", executable]),
+                executable,
+                true,
+            );
+            let mut own: Vec<_> = exe_config.integrations.iter().collect();
+            own.sort_unstable_by_key(|(name, _)| *name);
+            for (name, integration) in &own {
+                let keys = ["executables", executable, "integrations", name];
+                if config.integrations.contains_key(*name) {
+                    self.error(
+                        self.key_span(&keys),
+                        format!("{name} is already an integration every executable shares"),
+                    );
+                    continue;
+                }
+                self.check_integration(&keys, name, integration);
+            }
+            self.check_collisions(&["executables", executable, "integrations"], &own, &shared);
+
+            let snake_executable = executable.replace('-', "_");
+            if let Some(name) = shared
+                .iter()
+                .chain(&own)
+                .map(|(name, _)| name)
+                .find(|name| name.replace('-', "_") == snake_executable)
+            {
+                self.error(
+                    self.key_span(&["executables", executable]),
+                    format!("Executable {executable} has the same name as its integration {name}"),
+                );
+            }
+        }
+        if !workspace {
+            let snake_executable = config.executable_name.replace('-', "_");
+            if let Some((name, _)) = shared
+                .iter()
+                .find(|(name, _)| name.replace('-', "_") == snake_executable)
+            {
+                self.error(
+                    self.top_level_span("executable_name"),
+                    format!(
+                        "Executable {} has the same name as its integration {name}",
+                        config.executable_name
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Integrations whose names only differ by `-` and `_` would be the same crate in `generated.rs`.
+    fn check_collisions(
+        &mut self,
+        table: &[&str],
+        integrations: &[(&String, &Integration)],
+        earlier: &[(&String, &Integration)],
+    ) {
+        let mut seen: FxHashMap<String, &str> = earlier
+            .iter()
+            .map(|(name, _)| (name.replace('-', "_"), name.as_str()))
+            .collect();
+        for (name, _) in integrations {
+            let snake_name = name.replace('-', "_");
+            match seen.get(&snake_name) {
+                Some(other) if *other != name.as_str() => {
+                    let mut keys = table.to_vec();
+                    keys.push(name);
+                    self.error(
+                        self.key_span(&keys),
+                        format!("Integration {name} has the same crate name as {other}"),
+                    );
+                }
+                _ => {
+                    seen.insert(snake_name, name);
+                }
+            }
+        }
+    }
+}
+
+/// Checks `build-config.toml` without generating or building anything.
+pub fn check_build_config(path: &Path) -> anyhow::Result<CheckReport> {
+    let source = std::fs::read_to_string(path.join("build-config.toml"))
+        .context("Reading build-config.toml")?;
+    Ok(check_source(&source))
+}
+
+/// Checks the contents of a `build-config.toml`. Paths in it are relative to the current folder.
+fn check_source(source: &str) -> CheckReport {
+    let document = match ImDocument::parse(source) {
+        Ok(document) => document,
+        Err(e) => {
+            return CheckReport {
+                diagnostics: vec![Diagnostic {
+                    location: e.span().map(|span| location(source, span.start)),
+                    message: e.message().to_string(),
+                }],
+            };
+        }
+    };
+    let mut checker = Checker {
+        source,
+        document,
+        report: CheckReport::default(),
+    };
+
+    // Integrations are untagged, so serde cannot say which key of a table it did not expect
+    checker.check_integration_keys();
+    let mut ignored = vec![];
+    let result = serde_ignored::deserialize(toml::Deserializer::new(source), |path| {
+        let mut keys = vec![];
+        segments(&path, &mut keys);
+        ignored.push(keys);
+    });
+    let config: BuildConfig = match result {
+        Ok(config) => config,
+        Err(e) => {
+            if !e.message().contains("untagged enum Integration") {
+                checker.error(e.span(), e.message());
+            } else if checker.report.is_ok() {
+                checker.error(
+                    e.span(),
+                    "An integration must be a string, or a table setting one of version, path and git",
+                );
+            }
+            return checker.report;
+        }
+    };
+    for keys in ignored {
+        let span = checker.key_span(&keys);
+        checker.error(span, format!("Unknown key `{}`", keys.join(".")));
+    }
+    checker.check(&config);
+    checker
+        .report
+        .diagnostics
+        .sort_by_key(|diagnostic| diagnostic.location);
+    checker.report
+}
+
+/// Prints every problem in `build-config.toml`.
+pub fn check_at_path(path: &Path) -> anyhow::Result<ExitCode> {
+    let report = check_build_config(path)?;
+    print!("{report}");
+    if report.is_ok() {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The locations and messages `source` is reported with.
+    fn diagnostics(source: &str) -> Vec<(Option<(usize, usize)>, String)> {
+        check_source(source)
+            .diagnostics
+            .into_iter()
+            .map(|diagnostic| (diagnostic.location, diagnostic.message))
+            .collect()
+    }
+
+    #[test]
+    fn valid_configs_have_no_diagnostics() {
+        for source in [
+            "",
+            "executable_name = \"school\"\n[integrations]\nquick-chat = \"0.1.0\"\n",
+            "[integrations]\nbilling = { version = \"0.1\", features = [\"stripe\"] }\n",
+            "[executables.school.integrations]\nscorm = \"0.2.0\"\n",
+        ] {
+            assert!(check_source(source).is_ok(), "{source}");
+        }
+    }
+
+    #[test]
+    fn problems_are_reported_where_they_are() {
+        let cases = [
+            ("executable_name = \"Core\"", 1, 1, "must start with a lowercase"),
+            ("executable_name = \"anyhow\"", 1, 1, "reserved name"),
+            ("[integrations]\nchat = \"0.1\"", 2, 1, "is read as a path"),
+            ("[integrations]\nchat = \"registry:@1\"", 2, 1, "does not name a crate"),
+            ("[integrations]\nchat = { version = \"1\", tags = \"v1\" }", 2, 25, "`integrations.chat.tags`"),
+            ("[integrations]\nchat = 1", 2, 8, "must be a string, or a table"),
+            ("colour = \"red\"", 1, 1, "Unknown key `colour`"),
+            ("[registry]\nname = \"ours\"\nindex = \"https://example.com\"", 3, 1, "sparse registry"),
+            ("[targets.linux]", 1, 10, "not a target triple"),
+            ("[integrations]\nmy-chat = \"0.1.0\"\nmy_chat = \"0.1.0\"", 3, 1, "same crate name"),
+            ("executable_name = \"chat\"\n[integrations]\nchat = \"0.1.0\"", 1, 1, "same name as its integration"),
+            ("[integrations]\nchat = \"0.1.0\"\n[executables.school.integrations]\nchat = \"0.1.0\"", 4, 1, "already an integration"),
+        ];
+        for (source, line, column, message) in cases {
+            let diagnostics = diagnostics(source);
+            assert_eq!(diagnostics.len(), 1, "{source}: {diagnostics:?}");
+            let (location, reported) = &diagnostics[0];
+            assert_eq!(*location, Some((line, column)), "{source}: {reported}");
+            assert!(reported.contains(message), "{source}: {reported}");
+        }
+    }
+
+    #[test]
+    fn syntax_errors_are_located() {
+        let diagnostics = diagnostics("[integrations]\nchat = ");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].0, Some((2, 8)));
+    }
+
+    #[test]
+    fn every_problem_is_reported_in_order() {
+        let diagnostics = diagnostics(
+            "[targets.linux]\n[integrations]\nchat = \"0.1\"\n[registry]\nname = \"ours\"\nindex = \"https://example.com\"",
+        );
+        let lines: Vec<_> = diagnostics
+            .iter()
+            .map(|(location, _)| location.unwrap().0)
+            .collect();
+        assert_eq!(lines, [1, 3, 6]);
+    }
+
+    #[test]
+    fn locations_count_characters() {
+        assert_eq!(location("a\nbcd", 0), (1, 1));
+        assert_eq!(location("a\nbcd", 4), (2, 3));
+        assert_eq!(location("é = 1", 2), (1, 2));
+        assert_eq!(location("a", 10), (1, 2));
+    }
+}
@@ -0,0 +1,99 @@
+use std::{path::Path, process::ExitCode};
+
+use anyhow::Context;
+use tracing::{info, warn};
+
+use crate::{
+    build::read_build_config,
+    generated_files::{self, GeneratedFiles},
+};
+
+fn remove(path: &Path, dry_run: bool) -> anyhow::Result<()> {
+    if dry_run {
+        info!("Would remove {}", path.display());
+        return Ok(());
+    }
+    if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+    .with_context(|| format!("Removing {}", path.display()))?;
+    info!("Removed {}", path.display());
+    Ok(())
+}
+
+/// Removes the executables and build artifacts teach-tech generated, keeping anything it did not
+/// create and any generated file that has been edited since.
+pub fn clean_at_path(path: &Path, dry_run: bool) -> anyhow::Result<ExitCode> {
+    let config = read_build_config(path)?;
+    let root = config.root();
+    if !root.join(generated_files::FILE_NAME).exists() {
+        return Err(anyhow::anyhow!(
+            "{} has no {}, so it is unknown what teach-tech generated there",
+            root.display(),
+            generated_files::FILE_NAME
+        ));
+    }
+    let generated = GeneratedFiles::load(&root)?;
+    let mut kept = false;
+
+    for artifact in generated.artifacts.iter().filter(|path| path.exists()) {
+        remove(artifact, dry_run)?;
+    }
+    for (file, hash) in &generated.files {
+        let Ok(contents) = std::fs::read(file) else {
+            continue;
+        };
+        if generated_files::hash(&contents) == *hash {
+            remove(file, dry_run)?;
+        } else {
+            warn!(
+                "Keeping {}, which was edited after it was generated",
+                file.display()
+            );
+            kept = true;
+        }
+    }
+
+    // Deepest first, so that folders are empty by the time their parents are reached
+    let mut dirs: Vec<_> = generated.dirs.iter().filter(|dir| dir.exists()).collect();
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    for dir in dirs {
+        if dir == &root {
+            continue;
+        }
+        if dry_run {
+            info!("Would remove {} once empty", dir.display());
+        } else if std::fs::remove_dir(dir).is_ok() {
+            info!("Removed {}", dir.display());
+        } else {
+            warn!(
+                "Keeping {}, which has files teach-tech did not create",
+                dir.display()
+            );
+            kept = true;
+        }
+    }
+
+    if dry_run {
+        return Ok(ExitCode::SUCCESS);
+    }
+    if kept {
+        // Remembers what is left, so that a later clean still recognizes it
+        let mut remaining = GeneratedFiles::load(&root)?;
+        remaining.artifacts.clear();
+        remaining.files.retain(|file, _| file.exists());
+        remaining.dirs.retain(|dir| dir.exists());
+        remaining.save()?;
+    } else {
+        remove(&root.join(generated_files::FILE_NAME), false)?;
+        if generated.dirs.contains(&root) && std::fs::remove_dir(&root).is_err() {
+            warn!(
+                "Keeping {}, which has files teach-tech did not create",
+                root.display()
+            );
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,120 @@
+use std::fmt::Write;
+
+use anyhow::Context;
+use tracing::{info, warn};
+
+use crate::{
+    build::Generated,
+    generated_files::GeneratedFiles,
+    metadata::{self, Package},
+};
+
+/// The config example a package declares with `config-example` under
+/// `[package.metadata.teach-tech]`, as a path relative to its `Cargo.toml`.
+///
+/// With `tables_only`, the example may not set keys outside of a table, as they would end up in
+/// the last table of the example before it.
+fn read_example(package: &Package, tables_only: bool) -> anyhow::Result<Option<String>> {
+    let Some(file) = package.metadata["teach-tech"]["config-example"].as_str() else {
+        return Ok(None);
+    };
+    let path = package
+        .manifest_path
+        .parent()
+        .map(|dir| dir.join(file))
+        .unwrap_or_else(|| file.into());
+    let example = std::fs::read_to_string(&path)
+        .with_context(|| format!("Reading {} of {}", path.display(), package.name))?;
+    let table = match example.parse::<toml::Table>() {
+        Ok(table) => table,
+        Err(e) => {
+            return Err(anyhow::anyhow!(
+                "The config example of {} is not valid TOML: {e}",
+                package.name
+            ))
+        }
+    };
+    if tables_only {
+        if let Some(key) = table
+            .iter()
+            .find(|(_, value)| !value.is_table())
+            .map(|(key, _)| key)
+        {
+            return Err(anyhow::anyhow!(
+                "The config example of {} sets {key} outside of a table, so it was left out",
+                package.name
+            ));
+        }
+    }
+    Ok(Some(example))
+}
+
+/// Writes a `teach-config.example.toml` next to each executable, made of the config examples of
+/// teach-tech-core and every integration it uses.
+pub fn write_config_examples(
+    generated: &Generated,
+    files: &mut GeneratedFiles,
+) -> anyhow::Result<()> {
+    let metadata = metadata::read(&generated.root)?;
+    for executable in &generated.executables {
+        let dependencies = metadata.dependencies(&executable.name);
+        let mut integrations: Vec<_> = dependencies
+            .iter()
+            .filter(|(name, _)| {
+                executable
+                    .integrations
+                    .keys()
+                    .any(|integration| integration.replace('-', "_") == *name)
+            })
+            .map(|(_, package)| *package)
+            .collect();
+        integrations.sort_by(|a, b| a.name.cmp(&b.name));
+        let core = dependencies
+            .iter()
+            .find(|(name, _)| *name == "teach_tech_core")
+            .map(|(_, package)| *package);
+
+        let mut out = String::from(
+            "# Generated by `teach-tech build` from the config examples of teach-tech-core and the\n\
+             # integrations. Copy it to teach-config.toml next to the executable and fill it in.\n",
+        );
+        for package in core.into_iter().chain(integrations) {
+            let example = match read_example(package, package.name != "teach-tech-core") {
+                Ok(Some(example)) => example,
+                Ok(None) => {
+                    warn!(
+                        "{} does not declare a config example, so its settings are missing",
+                        package.name
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    warn!("{e:#}");
+                    continue;
+                }
+            };
+            let before = out.len();
+            write!(
+                out,
+                "\n# ---- {} {} ----\n\n{}",
+                package.name,
+                package.version,
+                example.trim_end()
+            )
+            .unwrap();
+            out.push('\n');
+            if out.parse::<toml::Table>().is_err() {
+                warn!(
+                    "The config example of {} conflicts with the ones before it, so it was left out",
+                    package.name
+                );
+                out.truncate(before);
+            }
+        }
+
+        let path = executable.path.join("teach-config.example.toml");
+        files.write(&path, out)?;
+        info!("Wrote {}", path.display());
+    }
+    Ok(())
+}
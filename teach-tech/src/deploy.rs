@@ -0,0 +1,177 @@
+use std::{path::Path, process::ExitCode};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    build::{read_build_config, select, Executable},
+    generated_files::GeneratedFiles,
+};
+
+/// The `[deploy]` section of `build-config.toml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeployConfig {
+    /// Each executable is installed into its own folder in here
+    #[serde(default = "default_install_dir")]
+    #[serde(alias = "install-dir")]
+    pub install_dir: String,
+    /// The system user the service runs as, which defaults to the executable's name
+    pub user: Option<String>,
+    /// The domain nginx serves the API on
+    #[serde(default = "default_server_name")]
+    #[serde(alias = "server-name")]
+    pub server_name: String,
+    /// The local port the executable listens on behind nginx; with several executables, each one
+    /// takes the next port
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+impl Default for DeployConfig {
+    fn default() -> Self {
+        Self {
+            install_dir: default_install_dir(),
+            user: None,
+            server_name: default_server_name(),
+            port: default_port(),
+        }
+    }
+}
+
+fn default_install_dir() -> String {
+    "/opt/teach-tech".to_string()
+}
+
+fn default_server_name() -> String {
+    "_".to_string()
+}
+
+fn default_port() -> u16 {
+    8080
+}
+
+const SYSTEMD_UNIT: &str = r#"# Generated by `teach-tech deploy-files`. Install with:
+#   cp {name}.service /etc/systemd/system/ && systemctl daemon-reload && systemctl enable --now {name}
+# teach-config.toml must be placed in {dir} and should contain:
+#   server_address = "127.0.0.1:{port}"
+#   trusted_proxies = ["127.0.0.1/32"]
+
+[Unit]
+Description={name} (teach-tech)
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=simple
+User={user}
+Group={user}
+WorkingDirectory={dir}
+EnvironmentFile={dir}/{name}.env
+ExecStart={dir}/{name} run
+Restart=on-failure
+RestartSec=5
+# The executable runs its shutdown hooks on ctrl-c
+KillSignal=SIGINT
+TimeoutStopSec=30
+NoNewPrivileges=true
+PrivateTmp=true
+ProtectSystem=strict
+ProtectHome=true
+ReadWritePaths={dir}
+
+[Install]
+WantedBy=multi-user.target
+"#;
+
+const NGINX_CONF: &str = r#"# Generated by `teach-tech deploy-files`. Include from the http block, for example by copying
+# this file into /etc/nginx/conf.d/, then add TLS with certbot or your own certificates.
+
+map $http_upgrade ${snake_name}_connection_upgrade {
+    default upgrade;
+    '' close;
+}
+
+upstream {snake_name} {
+    server 127.0.0.1:{port};
+    keepalive 16;
+}
+
+server {
+    listen 80;
+    listen [::]:80;
+    server_name {server_name};
+
+    client_max_body_size 25m;
+
+    location / {
+        proxy_pass http://{snake_name};
+        proxy_http_version 1.1;
+        proxy_set_header Host $host;
+        proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;
+        proxy_set_header X-Forwarded-Proto $scheme;
+        # WebSockets, such as quick-chat's, need the upgrade passed through and long timeouts
+        proxy_set_header Upgrade $http_upgrade;
+        proxy_set_header Connection ${snake_name}_connection_upgrade;
+        proxy_read_timeout 1h;
+    }
+}
+"#;
+
+const ENV_FILE: &str = r#"# Generated by `teach-tech deploy-files`. Read by {name}.service; keep this file private:
+#   chown root:{user} {name}.env && chmod 640 {name}.env
+
+# Log filter, such as INFO or teach_tech_core=DEBUG,INFO
+LOG_LEVEL=INFO
+
+# The key used to encrypt sensitive columns, as 64 hex characters (`openssl rand -hex 32`).
+# Overrides encryption.key in teach-config.toml. Never lose it, as encrypted data cannot be read
+# without it
+# ENCRYPTION_KEY=
+"#;
+
+fn render(template: &str, executable: &Executable, port: u16, config: &DeployConfig) -> String {
+    let dir = format!(
+        "{}/{}",
+        config.install_dir.trim_end_matches('/'),
+        executable.name
+    );
+    template
+        .replace("{snake_name}", &executable.name.replace('-', "_"))
+        .replace("{name}", &executable.name)
+        .replace("{user}", config.user.as_deref().unwrap_or(&executable.name))
+        .replace("{dir}", &dir)
+        .replace("{server_name}", &config.server_name)
+        .replace("{port}", &port.to_string())
+}
+
+/// Writes a systemd unit, an nginx site and an environment file template into each executable's
+/// `deploy` folder.
+pub fn deploy_files_at_path(path: &Path, executable: Option<&str>) -> anyhow::Result<ExitCode> {
+    let config = read_build_config(path)?;
+    let mut generated_files = GeneratedFiles::load(&config.root())?;
+    let executables = config.executables();
+    for executable in select(&executables, executable)? {
+        let index = executables
+            .iter()
+            .position(|exe| exe.name == executable.name)
+            .unwrap();
+        let port = config.deploy.port + index as u16;
+        let deploy_path = executable.path.join("deploy");
+        generated_files.create_dir_all(&deploy_path)?;
+        let files = [
+            (format!("{}.service", executable.name), SYSTEMD_UNIT),
+            (format!("{}.nginx.conf", executable.name), NGINX_CONF),
+            (format!("{}.env", executable.name), ENV_FILE),
+        ];
+        for (file, template) in files {
+            let file_path = deploy_path.join(file);
+            generated_files.write(
+                &file_path,
+                render(template, executable, port, &config.deploy),
+            )?;
+        }
+        info!("Wrote deployment files to {}", deploy_path.display());
+    }
+    generated_files.save()?;
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,148 @@
+use std::{
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitCode},
+    sync::mpsc::{channel, RecvTimeoutError},
+    time::Duration,
+};
+
+use anyhow::Context;
+use notify::{EventKind, RecursiveMode, Watcher};
+use tracing::{error, info, warn};
+
+use crate::build::{generate_at_path, read_build_config, BuildConfig};
+
+/// How long to wait for more changes before restarting, as editors often write several files at once
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The folders whose changes should restart the executable.
+fn watched_paths(path: &Path, config: &BuildConfig) -> Vec<PathBuf> {
+    let mut paths = vec![path.join("build-config.toml")];
+    let executable_integrations = config
+        .executables
+        .values()
+        .flat_map(|executable| executable.integrations.values());
+    for integration in config.integrations.values().chain(executable_integrations) {
+        let Some(integration) = integration.local_path() else {
+            continue;
+        };
+        paths.push(integration.join("src"));
+        paths.push(integration.join("Cargo.toml"));
+    }
+    paths
+}
+
+fn start(path: &Path, executable: Option<&str>) -> anyhow::Result<Option<Child>> {
+    let generated = generate_at_path(path, false)?;
+    let executable = generated.select_one(executable)?;
+    let name = &executable.name;
+    let status = Command::new("cargo")
+        .args(["build", "-p", name])
+        .current_dir(&generated.root)
+        .status()
+        .with_context(|| format!("Building {name}"))?;
+    if !status.success() {
+        return Ok(None);
+    }
+    info!("Starting {name}");
+    let child = Command::new("cargo")
+        .args(["run", "-p", name, "--", "run"])
+        // Restarts are handled here, so core's own reloader would only start a second copy
+        .env("HOT_RELOAD", "disable")
+        .current_dir(&executable.path)
+        .spawn()
+        .with_context(|| format!("Running {name}"))?;
+    Ok(Some(child))
+}
+
+fn stop(child: &mut Child) {
+    // Give the executable a chance to run its shutdown hooks before killing it
+    let _ = Command::new("kill")
+        .args(["-s", "INT", &child.id().to_string()])
+        .status();
+    for _ in 0..50 {
+        if let Ok(Some(_)) = child.try_wait() {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    warn!("Executable did not stop in time, killing it");
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Builds and runs the executable, rebuilding and restarting it whenever an integration changes.
+///
+/// `executable` must be given when `build-config.toml` defines several executables.
+pub fn dev_at_path(path: &Path, executable: Option<&str>) -> anyhow::Result<ExitCode> {
+    if executable.is_none() && read_build_config(path)?.executables.len() > 1 {
+        return Err(anyhow::anyhow!(
+            "build-config.toml defines several executables; choose one with --executable"
+        ));
+    }
+    let (tx, rx) = channel();
+    let mut watcher = notify::recommended_watcher(tx).context("Creating file watcher")?;
+    let mut watched: Vec<PathBuf> = vec![];
+
+    loop {
+        for old in watched.drain(..) {
+            let _ = watcher.unwatch(&old);
+        }
+        match read_build_config(path) {
+            Ok(config) => {
+                for watch in watched_paths(path, &config) {
+                    if !watch.exists() {
+                        continue;
+                    }
+                    if let Err(e) = watcher.watch(&watch, RecursiveMode::Recursive) {
+                        error!("Error watching {}: {e:#}", watch.display());
+                        continue;
+                    }
+                    watched.push(watch);
+                }
+            }
+            Err(e) => error!("{e:#}"),
+        }
+
+        let mut child = match start(path, executable) {
+            Ok(child) => child,
+            Err(e) => {
+                error!("{e:#}");
+                None
+            }
+        };
+        if child.is_none() {
+            warn!("Waiting for changes before rebuilding");
+        }
+
+        // Wait for a relevant change, then for the burst of changes to settle
+        loop {
+            let event = rx.recv().context("File watcher stopped")?;
+            match event {
+                Ok(event) => match event.kind {
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {
+                        if let Some(changed) = event.paths.first() {
+                            info!("{} changed", changed.display());
+                        }
+                        break;
+                    }
+                    _ => {}
+                },
+                Err(e) => error!("Error watching for file changes: {e:#}"),
+            }
+        }
+        loop {
+            match rx.recv_timeout(DEBOUNCE) {
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow::anyhow!("File watcher stopped"))
+                }
+            }
+        }
+
+        if let Some(child) = &mut child {
+            info!("Restarting");
+            stop(child);
+        }
+    }
+}
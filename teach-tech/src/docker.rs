@@ -0,0 +1,115 @@
+use std::{path::Path, process::ExitCode};
+
+use tracing::{info, span, warn, Level};
+
+use crate::build::{generate_at_path, select, BuildConfig, Executable, Integration};
+
+/// The toolchain teach-tech-core is developed against, as it relies on nightly features
+const RUST_TOOLCHAIN: &str = "nightly-2024-09-06";
+
+const DOCKERFILE: &str = r#"# Generated by `teach-tech build --docker`. Build from the folder containing build-config.toml:
+#   docker build -f {executable_path}/Dockerfile -t {executable_name}:{version} .
+# Run with the configuration mounted in:
+#   docker run -p 80:80 -v ./teach-config.toml:/etc/teach-tech/teach-config.toml:ro {executable_name}:{version}
+
+FROM rust:1-bookworm AS build
+RUN rustup toolchain install {toolchain} --profile minimal && rustup default {toolchain}
+WORKDIR /src
+COPY . .
+RUN cargo build --release --locked --manifest-path {root}/Cargo.toml -p {executable_name} \
+    && cp {root}/target/release/{executable_name} /usr/local/bin/{executable_name}
+
+FROM debian:bookworm-slim
+RUN apt-get update \
+    && apt-get install -y --no-install-recommends ca-certificates curl \
+    && rm -rf /var/lib/apt/lists/*
+COPY --from=build /usr/local/bin/{executable_name} /usr/local/bin/{executable_name}
+# teach-config.toml is read from the working directory
+WORKDIR /etc/teach-tech
+VOLUME /etc/teach-tech
+EXPOSE 80
+HEALTHCHECK --interval=30s --timeout=5s --start-period=30s --retries=3 \
+    CMD curl -fsS http://localhost:80/health || exit 1
+ENTRYPOINT ["/usr/local/bin/{executable_name}"]
+CMD ["run"]
+"#;
+
+const DOCKERIGNORE: &str = "**/target\n**/.git\n**/node_modules\n";
+
+/// Generates the executables and a Dockerfile for each, then builds the images if docker is
+/// available.
+pub fn build_docker_at_path(
+    path: &Path,
+    locked: bool,
+    executable: Option<&str>,
+) -> anyhow::Result<ExitCode> {
+    let mut generated = generate_at_path(path, locked)?;
+    let BuildConfig {
+        integrations,
+        teach_tech_core,
+        executables,
+        ..
+    } = &generated.config;
+
+    // Only what is inside the build context can be copied into the image
+    let local_paths = integrations
+        .values()
+        .chain(
+            executables
+                .values()
+                .flat_map(|exe| exe.integrations.values()),
+        )
+        .filter_map(Integration::local_path)
+        .chain(std::iter::once(Path::new(teach_tech_core)))
+        .filter(|path| path.is_absolute());
+    for path in local_paths {
+        warn!(
+            "{} is an absolute path, which will not exist inside the image",
+            path.display()
+        );
+    }
+    if !Path::new(".dockerignore").exists() {
+        generated
+            .files
+            .write(Path::new(".dockerignore"), DOCKERIGNORE)?;
+    }
+
+    for executable in select(&generated.executables, executable)? {
+        let Executable {
+            name,
+            version,
+            path: executable_path,
+            ..
+        } = executable;
+        let span = span!(Level::INFO, "Creating image for {name}");
+        let _enter = span.enter();
+
+        let dockerfile = DOCKERFILE
+            .replace("{executable_path}", &executable_path.display().to_string())
+            .replace("{executable_name}", name)
+            .replace("{root}", &generated.root.display().to_string())
+            .replace("{version}", &version.to_string())
+            .replace("{toolchain}", RUST_TOOLCHAIN);
+        let dockerfile_path = executable_path.join("Dockerfile");
+        generated.files.write(&dockerfile_path, dockerfile)?;
+        generated.files.save()?;
+
+        let tag = format!("{name}:{version}");
+        let status = std::process::Command::new("docker")
+            .args(["build", "-f"])
+            .arg(&dockerfile_path)
+            .args(["-t", &tag, "."])
+            .status();
+        match status {
+            Ok(status) if status.success() => info!("Built image {tag}"),
+            Ok(_) => return Ok(ExitCode::FAILURE),
+            Err(e) => {
+                warn!(
+                    "Could not run docker ({e}); {} was still written",
+                    dockerfile_path.display()
+                );
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
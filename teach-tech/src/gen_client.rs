@@ -0,0 +1,306 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+    process::{Command, ExitCode},
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::build::{generate_at_path, Executable};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Lang {
+    /// A TypeScript package using `fetch`
+    Ts,
+}
+
+/// The parts of the OpenAPI document written by the executable's `openapi` command that clients
+/// are generated from.
+#[derive(Deserialize)]
+struct Spec {
+    paths: BTreeMap<String, BTreeMap<String, Operation>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Operation {
+    operation_id: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    parameters: Vec<Parameter>,
+    request_body: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct Parameter {
+    name: String,
+}
+
+const PACKAGE_JSON: &str = r#"{
+  "name": "{name}-client",
+  "version": "{version}",
+  "description": "Generated by `teach-tech gen-client` from the API of {name}",
+  "type": "module",
+  "main": "dist/index.js",
+  "types": "dist/index.d.ts",
+  "files": ["dist", "openapi.json"],
+  "scripts": {
+    "build": "tsc",
+    "prepare": "tsc"
+  },
+  "devDependencies": {
+    "typescript": "^5.6.0"
+  }
+}
+"#;
+
+const TSCONFIG_JSON: &str = r#"{
+  "compilerOptions": {
+    "target": "ES2020",
+    "module": "ES2020",
+    "moduleResolution": "node",
+    "lib": ["ES2020", "DOM"],
+    "declaration": true,
+    "strict": true,
+    "outDir": "dist"
+  },
+  "include": ["src"]
+}
+"#;
+
+const INDEX_TS: &str = r#"// Generated by `teach-tech gen-client` from the API of {name} {version}.
+// Regenerate it instead of editing it, so that it keeps matching the API.
+
+export interface ClientOptions {
+  /** Where the API is served, such as https://api.example.edu */
+  baseUrl: string;
+  /** The bearer token sent with every request, or a function returning the current one */
+  token?: string | (() => string | undefined);
+  fetch?: typeof fetch;
+}
+
+export interface RequestOptions extends RequestInit {
+  query?: Record<string, string | number | boolean | undefined>;
+}
+
+export class ApiError extends Error {
+  constructor(
+    public readonly status: number,
+    public readonly body: string,
+  ) {
+    super(`Request failed with status ${status}`);
+  }
+}
+
+function path(value: string | number): string {
+  return encodeURIComponent(String(value));
+}
+
+export class Client {
+  constructor(private readonly options: ClientOptions) {}
+
+  private async request<T>(
+    method: string,
+    url: string,
+    body: unknown,
+    options: RequestOptions = {},
+  ): Promise<T> {
+    const { query, ...init } = options;
+    const search = new URLSearchParams();
+    for (const [key, value] of Object.entries(query ?? {})) {
+      if (value !== undefined) {
+        search.set(key, String(value));
+      }
+    }
+    if (search.size > 0) {
+      url += `?${search}`;
+    }
+    const token =
+      typeof this.options.token === "function" ? this.options.token() : this.options.token;
+    const headers = new Headers(init.headers);
+    if (token !== undefined) {
+      headers.set("Authorization", `Bearer ${token}`);
+    }
+    if (body !== undefined) {
+      headers.set("Content-Type", "application/json");
+    }
+    const response = await (this.options.fetch ?? fetch)(
+      this.options.baseUrl.replace(/\/$/, "") + url,
+      {
+        ...init,
+        method,
+        headers,
+        body: body === undefined ? undefined : JSON.stringify(body),
+      },
+    );
+    const text = await response.text();
+    if (!response.ok) {
+      throw new ApiError(response.status, text);
+    }
+    if (response.headers.get("Content-Type")?.includes("json")) {
+      return JSON.parse(text) as T;
+    }
+    return text as T;
+  }
+{operations}}
+"#;
+
+fn render(template: &str, executable: &Executable) -> String {
+    template
+        .replace("{name}", &executable.name)
+        .replace("{version}", &executable.version.to_string())
+}
+
+/// A method of the TypeScript `Client` for each operation.
+fn typescript_operations(spec: &Spec) -> String {
+    let mut out = String::new();
+    for (path, operations) in &spec.paths {
+        for (method, operation) in operations {
+            let method = method.to_uppercase();
+            let mut url = path.clone();
+            for parameter in &operation.parameters {
+                url = url.replace(
+                    &format!("{{{}}}", parameter.name),
+                    &format!("${{path(params.{})}}", parameter.name),
+                );
+            }
+            let mut arguments = vec![];
+            if !operation.parameters.is_empty() {
+                let fields: Vec<_> = operation
+                    .parameters
+                    .iter()
+                    .map(|parameter| format!("{}: string | number", parameter.name))
+                    .collect();
+                arguments.push(format!("params: {{ {} }}", fields.join("; ")));
+            }
+            let body = if operation.request_body.is_some() {
+                arguments.push("body?: unknown".to_string());
+                "body"
+            } else {
+                "undefined"
+            };
+            arguments.push("options?: RequestOptions".to_string());
+
+            let from = match operation.tags.first() {
+                Some(tag) => format!(", from {tag}"),
+                None => String::new(),
+            };
+            writeln!(out, "\n  /** `{method} {path}`{from} */").unwrap();
+            writeln!(
+                out,
+                "  {}<T = unknown>({}): Promise<T> {{",
+                operation.operation_id,
+                arguments.join(", ")
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "    return this.request<T>(\"{method}\", `{url}`, {body}, options);"
+            )
+            .unwrap();
+            writeln!(out, "  }}").unwrap();
+        }
+    }
+    out
+}
+
+/// Writes the OpenAPI document of the executable's routes to `output`, using its
+/// `teach-config.toml` or, when there is none, its `teach-config.example.toml`.
+fn dump_spec(root: &Path, executable: &Executable, output: &Path) -> anyhow::Result<()> {
+    let status = Command::new("cargo")
+        .args(["build", "-p", &executable.name])
+        .current_dir(root)
+        .status()
+        .with_context(|| format!("Building {}", executable.name))?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("Building {} failed", executable.name));
+    }
+    let binary = root
+        .join("target")
+        .join("debug")
+        .join(&executable.name)
+        .canonicalize()
+        .with_context(|| format!("Finding the {} executable", executable.name))?;
+
+    // teach-config.toml is read from the working directory
+    let config_dir = if executable.path.join("teach-config.toml").exists() {
+        executable.path.clone()
+    } else {
+        let example = executable.path.join("teach-config.example.toml");
+        let dir = std::env::temp_dir().join(format!("teach-tech-spec-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Creating {} folder", dir.display()))?;
+        std::fs::copy(&example, dir.join("teach-config.toml"))
+            .with_context(|| format!("Copying {}", example.display()))?;
+        dir
+    };
+    let output =
+        std::path::absolute(output).with_context(|| format!("Resolving {}", output.display()))?;
+    let status = Command::new(&binary)
+        .arg("openapi")
+        .arg("--output")
+        .arg(&output)
+        .current_dir(&config_dir)
+        .status()
+        .with_context(|| format!("Running {}", binary.display()));
+    if config_dir != executable.path {
+        let _ = std::fs::remove_dir_all(&config_dir);
+    }
+    if !status?.success() {
+        return Err(anyhow::anyhow!(
+            "{} could not describe its routes",
+            executable.name
+        ));
+    }
+    Ok(())
+}
+
+/// Generates a client package for the executable's API into `output`, which defaults to a
+/// `client-<lang>` folder next to the executable.
+pub fn gen_client_at_path(
+    path: &Path,
+    lang: Lang,
+    executable: Option<&str>,
+    output: Option<PathBuf>,
+) -> anyhow::Result<ExitCode> {
+    let mut generated = generate_at_path(path, false)?;
+    let executable = generated.select_one(executable)?.clone();
+    let output = output.unwrap_or_else(|| {
+        executable.path.join(match lang {
+            Lang::Ts => "client-ts",
+        })
+    });
+    generated.files.create_dir_all(&output.join("src"))?;
+
+    let spec_path = output.join("openapi.json");
+    dump_spec(&generated.root, &executable, &spec_path)?;
+    generated.files.record(&spec_path)?;
+    let spec: Spec = serde_json::from_str(
+        &std::fs::read_to_string(&spec_path)
+            .with_context(|| format!("Reading {}", spec_path.display()))?,
+    )
+    .with_context(|| format!("Parsing {}", spec_path.display()))?;
+
+    match lang {
+        Lang::Ts => {
+            let index = render(INDEX_TS, &executable)
+                .replace("{operations}", &typescript_operations(&spec));
+            let files = [
+                ("package.json", render(PACKAGE_JSON, &executable)),
+                ("tsconfig.json", TSCONFIG_JSON.to_string()),
+                ("src/index.ts", index),
+            ];
+            for (file, contents) in files {
+                generated.files.write(&output.join(file), contents)?;
+            }
+        }
+    }
+    generated.files.save()?;
+    info!("Wrote the client to {}", output.display());
+    Ok(ExitCode::SUCCESS)
+}
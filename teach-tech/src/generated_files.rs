@@ -0,0 +1,96 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// The name of the file in the generated root that lists what teach-tech created.
+pub const FILE_NAME: &str = ".teach-tech-generated.toml";
+
+/// Everything teach-tech created, so that `teach-tech clean` only removes those.
+///
+/// Paths are stored as they were written, relative to the folder teach-tech was run from.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GeneratedFiles {
+    #[serde(skip)]
+    path: PathBuf,
+    /// Files with a hash of the contents teach-tech last wrote, so edits can be detected
+    #[serde(default)]
+    pub files: BTreeMap<PathBuf, String>,
+    /// Folders teach-tech created, which are only removed once empty
+    #[serde(default)]
+    pub dirs: BTreeSet<PathBuf>,
+    /// Outputs of cargo and copied executables, which are removed as is
+    #[serde(default)]
+    pub artifacts: BTreeSet<PathBuf>,
+}
+
+pub fn hash(contents: &[u8]) -> String {
+    format!("{:016x}", fxhash::hash64(contents))
+}
+
+impl GeneratedFiles {
+    /// Reads the list kept in `root`, or starts an empty one.
+    pub fn load(root: &Path) -> anyhow::Result<Self> {
+        let path = root.join(FILE_NAME);
+        let mut generated = if path.exists() {
+            toml::from_str(
+                &std::fs::read_to_string(&path)
+                    .with_context(|| format!("Reading {}", path.display()))?,
+            )
+            .with_context(|| format!("Parsing {}", path.display()))?
+        } else {
+            Self::default()
+        };
+        generated.path = path;
+        Ok(generated)
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            if !parent.exists() {
+                return Ok(());
+            }
+        }
+        std::fs::write(
+            &self.path,
+            toml::to_string(self).context("Serializing the list of generated files")?,
+        )
+        .with_context(|| format!("Creating {}", self.path.display()))
+    }
+
+    /// Creates `path` and its missing parents, remembering the ones that did not exist.
+    pub fn create_dir_all(&mut self, path: &Path) -> anyhow::Result<()> {
+        let missing: Vec<_> = path
+            .ancestors()
+            .take_while(|ancestor| !ancestor.as_os_str().is_empty() && !ancestor.exists())
+            .map(Path::to_path_buf)
+            .collect();
+        std::fs::create_dir_all(path)
+            .with_context(|| format!("Creating {} folder", path.display()))?;
+        self.dirs.extend(missing);
+        Ok(())
+    }
+
+    /// Writes a file and remembers its contents.
+    pub fn write(&mut self, path: &Path, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+        let contents = contents.as_ref();
+        std::fs::write(path, contents).with_context(|| format!("Creating {}", path.display()))?;
+        self.files.insert(path.to_path_buf(), hash(contents));
+        Ok(())
+    }
+
+    /// Remembers the contents of a file that was written some other way.
+    pub fn record(&mut self, path: &Path) -> anyhow::Result<()> {
+        let contents =
+            std::fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+        self.files.insert(path.to_path_buf(), hash(&contents));
+        Ok(())
+    }
+
+    pub fn record_artifact(&mut self, path: &Path) {
+        self.artifacts.insert(path.to_path_buf());
+    }
+}
@@ -5,12 +5,109 @@ use build::build_at_path;
 use clap::{builder::OsStr, Parser, Subcommand};
 
 pub mod build;
+pub mod check;
+pub mod clean;
+pub mod config_example;
+pub mod deploy;
+pub mod dev;
+pub mod docker;
+pub mod gen_client;
+pub mod generated_files;
+pub mod lockfile;
+pub mod metadata;
+pub mod profile;
+pub mod registry;
+pub mod scaffold;
+pub mod target;
+pub mod upgrade;
+pub mod wiring;
 
 #[derive(Subcommand)]
 pub enum Command {
     Build {
         #[arg(default_value = OsStr::from("."))]
         path: PathBuf,
+        /// Writes a Dockerfile for the executable and builds its image instead
+        #[arg(long)]
+        docker: bool,
+        /// Fails instead of updating the executable's Cargo.lock
+        #[arg(long)]
+        locked: bool,
+        /// Only builds this executable, when build-config.toml defines several
+        #[arg(long)]
+        executable: Option<String>,
+        /// Cross-compiles for this target triple, using the settings under [targets.<triple>]
+        #[arg(long)]
+        target: Option<String>,
+        /// Builds with optimizations, using the settings under [release]
+        #[arg(long)]
+        release: bool,
+        /// Copies the built executables into this folder
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Checks build-config.toml and the paths it refers to without generating anything
+    Check {
+        #[arg(default_value = OsStr::from("."))]
+        path: PathBuf,
+    },
+    /// Builds and runs the executable, restarting it when an integration changes
+    Dev {
+        #[arg(default_value = OsStr::from("."))]
+        path: PathBuf,
+        /// The executable to run, when build-config.toml defines several
+        #[arg(long)]
+        executable: Option<String>,
+    },
+    /// Writes a systemd unit, nginx site and environment file for each executable
+    DeployFiles {
+        #[arg(default_value = OsStr::from("."))]
+        path: PathBuf,
+        #[arg(long)]
+        executable: Option<String>,
+    },
+    /// Generates a typed client package for the executable's API
+    GenClient {
+        #[arg(default_value = OsStr::from("."))]
+        path: PathBuf,
+        #[arg(long, value_enum)]
+        lang: gen_client::Lang,
+        /// The executable whose API to describe, when build-config.toml defines several
+        #[arg(long)]
+        executable: Option<String>,
+        /// The folder to write the package to, instead of client-<lang> next to the executable
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Removes the generated executables and build artifacts, keeping anything teach-tech did not
+    /// create
+    Clean {
+        #[arg(default_value = OsStr::from("."))]
+        path: PathBuf,
+        /// Only lists what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Moves teach-tech-core and the integrations to newer releases, then rebuilds
+    Upgrade {
+        #[arg(default_value = OsStr::from("."))]
+        path: PathBuf,
+        /// Also considers releases that are not semver compatible
+        #[arg(long)]
+        breaking: bool,
+        /// Only lists the available upgrades
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Generates the skeleton of a new integration crate
+    NewIntegration {
+        name: String,
+        /// The folder to create the crate in
+        #[arg(long, default_value = OsStr::from("."))]
+        path: PathBuf,
+        /// A version or path, as in build-config.toml
+        #[arg(long, default_value = "0.1.0")]
+        teach_tech_core: String,
     },
 }
 
@@ -24,6 +121,49 @@ pub fn main() -> anyhow::Result<ExitCode> {
     let Cli { command } = Cli::parse();
     tracing_subscriber::fmt().init();
     match command {
-        Command::Build { path } => build_at_path(&path),
+        Command::Build {
+            path,
+            docker,
+            locked,
+            executable,
+            target,
+            release,
+            out,
+        } => {
+            if docker {
+                docker::build_docker_at_path(&path, locked, executable.as_deref())
+            } else {
+                build_at_path(
+                    &path,
+                    locked,
+                    executable.as_deref(),
+                    target.as_deref(),
+                    release,
+                    out.as_deref(),
+                )
+            }
+        }
+        Command::Check { path } => check::check_at_path(&path),
+        Command::Dev { path, executable } => dev::dev_at_path(&path, executable.as_deref()),
+        Command::DeployFiles { path, executable } => {
+            deploy::deploy_files_at_path(&path, executable.as_deref())
+        }
+        Command::GenClient {
+            path,
+            lang,
+            executable,
+            output,
+        } => gen_client::gen_client_at_path(&path, lang, executable.as_deref(), output),
+        Command::Clean { path, dry_run } => clean::clean_at_path(&path, dry_run),
+        Command::Upgrade {
+            path,
+            breaking,
+            dry_run,
+        } => upgrade::upgrade_at_path(&path, breaking, dry_run),
+        Command::NewIntegration {
+            name,
+            path,
+            teach_tech_core,
+        } => scaffold::new_integration(&name, &path, &teach_tech_core),
     }
 }
@@ -0,0 +1,160 @@
+use std::{collections::BTreeMap, path::Path, process::Command};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+struct CargoLock {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: semver::Version,
+    source: Option<String>,
+    checksum: Option<String>,
+}
+
+/// The exact version of a dependency the executable was built with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResolvedDependency {
+    pub version: semver::Version,
+    /// Where cargo fetched the dependency from, or `None` for local paths
+    pub source: Option<String>,
+    /// The SHA-256 of the downloaded `.crate` file, for registry dependencies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+/// The contents of `build-manifest.toml`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BuildManifest {
+    pub dependencies: BTreeMap<String, ResolvedDependency>,
+}
+
+/// Resolves the executable's dependencies into its `Cargo.lock`, then reads back the versions
+/// chosen for each dependency, given as its name in `Cargo.toml` and its package name.
+///
+/// With `locked`, the existing `Cargo.lock` must already satisfy `Cargo.toml`.
+pub fn resolve<'a>(
+    executable_path: &Path,
+    dependencies: impl IntoIterator<Item = (&'a str, &'a str)>,
+    locked: bool,
+) -> anyhow::Result<BuildManifest> {
+    let lock_path = executable_path.join("Cargo.lock");
+    if locked && !lock_path.exists() {
+        return Err(anyhow::anyhow!(
+            "{} does not exist, so the build cannot be locked",
+            lock_path.display()
+        ));
+    }
+    let mut command = Command::new("cargo");
+    command.arg("fetch").current_dir(executable_path);
+    if locked {
+        command.arg("--locked");
+    }
+    let status = command.status().context("Resolving dependencies")?;
+    if !status.success() {
+        return Err(anyhow::anyhow!(
+            "Resolving dependencies failed{}",
+            if locked {
+                "; Cargo.lock may be out of date with build-config.toml"
+            } else {
+                ""
+            }
+        ));
+    }
+
+    let lock = std::fs::read_to_string(&lock_path)
+        .with_context(|| format!("Reading {}", lock_path.display()))?;
+    read_manifest(&lock, dependencies).with_context(|| format!("Parsing {}", lock_path.display()))
+}
+
+/// Reads the versions `lock`, the contents of a `Cargo.lock`, has for each dependency.
+fn read_manifest<'a>(
+    lock: &str,
+    dependencies: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> anyhow::Result<BuildManifest> {
+    let lock: CargoLock = toml::from_str(lock)?;
+    let mut manifest = BuildManifest::default();
+    for (name, package_name) in dependencies {
+        let Some(package) = lock
+            .package
+            .iter()
+            .find(|package| package.name == package_name)
+        else {
+            return Err(anyhow::anyhow!("{package_name} is missing from Cargo.lock"));
+        };
+        manifest.dependencies.insert(
+            name.to_string(),
+            ResolvedDependency {
+                version: package.version.clone(),
+                source: package.source.clone(),
+                checksum: package.checksum.clone(),
+            },
+        );
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCK: &str = r#"
+version = 4
+
+[[package]]
+name = "quick-chat"
+version = "0.2.1"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "abc123"
+
+[[package]]
+name = "teach-tech-core"
+version = "0.1.0"
+"#;
+
+    #[test]
+    fn dependencies_are_read_by_package_name() {
+        let manifest = read_manifest(
+            LOCK,
+            [
+                ("chat", "quick-chat"),
+                ("teach-tech-core", "teach-tech-core"),
+            ],
+        )
+        .unwrap();
+        let chat = &manifest.dependencies["chat"];
+        assert_eq!(chat.version, semver::Version::new(0, 2, 1));
+        assert_eq!(
+            chat.source.as_deref(),
+            Some("registry+https://github.com/rust-lang/crates.io-index")
+        );
+        assert_eq!(chat.checksum.as_deref(), Some("abc123"));
+        let core = &manifest.dependencies["teach-tech-core"];
+        assert_eq!(core.version, semver::Version::new(0, 1, 0));
+        assert!(core.source.is_none() && core.checksum.is_none());
+    }
+
+    #[test]
+    fn missing_dependencies_are_errors() {
+        let error = read_manifest(LOCK, [("scorm", "scorm")]).unwrap_err();
+        assert_eq!(error.to_string(), "scorm is missing from Cargo.lock");
+        assert!(read_manifest("package = 1", [("scorm", "scorm")]).is_err());
+    }
+
+    #[test]
+    fn local_dependencies_are_written_without_a_checksum() {
+        let manifest = read_manifest(LOCK, [("teach-tech-core", "teach-tech-core")]).unwrap();
+        let written = toml::to_string(&manifest).unwrap();
+        assert!(!written.contains("checksum"), "{written}");
+        let read: BuildManifest = toml::from_str(&written).unwrap();
+        assert_eq!(
+            read.dependencies["teach-tech-core"].version,
+            semver::Version::new(0, 1, 0)
+        );
+    }
+}
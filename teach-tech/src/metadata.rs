@@ -0,0 +1,84 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// The parts of `cargo metadata` the builder reads.
+#[derive(Debug, Deserialize)]
+pub struct Metadata {
+    pub packages: Vec<Package>,
+    pub resolve: Option<Resolve>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Package {
+    pub id: String,
+    pub name: String,
+    pub version: semver::Version,
+    pub manifest_path: PathBuf,
+    /// `[package.metadata]`, where integrations describe themselves under `teach-tech`
+    #[serde(default)]
+    pub metadata: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Resolve {
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Node {
+    pub id: String,
+    pub deps: Vec<NodeDep>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NodeDep {
+    /// The name the dependency is used as in code, with `-` replaced by `_`
+    pub name: String,
+    pub pkg: String,
+}
+
+impl Metadata {
+    pub fn package(&self, id: &str) -> Option<&Package> {
+        self.packages.iter().find(|package| package.id == id)
+    }
+
+    /// The packages the package `name` depends on directly, by the names it uses for them.
+    pub fn dependencies(&self, name: &str) -> Vec<(&str, &Package)> {
+        let Some(package) = self.packages.iter().find(|package| package.name == name) else {
+            return vec![];
+        };
+        let Some(node) = self
+            .resolve
+            .iter()
+            .flat_map(|resolve| &resolve.nodes)
+            .find(|node| node.id == package.id)
+        else {
+            return vec![];
+        };
+        node.deps
+            .iter()
+            .filter_map(|dep| Some((dep.name.as_str(), self.package(&dep.pkg)?)))
+            .collect()
+    }
+}
+
+/// Runs `cargo metadata` in `root`.
+pub fn read(root: &Path) -> anyhow::Result<Metadata> {
+    let output = Command::new("cargo")
+        .args(["metadata", "--format-version", "1"])
+        .current_dir(root)
+        .output()
+        .context("Reading cargo metadata")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Reading cargo metadata failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    serde_json::from_slice(&output.stdout).context("Parsing cargo metadata")
+}
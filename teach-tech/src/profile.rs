@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+
+/// The `[release]` section of `build-config.toml`, which becomes the release profile of the
+/// generated crates and is used by `teach-tech build --release`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReleaseConfig {
+    /// One of `off`, `thin` and `fat`
+    #[serde(default = "default_lto")]
+    pub lto: String,
+    /// Removes symbols and debug information from the executable
+    #[serde(default = "default_true")]
+    pub strip: bool,
+    /// Fewer units optimize better but compile slower
+    #[serde(default = "default_codegen_units")]
+    #[serde(alias = "codegen-units")]
+    pub codegen_units: u32,
+    /// Aborts instead of unwinding on panic, which makes the executable smaller
+    #[serde(default)]
+    #[serde(alias = "panic-abort")]
+    pub panic_abort: bool,
+}
+
+impl Default for ReleaseConfig {
+    fn default() -> Self {
+        Self {
+            lto: default_lto(),
+            strip: default_true(),
+            codegen_units: default_codegen_units(),
+            panic_abort: false,
+        }
+    }
+}
+
+fn default_lto() -> String {
+    "thin".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_codegen_units() -> u32 {
+    1
+}
+
+impl ReleaseConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !matches!(self.lto.as_str(), "off" | "thin" | "fat") {
+            return Err(anyhow::anyhow!(
+                "lto must be one of off, thin and fat, not {}",
+                self.lto
+            ));
+        }
+        if self.codegen_units == 0 {
+            return Err(anyhow::anyhow!("codegen_units must be at least 1"));
+        }
+        Ok(())
+    }
+
+    /// The `[profile.release]` section of the `Cargo.toml` cargo is run from.
+    pub fn cargo_profile(&self) -> String {
+        let mut profile = format!(
+            "\n[profile.release]\nlto = \"{}\"\nstrip = {}\ncodegen-units = {}\n",
+            self.lto, self.strip, self.codegen_units
+        );
+        if self.panic_abort {
+            profile.push_str("panic = \"abort\"\n");
+        }
+        profile
+    }
+}
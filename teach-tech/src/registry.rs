@@ -0,0 +1,113 @@
+use std::process::Command;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// The `[registry]` section of `build-config.toml`, for schools hosting their own crate registry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistryConfig {
+    /// How the registry is referred to in the generated `.cargo/config.toml`
+    pub name: String,
+    /// A sparse index URL, such as `sparse+https://crates.example.edu/index/`
+    pub index: String,
+}
+
+const CRATES_IO_INDEX: &str = "sparse+https://index.crates.io/";
+
+/// A line of a sparse index file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct IndexEntry {
+    pub vers: semver::Version,
+    pub cksum: String,
+    #[serde(default)]
+    pub yanked: bool,
+}
+
+/// A `registry:<crate>@<version requirement>` integration, resolved against the index.
+#[derive(Debug, Clone)]
+pub struct RegistryCrate {
+    pub package: String,
+    pub version: semver::Version,
+    /// The SHA-256 of the `.crate` file, as published in the index
+    pub checksum: String,
+}
+
+/// Parses the part of an integration after `registry:`.
+pub fn parse_spec(spec: &str) -> anyhow::Result<(&str, semver::VersionReq)> {
+    let (package, requirement) = match spec.split_once('@') {
+        Some((package, requirement)) => (
+            package,
+            requirement
+                .parse()
+                .with_context(|| format!("Parsing the version requirement in registry:{spec}"))?,
+        ),
+        None => (spec, semver::VersionReq::STAR),
+    };
+    if package.is_empty() {
+        return Err(anyhow::anyhow!("registry:{spec} does not name a crate"));
+    }
+    Ok((package, requirement))
+}
+
+/// The path of a crate's file within a cargo index.
+fn index_path(package: &str) -> String {
+    let package = package.to_lowercase();
+    match package.len() {
+        1 => format!("1/{package}"),
+        2 => format!("2/{package}"),
+        3 => format!("3/{}/{package}", &package[..1]),
+        _ => format!("{}/{}/{package}", &package[..2], &package[2..4]),
+    }
+}
+
+/// Every release of a crate listed in the index, including yanked ones.
+pub fn releases(
+    package: &str,
+    registry: Option<&RegistryConfig>,
+) -> anyhow::Result<Vec<IndexEntry>> {
+    let index = registry.map_or(CRATES_IO_INDEX, |registry| &registry.index);
+    let Some(index) = index.strip_prefix("sparse+") else {
+        return Err(anyhow::anyhow!(
+            "Only sparse registry indices are supported, but {index} is not one"
+        ));
+    };
+    let url = format!("{}/{}", index.trim_end_matches('/'), index_path(package));
+    let output = Command::new("curl")
+        .args(["-fsSL", &url])
+        .output()
+        .context("Running curl to read the registry index")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Reading {url} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let body = String::from_utf8(output.stdout).with_context(|| format!("Reading {url}"))?;
+    body.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).with_context(|| format!("Parsing {url}")))
+        .collect()
+}
+
+/// The newest release matching `requirement` that has not been yanked.
+pub fn newest(releases: Vec<IndexEntry>, requirement: &semver::VersionReq) -> Option<IndexEntry> {
+    releases
+        .into_iter()
+        .filter(|entry| !entry.yanked && requirement.matches(&entry.vers))
+        .max_by(|a, b| a.vers.cmp(&b.vers))
+}
+
+/// Finds the newest release of the crate that satisfies the spec and has not been yanked.
+pub fn resolve(spec: &str, registry: Option<&RegistryConfig>) -> anyhow::Result<RegistryCrate> {
+    let (package, requirement) = parse_spec(spec)?;
+    let Some(best) = newest(releases(package, registry)?, &requirement) else {
+        return Err(anyhow::anyhow!(
+            "No release of {package} satisfies {requirement}"
+        ));
+    };
+    Ok(RegistryCrate {
+        package: package.to_string(),
+        version: best.vers,
+        checksum: best.cksum,
+    })
+}
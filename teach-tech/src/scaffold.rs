@@ -0,0 +1,270 @@
+use std::{path::Path, process::ExitCode};
+
+use anyhow::Context;
+use tracing::info;
+
+const CARGO_TOML: &str = r#"[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2021"
+
+[dependencies]
+{teach_tech_core}
+serde = { version = "1.0.214", features = ["derive"] }
+sea-orm = "1.1.1"
+toml = "0.8.19"
+tracing = "0.1.40"
+
+[dev-dependencies]
+tokio = { version = "1.41.0", features = ["macros", "rt"] }
+tower = { version = "0.5.1", features = ["util"] }
+
+[package.metadata.teach-tech]
+# Collected into teach-config.example.toml by `teach-tech build`
+config-example = "teach-config.example.toml"
+# Integrations that have to be added to the core before this one, by package name
+depends-on = []
+"#;
+
+const CONFIG_EXAMPLE: &str = r#"[{snake_name}]
+greeting = "Hello from {name}"
+"#;
+
+const LIB_RS: &str = r#"use serde::Deserialize;
+use teach_tech_core::{
+    anyhow,
+    axum::{http::StatusCode, response::IntoResponse, routing::get, Json},
+    db::get_db,
+    sea_orm::EntityTrait,
+    TeachCore,
+};
+use tracing::error;
+
+pub mod example;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct {config_name}Config {
+    #[serde(default)]
+    pub {snake_name}: {config_name}Options,
+}
+
+/// The `[{snake_name}]` section of `teach-config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct {config_name}Options {
+    #[serde(default = "default_greeting")]
+    pub greeting: String,
+}
+
+impl Default for {config_name}Options {
+    fn default() -> Self {
+        Self {
+            greeting: default_greeting(),
+        }
+    }
+}
+
+fn default_greeting() -> String {
+    "Hello from {name}".to_string()
+}
+
+pub async fn add_to_core<S: Clone + Send + Sync + 'static>(
+    mut core: TeachCore<S>,
+) -> anyhow::Result<TeachCore<S>> {
+    core.add_info("{name}", env!("CARGO_PKG_VERSION"));
+    core.declare_config::<{config_name}Config>();
+    let config: {config_name}Config = toml::from_str(core.get_config_str())?;
+    core.add_db_reset_config(example::Entity);
+
+    let greeting = config.{snake_name}.greeting;
+    core = core.modify_router(|router| {
+        router
+            .route("/{name}", get(move || std::future::ready(greeting.clone())))
+            .route(
+                "/{name}/examples",
+                get(|| async {
+                    match example::Entity::find().all(get_db()).await {
+                        Ok(examples) => (StatusCode::OK, Json(examples)).into_response(),
+                        Err(e) => {
+                            error!("Error listing examples: {e:#}");
+                            (StatusCode::INTERNAL_SERVER_ERROR, ()).into_response()
+                        }
+                    }
+                }),
+            )
+    });
+
+    Ok(core)
+}
+"#;
+
+const EXAMPLE_RS: &str = r#"use sea_orm::entity::prelude::*;
+use serde::Serialize;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "{snake_name}_examples")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub name: String,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+"#;
+
+const TEST_RS: &str = r##"use {snake_name}::{config_name}Config;
+
+#[test]
+fn config_defaults() {
+    let config: {config_name}Config = toml::from_str("").unwrap();
+    assert_eq!(config.{snake_name}.greeting, "Hello from {name}");
+}
+
+#[test]
+fn config_section() {
+    let config: {config_name}Config = toml::from_str(
+        r#"
+        [{snake_name}]
+        greeting = "Hi"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.{snake_name}.greeting, "Hi");
+}
+"##;
+
+const EXAMPLES_TEST_RS: &str = r##"use teach_tech_core::{
+    axum::{
+        body::{self, Body},
+        http::{Request, StatusCode},
+    },
+    TeachCore,
+};
+use tower::ServiceExt;
+
+// One test, since the database can only be connected once per process
+#[tokio::test]
+async fn examples_start_empty() {
+    let core = TeachCore::for_tests(r#"database_url = "sqlite::memory:""#)
+        .await
+        .unwrap();
+    let router = {snake_name}::add_to_core(core)
+        .await
+        .unwrap()
+        .into_test_router()
+        .await
+        .unwrap();
+
+    let response = router
+        .oneshot(
+            Request::get("/{name}/examples")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"[]");
+}
+"##;
+
+pub(crate) fn validate_name(name: &str) -> anyhow::Result<()> {
+    let mut chars = name.chars();
+    if !chars.next().is_some_and(|c| c.is_ascii_lowercase()) {
+        return Err(anyhow::anyhow!(
+            "{name} must start with a lowercase ASCII letter"
+        ));
+    }
+    if !chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_') {
+        return Err(anyhow::anyhow!(
+            "{name} may only contain lowercase ASCII letters, digits, '-' and '_'"
+        ));
+    }
+    if matches!(
+        name,
+        "teach-tech" | "teach-tech-core" | "test" | "core" | "std"
+    ) {
+        return Err(anyhow::anyhow!("{name} is a reserved name"));
+    }
+    Ok(())
+}
+
+fn render(template: &str, name: &str, teach_tech_core: &str) -> String {
+    let snake_name = name.replace('-', "_");
+    let config_name: String = snake_name
+        .split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            std::iter::once(first).chain(chars).collect::<String>()
+        })
+        .collect();
+    template
+        .replace("{teach_tech_core}", teach_tech_core)
+        .replace("{config_name}", &config_name)
+        .replace("{snake_name}", &snake_name)
+        .replace("{name}", name)
+}
+
+/// Generates a new integration crate named `name` inside `path`.
+///
+/// `teach_tech_core` takes the same forms as in `build-config.toml`: a version or a path.
+pub fn new_integration(name: &str, path: &Path, teach_tech_core: &str) -> anyhow::Result<ExitCode> {
+    validate_name(name)?;
+    let crate_path = path.join(name);
+    if crate_path.exists() {
+        return Err(anyhow::anyhow!("{} already exists", crate_path.display()));
+    }
+
+    let teach_tech_core = if let Ok(version) = teach_tech_core.parse::<semver::Version>() {
+        format!("teach-tech-core = \"{version}\"")
+    } else {
+        let core_path = Path::new(teach_tech_core);
+        if !core_path.join("Cargo.toml").is_file() {
+            return Err(anyhow::anyhow!(
+                "Path {teach_tech_core}/Cargo.toml does not exist"
+            ));
+        }
+        // The crate may be generated anywhere, so relative paths cannot be kept as is
+        let core_path = core_path
+            .canonicalize()
+            .with_context(|| format!("Resolving {teach_tech_core}"))?;
+        format!("teach-tech-core.path = \"{}\"", core_path.display())
+    };
+
+    std::fs::create_dir_all(crate_path.join("src"))
+        .with_context(|| format!("Creating {name}/src folder"))?;
+    std::fs::create_dir(crate_path.join("tests"))
+        .with_context(|| format!("Creating {name}/tests folder"))?;
+
+    let files = [
+        (".gitignore", "/target\n"),
+        ("Cargo.toml", CARGO_TOML),
+        ("teach-config.example.toml", CONFIG_EXAMPLE),
+        ("src/lib.rs", LIB_RS),
+        ("src/example.rs", EXAMPLE_RS),
+        ("tests/config.rs", TEST_RS),
+        ("tests/examples.rs", EXAMPLES_TEST_RS),
+    ];
+    for (file, template) in files {
+        std::fs::write(
+            crate_path.join(file),
+            render(template, name, &teach_tech_core),
+        )
+        .with_context(|| format!("Creating {name}/{file}"))?;
+    }
+
+    info!("Created {}", crate_path.display());
+    info!(
+        "Add `{name} = \"{}\"` under [integrations] in build-config.toml to use it",
+        crate_path.display()
+    );
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,75 @@
+use std::{collections::BTreeMap, fmt::Write, path::Path, process::Command};
+
+use serde::{Deserialize, Serialize};
+
+/// A `[targets.<triple>]` section of `build-config.toml`, used when building with `--target`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TargetConfig {
+    /// Builds with `cross` instead of cargo, which brings its own linker and C libraries
+    #[serde(default)]
+    pub cross: bool,
+    /// The linker cargo should use, such as `aarch64-linux-gnu-gcc`
+    pub linker: Option<String>,
+    #[serde(default)]
+    pub rustflags: Vec<String>,
+}
+
+/// Whether a target triple has the shape `<arch>-<vendor or os>[-...]`.
+pub fn is_triple(triple: &str) -> bool {
+    let parts: Vec<_> = triple.split('-').collect();
+    parts.len() >= 2
+        && parts.iter().all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        })
+}
+
+/// The `[target.<triple>]` sections of the generated `.cargo/config.toml`.
+pub fn cargo_config(targets: &BTreeMap<String, TargetConfig>) -> String {
+    let mut out = String::new();
+    for (triple, target) in targets {
+        if target.linker.is_none() && target.rustflags.is_empty() {
+            continue;
+        }
+        writeln!(out, "[target.\"{triple}\"]").unwrap();
+        if let Some(linker) = &target.linker {
+            writeln!(out, "linker = \"{linker}\"").unwrap();
+        }
+        if !target.rustflags.is_empty() {
+            let rustflags: Vec<_> = target
+                .rustflags
+                .iter()
+                .map(|flag| format!("\"{flag}\""))
+                .collect();
+            writeln!(out, "rustflags = [{}]", rustflags.join(", ")).unwrap();
+        }
+    }
+    out
+}
+
+/// Fails with a hint when rustup does not have the standard library for `triple` in the toolchain
+/// used from `root`.
+///
+/// Nothing is checked when rustup is not installed.
+pub fn check_installed(root: &Path, triple: &str) -> anyhow::Result<()> {
+    let Ok(output) = Command::new("rustup")
+        .args(["target", "list", "--installed"])
+        .current_dir(root)
+        .output()
+    else {
+        return Ok(());
+    };
+    if !output.status.success() {
+        return Ok(());
+    }
+    let installed = String::from_utf8_lossy(&output.stdout);
+    if installed.lines().any(|line| line.trim() == triple) {
+        return Ok(());
+    }
+    Err(anyhow::anyhow!(
+        "The {triple} target is not installed; run `rustup target add {triple}`, or set \
+         cross = true under [targets.{triple}] in build-config.toml to build with cross"
+    ))
+}
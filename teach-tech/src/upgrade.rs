@@ -0,0 +1,268 @@
+use std::{path::Path, process::ExitCode};
+
+use anyhow::Context;
+use toml_edit::{DocumentMut, Item, Value};
+use tracing::{info, warn};
+
+use crate::{
+    build::{build_at_path, generate_at_path, read_build_config},
+    metadata,
+    registry::{self, RegistryConfig},
+};
+
+/// How a version is written in `build-config.toml`, which decides how it is rewritten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Form {
+    /// `name = "1.2.3"`
+    Version,
+    /// `name = { version = "1.2" }`
+    Requirement,
+    /// `name = "registry:crate@1.2"`
+    Registry,
+}
+
+/// A dependency whose version can be upgraded.
+#[derive(Debug, Clone)]
+struct Candidate {
+    /// The keys leading to the table holding the dependency
+    table: Vec<String>,
+    name: String,
+    package: String,
+    current: String,
+    form: Form,
+}
+
+#[derive(Debug, Clone)]
+struct Upgrade {
+    candidate: Candidate,
+    /// The lowest version the old requirement allowed
+    from: semver::Version,
+    to: semver::Version,
+}
+
+/// The lowest version a requirement such as `1.2` or `=1.2.3` allows.
+fn lower_bound(requirement: &str) -> Option<semver::Version> {
+    if let Ok(version) = requirement.parse::<semver::Version>() {
+        return Some(version);
+    }
+    let requirement: semver::VersionReq = requirement.parse().ok()?;
+    let comparator = requirement.comparators.first()?;
+    Some(semver::Version::new(
+        comparator.major,
+        comparator.minor.unwrap_or(0),
+        comparator.patch.unwrap_or(0),
+    ))
+}
+
+fn integration_candidates(table: &[&str], integrations: &toml_edit::Table) -> Vec<Candidate> {
+    let mut candidates = vec![];
+    for (name, item) in integrations.iter() {
+        let (current, form, package) = match item.as_value() {
+            Some(Value::String(metadata)) => {
+                let metadata = metadata.value();
+                if metadata.parse::<semver::Version>().is_ok() {
+                    (metadata.clone(), Form::Version, name.to_string())
+                } else if let Some(spec) = metadata.strip_prefix("registry:") {
+                    let Ok((package, requirement)) = registry::parse_spec(spec) else {
+                        continue;
+                    };
+                    (requirement.to_string(), Form::Registry, package.to_string())
+                } else {
+                    continue;
+                }
+            }
+            Some(Value::InlineTable(detailed)) => {
+                let Some(version) = detailed.get("version").and_then(Value::as_str) else {
+                    continue;
+                };
+                (version.to_string(), Form::Requirement, name.to_string())
+            }
+            _ => continue,
+        };
+        candidates.push(Candidate {
+            table: table.iter().map(|key| key.to_string()).collect(),
+            name: name.to_string(),
+            package,
+            current,
+            form,
+        });
+    }
+    candidates
+}
+
+fn candidates(document: &DocumentMut) -> Vec<Candidate> {
+    let mut candidates = vec![];
+    for key in ["teach-tech-core", "teach_tech_core"] {
+        let Some(current) = document.get(key).and_then(Item::as_str) else {
+            continue;
+        };
+        if current.parse::<semver::Version>().is_ok() {
+            candidates.push(Candidate {
+                table: vec![],
+                name: key.to_string(),
+                package: "teach-tech-core".to_string(),
+                current: current.to_string(),
+                form: Form::Version,
+            });
+        }
+    }
+    if let Some(integrations) = document.get("integrations").and_then(Item::as_table) {
+        candidates.extend(integration_candidates(&["integrations"], integrations));
+    }
+    if let Some(executables) = document.get("executables").and_then(Item::as_table) {
+        for (executable, item) in executables.iter() {
+            let Some(integrations) = item.get("integrations").and_then(Item::as_table) else {
+                continue;
+            };
+            candidates.extend(integration_candidates(
+                &["executables", executable, "integrations"],
+                integrations,
+            ));
+        }
+    }
+    candidates
+}
+
+/// Finds the newest release each candidate can move to.
+fn find_upgrades(
+    candidates: Vec<Candidate>,
+    registry: Option<&RegistryConfig>,
+    breaking: bool,
+) -> anyhow::Result<Vec<Upgrade>> {
+    let mut upgrades = vec![];
+    for candidate in candidates {
+        let Some(from) = lower_bound(&candidate.current) else {
+            warn!(
+                "Could not understand the version {} of {}",
+                candidate.current, candidate.name
+            );
+            continue;
+        };
+        // Only registry: integrations are looked up in a school's registry
+        let index = match candidate.form {
+            Form::Registry => registry,
+            Form::Version | Form::Requirement => None,
+        };
+        let requirement = if breaking {
+            semver::VersionReq::STAR
+        } else if candidate.form == Form::Registry {
+            // Compatible releases are already picked up by every build
+            continue;
+        } else {
+            format!("^{from}").parse()?
+        };
+        let releases = registry::releases(&candidate.package, index)?;
+        let Some(newest) = registry::newest(releases, &requirement) else {
+            continue;
+        };
+        if newest.vers > from {
+            upgrades.push(Upgrade {
+                candidate,
+                from,
+                to: newest.vers,
+            });
+        }
+    }
+    Ok(upgrades)
+}
+
+fn apply(document: &mut DocumentMut, upgrade: &Upgrade) {
+    let Candidate {
+        table,
+        name,
+        package,
+        form,
+        ..
+    } = &upgrade.candidate;
+    let mut item = document.as_item_mut();
+    for key in table {
+        item = &mut item[key.as_str()];
+    }
+    let to = &upgrade.to;
+    match form {
+        Form::Version => item[name.as_str()] = toml_edit::value(to.to_string()),
+        Form::Requirement => item[name.as_str()]["version"] = toml_edit::value(to.to_string()),
+        Form::Registry => {
+            item[name.as_str()] = toml_edit::value(format!("registry:{package}@{to}"));
+        }
+    }
+}
+
+/// Prints the notes integrations publish under
+/// `[package.metadata.teach-tech.breaking-changes]` for the versions that were skipped over.
+fn report_notes(root: &Path, upgrades: &[Upgrade]) -> anyhow::Result<()> {
+    let metadata = metadata::read(root)?;
+
+    for upgrade in upgrades {
+        let Some(package) = metadata.packages.iter().find(|package| {
+            package.name == upgrade.candidate.package && package.version == upgrade.to
+        }) else {
+            continue;
+        };
+        let Some(notes) = package.metadata["teach-tech"]["breaking-changes"].as_object() else {
+            continue;
+        };
+        let mut notes: Vec<_> = notes
+            .iter()
+            .filter_map(|(version, note)| {
+                Some((version.parse::<semver::Version>().ok()?, note.as_str()?))
+            })
+            .filter(|(version, _)| *version > upgrade.from && *version <= upgrade.to)
+            .collect();
+        notes.sort_by(|a, b| a.0.cmp(&b.0));
+        for (version, note) in notes {
+            println!("{} {version}: {note}", package.name);
+        }
+    }
+    Ok(())
+}
+
+/// Moves teach-tech-core and the integrations to their newest releases, then rebuilds.
+///
+/// Only semver compatible releases are considered unless `breaking` is set.
+pub fn upgrade_at_path(path: &Path, breaking: bool, dry_run: bool) -> anyhow::Result<ExitCode> {
+    let config = read_build_config(path)?;
+    let config_path = path.join("build-config.toml");
+    let mut document: DocumentMut = std::fs::read_to_string(&config_path)
+        .context("Reading build-config.toml")?
+        .parse()
+        .context("Parsing build-config.toml")?;
+
+    let upgrades = find_upgrades(candidates(&document), config.registry.as_ref(), breaking)?;
+    if upgrades.is_empty() {
+        println!("Everything is up to date");
+        return Ok(ExitCode::SUCCESS);
+    }
+    for upgrade in &upgrades {
+        println!(
+            "{}: {} -> {}",
+            upgrade.candidate.name, upgrade.candidate.current, upgrade.to
+        );
+    }
+    if dry_run {
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    for upgrade in &upgrades {
+        apply(&mut document, upgrade);
+    }
+    std::fs::write(&config_path, document.to_string()).context("Writing build-config.toml")?;
+
+    let generated = generate_at_path(path, false)?;
+    let mut command = std::process::Command::new("cargo");
+    command.arg("update").current_dir(&generated.root);
+    for upgrade in &upgrades {
+        command.args(["-p", &upgrade.candidate.package]);
+    }
+    let status = command.status().context("Updating Cargo.lock")?;
+    if !status.success() {
+        return Ok(ExitCode::FAILURE);
+    }
+
+    info!("Rebuilding with the upgraded versions");
+    let exit_code = build_at_path(path, false, None, None, false, None)?;
+    if let Err(e) = report_notes(&generated.root, &upgrades) {
+        warn!("Could not gather breaking change notes: {e:#}");
+    }
+    Ok(exit_code)
+}
@@ -0,0 +1,203 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{build::Executable, metadata::Metadata};
+
+/// The integrations a package says must be added to the core before it, as package names under
+/// `depends-on` in `[package.metadata.teach-tech]`.
+fn declared_dependencies(metadata: &serde_json::Value) -> anyhow::Result<Vec<&str>> {
+    let depends_on = &metadata["teach-tech"]["depends-on"];
+    if depends_on.is_null() {
+        return Ok(vec![]);
+    }
+    depends_on
+        .as_array()
+        .and_then(|names| names.iter().map(|name| name.as_str()).collect())
+        .ok_or_else(|| anyhow::anyhow!("depends-on must be a list of package names"))
+}
+
+/// The order in which `add_to_core` has to be called for the integrations of `executable`, so
+/// that every integration comes after the ones it depends on.
+///
+/// Integrations that do not depend on each other are kept in alphabetical order.
+pub fn integration_order(
+    metadata: &Metadata,
+    executable: &Executable,
+) -> anyhow::Result<Vec<String>> {
+    let packages = metadata.dependencies(&executable.name);
+    // Package names to the names the integrations have in build-config.toml
+    let mut by_package = BTreeMap::new();
+    let mut dependencies: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for name in executable.integrations.keys() {
+        dependencies.insert(name, BTreeSet::new());
+        let code_name = name.replace('-', "_");
+        if let Some((_, package)) = packages.iter().find(|(used_as, _)| *used_as == code_name) {
+            by_package.insert(package.name.as_str(), name.as_str());
+        }
+    }
+    for (used_as, package) in &packages {
+        let Some(name) = executable
+            .integrations
+            .keys()
+            .find(|name| name.replace('-', "_") == *used_as)
+        else {
+            continue;
+        };
+        let declared = declared_dependencies(&package.metadata)
+            .map_err(|e| anyhow::anyhow!("{} {}: {e}", package.name, package.version))?;
+        for dependency in declared {
+            let Some(dependency) = by_package.get(dependency) else {
+                return Err(anyhow::anyhow!(
+                    "{name} depends on {dependency}, which is not an integration of {}",
+                    executable.name
+                ));
+            };
+            dependencies
+                .get_mut(name.as_str())
+                .unwrap()
+                .insert(dependency);
+        }
+    }
+
+    let mut order = vec![];
+    while !dependencies.is_empty() {
+        let ready: Vec<_> = dependencies
+            .iter()
+            .filter(|(_, deps)| deps.iter().all(|dep| !dependencies.contains_key(dep)))
+            .map(|(&name, _)| name)
+            .collect();
+        if ready.is_empty() {
+            // Every integration left depends on another one left, so following them loops
+            let mut cycle = vec![*dependencies.keys().next().unwrap()];
+            loop {
+                let last = cycle.last().unwrap();
+                let next = dependencies[last]
+                    .iter()
+                    .find(|dep| dependencies.contains_key(*dep))
+                    .unwrap();
+                if let Some(start) = cycle.iter().position(|name| name == next) {
+                    cycle.drain(..start);
+                    cycle.push(next);
+                    break;
+                }
+                cycle.push(next);
+            }
+            return Err(anyhow::anyhow!(
+                "The integrations of {} depend on each other in a cycle: {}",
+                executable.name,
+                cycle.join(" -> ")
+            ));
+        }
+        for name in ready {
+            dependencies.remove(name);
+            order.push(name.to_string());
+        }
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::build::Integration;
+
+    /// Integrations, each with the packages it declares in `depends-on`.
+    type Integrations<'a> = &'a [(&'a str, &'a [&'a str])];
+
+    /// The order of an executable with `integrations`.
+    fn order(integrations: Integrations) -> anyhow::Result<Vec<String>> {
+        let mut packages = vec![serde_json::json!({
+            "id": "exe",
+            "name": "exe",
+            "version": "0.1.0",
+            "manifest_path": "exe/Cargo.toml",
+        })];
+        let mut deps = vec![];
+        for (name, depends_on) in integrations {
+            packages.push(serde_json::json!({
+                "id": name,
+                "name": name,
+                "version": "0.1.0",
+                "manifest_path": format!("{name}/Cargo.toml"),
+                "metadata": { "teach-tech": { "depends-on": depends_on } },
+            }));
+            deps.push(serde_json::json!({ "name": name.replace('-', "_"), "pkg": name }));
+        }
+        let metadata: Metadata = serde_json::from_value(serde_json::json!({
+            "packages": packages,
+            "resolve": { "nodes": [{ "id": "exe", "deps": deps }] },
+        }))
+        .unwrap();
+        let executable = Executable {
+            name: "exe".to_string(),
+            version: semver::Version::new(0, 1, 0),
+            path: PathBuf::from("exe"),
+            integrations: integrations
+                .iter()
+                .map(|(name, _)| (name.to_string(), Integration::Short("0.1.0".to_string())))
+                .collect(),
+        };
+        integration_order(&metadata, &executable)
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let cases: &[(Integrations, &[&str])] = &[
+            (&[], &[]),
+            (&[("b", &[]), ("a", &[])], &["a", "b"]),
+            (&[("a", &["b"]), ("b", &[])], &["b", "a"]),
+            (
+                &[("a", &["b"]), ("b", &["c"]), ("c", &[])],
+                &["c", "b", "a"],
+            ),
+            (
+                &[("d", &["b", "c"]), ("c", &["a"]), ("b", &["a"]), ("a", &[])],
+                &["a", "b", "c", "d"],
+            ),
+            (
+                &[("quick-chat", &["billing"]), ("billing", &[])],
+                &["billing", "quick-chat"],
+            ),
+        ];
+        for (integrations, expected) in cases {
+            assert_eq!(order(integrations).unwrap(), *expected, "{integrations:?}");
+        }
+    }
+
+    #[test]
+    fn cycles_are_refused() {
+        let cases: &[(Integrations, &str)] = &[
+            (&[("a", &["a"])], "a -> a"),
+            (&[("a", &["b"]), ("b", &["a"])], "a -> b -> a"),
+            (
+                &[("a", &["b"]), ("b", &["c"]), ("c", &["b"])],
+                "b -> c -> b",
+            ),
+        ];
+        for (integrations, cycle) in cases {
+            let error = order(integrations).unwrap_err().to_string();
+            assert_eq!(
+                error,
+                format!("The integrations of exe depend on each other in a cycle: {cycle}")
+            );
+        }
+    }
+
+    #[test]
+    fn undeclared_dependencies_are_refused() {
+        let error = order(&[("a", &["billing"])]).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "a depends on billing, which is not an integration of exe"
+        );
+    }
+
+    #[test]
+    fn depends_on_must_list_names() {
+        let metadata = serde_json::json!({ "teach-tech": { "depends-on": "billing" } });
+        assert!(declared_dependencies(&metadata).is_err());
+        let metadata = serde_json::json!({ "teach-tech": {} });
+        assert!(declared_dependencies(&metadata).unwrap().is_empty());
+    }
+}